@@ -36,6 +36,7 @@ fn print_crypto_details(header: &FileHeader, aad: &[u8]) {
 	header.keyslots.iter().enumerate().for_each(|(i, k)| {
 		printdoc! {"
             Keyslot {index}:
+              Label: {label}
               Version: {version}
               Algorithm: {algorithm}
               Hashing algorithm: {hashing_algorithm}
@@ -44,6 +45,7 @@ fn print_crypto_details(header: &FileHeader, aad: &[u8]) {
               Master key nonce (hex): {nonce}
         ",
 			index = i + i,
+			label = k.label.as_deref().unwrap_or("None"),
 			version = k.version,
 			algorithm = k.algorithm,
 			hashing_algorithm = k.hashing_algorithm,
@@ -30,7 +30,12 @@ const LibraryGeneralSettingsScreen = ({
 	const { mutate: editLibrary } = useBridgeMutation('library.edit');
 
 	useAutoForm(form, (value) => {
-		editLibrary({ description: value.description, name: value.name, id: library.uuid });
+		editLibrary({
+			description: value.description,
+			name: value.name,
+			shareable: null,
+			id: library.uuid
+		});
 		// console.log('Updated', value);
 		// TODO: Show toast
 	});
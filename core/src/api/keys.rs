@@ -1,10 +1,10 @@
-use sd_crypto::keys::keymanager::{StoredKey, StoredKeyType};
+use sd_crypto::keys::keymanager::{ImportPolicy, KeyManagerEvent, StoredKey, StoredKeyType};
 use sd_crypto::primitives::SECRET_KEY_IDENTIFIER;
 use sd_crypto::types::{Algorithm, HashingAlgorithm, SecretKeyString};
 use sd_crypto::{Error, Protected};
 use serde::Deserialize;
 use specta::Type;
-use std::{path::PathBuf, str::FromStr};
+use std::{path::PathBuf, str::FromStr, time::Duration};
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use uuid::Uuid;
@@ -36,13 +36,52 @@ pub struct RestoreBackupArgs {
 	path: PathBuf,
 }
 
+#[derive(Type, Deserialize)]
+pub struct ExportKeysArgs {
+	password: Protected<String>,
+	path: PathBuf,
+}
+
+#[derive(Type, Deserialize)]
+pub struct ImportKeysArgs {
+	password: Protected<String>,
+	path: PathBuf,
+	policy: ImportPolicy,
+}
+
 #[derive(Type, Deserialize)]
 pub struct MasterPasswordChangeArgs {
+	old_password: Protected<String>,
 	password: Protected<String>,
 	algorithm: Algorithm,
 	hashing_algorithm: HashingAlgorithm,
 }
 
+#[derive(Type, Deserialize)]
+pub struct VerifyPasswordArgs {
+	uuid: Uuid,
+	password: Protected<String>,
+}
+
+#[derive(Type, Deserialize)]
+pub struct KeyRenameArgs {
+	uuid: Uuid,
+	label: String,
+	description: Option<String>,
+}
+
+#[derive(Type, Deserialize)]
+pub struct MountQueuedArgs {
+	uuid: Uuid,
+	password: Protected<String>,
+}
+
+#[derive(Type, Deserialize)]
+pub struct MountWithTtlArgs {
+	uuid: Uuid,
+	ttl_secs: u32,
+}
+
 #[derive(Type, Deserialize)]
 pub struct AutomountUpdateArgs {
 	uuid: Uuid,
@@ -62,6 +101,10 @@ pub(crate) fn mount() -> RouterBuilder {
 		.library_query("listMounted", |t| {
 			t(|_, _: (), library| async move { Ok(library.key_manager.get_mounted_uuids()) })
 		})
+		// this doesn't include any secret material, so the UI can show details about each mounted key
+		.library_query("mountedKeys", |t| {
+			t(|_, _: (), library| async move { Ok(library.key_manager.mounted_keys().await) })
+		})
 		.library_query("getKey", |t| {
 			t(|_, key_uuid: Uuid, library| async move {
 				Ok(library
@@ -72,14 +115,97 @@ pub(crate) fn mount() -> RouterBuilder {
 					.clone())
 			})
 		})
+		// this doesn't mount the key, so it can be used for confirming the user's password
+		.library_mutation("verifyPassword", |t| {
+			t(|_, args: VerifyPasswordArgs, library| async move {
+				Ok(library
+					.key_manager
+					.verify_password(args.uuid, args.password)
+					.await?)
+			})
+		})
 		.library_mutation("mount", |t| {
 			t(|_, key_uuid: Uuid, library| async move {
 				library.key_manager.mount(key_uuid).await?;
 				// we also need to dispatch jobs that automatically decrypt preview media and metadata here
 				invalidate_query!(library, "keys.listMounted");
+				invalidate_query!(library, "keys.mountedKeys");
+				Ok(())
+			})
+		})
+		// this is so the UI can show keys that are waiting for a password separately from mounted keys
+		.library_query("keyState", |t| {
+			t(
+				|_, key_uuid: Uuid, library| async move { Ok(library.key_manager.key_state(key_uuid)?) },
+			)
+		})
+		.library_mutation("queue", |t| {
+			t(|_, key_uuid: Uuid, library| async move {
+				library.key_manager.queue_key(key_uuid)?;
+				invalidate_query!(library, "keys.keyState");
 				Ok(())
 			})
 		})
+		.library_mutation("unqueue", |t| {
+			t(|_, key_uuid: Uuid, library| async move {
+				library.key_manager.unqueue_key(key_uuid)?;
+				invalidate_query!(library, "keys.keyState");
+				Ok(())
+			})
+		})
+		.library_mutation("mountQueued", |t| {
+			t(|_, args: MountQueuedArgs, library| async move {
+				library
+					.key_manager
+					.mount_queued(args.uuid, args.password)
+					.await?;
+				invalidate_query!(library, "keys.keyState");
+				invalidate_query!(library, "keys.listMounted");
+				invalidate_query!(library, "keys.mountedKeys");
+				Ok(())
+			})
+		})
+		// this mounts every key that matches the password, and skips any that are already mounted
+		.library_mutation("mountAll", |t| {
+			t(|_, password: Protected<String>, library| async move {
+				let report = library.key_manager.mount_all(password).await?;
+				invalidate_query!(library, "keys.listMounted");
+				invalidate_query!(library, "keys.mountedKeys");
+				Ok(report)
+			})
+		})
+		// the key is unmounted once it hasn't been used for `ttl_secs`, and `keys.keyExpired` is emitted
+		.library_mutation("mountWithTtl", |t| {
+			t(|_, args: MountWithTtlArgs, library| async move {
+				library
+					.key_manager
+					.mount_with_ttl(args.uuid, Duration::from_secs(args.ttl_secs.into()))
+					.await?;
+				invalidate_query!(library, "keys.listMounted");
+				invalidate_query!(library, "keys.mountedKeys");
+				Ok(())
+			})
+		})
+		// this is so the UI can prompt for the key again once it expires
+		.library_subscription("keyExpired", |t| {
+			t(|ctx, _: (), library_id| {
+				async_stream::stream! {
+					let Some(library) = ctx.library_manager.get_ctx(library_id).await else {
+						return
+					};
+					let mut rx = library.key_manager.subscribe();
+					while let Ok(event) = rx.recv().await {
+						match event {
+							KeyManagerEvent::KeyExpired(uuid) => {
+								invalidate_query!(library, "keys.listMounted");
+								invalidate_query!(library, "keys.mountedKeys");
+								yield uuid;
+							}
+						}
+					}
+				}
+			})
+		})
 		.library_query("getSecretKey", |t| {
 			t(|_, _: (), library| async move {
 				if library
@@ -106,6 +232,7 @@ pub(crate) fn mount() -> RouterBuilder {
 				library.key_manager.unmount(key_uuid)?;
 				// we also need to delete all in-memory decrypted data associated with this key
 				invalidate_query!(library, "keys.listMounted");
+				invalidate_query!(library, "keys.mountedKeys");
 				Ok(())
 			})
 		})
@@ -129,6 +256,37 @@ pub(crate) fn mount() -> RouterBuilder {
 				Ok(())
 			})
 		})
+		.library_mutation("rename", |t| {
+			t(|_, args: KeyRenameArgs, library| async move {
+				library
+					.key_manager
+					.rename_key(args.uuid, args.label.clone())
+					.await?;
+				library
+					.key_manager
+					.set_key_description(args.uuid, args.description.clone())
+					.await?;
+
+				if !library.key_manager.is_memory_only(args.uuid).await? {
+					library
+						.db
+						.key()
+						.update(
+							key::uuid::equals(args.uuid.to_string()),
+							vec![
+								key::SetParam::SetName(Some(args.label)),
+								key::SetParam::SetDescription(args.description),
+							],
+						)
+						.exec()
+						.await?;
+				}
+
+				invalidate_query!(library, "keys.list");
+				invalidate_query!(library, "keys.mountedKeys");
+				Ok(())
+			})
+		})
 		.library_mutation("updateAutomountStatus", |t| {
 			t(|_, args: AutomountUpdateArgs, library| async move {
 				if !library.key_manager.is_memory_only(args.uuid).await? {
@@ -148,6 +306,7 @@ pub(crate) fn mount() -> RouterBuilder {
 						.await?;
 
 					invalidate_query!(library, "keys.list");
+					invalidate_query!(library, "keys.mountedKeys");
 				}
 
 				Ok(())
@@ -169,6 +328,7 @@ pub(crate) fn mount() -> RouterBuilder {
 				// we also need to delete all in-memory decrypted data associated with this key
 				invalidate_query!(library, "keys.list");
 				invalidate_query!(library, "keys.listMounted");
+				invalidate_query!(library, "keys.mountedKeys");
 				invalidate_query!(library, "keys.getDefault");
 				Ok(())
 			})
@@ -189,6 +349,20 @@ pub(crate) fn mount() -> RouterBuilder {
 
 				invalidate_query!(library, "keys.isUnlocked");
 
+				// the default key can only be restored once the key manager has been unlocked
+				if let Some(key) = library
+					.db
+					.key()
+					.find_first(vec![key::default::equals(true)])
+					.exec()
+					.await?
+				{
+					let uuid = Uuid::from_str(&key.uuid).map_err(|_| Error::Serialization)?;
+					library.key_manager.set_default(Some(uuid)).await?;
+
+					invalidate_query!(library, "keys.getDefault");
+				}
+
 				let automount = library
 					.db
 					.key()
@@ -203,13 +377,15 @@ pub(crate) fn mount() -> RouterBuilder {
 						.await?;
 
 					invalidate_query!(library, "keys.listMounted");
+					invalidate_query!(library, "keys.mountedKeys");
 				}
 
 				Ok(())
 			})
 		})
+		// providing `null` clears the default key
 		.library_mutation("setDefault", |t| {
-			t(|_, key_uuid: Uuid, library| async move {
+			t(|_, key_uuid: Option<Uuid>, library| async move {
 				library.key_manager.set_default(key_uuid).await?;
 
 				library
@@ -222,17 +398,20 @@ pub(crate) fn mount() -> RouterBuilder {
 					.exec()
 					.await?;
 
-				library
-					.db
-					.key()
-					.update(
-						key::uuid::equals(key_uuid.to_string()),
-						vec![key::SetParam::SetDefault(true)],
-					)
-					.exec()
-					.await?;
+				if let Some(key_uuid) = key_uuid {
+					library
+						.db
+						.key()
+						.update(
+							key::uuid::equals(key_uuid.to_string()),
+							vec![key::SetParam::SetDefault(true)],
+						)
+						.exec()
+						.await?;
+				}
 
 				invalidate_query!(library, "keys.getDefault");
+				invalidate_query!(library, "keys.mountedKeys");
 				Ok(())
 			})
 		})
@@ -246,6 +425,7 @@ pub(crate) fn mount() -> RouterBuilder {
 			t(|_, _: (), library| async move {
 				library.key_manager.empty_keymount();
 				invalidate_query!(library, "keys.listMounted");
+				invalidate_query!(library, "keys.mountedKeys");
 				Ok(())
 			})
 		})
@@ -289,6 +469,7 @@ pub(crate) fn mount() -> RouterBuilder {
 
 				invalidate_query!(library, "keys.list");
 				invalidate_query!(library, "keys.listMounted");
+				invalidate_query!(library, "keys.mountedKeys");
 				Ok(())
 			})
 		})
@@ -305,12 +486,64 @@ pub(crate) fn mount() -> RouterBuilder {
 
 				let mut output_file = File::create(path).await.map_err(Error::Io)?;
 				output_file
-					.write_all(&serde_json::to_vec(&stored_keys).map_err(|_| Error::Serialization)?)
+					.write_all(&serde_json::to_vec(&stored_keys).map_err(Error::from)?)
 					.await
 					.map_err(Error::Io)?;
 				Ok(())
 			})
 		})
+		// unlike `backupKeystore`, this backup is encrypted with the provided password and only requires it to be imported
+		.library_mutation("exportKeys", |t| {
+			t(|_, args: ExportKeysArgs, library| async move {
+				let backup = library.key_manager.export_keys(args.password).await?;
+
+				let mut output_file = File::create(args.path).await.map_err(Error::Io)?;
+				output_file.write_all(&backup).await.map_err(Error::Io)?;
+				Ok(())
+			})
+		})
+		// this imports a backup created with `exportKeys`
+		.library_mutation("importKeys", |t| {
+			t(|_, args: ImportKeysArgs, library| async move {
+				let mut input_file = File::open(args.path).await.map_err(Error::Io)?;
+
+				let mut backup = Vec::new();
+
+				input_file
+					.read_to_end(&mut backup)
+					.await
+					.map_err(Error::Io)?;
+
+				let report = library
+					.key_manager
+					.import_keys(&backup, args.password, args.policy)
+					.await?;
+
+				// overwritten keys may have been memory-only, in which case they won't be in the database
+				library
+					.db
+					.key()
+					.delete_many(vec![key::uuid::in_vec(
+						report.overwritten.iter().map(ToString::to_string).collect(),
+					)])
+					.exec()
+					.await?;
+
+				for uuid in report.added.iter().chain(&report.overwritten) {
+					write_storedkey_to_db(
+						&library.db,
+						&library.key_manager.access_keystore(*uuid).await?,
+					)
+					.await?;
+				}
+
+				invalidate_query!(library, "keys.list");
+				invalidate_query!(library, "keys.listMounted");
+				invalidate_query!(library, "keys.mountedKeys");
+
+				Ok(report)
+			})
+		})
 		.library_mutation("restoreKeystore", |t| {
 			t(|_, args: RestoreBackupArgs, library| async move {
 				let mut input_file = File::open(args.path).await.map_err(Error::Io)?;
@@ -323,7 +556,7 @@ pub(crate) fn mount() -> RouterBuilder {
 					.map_err(Error::Io)?;
 
 				let stored_keys: Vec<StoredKey> =
-					serde_json::from_slice(&backup).map_err(|_| Error::Serialization)?;
+					serde_json::from_slice(&backup).map_err(Error::from)?;
 
 				let updated_keys = library
 					.key_manager
@@ -340,15 +573,18 @@ pub(crate) fn mount() -> RouterBuilder {
 
 				invalidate_query!(library, "keys.list");
 				invalidate_query!(library, "keys.listMounted");
+				invalidate_query!(library, "keys.mountedKeys");
 
 				Ok(TryInto::<u32>::try_into(updated_keys.len()).unwrap()) // We convert from `usize` (bigint type) to `u32` (number type) because rspc doesn't support bigints.
 			})
 		})
 		.library_mutation("changeMasterPassword", |t| {
 			t(|_, args: MasterPasswordChangeArgs, library| async move {
-				let verification_key = library
+				let (verification_key, _) = library
 					.key_manager
 					.change_master_password(
+						args.old_password,
+						None,
 						args.password,
 						args.algorithm,
 						args.hashing_algorithm,
@@ -358,18 +594,67 @@ pub(crate) fn mount() -> RouterBuilder {
 
 				invalidate_query!(library, "keys.getSecretKey");
 
+				// the new verification key is written before the old one is removed. they both decrypt the same root key,
+				// so the key manager can still be unlocked (with either password) if we're interrupted in between
+				write_storedkey_to_db(&library.db, &verification_key).await?;
+
 				// remove old root key if present
 				library
 					.db
 					.key()
-					.delete_many(vec![key::key_type::equals(
-						serde_json::to_string(&StoredKeyType::Root).unwrap(),
-					)])
+					.delete_many(vec![
+						key::key_type::equals(serde_json::to_string(&StoredKeyType::Root).unwrap()),
+						key::uuid::not(verification_key.uuid.to_string()),
+					])
 					.exec()
 					.await?;
 
-				// write the new verification key
-				write_storedkey_to_db(&library.db, &verification_key).await?;
+				Ok(())
+			})
+		})
+		.library_mutation("rotateRootKey", |t| {
+			t(|_, password: Protected<String>, library| async move {
+				let (verification_key, stored_keys) = library
+					.key_manager
+					.rotate_root_key(password, None, library.id)
+					.await?;
+
+				// the stored keys can only be mounted with the new root key, so they're written in the same batch as the new verification key
+				library
+					.db
+					._batch((
+						stored_keys
+							.iter()
+							.filter(|key| !key.memory_only)
+							.map(|key| {
+								library.db.key().update(
+									key::uuid::equals(key.uuid.to_string()),
+									vec![
+										key::master_key::set(key.master_key.to_vec()),
+										key::master_key_nonce::set(key.master_key_nonce.to_vec()),
+										key::salt::set(key.salt.to_vec()),
+									],
+								)
+							})
+							.collect::<Vec<_>>(),
+						library.db.key().update_many(
+							vec![key::key_type::equals(
+								serde_json::to_string(&StoredKeyType::Root).unwrap(),
+							)],
+							vec![
+								key::uuid::set(verification_key.uuid.to_string()),
+								key::content_salt::set(verification_key.content_salt.0.to_vec()),
+								key::master_key::set(verification_key.master_key.to_vec()),
+								key::master_key_nonce::set(
+									verification_key.master_key_nonce.to_vec(),
+								),
+								key::key_nonce::set(verification_key.key_nonce.to_vec()),
+								key::key::set(verification_key.key.to_vec()),
+								key::salt::set(verification_key.salt.to_vec()),
+							],
+						),
+					))
+					.await?;
 
 				Ok(())
 			})
@@ -158,12 +158,20 @@ pub(crate) fn mount() -> RouterBuilder {
 				pub id: Uuid,
 				pub name: Option<String>,
 				pub description: Option<String>,
+				pub shareable: Option<bool>,
+				pub sync_enabled: Option<bool>,
 			}
 
 			t(|node, args: EditLibraryArgs| async move {
 				Ok(node
 					.library_manager
-					.edit(args.id, args.name, args.description)
+					.edit(
+						args.id,
+						args.name,
+						args.description,
+						args.shareable,
+						args.sync_enabled,
+					)
 					.await?)
 			})
 		})
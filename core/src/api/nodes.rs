@@ -1,24 +1,45 @@
 use super::RouterBuilder;
-use rspc::Type;
+use rspc::{ErrorCode, Type};
 use serde::{Deserialize, Serialize};
 
 pub(crate) fn mount() -> RouterBuilder {
-	<RouterBuilder>::new().mutation("tokenizeSensitiveKey", |t| {
-		#[derive(Deserialize, Type)]
-		pub struct TokenizeKeyArgs {
-			pub secret_key: String,
-		}
-		#[derive(Serialize, Type)]
-		pub struct TokenizeResponse {
-			pub token: String,
-		}
+	<RouterBuilder>::new()
+		.mutation("edit", |t| {
+			#[derive(Deserialize, Type)]
+			pub struct EditNodeArgs {
+				pub name: String,
+			}
 
-		t(|ctx, args: TokenizeKeyArgs| async move {
-			let token = ctx.secure_temp_keystore.tokenize(args.secret_key);
+			t(|ctx, args: EditNodeArgs| async move {
+				let name = args.name.trim();
+				if name.is_empty() {
+					return Err(rspc::Error::new(
+						ErrorCode::BadRequest,
+						"the node name can't be empty".into(),
+					));
+				}
 
-			Ok(TokenizeResponse {
-				token: token.to_string(),
+				ctx.p2p.set_node_name(name.to_owned()).await.map_err(|err| {
+					rspc::Error::new(ErrorCode::InternalServerError, err.to_string())
+				})
+			})
+		})
+		.mutation("tokenizeSensitiveKey", |t| {
+			#[derive(Deserialize, Type)]
+			pub struct TokenizeKeyArgs {
+				pub secret_key: String,
+			}
+			#[derive(Serialize, Type)]
+			pub struct TokenizeResponse {
+				pub token: String,
+			}
+
+			t(|ctx, args: TokenizeKeyArgs| async move {
+				let token = ctx.secure_temp_keystore.tokenize(args.secret_key);
+
+				Ok(TokenizeResponse {
+					token: token.to_string(),
+				})
 			})
 		})
-	})
 }
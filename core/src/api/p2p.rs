@@ -1,9 +1,9 @@
-use rspc::Type;
+use rspc::{ErrorCode, Type};
 use sd_p2p::PeerId;
 use serde::Deserialize;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use crate::p2p::P2PEvent;
+use crate::p2p::{DialPolicy, FileRequest, P2PEvent};
 
 use super::RouterBuilder;
 
@@ -14,18 +14,28 @@ pub(crate) fn mount() -> RouterBuilder {
 				let mut rx = ctx.p2p.subscribe();
 				async_stream::stream! {
 					// TODO: Don't block subscription start
-					for peer in ctx.p2p.manager.get_discovered_peers().await {
+					for peer in ctx.p2p.manager().get_discovered_peers().await {
+						let addresses = ctx.p2p.peer_addresses(peer.peer_id).await.addresses;
 						yield P2PEvent::DiscoveredPeer {
 							peer_id: peer.peer_id,
 							metadata: peer.metadata,
+							addresses: addresses.into_iter().map(|addr| addr.address).collect(),
 						};
 					}
 
-					// // TODO: Don't block subscription start
-					// for peer in ctx.p2p_manager.get_connected_peers().await.unwrap() {
-					// 	// TODO: Send to frontend
-					// }
+					yield P2PEvent::ListenAddrsChanged {
+						addresses: ctx.p2p.listen_addrs().await,
+					};
 
+					// Late subscribers need the peers which are already connected, not just the ones which connect later
+					for peer in ctx.p2p.connected_peers().await {
+						yield P2PEvent::ConnectedPeer {
+							peer_id: peer.peer_id,
+							address: peer.addresses.first().copied(),
+							transport: peer.transport,
+							relayed: peer.relayed,
+						};
+					}
 
 					while let Ok(event) = rx.recv().await {
 						yield event;
@@ -33,6 +43,198 @@ pub(crate) fn mount() -> RouterBuilder {
 				}
 			})
 		})
+		.query("connectedPeers", |t| {
+			t(|ctx, _: ()| async move { ctx.p2p.connected_peers().await })
+		})
+		.query("peerAddresses", |t| {
+			t(|ctx, peer_id: PeerId| async move { ctx.p2p.peer_addresses(peer_id).await })
+		})
+		.query("syncQueues", |t| {
+			t(|ctx, _: ()| async move { ctx.p2p.sync_queue_stats().await })
+		})
+		.query("lanes", |t| {
+			t(|ctx, _: ()| async move { ctx.p2p.lane_stats() })
+		})
+		.query("traffic", |t| {
+			t(|ctx, _: ()| async move { ctx.p2p.traffic().await })
+		})
+		.query("status", |t| {
+			t(|ctx, _: ()| async move { ctx.p2p.status().await })
+		})
+		.query("blockedPeers", |t| {
+			t(|ctx, _: ()| async move { ctx.p2p.blocked_peers().into_iter().collect::<Vec<_>>() })
+		})
+		.query("listenAddrs", |t| {
+			t(|ctx, _: ()| async move {
+				ctx.p2p
+					.listen_addrs()
+					.await
+					.into_iter()
+					.map(|addr| addr.to_string())
+					.collect::<Vec<_>>()
+			})
+		})
+		.query("manualPeers", |t| {
+			t(|ctx, _: ()| async move {
+				ctx.p2p
+					.manual_peers()
+					.await
+					.into_iter()
+					.map(|addr| addr.to_string())
+					.collect::<Vec<_>>()
+			})
+		})
+		.mutation("addManualPeer", |t| {
+			t(|ctx, addr: String| async move {
+				ctx.p2p
+					.add_manual_peer(&addr)
+					.await
+					.map(|addr| addr.to_string())
+					.map_err(|err| rspc::Error::new(ErrorCode::BadRequest, err.to_string()))
+			})
+		})
+		.mutation("removeManualPeer", |t| {
+			t(|ctx, addr: String| async move {
+				ctx.p2p
+					.remove_manual_peer(&addr)
+					.await
+					.map_err(|err| rspc::Error::new(ErrorCode::BadRequest, err.to_string()))
+			})
+		})
+		.mutation("connect", |t| {
+			t(|ctx, peer_id: PeerId| async move {
+				ctx.p2p
+					.connect_to(peer_id)
+					.await
+					.map_err(|err| rspc::Error::new(ErrorCode::BadRequest, err.to_string()))
+			})
+		})
+		.mutation("disconnect", |t| {
+			t(|ctx, peer_id: PeerId| async move {
+				ctx.p2p.disconnect_peer(peer_id).await;
+				Ok(())
+			})
+		})
+		.mutation("blockPeer", |t| {
+			t(|ctx, peer_id: PeerId| async move {
+				ctx.p2p.block_peer(peer_id).await.map_err(|err| {
+					rspc::Error::new(ErrorCode::InternalServerError, err.to_string())
+				})
+			})
+		})
+		.mutation("unblockPeer", |t| {
+			t(|ctx, peer_id: PeerId| async move {
+				ctx.p2p.unblock_peer(peer_id).await.map_err(|err| {
+					rspc::Error::new(ErrorCode::InternalServerError, err.to_string())
+				})
+			})
+		})
+		.mutation("setDialPolicy", |t| {
+			t(|ctx, policy: DialPolicy| async move {
+				ctx.p2p.set_dial_policy(policy).await.map_err(|err| {
+					rspc::Error::new(ErrorCode::InternalServerError, err.to_string())
+				})
+			})
+		})
+		.mutation("pinAddress", |t| {
+			#[derive(Type, Deserialize)]
+			pub struct PinAddressArgs {
+				peer_id: PeerId,
+				address: Option<String>,
+			}
+
+			t(|ctx, args: PinAddressArgs| async move {
+				ctx.p2p
+					.pin_address(args.peer_id, args.address.as_deref())
+					.await
+					.map(|addr| addr.map(|addr| addr.to_string()))
+					.map_err(|err| rspc::Error::new(ErrorCode::BadRequest, err.to_string()))
+			})
+		})
+		.mutation("setDiscoveryEnabled", |t| {
+			t(|ctx, enabled: bool| async move {
+				ctx.p2p.set_discovery_enabled(enabled).await.map_err(|err| {
+					rspc::Error::new(ErrorCode::InternalServerError, err.to_string())
+				})
+			})
+		})
+		.mutation("setPeerNickname", |t| {
+			#[derive(Type, Deserialize)]
+			pub struct SetPeerNicknameArgs {
+				peer_id: PeerId,
+				nickname: Option<String>,
+			}
+
+			t(|ctx, args: SetPeerNicknameArgs| async move {
+				ctx.p2p
+					.set_peer_nickname(args.peer_id, args.nickname.as_deref())
+					.await
+					.map_err(|err| {
+						rspc::Error::new(ErrorCode::InternalServerError, err.to_string())
+					})
+			})
+		})
+		.mutation("initiatePairing", |t| {
+			t(|ctx, peer_id: PeerId| async move {
+				ctx.p2p
+					.initiate_pairing(peer_id)
+					.await
+					.map_err(|err| rspc::Error::new(ErrorCode::BadRequest, err.to_string()))
+			})
+		})
+		.mutation("confirmPairing", |t| {
+			#[derive(Type, Deserialize)]
+			pub struct ConfirmPairingArgs {
+				peer_id: PeerId,
+				code: String,
+			}
+
+			t(|ctx, args: ConfirmPairingArgs| async move {
+				ctx.p2p
+					.confirm_pairing(args.peer_id, args.code)
+					.await
+					.map_err(|err| rspc::Error::new(ErrorCode::BadRequest, err.to_string()))
+			})
+		})
+		.mutation("acceptPairing", |t| {
+			t(|ctx, peer_id: PeerId| async move {
+				ctx.p2p
+					.accept_pairing(peer_id)
+					.await
+					.map_err(|err| rspc::Error::new(ErrorCode::BadRequest, err.to_string()))
+			})
+		})
+		.mutation("rejectPairing", |t| {
+			t(|ctx, peer_id: PeerId| async move {
+				ctx.p2p
+					.reject_pairing(peer_id)
+					.await
+					.map_err(|err| rspc::Error::new(ErrorCode::BadRequest, err.to_string()))
+			})
+		})
+		.mutation("unpair", |t| {
+			t(|ctx, peer_id: PeerId| async move {
+				ctx.p2p.unpair(peer_id).await.map_err(|err| {
+					rspc::Error::new(ErrorCode::InternalServerError, err.to_string())
+				})
+			})
+		})
+		.mutation("requestFile", |t| {
+			#[derive(Type, Deserialize)]
+			pub struct RequestFileArgs {
+				peer_id: PeerId,
+				file: FileRequest,
+				path: String,
+			}
+
+			t(|ctx, args: RequestFileArgs| async move {
+				ctx.p2p
+					.request_file(args.peer_id, args.file, Path::new(&args.path))
+					.await
+					.map(|size| size.to_string())
+					.map_err(|err| rspc::Error::new(ErrorCode::BadRequest, err.to_string()))
+			})
+		})
 		.mutation("spacedrop", |t| {
 			#[derive(Type, Deserialize)]
 			pub struct SpacedropArgs {
@@ -130,7 +130,7 @@ impl Node {
 		let jobs = JobManager::new();
 		let location_manager = LocationManager::new();
 		let secure_temp_keystore = SecureTempKeystore::new();
-		let (p2p, mut p2p_rx) = P2PManager::new(config.clone()).await;
+		let (p2p, mut p2p_rx) = P2PManager::new(config.clone()).await?;
 
 		let library_manager = LibraryManager::new(
 			data_dir.join("libraries"),
@@ -143,6 +143,7 @@ impl Node {
 			},
 		)
 		.await?;
+		p2p.set_library_manager(library_manager.clone());
 
 		// Adding already existing locations for location management
 		for library in library_manager.get_all_libraries().await {
@@ -193,16 +194,16 @@ impl Node {
 						} => {
 							debug!("going to ingest {} operations", operations.len());
 
-							let libraries = library_manager.libraries.read().await;
-
-							let Some(library) = libraries.first() else {
-                                warn!("no library found!");
-                                continue;
-                            };
+							// Operations are only sent to peers which are members of the library so they must only be applied to that library
+							let Some(library) = library_manager.get_ctx(library_id).await else {
+								warn!("Dropping sync operations for library '{library_id}' which isn't loaded on this node");
+								continue;
+							};
 
 							for op in operations {
-								println!("ingest lib id: {}", library.id);
-								library.sync.ingest_op(op).await.unwrap();
+								if let Err(err) = library.sync.ingest_op(op).await {
+									error!("Error ingesting sync operation for library '{library_id}': {err}");
+								}
 							}
 						}
 						_ => {}
@@ -229,6 +230,7 @@ impl Node {
 	pub async fn shutdown(&self) {
 		info!("Spacedrive shutting down...");
 		self.jobs.pause().await;
+		self.p2p.shutdown().await;
 		info!("Spacedrive Core shutdown successful!");
 	}
 
@@ -259,4 +261,6 @@ pub enum NodeError {
 	FailedToInitializeLibraryManager(#[from] library::LibraryManagerError),
 	#[error("Location manager error: {0}")]
 	LocationManager(#[from] LocationManagerError),
+	#[error("Failed to start P2P: {0}")]
+	P2PManager(#[from] sd_p2p::ManagerError),
 }
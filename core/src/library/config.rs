@@ -4,9 +4,12 @@ use std::{
 	path::PathBuf,
 };
 
+use base64::{engine::general_purpose::STANDARD, Engine};
 use rspc::Type;
+use sd_crypto::{primitives::to_array, types::Key};
+use sd_p2p::Keypair;
 use serde::{Deserialize, Serialize};
-use std::io::Write;
+use std::{fmt, io::Write};
 use uuid::Uuid;
 
 use crate::node::ConfigMetadata;
@@ -22,9 +25,76 @@ pub struct LibraryConfig {
 	pub name: String,
 	/// description is a user set description of the library. This is used in the UI and is set by the user.
 	pub description: String,
+	/// shareable is set by the user to allow the library to be listed to other peers so they can pair with it.
+	#[serde(default)]
+	pub shareable: bool,
+	/// sync_disabled is set by the user to stop the library's sync operations from being sent to or accepted from other peers. Sync is enabled by default.
+	#[serde(default)]
+	pub sync_disabled: bool,
 	// /// is_encrypted is a flag that is set to true if the library is encrypted.
 	// #[serde(default)]
 	// pub is_encrypted: bool,
+	/// sync_key is the secret shared between all nodes in the library. It is used to sign and verify the sync operations sent between them.
+	#[serde(default)]
+	#[specta(skip)]
+	pub sync_key: SyncKey,
+	/// keypair is this node's signing keypair for the library. Unlike the node's keypair it is only used within this library, so a leaked key can't be used to impersonate the node in its other libraries.
+	/// Libraries created before library keypairs existed don't have one until [super::LibraryManager::keypair] is first called.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	#[specta(skip)]
+	pub keypair: Option<Keypair>,
+}
+
+/// SyncKey is used to authenticate sync operations sent between the nodes of a library.
+/// It's stored in the library config as base64.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct SyncKey(Key);
+
+impl SyncKey {
+	pub fn key(&self) -> &Key {
+		&self.0
+	}
+}
+
+impl Default for SyncKey {
+	fn default() -> Self {
+		Self(Key::generate())
+	}
+}
+
+// `blake3::Hash` implements `PartialEq` in constant-time
+impl PartialEq for SyncKey {
+	fn eq(&self, other: &Self) -> bool {
+		blake3::Hash::from(*self.0.expose()) == blake3::Hash::from(*other.0.expose())
+	}
+}
+
+impl Eq for SyncKey {}
+
+impl fmt::Debug for SyncKey {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("SyncKey([REDACTED])")
+	}
+}
+
+impl From<SyncKey> for String {
+	fn from(key: SyncKey) -> Self {
+		STANDARD.encode(key.0.expose())
+	}
+}
+
+impl TryFrom<String> for SyncKey {
+	type Error = &'static str;
+
+	fn try_from(value: String) -> Result<Self, Self::Error> {
+		let bytes = STANDARD
+			.decode(value)
+			.map_err(|_| "invalid sync key encoding")?;
+		Ok(Self(Key::new(
+			to_array(&bytes).map_err(|_| "invalid sync key length")?,
+		)))
+	}
 }
 
 impl LibraryConfig {
@@ -33,10 +103,19 @@ impl LibraryConfig {
 		let mut file = File::open(&file_dir)?;
 		let base_config: ConfigMetadata = serde_json::from_reader(BufReader::new(&mut file))?;
 
-		Self::migrate_config(base_config.version, file_dir)?;
+		Self::migrate_config(base_config.version, file_dir.clone())?;
 
 		file.rewind()?;
-		Ok(serde_json::from_reader(BufReader::new(&mut file))?)
+		let value: serde_json::Value = serde_json::from_reader(BufReader::new(&mut file))?;
+		let is_missing_sync_key = value.get("sync_key").is_none();
+		let config = serde_json::from_value(value)?;
+
+		// Libraries created before sync keys existed have one generated on load which must be saved so it doesn't change every launch.
+		if is_missing_sync_key {
+			Self::save(file_dir, &config).await?;
+		}
+
+		Ok(config)
 	}
 
 	/// save will write the configuration back to disk
@@ -2,8 +2,9 @@ use crate::{
 	invalidate_query,
 	location::file_path_helper::LastFilePathIdManager,
 	node::Platform,
-	prisma::{node, PrismaClient},
-	sync::{SyncManager, SyncMessage},
+	p2p::{next_batch, SharedLibrary},
+	prisma::{node, shared_operation, PrismaClient},
+	sync::SyncManager,
 	util::{
 		db::{load_and_migrate, write_storedkey_to_db},
 		seeder::{indexer_rules_seeder, SeederError},
@@ -11,10 +12,12 @@ use crate::{
 	NodeContext,
 };
 
+use futures::{Stream, StreamExt};
 use sd_crypto::{
 	keys::keymanager::{KeyManager, StoredKey},
 	types::{EncryptedKey, Nonce, OnboardingConfig, Salt},
 };
+use sd_p2p::Keypair;
 use std::{
 	env, fs, io,
 	path::{Path, PathBuf},
@@ -23,10 +26,10 @@ use std::{
 };
 use thiserror::Error;
 use tokio::sync::RwLock;
-use tracing::debug;
+use tracing::{debug, warn};
 use uuid::Uuid;
 
-use super::{Library, LibraryConfig, LibraryConfigWrapped};
+use super::{Library, LibraryConfig, LibraryConfigWrapped, SyncKey};
 
 /// LibraryManager is a singleton that manages all libraries for a node.
 pub struct LibraryManager {
@@ -74,8 +77,6 @@ pub async fn seed_keymanager(
 	client: &PrismaClient,
 	km: &Arc<KeyManager>,
 ) -> Result<(), LibraryManagerError> {
-	let mut default = None;
-
 	// collect and serialize the stored keys
 	let stored_keys: Vec<StoredKey> = client
 		.key()
@@ -87,10 +88,6 @@ pub async fn seed_keymanager(
 			let key = key.clone();
 			let uuid = uuid::Uuid::from_str(&key.uuid).unwrap();
 
-			if key.default {
-				default = Some(uuid);
-			}
-
 			Ok(StoredKey {
 				uuid,
 				version: serde_json::from_str(&key.version)
@@ -109,6 +106,8 @@ pub async fn seed_keymanager(
 				salt: Salt::try_from(key.salt)?,
 				memory_only: false,
 				automount: key.automount,
+				label: key.name.unwrap_or_else(|| StoredKey::default_label(uuid)),
+				description: key.description,
 			})
 		})
 		.collect::<Result<Vec<StoredKey>, sd_crypto::Error>>()
@@ -117,9 +116,6 @@ pub async fn seed_keymanager(
 	// insert all keys from the DB into the keymanager's keystore
 	km.populate_keystore(stored_keys).await?;
 
-	// if any key had an associated default tag
-	default.map(|k| km.set_default(k));
-
 	Ok(())
 }
 
@@ -236,6 +232,8 @@ impl LibraryManager {
 		id: Uuid,
 		name: Option<String>,
 		description: Option<String>,
+		shareable: Option<bool>,
+		sync_enabled: Option<bool>,
 	) -> Result<(), LibraryManagerError> {
 		// check library is valid
 		let mut libraries = self.libraries.write().await;
@@ -251,6 +249,12 @@ impl LibraryManager {
 		if let Some(description) = description {
 			library.config.description = description;
 		}
+		if let Some(shareable) = shareable {
+			library.config.shareable = shareable;
+		}
+		if let Some(sync_enabled) = sync_enabled {
+			library.config.sync_disabled = !sync_enabled;
+		}
 
 		LibraryConfig::save(
 			Path::new(&self.libraries_dir).join(format!("{id}.sdlibrary")),
@@ -258,11 +262,124 @@ impl LibraryManager {
 		)
 		.await?;
 
+		if let Some(sync_enabled) = sync_enabled {
+			self.node_context
+				.p2p
+				.set_sync_enabled(id, sync_enabled)
+				.await;
+		}
+
 		invalidate_query!(library, "library.list");
 
 		Ok(())
 	}
 
+	/// shared_libraries returns the libraries which the user has marked as shareable with other peers.
+	/// The version is the timestamp of the library's latest sync operation so a peer can tell if it's behind.
+	pub(crate) async fn shared_libraries(&self) -> Vec<SharedLibrary> {
+		self.stream_shared_libraries().collect().await
+	}
+
+	/// stream_shared_libraries is the same as `shared_libraries` but each library is yielded as soon as its version has been read.
+	pub(crate) fn stream_shared_libraries(&self) -> impl Stream<Item = SharedLibrary> + Send + '_ {
+		async_stream::stream! {
+			let libraries = self.libraries.read().await.clone();
+
+			for library in libraries.into_iter().filter(|lib| lib.config.shareable) {
+				let version = library
+					.db
+					.shared_operation()
+					.find_first(vec![])
+					.order_by(shared_operation::timestamp::order(
+						prisma_client_rust::Direction::Desc,
+					))
+					.exec()
+					.await
+					.map_err(|err| {
+						warn!(
+							"Error getting the version of library '{}': {err}",
+							library.id
+						)
+					})
+					.ok()
+					.flatten()
+					.map_or(0, |op| op.timestamp as u64);
+
+				yield SharedLibrary {
+					id: library.id,
+					name: library.config.name,
+					version,
+				};
+			}
+		}
+	}
+
+	/// set_sync_key replaces the sync key of a library with the one from a paired node so they can verify each other's sync operations.
+	pub(crate) async fn set_sync_key(
+		&self,
+		id: Uuid,
+		sync_key: SyncKey,
+	) -> Result<(), LibraryManagerError> {
+		let mut libraries = self.libraries.write().await;
+		let library = libraries
+			.iter_mut()
+			.find(|lib| lib.id == id)
+			.ok_or(LibraryManagerError::LibraryNotFound)?;
+
+		library.config.sync_key = sync_key.clone();
+
+		LibraryConfig::save(
+			Path::new(&self.libraries_dir).join(format!("{id}.sdlibrary")),
+			&library.config,
+		)
+		.await?;
+
+		self.node_context.p2p.add_library(id, sync_key).await;
+
+		Ok(())
+	}
+
+	/// keypair returns this node's signing keypair for the library, generating and saving one if the library doesn't have one yet.
+	pub(crate) async fn keypair(&self, id: Uuid) -> Result<Keypair, LibraryManagerError> {
+		if let Some(keypair) = self
+			.libraries
+			.read()
+			.await
+			.iter()
+			.find(|lib| lib.id == id)
+			.ok_or(LibraryManagerError::LibraryNotFound)?
+			.config
+			.keypair
+			.clone()
+		{
+			return Ok(keypair);
+		}
+
+		let mut libraries = self.libraries.write().await;
+		let library = libraries
+			.iter_mut()
+			.find(|lib| lib.id == id)
+			.ok_or(LibraryManagerError::LibraryNotFound)?;
+
+		// Another caller may have generated the keypair while the lock was released
+		if let Some(keypair) = &library.config.keypair {
+			return Ok(keypair.clone());
+		}
+
+		let keypair = Keypair::generate();
+		library.config.keypair = Some(keypair.clone());
+
+		LibraryConfig::save(
+			Path::new(&self.libraries_dir).join(format!("{id}.sdlibrary")),
+			&library.config,
+		)
+		.await?;
+
+		debug!("Generated a signing keypair for library '{id}'");
+
+		Ok(keypair)
+	}
+
 	pub async fn delete_library(&self, id: Uuid) -> Result<(), LibraryManagerError> {
 		let mut libraries = self.libraries.write().await;
 
@@ -277,6 +394,7 @@ impl LibraryManager {
 		invalidate_query!(library, "library.list");
 
 		libraries.retain(|l| l.id != id);
+		self.node_context.p2p.remove_library(id).await;
 
 		Ok(())
 	}
@@ -339,14 +457,36 @@ impl LibraryManager {
 
 		let (sync_manager, mut sync_rx) = SyncManager::new(&db, id);
 
+		node_context
+			.p2p
+			.set_sync_enabled(id, !config.sync_disabled)
+			.await;
+		node_context
+			.p2p
+			.add_library(id, config.sync_key.clone())
+			.await;
+
 		tokio::spawn({
 			let node_context = node_context.clone();
 
 			async move {
-				while let Ok(op) = sync_rx.recv().await {
-					let SyncMessage::Created(op) = op else { continue; };
-
-					node_context.p2p.broadcast_sync_events(id, vec![op]).await;
+				let batch_config = node_context.p2p.batch_config().clone();
+				let (queue_tx, mut queue_rx) = node_context.p2p.sync_queue(id).await;
+
+				// Batching is done separately to sending so a slow network fills the queue, which applies the backpressure policy
+				tokio::spawn(async move {
+					while let Some(operations) = next_batch(&mut sync_rx, &batch_config).await {
+						if !queue_tx.push(operations).await {
+							break;
+						}
+					}
+				});
+
+				while let Some(operations) = queue_rx.recv().await {
+					node_context
+						.p2p
+						.broadcast_sync_events(id, operations, None)
+						.await;
 				}
 			}
 		});
@@ -1,9 +1,11 @@
 use rspc::Type;
-use sd_p2p::Keypair;
+use sd_p2p::{Keypair, Multiaddr, PeerId, Transport};
 use serde::{Deserialize, Serialize};
 use std::{
+	collections::{HashMap, HashSet},
 	fs::File,
 	io::{self, BufReader, Seek, Write},
+	net::{IpAddr, SocketAddr},
 	path::{Path, PathBuf},
 	sync::Arc,
 };
@@ -11,6 +13,8 @@ use thiserror::Error;
 use tokio::sync::{RwLock, RwLockWriteGuard};
 use uuid::Uuid;
 
+use crate::p2p::{Compression, DialPolicy, KnownPeer, StreamKey};
+
 /// NODE_STATE_CONFIG_NAME is the name of the file which stores the NodeState
 pub const NODE_STATE_CONFIG_NAME: &str = "node_state.sdconfig";
 
@@ -39,7 +43,8 @@ pub struct NodeConfig {
 	pub id: Uuid,
 	/// name is the display name of the current node. This is set by the user and is shown in the UI. // TODO: Length validation so it can fit in DNS record
 	pub name: String,
-	// the port this node uses for peer to peer communication. By default a random free port will be chosen each time the application is started.
+	// the port this node uses for peer to peer communication. By default (or if set to `0`) a random free port will be chosen each time the application is started.
+	// Changing this requires the P2P subsystem to be restarted.
 	pub p2p_port: Option<u32>,
 	/// The p2p identity keypair for this node. This is used to identify the node on the network.
 	#[serde(default = "default_keypair")]
@@ -48,6 +53,52 @@ pub struct NodeConfig {
 	// TODO: These will probs be replaced by your Spacedrive account in the near future.
 	pub p2p_email: Option<String>,
 	pub p2p_img_url: Option<String>,
+	/// which discovered peers should be automatically connected to.
+	#[serde(default)]
+	pub p2p_dial_policy: DialPolicy,
+	/// the peers which the user has paired with this node by confirming a pairing code.
+	#[serde(default)]
+	pub p2p_paired_peers: HashSet<PeerId>,
+	/// the keys established with paired peers during pairing which unicast streams with them are encrypted with.
+	#[serde(default)]
+	#[specta(skip)]
+	pub p2p_stream_keys: HashMap<PeerId, StreamKey>,
+	/// is this node discoverable and discovering other nodes on the local network using mDNS.
+	#[serde(default = "default_discovery_enabled")]
+	pub p2p_discovery_enabled: bool,
+	/// peers which were added by address for networks where mDNS doesn't work. These are dialed on startup.
+	#[serde(default)]
+	pub p2p_manual_peers: Vec<SocketAddr>,
+	/// the name of the network this node is a part of. Only nodes with the same network name can discover and connect to each other. Changing this requires a restart.
+	#[serde(default)]
+	pub p2p_network_name: Option<String>,
+	/// the peers which the user has blocked. Connections with these are refused.
+	#[serde(default)]
+	pub p2p_blocked_peers: HashSet<PeerId>,
+	/// the addresses of the network interfaces to listen on for peer to peer communication. If empty every interface is used.
+	/// Changing this requires the P2P subsystem to be restarted.
+	#[serde(default)]
+	pub p2p_listen_addrs: Vec<IpAddr>,
+	/// the address the user has pinned for each peer. It's always dialed before any of the peer's other addresses.
+	#[serde(default)]
+	pub p2p_pinned_addresses: HashMap<PeerId, SocketAddr>,
+	/// the transports to connect to peers with in order of preference. TCP is always used as the last fallback even if it's not included.
+	/// Changing this requires the P2P subsystem to be restarted.
+	#[serde(default = "default_transports")]
+	pub p2p_transports: Vec<Transport>,
+	/// the circuit relays used to connect with peers on other networks, see [sd_p2p::ManagerConfig::relays]. Relaying is disabled if this is empty.
+	/// Changing this requires the P2P subsystem to be restarted.
+	#[serde(default)]
+	#[specta(type = Vec<String>)]
+	pub p2p_relays: Vec<Multiaddr>,
+	/// the peers which are reconnected to on startup along with their last-known addresses, pairing status and nickname.
+	/// Peers stay in this list when they can't be reached so they are retried on the next startup.
+	#[serde(default)]
+	pub p2p_known_peers: HashMap<PeerId, KnownPeer>,
+	/// how payloads sent to other peers are compressed. Changing this requires the P2P subsystem to be restarted.
+	#[serde(default)]
+	#[specta(skip)]
+	pub p2p_compression: Compression,
 }
 
 // TODO: Probs remove this in future. It's just to prevent breaking changes.
@@ -55,6 +106,14 @@ fn default_keypair() -> Keypair {
 	Keypair::generate()
 }
 
+fn default_discovery_enabled() -> bool {
+	true
+}
+
+fn default_transports() -> Vec<Transport> {
+	vec![Transport::Quic, Transport::Tcp]
+}
+
 #[derive(Error, Debug)]
 pub enum NodeConfigError {
 	#[error("error saving or loading the config from the filesystem")]
@@ -84,6 +143,19 @@ impl NodeConfig {
 			keypair: Keypair::generate(),
 			p2p_email: None,
 			p2p_img_url: None,
+			p2p_dial_policy: DialPolicy::default(),
+			p2p_paired_peers: HashSet::new(),
+			p2p_stream_keys: HashMap::new(),
+			p2p_discovery_enabled: true,
+			p2p_manual_peers: Vec::new(),
+			p2p_network_name: None,
+			p2p_blocked_peers: HashSet::new(),
+			p2p_listen_addrs: Vec::new(),
+			p2p_pinned_addresses: HashMap::new(),
+			p2p_transports: default_transports(),
+			p2p_relays: Vec::new(),
+			p2p_known_peers: HashMap::new(),
+			p2p_compression: Compression::default(),
 		}
 	}
 }
@@ -110,7 +182,6 @@ impl NodeConfigManager {
 	}
 
 	/// write allows the user to update the configuration. This is done in a closure while a Mutex lock is held so that the user can't cause a race condition if the config were to be updated in multiple parts of the app at the same time.
-	#[allow(unused)]
 	pub(crate) async fn write<F: FnOnce(RwLockWriteGuard<NodeConfig>)>(
 		&self,
 		mutation_fn: F,
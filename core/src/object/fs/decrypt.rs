@@ -1,8 +1,8 @@
-use sd_crypto::{crypto::Decryptor, header::file::FileHeader, Protected};
+use sd_crypto::{header::file::FileHeader, Protected};
 use serde::{Deserialize, Serialize};
 use specta::Type;
 use std::{collections::VecDeque, path::PathBuf};
-use tokio::fs::File;
+use tokio::fs::{self, File};
 
 use crate::job::{JobError, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext};
 
@@ -81,7 +81,6 @@ impl StatefulJob for FileDecryptorJob {
 		);
 
 		let mut reader = File::open(info.fs_path.clone()).await?;
-		let mut writer = File::create(output_path).await?;
 
 		let (header, aad) = FileHeader::from_reader(&mut reader).await?;
 
@@ -129,11 +128,17 @@ impl StatefulJob for FileDecryptorJob {
 			header.decrypt_master_key_from_prehashed(keys).await?
 		};
 
-		let decryptor = Decryptor::new(master_key, header.nonce, header.algorithm)?;
-
-		decryptor
-			.decrypt_streams(&mut reader, &mut writer, &aad)
-			.await?;
+		let mut writer = File::create(&output_path).await?;
+
+		if let Err(e) = header
+			.decrypt(master_key, &mut reader, &mut writer, &aad)
+			.await
+		{
+			// don't leave a partially decrypted file behind
+			drop(writer);
+			fs::remove_file(&output_path).await.ok();
+			return Err(e.into());
+		}
 
 		// need to decrypt preview media/metadata, and maybe add an option in the UI so the user can chosoe to restore these values
 		// for now this can't easily be implemented, as we don't know what the new object id for the file will be (we know the old one, but it may differ)
@@ -4,7 +4,6 @@ use std::path::PathBuf;
 
 use chrono::FixedOffset;
 use sd_crypto::{
-	crypto::Encryptor,
 	header::{file::FileHeader, keyslot::Keyslot},
 	primitives::{LATEST_FILE_HEADER, LATEST_KEYSLOT, LATEST_METADATA, LATEST_PREVIEW_MEDIA},
 	types::{Algorithm, Key},
@@ -25,11 +24,14 @@ pub struct FileEncryptorJobState {}
 pub struct FileEncryptorJobInit {
 	pub location_id: i32,
 	pub path_id: i32,
-	pub key_uuid: uuid::Uuid,
+	/// the default key is used if this isn't provided
+	pub key_uuid: Option<uuid::Uuid>,
 	pub algorithm: Algorithm,
 	pub metadata: bool,
 	pub preview_media: bool,
 	pub output_path: Option<PathBuf>,
+	/// the file is encrypted in parallel segments of this many bytes if this is provided
+	pub segment_size: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -82,12 +84,14 @@ impl StatefulJob for FileEncryptorJob {
 		if !info.path_data.is_dir {
 			// handle overwriting checks, and making sure there's enough available space
 
-			let user_key = key_manager
-				.access_keymount(state.init.key_uuid)
-				.await?
-				.hashed_key;
+			let key_uuid = match state.init.key_uuid {
+				Some(key_uuid) => key_uuid,
+				None => key_manager.get_default().await?,
+			};
 
-			let user_key_details = key_manager.access_keystore(state.init.key_uuid).await?;
+			let user_key = key_manager.access_keymount(key_uuid).await?.hashed_key;
+
+			let user_key_details = key_manager.access_keystore(key_uuid).await?;
 
 			let output_path = state.init.output_path.clone().map_or_else(
 				|| {
@@ -144,6 +148,7 @@ impl StatefulJob for FileEncryptorJob {
 					.await?,
 				],
 			)?;
+			header.set_segment_size(state.init.segment_size)?;
 
 			if state.init.metadata || state.init.preview_media {
 				// if any are requested, we can make the query as it'll be used at least once
@@ -203,11 +208,8 @@ impl StatefulJob for FileEncryptorJob {
 			}
 
 			header.write(&mut writer).await?;
-
-			let encryptor = Encryptor::new(master_key, header.nonce, header.algorithm)?;
-
-			encryptor
-				.encrypt_streams(&mut reader, &mut writer, &header.generate_aad())
+			header
+				.encrypt_detached(master_key, &mut reader, &mut writer)
 				.await?;
 		} else {
 			warn!(
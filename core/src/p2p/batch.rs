@@ -0,0 +1,154 @@
+use std::time::Duration;
+
+use sd_sync::CRDTOperation;
+use tokio::{
+	sync::broadcast::{self, error::RecvError},
+	time::{timeout_at, Instant},
+};
+use tracing::warn;
+
+use crate::sync::SyncMessage;
+
+/// Controls how the sync operations created in a library are batched before they are broadcast to other peers.
+/// Batching means a bulk operation (Eg. tagging thousands of files) is sent as a few large messages instead of thousands of tiny ones.
+#[derive(Debug, Clone)]
+pub struct BatchConfig {
+	/// the maximum amount of time to wait for more operations after the first operation of a batch
+	pub window: Duration,
+	/// the batch is sent early if no more operations arrive within this long
+	pub idle_timeout: Duration,
+	/// the maximum number of operations in a batch. This bounds the size of a single message.
+	pub max_batch_size: usize,
+}
+
+impl Default for BatchConfig {
+	fn default() -> Self {
+		Self {
+			window: Duration::from_millis(50),
+			idle_timeout: Duration::from_millis(10),
+			max_batch_size: 1000,
+		}
+	}
+}
+
+/// next_batch will wait for the next operations created in the library and return them once the batch is full, the window has elapsed or no more operations are arriving.
+/// Operations are returned in the order they were created. This returns `None` once the channel is closed and all operations have been returned.
+pub async fn next_batch(
+	rx: &mut broadcast::Receiver<SyncMessage>,
+	config: &BatchConfig,
+) -> Option<Vec<CRDTOperation>> {
+	let mut batch = Vec::new();
+	let mut deadline = None;
+
+	loop {
+		let msg = match deadline {
+			None => rx.recv().await,
+			Some(deadline) => {
+				let idle_deadline = Instant::now() + config.idle_timeout;
+				match timeout_at(idle_deadline.min(deadline), rx.recv()).await {
+					Ok(msg) => msg,
+					Err(_) => return Some(batch),
+				}
+			}
+		};
+
+		match msg {
+			Ok(SyncMessage::Created(op)) => {
+				batch.push(op);
+				if batch.len() >= config.max_batch_size {
+					return Some(batch);
+				}
+
+				deadline.get_or_insert_with(|| Instant::now() + config.window);
+			}
+			// Operations ingested from other peers don't need to be broadcast again
+			Ok(SyncMessage::Ingested(_)) => {}
+			Err(RecvError::Lagged(count)) => {
+				warn!("Sync operation batching fell behind and skipped '{count}' operations!");
+			}
+			Err(RecvError::Closed) => return (!batch.is_empty()).then_some(batch),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use sd_sync::{CRDTOperationType, OwnedOperation};
+	use uhlc::NTP64;
+	use uuid::Uuid;
+
+	use super::*;
+
+	fn operation() -> CRDTOperation {
+		CRDTOperation {
+			node: Uuid::new_v4(),
+			timestamp: NTP64(1),
+			id: Uuid::new_v4(),
+			typ: CRDTOperationType::Owned(OwnedOperation {
+				model: "location".to_owned(),
+				items: Vec::new(),
+			}),
+		}
+	}
+
+	#[tokio::test]
+	async fn test_batch_preserves_order() {
+		let (tx, mut rx) = broadcast::channel(64);
+		let ids = (0..10)
+			.map(|_| {
+				let op = operation();
+				let id = op.id;
+				tx.send(SyncMessage::Created(op)).unwrap();
+				id
+			})
+			.collect::<Vec<_>>();
+
+		let batch = next_batch(&mut rx, &BatchConfig::default()).await.unwrap();
+		assert_eq!(batch.into_iter().map(|op| op.id).collect::<Vec<_>>(), ids);
+	}
+
+	#[tokio::test]
+	async fn test_batch_max_size() {
+		let (tx, mut rx) = broadcast::channel(64);
+		for _ in 0..5 {
+			tx.send(SyncMessage::Created(operation())).unwrap();
+		}
+
+		let config = BatchConfig {
+			max_batch_size: 2,
+			..Default::default()
+		};
+		assert_eq!(next_batch(&mut rx, &config).await.unwrap().len(), 2);
+		assert_eq!(next_batch(&mut rx, &config).await.unwrap().len(), 2);
+		assert_eq!(next_batch(&mut rx, &config).await.unwrap().len(), 1);
+	}
+
+	#[tokio::test]
+	async fn test_batch_flushed_when_idle() {
+		let (tx, mut rx) = broadcast::channel(64);
+		tx.send(SyncMessage::Created(operation())).unwrap();
+
+		// The sender is kept open so the batch must be flushed by the idle timeout
+		let config = BatchConfig {
+			window: Duration::from_secs(60),
+			..Default::default()
+		};
+		let batch = tokio::time::timeout(Duration::from_secs(5), next_batch(&mut rx, &config))
+			.await
+			.unwrap();
+		assert_eq!(batch.unwrap().len(), 1);
+		drop(tx);
+	}
+
+	#[tokio::test]
+	async fn test_batch_closed() {
+		let (tx, mut rx) = broadcast::channel(64);
+		tx.send(SyncMessage::Ingested(operation())).unwrap();
+		tx.send(SyncMessage::Created(operation())).unwrap();
+		drop(tx);
+
+		let config = BatchConfig::default();
+		assert_eq!(next_batch(&mut rx, &config).await.unwrap().len(), 1);
+		assert!(next_batch(&mut rx, &config).await.is_none());
+	}
+}
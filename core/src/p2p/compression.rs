@@ -0,0 +1,152 @@
+use serde::{Deserialize, Serialize};
+
+use super::MessageError;
+
+/// the payload is sent as is
+const FLAG_UNCOMPRESSED: u8 = 0;
+/// the payload is compressed with zstd
+const FLAG_ZSTD: u8 = 1;
+
+/// Controls how payloads sent to other peers are compressed. This is configured with [crate::node::NodeConfig::p2p_compression].
+/// Compression is only done when the Core is built with the `compress` feature but compressed payloads from other peers can only be decoded with it enabled as well.
+/// Peers advertise whether they can decode compressed payloads (see [super::PeerMetadata]) and those which can't are sent them uncompressed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Compression {
+	/// the zstd compression level
+	pub level: i32,
+	/// payloads smaller than this many bytes are sent uncompressed as compressing them wouldn't help
+	pub threshold: usize,
+}
+
+impl Default for Compression {
+	fn default() -> Self {
+		Self {
+			level: 3,
+			threshold: 512,
+		}
+	}
+}
+
+impl Compression {
+	/// uncompressed never compresses payloads. This is used for peers which can't decode compressed payloads.
+	pub fn uncompressed() -> Self {
+		Self {
+			threshold: usize::MAX,
+			..Default::default()
+		}
+	}
+
+	/// encode_payload prefixes the payload with a flag byte, compressing it if it's worth it.
+	pub fn encode_payload(&self, payload: Vec<u8>) -> Result<Vec<u8>, MessageError> {
+		#[cfg(feature = "compress")]
+		if payload.len() >= self.threshold {
+			let mut buf = vec![FLAG_ZSTD];
+			buf.extend(zstd::bulk::compress(&payload, self.level)?);
+			return Ok(buf);
+		}
+
+		let mut buf = Vec::with_capacity(payload.len() + 1);
+		buf.push(FLAG_UNCOMPRESSED);
+		buf.extend(payload);
+		Ok(buf)
+	}
+}
+
+/// decode_payload reverses `Compression::encode_payload`. The decompressed payload can be at most `max_size` bytes.
+pub fn decode_payload(mut buf: Vec<u8>, max_size: usize) -> Result<Vec<u8>, MessageError> {
+	if buf.is_empty() {
+		return Err(MessageError::InvalidPayload);
+	}
+
+	match buf[0] {
+		FLAG_UNCOMPRESSED => {
+			buf.remove(0);
+			Ok(buf)
+		}
+		#[cfg(feature = "compress")]
+		FLAG_ZSTD => {
+			use std::io::Read;
+
+			// The payload is decompressed as a stream as `zstd::bulk::decompress` allocates `max_size` bytes upfront
+			let mut payload = Vec::new();
+			zstd::stream::read::Decoder::new(&buf[1..])?
+				.take((max_size as u64).saturating_add(1))
+				.read_to_end(&mut payload)?;
+			if payload.len() > max_size {
+				return Err(MessageError::TooLarge {
+					len: payload.len(),
+					max: max_size,
+				});
+			}
+			Ok(payload)
+		}
+		#[cfg(not(feature = "compress"))]
+		FLAG_ZSTD => {
+			let _ = max_size;
+			Err(MessageError::CompressionUnsupported)
+		}
+		_ => Err(MessageError::InvalidPayload),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_payload_roundtrip() {
+		let compression = Compression::default();
+
+		for payload in [vec![], vec![1, 2, 3], vec![42; 4096]] {
+			let encoded = compression.encode_payload(payload.clone()).unwrap();
+			assert_eq!(decode_payload(encoded, usize::MAX).unwrap(), payload);
+		}
+	}
+
+	#[test]
+	fn test_uncompressed() {
+		let encoded = Compression::uncompressed()
+			.encode_payload(vec![42; 4096])
+			.unwrap();
+		assert_eq!(encoded[0], FLAG_UNCOMPRESSED);
+		assert_eq!(decode_payload(encoded, usize::MAX).unwrap(), vec![42; 4096]);
+	}
+
+	#[test]
+	fn test_small_payload_not_compressed() {
+		let encoded = Compression::default()
+			.encode_payload(vec![1, 2, 3])
+			.unwrap();
+		assert_eq!(encoded, vec![FLAG_UNCOMPRESSED, 1, 2, 3]);
+	}
+
+	#[cfg(feature = "compress")]
+	#[test]
+	fn test_large_payload_compressed() {
+		let encoded = Compression::default()
+			.encode_payload(vec![42; 4096])
+			.unwrap();
+		assert_eq!(encoded[0], FLAG_ZSTD);
+		assert!(encoded.len() < 4096);
+	}
+
+	#[cfg(feature = "compress")]
+	#[test]
+	fn test_compressed_payload_too_large() {
+		let encoded = Compression::default()
+			.encode_payload(vec![42; 4096])
+			.unwrap();
+		assert!(matches!(
+			decode_payload(encoded.clone(), 4095),
+			Err(MessageError::TooLarge { max: 4095, .. })
+		));
+		assert_eq!(decode_payload(encoded, 4096).unwrap(), vec![42; 4096]);
+	}
+
+	#[test]
+	fn test_invalid_payload() {
+		assert!(decode_payload(vec![], usize::MAX).is_err());
+		assert!(decode_payload(vec![255, 1, 2], usize::MAX).is_err());
+	}
+}
@@ -0,0 +1,156 @@
+use std::{
+	collections::HashMap,
+	time::{Duration, Instant},
+};
+
+use sd_p2p::PeerId;
+
+/// Controls how peers which are repeatedly announced over mDNS are handled.
+/// mDNS re-announces every peer periodically so without this a peer would be dialed every time it's announced.
+#[derive(Debug, Clone)]
+pub struct DiscoveryConfig {
+	/// a peer won't be dialed again within this long of the last dial so the connection has time to be established
+	pub dedup_window: Duration,
+	/// a peer which hasn't been announced for this long is forgotten so its next announcement is treated as a new discovery
+	pub last_seen_expiry: Duration,
+}
+
+impl Default for DiscoveryConfig {
+	fn default() -> Self {
+		Self {
+			dedup_window: Duration::from_secs(30),
+			last_seen_expiry: Duration::from_secs(5 * 60),
+		}
+	}
+}
+
+struct SeenPeer {
+	last_seen: Instant,
+	last_dialed: Option<Instant>,
+}
+
+/// Keeps track of when each discovered peer was last announced and dialed.
+#[derive(Default)]
+pub struct DiscoveredPeers(HashMap<PeerId, SeenPeer>);
+
+impl DiscoveredPeers {
+	/// seen records that the peer was announced. This returns `true` if the peer is new or was forgotten since it was last announced.
+	pub fn seen(&mut self, peer_id: PeerId, config: &DiscoveryConfig, now: Instant) -> bool {
+		self.0
+			.retain(|_, peer| now.duration_since(peer.last_seen) < config.last_seen_expiry);
+
+		match self.0.get_mut(&peer_id) {
+			Some(peer) => {
+				peer.last_seen = now;
+				false
+			}
+			None => {
+				self.0.insert(
+					peer_id,
+					SeenPeer {
+						last_seen: now,
+						last_dialed: None,
+					},
+				);
+				true
+			}
+		}
+	}
+
+	/// try_dial returns whether the peer should be dialed and if so records the dial.
+	/// Peers which are already connected or were dialed within the `dedup_window` are skipped.
+	pub fn try_dial(
+		&mut self,
+		peer_id: PeerId,
+		is_connected: bool,
+		config: &DiscoveryConfig,
+		now: Instant,
+	) -> bool {
+		if is_connected {
+			return false;
+		}
+
+		let peer = self.0.entry(peer_id).or_insert(SeenPeer {
+			last_seen: now,
+			last_dialed: None,
+		});
+
+		if peer.last_dialed.map_or(false, |dialed| {
+			now.duration_since(dialed) < config.dedup_window
+		}) {
+			return false;
+		}
+
+		peer.last_dialed = Some(now);
+		true
+	}
+
+	/// disconnected allows the peer to be dialed straight away the next time it's announced.
+	pub fn disconnected(&mut self, peer_id: &PeerId) {
+		if let Some(peer) = self.0.get_mut(peer_id) {
+			peer.last_dialed = None;
+		}
+	}
+
+	pub fn remove(&mut self, peer_id: &PeerId) {
+		self.0.remove(peer_id);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::str::FromStr;
+
+	use super::*;
+
+	fn peer_id() -> PeerId {
+		PeerId::from_str("12D3KooW9tHTtS3inCZiYykw4u5G4frbjVFqhkmJX12gSNCVeH3e").unwrap()
+	}
+
+	fn config() -> DiscoveryConfig {
+		DiscoveryConfig {
+			dedup_window: Duration::from_secs(10),
+			last_seen_expiry: Duration::from_secs(60),
+		}
+	}
+
+	#[test]
+	fn test_dial_dedup() {
+		let (config, peer, start) = (config(), peer_id(), Instant::now());
+		let mut peers = DiscoveredPeers::default();
+
+		assert!(peers.seen(peer, &config, start));
+		assert!(peers.try_dial(peer, false, &config, start));
+
+		// Re-announced while the dial is in progress
+		let now = start + Duration::from_secs(5);
+		assert!(!peers.seen(peer, &config, now));
+		assert!(!peers.try_dial(peer, false, &config, now));
+
+		// Re-announced once the connection is established
+		let now = start + Duration::from_secs(20);
+		assert!(!peers.try_dial(peer, true, &config, now));
+
+		// The dial failed so it's retried once the window has passed
+		assert!(peers.try_dial(peer, false, &config, now));
+
+		peers.disconnected(&peer);
+		assert!(peers.try_dial(peer, false, &config, now));
+	}
+
+	#[test]
+	fn test_last_seen_expiry() {
+		let (config, peer, start) = (config(), peer_id(), Instant::now());
+		let mut peers = DiscoveredPeers::default();
+
+		assert!(peers.seen(peer, &config, start));
+		assert!(!peers.seen(peer, &config, start + Duration::from_secs(50)));
+
+		// Each announcement refreshes the last seen time
+		assert!(!peers.seen(peer, &config, start + Duration::from_secs(100)));
+		assert!(peers.seen(peer, &config, start + Duration::from_secs(200)));
+
+		peers.remove(&peer);
+		assert!(peers.seen(peer, &config, start + Duration::from_secs(200)));
+	}
+}
@@ -0,0 +1,487 @@
+use std::{
+	fmt, io,
+	pin::Pin,
+	task::{ready, Context, Poll},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sd_crypto::{
+	crypto::{Decryptor, Encryptor},
+	primitives::{to_array, AEAD_TAG_LEN, BLOCK_LEN},
+	types::{Algorithm, Key, Nonce},
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// the algorithm used to encrypt streams between paired peers.
+const ALGORITHM: Algorithm = Algorithm::XChaCha20Poly1305;
+
+/// the size of a frame header. This is a 1 byte frame kind followed by the u32 little endian length of the ciphertext.
+const FRAME_HEADER_LEN: usize = 5;
+
+/// the largest ciphertext a frame can hold. Anything larger is rejected without being read.
+const MAX_FRAME_LEN: usize = BLOCK_LEN + AEAD_TAG_LEN;
+
+/// the context the initiator derives the subkey it encrypts with from. See [EncryptedStream::new].
+const INITIATOR_SUBKEY_CONTEXT: &str =
+	"spacedrive 2023-04-05 16:32:08 encrypted stream initiator subkey";
+
+/// the context the responder derives the subkey it encrypts with from. See [EncryptedStream::new].
+const RESPONDER_SUBKEY_CONTEXT: &str =
+	"spacedrive 2023-04-05 16:32:19 encrypted stream responder subkey";
+
+const FRAME_NEXT: u8 = 0;
+const FRAME_LAST: u8 = 1;
+
+/// StreamKey is the secret established with a peer during pairing which the unicast streams between the two nodes are encrypted with.
+/// It's stored in the node config as base64.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct StreamKey(Key);
+
+impl StreamKey {
+	pub fn new(key: Key) -> Self {
+		Self(key)
+	}
+
+	/// subkey derives the key a single direction of a stream is encrypted with from both of the stream's nonces.
+	fn subkey(&self, context: &str, initiator_nonce: &[u8], responder_nonce: &[u8]) -> Key {
+		let mut hasher = blake3::Hasher::new_derive_key(context);
+		hasher.update(self.0.expose());
+		hasher.update(initiator_nonce);
+		hasher.update(responder_nonce);
+		Key::new(*hasher.finalize().as_bytes())
+	}
+}
+
+impl fmt::Debug for StreamKey {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("StreamKey([REDACTED])")
+	}
+}
+
+impl From<StreamKey> for String {
+	fn from(key: StreamKey) -> Self {
+		STANDARD.encode(key.0.expose())
+	}
+}
+
+impl TryFrom<String> for StreamKey {
+	type Error = &'static str;
+
+	fn try_from(value: String) -> Result<Self, Self::Error> {
+		let bytes = STANDARD
+			.decode(value)
+			.map_err(|_| "invalid stream key encoding")?;
+		Ok(Self(Key::new(
+			to_array(&bytes).map_err(|_| "invalid stream key length")?,
+		)))
+	}
+}
+
+#[derive(Debug, Error)]
+pub enum EncryptionError {
+	#[error("io error: {0}")]
+	Io(#[from] io::Error),
+	#[error("crypto error: {0}")]
+	Crypto(#[from] sd_crypto::Error),
+	#[error("the peer reused our nonce")]
+	ReusedNonce,
+}
+
+/// EncryptedStream wraps a unicast stream so everything written to it is encrypted with the STREAM construction from `sd_crypto`.
+///
+/// Each direction has its own random nonce which is exchanged when the stream is opened.
+/// Both nonces are mixed into a subkey for each direction so frames can't be replayed into another stream or reflected back to their sender.
+/// Data is sent as frames of a 1 byte kind, a u32 little endian length and then the ciphertext of a single STREAM block.
+/// A block is sealed whenever the stream is flushed or `BLOCK_LEN` bytes have been buffered and the final block is sent on shutdown so truncation can be detected.
+pub struct EncryptedStream<S> {
+	stream: S,
+	/// `None` once the final block has been written.
+	encryptor: Option<Encryptor>,
+	/// `None` once the final block has been read.
+	decryptor: Option<Decryptor>,
+	/// plaintext which hasn't been sealed into a block yet.
+	write_buf: Vec<u8>,
+	/// encrypted frames which haven't been written to the stream yet.
+	out_buf: Vec<u8>,
+	out_pos: usize,
+	/// the frame which is currently being read.
+	in_buf: Vec<u8>,
+	in_filled: usize,
+	in_len: Option<usize>,
+	/// decrypted data which hasn't been read yet.
+	read_buf: Vec<u8>,
+	read_pos: usize,
+	corrupted: bool,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> EncryptedStream<S> {
+	/// new will exchange nonces with the peer and return the stream once both sides are ready.
+	/// Both peers must call this with the same key or the first read will fail.
+	/// `initiator` must be set by the peer which opened the stream and unset by the other so each side decrypts with the subkey the other encrypts with.
+	pub async fn new(
+		mut stream: S,
+		key: &StreamKey,
+		initiator: bool,
+	) -> Result<Self, EncryptionError> {
+		let nonce = Nonce::generate(ALGORITHM)?;
+		stream.write_u8(nonce.len() as u8).await?;
+		stream.write_all(&nonce).await?;
+		stream.flush().await?;
+
+		let len = stream.read_u8().await? as usize;
+		if len != ALGORITHM.nonce_len() {
+			return Err(sd_crypto::Error::NonceLengthMismatch.into());
+		}
+		let mut remote_nonce = vec![0; len];
+		stream.read_exact(&mut remote_nonce).await?;
+		let remote_nonce = Nonce::try_from(remote_nonce)?;
+
+		// Our nonce being sent back to us would cause the keystream to be reused between both directions
+		if remote_nonce == nonce {
+			return Err(EncryptionError::ReusedNonce);
+		}
+
+		let (initiator_nonce, responder_nonce) = if initiator {
+			(&nonce, &remote_nonce)
+		} else {
+			(&remote_nonce, &nonce)
+		};
+		let initiator_key = key.subkey(INITIATOR_SUBKEY_CONTEXT, initiator_nonce, responder_nonce);
+		let responder_key = key.subkey(RESPONDER_SUBKEY_CONTEXT, initiator_nonce, responder_nonce);
+		let (encrypt_key, decrypt_key) = if initiator {
+			(initiator_key, responder_key)
+		} else {
+			(responder_key, initiator_key)
+		};
+
+		Ok(Self {
+			stream,
+			encryptor: Some(Encryptor::new(encrypt_key, nonce, ALGORITHM)?),
+			decryptor: Some(Decryptor::new(decrypt_key, remote_nonce, ALGORITHM)?),
+			write_buf: Vec::new(),
+			out_buf: Vec::new(),
+			out_pos: 0,
+			in_buf: Vec::new(),
+			in_filled: 0,
+			in_len: None,
+			read_buf: Vec::new(),
+			read_pos: 0,
+			corrupted: false,
+		})
+	}
+
+	/// is_corrupted returns true if data from the peer failed to decrypt. The connection with the peer should be closed when this happens.
+	pub fn is_corrupted(&self) -> bool {
+		self.corrupted
+	}
+
+	/// seal encrypts the buffered plaintext into a frame.
+	fn seal(&mut self, last: bool) -> io::Result<()> {
+		let chunk = if last {
+			self.encryptor
+				.take()
+				.ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?
+				.encrypt_last(self.write_buf.as_slice())
+		} else {
+			self.encryptor
+				.as_mut()
+				.ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?
+				.encrypt_next(self.write_buf.as_slice())
+		}
+		.map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+		self.write_buf.clear();
+
+		let len = u32::try_from(chunk.len())
+			.map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+		self.out_buf
+			.push(if last { FRAME_LAST } else { FRAME_NEXT });
+		self.out_buf.extend_from_slice(&len.to_le_bytes());
+		self.out_buf.extend_from_slice(&chunk);
+		Ok(())
+	}
+
+	/// open decrypts the frame which has been read into `in_buf`.
+	fn open(&mut self) -> io::Result<()> {
+		let ciphertext = &self.in_buf[FRAME_HEADER_LEN..self.in_filled];
+		let result = match self.in_buf[0] {
+			FRAME_NEXT => match self.decryptor.as_mut() {
+				Some(decryptor) => decryptor.decrypt_next(ciphertext),
+				None => Err(sd_crypto::Error::Decrypt),
+			},
+			FRAME_LAST => match self.decryptor.take() {
+				Some(decryptor) => decryptor.decrypt_last(ciphertext),
+				None => Err(sd_crypto::Error::Decrypt),
+			},
+			_ => Err(sd_crypto::Error::Decrypt),
+		};
+
+		self.in_filled = 0;
+		self.in_len = None;
+
+		match result {
+			Ok(plaintext) => {
+				self.read_buf = plaintext;
+				self.read_pos = 0;
+				Ok(())
+			}
+			Err(err) => {
+				self.corrupted = true;
+				self.decryptor = None;
+				Err(io::Error::new(io::ErrorKind::InvalidData, err))
+			}
+		}
+	}
+
+	/// poll_write_out writes the encrypted frames in `out_buf` to the stream.
+	fn poll_write_out(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		while self.out_pos < self.out_buf.len() {
+			let n =
+				ready!(Pin::new(&mut self.stream).poll_write(cx, &self.out_buf[self.out_pos..]))?;
+			if n == 0 {
+				return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+			}
+			self.out_pos += n;
+		}
+
+		self.out_buf.clear();
+		self.out_pos = 0;
+		Poll::Ready(Ok(()))
+	}
+
+	/// poll_flush_buf seals any buffered plaintext and writes it to the stream.
+	fn poll_flush_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		loop {
+			ready!(self.poll_write_out(cx))?;
+			if self.write_buf.is_empty() {
+				return Poll::Ready(Ok(()));
+			}
+			self.seal(false)?;
+		}
+	}
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for EncryptedStream<S> {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		let this = self.get_mut();
+
+		loop {
+			if this.read_pos < this.read_buf.len() {
+				let len = buf.remaining().min(this.read_buf.len() - this.read_pos);
+				buf.put_slice(&this.read_buf[this.read_pos..this.read_pos + len]);
+				this.read_pos += len;
+				return Poll::Ready(Ok(()));
+			}
+
+			// The final block has been read so this is EOF
+			if this.decryptor.is_none() {
+				if this.corrupted {
+					return Poll::Ready(Err(io::ErrorKind::InvalidData.into()));
+				}
+				return Poll::Ready(Ok(()));
+			}
+
+			let needed = FRAME_HEADER_LEN + this.in_len.unwrap_or(0);
+			if this.in_buf.len() < needed {
+				this.in_buf.resize(needed, 0);
+			}
+
+			while this.in_filled < needed {
+				let mut in_buf = ReadBuf::new(&mut this.in_buf[this.in_filled..needed]);
+				ready!(Pin::new(&mut this.stream).poll_read(cx, &mut in_buf))?;
+				let n = in_buf.filled().len();
+				if n == 0 {
+					return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+				}
+				this.in_filled += n;
+			}
+
+			if this.in_len.is_none() {
+				let len = u32::from_le_bytes([
+					this.in_buf[1],
+					this.in_buf[2],
+					this.in_buf[3],
+					this.in_buf[4],
+				]) as usize;
+				if len > MAX_FRAME_LEN {
+					this.corrupted = true;
+					this.decryptor = None;
+					return Poll::Ready(Err(io::Error::new(
+						io::ErrorKind::InvalidData,
+						"encrypted frame too large",
+					)));
+				}
+
+				this.in_len = Some(len);
+				continue;
+			}
+
+			this.open()?;
+		}
+	}
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for EncryptedStream<S> {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		let this = self.get_mut();
+		if this.encryptor.is_none() {
+			return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+		}
+
+		if this.write_buf.len() >= BLOCK_LEN {
+			ready!(this.poll_flush_buf(cx))?;
+		}
+
+		let len = buf.len().min(BLOCK_LEN - this.write_buf.len());
+		this.write_buf.extend_from_slice(&buf[..len]);
+		Poll::Ready(Ok(len))
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		let this = self.get_mut();
+		ready!(this.poll_flush_buf(cx))?;
+		Pin::new(&mut this.stream).poll_flush(cx)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		let this = self.get_mut();
+		if this.encryptor.is_some() {
+			ready!(this.poll_flush_buf(cx))?;
+			this.seal(true)?;
+		}
+
+		ready!(this.poll_write_out(cx))?;
+		Pin::new(&mut this.stream).poll_shutdown(cx)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::io::duplex;
+
+	use super::*;
+
+	#[tokio::test]
+	async fn test_encrypted_stream() {
+		let key = StreamKey::new(Key::generate());
+		let (a, b) = duplex(64);
+
+		let (a, b) = tokio::join!(
+			EncryptedStream::new(a, &key, true),
+			EncryptedStream::new(b, &key, false)
+		);
+		let (mut a, mut b) = (a.unwrap(), b.unwrap());
+
+		let data = (0..10 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+		let (written, read) = tokio::join!(
+			async {
+				a.write_all(&data).await?;
+				a.flush().await?;
+				a.write_all(b"more").await?;
+				a.shutdown().await
+			},
+			async {
+				let mut buf = Vec::new();
+				b.read_to_end(&mut buf).await.map(|_| buf)
+			}
+		);
+		written.unwrap();
+
+		let mut expected = data;
+		expected.extend_from_slice(b"more");
+		assert_eq!(read.unwrap(), expected);
+		assert!(!b.is_corrupted());
+	}
+
+	#[tokio::test]
+	async fn test_encrypted_stream_wrong_key() {
+		let (key_a, key_b) = (
+			StreamKey::new(Key::generate()),
+			StreamKey::new(Key::generate()),
+		);
+		let (a, b) = duplex(1024);
+
+		let (a, b) = tokio::join!(
+			EncryptedStream::new(a, &key_a, true),
+			EncryptedStream::new(b, &key_b, false)
+		);
+		let (mut a, mut b) = (a.unwrap(), b.unwrap());
+
+		a.write_all(b"hello").await.unwrap();
+		a.flush().await.unwrap();
+
+		let mut buf = [0; 5];
+		let err = b.read_exact(&mut buf).await.unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+		assert!(b.is_corrupted());
+	}
+
+	#[tokio::test]
+	async fn test_encrypted_stream_truncated() {
+		let key = StreamKey::new(Key::generate());
+		let (a, b) = duplex(1024);
+
+		let (a, b) = tokio::join!(
+			EncryptedStream::new(a, &key, true),
+			EncryptedStream::new(b, &key, false)
+		);
+		let (mut a, mut b) = (a.unwrap(), b.unwrap());
+
+		// Closing the underlying stream without writing the final block must not look like a clean EOF
+		a.write_all(b"hello").await.unwrap();
+		a.flush().await.unwrap();
+		drop(a);
+
+		let mut buf = [0; 5];
+		b.read_exact(&mut buf).await.unwrap();
+		assert_eq!(&buf, b"hello");
+		assert_eq!(
+			b.read_u8().await.unwrap_err().kind(),
+			io::ErrorKind::UnexpectedEof
+		);
+	}
+
+	#[tokio::test]
+	async fn test_encrypted_stream_replay() {
+		let key = StreamKey::new(Key::generate());
+
+		// Record everything the initiator sends over a stream, including its nonce
+		let (a, mut recorder) = duplex(1024);
+		let (a, nonce) = tokio::join!(EncryptedStream::new(a, &key, true), async {
+			let mut nonce = vec![0; 1 + ALGORITHM.nonce_len()];
+			recorder.read_exact(&mut nonce).await.unwrap();
+			let remote_nonce = Nonce::generate(ALGORITHM).unwrap();
+			recorder.write_u8(remote_nonce.len() as u8).await.unwrap();
+			recorder.write_all(&remote_nonce).await.unwrap();
+			nonce
+		});
+		let mut a = a.unwrap();
+		a.write_all(b"hello").await.unwrap();
+		a.shutdown().await.unwrap();
+		drop(a);
+
+		let mut recording = Vec::new();
+		recorder.read_to_end(&mut recording).await.unwrap();
+		recording.splice(..0, nonce);
+
+		// Replaying the recording into a new stream must fail as the responder picked a new nonce
+		let (b, mut replayer) = duplex(1024);
+		replayer.write_all(&recording).await.unwrap();
+		let mut b = EncryptedStream::new(b, &key, false).await.unwrap();
+
+		let mut buf = [0; 5];
+		let err = b.read_exact(&mut buf).await.unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+		assert!(b.is_corrupted());
+	}
+}
@@ -0,0 +1,305 @@
+use std::{
+	sync::{
+		atomic::{AtomicU64, AtomicUsize, Ordering},
+		Arc,
+	},
+	time::{Duration, Instant},
+};
+
+use rspc::Type;
+use serde::Serialize;
+use serde_with::{serde_as, DurationMilliSecondsWithFrac};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+/// the priority of the traffic on a stream.
+/// Every request and message is sent on its own stream so the lanes don't need separate connections, but bulk streams are scheduled so they can't starve the control lane.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lane {
+	/// small, latency sensitive traffic. Eg. sync operations, pings and pairing.
+	Control,
+	/// large transfers which can make progress in the background. Eg. chunks of a file transfer or a Spacedrop.
+	Bulk,
+}
+
+/// Controls how bulk streams are scheduled around the control lane.
+#[derive(Debug, Clone)]
+pub struct LaneConfig {
+	/// the maximum number of bulk streams which are sent or handled at once across all peers.
+	pub max_bulk_in_flight: usize,
+	/// how long a bulk stream waits for the control streams in flight to finish before it's started anyway.
+	/// This bounds how much bulk traffic can be slowed down so a constant stream of sync operations can't stop a transfer.
+	pub max_bulk_delay: Duration,
+}
+
+impl Default for LaneConfig {
+	fn default() -> Self {
+		Self {
+			max_bulk_in_flight: 4,
+			// A 1 MiB chunk takes about this long on a gigabit LAN so a transfer still gets at least half of the link
+			max_bulk_delay: Duration::from_millis(10),
+		}
+	}
+}
+
+/// A snapshot of the lanes so the effect of bulk transfers on the control lane can be checked.
+/// The latencies are averaged over the control requests sent since the node started.
+#[serde_as]
+#[derive(Debug, Clone, Default, PartialEq, Type, Serialize)]
+pub struct LaneStats {
+	pub control_in_flight: u32,
+	pub bulk_in_flight: u32,
+	/// the average time in milliseconds a control request took to be answered while no bulk streams were in flight.
+	#[serde_as(as = "Option<DurationMilliSecondsWithFrac<f64>>")]
+	#[specta(type = Option<f64>)]
+	pub control_latency: Option<Duration>,
+	/// the average time in milliseconds a control request took to be answered while a bulk stream was in flight.
+	/// If this is much higher than `control_latency` bulk transfers are slowing down sync.
+	#[serde_as(as = "Option<DurationMilliSecondsWithFrac<f64>>")]
+	#[specta(type = Option<f64>)]
+	pub control_latency_during_bulk: Option<Duration>,
+	/// the number of bulk streams which were delayed because control streams were in flight.
+	pub bulk_delays: u32,
+	/// the total time in milliseconds bulk streams have been delayed for.
+	#[serde_as(as = "DurationMilliSecondsWithFrac<f64>")]
+	#[specta(type = f64)]
+	pub bulk_delay: Duration,
+}
+
+/// LatencyCounter accumulates latency samples in microseconds so they can be averaged without locking.
+#[derive(Debug, Default)]
+struct LatencyCounter {
+	samples: AtomicU64,
+	total_micros: AtomicU64,
+}
+
+impl LatencyCounter {
+	fn record(&self, latency: Duration) {
+		self.samples.fetch_add(1, Ordering::Relaxed);
+		self.total_micros.fetch_add(
+			u64::try_from(latency.as_micros()).unwrap_or(u64::MAX),
+			Ordering::Relaxed,
+		);
+	}
+
+	fn average(&self) -> Option<Duration> {
+		let samples = self.samples.load(Ordering::Relaxed);
+		(samples != 0)
+			.then(|| Duration::from_micros(self.total_micros.load(Ordering::Relaxed) / samples))
+	}
+}
+
+/// Lanes schedules the streams of each [Lane]. A [LaneGuard] must be held while a stream is sent or handled.
+#[derive(Debug)]
+pub struct Lanes {
+	config: LaneConfig,
+	control_in_flight: Arc<AtomicUsize>,
+	/// notified when the last control stream in flight finishes.
+	control_idle: Arc<Notify>,
+	bulk: Arc<Semaphore>,
+	control_latency: LatencyCounter,
+	control_latency_during_bulk: LatencyCounter,
+	bulk_delays: AtomicU64,
+	bulk_delay_micros: AtomicU64,
+}
+
+impl Lanes {
+	pub fn new(config: LaneConfig) -> Self {
+		Self {
+			bulk: Arc::new(Semaphore::new(config.max_bulk_in_flight.max(1))),
+			config,
+			control_in_flight: Default::default(),
+			control_idle: Default::default(),
+			control_latency: Default::default(),
+			control_latency_during_bulk: Default::default(),
+			bulk_delays: Default::default(),
+			bulk_delay_micros: Default::default(),
+		}
+	}
+
+	/// enter waits for a stream on the lane to be allowed to start.
+	/// Control streams start straight away. Bulk streams wait for a free slot and then for the control streams in flight to finish, for up to `max_bulk_delay`.
+	pub async fn enter(&self, lane: Lane) -> LaneGuard {
+		match lane {
+			Lane::Control => {
+				self.control_in_flight.fetch_add(1, Ordering::Relaxed);
+				LaneGuard::Control {
+					in_flight: self.control_in_flight.clone(),
+					idle: self.control_idle.clone(),
+				}
+			}
+			Lane::Bulk => {
+				// The semaphore is never closed
+				let permit = self
+					.bulk
+					.clone()
+					.acquire_owned()
+					.await
+					.expect("the bulk lane semaphore was closed");
+
+				let started = Instant::now();
+				let deadline = started + self.config.max_bulk_delay;
+				let mut delayed = false;
+				loop {
+					// The notification must be registered before the count is checked or the last control stream could finish in between
+					let idle = self.control_idle.notified();
+					if self.control_in_flight.load(Ordering::Relaxed) == 0 {
+						break;
+					}
+
+					delayed = true;
+					if tokio::time::timeout_at(deadline.into(), idle)
+						.await
+						.is_err()
+					{
+						break;
+					}
+				}
+
+				if delayed {
+					self.bulk_delays.fetch_add(1, Ordering::Relaxed);
+					self.bulk_delay_micros.fetch_add(
+						u64::try_from(started.elapsed().as_micros()).unwrap_or(u64::MAX),
+						Ordering::Relaxed,
+					);
+				}
+
+				LaneGuard::Bulk(permit)
+			}
+		}
+	}
+
+	/// bulk_in_flight returns the number of bulk streams which are currently being sent or handled.
+	pub fn bulk_in_flight(&self) -> usize {
+		self.config
+			.max_bulk_in_flight
+			.max(1)
+			.saturating_sub(self.bulk.available_permits())
+	}
+
+	/// record_control_latency records how long a control request took to be answered.
+	/// `during_bulk` is whether a bulk stream was in flight when the request was sent.
+	pub fn record_control_latency(&self, latency: Duration, during_bulk: bool) {
+		if during_bulk {
+			self.control_latency_during_bulk.record(latency);
+		} else {
+			self.control_latency.record(latency);
+		}
+	}
+
+	pub fn stats(&self) -> LaneStats {
+		LaneStats {
+			control_in_flight: u32::try_from(self.control_in_flight.load(Ordering::Relaxed))
+				.unwrap_or(u32::MAX),
+			bulk_in_flight: u32::try_from(self.bulk_in_flight()).unwrap_or(u32::MAX),
+			control_latency: self.control_latency.average(),
+			control_latency_during_bulk: self.control_latency_during_bulk.average(),
+			bulk_delays: u32::try_from(self.bulk_delays.load(Ordering::Relaxed))
+				.unwrap_or(u32::MAX),
+			bulk_delay: Duration::from_micros(self.bulk_delay_micros.load(Ordering::Relaxed)),
+		}
+	}
+}
+
+impl Default for Lanes {
+	fn default() -> Self {
+		Self::new(LaneConfig::default())
+	}
+}
+
+/// LaneGuard holds a stream's place in its lane. The next stream is scheduled once this is dropped.
+#[derive(Debug)]
+pub enum LaneGuard {
+	Control {
+		in_flight: Arc<AtomicUsize>,
+		idle: Arc<Notify>,
+	},
+	Bulk(OwnedSemaphorePermit),
+}
+
+impl Drop for LaneGuard {
+	fn drop(&mut self) {
+		if let Self::Control { in_flight, idle } = self {
+			if in_flight.fetch_sub(1, Ordering::Relaxed) == 1 {
+				idle.notify_waiters();
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn lanes(max_bulk_delay: Duration) -> Arc<Lanes> {
+		Arc::new(Lanes::new(LaneConfig {
+			max_bulk_in_flight: 1,
+			max_bulk_delay,
+		}))
+	}
+
+	#[tokio::test]
+	async fn test_bulk_waits_for_control() {
+		let lanes = lanes(Duration::from_secs(5));
+
+		let control = lanes.enter(Lane::Control).await;
+		let bulk = tokio::spawn({
+			let lanes = lanes.clone();
+			async move {
+				let _guard = lanes.enter(Lane::Bulk).await;
+			}
+		});
+
+		tokio::time::sleep(Duration::from_millis(20)).await;
+		assert!(!bulk.is_finished());
+
+		drop(control);
+		bulk.await.unwrap();
+
+		let stats = lanes.stats();
+		assert_eq!(stats.control_in_flight, 0);
+		assert_eq!(stats.bulk_in_flight, 0);
+		assert_eq!(stats.bulk_delays, 1);
+		assert!(stats.bulk_delay >= Duration::from_millis(20));
+	}
+
+	#[tokio::test]
+	async fn test_bulk_is_not_starved() {
+		let lanes = lanes(Duration::from_millis(10));
+
+		// The control lane never goes idle but the bulk stream still starts once it has waited `max_bulk_delay`
+		let _control = lanes.enter(Lane::Control).await;
+		let bulk = tokio::time::timeout(Duration::from_secs(5), lanes.enter(Lane::Bulk))
+			.await
+			.unwrap();
+		assert_eq!(lanes.stats().bulk_in_flight, 1);
+
+		// Bulk streams are limited to `max_bulk_in_flight` but control streams never wait
+		assert!(
+			tokio::time::timeout(Duration::from_millis(20), lanes.enter(Lane::Bulk))
+				.await
+				.is_err()
+		);
+		let _control = lanes.enter(Lane::Control).await;
+		assert_eq!(lanes.stats().control_in_flight, 2);
+
+		drop(bulk);
+		assert_eq!(lanes.stats().bulk_in_flight, 0);
+	}
+
+	#[test]
+	fn test_control_latency() {
+		let lanes = lanes(Duration::ZERO);
+		assert_eq!(lanes.stats().control_latency, None);
+
+		lanes.record_control_latency(Duration::from_millis(10), false);
+		lanes.record_control_latency(Duration::from_millis(20), false);
+		lanes.record_control_latency(Duration::from_millis(100), true);
+
+		let stats = lanes.stats();
+		assert_eq!(stats.control_latency, Some(Duration::from_millis(15)));
+		assert_eq!(
+			stats.control_latency_during_bulk,
+			Some(Duration::from_millis(100))
+		);
+	}
+}
@@ -0,0 +1,104 @@
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+
+static PING_EPOCH: Lazy<Instant> = Lazy::new(Instant::now);
+
+/// ping_timestamp returns the number of microseconds since the first ping was sent by this node.
+/// This is monotonic and only meaningful to this node so it is safe to compare with the `sent_at` echoed back by a peer without the clocks being in sync.
+pub fn ping_timestamp() -> u64 {
+	u64::try_from(PING_EPOCH.elapsed().as_micros()).unwrap_or(u64::MAX)
+}
+
+/// Controls how the round-trip latency to each connected peer is measured.
+/// Each peer is sent a [super::Request::TimedPing] every `interval` (or a [super::Request::Ping] if it's too old to understand it) and the time taken to respond is smoothed with an exponential moving average.
+#[derive(Debug, Clone)]
+pub struct LatencyConfig {
+	pub interval: Duration,
+	/// how much weight a new sample has, from `0.0` (ignored) to `1.0` (replaces the average).
+	pub smoothing: f64,
+	/// the number of pings in a row a peer can fail to respond to before it's disconnected, or `0` to never disconnect it.
+	/// QUIC connections have their own keepalive but TCP connections don't, so this is how a dead TCP connection is noticed.
+	pub max_missed_pings: u32,
+}
+
+impl Default for LatencyConfig {
+	fn default() -> Self {
+		Self {
+			interval: Duration::from_secs(15),
+			smoothing: 0.25,
+			max_missed_pings: 3,
+		}
+	}
+}
+
+impl LatencyConfig {
+	/// returns the new latency of a peer after measuring `sample`. The first sample is used as is.
+	pub fn smooth(&self, previous: Option<Duration>, sample: Duration) -> Duration {
+		match previous {
+			Some(previous) => {
+				let smoothing = self.smoothing.clamp(0.0, 1.0);
+				previous.mul_f64(1.0 - smoothing) + sample.mul_f64(smoothing)
+			}
+			None => sample,
+		}
+	}
+
+	/// returns whether a peer which has failed to respond to `missed` pings in a row should be disconnected.
+	pub fn is_dead(&self, missed: u32) -> bool {
+		self.max_missed_pings != 0 && missed >= self.max_missed_pings
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_latency_smoothing() {
+		let config = LatencyConfig {
+			smoothing: 0.25,
+			..Default::default()
+		};
+		let ms = Duration::from_millis;
+
+		assert_eq!(config.smooth(None, ms(100)), ms(100));
+		assert_eq!(config.smooth(Some(ms(100)), ms(100)), ms(100));
+
+		// A single slow sample only moves the average part of the way
+		assert_eq!(config.smooth(Some(ms(100)), ms(500)), ms(200));
+
+		let mut latency = ms(200);
+		for _ in 0..50 {
+			latency = config.smooth(Some(latency), ms(20));
+		}
+		assert!(latency < ms(21));
+
+		let config = LatencyConfig {
+			smoothing: 2.0,
+			..Default::default()
+		};
+		assert_eq!(config.smooth(Some(ms(100)), ms(500)), ms(500));
+	}
+
+	#[test]
+	fn test_is_dead() {
+		let config = LatencyConfig::default();
+		assert!(!config.is_dead(0));
+		assert!(!config.is_dead(2));
+		assert!(config.is_dead(3));
+
+		let config = LatencyConfig {
+			max_missed_pings: 0,
+			..Default::default()
+		};
+		assert!(!config.is_dead(u32::MAX));
+	}
+
+	#[test]
+	fn test_ping_timestamp() {
+		let sent_at = ping_timestamp();
+		std::thread::sleep(Duration::from_millis(5));
+		assert!(ping_timestamp().saturating_sub(sent_at) >= 5_000);
+	}
+}
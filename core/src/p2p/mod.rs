@@ -1,9 +1,84 @@
+mod batch;
+mod compression;
+mod discovery;
+mod encryption;
+mod lanes;
+mod latency;
 mod p2p_manager;
+mod pairing;
 mod peer_metadata;
 mod protocol;
+mod reconnect;
+mod relay;
+mod reliable_sync;
+mod signing;
+mod stream_limit;
+mod subscription;
+mod sync_checkpoint;
+mod sync_queue;
+mod transfer;
+mod verified_peer;
 
+pub use batch::*;
+pub use compression::*;
+pub use discovery::*;
+pub use encryption::*;
+pub use lanes::*;
+pub use latency::*;
 pub use p2p_manager::*;
+pub use pairing::*;
 pub use peer_metadata::*;
 pub use protocol::*;
+pub use reconnect::*;
+pub use relay::*;
+pub use reliable_sync::*;
+pub use signing::*;
+pub use stream_limit::*;
+pub use subscription::*;
+pub use sync_checkpoint::*;
+pub use sync_queue::*;
+pub use transfer::*;
+pub use verified_peer::*;
 
 pub(super) const SPACEDRIVE_APP_ID: &str = "spacedrive";
+
+/// the BLAKE3 context used to derive the app id from the user's network name.
+const NETWORK_ID_CONTEXT: &str = "spacedrive 2023-03-27 14:02:51 network id derivation";
+
+/// network_app_id returns the app id used for mDNS and the libp2p protocol name.
+/// The network name is hashed so it can contain any characters and the resulting id is short enough for a mDNS service name.
+pub(super) fn network_app_id(network_name: Option<&str>) -> String {
+	match network_name.map(str::trim).filter(|name| !name.is_empty()) {
+		Some(name) => {
+			let hash = blake3::derive_key(NETWORK_ID_CONTEXT, name.as_bytes());
+			let id = hash[..4]
+				.iter()
+				.map(|b| format!("{b:02x}"))
+				.collect::<String>();
+			format!("sd-{id}")
+		}
+		None => SPACEDRIVE_APP_ID.into(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_network_app_id() {
+		assert_eq!(network_app_id(None), SPACEDRIVE_APP_ID);
+		assert_eq!(network_app_id(Some("  ")), SPACEDRIVE_APP_ID);
+
+		let home = network_app_id(Some("home"));
+		assert_eq!(home, network_app_id(Some(" home ")));
+		assert_ne!(home, network_app_id(Some("office")));
+		assert_ne!(home, SPACEDRIVE_APP_ID);
+
+		for id in [home, network_app_id(Some("🏠 Our house!"))] {
+			// The mDNS service name must be at most 15 characters
+			assert!(id.len() <= 15);
+			assert!(id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'));
+		}
+	}
+}
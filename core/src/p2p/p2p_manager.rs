@@ -1,74 +1,535 @@
-use std::{path::PathBuf, str::FromStr, sync::Arc, time::Instant};
+use std::{
+	collections::{HashMap, HashSet, VecDeque},
+	future::Future,
+	io::SeekFrom,
+	net::{IpAddr, SocketAddr},
+	path::{Path, PathBuf},
+	str::FromStr,
+	sync::{
+		atomic::{AtomicBool, AtomicUsize, Ordering},
+		Arc, PoisonError,
+	},
+	time::{Duration, Instant},
+};
 
+use chrono::{DateTime, Utc};
+use futures::{future::join_all, stream::BoxStream, Stream, StreamExt};
+use once_cell::sync::OnceCell;
 use rspc::Type;
+use sd_crypto::types::Key;
 use sd_p2p::{
+	order_addresses, parse_peer_address,
 	spaceblock::{BlockSize, TransferRequest},
-	Event, Manager, PeerId,
+	spacetime::SpaceTimeStream,
+	AddressKind, DialError, Event, InvalidPeerAddress, Keypair, Manager, ManagerConfig,
+	ManagerError, PeerId, PeerStats, Transport,
 };
 use sd_sync::{CRDTOperation, CRDTOperationType, OwnedOperation};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr, DurationMilliSecondsWithFrac};
+use thiserror::Error;
 use tokio::{
-	fs::File,
-	io::{AsyncReadExt, AsyncWriteExt, BufReader},
-	sync::broadcast,
+	fs::{self, File},
+	io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader},
+	sync::{broadcast, watch, Mutex, Notify, RwLock, Semaphore},
+	task::JoinHandle,
 };
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, trace, warn};
 use uhlc::NTP64;
 use uuid::Uuid;
 
 use crate::{
-	node::NodeConfigManager,
-	p2p::{OperatingSystem, SPACEDRIVE_APP_ID},
+	library::{Library, LibraryManager, SyncKey},
+	node::{NodeConfig, NodeConfigError, NodeConfigManager},
 };
 
-use super::{Header, PeerMetadata};
+use super::{
+	decode_payload, encode_message_with_compression, network_app_id, pairing_commitment,
+	ping_timestamp, read_message, read_message_with_limit, relay_targets,
+	write_message_with_compression, BatchConfig, ChecksumAlgorithm, Compression, DiscoveredPeers,
+	DiscoveryConfig, EncryptedStream, EncryptionError, FileChecksum, FileHasher, FileRequest,
+	Header, Lane, LaneStats, Lanes, LatencyConfig, LibrarySigners, MessageError, PairingError,
+	Pairings, PeerMetadata, ReconnectConfig, ReconnectTarget, Reply, Request, Response,
+	SeenOperations, SharedLibrary, SignedOperation, StreamKey, Subscriptions, SyncBatchAction,
+	SyncCheckpoint, SyncCheckpoints, SyncInbox, SyncOutbox, SyncQueueConfig, SyncQueueReceiver,
+	SyncQueueSender, TransferId, DEFAULT_FILE_CHUNK_SIZE, DEFAULT_MAX_MESSAGE_SIZE,
+	ENCRYPTED_REQUEST_PROTO_VERSION, FILE_CHECKSUM_PROTO_VERSION, MAX_SYNC_OPERATIONS_PER_RESPONSE,
+	METADATA_CHANGED_PROTO_VERSION, MIN_PROTO_VERSION, PAIRING_ACCEPT_PROTO_VERSION,
+	PAIRING_EXPIRY_INTERVAL, PROTO_VERSION, RELIABLE_SYNC_PROTO_VERSION,
+	RESUME_TRANSFER_PROTO_VERSION, STREAMING_RESPONSE_PROTO_VERSION, SUBSCRIPTION_PROTO_VERSION,
+	SYNC_PROGRESS_PROTO_VERSION, TIMED_PING_PROTO_VERSION,
+};
 
 /// TODO: P2P event for the frontend
+#[serde_as]
 #[derive(Debug, Clone, Type, Serialize)]
 #[serde(tag = "type")]
 pub enum P2PEvent {
+	/// `addresses` are the addresses the peer is advertising in the order they'll be dialed. This is empty for peers which connected without being discovered.
 	DiscoveredPeer {
 		peer_id: PeerId,
 		metadata: PeerMetadata,
+		addresses: Vec<SocketAddr>,
+	},
+	/// a discovered peer stopped advertising itself on the local network so it should be removed from the list of nearby peers.
+	/// Unlike `DisconnectedPeer` this doesn't mean a connection was lost.
+	ExpiredPeer {
+		peer_id: PeerId,
+	},
+	/// a discovered or connected peer has changed its metadata. Eg. it was renamed or updated to a new version.
+	PeerMetadataChanged {
+		peer_id: PeerId,
+		metadata: PeerMetadata,
+	},
+	/// a discovered peer is running a version of Spacedrive or a protocol version which this node can't communicate with so it won't be connected to.
+	/// This is emitted whenever the peer's metadata changes while it's incompatible.
+	PeerIncompatible {
+		peer_id: PeerId,
+		version: Option<String>,
+		protocol_version: Option<u16>,
+	},
+	/// `address` is the address the connection was established through and `transport` is the transport it was established with. These are `None` if they couldn't be determined.
+	/// `relayed` is whether the connection is through a relay, in which case `address` is always `None`.
+	ConnectedPeer {
+		peer_id: PeerId,
+		address: Option<SocketAddr>,
+		transport: Option<Transport>,
+		relayed: bool,
+	},
+	/// a relayed connection with the peer has been upgraded to a direct connection through `address` by hole punching.
+	ConnectionUpgraded {
+		peer_id: PeerId,
+		address: Option<SocketAddr>,
+		transport: Option<Transport>,
+	},
+	DisconnectedPeer {
+		peer_id: PeerId,
+	},
+	/// a connection couldn't be established with the peer. This is emitted for every failed attempt so the frontend can show why a peer won't connect.
+	DialFailed {
+		peer_id: PeerId,
+		reason: DialError,
+	},
+	BootstrapProgress {
+		peer_id: PeerId,
+		progress: PeerBootstrapProgress,
+	},
+	FileTransferProgress {
+		peer_id: PeerId,
+		library_id: Uuid,
+		file_path_id: i32,
+		#[specta(type = String)]
+		#[serde_as(as = "DisplayFromStr")]
+		transferred: u64,
+		#[specta(type = String)]
+		#[serde_as(as = "DisplayFromStr")]
+		total: u64,
 	},
-	// TODO: Expire peer + connection/disconnect
 	SyncOperation {
 		library_id: Uuid,
 		operations: Vec<CRDTOperation>,
 	},
+	/// a connection is being attempted with a paired or manually added peer which was disconnected.
+	/// `attempt` starts at `0` and is incremented every time the connection fails.
+	ConnectingPeer {
+		peer_id: PeerId,
+		attempt: u32,
+	},
+	/// a peer which was being reconnected to after `ConnectingPeer` is connected again. This is emitted before `ConnectedPeer`.
+	ReconnectedPeer {
+		peer_id: PeerId,
+	},
+	/// a peer has started pairing with this node. The user should be asked for the code shown on the other device which is passed to `confirmPairing`.
+	PairingRequest {
+		peer_id: PeerId,
+	},
+	/// the code shown on this node was entered on the peer. The user should be asked to accept the pairing with `acceptPairing`, which shares the sync keys of the libraries both nodes are a member of, or to decline it with `rejectPairing`.
+	PairingConfirmed {
+		peer_id: PeerId,
+	},
+	/// a pairing with the peer wasn't confirmed within `PAIRING_TIMEOUT` so it has to be restarted. Any prompt for its code should be dismissed.
+	PairingExpired {
+		peer_id: PeerId,
+	},
+	/// the pairing with the peer was confirmed and it is now trusted.
+	/// `replaced` are the peers which were paired as the same device before it changed its keypair. They've been unpaired so the device is only listed once.
+	Paired {
+		peer_id: PeerId,
+		replaced: Vec<PeerId>,
+	},
+	/// the addresses this node can be reached at have changed. Eg. a network interface was added or removed.
+	/// Loopback and link-local addresses are not included as they can't be used by other devices.
+	ListenAddrsChanged {
+		addresses: Vec<SocketAddr>,
+	},
+	/// the P2P subsystem stopped unexpectedly. Every peer is disconnected while it's restarted.
+	SubsystemDown,
+	/// the P2P subsystem was restarted after `SubsystemDown` and is listening for connections again.
+	SubsystemRestarted,
+	/// the P2P subsystem couldn't be restarted after `SubsystemDown`. P2P is unavailable until the app is restarted.
+	SubsystemFailed {
+		error: String,
+	},
+}
+
+/// The stages of bootstrapping a connection with a peer which shares a library with this node.
+/// A bootstrap starts when the peer is discovered and finishes once the operations missed from it have been applied after it's connected.
+#[derive(Debug, Clone, Type, Serialize)]
+pub enum PeerBootstrapProgress {
+	Connecting,
+	ExchangingMetadata,
+	TransferringKeys,
+	/// the number of sync operations which have been applied from the peer out of the total which are known about so far.
+	/// This isn't emitted for peers older than [SYNC_PROGRESS_PROTO_VERSION].
+	InitialSync {
+		synced: u32,
+		total: u32,
+	},
+	/// the bootstrap finished and the peer is in sync. This is terminal.
+	Done,
+	/// the bootstrap was aborted. This is terminal so the frontend should stop displaying progress.
+	Error(String),
+}
+
+/// Controls which discovered peers are automatically dialed.
+/// Peers which don't match the policy are still emitted to the frontend as discovered but are never connected to.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Type, Serialize, Deserialize)]
+pub enum DialPolicy {
+	/// dial every discovered peer
+	All,
+	/// only dial peers which have been paired with this node
+	#[default]
+	Paired,
+	/// only dial the given peers
+	Allowlist(HashSet<PeerId>),
+	/// never dial discovered peers automatically. They're only connected to when the user chooses to with [P2PManager::connect_to].
+	Manual,
+}
+
+impl DialPolicy {
+	fn should_dial(&self, peer_id: &PeerId, is_paired: bool) -> bool {
+		match self {
+			Self::All => true,
+			Self::Paired => is_paired,
+			Self::Allowlist(peers) => peers.contains(peer_id),
+			Self::Manual => false,
+		}
+	}
+}
+
+/// The number of batches of sync operations in a library which are waiting to be sent to other peers.
+/// A queue which stays near its capacity means the network can't keep up with the operations being created.
+#[derive(Debug, Clone, Type, Serialize)]
+pub struct SyncQueueStats {
+	pub library_id: Uuid,
+	pub depth: u32,
+	pub capacity: u32,
+}
+
+/// The data exchanged with a peer, or with every peer for [P2PTraffic::totals]. A message is a single broadcast or stream.
+#[serde_as]
+#[derive(Debug, Clone, Type, Serialize)]
+pub struct Traffic {
+	#[specta(type = String)]
+	#[serde_as(as = "DisplayFromStr")]
+	pub bytes_sent: u64,
+	#[specta(type = String)]
+	#[serde_as(as = "DisplayFromStr")]
+	pub bytes_received: u64,
+	pub messages_sent: u32,
+	pub messages_received: u32,
+	/// the number of the messages which were broadcasts. The rest were requests and responses.
+	pub broadcasts_sent: u32,
+	pub broadcasts_received: u32,
+	/// the last time anything was exchanged. `None` if nothing ever has been.
+	pub last_activity: Option<DateTime<Utc>>,
+}
+
+impl From<PeerStats> for Traffic {
+	fn from(stats: PeerStats) -> Self {
+		let count = |n: u64| u32::try_from(n).unwrap_or(u32::MAX);
+
+		Self {
+			bytes_sent: stats.bytes_sent,
+			bytes_received: stats.bytes_received,
+			messages_sent: count(stats.messages_sent),
+			messages_received: count(stats.messages_received),
+			broadcasts_sent: count(stats.broadcasts_sent),
+			broadcasts_received: count(stats.broadcasts_received),
+			last_activity: stats.last_activity.map(DateTime::from),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Type, Serialize)]
+pub struct PeerTraffic {
+	pub peer_id: PeerId,
+	/// the name to show for the peer, see [ConnectedPeer::name]. `None` if the peer isn't connected and hasn't been given a nickname.
+	pub name: Option<String>,
+	pub traffic: Traffic,
+}
+
+/// The data exchanged with peers since the node was started. This is returned by `p2p.traffic` to help diagnose slow syncs.
+#[derive(Debug, Clone, Type, Serialize)]
+pub struct P2PTraffic {
+	pub totals: Traffic,
+	/// every peer which has been connected to, most data exchanged first.
+	pub peers: Vec<PeerTraffic>,
+}
+
+/// A snapshot of the state of the P2P subsystem. This is returned by `p2p.status` so the frontend can show whether P2P is working without subscribing to events.
+#[derive(Debug, Clone, Type, Serialize)]
+pub struct P2PStatus {
+	/// the P2P subsystem is running and has at least one address other devices can reach it at.
+	pub listening: bool,
+	pub listen_addrs: Vec<SocketAddr>,
+	pub discovery_enabled: bool,
+	pub connected_peer_count: u32,
+	/// the number of peers which are remembered in the node config so they can be reconnected to. See `KnownPeer`.
+	pub known_peer_count: u32,
+	/// the most recent failure to dial a peer or of the P2P subsystem since the node was started, so transient problems are still visible once they've passed.
+	pub last_error: Option<String>,
+	/// the number of sync messages received from peers since the node was started which were dropped as they couldn't be decoded.
+	pub malformed_sync_messages: u32,
+}
+
+/// The addresses of a peer in the order they will be dialed. This is returned by `peerAddresses` to help troubleshoot connections.
+#[derive(Debug, Clone, Type, Serialize)]
+pub struct PeerAddresses {
+	pub addresses: Vec<PeerAddress>,
+	/// the address the user has pinned for the peer. This is dialed first.
+	pub pinned: Option<SocketAddr>,
+	/// the address the peer is connected through, or was last connected through if it's disconnected. This is dialed after the pinned address.
+	pub last_connected: Option<SocketAddr>,
+}
+
+#[derive(Debug, Clone, Type, Serialize)]
+pub struct PeerAddress {
+	pub address: SocketAddr,
+	pub kind: AddressKind,
+}
+
+/// A peer which currently has an active connection with this node.
+#[serde_as]
+#[derive(Debug, Clone, Type, Serialize)]
+pub struct ConnectedPeer {
+	pub peer_id: PeerId,
+	/// will be `None` if the peer has not been discovered over mDNS or sent us its metadata
+	pub metadata: Option<PeerMetadata>,
+	/// the nickname the user has given the peer, see [P2PManager::set_peer_nickname].
+	pub nickname: Option<String>,
+	/// the name to show for the peer. This is its nickname if it has one, otherwise the name it advertises in its metadata.
+	pub name: Option<String>,
+	pub addresses: Vec<SocketAddr>,
+	/// the transport the connection was established with.
+	pub transport: Option<Transport>,
+	/// is the connection through a relay, see [sd_p2p::ManagerConfig::relays]. `addresses` is empty while it is as only the relay's address is known.
+	pub relayed: bool,
+	pub connected_at: DateTime<Utc>,
+	/// how long in milliseconds the peer has been connected for. This is calculated when the connected peers are requested.
+	#[serde_as(as = "DurationMilliSecondsWithFrac<f64>")]
+	#[specta(type = f64)]
+	pub connected_for: Duration,
+	/// the smoothed round-trip time to the peer in milliseconds. This will be `None` until the peer has responded to a ping.
+	#[serde_as(as = "Option<DurationMilliSecondsWithFrac<f64>>")]
+	#[specta(type = Option<f64>)]
+	pub latency: Option<Duration>,
+	/// the number of streams from the peer which are currently being handled. This saturates at the [StreamLimitConfig] `max_in_flight`.
+	pub in_flight_streams: u32,
+	/// the number of pings in a row the peer hasn't responded to, see [LatencyConfig::max_missed_pings].
+	#[serde(skip)]
+	missed_pings: u32,
+}
+
+#[derive(Debug, Error)]
+pub enum P2PError {
+	#[error("peer '{0}' is not connected")]
+	PeerNotConnected(PeerId),
+	#[error("peer '{0}' has not been discovered")]
+	PeerNotDiscovered(PeerId),
+	#[error("peer '{0}' is running an incompatible version")]
+	PeerIncompatible(PeerId),
+	#[error("error pairing with peer: {0}")]
+	Pairing(#[from] PairingError),
+	#[error("error connecting to peer: {0}")]
+	Dial(#[from] DialError),
+	#[error("error saving node config: {0}")]
+	NodeConfig(#[from] NodeConfigError),
+	#[error(transparent)]
+	InvalidAddress(#[from] InvalidPeerAddress),
+	#[error("io error communicating with peer: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("error sending or receiving message: {0}")]
+	Message(#[from] MessageError),
+	#[error("timed out waiting for a response from the peer")]
+	Timeout,
+	#[error("the peer responded with an error: {0}")]
+	Remote(String),
+	#[error("protocol error: {0}")]
+	Protocol(String),
+	#[error("the peer responded with an unexpected response")]
+	UnexpectedResponse,
+	#[error("the transferred file's checksum '{actual}' doesn't match the expected '{expected}'")]
+	TransferChecksumMismatch {
+		expected: FileChecksum,
+		actual: FileChecksum,
+	},
+	#[error("peer is running protocol version '{0}' which is not compatible with this node")]
+	IncompatibleVersion(u16),
+	#[error("peer is running protocol version '{0}' which doesn't support this request")]
+	UnsupportedRequest(u16),
+	#[error("error encrypting stream with peer: {0}")]
+	Encryption(#[from] EncryptionError),
+	#[error("too many streams from this peer are already being handled")]
+	TooManyStreams,
+	#[error(
+		"peer '{peer_id}' is not authorized to send sync operations for library '{library_id}'"
+	)]
+	PeerNotAuthorized { peer_id: PeerId, library_id: Uuid },
 }
 
+/// the default amount of time to wait for a peer to respond to a [Request].
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// how long a request from a peer is handled for before it's responded to with a [Response::ProtocolError].
+/// This is shorter than [DEFAULT_REQUEST_TIMEOUT] so the peer receives the error before it gives up waiting.
+const DEFAULT_REQUEST_HANDLER_TIMEOUT: Duration = Duration::from_secs(8);
+
+/// how long [P2PManager::shutdown] waits for each background task to stop before it's aborted.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// how long to wait after our metadata changes before it's sent to the connected peers, so many changes in quick succession (Eg. typing a new node name) are only sent once.
+const METADATA_PUSH_DEBOUNCE: Duration = Duration::from_secs(1);
+
+/// the number of times the [Manager] is recreated after its event stream unexpectedly closes before giving up.
+/// The attempts are spaced out using the [ReconnectConfig] delay.
+const MAX_SUBSYSTEM_RESTARTS: u32 = 5;
+
 pub struct P2PManager {
 	pub events: broadcast::Sender<P2PEvent>,
-	pub manager: Arc<Manager<PeerMetadata>>,
+	/// this is replaced if the P2P subsystem has to be restarted so it must be accessed through `manager()`.
+	manager: std::sync::RwLock<Arc<Manager<PeerMetadata>>>,
+	node_config: Arc<NodeConfigManager>,
+	dial_policy: Arc<RwLock<DialPolicy>>,
+	/// the peers which have been paired with this node. These are loaded from the node config.
+	paired_peers: Arc<RwLock<HashSet<PeerId>>>,
+	/// pairings which are waiting for the user to confirm the code.
+	pairings: Pairings,
+	connected_peers: Arc<RwLock<HashMap<PeerId, ConnectedPeer>>>,
+	/// the [PROTO_VERSION] negotiated with each connected peer. This is the lower of the two node's versions.
+	peer_versions: RwLock<HashMap<PeerId, u16>>,
+	/// the address each peer was last connected through. It's preferred over the peer's other addresses when reconnecting.
+	last_addresses: RwLock<HashMap<PeerId, SocketAddr>>,
+	/// the libraries loaded on this node and their sync keys. These are advertised to other peers through the `PeerMetadata`.
+	libraries: Arc<RwLock<HashMap<Uuid, SyncKey>>>,
+	/// the libraries which the user has disabled sync for. Their sync operations aren't sent to or accepted from other peers, see [Self::set_sync_enabled].
+	sync_disabled: RwLock<HashSet<Uuid>>,
+	/// a cache of the connected peers which are members of each library. This is cleared whenever a peer joins or leaves.
+	library_peers: Arc<RwLock<HashMap<Uuid, Vec<PeerId>>>>,
+	/// this is set once the [LibraryManager] is created as it depends on the [P2PManager].
+	library_manager: OnceCell<Arc<LibraryManager>>,
+	/// how payloads sent to peers which can decode them are compressed, see [P2PManager::compression_for].
+	compression: Compression,
+	/// how the sync events created in each library are batched before being sent to other peers.
+	batch: BatchConfig,
+	/// how batches of sync events waiting to be sent are queued when the network can't keep up.
+	sync_queue: SyncQueueConfig,
+	/// the number of batches waiting in each library's sync queue.
+	sync_queue_depths: RwLock<HashMap<Uuid, Arc<AtomicUsize>>>,
+	/// the batches of sync events which each peer hasn't acknowledged yet, for each library.
+	sync_outboxes: RwLock<HashMap<(Uuid, PeerId), Arc<Mutex<SyncOutbox>>>>,
+	/// the batches of sync events which have been applied from each peer. This is locked while a batch is applied so retransmits aren't applied concurrently.
+	sync_inbox: Mutex<SyncInbox>,
+	/// the newest operations applied from each peer so reconnecting only transfers the operations which were missed.
+	sync_checkpoints: Mutex<SyncCheckpoints>,
+	/// the peers which are sent the sync events of each library.
+	subscriptions: RwLock<Subscriptions>,
+	/// the public key of the library keypair each node has signed its sync operations with, for each library.
+	library_signers: RwLock<LibrarySigners>,
+	/// the libraries each connected peer has been verified as a member of, see [VerifiedPeer]. This is cleared whenever the pairings or libraries change.
+	verified_peers: RwLock<HashMap<PeerId, VerifiedPeer>>,
+	/// the sync operations which have recently been created on or received by this node, so ones which come back from another peer are dropped.
+	seen_operations: Mutex<SeenOperations>,
+	/// the sync operations which have been received from a peer and are waiting to be relayed to the library's other peers, see [P2PManager::relay_sync_operations].
+	relay_queue: Mutex<VecDeque<(Uuid, PeerId, Vec<SignedOperation>)>>,
+	/// notified when operations are added to `relay_queue`.
+	relay_queued: Notify,
+	/// set while the P2P subsystem is down, from `SubsystemDown` until it's restarted.
+	subsystem_down: AtomicBool,
+	/// the number of sync messages from peers which were dropped as they couldn't be decoded, see [P2PStatus::malformed_sync_messages].
+	malformed_sync_messages: AtomicUsize,
+	/// the most recent dial or subsystem failure, see [P2PStatus::last_error].
+	last_error: std::sync::Mutex<Option<String>>,
+	/// how dropped connections to paired and manually added peers are retried.
+	reconnect: ReconnectConfig,
+	/// how repeated announcements of the same peer are deduplicated.
+	discovery: DiscoveryConfig,
+	/// how often the latency to each connected peer is measured.
+	latency: LatencyConfig,
+	/// how many inbound streams from each peer are handled at once.
+	stream_limit: StreamLimitConfig,
+	/// the streams from each peer which are currently being handled.
+	stream_limits: StreamLimits,
+	/// schedules the streams sent to and received from peers so bulk transfers can't starve sync.
+	lanes: Lanes,
+	/// how long a request which doesn't stream its response is handled for before the peer is sent a [Response::ProtocolError].
+	request_handler_timeout: Duration,
+	/// the largest [Request] which is read from a peer. A larger one is refused from its length prefix before any of it is buffered.
+	max_request_size: usize,
+	/// the targets which currently have a reconnect running so concurrent attempts are coalesced.
+	reconnecting: Mutex<HashSet<ReconnectTarget>>,
+	/// the peers the user has disconnected from with [P2PManager::disconnect_peer]. These aren't reconnected to until the user connects to them again.
+	disconnected_peers: RwLock<HashSet<PeerId>>,
+	/// notified by [P2PManager::update_metadata] so our new metadata is sent to the connected peers, see [Request::MetadataChanged].
+	metadata_changed: Notify,
+	shutdown: watch::Sender<bool>,
+	tasks: Mutex<Vec<JoinHandle<()>>>,
 }
 
 impl P2PManager {
+	/// new will start the P2P subsystem. This errors if the listen addresses in the node config can't be bound to, Eg. because the port is already in use.
 	pub async fn new(
 		node_config: Arc<NodeConfigManager>,
-	) -> (Arc<Self>, broadcast::Receiver<P2PEvent>) {
-		let (config, keypair) = {
+	) -> Result<(Arc<Self>, broadcast::Receiver<P2PEvent>), ManagerError> {
+		let (dial_policy, paired_peers, last_addresses, compression) = {
 			let config = node_config.get().await;
 			(
-				PeerMetadata {
-					name: config.name.clone(),
-					operating_system: Some(OperatingSystem::get_os()),
-					version: Some(env!("CARGO_PKG_VERSION").to_string()),
-					email: config.p2p_email.clone(),
-					img_url: config.p2p_img_url.clone(),
-				},
-				config.keypair,
+				Arc::new(RwLock::new(config.p2p_dial_policy)),
+				Arc::new(RwLock::new(config.p2p_paired_peers)),
+				// Until a peer connects again the most recent address from the last run is preferred
+				config
+					.p2p_known_peers
+					.iter()
+					.filter_map(|(peer_id, peer)| Some((*peer_id, *peer.addresses.first()?)))
+					.collect::<HashMap<_, _>>(),
+				config.p2p_compression,
 			)
-		}; // TODO: Update this throughout the application lifecycle
+		};
+		let libraries = Arc::new(RwLock::new(HashMap::new()));
+		let connected_peers = Arc::new(RwLock::new(HashMap::<PeerId, ConnectedPeer>::new()));
+		let library_peers = Arc::new(RwLock::new(HashMap::new()));
+		let sync_checkpoints = SyncCheckpoints::load(&node_config.data_directory()).await;
 
-		let (manager, mut stream) = Manager::new(SPACEDRIVE_APP_ID, &keypair, {
+		// The metadata is read from the config every time it's advertised so changes to the node config are picked up without a restart.
+		// Once `update_metadata` has been called it must be called again after the node config changes as the advertised metadata is replaced.
+		let metadata_fn = {
+			let node_config = node_config.clone();
+			let libraries = libraries.clone();
 			move || {
-				let config = config.clone();
-				async move { config }
+				let node_config = node_config.clone();
+				let libraries = libraries.clone();
+				async move {
+					PeerMetadata::from_node_config(
+						&node_config.get().await,
+						libraries.read().await.keys().copied().collect(),
+					)
+				}
 			}
-		})
-		.await
-		.unwrap();
+		};
+
+		let (app_id, keypair, manager_config) = manager_config(&node_config).await;
+		let (manager, mut stream) =
+			Manager::new(&app_id, &keypair, manager_config, metadata_fn.clone()).await?;
 
 		info!(
 			"Node '{}' is now online listening at addresses: {:?}",
@@ -78,105 +539,815 @@ impl P2PManager {
 
 		let (tx, rx) = broadcast::channel(100);
 
-		tokio::spawn({
+		let (shutdown, _) = watch::channel(false);
+		let this = Arc::new(Self {
+			events: tx.clone(),
+			manager: std::sync::RwLock::new(manager),
+			node_config,
+			dial_policy: dial_policy.clone(),
+			paired_peers: paired_peers.clone(),
+			pairings: Pairings::default(),
+			connected_peers: connected_peers.clone(),
+			peer_versions: RwLock::new(HashMap::new()),
+			last_addresses: RwLock::new(last_addresses),
+			libraries: libraries.clone(),
+			sync_disabled: RwLock::new(HashSet::new()),
+			library_peers: library_peers.clone(),
+			library_manager: OnceCell::new(),
+			compression,
+			batch: BatchConfig::default(),
+			sync_queue: SyncQueueConfig::default(),
+			sync_queue_depths: RwLock::new(HashMap::new()),
+			sync_outboxes: RwLock::new(HashMap::new()),
+			sync_inbox: Mutex::new(SyncInbox::default()),
+			sync_checkpoints: Mutex::new(sync_checkpoints),
+			subscriptions: RwLock::new(Subscriptions::default()),
+			library_signers: RwLock::new(LibrarySigners::default()),
+			verified_peers: RwLock::new(HashMap::new()),
+			seen_operations: Mutex::new(SeenOperations::default()),
+			relay_queue: Mutex::new(VecDeque::new()),
+			relay_queued: Notify::new(),
+			subsystem_down: AtomicBool::new(false),
+			malformed_sync_messages: AtomicUsize::new(0),
+			last_error: std::sync::Mutex::new(None),
+			reconnect: ReconnectConfig::default(),
+			discovery: DiscoveryConfig::default(),
+			latency: LatencyConfig::default(),
+			stream_limit: StreamLimitConfig::default(),
+			stream_limits: StreamLimits::default(),
+			lanes: Lanes::default(),
+			request_handler_timeout: DEFAULT_REQUEST_HANDLER_TIMEOUT,
+			max_request_size: DEFAULT_MAX_MESSAGE_SIZE,
+			reconnecting: Mutex::new(HashSet::new()),
+			disconnected_peers: RwLock::new(HashSet::new()),
+			metadata_changed: Notify::new(),
+			shutdown,
+			tasks: Mutex::new(Vec::new()),
+		});
+
+		let event_loop = tokio::spawn({
+			let this = this.clone();
 			let events = tx.clone();
+			let library_peers = library_peers.clone();
+			let libraries = libraries.clone();
+			let dial_policy = dial_policy.clone();
+			let paired_peers = paired_peers.clone();
+			let connected_peers = connected_peers.clone();
 
 			async move {
-				while let Some(event) = stream.next().await {
-					match event {
-						Event::PeerDiscovered(event) => {
-							debug!(
-								"Discovered peer by id '{}' with address '{:?}' and metadata: {:?}",
-								event.peer_id, event.addresses, event.metadata
-							);
+				let emit_progress = |peer_id, progress| {
+					events
+						.send(P2PEvent::BootstrapProgress { peer_id, progress })
+						.map_err(|_| error!("Failed to send event to p2p event stream!"))
+						.ok();
+				};
 
-							events
-								.send(P2PEvent::DiscoveredPeer {
-									peer_id: event.peer_id.clone(),
-									metadata: event.metadata.clone(),
-								})
-								.map_err(|_| error!("Failed to send event to p2p event stream!"))
-								.ok();
+				loop {
+					// mDNS will rediscover peers every time they readvertise so we keep track of what the frontend has already seen.
+					let mut discovered = HashMap::<PeerId, PeerMetadata>::new();
+					// peers which share a library with us that we are currently trying to connect to.
+					let mut bootstrapping = HashSet::<PeerId>::new();
+					// when each peer was last announced and dialed so re-announcements of a peer don't dial it again.
+					let mut discovered_peers = DiscoveredPeers::default();
+					// the addresses last sent to the frontend so an event is only emitted when they change.
+					let mut listen_addrs = Vec::new();
 
-							// TODO: Don't just connect to everyone when we find them. We should only do it if we know them.
-							event.dial().await;
-						}
-						Event::PeerMessage(mut event) => {
-							let events = events.clone();
+					while let Some(event) = stream.next().await {
+						match event {
+							Event::PeerDiscovered(event) => {
+								// A node can see its own advertisement, Eg. when several instances on one machine share a data directory. It must never be listed or dialed.
+								if event.peer_id == this.manager().peer_id() {
+									trace!("Ignoring discovery of this node's own peer id");
+									continue;
+								}
+
+								let now = Instant::now();
+								if discovered_peers.seen(event.peer_id, &this.discovery, now) {
+									debug!(
+										"Discovered peer by id '{}' with address '{:?}' and metadata: {:?}",
+										event.peer_id, event.addresses, event.metadata
+									);
+								} else {
+									trace!("Peer '{}' is still present", event.peer_id);
+								}
 
-							tokio::spawn(async move {
-								let header = Header::from_stream(&mut event.stream).await.unwrap();
+								if discovered.get(&event.peer_id) != Some(&event.metadata) {
+									discovered.insert(event.peer_id, event.metadata.clone());
+									library_peers.write().await.clear();
 
-								match header {
-									Header::Ping => {
-										debug!("Received ping from peer '{}'", event.peer_id);
+									if let Some(peer) =
+										connected_peers.write().await.get_mut(&event.peer_id)
+									{
+										peer.metadata = Some(event.metadata.clone());
 									}
-									Header::Spacedrop(req) => {
-										info!("Received Spacedrop from peer '{}' for file '{}' with file length '{}'", event.peer_id, req.name, req.size);
-
-										// TODO: Ask the user if they wanna reject/accept it
 
-										// TODO: Deal with binary data. Deal with blocking based on `req.block_size`, etc
-										let mut s = String::new();
-										event.stream.read_to_string(&mut s).await.unwrap();
+									events
+										.send(P2PEvent::DiscoveredPeer {
+											peer_id: event.peer_id,
+											metadata: event.metadata.clone(),
+											addresses: this
+												.dial_order(event.peer_id, event.addresses.clone())
+												.await,
+										})
+										.map_err(|_| {
+											error!("Failed to send event to p2p event stream!")
+										})
+										.ok();
 
-										println!(
-										"Recieved file '{}' with content '{}' through Spacedrop!",
-										req.name, s
-									);
+									if !event.metadata.is_compatible() {
+										warn!(
+											"Peer '{}' is running an incompatible version '{:?}' with protocol version '{:?}'",
+											event.peer_id, event.metadata.version, event.metadata.protocol_version
+										);
 
-										// TODO: Save to the filesystem
+										events
+											.send(P2PEvent::PeerIncompatible {
+												peer_id: event.peer_id,
+												version: event.metadata.version.clone(),
+												protocol_version: event.metadata.protocol_version,
+											})
+											.map_err(|_| {
+												error!("Failed to send event to p2p event stream!")
+											})
+											.ok();
 									}
-									Header::Sync(library_id, len) => {
-										info!("Received Sync events from peer '{}' for library_id '{}' with length '{}'", event.peer_id, library_id, len);
+								}
 
-										let mut buf = vec![0; len as usize]; // TODO: Designed for easily being able to be DOS the current Node
-										event.stream.read_exact(&mut buf).await.unwrap();
+								if !event.metadata.is_compatible() {
+									continue;
+								}
 
-										let mut buf: &[u8] = &buf;
-										let operations = rmp_serde::from_read(&mut buf).unwrap();
+								let shares_library = {
+									let libraries = libraries.read().await;
+									event
+										.metadata
+										.libraries
+										.iter()
+										.any(|id| libraries.contains_key(id))
+								};
+								if shares_library
+									&& !connected_peers.read().await.contains_key(&event.peer_id)
+									&& bootstrapping.insert(event.peer_id)
+								{
+									emit_progress(event.peer_id, PeerBootstrapProgress::Connecting);
+								}
 
-										println!("Received sync events for library '{library_id}': {operations:?}");
+								let is_paired = paired_peers.read().await.contains(&event.peer_id);
+								let is_connected =
+									connected_peers.read().await.contains_key(&event.peer_id);
+								if dial_policy
+									.read()
+									.await
+									.should_dial(&event.peer_id, is_paired)
+									&& discovered_peers.try_dial(
+										event.peer_id,
+										is_connected,
+										&this.discovery,
+										now,
+									) {
+									// The dial can't finish until this loop polls the stream again so it's awaited in the background
+									let this = this.clone();
+									tokio::spawn(async move {
+										this.dial_peer(event.peer_id, event.addresses).await.ok();
+									});
+								}
+							}
+							Event::PeerMetadataChanged(event) => {
+								debug!(
+									"Peer '{}' changed its metadata to: {:?}",
+									event.peer_id, event.metadata
+								);
 
-										events
-											.send(P2PEvent::SyncOperation {
+								discovered.insert(event.peer_id, event.metadata.clone());
+								library_peers.write().await.clear();
+
+								if let Some(peer) =
+									connected_peers.write().await.get_mut(&event.peer_id)
+								{
+									peer.metadata = Some(event.metadata.clone());
+								}
+
+								if !event.metadata.is_compatible() {
+									events
+										.send(P2PEvent::PeerIncompatible {
+											peer_id: event.peer_id,
+											version: event.metadata.version.clone(),
+											protocol_version: event.metadata.protocol_version,
+										})
+										.map_err(|_| {
+											error!("Failed to send event to p2p event stream!")
+										})
+										.ok();
+								}
+
+								events
+									.send(P2PEvent::PeerMetadataChanged {
+										peer_id: event.peer_id,
+										metadata: event.metadata,
+									})
+									.map_err(|_| {
+										error!("Failed to send event to p2p event stream!")
+									})
+									.ok();
+							}
+							Event::PeerMessage(mut event) => {
+								let this = this.clone();
+								let events = events.clone();
+
+								tokio::spawn(async move {
+									// This is held until the stream has been handled
+									let Some(_permit) = this.stream_limits.acquire(event.peer_id, &this.stream_limit).await else {
+										warn!("Rejecting stream from peer '{}' as it has too many streams in flight", event.peer_id);
+										this.reject_stream(event.peer_id, event.stream).await;
+										return;
+									};
+
+									let header = match Header::from_stream(&mut event.stream).await
+									{
+										Ok(header) => header,
+										Err(err) => {
+											warn!(
+												"Received malformed header from peer '{}': {err}",
+												event.peer_id
+											);
+											return;
+										}
+									};
+
+									// This is held until the stream has been handled. Requests are scheduled once it's known which request was sent
+									let _lane = match header.lane() {
+										Some(lane) => Some(this.lanes.enter(lane).await),
+										None => None,
+									};
+
+									match header {
+										Header::Ping => {
+											debug!("Received ping from peer '{}'", event.peer_id);
+										}
+										Header::Spacedrop(req) => {
+											info!("Received Spacedrop from peer '{}' for file '{}' with file length '{}'", event.peer_id, req.name, req.size);
+
+											// TODO: Ask the user if they wanna reject/accept it
+
+											// TODO: Deal with binary data. Deal with blocking based on `req.block_size`, etc
+											let mut s = String::new();
+											if let Err(err) =
+												event.stream.read_to_string(&mut s).await
+											{
+												warn!(
+													"Error reading Spacedrop from peer '{}': {err}",
+													event.peer_id
+												);
+												return;
+											}
+
+											debug!(
+												"Received file '{}' with length '{}' through Spacedrop from peer '{}'",
+												req.name,
+												s.len(),
+												event.peer_id
+											);
+
+											// TODO: Save to the filesystem
+										}
+										Header::Sync(library_id, len) => {
+											info!("Received Sync events from peer '{}' for library_id '{}' with length '{}'", event.peer_id, library_id, len);
+
+											if this
+												.authorize_sync(event.peer_id, library_id)
+												.await
+												.is_err()
+											{
+												return;
+											}
+
+											// Only this stream is dropped so a malformed message doesn't affect the connection or any other stream
+											let signed_operations =
+												match read_sync_operations(&mut event.stream, len)
+													.await
+												{
+													Ok(operations) => operations,
+													Err(err) => {
+														if !matches!(err, SyncMessageError::Io(_)) {
+															this.malformed_sync_messages
+																.fetch_add(1, Ordering::Relaxed);
+														}
+														warn!("Dropping sync events from peer '{}': {err}", event.peer_id);
+														return;
+													}
+												};
+
+											let Some(sync_key) = this.libraries.read().await.get(&library_id).cloned() else {
+												warn!("Dropping sync events from peer '{}' for library '{library_id}' which isn't loaded on this node", event.peer_id);
+												return;
+											};
+
+											let operations = this
+												.verify_operations(
+													event.peer_id,
+													library_id,
+													&sync_key,
+													signed_operations,
+												)
+												.await;
+											let (signed_operations, operations): (Vec<_>, Vec<_>) =
+												this.unseen_operations(operations)
+													.await
+													.into_iter()
+													.unzip();
+											if operations.is_empty() {
+												return;
+											}
+
+											this.queue_relay(
 												library_id,
-												operations,
-											})
-											.ok();
+												event.peer_id,
+												signed_operations,
+											)
+											.await;
+
+											debug!("Received '{}' sync events from peer '{}' for library '{library_id}'", operations.len(), event.peer_id);
+
+											events
+												.send(P2PEvent::SyncOperation {
+													library_id,
+													operations,
+												})
+												.ok();
+										}
+										Header::Request => {
+											let SpaceTimeStream::Unicast(mut stream) = event.stream else {
+												warn!("Received request from peer '{}' on a non-unicast stream", event.peer_id);
+												return;
+											};
+
+											this.respond(event.peer_id, &mut stream).await;
+										}
+										Header::EncryptedRequest => {
+											let SpaceTimeStream::Unicast(stream) = event.stream else {
+												warn!("Received request from peer '{}' on a non-unicast stream", event.peer_id);
+												return;
+											};
+
+											let Some(key) = this.stream_key(event.peer_id).await else {
+												warn!("Received encrypted request from peer '{}' which isn't paired", event.peer_id);
+												return;
+											};
+
+											let mut stream =
+												match EncryptedStream::new(stream, &key, false)
+													.await
+												{
+													Ok(stream) => stream,
+													Err(err) => {
+														warn!("Error opening encrypted stream with peer '{}': {err}", event.peer_id);
+														if !matches!(err, EncryptionError::Io(_)) {
+															this.manager()
+																.disconnect(event.peer_id)
+																.await;
+														}
+														return;
+													}
+												};
+
+											this.respond(event.peer_id, &mut stream).await;
+
+											if stream.is_corrupted() {
+												warn!("Disconnecting from peer '{}' as its request failed to decrypt", event.peer_id);
+												this.manager().disconnect(event.peer_id).await;
+												return;
+											}
+											stream.shutdown().await.ok();
+										}
+									}
+								});
+							}
+							Event::PeerExpired { id, .. } => {
+								debug!("Peer '{id}' expired");
+								discovered.remove(&id);
+								discovered_peers.remove(&id);
+								library_peers.write().await.clear();
+
+								if bootstrapping.remove(&id) {
+									emit_progress(
+										id,
+										PeerBootstrapProgress::Error(
+											"Peer went offline before a connection could be established".into(),
+										),
+									);
+								}
+
+								events
+									.send(P2PEvent::ExpiredPeer { peer_id: id })
+									.map_err(|_| {
+										error!("Failed to send event to p2p event stream!")
+									})
+									.ok();
+							}
+							Event::PeerConnected(event) => {
+								debug!(
+									"Peer '{}' connected through '{:?}'",
+									event.peer_id, event.address
+								);
+								if let Some(address) = event.address {
+									this.last_addresses
+										.write()
+										.await
+										.insert(event.peer_id, address);
+									this.remember_address(event.peer_id, address).await;
+								}
+
+								connected_peers.write().await.insert(
+									event.peer_id,
+									ConnectedPeer {
+										peer_id: event.peer_id,
+										metadata: discovered.get(&event.peer_id).cloned(),
+										nickname: None,
+										name: None,
+										addresses: event.address.into_iter().collect(),
+										transport: event.transport,
+										relayed: event.relayed,
+										connected_at: Utc::now(),
+										connected_for: Duration::ZERO,
+										latency: None,
+										in_flight_streams: 0,
+										missed_pings: 0,
+									},
+								);
+								library_peers.write().await.clear();
+
+								// The bootstrap continues with the initial sync, which is finished in the task below
+								let is_bootstrapping = bootstrapping.remove(&event.peer_id);
+
+								if this
+									.reconnecting
+									.lock()
+									.await
+									.contains(&ReconnectTarget::Peer(event.peer_id))
+								{
+									events
+										.send(P2PEvent::ReconnectedPeer {
+											peer_id: event.peer_id,
+										})
+										.map_err(|_| {
+											error!("Failed to send event to p2p event stream!")
+										})
+										.ok();
+								}
+
+								events
+									.send(P2PEvent::ConnectedPeer {
+										peer_id: event.peer_id,
+										address: event.address,
+										transport: event.transport,
+										relayed: event.relayed,
+									})
+									.map_err(|_| {
+										error!("Failed to send event to p2p event stream!")
+									})
+									.ok();
+
+								tokio::spawn({
+									let this = this.clone();
+									let peer_id = event.peer_id;
+									let is_discovered = discovered.contains_key(&peer_id);
+									async move {
+										if let Err(err) = this.negotiate(peer_id).await {
+											if is_bootstrapping {
+												this.emit_bootstrap_progress(
+													peer_id,
+													PeerBootstrapProgress::Error(err.to_string()),
+												);
+											}
+											return;
+										}
+
+										if is_bootstrapping {
+											this.emit_bootstrap_progress(
+												peer_id,
+												PeerBootstrapProgress::ExchangingMetadata,
+											);
+										}
+
+										// Peers which were dialed by address (or dialed us) haven't been discovered so we must ask them for their metadata
+										if !is_discovered {
+											this.exchange_metadata(peer_id).await;
+										}
+
+										for library_id in this
+											.libraries
+											.read()
+											.await
+											.keys()
+											.copied()
+											.collect::<Vec<_>>()
+										{
+											if this
+												.library_peers(library_id)
+												.await
+												.contains(&peer_id)
+											{
+												this.subscribe(peer_id, library_id).await;
+											}
+										}
+
+										// Retransmit the sync events the peer missed while it was disconnected
+										this.flush_sync_outboxes(peer_id).await;
+
+										// Fetch the operations we missed while disconnected from the peer
+										let result =
+											this.resume_sync(peer_id, is_bootstrapping).await;
+										if is_bootstrapping {
+											this.emit_bootstrap_progress(
+												peer_id,
+												match result {
+													Ok(()) => PeerBootstrapProgress::Done,
+													Err(err) => PeerBootstrapProgress::Error(err),
+												},
+											);
+										}
 									}
+								});
+							}
+							Event::PeerConnectionUpgraded(event) => {
+								debug!(
+									"Connection with peer '{}' upgraded to a direct connection through '{:?}'",
+									event.peer_id, event.address
+								);
+								if let Some(address) = event.address {
+									this.last_addresses
+										.write()
+										.await
+										.insert(event.peer_id, address);
+									this.remember_address(event.peer_id, address).await;
 								}
-							});
+
+								if let Some(peer) =
+									connected_peers.write().await.get_mut(&event.peer_id)
+								{
+									peer.addresses = event.address.into_iter().collect();
+									peer.transport = event.transport;
+									peer.relayed = false;
+								}
+
+								events
+									.send(P2PEvent::ConnectionUpgraded {
+										peer_id: event.peer_id,
+										address: event.address,
+										transport: event.transport,
+									})
+									.map_err(|_| {
+										error!("Failed to send event to p2p event stream!")
+									})
+									.ok();
+							}
+							Event::PeerDisconnected(peer_id) => {
+								debug!("Peer '{peer_id}' disconnected");
+								let peer = connected_peers.write().await.remove(&peer_id);
+								discovered_peers.disconnected(&peer_id);
+								library_peers.write().await.clear();
+								this.peer_versions.write().await.remove(&peer_id);
+								this.verified_peers.write().await.remove(&peer_id);
+								this.stream_limits.remove(&peer_id);
+								this.subscriptions.write().await.remove_peer(&peer_id);
+
+								events
+									.send(P2PEvent::DisconnectedPeer { peer_id })
+									.map_err(|_| {
+										error!("Failed to send event to p2p event stream!")
+									})
+									.ok();
+
+								// `spawn_reconnect` will stop straight away if the peer isn't paired or manually added
+								if !*this.shutdown.borrow() {
+									this.spawn_reconnect(
+										ReconnectTarget::Peer(peer_id),
+										peer.map(|peer| peer.addresses).unwrap_or_default(),
+									)
+									.await;
+								}
+							}
+							Event::AddListenAddr(_) | Event::RemoveListenAddr(_) => {
+								let addresses = this.listen_addrs().await;
+								if addresses != listen_addrs {
+									debug!("Listen addresses changed to: {addresses:?}");
+									listen_addrs = addresses.clone();
+
+									events
+										.send(P2PEvent::ListenAddrsChanged { addresses })
+										.map_err(|_| {
+											error!("Failed to send event to p2p event stream!")
+										})
+										.ok();
+								}
+							}
+							_ => debug!("event: {:?}", event),
+						}
+					}
+
+					if *this.shutdown.borrow() {
+						debug!("Manager event stream closed due to shutdown!");
+						break;
+					}
+
+					error!("Manager event stream closed! Restarting the P2P subsystem...");
+					this.subsystem_down.store(true, Ordering::Relaxed);
+					this.set_last_error("the P2P subsystem stopped unexpectedly".into());
+					events
+						.send(P2PEvent::SubsystemDown)
+						.map_err(|_| error!("Failed to send event to p2p event stream!"))
+						.ok();
+
+					// Every connection belonged to the old manager so they are all gone
+					let peers = connected_peers
+						.write()
+						.await
+						.drain()
+						.map(|(peer_id, peer)| (peer_id, peer.addresses))
+						.collect::<Vec<_>>();
+					library_peers.write().await.clear();
+					this.peer_versions.write().await.clear();
+					this.verified_peers.write().await.clear();
+					for (peer_id, _) in &peers {
+						events
+							.send(P2PEvent::DisconnectedPeer { peer_id: *peer_id })
+							.map_err(|_| error!("Failed to send event to p2p event stream!"))
+							.ok();
+					}
+
+					let mut attempt = 0;
+					stream = loop {
+						tokio::time::sleep(this.reconnect.delay(attempt)).await;
+						if *this.shutdown.borrow() {
+							return;
+						}
+
+						let (app_id, keypair, manager_config) =
+							manager_config(&this.node_config).await;
+						let error = match Manager::new(
+							&app_id,
+							&keypair,
+							manager_config,
+							metadata_fn.clone(),
+						)
+						.await
+						{
+							Ok((manager, stream)) => {
+								*this.manager.write().unwrap_or_else(PoisonError::into_inner) =
+									manager;
+								break stream;
+							}
+							Err(err) => err,
+						};
+
+						attempt += 1;
+						if attempt >= MAX_SUBSYSTEM_RESTARTS {
+							error!("Giving up restarting the P2P subsystem after '{attempt}' attempts: {error}");
+							this.set_last_error(format!(
+								"the P2P subsystem couldn't be restarted: {error}"
+							));
+							events
+								.send(P2PEvent::SubsystemFailed {
+									error: error.to_string(),
+								})
+								.map_err(|_| error!("Failed to send event to p2p event stream!"))
+								.ok();
+							return;
 						}
-						_ => debug!("event: {:?}", event),
+
+						warn!("Error restarting the P2P subsystem (attempt {attempt}/{MAX_SUBSYSTEM_RESTARTS}): {error}");
+					};
+
+					// The node may have been shut down while the new manager was starting
+					if *this.shutdown.borrow() {
+						this.manager().shutdown().await;
+						return;
+					}
+
+					info!(
+						"P2P subsystem restarted. Node '{}' is now listening at addresses: {:?}",
+						this.manager().peer_id(),
+						this.manager().listen_addrs().await
+					);
+					this.subsystem_down.store(false, Ordering::Relaxed);
+					events
+						.send(P2PEvent::SubsystemRestarted)
+						.map_err(|_| error!("Failed to send event to p2p event stream!"))
+						.ok();
+
+					// The advertised metadata must be restored as it's replaced when libraries are loaded
+					this.update_metadata().await;
+					for (peer_id, addresses) in peers {
+						this.spawn_reconnect(ReconnectTarget::Peer(peer_id), addresses)
+							.await;
+					}
+					for addr in this.node_config.get().await.p2p_manual_peers {
+						this.spawn_reconnect(ReconnectTarget::Address(addr), Vec::new())
+							.await;
 					}
 				}
+			}
+		});
+		this.tasks.lock().await.push(event_loop);
 
-				error!(
-					"Manager event stream closed! The core is unstable from this point forward!"
-				);
+		let latency_loop = tokio::spawn({
+			let this = this.clone();
+			let mut shutdown = this.shutdown.subscribe();
+
+			async move {
+				loop {
+					tokio::select! {
+						_ = tokio::time::sleep(this.latency.interval) => {}
+						_ = shutdown.changed() => break,
+					}
+
+					let peers = this
+						.connected_peers
+						.read()
+						.await
+						.keys()
+						.copied()
+						.collect::<Vec<_>>();
+					join_all(
+						peers
+							.into_iter()
+							.map(|peer_id| this.refresh_latency(peer_id)),
+					)
+					.await;
+				}
 			}
 		});
+		this.tasks.lock().await.push(latency_loop);
 
-		// TODO: proper shutdown
-		// https://docs.rs/ctrlc/latest/ctrlc/
-		// https://docs.rs/system_shutdown/latest/system_shutdown/
+		let pairing_loop = tokio::spawn({
+			let this = this.clone();
+			let mut shutdown = this.shutdown.subscribe();
 
-		let this = Arc::new(Self {
-			events: tx,
-			manager,
+			async move {
+				loop {
+					tokio::select! {
+						_ = tokio::time::sleep(PAIRING_EXPIRY_INTERVAL) => {}
+						_ = shutdown.changed() => break,
+					}
+
+					for peer_id in this.pairings.expire().await {
+						debug!("Pairing with peer '{peer_id}' expired before it was confirmed");
+						this.events
+							.send(P2PEvent::PairingExpired { peer_id })
+							.map_err(|_| error!("Failed to send event to p2p event stream!"))
+							.ok();
+					}
+				}
+			}
 		});
+		this.tasks.lock().await.push(pairing_loop);
 
-		// TODO: Probs remove this once connection timeout/keepalive are working correctly
-		tokio::spawn({
+		let metadata_loop = tokio::spawn({
 			let this = this.clone();
+			let mut shutdown = this.shutdown.subscribe();
+
 			async move {
 				loop {
-					tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-					this.ping().await;
+					tokio::select! {
+						_ = this.metadata_changed.notified() => {}
+						_ = shutdown.changed() => break,
+					}
+
+					// Every change made while waiting is sent together as only the latest metadata is sent
+					tokio::select! {
+						_ = tokio::time::sleep(METADATA_PUSH_DEBOUNCE) => {}
+						_ = shutdown.changed() => break,
+					}
+
+					this.push_metadata().await;
+				}
+			}
+		});
+		this.tasks.lock().await.push(metadata_loop);
+
+		let relay_loop = tokio::spawn({
+			let this = this.clone();
+			let mut shutdown = this.shutdown.subscribe();
+
+			async move {
+				loop {
+					tokio::select! {
+						_ = this.relay_queued.notified() => {}
+						_ = shutdown.changed() => break,
+					}
+
+					let queued = std::mem::take(&mut *this.relay_queue.lock().await);
+					for (library_id, from, operations) in queued {
+						this.relay_sync_operations(library_id, from, operations)
+							.await;
+					}
 				}
 			}
 		});
+		this.tasks.lock().await.push(relay_loop);
+
+		// This runs alongside mDNS so known peers are dialed at their last-known addresses without waiting to be discovered
+		this.reconnect_known_peers().await;
 
 		// TODO(@Oscar): Remove this in the future once i'm done using it for testing
 		if std::env::var("SPACEDROP_DEMO").is_ok() {
@@ -185,7 +1356,7 @@ impl P2PManager {
 				async move {
 					tokio::time::sleep(std::time::Duration::from_secs(5)).await;
 					let mut connected = this
-						.manager
+						.manager()
 						.get_connected_peers()
 						.await
 						.unwrap()
@@ -232,44 +1403,2536 @@ impl P2PManager {
 			// });
 		}
 
-		(this, rx)
+		Ok((this, rx))
+	}
+
+	/// manager returns the underlying [Manager]. This shouldn't be held onto as it's replaced if the P2P subsystem is restarted.
+	pub fn manager(&self) -> Arc<Manager<PeerMetadata>> {
+		self.manager
+			.read()
+			.unwrap_or_else(PoisonError::into_inner)
+			.clone()
 	}
 
 	pub fn subscribe(&self) -> broadcast::Receiver<P2PEvent> {
 		self.events.subscribe()
 	}
 
-	#[allow(unused)] // TODO: Remove `allow(unused)` once integrated
-	pub async fn broadcast_sync_events(&self, library_id: Uuid, event: Vec<CRDTOperation>) {
-		let mut buf = rmp_serde::to_vec_named(&event).unwrap(); // TODO: Error handling
-		let mut head_buf = Header::Sync(library_id, buf.len() as u32).to_bytes(); // Max Sync payload is like 4GB
-		head_buf.append(&mut buf);
+	/// Shutdown the P2P system. This stops the background tasks, unregisters the mDNS service and closes all connections.
+	/// Calling this more than once is a no-op.
+	pub async fn shutdown(&self) {
+		if self.shutdown.send_replace(true) {
+			return;
+		}
 
-		debug!("broadcasting sync events. payload_len={}", buf.len());
+		debug!("Shutting down P2P manager...");
+		self.manager().shutdown().await;
 
-		self.manager.broadcast(head_buf).await;
+		for mut task in self.tasks.lock().await.drain(..) {
+			match tokio::time::timeout(SHUTDOWN_TIMEOUT, &mut task).await {
+				Ok(result) => {
+					result
+						.map_err(|err| error!("Error joining P2P task during shutdown: {err}"))
+						.ok();
+				}
+				Err(_) => {
+					warn!("P2P task didn't stop within '{SHUTDOWN_TIMEOUT:?}' of shutting down, aborting it");
+					task.abort();
+				}
+			}
+		}
 	}
 
-	pub async fn ping(&self) {
-		self.manager.broadcast(Header::Ping.to_bytes()).await;
+	pub fn batch_config(&self) -> &BatchConfig {
+		&self.batch
 	}
 
-	pub async fn big_bad_spacedrop(&self, peer_id: PeerId, path: PathBuf) {
-		let mut stream = self.manager.stream(peer_id).await.unwrap(); // TODO: handle providing incorrect peer id
+	/// sync_queue creates the queue which the batches of sync events created in a library wait in before being sent to other peers.
+	pub async fn sync_queue(&self, library_id: Uuid) -> (SyncQueueSender, SyncQueueReceiver) {
+		let depth = self
+			.sync_queue_depths
+			.write()
+			.await
+			.entry(library_id)
+			.or_default()
+			.clone();
 
-		let file = File::open(&path).await.unwrap();
-		let metadata = file.metadata().await.unwrap();
-		let mut reader = BufReader::new(file);
+		super::sync_queue(library_id, &self.sync_queue, depth)
+	}
 
-		stream
-			.write_all(
-				&Header::Spacedrop(TransferRequest {
-					name: path.file_name().unwrap().to_str().unwrap().to_string(), // TODO: Encode this as bytes instead
-					size: metadata.len(),
-					block_size: BlockSize::from_size(metadata.len()),
-				})
-				.to_bytes(),
-			)
+	/// sync_queue_stats returns how many batches of sync events are waiting to be sent in each library.
+	pub async fn sync_queue_stats(&self) -> Vec<SyncQueueStats> {
+		let mut stats = self
+			.sync_queue_depths
+			.read()
+			.await
+			.iter()
+			.map(|(library_id, depth)| SyncQueueStats {
+				library_id: *library_id,
+				depth: depth.load(Ordering::Relaxed) as u32,
+				capacity: self.sync_queue.capacity as u32,
+			})
+			.collect::<Vec<_>>();
+		stats.sort_by_key(|stats| stats.library_id);
+		stats
+	}
+
+	/// lane_stats returns how the control and bulk lanes are being scheduled so the effect of bulk transfers on sync can be checked.
+	pub fn lane_stats(&self) -> LaneStats {
+		self.lanes.stats()
+	}
+
+	/// traffic returns the data exchanged with each peer since the P2P subsystem was last started.
+	pub async fn traffic(&self) -> P2PTraffic {
+		let stats = self.manager().stats();
+		let names = self
+			.connected_peers()
+			.await
+			.into_iter()
+			.map(|peer| (peer.peer_id, peer.name))
+			.collect::<HashMap<_, _>>();
+		let known_peers = self.node_config.get().await.p2p_known_peers;
+
+		let mut peers = stats
+			.peers
+			.into_iter()
+			.map(|(peer_id, stats)| PeerTraffic {
+				peer_id,
+				name: names.get(&peer_id).cloned().flatten().or_else(|| {
+					known_peers
+						.get(&peer_id)
+						.and_then(|known| known.nickname.clone())
+				}),
+				traffic: stats.into(),
+			})
+			.collect::<Vec<_>>();
+		peers.sort_by_key(|peer| {
+			std::cmp::Reverse(
+				peer.traffic
+					.bytes_sent
+					.saturating_add(peer.traffic.bytes_received),
+			)
+		});
+
+		P2PTraffic {
+			totals: stats.totals.into(),
+			peers,
+		}
+	}
+
+	pub fn set_library_manager(&self, library_manager: Arc<LibraryManager>) {
+		if self.library_manager.set(library_manager).is_err() {
+			warn!("Attempted to set the 'LibraryManager' on the 'P2PManager' more than once!");
+		}
+	}
+
+	pub fn library_manager(&self) -> Option<&LibraryManager> {
+		self.library_manager.get().map(|v| v.as_ref())
+	}
+
+	/// returns the addresses other devices can use to reach this node, sorted so they are stable between calls.
+	pub async fn listen_addrs(&self) -> Vec<SocketAddr> {
+		let mut addresses = self
+			.manager()
+			.listen_addrs()
+			.await
+			.into_iter()
+			.filter(is_shareable_addr)
+			.collect::<Vec<_>>();
+		addresses.sort();
+		addresses
+	}
+
+	/// status returns a snapshot of the P2P subsystem from the state the manager already has, so it doesn't wait on the network.
+	pub async fn status(&self) -> P2PStatus {
+		let listen_addrs = self.listen_addrs().await;
+		let config = self.node_config.get().await;
+
+		P2PStatus {
+			listening: !self.subsystem_down.load(Ordering::Relaxed)
+				&& !self.manager().is_shutdown()
+				&& !listen_addrs.is_empty(),
+			listen_addrs,
+			discovery_enabled: config.p2p_discovery_enabled,
+			connected_peer_count: u32::try_from(self.connected_peers.read().await.len())
+				.unwrap_or(u32::MAX),
+			known_peer_count: u32::try_from(config.p2p_known_peers.len()).unwrap_or(u32::MAX),
+			last_error: self
+				.last_error
+				.lock()
+				.unwrap_or_else(PoisonError::into_inner)
+				.clone(),
+			malformed_sync_messages: u32::try_from(
+				self.malformed_sync_messages.load(Ordering::Relaxed),
+			)
+			.unwrap_or(u32::MAX),
+		}
+	}
+
+	fn set_last_error(&self, error: String) {
+		*self
+			.last_error
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner) = Some(error);
+	}
+
+	/// peer_latency returns the smoothed round-trip time to a connected peer.
+	/// This will be `None` if the peer isn't connected or hasn't responded to a ping yet.
+	pub async fn peer_latency(&self, peer_id: PeerId) -> Option<Duration> {
+		self.connected_peers
+			.read()
+			.await
+			.get(&peer_id)
+			.and_then(|peer| peer.latency)
+	}
+
+	/// refresh_latency will ping a peer and update its latency with the time taken to respond.
+	async fn refresh_latency(&self, peer_id: PeerId) {
+		let version = match self.negotiate(peer_id).await {
+			Ok(version) => version,
+			Err(err) => {
+				debug!("Error measuring latency to peer '{peer_id}': {err}");
+				self.missed_ping(peer_id).await;
+				return;
+			}
+		};
+
+		// Peers which understand `TimedPing` echo the timestamp so the round-trip can be measured from the response itself
+		let start = Instant::now();
+		let request = if version >= TIMED_PING_PROTO_VERSION {
+			Request::TimedPing {
+				sent_at: ping_timestamp(),
+			}
+		} else {
+			Request::Ping
+		};
+		let sample = match self
+			.send_to_timeout(peer_id, request, self.latency.interval)
+			.await
+		{
+			Ok(Response::TimedPong { sent_at }) => {
+				Duration::from_micros(ping_timestamp().saturating_sub(sent_at))
+			}
+			Ok(Response::Pong) => start.elapsed(),
+			Ok(response) => {
+				warn!(
+					"Peer '{peer_id}' responded to ping with an unexpected response: {response:?}"
+				);
+				return;
+			}
+			Err(err) => {
+				debug!("Error measuring latency to peer '{peer_id}': {err}");
+				self.missed_ping(peer_id).await;
+				return;
+			}
+		};
+
+		if let Some(peer) = self.connected_peers.write().await.get_mut(&peer_id) {
+			peer.missed_pings = 0;
+			peer.latency = Some(self.latency.smooth(peer.latency, sample));
+			trace!(
+				"Latency to peer '{peer_id}' is '{:?}' (sample '{sample:?}')",
+				peer.latency
+			);
+		}
+	}
+
+	/// missed_ping records that a peer didn't respond to a ping, disconnecting it once it has missed [LatencyConfig::max_missed_pings] in a row.
+	/// A `DisconnectedPeer` event is emitted once the connection is closed.
+	async fn missed_ping(&self, peer_id: PeerId) {
+		let missed = match self.connected_peers.write().await.get_mut(&peer_id) {
+			Some(peer) => {
+				peer.missed_pings += 1;
+				peer.missed_pings
+			}
+			None => return,
+		};
+
+		if self.latency.is_dead(missed) {
+			warn!(
+				"Peer '{peer_id}' hasn't responded to '{missed}' pings in a row, disconnecting it"
+			);
+			self.manager().disconnect(peer_id).await;
+		}
+	}
+
+	/// returns the peers which currently have an active connection with this node.
+	/// Nicknames are read from the node config every time so a change is shown straight away.
+	pub async fn connected_peers(&self) -> Vec<ConnectedPeer> {
+		let known_peers = self.node_config.get().await.p2p_known_peers;
+		let now = Utc::now();
+
+		self.connected_peers
+			.read()
+			.await
+			.values()
+			.cloned()
+			.map(|mut peer| {
+				peer.in_flight_streams = self.peer_in_flight_streams(&peer.peer_id);
+				peer.connected_for = (now - peer.connected_at).to_std().unwrap_or_default();
+				peer.nickname = known_peers
+					.get(&peer.peer_id)
+					.and_then(|known| known.nickname.clone());
+				peer.name = peer
+					.nickname
+					.clone()
+					.or_else(|| peer.metadata.as_ref().map(|metadata| metadata.name.clone()));
+				peer
+			})
+			.collect()
+	}
+
+	/// peer_in_flight_streams returns the number of streams from the peer which are currently being handled.
+	/// A peer which stays at the [StreamLimitConfig] `max_in_flight` is saturated and its new streams are being queued or rejected.
+	pub fn peer_in_flight_streams(&self, peer_id: &PeerId) -> u32 {
+		u32::try_from(self.stream_limits.in_flight(peer_id, &self.stream_limit)).unwrap_or(u32::MAX)
+	}
+
+	/// send_to will send a request to a single connected peer and wait for it's response.
+	/// This will return `P2PError::Timeout` if the peer doesn't respond within `DEFAULT_REQUEST_TIMEOUT`.
+	/// Every request is sent over its own stream so concurrent requests to the same peer are never mixed up or stuck behind each other.
+	pub async fn send_to(&self, peer_id: PeerId, request: Request) -> Result<Response, P2PError> {
+		self.send_to_timeout(peer_id, request, DEFAULT_REQUEST_TIMEOUT)
+			.await
+	}
+
+	/// send_to_timeout is the same as `send_to` but with a custom timeout for the response.
+	/// The request won't be sent if the peer is running a protocol version which doesn't support it.
+	/// Requests to paired peers are encrypted with the key established during pairing.
+	pub async fn send_to_timeout(
+		&self,
+		peer_id: PeerId,
+		request: Request,
+		timeout: Duration,
+	) -> Result<Response, P2PError> {
+		let version = self.negotiate(peer_id).await?;
+		if request.min_proto_version() > version {
+			return Err(P2PError::UnsupportedRequest(version));
+		}
+
+		let lane = request.lane();
+		let _lane = self.lanes.enter(lane).await;
+		let during_bulk = self.lanes.bulk_in_flight() > 0;
+		let sent_at = Instant::now();
+
+		// Pairing requests are never encrypted as the peer may have discarded the key of a previous pairing with us
+		let is_pairing = matches!(
+			request,
+			Request::PairingStart { .. }
+				| Request::PairingReveal { .. }
+				| Request::PairingConfirm { .. }
+		);
+		let key = if version >= ENCRYPTED_REQUEST_PROTO_VERSION && !is_pairing {
+			self.stream_key(peer_id).await
+		} else {
+			None
+		};
+		let result = match key {
+			Some(key) => {
+				self.encrypted_request(peer_id, &request, &key, timeout)
+					.await
+			}
+			None => self.request(peer_id, &request, timeout).await,
+		};
+
+		if lane == Lane::Control && result.is_ok() {
+			self.lanes
+				.record_control_latency(sent_at.elapsed(), during_bulk);
+		}
+		result
+	}
+
+	/// send_to_streaming is the same as `send_to_timeout` for requests which stream their response, see [Request::is_streaming].
+	/// Each frame is yielded as soon as it's received and `timeout` applies to each frame rather than the whole response. Any other request yields its single response.
+	pub fn send_to_streaming(
+		&self,
+		peer_id: PeerId,
+		request: Request,
+		timeout: Duration,
+	) -> impl Stream<Item = Result<Response, P2PError>> + Send + '_ {
+		async_stream::try_stream! {
+			if request.is_streaming() {
+				let _lane = self.lanes.enter(request.lane()).await;
+				let mut stream = self.open_request_stream(peer_id, &request).await?;
+				let compression = self.compression_for(peer_id).await;
+				write_message_with_compression(&mut stream, &request, &compression).await?;
+
+				while let Some(frame) = read_frame(&mut stream, timeout).await? {
+					yield frame;
+				}
+				stream.shutdown().await.ok();
+			} else {
+				yield self.send_to_timeout(peer_id, request, timeout).await?;
+			}
+		}
+	}
+
+	/// open_request_stream opens a stream to the peer and writes the header for the request. The stream is encrypted if the peer is paired with this node.
+	async fn open_request_stream(
+		&self,
+		peer_id: PeerId,
+		request: &Request,
+	) -> Result<Box<dyn RequestStream>, P2PError> {
+		let version = self.negotiate(peer_id).await?;
+		if request.min_proto_version() > version {
+			return Err(P2PError::UnsupportedRequest(version));
+		}
+
+		let mut stream = self
+			.manager()
+			.stream(peer_id)
+			.await
+			.map_err(|_| P2PError::PeerNotConnected(peer_id))?;
+
+		let key = if version >= ENCRYPTED_REQUEST_PROTO_VERSION {
+			self.stream_key(peer_id).await
+		} else {
+			None
+		};
+		let Some(key) = key else {
+			stream.write_all(&Header::Request.to_bytes()).await?;
+			return Ok(Box::new(stream));
+		};
+
+		stream
+			.write_all(&Header::EncryptedRequest.to_bytes())
+			.await?;
+		match EncryptedStream::new(stream, &key, true).await {
+			Ok(stream) => Ok(Box::new(stream)),
+			Err(err) => {
+				if !matches!(err, EncryptionError::Io(_)) {
+					warn!("Disconnecting from peer '{peer_id}' as the encrypted stream couldn't be opened: {err}");
+					self.manager().disconnect(peer_id).await;
+				}
+				Err(err.into())
+			}
+		}
+	}
+
+	/// request will send a request to a peer without checking its protocol version.
+	async fn request(
+		&self,
+		peer_id: PeerId,
+		request: &Request,
+		timeout: Duration,
+	) -> Result<Response, P2PError> {
+		let mut stream = self
+			.manager()
+			.stream(peer_id)
+			.await
+			.map_err(|_| P2PError::PeerNotConnected(peer_id))?;
+
+		let compression = self.compression_for(peer_id).await;
+		let result = send_request(&mut stream, request, &compression, timeout).await;
+		stream.close().await.ok();
+		result
+	}
+
+	/// encrypted_request is the same as `request` but the stream is encrypted with the peer's [StreamKey].
+	/// The connection is closed if the response fails to decrypt as the peer or something in between is misbehaving.
+	async fn encrypted_request(
+		&self,
+		peer_id: PeerId,
+		request: &Request,
+		key: &StreamKey,
+		timeout: Duration,
+	) -> Result<Response, P2PError> {
+		let mut stream = self
+			.manager()
+			.stream(peer_id)
+			.await
+			.map_err(|_| P2PError::PeerNotConnected(peer_id))?;
+		stream
+			.write_all(&Header::EncryptedRequest.to_bytes())
+			.await?;
+
+		let mut stream = match EncryptedStream::new(stream, key, true).await {
+			Ok(stream) => stream,
+			Err(err) => {
+				if !matches!(err, EncryptionError::Io(_)) {
+					warn!("Disconnecting from peer '{peer_id}' as the encrypted stream couldn't be opened: {err}");
+					self.manager().disconnect(peer_id).await;
+				}
+				return Err(err.into());
+			}
+		};
+
+		let compression = self.compression_for(peer_id).await;
+		let result = exchange(&mut stream, request, &compression, timeout).await;
+		if stream.is_corrupted() {
+			warn!("Disconnecting from peer '{peer_id}' as its response failed to decrypt");
+			self.manager().disconnect(peer_id).await;
+		} else {
+			stream.shutdown().await.ok();
+		}
+		result
+	}
+
+	/// respond reads a request from the stream, handles it and writes the response back.
+	/// The request is scheduled on its [Lane] until the response has been written. Requests which stream their response are written a frame at a time.
+	/// Handling a request which doesn't stream its response is bounded by `request_handler_timeout`.
+	async fn respond(&self, peer_id: PeerId, stream: &mut (impl AsyncRead + AsyncWrite + Unpin)) {
+		let mut lane = None;
+		respond_with_reply(
+			peer_id,
+			stream,
+			&self.compression_for(peer_id).await,
+			self.max_request_size,
+			|peer_id, request| {
+				let lane = &mut lane;
+				async move {
+					debug!("Received request '{request:?}' from peer '{peer_id}'");
+					*lane = Some(self.lanes.enter(request.lane()).await);
+					Ok(if request.is_streaming() {
+						Reply::Stream(request.handle_stream(self, peer_id))
+					} else {
+						Reply::Single(
+							with_handler_timeout(
+								self.request_handler_timeout,
+								request.handle(self, peer_id),
+							)
+							.await?,
+						)
+					})
+				}
+			},
+		)
+		.await
+	}
+
+	/// reject_stream is called instead of handling a stream when the peer has too many streams in flight.
+	/// Requests are responded to with a [Response::Error] so the peer doesn't have to wait for a timeout. Other streams are dropped.
+	async fn reject_stream(&self, peer_id: PeerId, mut stream: SpaceTimeStream) {
+		tokio::time::timeout(DEFAULT_REQUEST_TIMEOUT, async {
+			if let (Ok(Header::Request), SpaceTimeStream::Unicast(stream)) =
+				(Header::from_stream(&mut stream).await, &mut stream)
+			{
+				respond_with(
+					peer_id,
+					stream,
+					&self.compression_for(peer_id).await,
+					self.max_request_size,
+					|_, _| async { Err(P2PError::TooManyStreams) },
+				)
+				.await;
+			}
+		})
+		.await
+		.ok();
+	}
+
+	/// authorize checks the peer is allowed to access the data of a library. Only peers which are paired with this node can access a library it is a member of.
+	/// The same error is returned whether or not the library exists so unpaired peers can't discover which libraries this node has.
+	pub(super) async fn authorize(
+		&self,
+		peer_id: PeerId,
+		library_id: Uuid,
+	) -> Result<(), Response> {
+		if self.paired_peers.read().await.contains(&peer_id)
+			&& self.libraries.read().await.contains_key(&library_id)
+		{
+			return Ok(());
+		}
+
+		warn!("Rejecting request from peer '{peer_id}' for library '{library_id}' as it isn't authorized");
+		Err(Response::Error(format!(
+			"unauthorized: peer is not paired with library '{library_id}'"
+		)))
+	}
+
+	/// verify_peer establishes which of the libraries loaded on this node the peer is a member of and caches it for the connection.
+	async fn verify_peer(&self, peer_id: PeerId) -> VerifiedPeer {
+		let peer = VerifiedPeer::new(
+			peer_id,
+			self.paired_peers.read().await.contains(&peer_id),
+			self.libraries.read().await.keys().copied(),
+		);
+		self.verified_peers
+			.write()
+			.await
+			.insert(peer_id, peer.clone());
+		peer
+	}
+
+	/// authorize_sync checks the peer has been verified as a member of the library, and that sync is enabled for it, before sync operations are exchanged with the peer.
+	/// The peer is verified now if the cache was cleared since its handshake.
+	pub(super) async fn authorize_sync(
+		&self,
+		peer_id: PeerId,
+		library_id: Uuid,
+	) -> Result<(), P2PError> {
+		let peer = match self.verified_peers.read().await.get(&peer_id).cloned() {
+			Some(peer) => peer,
+			None => self.verify_peer(peer_id).await,
+		};
+
+		authorize_sync(&peer, &*self.sync_disabled.read().await, library_id).map_err(|err| {
+			warn!("Dropping sync operations from peer '{peer_id}': {err}");
+			err
+		})
+	}
+
+	/// set_sync_enabled will start or stop sending and accepting the sync operations of a library. This takes effect for the next operation, so nothing has to be restarted.
+	/// Operations created while sync is disabled aren't sent once it's enabled again.
+	pub async fn set_sync_enabled(&self, library_id: Uuid, enabled: bool) {
+		let mut sync_disabled = self.sync_disabled.write().await;
+		if enabled {
+			sync_disabled.remove(&library_id);
+		} else {
+			sync_disabled.insert(library_id);
+		}
+	}
+
+	/// sync_enabled returns if the sync operations of a library are sent to and accepted from other peers.
+	pub async fn sync_enabled(&self, library_id: Uuid) -> bool {
+		!self.sync_disabled.read().await.contains(&library_id)
+	}
+
+	/// compression_for returns how payloads sent to the peer are compressed.
+	/// They are only compressed if the peer has advertised in its metadata that it can decode them, so peers built without the `compress` feature keep working.
+	async fn compression_for(&self, peer_id: PeerId) -> Compression {
+		if self.supports_compression(peer_id).await {
+			self.compression.clone()
+		} else {
+			Compression::uncompressed()
+		}
+	}
+
+	/// supports_compression checks if the peer can decode compressed payloads. This is `false` until its metadata is known.
+	async fn supports_compression(&self, peer_id: PeerId) -> bool {
+		self.connected_peers
+			.read()
+			.await
+			.get(&peer_id)
+			.and_then(|peer| peer.metadata.as_ref())
+			.map_or(false, |metadata| metadata.compression)
+	}
+
+	/// stream_key returns the key established with the peer during pairing. `None` if the peer isn't paired.
+	async fn stream_key(&self, peer_id: PeerId) -> Option<StreamKey> {
+		self.node_config
+			.get()
+			.await
+			.p2p_stream_keys
+			.get(&peer_id)
+			.cloned()
+	}
+
+	/// negotiate will exchange protocol versions with a peer if it hasn't been done already and return the version both nodes understand.
+	/// The connection is closed if the peer's version isn't compatible with this node.
+	async fn negotiate(&self, peer_id: PeerId) -> Result<u16, P2PError> {
+		if let Some(version) = self.peer_versions.read().await.get(&peer_id) {
+			return Ok(*version);
+		}
+
+		let request = Request::Hello {
+			proto_version: PROTO_VERSION,
+		};
+		let result = match self
+			.request(peer_id, &request, DEFAULT_REQUEST_TIMEOUT)
+			.await?
+		{
+			Response::Hello { proto_version } if proto_version >= MIN_PROTO_VERSION => {
+				Ok(proto_version.min(PROTO_VERSION))
+			}
+			Response::Hello { proto_version } => Err(P2PError::IncompatibleVersion(proto_version)),
+			// Peers which don't understand `Hello` are older than the first version
+			Response::Error(err) => Err(P2PError::Remote(err)),
+			_ => Err(P2PError::UnexpectedResponse),
+		};
+
+		match result {
+			Ok(version) => {
+				debug!("Negotiated protocol version '{version}' with peer '{peer_id}'");
+				self.peer_versions.write().await.insert(peer_id, version);
+				self.verify_peer(peer_id).await;
+				Ok(version)
+			}
+			Err(err) => {
+				warn!("Disconnecting from peer '{peer_id}' as its protocol version is incompatible: {err}");
+				if let P2PError::IncompatibleVersion(_) = err {
+					self.emit_dial_failed(peer_id, DialError::Incompatible);
+				}
+				self.manager().disconnect(peer_id).await;
+				Err(err)
+			}
+		}
+	}
+
+	/// handle_hello will record the protocol version sent by a peer and respond with our own.
+	pub(super) async fn handle_hello(&self, peer_id: PeerId, proto_version: u16) -> Response {
+		if proto_version < MIN_PROTO_VERSION {
+			warn!("Rejecting peer '{peer_id}' running protocol version '{proto_version}' which is older than the minimum supported version '{MIN_PROTO_VERSION}'");
+			return Response::Error(format!(
+				"protocol version '{proto_version}' is not supported. The minimum supported version is '{MIN_PROTO_VERSION}'"
+			));
+		}
+
+		self.peer_versions
+			.write()
+			.await
+			.insert(peer_id, proto_version.min(PROTO_VERSION));
+		self.verify_peer(peer_id).await;
+		Response::Hello {
+			proto_version: PROTO_VERSION,
+		}
+	}
+
+	/// handle_sync_batch will apply a batch of sync events sent with reliable sync and acknowledge the highest contiguous batch applied from the peer.
+	/// Batches which were already applied are acknowledged without being applied again, and batches which arrive before an earlier one are held until it has been applied.
+	pub(super) async fn handle_sync_batch(
+		&self,
+		peer_id: PeerId,
+		library_id: Uuid,
+		epoch: Uuid,
+		sequence: u64,
+		operations: Vec<SignedOperation>,
+	) -> Response {
+		let Some(sync_key) = self.libraries.read().await.get(&library_id).cloned() else {
+			return Response::Error(format!("library '{library_id}' isn't loaded on this node"));
+		};
+
+		let Some(library_manager) = self.library_manager() else {
+			return Response::Error("node is not ready".into());
+		};
+		let Some(library) = library_manager.get_ctx(library_id).await else {
+			return Response::Error(format!("library '{library_id}' isn't loaded on this node"));
+		};
+
+		// This is held while the batches are applied so the batches from a peer are never applied concurrently
+		let mut inbox = self.sync_inbox.lock().await;
+		match inbox.receive(library_id, peer_id, epoch, sequence) {
+			SyncBatchAction::Apply => {
+				let mut next = Some((epoch, sequence, operations));
+				while let Some((epoch, sequence, operations)) = next {
+					let mut applied = Vec::new();
+					let result = self
+						.apply_sync_batch(
+							&library,
+							peer_id,
+							library_id,
+							&sync_key,
+							operations,
+							&mut applied,
+						)
+						.await;
+					self.queue_relay(library_id, peer_id, applied).await;
+
+					if let Err(err) = result {
+						// The batch isn't acknowledged so it will be retransmitted
+						error!("Error applying sync batch '{sequence}' from peer '{peer_id}' for library '{library_id}': {err}");
+						break;
+					}
+
+					inbox.applied(library_id, peer_id, epoch, sequence);
+					next = inbox.next_held(library_id, peer_id);
+				}
+			}
+			SyncBatchAction::Duplicate => {
+				debug!("Ignoring duplicate sync batch '{sequence}' from peer '{peer_id}' for library '{library_id}'");
+			}
+			SyncBatchAction::Gap => {
+				if inbox.hold(library_id, peer_id, epoch, sequence, operations) {
+					debug!("Received sync batch '{sequence}' from peer '{peer_id}' for library '{library_id}' out of order, holding it until the earlier batches arrive");
+				} else {
+					debug!("Received sync batch '{sequence}' from peer '{peer_id}' for library '{library_id}' out of order, waiting for it to be retransmitted after the earlier batches");
+				}
+			}
+		}
+
+		Response::SyncAck {
+			applied: inbox.highest_applied(library_id, peer_id),
+		}
+	}
+
+	/// apply_sync_batch will apply the operations in a batch which the peer signed, in the order they were created.
+	/// The operations which are applied are added to `applied`, even if a later one fails, so they can be relayed.
+	async fn apply_sync_batch(
+		&self,
+		library: &Library,
+		peer_id: PeerId,
+		library_id: Uuid,
+		sync_key: &SyncKey,
+		operations: Vec<SignedOperation>,
+		applied: &mut Vec<SignedOperation>,
+	) -> prisma_client_rust::Result<()> {
+		for (signed, op) in self
+			.verify_operations(peer_id, library_id, sync_key, operations)
+			.await
+		{
+			// Operations are only remembered once they're applied so a retransmitted batch still applies the ones which failed
+			if self.seen_operations.lock().await.contains(&op.id) {
+				continue;
+			}
+
+			let id = op.id;
+			library.sync.ingest_op(op).await?;
+			self.seen_operations.lock().await.insert(id);
+			applied.push(signed);
+		}
+
+		Ok(())
+	}
+
+	/// flush_sync_outbox will send the batches of sync events which the peer hasn't acknowledged in order.
+	/// This stops at the first batch which isn't acknowledged so the rest are retransmitted after it next time.
+	async fn flush_sync_outbox(&self, library_id: Uuid, peer_id: PeerId) {
+		let Some(outbox) = self
+			.sync_outboxes
+			.read()
+			.await
+			.get(&(library_id, peer_id))
+			.cloned()
+		else {
+			return;
+		};
+
+		let mut outbox = outbox.lock().await;
+		let epoch = outbox.epoch();
+		for (sequence, operations) in outbox.pending() {
+			let request = Request::SyncBatch {
+				library_id,
+				epoch,
+				sequence,
+				operations,
+			};
+
+			match self.send_to(peer_id, request).await {
+				Ok(Response::SyncAck { applied }) => {
+					outbox.ack(applied);
+					if applied < sequence {
+						debug!("Peer '{peer_id}' hasn't applied sync batch '{sequence}' for library '{library_id}', it will be retransmitted");
+						break;
+					}
+				}
+				Ok(response) => {
+					warn!("Unexpected response to sync batch from peer '{peer_id}': {response:?}");
+					break;
+				}
+				Err(err) => {
+					debug!("Error sending sync batch '{sequence}' to peer '{peer_id}' for library '{library_id}', it will be retransmitted: {err}");
+					break;
+				}
+			}
+		}
+	}
+
+	/// flush_sync_outboxes will retransmit the unacknowledged sync events in every library to the peer.
+	async fn flush_sync_outboxes(&self, peer_id: PeerId) {
+		let libraries = self
+			.sync_outboxes
+			.read()
+			.await
+			.keys()
+			.filter(|(_, id)| *id == peer_id)
+			.map(|(library_id, _)| *library_id)
+			.collect::<Vec<_>>();
+
+		for library_id in libraries {
+			self.flush_sync_outbox(library_id, peer_id).await;
+		}
+	}
+
+	/// handle_sync_operations returns the operations in the library which are newer than the peer's checkpoint, oldest first.
+	/// At most [MAX_SYNC_OPERATIONS_PER_RESPONSE] operations are returned and `more` is set if the peer should ask again.
+	/// If `with_total` is set the response also includes how many operations are newer than the checkpoint, see [Response::SyncOperationsWithTotal].
+	pub(super) async fn handle_sync_operations(
+		&self,
+		library_id: Uuid,
+		since: SyncCheckpoint,
+		with_total: bool,
+	) -> Response {
+		let Some(sync_key) = self.libraries.read().await.get(&library_id).cloned() else {
+			return Response::Error(format!("library '{library_id}' isn't loaded on this node"));
+		};
+
+		let Some(library_manager) = self.library_manager() else {
+			return Response::Error("node is not ready".into());
+		};
+		let Some(library) = library_manager.get_ctx(library_id).await else {
+			return Response::Error(format!("library '{library_id}' isn't loaded on this node"));
+		};
+
+		let mut operations = match library.sync.get_ops().await {
+			Ok(operations) => operations
+				.into_iter()
+				.filter(|op| !since.contains(op))
+				.collect::<Vec<_>>(),
+			Err(err) => {
+				error!("Error reading sync operations for library '{library_id}': {err}");
+				return Response::Error("error reading sync operations".into());
+			}
+		};
+
+		let total = operations.len() as u64;
+		let more = operations.len() > MAX_SYNC_OPERATIONS_PER_RESPONSE;
+		operations.truncate(MAX_SYNC_OPERATIONS_PER_RESPONSE);
+
+		let Some(keypair) = self.library_keypair(library_id).await else {
+			return Response::Error("error signing sync operations".into());
+		};
+
+		match operations
+			.iter()
+			.map(|op| SignedOperation::sign(&sync_key, &keypair, library_id, op))
+			.collect::<Result<Vec<_>, _>>()
+		{
+			Ok(operations) if with_total => Response::SyncOperationsWithTotal {
+				operations,
+				more,
+				total,
+			},
+			Ok(operations) => Response::SyncOperations { operations, more },
+			Err(err) => {
+				error!("Error signing sync operations for library '{library_id}': {err}");
+				Response::Error("error signing sync operations".into())
+			}
+		}
+	}
+
+	/// handle_subscribe will start sending the sync events of the library to the peer. The peer has been authorized so it's a member of the library.
+	pub(super) async fn handle_subscribe(&self, peer_id: PeerId, library_id: Uuid) -> Response {
+		if self
+			.subscriptions
+			.write()
+			.await
+			.subscribe(library_id, peer_id)
+		{
+			debug!("Peer '{peer_id}' subscribed to library '{library_id}'");
+		}
+
+		Response::Subscribed
+	}
+
+	/// handle_unsubscribe will stop sending the sync events of the library to the peer.
+	pub(super) async fn handle_unsubscribe(&self, peer_id: PeerId, library_id: Uuid) -> Response {
+		if self
+			.subscriptions
+			.write()
+			.await
+			.unsubscribe(library_id, &peer_id)
+		{
+			debug!("Peer '{peer_id}' unsubscribed from library '{library_id}'");
+		}
+
+		Response::Unsubscribed
+	}
+
+	/// subscribe will ask a paired peer to send us the sync events of the library. The peer is subscribed to our sync events in return once it accepts.
+	async fn subscribe(&self, peer_id: PeerId, library_id: Uuid) {
+		if !self.paired_peers.read().await.contains(&peer_id) {
+			return;
+		}
+
+		match self.send_to(peer_id, Request::Subscribe(library_id)).await {
+			Ok(Response::Subscribed) => {
+				self.subscriptions
+					.write()
+					.await
+					.subscribe(library_id, peer_id);
+			}
+			Ok(Response::Error(err)) => {
+				debug!("Peer '{peer_id}' rejected subscribing to library '{library_id}': {err}");
+			}
+			Ok(response) => {
+				warn!("Unexpected response to subscribe from peer '{peer_id}': {response:?}");
+			}
+			// Older peers are sent the sync events of every library they are a member of
+			Err(P2PError::UnsupportedRequest(_)) => {}
+			Err(err) => {
+				debug!("Error subscribing to library '{library_id}' with peer '{peer_id}': {err}");
+			}
+		}
+	}
+
+	/// unsubscribe will ask every subscriber of the library to stop sending us its sync events and stop sending them ours.
+	async fn unsubscribe(&self, library_id: Uuid) {
+		let subscribers = self.subscriptions.read().await.subscribers(&library_id);
+		self.subscriptions.write().await.remove_library(&library_id);
+
+		join_all(subscribers.into_iter().map(|peer_id| async move {
+			if let Err(err) = self
+				.send_to(peer_id, Request::Unsubscribe(library_id))
+				.await
+			{
+				debug!(
+					"Error unsubscribing from library '{library_id}' with peer '{peer_id}': {err}"
+				);
+			}
+		}))
+		.await;
+	}
+
+	/// resume_sync will ask a paired peer for the operations after our checkpoint in every library we share with it.
+	/// The checkpoint is persisted after every response so an interrupted resume continues where it stopped.
+	/// If `bootstrapping` is set the number of operations applied is emitted as [PeerBootstrapProgress::InitialSync] for peers running [SYNC_PROGRESS_PROTO_VERSION] or later.
+	/// An error is returned if the operations of any library couldn't be fetched or applied.
+	async fn resume_sync(&self, peer_id: PeerId, bootstrapping: bool) -> Result<(), String> {
+		if !self.paired_peers.read().await.contains(&peer_id) {
+			return Ok(());
+		}
+		let with_total = bootstrapping
+			&& self
+				.peer_versions
+				.read()
+				.await
+				.get(&peer_id)
+				.map_or(false, |version| *version >= SYNC_PROGRESS_PROTO_VERSION);
+
+		let libraries = self
+			.libraries
+			.read()
+			.await
+			.iter()
+			.map(|(library_id, sync_key)| (*library_id, sync_key.clone()))
+			.collect::<Vec<_>>();
+
+		// The total is only known for the library being synced so it grows as each library is started
+		let mut synced = 0u64;
+		let mut error = None;
+		for (library_id, sync_key) in libraries {
+			if !self.library_peers(library_id).await.contains(&peer_id) {
+				continue;
+			}
+
+			let Some(library_manager) = self.library_manager() else {
+				return Err("node is not ready".into());
+			};
+			let Some(library) = library_manager.get_ctx(library_id).await else {
+				continue;
+			};
+
+			let mut checkpoint = self.sync_checkpoints.lock().await.get(library_id, peer_id);
+			loop {
+				let request = if with_total {
+					Request::SyncOperationsWithTotal {
+						library_id,
+						since: checkpoint.clone(),
+					}
+				} else {
+					Request::SyncOperations {
+						library_id,
+						since: checkpoint.clone(),
+					}
+				};
+
+				let (operations, more, total) = match self.send_to(peer_id, request).await {
+					Ok(Response::SyncOperations { operations, more }) => (operations, more, None),
+					Ok(Response::SyncOperationsWithTotal {
+						operations,
+						more,
+						total,
+					}) => (operations, more, Some(total)),
+					Ok(Response::Error(err)) => {
+						debug!("Peer '{peer_id}' couldn't resume sync for library '{library_id}': {err}");
+						error = Some(err);
+						break;
+					}
+					Ok(response) => {
+						warn!("Unexpected response to sync operations from peer '{peer_id}': {response:?}");
+						error = Some(P2PError::UnexpectedResponse.to_string());
+						break;
+					}
+					Err(P2PError::UnsupportedRequest(_)) => {
+						debug!("Peer '{peer_id}' doesn't support resuming sync, waiting for new sync events instead");
+						return Ok(());
+					}
+					Err(err) => {
+						debug!("Error resuming sync for library '{library_id}' from peer '{peer_id}': {err}");
+						error = Some(err.to_string());
+						break;
+					}
+				};
+
+				let previous = checkpoint.clone();
+				let synced_before = synced;
+				let mut failed = false;
+				for (_, op) in self
+					.verify_operations(peer_id, library_id, &sync_key, operations)
+					.await
+				{
+					if let Err(err) = library.sync.ingest_op(op.clone()).await {
+						// The checkpoint isn't advanced past this operation so it's requested again next time
+						error!("Error applying sync operation from peer '{peer_id}' for library '{library_id}': {err}");
+						error = Some(format!("error applying sync operation: {err}"));
+						failed = true;
+						break;
+					}
+					checkpoint.advance(&op);
+					synced += 1;
+				}
+
+				// `total` includes the operations in this response so it's counted from before they were applied
+				if let Some(total) = total {
+					self.emit_bootstrap_progress(
+						peer_id,
+						PeerBootstrapProgress::InitialSync {
+							synced: u32::try_from(synced).unwrap_or(u32::MAX),
+							total: u32::try_from(synced_before + total).unwrap_or(u32::MAX),
+						},
+					);
+				}
+
+				if checkpoint != previous {
+					if let Err(err) = self
+						.sync_checkpoints
+						.lock()
+						.await
+						.set(library_id, peer_id, checkpoint.clone())
+						.await
+					{
+						warn!("Error saving sync checkpoint for library '{library_id}' from peer '{peer_id}': {err}");
+					}
+				}
+
+				// Stop if the peer didn't make progress so a misbehaving peer can't keep us looping
+				if failed || !more || checkpoint == previous {
+					break;
+				}
+			}
+		}
+
+		error.map_or(Ok(()), Err)
+	}
+
+	fn emit_bootstrap_progress(&self, peer_id: PeerId, progress: PeerBootstrapProgress) {
+		self.events
+			.send(P2PEvent::BootstrapProgress { peer_id, progress })
+			.map_err(|_| error!("Failed to send event to p2p event stream!"))
+			.ok();
+	}
+
+	/// shared_libraries asks a peer for the libraries it's willing to share so the user can pick which to pair with.
+	/// Peers running [STREAMING_RESPONSE_PROTO_VERSION] or later stream the libraries back so the response isn't limited by [DEFAULT_MAX_MESSAGE_SIZE].
+	pub async fn shared_libraries(&self, peer_id: PeerId) -> Result<Vec<SharedLibrary>, P2PError> {
+		if self.negotiate(peer_id).await? < STREAMING_RESPONSE_PROTO_VERSION {
+			return match self.send_to(peer_id, Request::SharedLibraries).await? {
+				Response::SharedLibraries(libraries) => Ok(libraries),
+				Response::Error(err) => Err(P2PError::Remote(err)),
+				_ => Err(P2PError::UnexpectedResponse),
+			};
+		}
+
+		let frames = self.send_to_streaming(
+			peer_id,
+			Request::StreamSharedLibraries,
+			DEFAULT_REQUEST_TIMEOUT,
+		);
+		futures::pin_mut!(frames);
+
+		let mut libraries = Vec::new();
+		while let Some(frame) = frames.next().await {
+			match frame? {
+				Response::SharedLibrary(library) => libraries.push(library),
+				_ => return Err(P2PError::UnexpectedResponse),
+			}
+		}
+		Ok(libraries)
+	}
+
+	/// request_file will download a file from a peer to `path` in chunks of the request's `chunk_size`, emitting `P2PEvent::FileTransferProgress` as it goes.
+	/// An interrupted transfer can be resumed by setting the request's `offset` to the number of bytes which were already written to `path`.
+	/// Returns the total size of the file once the transfer completes.
+	///
+	/// The file is verified against the checksum sent by the peer once it's complete. If it doesn't match the file is deleted and [P2PError::TransferChecksumMismatch] is returned.
+	/// Peers running a version older than [FILE_CHECKSUM_PROTO_VERSION] don't send a checksum so files from them aren't verified.
+	///
+	/// Before a transfer is resumed the peer checks the bytes which were already written are still the start of the file, see [Request::ResumeTransfer]. If they aren't the file is transferred again from the start.
+	/// An error is returned if `offset` is past the end of the peer's file.
+	pub async fn request_file(
+		&self,
+		peer_id: PeerId,
+		request: FileRequest,
+		path: &Path,
+	) -> Result<u64, P2PError> {
+		let result = self.download_file(peer_id, request, path).await;
+
+		// Other errors leave the partial file so the transfer can be resumed but a corrupt file can't be trusted
+		if let Err(P2PError::TransferChecksumMismatch { .. }) = result {
+			if let Err(err) = fs::remove_file(path).await {
+				warn!(
+					"Error removing file '{}' which failed verification: {err}",
+					path.display()
+				);
+			}
+		}
+
+		result
+	}
+
+	async fn download_file(
+		&self,
+		peer_id: PeerId,
+		request: FileRequest,
+		path: &Path,
+	) -> Result<u64, P2PError> {
+		let FileRequest {
+			transfer_id,
+			mut offset,
+			chunk_size,
+		} = request;
+		let TransferId {
+			library_id,
+			location_id,
+			file_path_id,
+		} = transfer_id;
+		let chunk_size = chunk_size.unwrap_or(DEFAULT_FILE_CHUNK_SIZE);
+
+		let mut file = fs::OpenOptions::new()
+			.create(true)
+			.read(true)
+			.write(true)
+			.open(path)
+			.await?;
+		// Anything after `offset` is from a previous attempt which may not have been written completely
+		file.set_len(offset).await?;
+		file.seek(SeekFrom::Start(offset)).await?;
+
+		let version = self.negotiate(peer_id).await?;
+		let with_checksum = version >= FILE_CHECKSUM_PROTO_VERSION;
+		if !with_checksum {
+			debug!("Peer '{peer_id}' doesn't support file checksums so the file won't be verified");
+		}
+
+		let mut expected_size = None;
+		let mut verifier: Option<(FileChecksum, FileHasher)> = None;
+
+		// The bytes which were already received are checked before resuming, so a file which changed since the transfer was interrupted is transferred again instead of failing once it's complete
+		if offset > 0 && version >= RESUME_TRANSFER_PROTO_VERSION {
+			file.seek(SeekFrom::Start(0)).await?;
+			let mut hasher = ChecksumAlgorithm::Blake3.hasher();
+			hasher
+				.update_from_reader(&mut (&mut file).take(offset))
+				.await?;
+
+			let request = Request::ResumeTransfer {
+				transfer_id,
+				offset,
+				checksum: hasher.finalize(),
+			};
+
+			match self.send_to(peer_id, request).await? {
+				Response::TransferResumed {
+					offset: resumed,
+					size,
+					checksum,
+				} if resumed == offset || resumed == 0 => {
+					if resumed != offset {
+						debug!("File '{}' changed since its transfer from peer '{peer_id}' was interrupted so it's being transferred again", path.display());
+						hasher = checksum.algorithm.hasher();
+						file.set_len(resumed).await?;
+						offset = resumed;
+					}
+
+					file.seek(SeekFrom::Start(offset)).await?;
+					expected_size = Some(size);
+					verifier = Some((checksum, hasher));
+				}
+				Response::Error(err) => return Err(P2PError::Remote(err)),
+				_ => return Err(P2PError::UnexpectedResponse),
+			}
+		}
+
+		loop {
+			let request = if with_checksum {
+				Request::FileChunkWithChecksum {
+					library_id,
+					location_id,
+					file_path_id,
+					offset,
+					len: chunk_size,
+					expected_size,
+				}
+			} else {
+				Request::FileChunk {
+					library_id,
+					location_id,
+					file_path_id,
+					offset,
+					len: chunk_size,
+					expected_size,
+				}
+			};
+
+			let (bytes, size, eof) = match self.send_to(peer_id, request).await? {
+				Response::FileChunk { bytes, size, eof } if !with_checksum => (bytes, size, eof),
+				Response::FileChunkWithChecksum {
+					bytes,
+					size,
+					eof,
+					checksum,
+				} if with_checksum => {
+					match (checksum, expected_size) {
+						// The bytes which were already received are hashed from disk so a resumed transfer can be verified too
+						(Some(checksum), None) => {
+							file.seek(SeekFrom::Start(0)).await?;
+							let mut hasher = checksum.algorithm.hasher();
+							hasher
+								.update_from_reader(&mut (&mut file).take(offset))
+								.await?;
+							verifier = Some((checksum, hasher));
+						}
+						(None, Some(_)) => {}
+						// The checksum must be sent with the first chunk and only the first chunk
+						_ => return Err(P2PError::UnexpectedResponse),
+					}
+
+					(bytes, size, eof)
+				}
+				Response::Error(err) => return Err(P2PError::Remote(err)),
+				_ => return Err(P2PError::UnexpectedResponse),
+			};
+
+			// The peer must make progress or we would loop forever
+			if bytes.is_empty() && !eof {
+				return Err(P2PError::UnexpectedResponse);
+			}
+
+			file.write_all(&bytes).await?;
+			if let Some((_, hasher)) = &mut verifier {
+				hasher.update(&bytes);
+			}
+			offset += bytes.len() as u64;
+			expected_size = Some(size);
+
+			self.events
+				.send(P2PEvent::FileTransferProgress {
+					peer_id,
+					library_id,
+					file_path_id,
+					transferred: offset,
+					total: size,
+				})
+				.ok();
+
+			if eof {
+				file.flush().await?;
+
+				if let Some((expected, hasher)) = verifier {
+					let actual = hasher.finalize();
+					if actual != expected {
+						return Err(P2PError::TransferChecksumMismatch { expected, actual });
+					}
+				}
+
+				return Ok(size);
+			}
+		}
+	}
+
+	/// set_dial_policy will change which discovered peers are dialed and persist it to the node config.
+	/// Peers which are already connected will not be disconnected.
+	pub async fn set_dial_policy(&self, policy: DialPolicy) -> Result<(), NodeConfigError> {
+		self.node_config
+			.write({
+				let policy = policy.clone();
+				move |mut config| config.p2p_dial_policy = policy
+			})
+			.await?;
+		*self.dial_policy.write().await = policy;
+		Ok(())
+	}
+
+	/// set_node_name renames this node and readvertises its metadata so other peers see the new name without waiting for the next advertisement.
+	pub async fn set_node_name(&self, name: String) -> Result<(), NodeConfigError> {
+		self.node_config
+			.write(move |mut config| config.name = name)
+			.await?;
+		self.update_metadata().await;
+		Ok(())
+	}
+
+	/// returns the metadata of this node which is advertised to other peers.
+	pub async fn metadata(&self) -> PeerMetadata {
+		PeerMetadata::from_node_config(
+			&self.node_config.get().await,
+			self.libraries.read().await.keys().copied().collect(),
+		)
+	}
+
+	/// add_manual_peer will add a peer by address for networks where mDNS discovery doesn't work.
+	/// The address is persisted to the node config so it will be dialed again on startup.
+	pub async fn add_manual_peer(self: &Arc<Self>, addr: &str) -> Result<SocketAddr, P2PError> {
+		let addr = parse_peer_address(addr)?;
+
+		let mut is_new = false;
+		self.node_config
+			.write(|mut config| {
+				if !config.p2p_manual_peers.contains(&addr) {
+					config.p2p_manual_peers.push(addr);
+					is_new = true;
+				}
+			})
+			.await?;
+
+		if is_new {
+			self.spawn_reconnect(ReconnectTarget::Address(addr), Vec::new())
+				.await;
+		}
+
+		Ok(addr)
+	}
+
+	/// dial_order returns the addresses in the order they should be dialed. The pinned address is tried first, then the one the peer was last connected through, then the rest by their [AddressKind].
+	async fn dial_order(&self, peer_id: PeerId, addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+		let pinned = self
+			.node_config
+			.get()
+			.await
+			.p2p_pinned_addresses
+			.get(&peer_id)
+			.copied();
+		let last = self.last_addresses.read().await.get(&peer_id).copied();
+
+		order_addresses(
+			&pinned.into_iter().chain(last).collect::<Vec<_>>(),
+			addresses,
+		)
+	}
+
+	/// dial_peer will try connecting to the peer through each of the addresses in [Self::dial_order].
+	/// A [P2PEvent::DialFailed] is emitted if none of them could be connected to.
+	/// This must not be awaited from the event loop as the dial only completes while the `ManagerStream` is being polled.
+	async fn dial_peer(
+		&self,
+		peer_id: PeerId,
+		addresses: Vec<SocketAddr>,
+	) -> Result<(), DialError> {
+		let addresses = self.dial_order(peer_id, addresses).await;
+		trace!("Dialing peer '{peer_id}' at '{addresses:?}'");
+		self.manager()
+			.dial(peer_id, addresses)
+			.await
+			.map_err(|reason| {
+				debug!("Failed to dial peer '{peer_id}': {reason}");
+				self.emit_dial_failed(peer_id, reason);
+				reason
+			})
+	}
+
+	fn emit_dial_failed(&self, peer_id: PeerId, reason: DialError) {
+		self.set_last_error(format!("couldn't connect to peer '{peer_id}': {reason}"));
+		self.events
+			.send(P2PEvent::DialFailed { peer_id, reason })
+			.map_err(|_| error!("Failed to send event to p2p event stream!"))
+			.ok();
+	}
+
+	/// peer_addresses returns every known address of the peer in the order they will be dialed, along with the pinned and last connected addresses.
+	pub async fn peer_addresses(&self, peer_id: PeerId) -> PeerAddresses {
+		let mut addresses = self
+			.manager()
+			.get_discovered_peers()
+			.await
+			.into_iter()
+			.find(|peer| peer.peer_id == peer_id)
+			.map(|peer| peer.addresses)
+			.unwrap_or_default();
+		if let Some(peer) = self.connected_peers.read().await.get(&peer_id) {
+			addresses.extend(peer.addresses.iter().copied());
+		}
+
+		PeerAddresses {
+			addresses: self
+				.dial_order(peer_id, addresses)
+				.await
+				.into_iter()
+				.map(|address| PeerAddress {
+					address,
+					kind: AddressKind::of(&address),
+				})
+				.collect(),
+			pinned: self
+				.node_config
+				.get()
+				.await
+				.p2p_pinned_addresses
+				.get(&peer_id)
+				.copied(),
+			last_connected: self.last_addresses.read().await.get(&peer_id).copied(),
+		}
+	}
+
+	/// set_peer_nickname sets (or clears with `None`) the nickname shown for the peer instead of the name it advertises. Eg. "Partner's laptop".
+	/// The nickname is persisted to the node config and is purely local so it's never sent to the peer. Returns the nickname which was set once it's been trimmed.
+	pub async fn set_peer_nickname(
+		&self,
+		peer_id: PeerId,
+		nickname: Option<&str>,
+	) -> Result<Option<String>, NodeConfigError> {
+		let nickname = normalize_nickname(nickname);
+
+		self.node_config
+			.write({
+				let nickname = nickname.clone();
+				move |mut config| {
+					config.p2p_known_peers.entry(peer_id).or_default().nickname = nickname;
+					forget_unused_peers(&mut config);
+				}
+			})
+			.await?;
+
+		Ok(nickname)
+	}
+
+	/// pin_address sets (or clears with `None`) the address which is always dialed first for the peer. Eg. for a peer which is reachable over several networks but only one is reliable.
+	/// The address is persisted to the node config and is used from the next time the peer is dialed.
+	pub async fn pin_address(
+		&self,
+		peer_id: PeerId,
+		addr: Option<&str>,
+	) -> Result<Option<SocketAddr>, P2PError> {
+		let addr = addr.map(parse_peer_address).transpose()?;
+
+		self.node_config
+			.write(move |mut config| match addr {
+				Some(addr) => {
+					config.p2p_pinned_addresses.insert(peer_id, addr);
+				}
+				None => {
+					config.p2p_pinned_addresses.remove(&peer_id);
+				}
+			})
+			.await?;
+
+		Ok(addr)
+	}
+
+	/// manual_peers returns the addresses which were added with `add_manual_peer`.
+	pub async fn manual_peers(&self) -> Vec<SocketAddr> {
+		self.node_config.get().await.p2p_manual_peers
+	}
+
+	/// remove_manual_peer will stop dialing a peer which was added with `add_manual_peer`.
+	/// An existing connection to the peer is kept open.
+	pub async fn remove_manual_peer(&self, addr: &str) -> Result<(), P2PError> {
+		let addr = parse_peer_address(addr)?;
+		self.node_config
+			.write(move |mut config| {
+				config.p2p_manual_peers.retain(|a| *a != addr);
+				forget_unused_peers(&mut config);
+			})
+			.await?;
+		Ok(())
+	}
+
+	/// unpair will remove the trust relationship with a peer so it's no longer automatically dialed or reconnected to.
+	/// An existing connection to the peer is kept open.
+	pub async fn unpair(&self, peer_id: PeerId) -> Result<(), NodeConfigError> {
+		self.node_config
+			.write(move |mut config| {
+				config.p2p_paired_peers.remove(&peer_id);
+				config.p2p_stream_keys.remove(&peer_id);
+				if let Some(known) = config.p2p_known_peers.get_mut(&peer_id) {
+					known.paired = false;
+				}
+				forget_unused_peers(&mut config);
+			})
+			.await?;
+		self.paired_peers.write().await.remove(&peer_id);
+		self.verified_peers.write().await.remove(&peer_id);
+		Ok(())
+	}
+
+	/// block_peer will disconnect the peer and refuse any connections with it until it's unblocked. This is persisted to the node config.
+	pub async fn block_peer(&self, peer_id: PeerId) -> Result<(), NodeConfigError> {
+		self.node_config
+			.write(move |mut config| {
+				config.p2p_blocked_peers.insert(peer_id);
+			})
+			.await?;
+		self.manager().block_peer(peer_id).await;
+		Ok(())
+	}
+
+	/// unblock_peer will allow connections with a peer which was blocked with `block_peer`.
+	pub async fn unblock_peer(&self, peer_id: PeerId) -> Result<(), NodeConfigError> {
+		self.node_config
+			.write(move |mut config| {
+				config.p2p_blocked_peers.remove(&peer_id);
+			})
+			.await?;
+		self.manager().unblock_peer(peer_id);
+		Ok(())
+	}
+
+	/// blocked_peers returns the peers which were blocked with `block_peer`.
+	pub fn blocked_peers(&self) -> HashSet<PeerId> {
+		self.manager().blocked_peers()
+	}
+
+	/// should we keep trying to reconnect to the target. A reconnect is stopped once the peer is unpaired or removed.
+	async fn should_reconnect(&self, target: ReconnectTarget, addresses: &[SocketAddr]) -> bool {
+		let manual_peers = self.node_config.get().await.p2p_manual_peers;
+		match target {
+			ReconnectTarget::Peer(peer_id) => {
+				!self.manager().is_blocked(&peer_id)
+					&& !self.disconnected_peers.read().await.contains(&peer_id)
+					&& (self.paired_peers.read().await.contains(&peer_id)
+						|| addresses.iter().any(|addr| manual_peers.contains(addr)))
+			}
+			ReconnectTarget::Address(addr) => manual_peers.contains(&addr),
+		}
+	}
+
+	async fn is_connected(&self, target: ReconnectTarget) -> bool {
+		let connected_peers = self.connected_peers.read().await;
+		match target {
+			ReconnectTarget::Peer(peer_id) => connected_peers.contains_key(&peer_id),
+			ReconnectTarget::Address(addr) => connected_peers
+				.values()
+				.any(|peer| peer.addresses.contains(&addr)),
+		}
+	}
+
+	/// reconnect_known_peers starts reconnecting to the known peers and manually added addresses in the node config.
+	/// Paired peers which don't have a known address yet are included so they're dialed as soon as they are discovered.
+	/// At most `max_startup_dials` of the first attempts run at once, after that the reconnects are spread out by their backoff.
+	async fn reconnect_known_peers(self: &Arc<Self>) {
+		let config = self.node_config.get().await;
+		let startup_dials = Arc::new(Semaphore::new(self.reconnect.max_startup_dials.max(1)));
+
+		let mut known_peers = config.p2p_known_peers;
+		for peer_id in config.p2p_paired_peers {
+			known_peers.entry(peer_id).or_default();
+		}
+
+		debug!(
+			"Reconnecting to '{}' known peers and '{}' manually added peers",
+			known_peers.len(),
+			config.p2p_manual_peers.len()
+		);
+		for (peer_id, peer) in known_peers {
+			self.spawn_reconnect_with(
+				ReconnectTarget::Peer(peer_id),
+				peer.addresses,
+				Some(startup_dials.clone()),
+			)
+			.await;
+		}
+		for addr in config.p2p_manual_peers {
+			self.spawn_reconnect_with(
+				ReconnectTarget::Address(addr),
+				Vec::new(),
+				Some(startup_dials.clone()),
+			)
+			.await;
+		}
+	}
+
+	/// remember_address records the address a known peer connected through so it's dialed first on the next startup.
+	/// Peers which aren't paired or manually added are not remembered so the list doesn't grow with every peer on the network.
+	async fn remember_address(&self, peer_id: PeerId, addr: SocketAddr) {
+		let config = self.node_config.get().await;
+		let known = config.p2p_known_peers.get(&peer_id);
+		if known.and_then(|known| known.addresses.first()) == Some(&addr)
+			|| !(known.is_some()
+				|| config.p2p_manual_peers.contains(&addr)
+				|| self.paired_peers.read().await.contains(&peer_id))
+		{
+			return;
+		}
+
+		self.node_config
+			.write(move |mut config| {
+				config
+					.p2p_known_peers
+					.entry(peer_id)
+					.or_default()
+					.record_address(addr)
+			})
+			.await
+			.map_err(|err| warn!("Error saving the address of peer '{peer_id}': {err}"))
+			.ok();
+	}
+
+	/// spawn_reconnect will keep dialing the target until a connection is established, backing off between attempts as configured by `ReconnectConfig`.
+	/// `addresses` are the last known addresses of the peer. Any addresses it's advertising over mDNS are tried as well.
+	/// If a reconnect is already running for the target this does nothing.
+	async fn spawn_reconnect(
+		self: &Arc<Self>,
+		target: ReconnectTarget,
+		addresses: Vec<SocketAddr>,
+	) {
+		self.spawn_reconnect_with(target, addresses, None).await;
+	}
+
+	/// spawn_reconnect_with is `spawn_reconnect` where the first attempt waits for a permit from `startup_dials` so only so many run at once.
+	async fn spawn_reconnect_with(
+		self: &Arc<Self>,
+		target: ReconnectTarget,
+		addresses: Vec<SocketAddr>,
+		mut startup_dials: Option<Arc<Semaphore>>,
+	) {
+		if !self.reconnecting.lock().await.insert(target) {
+			return;
+		}
+
+		let this = self.clone();
+		let mut shutdown = self.shutdown.subscribe();
+
+		let handle = tokio::spawn(async move {
+			let mut attempt = 0;
+			loop {
+				// Only the first attempt is limited as the later ones are already spread out by the backoff
+				let permit = match startup_dials.take() {
+					Some(startup_dials) => startup_dials.acquire_owned().await.ok(),
+					None => None,
+				};
+
+				let is_shutdown = *shutdown.borrow();
+				if is_shutdown
+					|| this.is_connected(target).await
+					|| !this.should_reconnect(target, &addresses).await
+				{
+					break;
+				}
+
+				if !this.reconnect.should_retry(attempt) {
+					debug!("Giving up reconnecting to '{target:?}' after {attempt} attempts");
+					break;
+				}
+
+				match target {
+					ReconnectTarget::Peer(peer_id) => {
+						this.events
+							.send(P2PEvent::ConnectingPeer { peer_id, attempt })
+							.map_err(|_| error!("Failed to send event to p2p event stream!"))
+							.ok();
+
+						let mut addresses = addresses.clone();
+						if let Some(peer) = this
+							.manager()
+							.get_discovered_peers()
+							.await
+							.into_iter()
+							.find(|peer| peer.peer_id == peer_id)
+						{
+							addresses.extend(peer.addresses);
+						}
+
+						debug!("Reconnecting to peer '{peer_id}' at '{addresses:?}' (attempt {attempt})");
+						// A failure is reported by `dial_peer` and the next attempt is made after the delay
+						this.dial_peer(peer_id, addresses).await.ok();
+					}
+					ReconnectTarget::Address(addr) => {
+						debug!("Dialing manually added peer at '{addr}' (attempt {attempt})");
+						this.manager().dial_address(addr).await;
+					}
+				}
+				drop(permit);
+
+				tokio::select! {
+					_ = tokio::time::sleep(this.reconnect.delay(attempt)) => {}
+					_ = shutdown.changed() => break,
+				}
+				attempt += 1;
+			}
+
+			this.reconnecting.lock().await.remove(&target);
+		});
+
+		self.tasks.lock().await.push(handle);
+	}
+
+	/// exchange_metadata will request the metadata of a peer which connected without being discovered so it can be treated like a discovered peer.
+	async fn exchange_metadata(&self, peer_id: PeerId) {
+		let metadata = match self.send_to(peer_id, Request::Metadata).await {
+			Ok(Response::Metadata(metadata)) => metadata,
+			Ok(_) => {
+				warn!("Peer '{peer_id}' responded to metadata request with an unexpected response");
+				return;
+			}
+			Err(err) => {
+				warn!("Error requesting metadata from peer '{peer_id}': {err}");
+				return;
+			}
+		};
+
+		match self.connected_peers.write().await.get_mut(&peer_id) {
+			Some(peer) => peer.metadata = Some(metadata.clone()),
+			None => return,
+		}
+		self.library_peers.write().await.clear();
+
+		self.events
+			.send(P2PEvent::DiscoveredPeer {
+				peer_id,
+				metadata,
+				addresses: Vec::new(),
+			})
+			.map_err(|_| error!("Failed to send event to p2p event stream!"))
+			.ok();
+	}
+
+	/// set_discovery_enabled will start or stop mDNS discovery and persist it to the node config.
+	/// Peers which are already connected will stay connected.
+	pub async fn set_discovery_enabled(&self, enabled: bool) -> Result<(), NodeConfigError> {
+		self.node_config
+			.write(move |mut config| config.p2p_discovery_enabled = enabled)
+			.await?;
+		self.manager().set_discovery_enabled(enabled).await;
+		Ok(())
+	}
+
+	/// register a library as loaded on this node so it's advertised to other peers.
+	/// The sync key is used to sign outgoing and verify incoming sync operations for the library.
+	pub async fn add_library(&self, library_id: Uuid, sync_key: SyncKey) {
+		self.libraries.write().await.insert(library_id, sync_key);
+		self.verified_peers.write().await.clear();
+		self.update_metadata().await;
+
+		join_all(
+			self.library_peers(library_id)
+				.await
+				.into_iter()
+				.map(|peer_id| self.subscribe(peer_id, library_id)),
+		)
+		.await;
+	}
+
+	/// unregister a library so it's no longer advertised to other peers.
+	pub async fn remove_library(&self, library_id: Uuid) {
+		self.libraries.write().await.remove(&library_id);
+		self.sync_disabled.write().await.remove(&library_id);
+		self.verified_peers.write().await.clear();
+		self.unsubscribe(library_id).await;
+		self.sync_queue_depths.write().await.remove(&library_id);
+		self.sync_outboxes
+			.write()
+			.await
+			.retain(|(id, _), _| *id != library_id);
+		self.library_signers
+			.write()
+			.await
+			.remove_library(library_id);
+		self.update_metadata().await;
+	}
+
+	/// library_keypair returns this node's signing keypair for the library. Libraries which don't have one yet have it generated the first time their operations are signed.
+	async fn library_keypair(&self, library_id: Uuid) -> Option<Keypair> {
+		self.library_manager()?
+			.keypair(library_id)
+			.await
+			.map_err(|err| {
+				error!("Error loading the signing keypair for library '{library_id}': {err}")
+			})
+			.ok()
+	}
+
+	/// verify_operations returns the operations which were signed with the library's sync key, along with their signed form, dropping any which weren't.
+	/// The first library keypair a node signs with is pinned while the library is loaded, so its operations which are signed by a different keypair, or aren't signed after it started signing, are also dropped, see [LibrarySigners].
+	async fn verify_operations(
+		&self,
+		peer_id: PeerId,
+		library_id: Uuid,
+		sync_key: &SyncKey,
+		operations: Vec<SignedOperation>,
+	) -> Vec<(SignedOperation, CRDTOperation)> {
+		let mut signers = self.library_signers.write().await;
+
+		operations
+			.into_iter()
+			.filter_map(
+				|signed| match signers.verify(sync_key, library_id, &signed) {
+					Ok(op) => Some((signed, op)),
+					Err(err) => {
+						warn!("Dropping sync operation from peer '{peer_id}' for library '{library_id}': {err}");
+						None
+					}
+				},
+			)
+			.collect()
+	}
+
+	/// unseen_operations returns the operations which haven't been created on or received by this node before, remembering them so they're dropped if they arrive again.
+	async fn unseen_operations(
+		&self,
+		operations: Vec<(SignedOperation, CRDTOperation)>,
+	) -> Vec<(SignedOperation, CRDTOperation)> {
+		let mut seen = self.seen_operations.lock().await;
+		operations
+			.into_iter()
+			.filter(|(_, op)| seen.insert(op.id))
+			.collect()
+	}
+
+	/// update_metadata will readvertise the metadata of this node so peers see changes to the node config or libraries without waiting for the next advertisement.
+	/// The new metadata is also sent to the connected peers, so ones which didn't discover us over mDNS see it too. Both are debounced.
+	/// This must be called whenever the node config or loaded libraries change.
+	pub async fn update_metadata(&self) {
+		self.manager().update_metadata(self.metadata().await).await;
+		self.metadata_changed.notify_one();
+	}
+
+	/// push_metadata will send our current metadata to every connected peer.
+	/// Peers running a version older than [METADATA_CHANGED_PROTO_VERSION] are skipped as they'll see it once we're readvertised.
+	async fn push_metadata(&self) {
+		let metadata = self.metadata().await;
+		let peers = self
+			.connected_peers
+			.read()
+			.await
+			.keys()
+			.copied()
+			.collect::<Vec<_>>();
+
+		join_all(peers.into_iter().map(|peer_id| {
+			let request = Request::MetadataChanged(metadata.clone());
+			async move {
+				match self.send_to(peer_id, request).await {
+					Ok(_) | Err(P2PError::UnsupportedRequest(_)) => {}
+					Err(err) => {
+						debug!("Error sending our metadata to peer '{peer_id}': {err}");
+					}
+				}
+			}
+		}))
+		.await;
+	}
+
+	/// handle_metadata_changed updates the metadata of a connected peer which has sent us its new metadata.
+	/// The same events are emitted as when a discovered peer readvertises itself with new metadata.
+	pub(super) async fn handle_metadata_changed(
+		&self,
+		peer_id: PeerId,
+		metadata: PeerMetadata,
+	) -> Response {
+		match self.connected_peers.write().await.get_mut(&peer_id) {
+			Some(peer) if peer.metadata.as_ref() != Some(&metadata) => {
+				peer.metadata = Some(metadata.clone());
+			}
+			_ => return Response::Pong,
+		}
+
+		debug!("Peer '{peer_id}' changed its metadata to: {metadata:?}");
+		self.library_peers.write().await.clear();
+
+		if !metadata.is_compatible() {
+			self.events
+				.send(P2PEvent::PeerIncompatible {
+					peer_id,
+					version: metadata.version.clone(),
+					protocol_version: metadata.protocol_version,
+				})
+				.map_err(|_| error!("Failed to send event to p2p event stream!"))
+				.ok();
+		}
+
+		self.events
+			.send(P2PEvent::PeerMetadataChanged { peer_id, metadata })
+			.map_err(|_| error!("Failed to send event to p2p event stream!"))
+			.ok();
+
+		Response::Pong
+	}
+
+	/// connect will dial a discovered peer if it's not already connected and wait for the connection to be established.
+	async fn connect(&self, peer_id: PeerId) -> Result<(), P2PError> {
+		// We subscribe before dialing so the connection event can't be missed
+		let mut rx = self.events.subscribe();
+		if self.connected_peers.read().await.contains_key(&peer_id) {
+			return Ok(());
+		}
+
+		let peer = self
+			.manager()
+			.get_discovered_peers()
+			.await
+			.into_iter()
+			.find(|peer| peer.peer_id == peer_id)
+			.ok_or(P2PError::PeerNotDiscovered(peer_id))?;
+
+		if !peer.metadata.is_compatible() {
+			return Err(P2PError::PeerIncompatible(peer_id));
+		}
+
+		self.dial_peer(peer_id, peer.addresses).await?;
+
+		tokio::time::timeout(DEFAULT_REQUEST_TIMEOUT, async {
+			loop {
+				match rx.recv().await {
+					Ok(P2PEvent::ConnectedPeer { peer_id: id, .. }) if id == peer_id => {
+						return Ok(())
+					}
+					Ok(_) => {}
+					Err(broadcast::error::RecvError::Lagged(_)) => {
+						if self.connected_peers.read().await.contains_key(&peer_id) {
+							return Ok(());
+						}
+					}
+					Err(broadcast::error::RecvError::Closed) => {
+						return Err(P2PError::PeerNotConnected(peer_id))
+					}
+				}
+			}
+		})
+		.await
+		.map_err(|_| P2PError::Timeout)?
+	}
+
+	/// connect_to will dial a discovered peer regardless of the [DialPolicy] and wait for the connection to be established.
+	/// This is how peers are connected to with [DialPolicy::Manual]. Blocked peers are never dialed.
+	pub async fn connect_to(&self, peer_id: PeerId) -> Result<(), P2PError> {
+		if self.manager().is_blocked(&peer_id) {
+			return Err(DialError::Blocked.into());
+		}
+
+		self.disconnected_peers.write().await.remove(&peer_id);
+		self.connect(peer_id).await
+	}
+
+	/// disconnect_peer will close the connection with a peer and stop it from being reconnected to until the user connects to it again with `connect_to`.
+	pub async fn disconnect_peer(&self, peer_id: PeerId) {
+		self.disconnected_peers.write().await.insert(peer_id);
+		self.manager().disconnect(peer_id).await;
+	}
+
+	/// initiate_pairing will start pairing with a discovered peer. The returned code must be shown to the user so they can enter it on the other device.
+	/// The other device must call `confirm_pairing` within `PAIRING_TIMEOUT` or pairing has to be restarted. Once it has, `P2PEvent::PairingConfirmed` is emitted and the user must accept the pairing with `accept_pairing`.
+	pub async fn initiate_pairing(&self, peer_id: PeerId) -> Result<String, P2PError> {
+		self.connect(peer_id).await?;
+
+		// Our secret is only revealed once the peer has sent its own, so neither of us can choose a secret knowing the other's
+		let secret = Key::generate();
+		let remote_secret = match self
+			.send_to(
+				peer_id,
+				Request::PairingStart {
+					commitment: pairing_commitment(secret.expose()).to_vec(),
+				},
+			)
+			.await?
+		{
+			Response::PairingStarted { secret } => secret,
+			Response::Error(err) => return Err(P2PError::Remote(err)),
+			_ => return Err(P2PError::UnexpectedResponse),
+		};
+
+		// This is started before revealing our secret as the peer can confirm the code as soon as it has it
+		let code = self
+			.pairings
+			.start(peer_id, self.manager().peer_id(), &secret, &remote_secret)
+			.await?;
+
+		match self
+			.send_to(
+				peer_id,
+				Request::PairingReveal {
+					secret: secret.expose().to_vec(),
+				},
+			)
+			.await
+		{
+			Ok(Response::Pong) => Ok(code),
+			Ok(Response::Error(err)) => {
+				self.pairings.reject(peer_id).await;
+				Err(P2PError::Remote(err))
+			}
+			Ok(_) => {
+				self.pairings.reject(peer_id).await;
+				Err(P2PError::UnexpectedResponse)
+			}
+			Err(err) => {
+				self.pairings.reject(peer_id).await;
+				Err(err)
+			}
+		}
+	}
+
+	/// confirm_pairing is called with the code the user entered after a `P2PEvent::PairingRequest` from the peer.
+	/// If the code matches on both nodes the pairing waits for the user on the initiator to accept it. They will then trust each other and the sync keys of the libraries they are both a member of are taken from the initiator, see [Self::handle_pairing_complete].
+	pub async fn confirm_pairing(&self, peer_id: PeerId, code: String) -> Result<(), P2PError> {
+		self.pairings.confirm(peer_id, &code, false).await?;
+
+		match self
+			.send_to(peer_id, Request::PairingConfirm { code })
+			.await
+		{
+			Ok(Response::PairingPending) => Ok(()),
+			// Older initiators share their library keys as soon as the code is confirmed
+			Ok(Response::PairingAccepted { library_keys }) => {
+				let stream_key = self.pairings.accept(peer_id, false).await?;
+				self.add_paired_peer(peer_id, stream_key).await?;
+				self.save_library_keys(peer_id, library_keys).await;
+				Ok(())
+			}
+			Ok(Response::Error(err)) => {
+				self.pairings.reject(peer_id).await;
+				Err(P2PError::Remote(err))
+			}
+			Ok(_) => {
+				self.pairings.reject(peer_id).await;
+				Err(P2PError::UnexpectedResponse)
+			}
+			Err(err) => {
+				self.pairings.reject(peer_id).await;
+				Err(err)
+			}
+		}
+	}
+
+	/// accept_pairing is called once the user has accepted a pairing after a `P2PEvent::PairingConfirmed` from the peer.
+	/// The sync keys of the libraries both nodes are a member of are only sent to the peer once it has been accepted.
+	pub async fn accept_pairing(&self, peer_id: PeerId) -> Result<(), P2PError> {
+		let stream_key = self.pairings.accept(peer_id, true).await?;
+
+		// This is sent before the pairing is saved as the peer can't decrypt our requests until it has saved it too
+		match self
+			.send_to(
+				peer_id,
+				Request::PairingComplete {
+					library_keys: self.shared_library_keys(peer_id).await,
+				},
+			)
+			.await?
+		{
+			Response::Pong => {}
+			Response::Error(err) => return Err(P2PError::Remote(err)),
+			_ => return Err(P2PError::UnexpectedResponse),
+		}
+
+		self.add_paired_peer(peer_id, stream_key).await?;
+		Ok(())
+	}
+
+	/// reject_pairing discards a pairing with the peer which is in progress, Eg. because the user declined it after a `P2PEvent::PairingConfirmed`.
+	/// The peer's side of the pairing expires after `PAIRING_TIMEOUT`.
+	pub async fn reject_pairing(&self, peer_id: PeerId) -> Result<(), P2PError> {
+		if self.pairings.reject(peer_id).await {
+			Ok(())
+		} else {
+			Err(PairingError::NotPending.into())
+		}
+	}
+
+	/// save_library_keys will save the sync keys of the libraries which were shared by a peer we paired with.
+	async fn save_library_keys(&self, peer_id: PeerId, library_keys: Vec<(Uuid, SyncKey)>) {
+		if let Some(library_manager) = self.library_manager() {
+			for (library_id, sync_key) in library_keys {
+				if let Err(err) = library_manager.set_sync_key(library_id, sync_key).await {
+					error!("Error saving sync key for library '{library_id}' from peer '{peer_id}': {err}");
+				}
+			}
+		}
+	}
+
+	/// shared_library_keys returns the sync keys which are shared with a peer when pairing with it.
+	/// We only share the keys of the libraries the peer has advertised it's a member of.
+	async fn shared_library_keys(&self, peer_id: PeerId) -> Vec<(Uuid, SyncKey)> {
+		let peer_libraries = self
+			.connected_peers
+			.read()
+			.await
+			.get(&peer_id)
+			.and_then(|peer| peer.metadata.as_ref())
+			.map(|metadata| metadata.libraries.clone())
+			.unwrap_or_default();
+
+		self.libraries
+			.read()
+			.await
+			.iter()
+			.filter(|(id, _)| peer_libraries.contains(*id))
+			.map(|(id, sync_key)| (*id, sync_key.clone()))
+			.collect()
+	}
+
+	/// handles a `Request::PairingStart` from the initiator of a pairing. Our secret is sent back in exchange for the commitment to the initiator's, which it reveals with `Request::PairingReveal`.
+	/// It's rejected if a pairing is already in progress with the peer, so the pairing the user is confirming can't be replaced.
+	pub(super) async fn handle_pairing_start(
+		&self,
+		peer_id: PeerId,
+		commitment: Vec<u8>,
+	) -> Response {
+		let local_secret = Key::generate();
+		let secret = local_secret.expose().to_vec();
+		if let Err(err) = self
+			.pairings
+			.commit(peer_id, &commitment, local_secret)
+			.await
+		{
+			warn!("Rejected pairing with peer '{peer_id}': {err}");
+			return Response::Error(err.to_string());
+		}
+
+		Response::PairingStarted { secret }
+	}
+
+	/// handles a `Request::PairingReveal` from the initiator of a pairing which we've sent our secret to.
+	/// The user is only asked for the code once the secret has been checked against the initiator's commitment.
+	pub(super) async fn handle_pairing_reveal(&self, peer_id: PeerId, secret: Vec<u8>) -> Response {
+		if let Err(err) = self
+			.pairings
+			.reveal(peer_id, self.manager().peer_id(), &secret)
+			.await
+		{
+			warn!("Rejected pairing with peer '{peer_id}': {err}");
+			return Response::Error(err.to_string());
+		}
+
+		self.events
+			.send(P2PEvent::PairingRequest { peer_id })
+			.map_err(|_| error!("Failed to send event to p2p event stream!"))
+			.ok();
+
+		Response::Pong
+	}
+
+	/// handles a `Request::PairingConfirm` from the responder of a pairing we initiated.
+	/// No keys are shared until the user has accepted the pairing with [Self::accept_pairing]. Peers older than [PAIRING_ACCEPT_PROTO_VERSION] are rejected as they expect the keys in the response.
+	pub(super) async fn handle_pairing_confirm(&self, peer_id: PeerId, code: String) -> Response {
+		let version = self.peer_versions.read().await.get(&peer_id).copied();
+		if version.map_or(true, |version| version < PAIRING_ACCEPT_PROTO_VERSION) {
+			self.pairings.reject(peer_id).await;
+			warn!("Rejected pairing with peer '{peer_id}' which is running protocol version '{version:?}'");
+			return Response::Error(
+				"this version of Spacedrive can't be paired with, update it to pair".into(),
+			);
+		}
+
+		if let Err(err) = self.pairings.confirm(peer_id, &code, true).await {
+			warn!("Rejected pairing with peer '{peer_id}': {err}");
+			return Response::Error(err.to_string());
+		}
+
+		self.events
+			.send(P2PEvent::PairingConfirmed { peer_id })
+			.map_err(|_| error!("Failed to send event to p2p event stream!"))
+			.ok();
+
+		Response::PairingPending
+	}
+
+	/// handles a `Request::PairingComplete` from the initiator of a pairing we confirmed, once its user has accepted it.
+	pub(super) async fn handle_pairing_complete(
+		&self,
+		peer_id: PeerId,
+		library_keys: Vec<(Uuid, SyncKey)>,
+	) -> Response {
+		let stream_key = match self.pairings.accept(peer_id, false).await {
+			Ok(stream_key) => stream_key,
+			Err(err) => {
+				warn!("Rejected pairing with peer '{peer_id}': {err}");
+				return Response::Error(err.to_string());
+			}
+		};
+
+		if let Err(err) = self.add_paired_peer(peer_id, stream_key).await {
+			error!("Error saving pairing with peer '{peer_id}': {err}");
+			return Response::Error("error saving pairing".into());
+		}
+
+		self.save_library_keys(peer_id, library_keys).await;
+		Response::Pong
+	}
+
+	/// add_paired_peer will persist that the peer is trusted so it will be dialed by `DialPolicy::Paired`.
+	/// The stream key is saved so requests with the peer are encrypted from now on.
+	/// If the peer is a device which was paired before under a different peer id its old pairing is replaced, see [replace_device_pairings].
+	async fn add_paired_peer(
+		&self,
+		peer_id: PeerId,
+		stream_key: StreamKey,
+	) -> Result<(), NodeConfigError> {
+		let last_address = self.last_addresses.read().await.get(&peer_id).copied();
+		let device_id = self
+			.connected_peers
+			.read()
+			.await
+			.get(&peer_id)
+			.and_then(|peer| peer.metadata.as_ref()?.device_id);
+
+		let mut replaced = Vec::new();
+		self.node_config
+			.write(|mut config| {
+				config.p2p_paired_peers.insert(peer_id);
+				config.p2p_stream_keys.insert(peer_id, stream_key);
+
+				let known = config.p2p_known_peers.entry(peer_id).or_default();
+				known.paired = true;
+				if let Some(addr) = last_address {
+					known.record_address(addr);
+				}
+
+				if let Some(device_id) = device_id {
+					known.device_id = Some(device_id);
+					replaced = replace_device_pairings(&mut config, peer_id, device_id);
+				}
+			})
+			.await?;
+		{
+			let mut paired_peers = self.paired_peers.write().await;
+			let mut verified_peers = self.verified_peers.write().await;
+			paired_peers.insert(peer_id);
+			verified_peers.remove(&peer_id);
+			for peer_id in &replaced {
+				paired_peers.remove(peer_id);
+				verified_peers.remove(peer_id);
+			}
+		}
+		if !replaced.is_empty() {
+			info!("Peer '{peer_id}' was paired as a device which was previously paired as '{replaced:?}', replacing its old pairing");
+		}
+
+		self.events
+			.send(P2PEvent::Paired { peer_id, replaced })
+			.map_err(|_| error!("Failed to send event to p2p event stream!"))
+			.ok();
+
+		Ok(())
+	}
+
+	/// returns the currently connected peers which have advertised that they are a member of the given library.
+	async fn library_peers(&self, library_id: Uuid) -> Vec<PeerId> {
+		if let Some(peers) = self.library_peers.read().await.get(&library_id) {
+			return peers.clone();
+		}
+
+		let connected = self
+			.manager()
+			.get_connected_peers()
+			.await
+			.unwrap_or_default()
+			.into_iter()
+			.collect::<HashSet<_>>();
+
+		let peers = self
+			.manager()
+			.get_discovered_peers()
+			.await
+			.into_iter()
+			.filter(|peer| {
+				connected.contains(&peer.peer_id) && peer.metadata.libraries.contains(&library_id)
+			})
+			.map(|peer| peer.peer_id)
+			.collect::<Vec<_>>();
+
+		self.library_peers
+			.write()
+			.await
+			.insert(library_id, peers.clone());
+
+		peers
+	}
+
+	/// subscribers returns the connected peers which should be sent the sync events of a library.
+	/// These are the peers subscribed to the library and, as they can't subscribe, older peers which advertise that they are a member of it.
+	async fn subscribers(&self, library_id: Uuid) -> Vec<PeerId> {
+		let library_peers = self.library_peers(library_id).await;
+		let mut peers = self.subscriptions.read().await.subscribers(&library_id);
+
+		let peer_versions = self.peer_versions.read().await;
+		peers.extend(library_peers.into_iter().filter(|peer_id| {
+			peer_versions
+				.get(peer_id)
+				.map_or(true, |version| *version < SUBSCRIPTION_PROTO_VERSION)
+		}));
+
+		peers.into_iter().collect()
+	}
+
+	/// broadcast_sync_events will send the sync events created in a library to every peer which is subscribed to it, see [Self::subscribers].
+	/// `exclude` is left out even if it's subscribed, so operations which were received from a peer are never echoed back to it.
+	pub async fn broadcast_sync_events(
+		&self,
+		library_id: Uuid,
+		event: Vec<CRDTOperation>,
+		exclude: Option<PeerId>,
+	) {
+		let Some(sync_key) = self.libraries.read().await.get(&library_id).cloned() else {
+			warn!("not sending sync events for library '{library_id}' which isn't loaded on this node");
+			return;
+		};
+
+		if !self.sync_enabled(library_id).await {
+			debug!("not sending sync events for library '{library_id}' as sync is disabled");
+			return;
+		}
+
+		let Some(keypair) = self.library_keypair(library_id).await else {
+			return;
+		};
+
+		let operations = match event
+			.iter()
+			.map(|op| SignedOperation::sign(&sync_key, &keypair, library_id, op))
+			.collect::<Result<Vec<_>, _>>()
+		{
+			Ok(operations) => operations,
+			Err(err) => {
+				error!("Error signing sync events: {err}");
+				return;
+			}
+		};
+
+		// This node's own operations are remembered so they're dropped if a peer relays them back
+		{
+			let mut seen = self.seen_operations.lock().await;
+			for op in &event {
+				seen.insert(op.id);
+			}
+		}
+
+		self.send_sync_operations(library_id, operations, exclude)
+			.await;
+	}
+
+	/// queue_relay will relay sync operations which were received from `from` and haven't been seen before, see [Self::relay_sync_operations].
+	/// They're relayed by a background task so the request they arrived in isn't held up waiting on other peers.
+	async fn queue_relay(&self, library_id: Uuid, from: PeerId, operations: Vec<SignedOperation>) {
+		if operations.is_empty() {
+			return;
+		}
+
+		self.relay_queue
+			.lock()
+			.await
+			.push_back((library_id, from, operations));
+		self.relay_queued.notify_one();
+	}
+
+	/// relay_sync_operations will forward sync operations which were received from `from` to the other peers subscribed to the library.
+	/// Operations which have already been relayed [super::MAX_RELAY_HOPS] times aren't forwarded.
+	/// Peers accept them as they're still signed by the node which created them, see [LibrarySigners].
+	async fn relay_sync_operations(
+		&self,
+		library_id: Uuid,
+		from: PeerId,
+		operations: Vec<SignedOperation>,
+	) {
+		if !self.sync_enabled(library_id).await {
+			return;
+		}
+
+		let operations = operations
+			.iter()
+			.filter_map(SignedOperation::relayed)
+			.collect::<Vec<_>>();
+		if operations.is_empty() {
+			return;
+		}
+
+		self.send_sync_operations(library_id, operations, Some(from))
+			.await;
+	}
+
+	/// send_sync_operations sends signed sync operations to every peer subscribed to the library except `exclude`.
+	/// Peers which support reliable sync are sent them with [Request::SyncBatch] and they are retransmitted until the peer acknowledges them.
+	async fn send_sync_operations(
+		&self,
+		library_id: Uuid,
+		operations: Vec<SignedOperation>,
+		exclude: Option<PeerId>,
+	) {
+		let peers = relay_targets(self.subscribers(library_id).await, exclude);
+
+		// Peers with an outbox are sent the events even if they are disconnected so they can be retransmitted once they reconnect
+		let mut reliable_peers = self
+			.sync_outboxes
+			.read()
+			.await
+			.keys()
+			.filter(|(id, peer_id)| *id == library_id && Some(*peer_id) != exclude)
+			.map(|(_, peer_id)| *peer_id)
+			.collect::<HashSet<_>>();
+		let mut broadcast_peers = Vec::new();
+		{
+			let peer_versions = self.peer_versions.read().await;
+			for peer_id in &peers {
+				match peer_versions.get(peer_id) {
+					Some(version) if *version >= RELIABLE_SYNC_PROTO_VERSION => {
+						reliable_peers.insert(*peer_id);
+					}
+					_ => broadcast_peers.push(*peer_id),
+				}
+			}
+		}
+
+		if reliable_peers.is_empty() && broadcast_peers.is_empty() {
+			debug!("no connected peers in library '{library_id}', skipping sending sync events");
+			return;
+		}
+
+		for peer_id in &reliable_peers {
+			let outbox = self
+				.sync_outboxes
+				.write()
+				.await
+				.entry((library_id, *peer_id))
+				.or_default()
+				.clone();
+
+			let (_, dropped) = outbox.lock().await.push(operations.clone());
+			if dropped > 0 {
+				warn!("Peer '{peer_id}' has fallen too far behind in library '{library_id}', dropped '{dropped}' unacknowledged sync operations which it will get when it next bootstraps");
+			}
+		}
+
+		for peer_id in peers
+			.iter()
+			.filter(|peer_id| reliable_peers.contains(peer_id))
+		{
+			self.flush_sync_outbox(library_id, *peer_id).await;
+		}
+
+		if broadcast_peers.is_empty() {
+			return;
+		}
+
+		let buf = match rmp_serde::to_vec_named(&operations) {
+			Ok(buf) => buf,
+			Err(err) => {
+				error!("Error encoding sync events: {err}");
+				return;
+			}
+		};
+		// The same payload is broadcast to every peer so it's only compressed if they can all decode it
+		let mut compression = self.compression.clone();
+		for peer_id in &broadcast_peers {
+			if !self.supports_compression(*peer_id).await {
+				compression = Compression::uncompressed();
+				break;
+			}
+		}
+		let mut buf = match compression.encode_payload(buf) {
+			Ok(buf) => buf,
+			Err(err) => {
+				error!("Error compressing sync events: {err}");
+				return;
+			}
+		};
+		let mut head_buf = Header::Sync(library_id, buf.len() as u32).to_bytes(); // Max Sync payload is like 4GB
+		head_buf.append(&mut buf);
+
+		debug!(
+			"sending sync events to peers '{broadcast_peers:?}'. payload_len={}",
+			head_buf.len()
+		);
+
+		let _lane = self.lanes.enter(Lane::Control).await;
+		for peer_id in broadcast_peers {
+			self.manager().send_to(peer_id, head_buf.clone()).await;
+		}
+	}
+
+	pub async fn big_bad_spacedrop(&self, peer_id: PeerId, path: PathBuf) {
+		let _lane = self.lanes.enter(Lane::Bulk).await;
+		let mut stream = self.manager().stream(peer_id).await.unwrap(); // TODO: handle providing incorrect peer id
+
+		let file = File::open(&path).await.unwrap();
+		let metadata = file.metadata().await.unwrap();
+		let mut reader = BufReader::new(file);
+
+		stream
+			.write_all(
+				&Header::Spacedrop(TransferRequest {
+					name: path.file_name().unwrap().to_str().unwrap().to_string(), // TODO: Encode this as bytes instead
+					size: metadata.len(),
+					block_size: BlockSize::from_size(metadata.len()),
+				})
+				.to_bytes(),
+			)
 			.await
 			.unwrap();
 
@@ -279,7 +3942,10 @@ impl P2PManager {
 		// TODO: Replace this with the Spaceblock `Block` system
 		let mut buffer = Vec::new();
 		reader.read_to_end(&mut buffer).await.unwrap();
-		println!("READ {:?}", buffer);
+		debug!(
+			"Read '{}' bytes for Spacedrop to peer '{peer_id}'",
+			buffer.len()
+		);
 		stream.write_all(&buffer).await.unwrap();
 
 		debug!(
@@ -288,3 +3954,764 @@ impl P2PManager {
 		);
 	}
 }
+
+/// manager_config returns the arguments to create the [Manager] with from the node config.
+async fn manager_config(node_config: &NodeConfigManager) -> (String, Keypair, ManagerConfig) {
+	let config = node_config.get().await;
+	(
+		network_app_id(config.p2p_network_name.as_deref()),
+		config.keypair,
+		ManagerConfig {
+			discovery_enabled: config.p2p_discovery_enabled,
+			blocked_peers: config.p2p_blocked_peers,
+			listen_port: config
+				.p2p_port
+				.and_then(|port| {
+					u16::try_from(port)
+						.map_err(|_| {
+							warn!("Ignoring invalid P2P port '{port}', a random port will be used instead")
+						})
+						.ok()
+				})
+				.unwrap_or(0),
+			listen_addrs: config.p2p_listen_addrs,
+			transports: config.p2p_transports,
+			relays: config.p2p_relays,
+			..Default::default()
+		},
+	)
+}
+
+/// replace_device_pairings unpairs the other peers which were paired as the same device as `peer_id` and returns them.
+/// This is only called once the user has confirmed pairing with `peer_id` as the device id is advertised by the peer itself, so it's never used to trust a peer without pairing.
+/// The nickname the user gave the old peer is kept if the new one doesn't have one.
+fn replace_device_pairings(
+	config: &mut NodeConfig,
+	peer_id: PeerId,
+	device_id: Uuid,
+) -> Vec<PeerId> {
+	let replaced = config
+		.p2p_known_peers
+		.iter()
+		.filter(|(id, known)| **id != peer_id && known.paired && known.device_id == Some(device_id))
+		.map(|(id, _)| *id)
+		.collect::<Vec<_>>();
+
+	for id in &replaced {
+		config.p2p_paired_peers.remove(id);
+		config.p2p_stream_keys.remove(id);
+
+		let nickname = config.p2p_known_peers.get_mut(id).and_then(|known| {
+			known.paired = false;
+			known.nickname.take()
+		});
+		if let Some(known) = config.p2p_known_peers.get_mut(&peer_id) {
+			known.nickname = known.nickname.take().or(nickname);
+		}
+	}
+
+	forget_unused_peers(config);
+	replaced
+}
+
+/// forget_unused_peers removes the known peers which there is no longer any reason to reconnect to. See `KnownPeer::is_unused`.
+fn forget_unused_peers(config: &mut NodeConfig) {
+	let manual_peers = config.p2p_manual_peers.clone();
+	config
+		.p2p_known_peers
+		.retain(|_, peer| !peer.is_unused(&manual_peers));
+}
+
+/// normalize_nickname trims a nickname so one which is only whitespace clears the peer's nickname instead.
+fn normalize_nickname(nickname: Option<&str>) -> Option<String> {
+	nickname
+		.map(str::trim)
+		.filter(|nickname| !nickname.is_empty())
+		.map(Into::into)
+}
+
+/// authorize_sync returns [P2PError::PeerNotAuthorized] unless the peer is a member of the library and sync hasn't been disabled for it.
+fn authorize_sync(
+	peer: &VerifiedPeer,
+	sync_disabled: &HashSet<Uuid>,
+	library_id: Uuid,
+) -> Result<(), P2PError> {
+	peer.authorize(library_id)?;
+
+	if sync_disabled.contains(&library_id) {
+		return Err(P2PError::PeerNotAuthorized {
+			peer_id: peer.peer_id(),
+			library_id,
+		});
+	}
+
+	Ok(())
+}
+
+/// is_shareable_addr returns if the address could be used to reach this node from another device.
+fn is_shareable_addr(addr: &SocketAddr) -> bool {
+	match addr.ip() {
+		IpAddr::V4(ip) => !(ip.is_loopback() || ip.is_unspecified() || ip.is_link_local()),
+		IpAddr::V6(ip) => {
+			!(ip.is_loopback() || ip.is_unspecified() || (ip.segments()[0] & 0xffc0) == 0xfe80)
+		}
+	}
+}
+
+/// send_request writes the request to the stream and waits for the peer's response.
+async fn send_request(
+	stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+	request: &Request,
+	compression: &Compression,
+	timeout: Duration,
+) -> Result<Response, P2PError> {
+	stream.write_all(&Header::Request.to_bytes()).await?;
+	exchange(stream, request, compression, timeout).await
+}
+
+/// exchange writes the request to a stream which the header has already been written to and waits for the peer's response.
+/// A [Response::ProtocolError] is returned as a [P2PError::Protocol] as it's never the response a request expects.
+async fn exchange(
+	stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+	request: &Request,
+	compression: &Compression,
+	timeout: Duration,
+) -> Result<Response, P2PError> {
+	write_message_with_compression(stream, request, compression).await?;
+
+	match tokio::time::timeout(timeout, read_message(stream))
+		.await
+		.map_err(|_| P2PError::Timeout)??
+	{
+		Response::ProtocolError(err) => Err(P2PError::Protocol(err)),
+		response => Ok(response),
+	}
+}
+
+/// read_frame reads the next frame of a streamed response. `None` once the peer has sent [Response::EndOfStream].
+async fn read_frame(
+	stream: &mut (impl AsyncRead + Unpin),
+	timeout: Duration,
+) -> Result<Option<Response>, P2PError> {
+	match tokio::time::timeout(timeout, read_message(stream))
+		.await
+		.map_err(|_| P2PError::Timeout)??
+	{
+		Response::EndOfStream => Ok(None),
+		Response::Error(err) => Err(P2PError::Remote(err)),
+		Response::ProtocolError(err) => Err(P2PError::Protocol(err)),
+		frame => Ok(Some(frame)),
+	}
+}
+
+/// A stream which a request can be sent over, Eg. a unicast stream or an [EncryptedStream] wrapping one.
+trait RequestStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> RequestStream for T {}
+
+/// An error reading the sync operations sent with [Header::Sync].
+#[derive(Debug, Error)]
+pub enum SyncMessageError {
+	#[error("the message is {0} bytes which is larger than the maximum of {DEFAULT_MAX_MESSAGE_SIZE} bytes")]
+	TooLarge(u32),
+	#[error("io error reading message: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("malformed payload: {0}")]
+	Payload(#[from] MessageError),
+	#[error("malformed operations: {0}")]
+	Decode(#[from] rmp_serde::decode::Error),
+}
+
+/// read_sync_operations reads the `len` byte payload which follows a [Header::Sync] and decodes the operations in it.
+/// The operations haven't been verified yet, see [SignedOperation::verify].
+async fn read_sync_operations(
+	stream: &mut (impl AsyncRead + Unpin),
+	len: u32,
+) -> Result<Vec<SignedOperation>, SyncMessageError> {
+	if len as usize > DEFAULT_MAX_MESSAGE_SIZE {
+		return Err(SyncMessageError::TooLarge(len));
+	}
+
+	let mut buf = vec![0; len as usize];
+	stream.read_exact(&mut buf).await?;
+
+	let buf = decode_payload(buf, DEFAULT_MAX_MESSAGE_SIZE)?;
+	Ok(rmp_serde::from_slice(&buf)?)
+}
+
+/// respond_with reads a request from the stream, passes it to the handler along with the `peer_id` it was received from and writes the response back.
+/// `peer_id` must be the identity verified by the connection so the handler can use it to authorize the request.
+/// This is the single place failures are converted into a [Response::Error] so an error reading, handling or encoding a request never panics or leaves the peer waiting.
+/// A [P2PError::Protocol] is sent as a [Response::ProtocolError] instead, which includes a request larger than `max_request_size`.
+async fn respond_with<F, Fut>(
+	peer_id: PeerId,
+	stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+	compression: &Compression,
+	max_request_size: usize,
+	handler: F,
+) where
+	F: FnOnce(PeerId, Request) -> Fut,
+	Fut: Future<Output = Result<Response, P2PError>>,
+{
+	respond_with_reply(
+		peer_id,
+		stream,
+		compression,
+		max_request_size,
+		|peer_id, request| async move { handler(peer_id, request).await.map(Reply::Single) },
+	)
+	.await
+}
+
+/// respond_with_reply is the same as `respond_with` but the handler can reply with a stream of frames, see [Reply].
+async fn respond_with_reply<'a, F, Fut>(
+	peer_id: PeerId,
+	stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+	compression: &Compression,
+	max_request_size: usize,
+	handler: F,
+) where
+	F: FnOnce(PeerId, Request) -> Fut,
+	Fut: Future<Output = Result<Reply<'a>, P2PError>>,
+{
+	let response = match handle_request(peer_id, stream, max_request_size, handler).await {
+		Ok(Reply::Single(response)) => Ok(response),
+		Ok(Reply::Stream(frames)) => {
+			return write_frames(peer_id, stream, compression, frames).await
+		}
+		Err(err) => Err(err),
+	};
+
+	let buf = match response.and_then(|response| {
+		encode_message_with_compression(&response, compression).map_err(Into::into)
+	}) {
+		Ok(buf) => buf,
+		Err(err) => {
+			warn!("Error handling request from peer '{peer_id}': {err}");
+			let response = match err {
+				P2PError::Protocol(err) => Response::ProtocolError(err),
+				err => Response::Error(err.to_string()),
+			};
+			match encode_message_with_compression(&response, compression) {
+				Ok(buf) => buf,
+				Err(err) => {
+					error!("Error encoding error response for peer '{peer_id}': {err}");
+					return;
+				}
+			}
+		}
+	};
+
+	if let Err(err) = async {
+		stream.write_all(&buf).await?;
+		stream.flush().await
+	}
+	.await
+	{
+		warn!("Error sending response to peer '{peer_id}': {err}");
+	}
+}
+
+/// handle_request reads a request from the stream and returns the handler's reply.
+async fn handle_request<'a, F, Fut>(
+	peer_id: PeerId,
+	stream: &mut (impl AsyncRead + Unpin),
+	max_request_size: usize,
+	handler: F,
+) -> Result<Reply<'a>, P2PError>
+where
+	F: FnOnce(PeerId, Request) -> Fut,
+	Fut: Future<Output = Result<Reply<'a>, P2PError>>,
+{
+	let request = read_message_with_limit::<Request>(stream, max_request_size)
+		.await
+		.map_err(|err| P2PError::Protocol(format!("malformed request: {err}")))?;
+	handler(peer_id, request).await
+}
+
+/// with_handler_timeout bounds how long a request is handled for so a slow handler can't leave the peer waiting until its own timeout.
+async fn with_handler_timeout<T>(
+	timeout: Duration,
+	handler: impl Future<Output = T>,
+) -> Result<T, P2PError> {
+	tokio::time::timeout(timeout, handler).await.map_err(|_| {
+		P2PError::Protocol(format!(
+			"timed out handling the request after {}ms",
+			timeout.as_millis()
+		))
+	})
+}
+
+/// write_frames writes each frame of a streamed reply as soon as it's produced, followed by [Response::EndOfStream].
+/// A [Response::Error] ends the stream early. It's also sent in place of a frame which fails to encode so the peer isn't left waiting.
+async fn write_frames(
+	peer_id: PeerId,
+	stream: &mut (impl AsyncWrite + Unpin),
+	compression: &Compression,
+	mut frames: BoxStream<'_, Response>,
+) {
+	if let Err(err) = async {
+		while let Some(frame) = frames.next().await {
+			let (buf, is_last) = match encode_message_with_compression(&frame, compression) {
+				Ok(buf) => (buf, matches!(frame, Response::Error(_))),
+				Err(err) => {
+					warn!("Error encoding response frame for peer '{peer_id}': {err}");
+					(
+						encode_message_with_compression(
+							&Response::Error(err.to_string()),
+							compression,
+						)?,
+						true,
+					)
+				}
+			};
+
+			// Each frame is flushed so the peer can handle it while the next one is produced
+			stream.write_all(&buf).await?;
+			stream.flush().await?;
+			if is_last {
+				return Ok(());
+			}
+		}
+
+		stream
+			.write_all(&encode_message_with_compression(
+				&Response::EndOfStream,
+				compression,
+			)?)
+			.await?;
+		stream.flush().await?;
+		Ok::<_, P2PError>(())
+	}
+	.await
+	{
+		warn!("Error sending response to peer '{peer_id}': {err}");
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::p2p::{write_message, KnownPeer, MESSAGE_PROTOCOL_VERSION};
+
+	use super::*;
+
+	fn peer_id() -> PeerId {
+		PeerId::from_str("12D3KooW9tHTtS3inCZiYykw4u5G4frbjVFqhkmJX12gSNCVeH3e").unwrap()
+	}
+
+	#[tokio::test]
+	async fn test_respond_with_handler_error() {
+		let (mut stream, mut peer) = tokio::io::duplex(1024);
+		write_message(&mut peer, &Request::Ping).await.unwrap();
+
+		respond_with(
+			peer_id(),
+			&mut stream,
+			&Compression::default(),
+			DEFAULT_MAX_MESSAGE_SIZE,
+			|_, _| async { Err(P2PError::UnexpectedResponse) },
+		)
+		.await;
+
+		let response = read_message::<Response>(&mut peer).await.unwrap();
+		assert_eq!(
+			response,
+			Response::Error(P2PError::UnexpectedResponse.to_string())
+		);
+	}
+
+	#[tokio::test]
+	async fn test_respond_with_peer_id() {
+		let (mut stream, mut peer) = tokio::io::duplex(1024);
+		write_message(&mut peer, &Request::Ping).await.unwrap();
+
+		respond_with(
+			peer_id(),
+			&mut stream,
+			&Compression::default(),
+			DEFAULT_MAX_MESSAGE_SIZE,
+			|from, request| async move {
+				assert_eq!(from, peer_id());
+				assert_eq!(request, Request::Ping);
+				Ok(Response::Pong)
+			},
+		)
+		.await;
+
+		assert_eq!(
+			read_message::<Response>(&mut peer).await.unwrap(),
+			Response::Pong
+		);
+	}
+
+	#[tokio::test]
+	async fn test_respond_with_malformed_request() {
+		let (mut stream, mut peer) = tokio::io::duplex(1024);
+		peer.write_all(&[MESSAGE_PROTOCOL_VERSION, 1, 0, 0, 0, 0xC1])
+			.await
+			.unwrap();
+
+		respond_with(
+			peer_id(),
+			&mut stream,
+			&Compression::default(),
+			DEFAULT_MAX_MESSAGE_SIZE,
+			|_, _| async { Ok(Response::Pong) },
+		)
+		.await;
+
+		assert!(matches!(
+			read_message::<Response>(&mut peer).await.unwrap(),
+			Response::ProtocolError(err) if err.starts_with("malformed request")
+		));
+	}
+
+	#[tokio::test]
+	async fn test_respond_with_oversized_request() {
+		let (mut stream, mut peer) = tokio::io::duplex(1024);
+		// Only the length prefix is sent. If the request was buffered before being refused this would wait for the rest of it forever
+		peer.write_all(&[MESSAGE_PROTOCOL_VERSION]).await.unwrap();
+		peer.write_u32_le(u32::MAX).await.unwrap();
+
+		tokio::time::timeout(
+			Duration::from_secs(1),
+			respond_with(
+				peer_id(),
+				&mut stream,
+				&Compression::default(),
+				1024,
+				|_, _| async { Ok(Response::Pong) },
+			),
+		)
+		.await
+		.unwrap();
+
+		assert!(matches!(
+			read_message::<Response>(&mut peer).await.unwrap(),
+			Response::ProtocolError(err) if err.starts_with("malformed request") && err.contains("larger than the maximum")
+		));
+	}
+
+	#[tokio::test]
+	async fn test_read_corrupt_sync_operations() {
+		let operations = vec![SignedOperation::sign(
+			&SyncKey::default(),
+			&Keypair::generate(),
+			Uuid::new_v4(),
+			&CRDTOperation {
+				node: Uuid::new_v4(),
+				timestamp: NTP64(1),
+				id: Uuid::new_v4(),
+				typ: CRDTOperationType::Owned(OwnedOperation {
+					model: "location".to_owned(),
+					items: Vec::new(),
+				}),
+			},
+		)
+		.unwrap()];
+		let valid = Compression::default()
+			.encode_payload(rmp_serde::to_vec_named(&operations).unwrap())
+			.unwrap();
+
+		let mut corrupt = valid.clone();
+		corrupt.truncate(valid.len() / 2);
+		for (buf, len) in [
+			// Not valid msgpack
+			(vec![0, 0xc1, 0xc1, 0xc1], 4),
+			// An unknown compression flag
+			(vec![42, 1, 2, 3], 4),
+			// msgpack which doesn't decode to operations
+			(corrupt.clone(), corrupt.len() as u32),
+		] {
+			assert!(matches!(
+				read_sync_operations(&mut &buf[..], len).await,
+				Err(SyncMessageError::Payload(_) | SyncMessageError::Decode(_))
+			));
+		}
+
+		// The stream ended before the whole message was sent
+		assert!(matches!(
+			read_sync_operations(&mut &valid[..], valid.len() as u32 + 1).await,
+			Err(SyncMessageError::Io(_))
+		));
+		assert!(matches!(
+			read_sync_operations(&mut &valid[..], u32::MAX).await,
+			Err(SyncMessageError::TooLarge(u32::MAX))
+		));
+
+		// A valid message is still decoded after the bad ones
+		assert_eq!(
+			read_sync_operations(&mut &valid[..], valid.len() as u32)
+				.await
+				.unwrap(),
+			operations
+		);
+	}
+
+	#[tokio::test]
+	async fn test_respond_with_handler_timeout() {
+		let (mut stream, mut peer) = tokio::io::duplex(1024);
+		write_message(&mut peer, &Request::Ping).await.unwrap();
+
+		respond_with(
+			peer_id(),
+			&mut stream,
+			&Compression::default(),
+			DEFAULT_MAX_MESSAGE_SIZE,
+			|_, _| async {
+				with_handler_timeout(
+					Duration::from_millis(10),
+					futures::future::pending::<Response>(),
+				)
+				.await
+			},
+		)
+		.await;
+
+		assert!(matches!(
+			read_message::<Response>(&mut peer).await.unwrap(),
+			Response::ProtocolError(err) if err.starts_with("timed out")
+		));
+	}
+
+	#[tokio::test]
+	async fn test_respond_with_stream() {
+		let (mut stream, mut peer) = tokio::io::duplex(1024);
+		write_message(&mut peer, &Request::StreamSharedLibraries)
+			.await
+			.unwrap();
+
+		respond_with_reply(
+			peer_id(),
+			&mut stream,
+			&Compression::default(),
+			DEFAULT_MAX_MESSAGE_SIZE,
+			|_, _| async {
+				Ok(Reply::Stream(
+					futures::stream::iter([Response::Pong, Response::Pong]).boxed(),
+				))
+			},
+		)
+		.await;
+
+		let timeout = Duration::from_secs(1);
+		for _ in 0..2 {
+			assert_eq!(
+				read_frame(&mut peer, timeout).await.unwrap(),
+				Some(Response::Pong)
+			);
+		}
+		assert_eq!(read_frame(&mut peer, timeout).await.unwrap(), None);
+	}
+
+	#[tokio::test]
+	async fn test_respond_with_stream_error() {
+		let (mut stream, mut peer) = tokio::io::duplex(1024);
+		write_message(&mut peer, &Request::StreamSharedLibraries)
+			.await
+			.unwrap();
+
+		respond_with_reply(
+			peer_id(),
+			&mut stream,
+			&Compression::default(),
+			DEFAULT_MAX_MESSAGE_SIZE,
+			|_, _| async {
+				Ok(Reply::Stream(
+					futures::stream::iter([
+						Response::Pong,
+						Response::Error("oops".into()),
+						Response::Pong,
+					])
+					.boxed(),
+				))
+			},
+		)
+		.await;
+		drop(stream);
+
+		// The error ends the stream so the frame after it is never sent
+		let timeout = Duration::from_secs(1);
+		assert_eq!(
+			read_frame(&mut peer, timeout).await.unwrap(),
+			Some(Response::Pong)
+		);
+		assert!(matches!(
+			read_frame(&mut peer, timeout).await,
+			Err(P2PError::Remote(err)) if err == "oops"
+		));
+		assert!(read_frame(&mut peer, timeout).await.is_err());
+	}
+
+	#[test]
+	fn test_authorize_sync() {
+		let [library_id, disabled_library_id] = [Uuid::new_v4(), Uuid::new_v4()];
+		let peer = VerifiedPeer::new(peer_id(), true, [library_id, disabled_library_id]);
+		let mut sync_disabled = HashSet::from([disabled_library_id]);
+
+		assert!(authorize_sync(&peer, &sync_disabled, library_id).is_ok());
+		assert!(matches!(
+			authorize_sync(&peer, &sync_disabled, disabled_library_id),
+			Err(P2PError::PeerNotAuthorized { library_id, .. }) if library_id == disabled_library_id
+		));
+
+		// Enabling sync again is picked up by the next operation
+		sync_disabled.remove(&disabled_library_id);
+		assert!(authorize_sync(&peer, &sync_disabled, disabled_library_id).is_ok());
+
+		// Sync being enabled doesn't authorize peers which aren't a member of the library
+		let peer = VerifiedPeer::new(peer_id(), false, [library_id]);
+		assert!(authorize_sync(&peer, &sync_disabled, library_id).is_err());
+	}
+
+	#[test]
+	fn test_replace_device_pairings() {
+		let [old, new, other] = [
+			"12D3KooW9tHTtS3inCZiYykw4u5G4frbjVFqhkmJX12gSNCVeH3e",
+			"12D3KooW9xCm2jWjNVrwh51SWCQBMYdMyeU3NpT85QhLVkF6PcNM",
+			"12D3KooWA284B2yjxoAAqAFwwVj6eRQ8DogF3t8wdpMzZ8Hh8wh4",
+		]
+		.map(|id| PeerId::from_str(id).unwrap());
+		let (device_id, other_device_id) = (Uuid::new_v4(), Uuid::new_v4());
+
+		let mut config = serde_json::from_value::<NodeConfig>(serde_json::json!({
+			"id": Uuid::new_v4(),
+			"name": "Spacedrive",
+		}))
+		.unwrap();
+		for (peer_id, device_id) in [(old, device_id), (new, device_id), (other, other_device_id)] {
+			config.p2p_paired_peers.insert(peer_id);
+			config
+				.p2p_stream_keys
+				.insert(peer_id, StreamKey::new(sd_crypto::types::Key::generate()));
+			config.p2p_known_peers.insert(
+				peer_id,
+				KnownPeer {
+					paired: true,
+					nickname: (peer_id == old).then(|| "MacBook".to_string()),
+					device_id: Some(device_id),
+					..Default::default()
+				},
+			);
+		}
+
+		// The device changed its keypair so its old pairing is replaced, but the other device is left alone
+		assert_eq!(
+			replace_device_pairings(&mut config, new, device_id),
+			vec![old]
+		);
+		assert_eq!(config.p2p_paired_peers, HashSet::from([new, other]));
+		assert!(!config.p2p_stream_keys.contains_key(&old));
+		assert_eq!(
+			config.p2p_known_peers[&new].nickname.as_deref(),
+			Some("MacBook")
+		);
+		assert!(!config.p2p_known_peers.contains_key(&old));
+
+		assert!(replace_device_pairings(&mut config, new, device_id).is_empty());
+	}
+
+	#[test]
+	fn test_normalize_nickname() {
+		assert_eq!(
+			normalize_nickname(Some("  My desktop ")),
+			Some("My desktop".into())
+		);
+		assert_eq!(normalize_nickname(Some("   ")), None);
+		assert_eq!(normalize_nickname(None), None);
+	}
+
+	#[test]
+	fn test_is_shareable_addr() {
+		for addr in ["192.168.1.5:7373", "10.0.0.1:1", "[2001:db8::1]:7373"] {
+			assert!(is_shareable_addr(&addr.parse().unwrap()), "{addr}");
+		}
+
+		for addr in [
+			"127.0.0.1:7373",
+			"0.0.0.0:7373",
+			"169.254.10.1:7373",
+			"[::1]:7373",
+			"[::]:7373",
+			"[fe80::1]:7373",
+		] {
+			assert!(!is_shareable_addr(&addr.parse().unwrap()), "{addr}");
+		}
+	}
+
+	#[tokio::test]
+	async fn test_request_timeout() {
+		// The peer accepts the stream but never responds
+		let (mut stream, _peer) = tokio::io::duplex(1024);
+
+		let result = send_request(
+			&mut stream,
+			&Request::Ping,
+			&Compression::default(),
+			Duration::from_millis(100),
+		)
+		.await;
+		assert!(matches!(result, Err(P2PError::Timeout)));
+	}
+
+	#[tokio::test]
+	async fn test_request_response() {
+		let (mut stream, mut peer) = tokio::io::duplex(1024);
+
+		tokio::spawn(async move {
+			let header = Header::from_reader(&mut peer).await.unwrap();
+			assert_eq!(header, Header::Request);
+
+			let request = read_message::<Request>(&mut peer).await.unwrap();
+			assert_eq!(request, Request::Ping);
+			write_message(&mut peer, &Response::Pong).await.unwrap();
+		});
+
+		let result = send_request(
+			&mut stream,
+			&Request::Ping,
+			&Compression::default(),
+			Duration::from_secs(5),
+		)
+		.await;
+		assert!(matches!(result, Ok(Response::Pong)));
+	}
+
+	#[tokio::test]
+	async fn test_concurrent_requests() {
+		let (mut a, mut peer_a) = tokio::io::duplex(1024);
+		let (mut b, mut peer_b) = tokio::io::duplex(1024);
+
+		// The peer responds to the second request before the first
+		tokio::spawn(async move {
+			for peer in [&mut peer_a, &mut peer_b] {
+				Header::from_reader(&mut *peer).await.unwrap();
+				read_message::<Request>(&mut *peer).await.unwrap();
+			}
+
+			write_message(&mut peer_b, &Response::Error("b".into()))
+				.await
+				.unwrap();
+			write_message(&mut peer_a, &Response::Error("a".into()))
+				.await
+				.unwrap();
+		});
+
+		let (a, b) = tokio::join!(
+			send_request(
+				&mut a,
+				&Request::Ping,
+				&Compression::default(),
+				Duration::from_secs(5)
+			),
+			send_request(
+				&mut b,
+				&Request::Ping,
+				&Compression::default(),
+				Duration::from_secs(5)
+			),
+		);
+		assert!(matches!(a, Ok(Response::Error(err)) if err == "a"));
+		assert!(matches!(b, Ok(Response::Error(err)) if err == "b"));
+	}
+}
@@ -0,0 +1,585 @@
+use std::{
+	collections::HashMap,
+	time::{Duration, Instant},
+};
+
+use sd_crypto::{primitives::KEY_LEN, types::Key};
+use sd_p2p::PeerId;
+use thiserror::Error;
+use tokio::sync::Mutex;
+
+use super::StreamKey;
+
+/// the BLAKE3 context used to commit to the initiator's ephemeral secret before the responder has sent its own.
+const PAIRING_COMMITMENT_CONTEXT: &str = "spacedrive 2023-04-03 14:20:51 pairing commitment";
+
+/// the BLAKE3 context used to derive the pairing code from the ephemeral secrets of both peers.
+const PAIRING_CODE_CONTEXT: &str = "spacedrive 2023-03-21 10:12:37 pairing code derivation";
+
+/// the BLAKE3 context used to derive the key unicast streams are encrypted with from the ephemeral secrets of both peers.
+const STREAM_KEY_CONTEXT: &str = "spacedrive 2023-03-28 09:41:05 stream key derivation";
+
+/// how long the user has to confirm a pairing code before the ephemeral secrets are discarded and pairing must be restarted.
+pub const PAIRING_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// how often the pairings are checked for any which have expired, see [Pairings::expire].
+pub const PAIRING_EXPIRY_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Error)]
+pub enum PairingError {
+	#[error("no pairing is in progress with this peer")]
+	NotPending,
+	#[error("the pairing code has expired")]
+	Expired,
+	#[error("the pairing code doesn't match")]
+	CodeMismatch,
+	#[error("a pairing is already in progress with this peer")]
+	AlreadyPending,
+	#[error("the pairing code hasn't been entered on the other device yet")]
+	NotConfirmed,
+	#[error("the pairing secret has the wrong length")]
+	InvalidSecret,
+	#[error("the pairing commitment has the wrong length")]
+	InvalidCommitment,
+	#[error("the pairing secret doesn't match the commitment sent by the peer")]
+	CommitmentMismatch,
+}
+
+/// check_secret returns an error if an ephemeral secret sent by a peer isn't the length of the ones we generate, so a short or empty secret can't weaken the pairing code or stream key.
+fn check_secret(secret: &[u8]) -> Result<(), PairingError> {
+	if secret.len() == KEY_LEN {
+		Ok(())
+	} else {
+		Err(PairingError::InvalidSecret)
+	}
+}
+
+/// pairing_commitment is sent by the initiator with `Request::PairingStart` in place of its ephemeral secret, which is only revealed once the responder has sent its own.
+/// Neither peer can then choose its secret after seeing the other's, so someone intercepting the connection can't search for secrets which make the codes on both devices match.
+pub fn pairing_commitment(secret: &[u8]) -> [u8; 32] {
+	blake3::derive_key(PAIRING_COMMITMENT_CONTEXT, secret)
+}
+
+/// pairing_code derives the 6-digit code which is shown to the user on both devices.
+/// The peer ids are included so the code will differ if someone has intercepted the connection, Eg. an impostor advertising the same name as the peer.
+/// This is only called by [Pairings] once the secrets have been exchanged with [pairing_commitment], as otherwise whoever sends their secret last could search for one which makes the codes match.
+fn pairing_code(
+	initiator: &PeerId,
+	responder: &PeerId,
+	initiator_secret: &[u8],
+	responder_secret: &[u8],
+) -> String {
+	let hash = blake3::derive_key(
+		PAIRING_CODE_CONTEXT,
+		&pairing_input(initiator, responder, initiator_secret, responder_secret),
+	);
+	let code = u32::from_le_bytes([hash[0], hash[1], hash[2], hash[3]]) % 1_000_000;
+	format!("{code:06}")
+}
+
+/// stream_key derives the key both peers will encrypt their unicast streams with once the pairing is confirmed.
+/// A different context is used to the pairing code so knowing the code reveals nothing about the key.
+fn stream_key(
+	initiator: &PeerId,
+	responder: &PeerId,
+	initiator_secret: &[u8],
+	responder_secret: &[u8],
+) -> StreamKey {
+	StreamKey::new(Key::new(blake3::derive_key(
+		STREAM_KEY_CONTEXT,
+		&pairing_input(initiator, responder, initiator_secret, responder_secret),
+	)))
+}
+
+fn pairing_input(
+	initiator: &PeerId,
+	responder: &PeerId,
+	initiator_secret: &[u8],
+	responder_secret: &[u8],
+) -> Vec<u8> {
+	let mut input = Vec::new();
+	input.extend_from_slice(initiator.to_string().as_bytes());
+	input.extend_from_slice(responder.to_string().as_bytes());
+	input.extend_from_slice(initiator_secret);
+	input.extend_from_slice(responder_secret);
+	input
+}
+
+/// compares the codes in constant-time so the time taken doesn't leak how many digits were correct.
+fn codes_match(a: &str, b: &str) -> bool {
+	a.len() == b.len()
+		&& a.bytes()
+			.zip(b.bytes())
+			.fold(0, |acc, (a, b)| acc | (a ^ b))
+			== 0
+}
+
+/// the ephemeral secrets of a pairing, see [Pairings::commit].
+enum PairingSecrets {
+	/// the responder is waiting for the initiator to reveal the secret it committed to. `secret` is the responder's own, which has already been sent to the initiator.
+	Committed {
+		commitment: blake3::Hash,
+		secret: Key,
+	},
+	/// both secrets are known so the code and the stream key have been derived.
+	Revealed { code: String, stream_key: StreamKey },
+}
+
+struct PendingPairing {
+	secrets: PairingSecrets,
+	/// did this node initiate the pairing
+	initiator: bool,
+	/// has the code been confirmed. The pairing is then waiting for the user on the initiator to accept it, see [Pairings::accept].
+	confirmed: bool,
+	started_at: Instant,
+}
+
+/// Pairings keeps track of the pairings which are waiting for the user to confirm the code, and then for the initiator to accept them.
+///
+/// A pairing goes through [Pairings::start] on the initiator, or [Pairings::commit] and [Pairings::reveal] on the responder, and then [Pairings::confirm] and [Pairings::accept] on both nodes.
+/// The initiator only shares its library keys once its user has accepted the pairing, so a device can't be paired without the user on the initiator seeing it.
+#[derive(Default)]
+pub struct Pairings(Mutex<HashMap<PeerId, PendingPairing>>);
+
+impl Pairings {
+	/// start a pairing we initiated with the peer once we've received its secret in exchange for our commitment, and return the code to show the user.
+	/// `PairingError::AlreadyPending` is returned if a pairing is already in progress with the peer.
+	pub async fn start(
+		&self,
+		peer_id: PeerId,
+		local: PeerId,
+		secret: &Key,
+		remote_secret: &[u8],
+	) -> Result<String, PairingError> {
+		check_secret(remote_secret)?;
+
+		let code = pairing_code(&local, &peer_id, secret.expose(), remote_secret);
+		let stream_key = stream_key(&local, &peer_id, secret.expose(), remote_secret);
+		self.insert(
+			peer_id,
+			PairingSecrets::Revealed {
+				code: code.clone(),
+				stream_key,
+			},
+			true,
+		)
+		.await?;
+		Ok(code)
+	}
+
+	/// commit starts a pairing the peer initiated with the commitment from its `PairingStart`. `secret` is our own secret, which is sent to the peer in return.
+	/// `PairingError::AlreadyPending` is returned if a pairing is already in progress with the peer, so it can't be replaced by another `PairingStart` before it has expired.
+	pub async fn commit(
+		&self,
+		peer_id: PeerId,
+		commitment: &[u8],
+		secret: Key,
+	) -> Result<(), PairingError> {
+		let commitment = <[u8; 32]>::try_from(commitment)
+			.map_err(|_| PairingError::InvalidCommitment)?
+			.into();
+		self.insert(
+			peer_id,
+			PairingSecrets::Committed { commitment, secret },
+			false,
+		)
+		.await
+	}
+
+	/// reveal checks the secret the initiator revealed against its commitment and derives the code and stream key, restarting the [PAIRING_TIMEOUT] so the user has time to enter the code.
+	/// The pending pairing is removed if the secret doesn't match, so the code is never derived from a secret the initiator chose after seeing ours.
+	pub async fn reveal(
+		&self,
+		peer_id: PeerId,
+		local: PeerId,
+		secret: &[u8],
+	) -> Result<(), PairingError> {
+		check_secret(secret)?;
+
+		let mut pairings = self.0.lock().await;
+		let pairing = pairings
+			.get_mut(&peer_id)
+			.filter(|pairing| !pairing.initiator)
+			.ok_or(PairingError::NotPending)?;
+		let PairingSecrets::Committed {
+			commitment,
+			secret: local_secret,
+		} = &pairing.secrets
+		else {
+			return Err(PairingError::NotPending);
+		};
+
+		if pairing.started_at.elapsed() >= PAIRING_TIMEOUT {
+			pairings.remove(&peer_id);
+			return Err(PairingError::Expired);
+		}
+
+		// `blake3::Hash` is compared in constant-time
+		if blake3::Hash::from(pairing_commitment(secret)) != *commitment {
+			pairings.remove(&peer_id);
+			return Err(PairingError::CommitmentMismatch);
+		}
+
+		pairing.secrets = PairingSecrets::Revealed {
+			code: pairing_code(&peer_id, &local, secret, local_secret.expose()),
+			stream_key: stream_key(&peer_id, &local, secret, local_secret.expose()),
+		};
+		pairing.started_at = Instant::now();
+		Ok(())
+	}
+
+	async fn insert(
+		&self,
+		peer_id: PeerId,
+		secrets: PairingSecrets,
+		initiator: bool,
+	) -> Result<(), PairingError> {
+		let mut pairings = self.0.lock().await;
+		if pairings.get(&peer_id).map_or(false, |pairing| {
+			pairing.started_at.elapsed() < PAIRING_TIMEOUT
+		}) {
+			return Err(PairingError::AlreadyPending);
+		}
+
+		pairings.insert(
+			peer_id,
+			PendingPairing {
+				secrets,
+				initiator,
+				confirmed: false,
+				started_at: Instant::now(),
+			},
+		);
+		Ok(())
+	}
+
+	/// confirm checks the code against the pending pairing with the peer. If it matches the pairing then waits to be accepted with [Pairings::accept], which restarts its [PAIRING_TIMEOUT].
+	/// The pending pairing is removed if the code is wrong so it can't be guessed by retrying.
+	pub async fn confirm(
+		&self,
+		peer_id: PeerId,
+		code: &str,
+		initiator: bool,
+	) -> Result<(), PairingError> {
+		let mut pairings = self.0.lock().await;
+		let pairing = pairings
+			.get_mut(&peer_id)
+			.filter(|pairing| pairing.initiator == initiator && !pairing.confirmed)
+			.ok_or(PairingError::NotPending)?;
+		let PairingSecrets::Revealed { code: expected, .. } = &pairing.secrets else {
+			return Err(PairingError::NotPending);
+		};
+
+		if pairing.started_at.elapsed() >= PAIRING_TIMEOUT {
+			pairings.remove(&peer_id);
+			return Err(PairingError::Expired);
+		}
+
+		if !codes_match(expected, code) {
+			pairings.remove(&peer_id);
+			return Err(PairingError::CodeMismatch);
+		}
+
+		pairing.confirmed = true;
+		pairing.started_at = Instant::now();
+		Ok(())
+	}
+
+	/// accept completes a pairing which has been confirmed and returns the key streams with the peer should be encrypted with.
+	/// `PairingError::NotConfirmed` is returned, and the pairing is left in progress, if the code hasn't been confirmed yet.
+	pub async fn accept(
+		&self,
+		peer_id: PeerId,
+		initiator: bool,
+	) -> Result<StreamKey, PairingError> {
+		let mut pairings = self.0.lock().await;
+		let pairing = pairings
+			.get(&peer_id)
+			.filter(|pairing| pairing.initiator == initiator)
+			.ok_or(PairingError::NotPending)?;
+
+		if pairing.started_at.elapsed() >= PAIRING_TIMEOUT {
+			pairings.remove(&peer_id);
+			return Err(PairingError::Expired);
+		}
+
+		if !pairing.confirmed {
+			return Err(PairingError::NotConfirmed);
+		}
+
+		match pairings.remove(&peer_id).map(|pairing| pairing.secrets) {
+			Some(PairingSecrets::Revealed { stream_key, .. }) => Ok(stream_key),
+			_ => Err(PairingError::NotPending),
+		}
+	}
+
+	/// reject discards the pairing with the peer, returning `false` if there wasn't one.
+	pub async fn reject(&self, peer_id: PeerId) -> bool {
+		self.0.lock().await.remove(&peer_id).is_some()
+	}
+
+	/// expire discards the pairings which weren't confirmed within [PAIRING_TIMEOUT] and returns the peers they were with, so the user can be told they have to be restarted.
+	pub async fn expire(&self) -> Vec<PeerId> {
+		self.expire_after(PAIRING_TIMEOUT).await
+	}
+
+	async fn expire_after(&self, timeout: Duration) -> Vec<PeerId> {
+		let mut expired = Vec::new();
+		self.0.lock().await.retain(|peer_id, pairing| {
+			let pending = pairing.started_at.elapsed() < timeout;
+			if !pending {
+				expired.push(*peer_id);
+			}
+			pending
+		});
+		expired
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{collections::HashSet, str::FromStr};
+
+	use super::*;
+
+	const PEERS: [&str; 3] = [
+		"12D3KooW9tHTtS3inCZiYykw4u5G4frbjVFqhkmJX12gSNCVeH3e",
+		"12D3KooW9xCm2jWjNVrwh51SWCQBMYdMyeU3NpT85QhLVkF6PcNM",
+		"12D3KooWA284B2yjxoAAqAFwwVj6eRQ8DogF3t8wdpMzZ8Hh8wh4",
+	];
+
+	fn peer_id(i: usize) -> PeerId {
+		PeerId::from_str(PEERS[i]).unwrap()
+	}
+
+	#[test]
+	fn test_pairing_code() {
+		let (a, b) = (peer_id(0), peer_id(1));
+		let code = pairing_code(&a, &b, b"initiator", b"responder");
+
+		assert_eq!(code.len(), 6);
+		assert!(code.chars().all(|c| c.is_ascii_digit()));
+		assert_eq!(code, pairing_code(&a, &b, b"initiator", b"responder"));
+		assert_ne!(
+			code,
+			pairing_code(&a, &peer_id(2), b"initiator", b"responder")
+		);
+	}
+
+	/// initiate starts a pairing with `peer` as the initiator, returning the code shown to the user.
+	async fn initiate(pairings: &Pairings, peer: PeerId) -> String {
+		pairings
+			.start(peer, peer_id(2), &Key::generate(), Key::generate().expose())
+			.await
+			.unwrap()
+	}
+
+	#[test]
+	fn test_stream_key() {
+		let (a, b) = (peer_id(0), peer_id(1));
+		let key = stream_key(&a, &b, b"initiator", b"responder");
+
+		assert_eq!(
+			String::from(key.clone()),
+			String::from(stream_key(&a, &b, b"initiator", b"responder"))
+		);
+		assert_ne!(
+			String::from(key),
+			String::from(stream_key(&b, &a, b"initiator", b"responder"))
+		);
+	}
+
+	#[test]
+	fn test_codes_match() {
+		assert!(codes_match("123456", "123456"));
+		assert!(!codes_match("123456", "123457"));
+		assert!(!codes_match("123456", "12345"));
+	}
+
+	#[test]
+	fn test_check_secret() {
+		assert!(check_secret(Key::generate().expose()).is_ok());
+		assert!(matches!(
+			check_secret(&[]),
+			Err(PairingError::InvalidSecret)
+		));
+		assert!(matches!(
+			check_secret(&[0; KEY_LEN - 1]),
+			Err(PairingError::InvalidSecret)
+		));
+	}
+
+	/// respond starts a pairing as the responder to `peer` and reveals its secret, returning the code the initiator would show.
+	async fn respond(pairings: &Pairings, peer: PeerId) -> String {
+		let (secret, local_secret) = (Key::generate(), Key::generate());
+		let code = pairing_code(&peer, &peer_id(2), secret.expose(), local_secret.expose());
+
+		pairings
+			.commit(peer, &pairing_commitment(secret.expose()), local_secret)
+			.await
+			.unwrap();
+		pairings
+			.reveal(peer, peer_id(2), secret.expose())
+			.await
+			.unwrap();
+		code
+	}
+
+	/// a code which doesn't match `code`.
+	fn wrong_code(code: &str) -> String {
+		format!("{:06}", (code.parse::<u32>().unwrap() + 1) % 1_000_000)
+	}
+
+	#[tokio::test]
+	async fn test_pairing_confirm() {
+		let pairings = Pairings::default();
+		let peer = peer_id(0);
+
+		let code = respond(&pairings, peer).await;
+
+		// The pairing can't be completed until the code is confirmed
+		assert!(matches!(
+			pairings.accept(peer, false).await,
+			Err(PairingError::NotConfirmed)
+		));
+		assert!(pairings.confirm(peer, &code, false).await.is_ok());
+
+		// The pairing can only be confirmed once
+		assert!(matches!(
+			pairings.confirm(peer, &code, false).await,
+			Err(PairingError::NotPending)
+		));
+		assert!(pairings.accept(peer, false).await.is_ok());
+		assert!(matches!(
+			pairings.accept(peer, false).await,
+			Err(PairingError::NotPending)
+		));
+
+		// A wrong code discards the pairing
+		let code = respond(&pairings, peer).await;
+		assert!(matches!(
+			pairings.confirm(peer, &wrong_code(&code), false).await,
+			Err(PairingError::CodeMismatch)
+		));
+		assert!(pairings.confirm(peer, &code, false).await.is_err());
+
+		// Only the other side of the pairing can confirm it
+		let code = initiate(&pairings, peer).await;
+		assert!(pairings.confirm(peer, &code, false).await.is_err());
+		assert!(pairings.accept(peer, false).await.is_err());
+	}
+
+	#[tokio::test]
+	async fn test_pairing_reveal() {
+		let pairings = Pairings::default();
+		let (peer, local) = (peer_id(0), peer_id(2));
+		let secret = Key::generate();
+
+		assert!(matches!(
+			pairings.commit(peer, &[0; 16], Key::generate()).await,
+			Err(PairingError::InvalidCommitment)
+		));
+
+		pairings
+			.commit(peer, &pairing_commitment(secret.expose()), Key::generate())
+			.await
+			.unwrap();
+
+		// There's no code to confirm until the secret has been revealed
+		assert!(matches!(
+			pairings.confirm(peer, "123456", false).await,
+			Err(PairingError::NotPending)
+		));
+
+		// A secret which doesn't match the commitment discards the pairing
+		assert!(matches!(
+			pairings.reveal(peer, local, Key::generate().expose()).await,
+			Err(PairingError::CommitmentMismatch)
+		));
+		assert!(matches!(
+			pairings.reveal(peer, local, secret.expose()).await,
+			Err(PairingError::NotPending)
+		));
+
+		// Only the responder reveals the initiator's secret
+		initiate(&pairings, peer).await;
+		assert!(matches!(
+			pairings.reveal(peer, local, secret.expose()).await,
+			Err(PairingError::NotPending)
+		));
+	}
+
+	#[tokio::test]
+	async fn test_pairing_impostor() {
+		let pairings = Pairings::default();
+		let (initiator, responder, impostor) = (peer_id(0), peer_id(1), peer_id(2));
+		let (secret, responder_secret) = (Key::generate(), Key::generate());
+
+		assert!(matches!(
+			pairings.start(impostor, initiator, &secret, &[]).await,
+			Err(PairingError::InvalidSecret)
+		));
+
+		// An impostor advertising the same name as the responder has a different peer id, so it gets a different code even if it relays the responder's secret
+		let code = pairings
+			.start(impostor, initiator, &secret, responder_secret.expose())
+			.await
+			.unwrap();
+		assert_ne!(
+			code,
+			pairing_code(
+				&initiator,
+				&responder,
+				secret.expose(),
+				responder_secret.expose()
+			)
+		);
+	}
+
+	#[tokio::test]
+	async fn test_pairing_already_pending() {
+		let pairings = Pairings::default();
+		let peer = peer_id(0);
+		let commitment = pairing_commitment(Key::generate().expose());
+
+		let code = initiate(&pairings, peer).await;
+
+		// Another `PairingStart` can't replace the pairing the user is confirming
+		assert!(matches!(
+			pairings.commit(peer, &commitment, Key::generate()).await,
+			Err(PairingError::AlreadyPending)
+		));
+		assert!(pairings.confirm(peer, &code, true).await.is_ok());
+
+		// A rejected pairing can be started again
+		assert!(pairings.reject(peer).await);
+		assert!(!pairings.reject(peer).await);
+		pairings
+			.commit(peer, &commitment, Key::generate())
+			.await
+			.unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_pairing_expire() {
+		let pairings = Pairings::default();
+		let (a, b) = (peer_id(0), peer_id(1));
+
+		let code = respond(&pairings, a).await;
+		initiate(&pairings, b).await;
+		assert!(pairings.expire().await.is_empty());
+
+		let expired = pairings.expire_after(Duration::ZERO).await;
+		assert_eq!(
+			expired.into_iter().collect::<HashSet<_>>(),
+			HashSet::from([a, b])
+		);
+
+		// An expired pairing is only reported once and can't be confirmed
+		assert!(pairings.expire_after(Duration::ZERO).await.is_empty());
+		assert!(matches!(
+			pairings.confirm(a, &code, false).await,
+			Err(PairingError::NotPending)
+		));
+	}
+}
@@ -1,34 +1,155 @@
-use std::{collections::HashMap, env, str::FromStr};
+use std::{collections::HashMap, env, ops::RangeInclusive, str::FromStr};
 
 use rspc::Type;
 use sd_p2p::Metadata;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-#[derive(Debug, Clone, Type, Serialize, Deserialize)]
+use crate::{
+	node::NodeConfig,
+	p2p::{MIN_PROTO_VERSION, PROTO_VERSION},
+};
+
+/// A single DNS TXT record entry (`key=value`) can be at most 255 bytes so we limit the advertised node name to stay well within it.
+const MAX_NAME_LEN: usize = 64;
+
+/// The `(major, minor)` versions of Spacedrive which this node can communicate with.
+/// Patch releases are always compatible so they aren't compared. Widen this range when a release doesn't break the protocol.
+pub const COMPATIBLE_VERSIONS: RangeInclusive<(u64, u64)> = (0, 1)..=(0, 1);
+
+#[derive(Debug, Clone, PartialEq, Eq, Type, Serialize, Deserialize)]
 pub struct PeerMetadata {
 	pub(super) name: String,
+	/// the id of the node the peer is running on, see [NodeConfig::id]. Unlike the peer id this isn't changed when the node's keypair is, so it identifies the same device across keypairs.
+	/// `None` for peers which were released before it was advertised.
+	pub(super) device_id: Option<Uuid>,
 	pub(super) operating_system: Option<OperatingSystem>,
+	/// the CPU architecture the peer is running on. Eg. `x86_64` or `aarch64`.
+	pub(super) architecture: Option<String>,
 	pub(super) version: Option<String>,
+	/// the peer's [PROTO_VERSION]. `None` for peers which were released before it was advertised.
+	pub(super) protocol_version: Option<u16>,
 	pub(super) email: Option<String>,
 	pub(super) img_url: Option<String>,
+	/// can the peer decode compressed payloads, see [super::Compression]. Peers which can't, or were released before it was advertised, are sent them uncompressed.
+	#[serde(default)]
+	pub(super) compression: bool,
+	pub(super) libraries: Vec<Uuid>,
+}
+
+impl PeerMetadata {
+	pub fn from_node_config(config: &NodeConfig, libraries: Vec<Uuid>) -> Self {
+		Self {
+			name: sanitize_name(&config.name),
+			device_id: Some(config.id),
+			operating_system: Some(OperatingSystem::get_os()),
+			architecture: Some(env::consts::ARCH.to_string()),
+			version: Some(env!("CARGO_PKG_VERSION").to_string()),
+			protocol_version: Some(PROTO_VERSION),
+			email: config.p2p_email.clone(),
+			img_url: config.p2p_img_url.clone(),
+			compression: cfg!(feature = "compress"),
+			libraries,
+		}
+	}
+}
+
+impl PeerMetadata {
+	/// is_compatible returns whether the peer's advertised version is within [COMPATIBLE_VERSIONS] and its protocol version is at least [MIN_PROTO_VERSION].
+	/// Peers which don't advertise a version are assumed to be compatible as the protocol version is still negotiated when connecting.
+	pub fn is_compatible(&self) -> bool {
+		self.is_compatible_with(MIN_PROTO_VERSION)
+	}
+
+	fn is_compatible_with(&self, min_proto_version: u16) -> bool {
+		let version_compatible = match &self.version {
+			Some(version) => {
+				parse_version(version).map_or(false, |v| COMPATIBLE_VERSIONS.contains(&v))
+			}
+			None => true,
+		};
+
+		version_compatible
+			&& self
+				.protocol_version
+				.map_or(true, |version| version >= min_proto_version)
+	}
+}
+
+/// parse_version returns the major and minor version of a semver version string. Eg. `0.1.0-beta` is `(0, 1)`.
+fn parse_version(version: &str) -> Option<(u64, u64)> {
+	let mut parts = version.trim_start_matches('v').split(['.', '-', '+']);
+	Some((parts.next()?.parse().ok()?, parts.next()?.parse().ok()?))
+}
+
+/// sanitize_name removes characters which can't be safely put into a DNS TXT record and truncates the name to `MAX_NAME_LEN` bytes.
+fn sanitize_name(name: &str) -> String {
+	let mut name = name
+		.trim()
+		.chars()
+		.filter(|c| !c.is_control())
+		.collect::<String>();
+
+	if name.len() > MAX_NAME_LEN {
+		let mut idx = MAX_NAME_LEN;
+		while !name.is_char_boundary(idx) {
+			idx -= 1;
+		}
+		name.truncate(idx);
+	}
+
+	name
+}
+
+/// The fields of [PeerMetadata] which are also advertised typed so they aren't stringified and reparsed.
+#[derive(Serialize, Deserialize)]
+pub struct TypedPeerMetadata {
+	operating_system: Option<OperatingSystem>,
+	architecture: Option<String>,
+	version: Option<String>,
 }
 
 impl Metadata for PeerMetadata {
+	type Typed = TypedPeerMetadata;
+
 	fn to_hashmap(self) -> HashMap<String, String> {
 		let mut map = HashMap::with_capacity(3);
 		map.insert("name".to_owned(), self.name);
+		if let Some(device_id) = self.device_id {
+			map.insert("device".to_owned(), device_id.simple().to_string());
+		}
 		if let Some(os) = self.operating_system {
 			map.insert("os".to_owned(), os.to_string());
 		}
+		if let Some(arch) = self.architecture {
+			map.insert("arch".to_owned(), arch);
+		}
 		if let Some(version) = self.version {
 			map.insert("version".to_owned(), version);
 		}
+		if let Some(protocol_version) = self.protocol_version {
+			map.insert("proto".to_owned(), protocol_version.to_string());
+		}
 		if let Some(email) = self.email {
 			map.insert("email".to_owned(), email);
 		}
 		if let Some(img_url) = self.img_url {
 			map.insert("img_url".to_owned(), img_url);
 		}
+		if self.compression {
+			map.insert("zstd".to_owned(), "1".to_owned());
+		}
+		// TODO: A TXT record entry is limited to 255 bytes so this will only fit ~7 libraries. This should be moved to a handshake.
+		if !self.libraries.is_empty() {
+			map.insert(
+				"libraries".to_owned(),
+				self.libraries
+					.iter()
+					.map(|id| id.simple().to_string())
+					.collect::<Vec<_>>()
+					.join(","),
+			);
+		}
 		map
 	}
 
@@ -44,26 +165,70 @@ impl Metadata for PeerMetadata {
 						.to_owned()
 				})?
 				.to_owned(),
+			device_id: data
+				.get("device")
+				.map(|v| Uuid::from_str(v).map_err(|_| "Unable to parse 'device_id'!"))
+				.transpose()?,
 			operating_system: data
 				.get("os")
 				.map(|os| os.parse().map_err(|_| "Unable to parse 'OperationSystem'!"))
 				.transpose()?,
+			architecture: data.get("arch").map(|v| v.to_owned()),
 			version: data.get("version").map(|v| v.to_owned()),
+			protocol_version: data
+				.get("proto")
+				.map(|v| v.parse().map_err(|_| "Unable to parse 'protocol_version'!"))
+				.transpose()?,
 			email: data.get("email").map(|v| v.to_owned()),
 			img_url: data.get("img_url").map(|v| v.to_owned()),
+			compression: data.get("zstd").map_or(false, |v| v == "1"),
+			libraries: data
+				.get("libraries")
+				.map(|v| {
+					v.split(',')
+						.map(Uuid::from_str)
+						.collect::<Result<Vec<_>, _>>()
+						.map_err(|_| "Unable to parse 'libraries'!")
+				})
+				.transpose()?
+				.unwrap_or_default(),
+		})
+	}
+
+	fn to_typed(&self) -> Option<Self::Typed> {
+		Some(TypedPeerMetadata {
+			operating_system: self.operating_system.clone(),
+			architecture: self.architecture.clone(),
+			version: self.version.clone(),
+		})
+	}
+
+	fn from_typed(typed: Self::Typed, data: &HashMap<String, String>) -> Result<Self, String>
+	where
+		Self: Sized,
+	{
+		Ok(Self {
+			operating_system: typed.operating_system,
+			architecture: typed.architecture,
+			version: typed.version,
+			..Self::from_hashmap(data)?
 		})
 	}
 }
 
 /// Represents the operating system which the remote peer is running.
 /// This is not used internally and predominantly is designed to be used for display purposes by the embedding application.
-#[derive(Debug, Clone, Type, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Type, Serialize, Deserialize)]
 pub enum OperatingSystem {
 	Windows,
 	Linux,
 	MacOS,
 	Ios,
 	Android,
+	FreeBSD,
+	OpenBSD,
+	NetBSD,
+	/// any other operating system, holding the raw value of [std::env::consts::OS].
 	Other(String),
 }
 
@@ -75,6 +240,9 @@ impl OperatingSystem {
 			"linux" => OperatingSystem::Linux,
 			"ios" => OperatingSystem::Ios,
 			"android" => OperatingSystem::Android,
+			"freebsd" => OperatingSystem::FreeBSD,
+			"openbsd" => OperatingSystem::OpenBSD,
+			"netbsd" => OperatingSystem::NetBSD,
 			platform => OperatingSystem::Other(platform.into()),
 		}
 	}
@@ -88,11 +256,11 @@ impl ToString for OperatingSystem {
 			OperatingSystem::MacOS => "MacOS".into(),
 			OperatingSystem::Ios => "IOS".into(),
 			OperatingSystem::Android => "Android".into(),
-			OperatingSystem::Other(s) => {
-				let mut chars = s.chars();
-				chars.next();
-				chars.as_str().to_string()
-			}
+			OperatingSystem::FreeBSD => "FreeBSD".into(),
+			OperatingSystem::OpenBSD => "OpenBSD".into(),
+			OperatingSystem::NetBSD => "NetBSD".into(),
+			// `std::env::consts::OS` is always lowercase so this can't be mistaken for one of the variants above by older peers, which only check the first character.
+			OperatingSystem::Other(s) => s.clone(),
 		}
 	}
 }
@@ -101,6 +269,13 @@ impl FromStr for OperatingSystem {
 	type Err = ();
 
 	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"FreeBSD" => return Ok(OperatingSystem::FreeBSD),
+			"OpenBSD" => return Ok(OperatingSystem::OpenBSD),
+			"NetBSD" => return Ok(OperatingSystem::NetBSD),
+			_ => {}
+		}
+
 		let mut chars = s.chars();
 		match chars.next() {
 			Some('W') => Ok(OperatingSystem::Windows),
@@ -112,3 +287,134 @@ impl FromStr for OperatingSystem {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use sd_p2p::{decode_metadata, encode_metadata};
+
+	use super::*;
+
+	#[test]
+	fn test_version_compatibility() {
+		let metadata = |version: Option<&str>| PeerMetadata {
+			name: "Spacedrive".into(),
+			device_id: None,
+			operating_system: None,
+			architecture: None,
+			version: version.map(Into::into),
+			protocol_version: Some(PROTO_VERSION),
+			email: None,
+			img_url: None,
+			compression: false,
+			libraries: Vec::new(),
+		};
+
+		// The local version must always be compatible with itself
+		assert!(metadata(Some(env!("CARGO_PKG_VERSION"))).is_compatible());
+		assert!(metadata(None).is_compatible());
+
+		let (major, minor) = *COMPATIBLE_VERSIONS.end();
+		assert!(metadata(Some(&format!("{major}.{minor}.99"))).is_compatible());
+		assert!(metadata(Some(&format!("{major}.{minor}.0-beta.1"))).is_compatible());
+		assert!(!metadata(Some(&format!("{major}.{}.0", minor + 1))).is_compatible());
+		assert!(!metadata(Some(&format!("{}.0.0", major + 1))).is_compatible());
+		assert!(!metadata(Some("not a version")).is_compatible());
+	}
+
+	#[test]
+	fn test_protocol_version_compatibility() {
+		let metadata = |protocol_version: Option<u16>| PeerMetadata {
+			name: "Spacedrive".into(),
+			device_id: None,
+			operating_system: None,
+			architecture: None,
+			version: None,
+			protocol_version,
+			email: None,
+			img_url: None,
+			compression: false,
+			libraries: Vec::new(),
+		};
+
+		assert!(metadata(Some(PROTO_VERSION)).is_compatible());
+		assert!(metadata(None).is_compatible());
+		assert!(!metadata(Some(MIN_PROTO_VERSION - 1)).is_compatible());
+
+		// A node which has dropped support for v1 won't dial or sync with a v1 node, but a v1 node can still talk to another v1 node
+		let (v1, v2) = (metadata(Some(1)), metadata(Some(2)));
+		assert!(!v1.is_compatible_with(2));
+		assert!(v2.is_compatible_with(2));
+		assert!(v2.is_compatible_with(1));
+
+		let data = encode_metadata(v1.clone());
+		assert_eq!(decode_metadata::<PeerMetadata>(&data).as_ref(), Ok(&v1));
+	}
+
+	#[test]
+	fn test_typed_metadata_roundtrip() {
+		let metadata = PeerMetadata {
+			name: "Spacedrive".into(),
+			device_id: Some(Uuid::new_v4()),
+			operating_system: Some(OperatingSystem::Other("dragonfly".into())),
+			architecture: Some("aarch64".into()),
+			version: Some("0.1.0".into()),
+			protocol_version: Some(PROTO_VERSION),
+			email: None,
+			img_url: None,
+			compression: true,
+			libraries: vec![Uuid::new_v4()],
+		};
+
+		let data = encode_metadata(metadata.clone());
+		assert_eq!(
+			decode_metadata::<PeerMetadata>(&data).as_ref(),
+			Ok(&metadata)
+		);
+	}
+
+	#[test]
+	fn test_metadata_without_device_id() {
+		let metadata = PeerMetadata {
+			name: "Spacedrive".into(),
+			device_id: Some(Uuid::new_v4()),
+			operating_system: None,
+			architecture: None,
+			version: None,
+			protocol_version: None,
+			email: None,
+			img_url: None,
+			compression: false,
+			libraries: Vec::new(),
+		};
+
+		let mut data = metadata.clone().to_hashmap();
+		assert_eq!(PeerMetadata::from_hashmap(&data).as_ref(), Ok(&metadata));
+
+		// Peers which were released before the device id was advertised are still decoded
+		data.remove("device");
+		assert_eq!(
+			PeerMetadata::from_hashmap(&data).map(|m| m.device_id),
+			Ok(None)
+		);
+
+		data.insert("device".into(), "not a uuid".into());
+		assert!(PeerMetadata::from_hashmap(&data).is_err());
+	}
+
+	#[test]
+	fn test_operating_system_string_roundtrip() {
+		for os in [
+			OperatingSystem::Windows,
+			OperatingSystem::Linux,
+			OperatingSystem::MacOS,
+			OperatingSystem::Ios,
+			OperatingSystem::Android,
+			OperatingSystem::FreeBSD,
+			OperatingSystem::OpenBSD,
+			OperatingSystem::NetBSD,
+			OperatingSystem::Other("dragonfly".into()),
+		] {
+			assert_eq!(os.to_string().parse(), Ok(os));
+		}
+	}
+}
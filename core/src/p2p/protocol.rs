@@ -1,7 +1,20 @@
-use tokio::io::AsyncReadExt;
+use futures::{
+	stream::{self, BoxStream},
+	StreamExt,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use uuid::Uuid;
 
-use sd_p2p::{spaceblock::TransferRequest, spacetime::SpaceTimeStream};
+use sd_p2p::{spaceblock::TransferRequest, spacetime::SpaceTimeStream, PeerId};
+
+use crate::library::SyncKey;
+
+use super::{
+	decode_payload, read_file_chunk, resume_file_transfer, Compression, FileChecksum, Lane,
+	P2PManager, PeerMetadata, SignedOperation, SyncCheckpoint, TransferId,
+};
 
 /// TODO
 #[derive(Debug, PartialEq, Eq)]
@@ -9,34 +22,599 @@ pub enum Header {
 	Ping,
 	Spacedrop(TransferRequest),
 	Sync(Uuid, u32),
+	/// a [Request] will follow the header and the peer expects a [Response] to be written back.
+	Request,
+	/// the same as [Header::Request] but the rest of the stream is wrapped in an [super::EncryptedStream] using the key established during pairing.
+	EncryptedRequest,
+}
+
+/// A request sent to a single peer using [crate::p2p::P2PManager::send_to].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Request {
+	/// sent on first contact with a peer so both nodes know which requests the other understands.
+	/// This must never be changed as it's decoded before the version is known.
+	Hello {
+		proto_version: u16,
+	},
+	Ping,
+	/// the same as [Request::Ping] but the peer echos `sent_at` back with [Response::TimedPong].
+	/// `sent_at` comes from [super::ping_timestamp] so the round-trip time can be measured using only the sender's clock.
+	TimedPing {
+		sent_at: u64,
+	},
+	/// request a range of a file. `expected_size` should be set to the size returned by the first chunk so the transfer errors if the file is changed.
+	FileChunk {
+		library_id: Uuid,
+		location_id: i32,
+		file_path_id: i32,
+		offset: u64,
+		len: u32,
+		expected_size: Option<u64>,
+	},
+	/// the same as [Request::FileChunk] but the peer replies with [Response::FileChunkWithChecksum] so the received file can be verified.
+	FileChunkWithChecksum {
+		library_id: Uuid,
+		location_id: i32,
+		file_path_id: i32,
+		offset: u64,
+		len: u32,
+		expected_size: Option<u64>,
+	},
+	/// start pairing with the peer. `commitment` is the [super::pairing_commitment] of the initiator's ephemeral secret used to derive the pairing code, which is sent with [Request::PairingReveal] once the responder has replied with its own.
+	PairingStart {
+		commitment: Vec<u8>,
+	},
+	/// sent by the responder once the user has entered the code shown on the initiator.
+	/// Initiators running [PAIRING_ACCEPT_PROTO_VERSION] or later reply with [Response::PairingPending] and send [Request::PairingComplete] once their user accepts the pairing.
+	PairingConfirm {
+		code: String,
+	},
+	/// ask for the peer's metadata. This is used for peers which were connected to without being discovered over mDNS.
+	Metadata,
+	/// ask for the libraries the peer is willing to share so the user can pick which to pair with.
+	SharedLibraries,
+	/// the same as [Request::SharedLibraries] but the peer streams each library back as a [Response::SharedLibrary] as soon as it's ready.
+	StreamSharedLibraries,
+	/// a batch of sync operations sent with reliable sync. The peer replies with [Response::SyncAck].
+	/// `sequence` is scoped to the library and the sender's `epoch`, see [super::SyncOutbox].
+	SyncBatch {
+		library_id: Uuid,
+		epoch: Uuid,
+		sequence: u64,
+		operations: Vec<SignedOperation>,
+	},
+	/// ask for the operations in a library which are newer than the checkpoint. The peer replies with [Response::SyncOperations].
+	/// This is sent when a peer reconnects so only the operations it missed are transferred, see [super::SyncCheckpoints].
+	SyncOperations {
+		library_id: Uuid,
+		since: SyncCheckpoint,
+	},
+	/// the same as [Request::SyncOperations] but the peer replies with [Response::SyncOperationsWithTotal] so the progress of the initial sync can be shown.
+	SyncOperationsWithTotal {
+		library_id: Uuid,
+		since: SyncCheckpoint,
+	},
+	/// ask to be sent the sync events created in a library. The peer replies with [Response::Subscribed] and is subscribed to our sync events in return.
+	/// Sync events are only sent to the subscribers of a library, see [super::Subscriptions].
+	Subscribe(Uuid),
+	/// stop receiving the sync events of a library, Eg. because it was unloaded. The peer replies with [Response::Unsubscribed].
+	Unsubscribe(Uuid),
+	/// sent to each connected peer when our metadata changes, Eg. the node was renamed, so it doesn't have to wait for us to be readvertised or rediscovered. The peer replies with [Response::Pong].
+	MetadataChanged(PeerMetadata),
+	/// sent before resuming an interrupted [Request::FileChunkWithChecksum] transfer. `checksum` is the hash of the first `offset` bytes which were already received.
+	/// The peer replies with [Response::TransferResumed] once it has checked they're still the start of the file, or an error if `offset` is past the end of the file.
+	ResumeTransfer {
+		transfer_id: TransferId,
+		offset: u64,
+		checksum: FileChecksum,
+	},
+	/// sent by the initiator of a pairing once its user has accepted it. Contains the sync keys of the libraries both nodes are a member of.
+	/// This is only accepted after the responder has confirmed the code with [Request::PairingConfirm]. The responder replies with [Response::Pong].
+	PairingComplete {
+		library_keys: Vec<(Uuid, SyncKey)>,
+	},
+	/// sent by the initiator of a pairing once it has received the responder's secret with [Response::PairingStarted]. The responder checks it against the commitment from [Request::PairingStart] before asking its user for the code, and replies with [Response::Pong].
+	PairingReveal {
+		secret: Vec<u8>,
+	},
+}
+
+/// The response to a [Request].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Response {
+	Hello {
+		proto_version: u16,
+	},
+	Pong,
+	/// the `sent_at` of the [Request::TimedPing] being responded to.
+	TimedPong {
+		sent_at: u64,
+	},
+	FileChunk {
+		bytes: Vec<u8>,
+		/// the total size of the file
+		size: u64,
+		/// is this the last chunk of the file
+		eof: bool,
+	},
+	/// the same as [Response::FileChunk] but the first chunk of a transfer (when `expected_size` wasn't set) includes the checksum of the whole file.
+	FileChunkWithChecksum {
+		bytes: Vec<u8>,
+		size: u64,
+		eof: bool,
+		checksum: Option<FileChecksum>,
+	},
+	/// the responder's ephemeral secret used to derive the pairing code.
+	PairingStarted {
+		secret: Vec<u8>,
+	},
+	/// the code matched and the pairing is waiting for the user on the initiator to accept it, see [Request::PairingComplete].
+	PairingPending,
+	/// the pairing was confirmed. Contains the sync keys of the libraries both nodes are a member of.
+	/// This is only sent by initiators older than [PAIRING_ACCEPT_PROTO_VERSION], newer ones reply with [Response::PairingPending].
+	PairingAccepted {
+		library_keys: Vec<(Uuid, SyncKey)>,
+	},
+	Metadata(PeerMetadata),
+	SharedLibraries(Vec<SharedLibrary>),
+	/// a single frame of the response to [Request::StreamSharedLibraries].
+	SharedLibrary(SharedLibrary),
+	/// the highest contiguous sequence of [Request::SyncBatch] which has been applied from the sender. Every batch after it should be retransmitted.
+	SyncAck {
+		applied: u64,
+	},
+	/// the operations after the requested checkpoint in timestamp order. If `more` is set the request should be repeated with the checkpoint advanced past them.
+	SyncOperations {
+		operations: Vec<SignedOperation>,
+		more: bool,
+	},
+	/// the same as [Response::SyncOperations] but `total` is the number of operations after the requested checkpoint, including the ones in this response.
+	SyncOperationsWithTotal {
+		operations: Vec<SignedOperation>,
+		more: bool,
+		total: u64,
+	},
+	/// the requester is subscribed to the library and the responder should be added to the requester's subscribers.
+	Subscribed,
+	/// the requester is no longer subscribed to the library and the responder should be removed from the requester's subscribers.
+	Unsubscribed,
+	/// the request couldn't be decoded or handled by the remote peer.
+	Error(String),
+	/// the request was malformed or the responder didn't finish handling it within its timeout.
+	/// Peers older than [PROTOCOL_ERROR_PROTO_VERSION] fail to decode this so the request still fails on their end.
+	ProtocolError(String),
+	/// the last frame of a streamed response, see [Request::is_streaming].
+	EndOfStream,
+	/// the transfer should continue from `offset`. This is 0 if the bytes which were already received aren't the start of the file anymore, so it has to be transferred again.
+	/// `checksum` is the checksum of the whole file, as it isn't sent with the chunks of a resumed transfer.
+	TransferResumed {
+		offset: u64,
+		size: u64,
+		checksum: FileChecksum,
+	},
+}
+
+/// What a handler replies to a [Request] with.
+/// Requests which stream their response (see [Request::is_streaming]) reply with frames which are written to the peer as they're produced, followed by [Response::EndOfStream].
+pub enum Reply<'a> {
+	Single(Response),
+	Stream(BoxStream<'a, Response>),
+}
+
+/// A library which a peer has marked as shareable. Returned by [Request::SharedLibraries].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SharedLibrary {
+	pub id: Uuid,
+	pub name: String,
+	/// the timestamp of the library's latest sync operation, or 0 if it has none.
+	pub version: u64,
+}
+
+impl Request {
+	/// the [PROTO_VERSION] this request was added in. It won't be sent to peers running an older version.
+	pub fn min_proto_version(&self) -> u16 {
+		match self {
+			Self::Hello { .. }
+			| Self::Ping
+			| Self::FileChunk { .. }
+			| Self::PairingConfirm { .. }
+			| Self::Metadata => 1,
+			Self::SharedLibraries => SHARED_LIBRARIES_PROTO_VERSION,
+			Self::SyncBatch { .. } => RELIABLE_SYNC_PROTO_VERSION,
+			Self::TimedPing { .. } => TIMED_PING_PROTO_VERSION,
+			Self::SyncOperations { .. } => SYNC_OPERATIONS_PROTO_VERSION,
+			Self::Subscribe(_) | Self::Unsubscribe(_) => SUBSCRIPTION_PROTO_VERSION,
+			Self::FileChunkWithChecksum { .. } => FILE_CHECKSUM_PROTO_VERSION,
+			Self::StreamSharedLibraries => STREAMING_RESPONSE_PROTO_VERSION,
+			Self::SyncOperationsWithTotal { .. } => SYNC_PROGRESS_PROTO_VERSION,
+			Self::MetadataChanged(_) => METADATA_CHANGED_PROTO_VERSION,
+			Self::ResumeTransfer { .. } => RESUME_TRANSFER_PROTO_VERSION,
+			Self::PairingComplete { .. } => PAIRING_ACCEPT_PROTO_VERSION,
+			Self::PairingStart { .. } | Self::PairingReveal { .. } => PAIRING_REVEAL_PROTO_VERSION,
+		}
+	}
+
+	/// is_streaming returns if the response to this request is streamed with [Request::handle_stream] instead of sent as a single [Response].
+	/// Streaming is opt-in for each request as it changes what is written to the stream, so it can only be used by requests added in [STREAMING_RESPONSE_PROTO_VERSION] or later.
+	pub fn is_streaming(&self) -> bool {
+		matches!(self, Self::StreamSharedLibraries)
+	}
+
+	/// the [Lane] this request is scheduled on. File chunks are bulk so they can't delay sync.
+	pub fn lane(&self) -> Lane {
+		match self {
+			Self::FileChunk { .. }
+			| Self::FileChunkWithChecksum { .. }
+			| Self::ResumeTransfer { .. } => Lane::Bulk,
+			_ => Lane::Control,
+		}
+	}
+
+	/// handle will respond to a request received from `peer_id`. Requests for the data of a library are rejected unless the peer is paired with it, see [P2PManager::authorize].
+	pub async fn handle(self, p2p: &P2PManager, peer_id: PeerId) -> Response {
+		match self {
+			Self::Hello { proto_version } => p2p.handle_hello(peer_id, proto_version).await,
+			Self::Ping => Response::Pong,
+			Self::TimedPing { sent_at } => Response::TimedPong { sent_at },
+			Self::FileChunk {
+				library_id,
+				location_id,
+				file_path_id,
+				offset,
+				len,
+				expected_size,
+			}
+			| Self::FileChunkWithChecksum {
+				library_id,
+				location_id,
+				file_path_id,
+				offset,
+				len,
+				expected_size,
+			} => {
+				if let Err(response) = p2p.authorize(peer_id, library_id).await {
+					return response;
+				}
+
+				let Some(library_manager) = p2p.library_manager() else {
+					return Response::Error("node is not ready".into());
+				};
+
+				read_file_chunk(
+					library_manager,
+					library_id,
+					location_id,
+					file_path_id,
+					offset,
+					len,
+					expected_size,
+					matches!(self, Self::FileChunkWithChecksum { .. }),
+				)
+				.await
+			}
+			Self::PairingStart { commitment } => {
+				p2p.handle_pairing_start(peer_id, commitment).await
+			}
+			Self::PairingReveal { secret } => p2p.handle_pairing_reveal(peer_id, secret).await,
+			Self::PairingConfirm { code } => p2p.handle_pairing_confirm(peer_id, code).await,
+			Self::Metadata => Response::Metadata(p2p.metadata().await),
+			// This is answered for unpaired peers as it's used to pick which libraries to pair with. It only includes libraries marked as shareable.
+			Self::SharedLibraries | Self::StreamSharedLibraries => {
+				let Some(library_manager) = p2p.library_manager() else {
+					return Response::Error("node is not ready".into());
+				};
+
+				Response::SharedLibraries(library_manager.shared_libraries().await)
+			}
+			Self::SyncBatch {
+				library_id,
+				epoch,
+				sequence,
+				operations,
+			} => {
+				if let Err(err) = p2p.authorize_sync(peer_id, library_id).await {
+					return Response::ProtocolError(err.to_string());
+				}
+
+				p2p.handle_sync_batch(peer_id, library_id, epoch, sequence, operations)
+					.await
+			}
+			Self::SyncOperations { library_id, since } => {
+				if let Err(response) = p2p.authorize(peer_id, library_id).await {
+					return response;
+				}
+
+				// Sync operations aren't sent for libraries with sync disabled, even if they're requested
+				if let Err(err) = p2p.authorize_sync(peer_id, library_id).await {
+					return Response::ProtocolError(err.to_string());
+				}
+
+				p2p.handle_sync_operations(library_id, since, false).await
+			}
+			Self::SyncOperationsWithTotal { library_id, since } => {
+				if let Err(response) = p2p.authorize(peer_id, library_id).await {
+					return response;
+				}
+
+				if let Err(err) = p2p.authorize_sync(peer_id, library_id).await {
+					return Response::ProtocolError(err.to_string());
+				}
+
+				p2p.handle_sync_operations(library_id, since, true).await
+			}
+			Self::Subscribe(library_id) => {
+				if let Err(response) = p2p.authorize(peer_id, library_id).await {
+					return response;
+				}
+
+				p2p.handle_subscribe(peer_id, library_id).await
+			}
+			// Unsubscribing isn't authorized as it's sent after the library has been unloaded by the peer
+			Self::Unsubscribe(library_id) => p2p.handle_unsubscribe(peer_id, library_id).await,
+			Self::MetadataChanged(metadata) => p2p.handle_metadata_changed(peer_id, metadata).await,
+			Self::ResumeTransfer {
+				transfer_id,
+				offset,
+				checksum,
+			} => {
+				if let Err(response) = p2p.authorize(peer_id, transfer_id.library_id).await {
+					return response;
+				}
+
+				let Some(library_manager) = p2p.library_manager() else {
+					return Response::Error("node is not ready".into());
+				};
+
+				resume_file_transfer(library_manager, transfer_id, offset, checksum).await
+			}
+			Self::PairingComplete { library_keys } => {
+				p2p.handle_pairing_complete(peer_id, library_keys).await
+			}
+		}
+	}
+
+	/// handle_stream is the same as [Request::handle] for requests which stream their response. Any other request is answered with a single frame.
+	pub fn handle_stream(self, p2p: &P2PManager, peer_id: PeerId) -> BoxStream<'_, Response> {
+		match self {
+			Self::StreamSharedLibraries => {
+				let Some(library_manager) = p2p.library_manager() else {
+					return stream::once(async { Response::Error("node is not ready".into()) })
+						.boxed();
+				};
+
+				library_manager
+					.stream_shared_libraries()
+					.map(Response::SharedLibrary)
+					.boxed()
+			}
+			request => stream::once(request.handle(p2p, peer_id)).boxed(),
+		}
+	}
+}
+
+#[derive(Debug, Error)]
+pub enum HeaderError {
+	#[error("io error reading header: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("invalid header discriminator '{0}'")]
+	InvalidDiscriminator(u8),
+	#[error("invalid spacedrop transfer request")]
+	InvalidTransferRequest,
+	#[error("spacedrop and request headers must be sent over a unicast stream")]
+	NotUnicast,
+}
+
+#[derive(Debug, Error)]
+pub enum MessageError {
+	#[error("io error sending or receiving message: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("error encoding message: {0}")]
+	Encode(#[from] rmp_serde::encode::Error),
+	#[error("error decoding message: {0}")]
+	Decode(#[from] rmp_serde::decode::Error),
+	#[error("unsupported message protocol version '{0}'")]
+	UnsupportedVersion(u8),
+	#[error("message of {len} bytes is larger than the maximum of {max} bytes")]
+	TooLarge { len: usize, max: usize },
+	#[error("invalid payload compression flag")]
+	InvalidPayload,
+	#[error("received a compressed payload but the 'compress' feature is not enabled")]
+	CompressionUnsupported,
+}
+
+/// the version of the message framing. Changes to [Request] or [Response] should bump [PROTO_VERSION] instead so they can be negotiated with older peers.
+pub const MESSAGE_PROTOCOL_VERSION: u8 = 2;
+
+/// the version of the [Request] and [Response] messages understood by this node. This is exchanged with [Request::Hello] when connecting to a peer.
+/// When a variant is added this must be bumped and the new variant must return the new version from [Request::min_proto_version].
+/// Changing an existing variant is a breaking change so it should be added as a new variant instead.
+///
+/// Version history:
+///  - 1: initial version
+///  - 2: requests to paired peers are sent with [Header::EncryptedRequest]
+///  - 3: added [Request::SharedLibraries]
+///  - 4: added [Request::SyncBatch] for reliable sync
+///  - 5: added [Request::TimedPing]
+///  - 6: added [Request::SyncOperations] to resume sync from a checkpoint
+///  - 7: added [Request::Subscribe] and [Request::Unsubscribe]
+///  - 8: added [Request::FileChunkWithChecksum] to verify file transfers
+///  - 9: added streamed responses and [Request::StreamSharedLibraries]
+///  - 10: added [Response::ProtocolError]
+///  - 11: added [Request::SyncOperationsWithTotal] to show the progress of the initial sync
+///  - 12: added [Request::MetadataChanged]
+///  - 13: added [Request::ResumeTransfer] to check the start of a file before resuming its transfer
+///  - 14: added [Request::PairingComplete] so pairings are accepted by the user on the initiator
+///  - 15: [Request::PairingStart] only carries a commitment to the initiator's secret, which is sent with [Request::PairingReveal]
+pub const PROTO_VERSION: u16 = 15;
+
+/// the first [PROTO_VERSION] which understands [Header::EncryptedRequest].
+pub const ENCRYPTED_REQUEST_PROTO_VERSION: u16 = 2;
+
+/// the first [PROTO_VERSION] which understands [Request::SharedLibraries].
+pub const SHARED_LIBRARIES_PROTO_VERSION: u16 = 3;
+
+/// the first [PROTO_VERSION] which understands [Request::SyncBatch]. Older peers are sent sync operations with [Header::Sync] instead.
+pub const RELIABLE_SYNC_PROTO_VERSION: u16 = 4;
+
+/// the first [PROTO_VERSION] which understands [Request::TimedPing]. Older peers are sent [Request::Ping] instead.
+pub const TIMED_PING_PROTO_VERSION: u16 = 5;
+
+/// the first [PROTO_VERSION] which understands [Request::SyncOperations]. Older peers aren't resumed and only receive new sync batches.
+pub const SYNC_OPERATIONS_PROTO_VERSION: u16 = 6;
+
+/// the first [PROTO_VERSION] which understands [Request::Subscribe]. Older peers are sent the sync events of every library they advertise membership of.
+pub const SUBSCRIPTION_PROTO_VERSION: u16 = 7;
+
+/// the first [PROTO_VERSION] which understands [Request::FileChunkWithChecksum]. Files received from older peers can't be verified.
+pub const FILE_CHECKSUM_PROTO_VERSION: u16 = 8;
+
+/// the first [PROTO_VERSION] which understands streamed responses, see [Request::is_streaming]. Older peers are sent [Request::SharedLibraries] instead.
+pub const STREAMING_RESPONSE_PROTO_VERSION: u16 = 9;
+
+/// the first [PROTO_VERSION] which understands [Response::ProtocolError].
+pub const PROTOCOL_ERROR_PROTO_VERSION: u16 = 10;
+
+/// the first [PROTO_VERSION] which understands [Request::SyncOperationsWithTotal]. The progress of the initial sync with older peers isn't shown.
+pub const SYNC_PROGRESS_PROTO_VERSION: u16 = 11;
+
+/// the first [PROTO_VERSION] which understands [Request::MetadataChanged]. Older peers only see our new metadata once we're readvertised.
+pub const METADATA_CHANGED_PROTO_VERSION: u16 = 12;
+
+/// the first [PROTO_VERSION] which understands [Request::ResumeTransfer]. Transfers resumed from older peers are only verified once they're complete.
+pub const RESUME_TRANSFER_PROTO_VERSION: u16 = 13;
+
+/// the first [PROTO_VERSION] which understands [Request::PairingComplete]. Older peers can't be paired with as they expect the library keys as soon as the code is confirmed.
+pub const PAIRING_ACCEPT_PROTO_VERSION: u16 = 14;
+
+/// the first [PROTO_VERSION] which understands [Request::PairingReveal]. Older peers expect the initiator's secret in [Request::PairingStart] so they can't be paired with.
+pub const PAIRING_REVEAL_PROTO_VERSION: u16 = 15;
+
+/// the maximum number of operations returned in a single [Response::SyncOperations] so the response stays under [DEFAULT_MAX_MESSAGE_SIZE].
+pub const MAX_SYNC_OPERATIONS_PER_RESPONSE: usize = 500;
+
+/// the oldest [PROTO_VERSION] this node can communicate with. Raise this when support for older peers is dropped.
+pub const MIN_PROTO_VERSION: u16 = 1;
+
+/// the default maximum size of a single message body. Anything larger will be rejected without being read.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 10 * 1024 * 1024; // 10 MiB
+
+/// write_message will write a msgpack message to the stream.
+/// A message is framed as a 1 byte protocol version, a u32 little endian length and then the msgpack body encoded by [Compression::encode_payload].
+/// The body isn't compressed as the peer might not be able to decode it, use `write_message_with_compression` for peers which can.
+pub async fn write_message<T: Serialize>(
+	stream: &mut (impl AsyncWrite + Unpin),
+	message: &T,
+) -> Result<(), MessageError> {
+	write_message_with_compression(stream, message, &Compression::uncompressed()).await
+}
+
+/// write_message_with_compression is the same as `write_message` but with custom compression settings.
+pub async fn write_message_with_compression<T: Serialize>(
+	stream: &mut (impl AsyncWrite + Unpin),
+	message: &T,
+	compression: &Compression,
+) -> Result<(), MessageError> {
+	let buf = encode_message_with_compression(message, compression)?;
+
+	stream.write_all(&buf).await?;
+	stream.flush().await?;
+	Ok(())
+}
+
+/// encode_message_with_compression will frame a message the same as `write_message_with_compression` without writing it.
+/// This allows a message which fails to encode to be replaced before anything is written to the stream.
+pub fn encode_message_with_compression<T: Serialize>(
+	message: &T,
+	compression: &Compression,
+) -> Result<Vec<u8>, MessageError> {
+	let payload = compression.encode_payload(rmp_serde::to_vec_named(message)?)?;
+	let len = u32::try_from(payload.len()).map_err(|_| MessageError::TooLarge {
+		len: payload.len(),
+		max: u32::MAX as usize,
+	})?;
+
+	let mut buf = Vec::with_capacity(5 + payload.len());
+	buf.push(MESSAGE_PROTOCOL_VERSION);
+	buf.extend_from_slice(&len.to_le_bytes());
+	buf.extend_from_slice(&payload);
+	Ok(buf)
+}
+
+/// read_message will read a message written by `write_message` from the stream.
+pub async fn read_message<T: DeserializeOwned>(
+	stream: &mut (impl AsyncRead + Unpin),
+) -> Result<T, MessageError> {
+	read_message_with_limit(stream, DEFAULT_MAX_MESSAGE_SIZE).await
+}
+
+/// read_message_with_limit is the same as `read_message` but will reject messages larger than `max_size` bytes.
+pub async fn read_message_with_limit<T: DeserializeOwned>(
+	stream: &mut (impl AsyncRead + Unpin),
+	max_size: usize,
+) -> Result<T, MessageError> {
+	let version = stream.read_u8().await?;
+	if version != MESSAGE_PROTOCOL_VERSION {
+		return Err(MessageError::UnsupportedVersion(version));
+	}
+
+	let len = stream.read_u32_le().await? as usize;
+	if len > max_size {
+		return Err(MessageError::TooLarge { len, max: max_size });
+	}
+
+	let mut buf = vec![0; len];
+	stream.read_exact(&mut buf).await?;
+	Ok(rmp_serde::from_slice(&decode_payload(buf, max_size)?)?)
 }
 
 impl Header {
-	pub async fn from_stream(stream: &mut SpaceTimeStream) -> Result<Self, ()> {
-		let discriminator = stream.read_u8().await.map_err(|e| {
-			dbg!(e);
-			()
-		})?; // TODO: Error handling
+	pub async fn from_stream(stream: &mut SpaceTimeStream) -> Result<Self, HeaderError> {
+		let header = Self::from_reader(stream).await?;
+
+		if matches!(
+			header,
+			Self::Spacedrop(_) | Self::Request | Self::EncryptedRequest
+		) && !matches!(stream, SpaceTimeStream::Unicast(_))
+		{
+			return Err(HeaderError::NotUnicast);
+		}
+
+		Ok(header)
+	}
+
+	/// the [Lane] the stream is scheduled on. `None` for requests as it depends on the [Request] which follows the header.
+	pub fn lane(&self) -> Option<Lane> {
+		match self {
+			Self::Spacedrop(_) => Some(Lane::Bulk),
+			Self::Ping | Self::Sync(..) => Some(Lane::Control),
+			Self::Request | Self::EncryptedRequest => None,
+		}
+	}
+
+	/// from_reader will decode a header from any reader. The data is untrusted so this must never panic.
+	pub async fn from_reader(reader: &mut (impl AsyncRead + Unpin)) -> Result<Self, HeaderError> {
+		let discriminator = reader.read_u8().await?;
 
 		match discriminator {
-			0 => match stream {
-				SpaceTimeStream::Unicast(stream) => {
-					Ok(Self::Spacedrop(TransferRequest::from_stream(stream).await?))
-				}
-				_ => todo!(),
-			},
+			0 => Ok(Self::Spacedrop(
+				TransferRequest::from_stream(reader)
+					.await
+					.map_err(|_| HeaderError::InvalidTransferRequest)?,
+			)),
 			1 => Ok(Self::Ping),
 			2 => {
 				let mut uuid = [0u8; 16];
-				stream.read_exact(&mut uuid).await.map_err(|_| ())?; // TODO: Error handling
+				reader.read_exact(&mut uuid).await?;
 
 				let mut len = [0; 4];
-				stream.read_exact(&mut len).await.map_err(|_| ())?; // TODO: Error handling
+				reader.read_exact(&mut len).await?;
 				let len = u32::from_le_bytes(len);
 
-				Ok(Self::Sync(Uuid::from_slice(&uuid).unwrap(), len)) // TODO: Error handling
+				Ok(Self::Sync(Uuid::from_bytes(uuid), len))
 			}
-			_ => Err(()),
+			3 => Ok(Self::Request),
+			4 => Ok(Self::EncryptedRequest),
+			d => Err(HeaderError::InvalidDiscriminator(d)),
 		}
 	}
 
@@ -58,31 +636,457 @@ impl Header {
 
 				bytes
 			}
+			Self::Request => vec![3],
+			Self::EncryptedRequest => vec![4],
 		}
 	}
 }
 
-// TODO: Unit test it because binary protocols are error prone
-// #[cfg(test)]
-// mod tests {
-// 	use super::*;
-
-// 	#[test]
-// 	fn test_proto() {
-// 		assert_eq!(
-// 			Header::from_bytes(&Header::Ping.to_bytes()),
-// 			Ok(Header::Ping)
-// 		);
-
-// 		assert_eq!(
-// 			Header::from_bytes(&Header::Spacedrop.to_bytes()),
-// 			Ok(Header::Spacedrop)
-// 		);
-
-// 		let uuid = Uuid::new_v4();
-// 		assert_eq!(
-// 			Header::from_bytes(&Header::Sync(uuid).to_bytes()),
-// 			Ok(Header::Sync(uuid))
-// 		);
-// 	}
-// }
+#[cfg(test)]
+mod tests {
+	use sd_p2p::spaceblock::BlockSize;
+
+	use crate::p2p::ChecksumAlgorithm;
+
+	use super::*;
+
+	fn spacedrop_header() -> Header {
+		Header::Spacedrop(TransferRequest {
+			name: "demo.txt".into(),
+			size: 42,
+			block_size: BlockSize::from_size(42),
+		})
+	}
+
+	#[tokio::test]
+	async fn test_proto() {
+		for header in [
+			Header::Ping,
+			spacedrop_header(),
+			Header::Sync(Uuid::new_v4(), 1337),
+			Header::Request,
+			Header::EncryptedRequest,
+		] {
+			let bytes = header.to_bytes();
+			assert_eq!(Header::from_reader(&mut &bytes[..]).await.unwrap(), header);
+		}
+	}
+
+	#[tokio::test]
+	async fn test_message() {
+		let mut buf = Vec::new();
+		write_message(&mut buf, &Request::Ping).await.unwrap();
+		assert_eq!(
+			read_message::<Request>(&mut &buf[..]).await.unwrap(),
+			Request::Ping
+		);
+
+		for len in 0..buf.len() {
+			assert!(read_message::<Request>(&mut &buf[..len]).await.is_err());
+		}
+	}
+
+	#[tokio::test]
+	async fn test_hello() {
+		let request = Request::Hello {
+			proto_version: PROTO_VERSION,
+		};
+
+		let mut buf = Vec::new();
+		write_message(&mut buf, &request).await.unwrap();
+		assert_eq!(
+			read_message::<Request>(&mut &buf[..]).await.unwrap(),
+			request
+		);
+
+		assert!(MIN_PROTO_VERSION <= PROTO_VERSION);
+		assert!(request.min_proto_version() <= PROTO_VERSION);
+	}
+
+	#[tokio::test]
+	async fn test_shared_libraries() {
+		let response = Response::SharedLibraries(vec![SharedLibrary {
+			id: Uuid::new_v4(),
+			name: "My Library".into(),
+			version: 42,
+		}]);
+
+		let mut buf = Vec::new();
+		write_message(&mut buf, &response).await.unwrap();
+		assert_eq!(
+			read_message::<Response>(&mut &buf[..]).await.unwrap(),
+			response
+		);
+
+		assert!(Request::SharedLibraries.min_proto_version() <= PROTO_VERSION);
+	}
+
+	#[tokio::test]
+	async fn test_stream_shared_libraries() {
+		let frames = [
+			Response::SharedLibrary(SharedLibrary {
+				id: Uuid::new_v4(),
+				name: "My Library".into(),
+				version: 42,
+			}),
+			Response::EndOfStream,
+		];
+
+		let mut buf = Vec::new();
+		for frame in &frames {
+			write_message(&mut buf, frame).await.unwrap();
+		}
+
+		let mut reader = &buf[..];
+		for frame in frames {
+			assert_eq!(read_message::<Response>(&mut reader).await.unwrap(), frame);
+		}
+
+		let request = Request::StreamSharedLibraries;
+		assert!(request.is_streaming());
+		assert!(!Request::SharedLibraries.is_streaming());
+		assert_eq!(
+			request.min_proto_version(),
+			STREAMING_RESPONSE_PROTO_VERSION
+		);
+		assert!(STREAMING_RESPONSE_PROTO_VERSION <= PROTO_VERSION);
+	}
+
+	#[tokio::test]
+	async fn test_sync_batch() {
+		let request = Request::SyncBatch {
+			library_id: Uuid::new_v4(),
+			epoch: Uuid::new_v4(),
+			sequence: 7,
+			operations: Vec::new(),
+		};
+
+		let mut buf = Vec::new();
+		write_message(&mut buf, &request).await.unwrap();
+		assert_eq!(
+			read_message::<Request>(&mut &buf[..]).await.unwrap(),
+			request
+		);
+
+		let response = Response::SyncAck { applied: 7 };
+		let mut buf = Vec::new();
+		write_message(&mut buf, &response).await.unwrap();
+		assert_eq!(
+			read_message::<Response>(&mut &buf[..]).await.unwrap(),
+			response
+		);
+
+		assert_eq!(request.min_proto_version(), RELIABLE_SYNC_PROTO_VERSION);
+		assert!(RELIABLE_SYNC_PROTO_VERSION <= PROTO_VERSION);
+	}
+
+	#[tokio::test]
+	async fn test_timed_ping() {
+		let request = Request::TimedPing { sent_at: 1337 };
+
+		let mut buf = Vec::new();
+		write_message(&mut buf, &request).await.unwrap();
+		assert_eq!(
+			read_message::<Request>(&mut &buf[..]).await.unwrap(),
+			request
+		);
+
+		let response = Response::TimedPong { sent_at: 1337 };
+		let mut buf = Vec::new();
+		write_message(&mut buf, &response).await.unwrap();
+		assert_eq!(
+			read_message::<Response>(&mut &buf[..]).await.unwrap(),
+			response
+		);
+
+		// Older peers must still be pinged with the unit variant
+		assert_eq!(Request::Ping.min_proto_version(), MIN_PROTO_VERSION);
+		assert_eq!(request.min_proto_version(), TIMED_PING_PROTO_VERSION);
+		assert!(TIMED_PING_PROTO_VERSION <= PROTO_VERSION);
+	}
+
+	#[tokio::test]
+	async fn test_sync_operations() {
+		let mut since = SyncCheckpoint::default();
+		since.0.insert(Uuid::new_v4(), 42);
+		let request = Request::SyncOperations {
+			library_id: Uuid::new_v4(),
+			since,
+		};
+
+		let mut buf = Vec::new();
+		write_message(&mut buf, &request).await.unwrap();
+		assert_eq!(
+			read_message::<Request>(&mut &buf[..]).await.unwrap(),
+			request
+		);
+
+		let response = Response::SyncOperations {
+			operations: Vec::new(),
+			more: true,
+		};
+		let mut buf = Vec::new();
+		write_message(&mut buf, &response).await.unwrap();
+		assert_eq!(
+			read_message::<Response>(&mut &buf[..]).await.unwrap(),
+			response
+		);
+
+		assert_eq!(request.min_proto_version(), SYNC_OPERATIONS_PROTO_VERSION);
+		assert!(SYNC_OPERATIONS_PROTO_VERSION <= PROTO_VERSION);
+	}
+
+	#[tokio::test]
+	async fn test_sync_operations_with_total() {
+		let request = Request::SyncOperationsWithTotal {
+			library_id: Uuid::new_v4(),
+			since: SyncCheckpoint::default(),
+		};
+		let response = Response::SyncOperationsWithTotal {
+			operations: Vec::new(),
+			more: true,
+			total: 1234,
+		};
+
+		let mut buf = Vec::new();
+		write_message(&mut buf, &request).await.unwrap();
+		write_message(&mut buf, &response).await.unwrap();
+
+		let mut reader = &buf[..];
+		assert_eq!(read_message::<Request>(&mut reader).await.unwrap(), request);
+		assert_eq!(
+			read_message::<Response>(&mut reader).await.unwrap(),
+			response
+		);
+
+		assert_eq!(request.min_proto_version(), SYNC_PROGRESS_PROTO_VERSION);
+		assert!(SYNC_PROGRESS_PROTO_VERSION <= PROTO_VERSION);
+	}
+
+	#[tokio::test]
+	async fn test_metadata_changed() {
+		let request = Request::MetadataChanged(PeerMetadata {
+			name: "Renamed".into(),
+			device_id: Some(Uuid::new_v4()),
+			operating_system: None,
+			architecture: None,
+			version: None,
+			protocol_version: Some(PROTO_VERSION),
+			email: None,
+			img_url: None,
+			compression: true,
+			libraries: vec![Uuid::new_v4()],
+		});
+
+		let mut buf = Vec::new();
+		write_message(&mut buf, &request).await.unwrap();
+		assert_eq!(
+			read_message::<Request>(&mut &buf[..]).await.unwrap(),
+			request
+		);
+
+		assert_eq!(request.min_proto_version(), METADATA_CHANGED_PROTO_VERSION);
+		assert!(METADATA_CHANGED_PROTO_VERSION <= PROTO_VERSION);
+	}
+
+	#[tokio::test]
+	async fn test_resume_transfer() {
+		let checksum = FileChecksum {
+			algorithm: ChecksumAlgorithm::Blake3,
+			hash: vec![0xAB; 32],
+		};
+		let request = Request::ResumeTransfer {
+			transfer_id: TransferId {
+				library_id: Uuid::new_v4(),
+				location_id: 1,
+				file_path_id: 42,
+			},
+			offset: 4096,
+			checksum: checksum.clone(),
+		};
+		let response = Response::TransferResumed {
+			offset: 4096,
+			size: 10_000,
+			checksum,
+		};
+
+		let mut buf = Vec::new();
+		write_message(&mut buf, &request).await.unwrap();
+		write_message(&mut buf, &response).await.unwrap();
+
+		let mut reader = &buf[..];
+		assert_eq!(read_message::<Request>(&mut reader).await.unwrap(), request);
+		assert_eq!(
+			read_message::<Response>(&mut reader).await.unwrap(),
+			response
+		);
+
+		assert_eq!(request.min_proto_version(), RESUME_TRANSFER_PROTO_VERSION);
+		assert_eq!(request.lane(), Lane::Bulk);
+		assert!(RESUME_TRANSFER_PROTO_VERSION <= PROTO_VERSION);
+	}
+
+	#[tokio::test]
+	async fn test_pairing_complete() {
+		let request = Request::PairingComplete {
+			library_keys: vec![(Uuid::new_v4(), SyncKey::default())],
+		};
+
+		let mut buf = Vec::new();
+		write_message(&mut buf, &request).await.unwrap();
+		write_message(&mut buf, &Response::PairingPending)
+			.await
+			.unwrap();
+
+		let mut reader = &buf[..];
+		assert_eq!(read_message::<Request>(&mut reader).await.unwrap(), request);
+		assert_eq!(
+			read_message::<Response>(&mut reader).await.unwrap(),
+			Response::PairingPending
+		);
+
+		assert_eq!(request.min_proto_version(), PAIRING_ACCEPT_PROTO_VERSION);
+		assert!(PAIRING_ACCEPT_PROTO_VERSION <= PROTO_VERSION);
+	}
+
+	#[tokio::test]
+	async fn test_pairing_reveal() {
+		let requests = [
+			Request::PairingStart {
+				commitment: vec![1; 32],
+			},
+			Request::PairingReveal {
+				secret: vec![2; 32],
+			},
+		];
+
+		let mut buf = Vec::new();
+		for request in &requests {
+			write_message(&mut buf, request).await.unwrap();
+		}
+
+		let mut reader = &buf[..];
+		for request in requests {
+			assert_eq!(read_message::<Request>(&mut reader).await.unwrap(), request);
+			assert_eq!(request.min_proto_version(), PAIRING_REVEAL_PROTO_VERSION);
+		}
+		assert!(PAIRING_REVEAL_PROTO_VERSION <= PROTO_VERSION);
+	}
+
+	#[tokio::test]
+	async fn test_subscribe() {
+		let library_id = Uuid::new_v4();
+		for (request, response) in [
+			(Request::Subscribe(library_id), Response::Subscribed),
+			(Request::Unsubscribe(library_id), Response::Unsubscribed),
+		] {
+			let mut buf = Vec::new();
+			write_message(&mut buf, &request).await.unwrap();
+			assert_eq!(
+				read_message::<Request>(&mut &buf[..]).await.unwrap(),
+				request
+			);
+
+			let mut buf = Vec::new();
+			write_message(&mut buf, &response).await.unwrap();
+			assert_eq!(
+				read_message::<Response>(&mut &buf[..]).await.unwrap(),
+				response
+			);
+
+			assert_eq!(request.min_proto_version(), SUBSCRIPTION_PROTO_VERSION);
+		}
+
+		assert!(SUBSCRIPTION_PROTO_VERSION <= PROTO_VERSION);
+	}
+
+	#[tokio::test]
+	async fn test_file_chunk_with_checksum() {
+		let request = Request::FileChunkWithChecksum {
+			library_id: Uuid::new_v4(),
+			location_id: 1,
+			file_path_id: 2,
+			offset: 0,
+			len: 1024,
+			expected_size: None,
+		};
+
+		let mut buf = Vec::new();
+		write_message(&mut buf, &request).await.unwrap();
+		assert_eq!(
+			read_message::<Request>(&mut &buf[..]).await.unwrap(),
+			request
+		);
+
+		let response = Response::FileChunkWithChecksum {
+			bytes: vec![1, 2, 3],
+			size: 3,
+			eof: true,
+			checksum: Some(FileChecksum {
+				algorithm: ChecksumAlgorithm::Blake3,
+				hash: blake3::hash(&[1, 2, 3]).as_bytes().to_vec(),
+			}),
+		};
+		let mut buf = Vec::new();
+		write_message(&mut buf, &response).await.unwrap();
+		assert_eq!(
+			read_message::<Response>(&mut &buf[..]).await.unwrap(),
+			response
+		);
+
+		assert_eq!(request.min_proto_version(), FILE_CHECKSUM_PROTO_VERSION);
+		assert!(FILE_CHECKSUM_PROTO_VERSION <= PROTO_VERSION);
+		assert_eq!(request.lane(), Lane::Bulk);
+		assert_eq!(Request::Ping.lane(), Lane::Control);
+	}
+
+	#[tokio::test]
+	async fn test_message_version_mismatch() {
+		let mut buf = Vec::new();
+		write_message(&mut buf, &Request::Ping).await.unwrap();
+		buf[0] = MESSAGE_PROTOCOL_VERSION + 1;
+
+		assert!(matches!(
+			read_message::<Request>(&mut &buf[..]).await,
+			Err(MessageError::UnsupportedVersion(_))
+		));
+	}
+
+	#[tokio::test]
+	async fn test_message_too_large() {
+		let mut buf = Vec::new();
+		write_message(&mut buf, &Response::Error("a".repeat(100)))
+			.await
+			.unwrap();
+
+		assert!(matches!(
+			read_message_with_limit::<Response>(&mut &buf[..], 10).await,
+			Err(MessageError::TooLarge { max: 10, .. })
+		));
+	}
+
+	#[tokio::test]
+	async fn test_truncated_headers_error() {
+		for header in [spacedrop_header(), Header::Sync(Uuid::new_v4(), 1337)] {
+			let bytes = header.to_bytes();
+			for len in 0..bytes.len() {
+				assert!(
+					Header::from_reader(&mut &bytes[..len]).await.is_err(),
+					"decoding '{header:?}' truncated to {len} bytes should fail"
+				);
+			}
+		}
+	}
+
+	#[tokio::test]
+	async fn test_malformed_headers_error() {
+		for bytes in [
+			&[255u8][..],
+			&[255, 1, 2, 3],
+			// Spacedrop with a name which is not valid UTF-8
+			&[0, 2, 0xC3, 0x28, 42],
+		] {
+			assert!(Header::from_reader(&mut &bytes[..]).await.is_err());
+		}
+	}
+}
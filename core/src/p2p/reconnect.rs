@@ -0,0 +1,170 @@
+use std::{net::SocketAddr, time::Duration};
+
+use rand::Rng;
+use rspc::Type;
+use sd_p2p::PeerId;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// the number of last-known addresses which are remembered for each [KnownPeer].
+const MAX_KNOWN_ADDRESSES: usize = 4;
+
+/// Controls how often a dropped connection to a paired or manually added peer is retried.
+/// The delay doubles after every failed attempt from `base_delay` up to `max_delay`.
+#[derive(Debug, Clone)]
+pub struct ReconnectConfig {
+	pub base_delay: Duration,
+	pub max_delay: Duration,
+	/// the maximum number of known peers which are dialed at once when the node starts. The rest wait for a free slot so a large list can't cause a connection storm.
+	pub max_startup_dials: usize,
+	/// the number of failed attempts after which a reconnect gives up. `0` retries until the peer is connected, unpaired or blocked.
+	pub max_attempts: u32,
+}
+
+impl Default for ReconnectConfig {
+	fn default() -> Self {
+		Self {
+			base_delay: Duration::from_secs(1),
+			max_delay: Duration::from_secs(5 * 60),
+			max_startup_dials: 8,
+			max_attempts: 0,
+		}
+	}
+}
+
+impl ReconnectConfig {
+	/// returns how long to wait after the given attempt (starting at `0`) before trying again.
+	/// Up to half the delay is random so peers which dropped at the same time don't all retry in lockstep.
+	pub fn delay(&self, attempt: u32) -> Duration {
+		self.delay_with_jitter(attempt, rand::thread_rng().gen_range(0.0..=1.0))
+	}
+
+	/// should_retry returns if another attempt should be made after the given number of failed attempts.
+	pub fn should_retry(&self, failed_attempts: u32) -> bool {
+		self.max_attempts == 0 || failed_attempts < self.max_attempts
+	}
+
+	fn delay_with_jitter(&self, attempt: u32, jitter: f64) -> Duration {
+		let delay = self
+			.base_delay
+			.saturating_mul(2u32.saturating_pow(attempt))
+			.min(self.max_delay);
+
+		delay / 2 + (delay / 2).mul_f64(jitter)
+	}
+}
+
+/// What a reconnect is trying to connect to. Only one reconnect runs for each target at a time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReconnectTarget {
+	/// a peer which has been connected to before
+	Peer(PeerId),
+	/// a manually added address where the peer is not yet known
+	Address(SocketAddr),
+}
+
+/// A peer which this node reconnects to on startup. These are persisted to the node config so they are dialed straight away instead of waiting for them to be discovered.
+/// A peer is only forgotten once the user unpairs it (and it has no nickname), never because it couldn't be reached.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, Type)]
+pub struct KnownPeer {
+	/// the addresses the peer was last connected through, most recent first.
+	#[serde(default)]
+	pub addresses: Vec<SocketAddr>,
+	/// has the peer been paired with this node.
+	#[serde(default)]
+	pub paired: bool,
+	/// the nickname the user has given the peer. This is only ever shown locally and is never sent to the peer.
+	#[serde(default)]
+	pub nickname: Option<String>,
+	/// the device the peer advertised when it was paired, see [super::PeerMetadata]. A device which changes its keypair is paired again under a new peer id and this is how its old pairing is found.
+	#[serde(default)]
+	pub device_id: Option<Uuid>,
+}
+
+impl KnownPeer {
+	/// record_address moves the address to the front of the last-known addresses, forgetting the oldest once there are more than [MAX_KNOWN_ADDRESSES].
+	pub fn record_address(&mut self, addr: SocketAddr) {
+		self.addresses.retain(|a| *a != addr);
+		self.addresses.insert(0, addr);
+		self.addresses.truncate(MAX_KNOWN_ADDRESSES);
+	}
+
+	/// is_unused returns if there is no longer any reason to remember the peer. Eg. it has been unpaired and it isn't reachable at one of the `manual_peers`.
+	pub fn is_unused(&self, manual_peers: &[SocketAddr]) -> bool {
+		!self.paired
+			&& self.nickname.is_none()
+			&& !self
+				.addresses
+				.iter()
+				.any(|addr| manual_peers.contains(addr))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_reconnect_delay() {
+		let config = ReconnectConfig {
+			base_delay: Duration::from_secs(1),
+			max_delay: Duration::from_secs(10),
+			..Default::default()
+		};
+
+		assert_eq!(config.delay_with_jitter(0, 1.0), Duration::from_secs(1));
+		assert_eq!(config.delay_with_jitter(1, 1.0), Duration::from_secs(2));
+		assert_eq!(config.delay_with_jitter(2, 1.0), Duration::from_secs(4));
+		assert_eq!(config.delay_with_jitter(3, 0.0), Duration::from_secs(4));
+		assert_eq!(config.delay_with_jitter(4, 1.0), Duration::from_secs(10));
+		assert_eq!(
+			config.delay_with_jitter(u32::MAX, 1.0),
+			Duration::from_secs(10)
+		);
+	}
+
+	#[test]
+	fn test_reconnect_max_attempts() {
+		let config = ReconnectConfig {
+			max_attempts: 3,
+			..Default::default()
+		};
+		assert!(config.should_retry(0));
+		assert!(config.should_retry(2));
+		assert!(!config.should_retry(3));
+
+		assert!(ReconnectConfig::default().should_retry(u32::MAX));
+	}
+
+	#[test]
+	fn test_reconnect_delay_jitter() {
+		let config = ReconnectConfig::default();
+		for attempt in 0..20 {
+			let delay = config.delay(attempt);
+			let max = config.delay_with_jitter(attempt, 1.0);
+			assert!(delay >= max / 2 && delay <= max);
+		}
+	}
+
+	#[test]
+	fn test_known_peer_addresses() {
+		let addrs = (1..=5)
+			.map(|port| SocketAddr::from(([192, 168, 1, 2], port)))
+			.collect::<Vec<_>>();
+
+		let mut peer = KnownPeer::default();
+		for addr in &addrs {
+			peer.record_address(*addr);
+		}
+		assert_eq!(peer.addresses, vec![addrs[4], addrs[3], addrs[2], addrs[1]]);
+
+		// Reconnecting through a remembered address moves it to the front without duplicating it
+		peer.record_address(addrs[2]);
+		assert_eq!(peer.addresses, vec![addrs[2], addrs[4], addrs[3], addrs[1]]);
+
+		assert!(peer.is_unused(&[]));
+		assert!(!peer.is_unused(&[addrs[3]]));
+		peer.paired = true;
+		assert!(!peer.is_unused(&[]));
+	}
+}
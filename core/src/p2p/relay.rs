@@ -0,0 +1,225 @@
+//! Relaying forwards the sync operations received from one peer to the other peers in the library, so peers which can't connect to each other directly still sync through a peer they can both reach.
+//!
+//! A relayed operation is never sent back to the peer it was received from, and every node remembers the operations it has recently seen so one which comes back around a loop is dropped.
+//! Each relay increments the operation's hop count, and it's no longer relayed once [MAX_RELAY_HOPS] is reached.
+
+use std::collections::{HashSet, VecDeque};
+
+use sd_p2p::PeerId;
+use uuid::Uuid;
+
+/// the maximum number of times a sync operation is relayed before it's only applied.
+pub const MAX_RELAY_HOPS: u8 = 4;
+
+/// the number of operation ids remembered by [SeenOperations]. Operations which come back after this many newer ones have been seen are applied again, which is harmless as applying an operation is idempotent.
+pub const SEEN_OPERATIONS_CAPACITY: usize = 10_000;
+
+/// The ids of the sync operations this node has recently created or received, so duplicates arriving from another peer can be dropped.
+#[derive(Debug)]
+pub struct SeenOperations {
+	capacity: usize,
+	ids: HashSet<Uuid>,
+	order: VecDeque<Uuid>,
+}
+
+impl Default for SeenOperations {
+	fn default() -> Self {
+		Self::new(SEEN_OPERATIONS_CAPACITY)
+	}
+}
+
+impl SeenOperations {
+	pub fn new(capacity: usize) -> Self {
+		Self {
+			capacity: capacity.max(1),
+			ids: HashSet::new(),
+			order: VecDeque::new(),
+		}
+	}
+
+	pub fn contains(&self, id: &Uuid) -> bool {
+		self.ids.contains(id)
+	}
+
+	/// insert records that an operation has been seen, forgetting the oldest once there are more than `capacity`.
+	/// This returns `false` if the operation has already been seen.
+	pub fn insert(&mut self, id: Uuid) -> bool {
+		if !self.ids.insert(id) {
+			return false;
+		}
+
+		self.order.push_back(id);
+		if self.order.len() > self.capacity {
+			if let Some(oldest) = self.order.pop_front() {
+				self.ids.remove(&oldest);
+			}
+		}
+
+		true
+	}
+}
+
+/// relay_targets returns the peers which operations should be sent to, leaving out `exclude` so an operation is never sent back to the peer it was received from.
+pub fn relay_targets(
+	peers: impl IntoIterator<Item = PeerId>,
+	exclude: Option<PeerId>,
+) -> Vec<PeerId> {
+	peers
+		.into_iter()
+		.filter(|peer_id| Some(*peer_id) != exclude)
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{collections::HashMap, str::FromStr};
+
+	use sd_p2p::Keypair;
+	use sd_sync::{CRDTOperation, CRDTOperationType, OwnedOperation};
+	use uhlc::NTP64;
+
+	use crate::{
+		library::SyncKey,
+		p2p::{LibrarySigners, SignedOperation, SignedOperationError},
+	};
+
+	use super::*;
+
+	fn peer_id(i: usize) -> PeerId {
+		const PEERS: [&str; 3] = [
+			"12D3KooW9tHTtS3inCZiYykw4u5G4frbjVFqhkmJX12gSNCVeH3e",
+			"12D3KooW9xCm2jWjNVrwh51SWCQBMYdMyeU3NpT85QhLVkF6PcNM",
+			"12D3KooWA284B2yjxoAAqAFwwVj6eRQ8DogF3t8wdpMzZ8Hh8wh4",
+		];
+		PeerId::from_str(PEERS[i]).unwrap()
+	}
+
+	fn operation() -> CRDTOperation {
+		CRDTOperation {
+			node: Uuid::new_v4(),
+			timestamp: NTP64(1),
+			id: Uuid::new_v4(),
+			typ: CRDTOperationType::Owned(OwnedOperation {
+				model: "location".to_owned(),
+				items: Vec::new(),
+			}),
+		}
+	}
+
+	#[test]
+	fn test_seen_operations() {
+		let mut seen = SeenOperations::new(2);
+		let ids = [Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+
+		assert!(seen.insert(ids[0]));
+		assert!(!seen.insert(ids[0]));
+		assert!(seen.insert(ids[1]));
+
+		// The oldest id is forgotten once the capacity is exceeded
+		assert!(seen.insert(ids[2]));
+		assert!(!seen.contains(&ids[0]));
+		assert!(seen.contains(&ids[1]) && seen.contains(&ids[2]));
+	}
+
+	#[derive(Default)]
+	struct Node {
+		peers: Vec<PeerId>,
+		seen: SeenOperations,
+		signers: LibrarySigners,
+		received: Vec<(PeerId, u8)>,
+	}
+
+	#[test]
+	fn test_relay_is_not_echoed() {
+		let key = SyncKey::default();
+		let library_id = Uuid::new_v4();
+		let [a, b, c] = [peer_id(0), peer_id(1), peer_id(2)];
+
+		// `a` and `c` can only reach each other through `b`
+		let mut nodes = HashMap::from([
+			(
+				a,
+				Node {
+					peers: vec![b],
+					..Default::default()
+				},
+			),
+			(
+				b,
+				Node {
+					peers: vec![a, c],
+					..Default::default()
+				},
+			),
+			(
+				c,
+				Node {
+					peers: vec![b],
+					..Default::default()
+				},
+			),
+		]);
+
+		let operation = operation();
+		let signed =
+			SignedOperation::sign(&key, &Keypair::generate(), library_id, &operation).unwrap();
+
+		// The origin remembers its own operations so they're dropped if they come back around a loop
+		let origin = nodes.get_mut(&a).unwrap();
+		assert!(origin.seen.insert(operation.id));
+		let mut in_flight = relay_targets(origin.peers.clone(), None)
+			.into_iter()
+			.map(|to| (a, to, signed.clone()))
+			.collect::<VecDeque<_>>();
+
+		while let Some((from, to, signed)) = in_flight.pop_front() {
+			let node = nodes.get_mut(&to).unwrap();
+			node.received.push((from, signed.hops()));
+
+			// The operation is still signed by its origin, so it's accepted even though it was relayed by another peer
+			let id = node.signers.verify(&key, library_id, &signed).unwrap().id;
+			if !node.seen.insert(id) {
+				continue;
+			}
+
+			if let Some(relayed) = signed.relayed() {
+				for peer_id in relay_targets(node.peers.clone(), Some(from)) {
+					in_flight.push_back((to, peer_id, relayed.clone()));
+				}
+			}
+		}
+
+		assert_eq!(nodes[&a].received, vec![]);
+		assert_eq!(nodes[&b].received, vec![(a, 0)]);
+		assert_eq!(nodes[&c].received, vec![(b, 1)]);
+
+		// The relay can't sign the operation as if it had created it
+		let resigned =
+			SignedOperation::sign(&key, &Keypair::generate(), library_id, &operation).unwrap();
+		assert!(matches!(
+			nodes
+				.get_mut(&c)
+				.unwrap()
+				.signers
+				.verify(&key, library_id, &resigned),
+			Err(SignedOperationError::UnexpectedSigner)
+		));
+	}
+
+	#[test]
+	fn test_relay_hop_limit() {
+		let mut signed = SignedOperation::sign(
+			&SyncKey::default(),
+			&Keypair::generate(),
+			Uuid::new_v4(),
+			&operation(),
+		)
+		.unwrap();
+
+		for hops in 1..=MAX_RELAY_HOPS {
+			signed = signed.relayed().unwrap();
+			assert_eq!(signed.hops(), hops);
+		}
+		assert!(signed.relayed().is_none());
+	}
+}
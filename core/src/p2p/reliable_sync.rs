@@ -0,0 +1,410 @@
+//! Reliable sync sends the sync operations created in a library to each peer with [super::Request::SyncBatch] instead of a fire-and-forget broadcast.
+//!
+//! Sequence numbers are scoped per library and per sender, and each receiving peer has its own [SyncOutbox] on the sender.
+//! Every outbox has a random epoch which is replaced whenever the sender can no longer retransmit some of the batches (Eg. it was restarted or the outbox overflowed).
+//! The receiver replies with the highest contiguous sequence it has applied and the sender retransmits every batch after it, so delivery is at-least-once.
+//! A batch which has already been applied is acknowledged without being applied again.
+//! A batch which arrives before an earlier one is held by the receiver and applied once the gap is filled, so the operations from each sender are always applied in the order they were created.
+
+use std::collections::{BTreeMap, HashMap};
+
+use sd_p2p::PeerId;
+use uuid::Uuid;
+
+use super::SignedOperation;
+
+/// the maximum number of unacknowledged batches kept for a peer. Once this is exceeded the oldest are dropped and the peer has to bootstrap to get them.
+pub const MAX_PENDING_SYNC_BATCHES: usize = 256;
+
+/// the maximum number of batches a receiver holds for each sender while it waits for an earlier one. Batches after this are dropped and retransmitted by the sender instead.
+pub const MAX_HELD_SYNC_BATCHES: usize = MAX_PENDING_SYNC_BATCHES;
+
+/// The batches sent to a single peer for a single library which it hasn't acknowledged yet.
+pub struct SyncOutbox {
+	epoch: Uuid,
+	next_sequence: u64,
+	pending: BTreeMap<u64, Vec<SignedOperation>>,
+}
+
+impl Default for SyncOutbox {
+	fn default() -> Self {
+		Self {
+			epoch: Uuid::new_v4(),
+			next_sequence: 1,
+			pending: BTreeMap::new(),
+		}
+	}
+}
+
+impl SyncOutbox {
+	pub fn epoch(&self) -> Uuid {
+		self.epoch
+	}
+
+	/// push queues a batch to be sent returning its sequence number.
+	/// If the outbox is full the oldest batch is dropped and a new epoch is started so the receiver doesn't wait for it to be retransmitted. This returns the number of dropped operations.
+	pub fn push(&mut self, operations: Vec<SignedOperation>) -> (u64, usize) {
+		let sequence = self.next_sequence;
+		self.next_sequence += 1;
+		self.pending.insert(sequence, operations);
+
+		let mut dropped = 0;
+		while self.pending.len() > MAX_PENDING_SYNC_BATCHES {
+			if let Some(sequence) = self.pending.keys().next().copied() {
+				dropped += self.pending.remove(&sequence).map_or(0, |ops| ops.len());
+			}
+		}
+		if dropped > 0 {
+			self.epoch = Uuid::new_v4();
+		}
+
+		(sequence, dropped)
+	}
+
+	/// ack removes every batch up to and including `applied` as the receiver has applied them.
+	pub fn ack(&mut self, applied: u64) {
+		self.pending = self.pending.split_off(&(applied + 1));
+	}
+
+	/// pending returns the batches which haven't been acknowledged in the order they must be sent.
+	pub fn pending(&self) -> Vec<(u64, Vec<SignedOperation>)> {
+		self.pending
+			.iter()
+			.map(|(sequence, operations)| (*sequence, operations.clone()))
+			.collect()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.pending.is_empty()
+	}
+}
+
+/// What a receiver should do with a [super::Request::SyncBatch].
+#[derive(Debug, PartialEq, Eq)]
+pub enum SyncBatchAction {
+	/// the batch is the next one expected from the sender so it should be applied and then passed to `SyncInbox::applied`.
+	Apply,
+	/// the batch was already applied so it should only be acknowledged.
+	Duplicate,
+	/// an earlier batch is missing so this one should be passed to `SyncInbox::hold` until the earlier batch is applied.
+	Gap,
+}
+
+struct SenderState {
+	epoch: Uuid,
+	applied: u64,
+	held: BTreeMap<u64, Vec<SignedOperation>>,
+}
+
+/// Keeps track of the highest contiguous sequence applied from each sender in each library, and the batches received out of order.
+/// This is only kept in memory as the operations missed while the node wasn't running are fetched when it next bootstraps.
+#[derive(Default)]
+pub struct SyncInbox(HashMap<(Uuid, PeerId), SenderState>);
+
+impl SyncInbox {
+	/// receive returns what should be done with the batch. A batch from a new epoch starts the sequence from itself as the sender can't retransmit anything before it.
+	pub fn receive(
+		&mut self,
+		library_id: Uuid,
+		peer_id: PeerId,
+		epoch: Uuid,
+		sequence: u64,
+	) -> SyncBatchAction {
+		let state = self
+			.0
+			.entry((library_id, peer_id))
+			.or_insert_with(|| SenderState {
+				epoch,
+				applied: sequence.saturating_sub(1),
+				held: BTreeMap::new(),
+			});
+
+		// The held batches can't be applied after a batch from a new epoch as the sequences are restarted
+		if state.epoch != epoch {
+			state.epoch = epoch;
+			state.applied = sequence.saturating_sub(1);
+			state.held.clear();
+		}
+
+		match sequence {
+			sequence if sequence <= state.applied => SyncBatchAction::Duplicate,
+			sequence if sequence == state.applied + 1 => SyncBatchAction::Apply,
+			_ => SyncBatchAction::Gap,
+		}
+	}
+
+	/// applied records that the batch was applied so it won't be applied again.
+	pub fn applied(&mut self, library_id: Uuid, peer_id: PeerId, epoch: Uuid, sequence: u64) {
+		if let Some(state) = self.0.get_mut(&(library_id, peer_id)) {
+			if state.epoch == epoch && sequence == state.applied + 1 {
+				state.applied = sequence;
+			}
+		}
+	}
+
+	/// hold keeps a batch which was received before an earlier one so it can be applied once the gap is filled, see [Self::next_held].
+	/// This returns `false` if the batch wasn't held because too many are already held for the sender, in which case the sender will retransmit it.
+	pub fn hold(
+		&mut self,
+		library_id: Uuid,
+		peer_id: PeerId,
+		epoch: Uuid,
+		sequence: u64,
+		operations: Vec<SignedOperation>,
+	) -> bool {
+		let Some(state) = self.0.get_mut(&(library_id, peer_id)) else {
+			return false;
+		};
+		if state.epoch != epoch || sequence <= state.applied + 1 {
+			return false;
+		}
+		if state.held.len() >= MAX_HELD_SYNC_BATCHES && !state.held.contains_key(&sequence) {
+			return false;
+		}
+
+		state.held.insert(sequence, operations);
+		true
+	}
+
+	/// next_held removes the held batch which follows the highest one applied from the sender, so it can be applied and then passed to [Self::applied].
+	pub fn next_held(
+		&mut self,
+		library_id: Uuid,
+		peer_id: PeerId,
+	) -> Option<(Uuid, u64, Vec<SignedOperation>)> {
+		let state = self.0.get_mut(&(library_id, peer_id))?;
+		let sequence = state.applied + 1;
+		state
+			.held
+			.remove(&sequence)
+			.map(|operations| (state.epoch, sequence, operations))
+	}
+
+	/// highest_applied returns the sequence which should be acknowledged to the sender.
+	pub fn highest_applied(&self, library_id: Uuid, peer_id: PeerId) -> u64 {
+		self.0
+			.get(&(library_id, peer_id))
+			.map(|state| state.applied)
+			.unwrap_or_default()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{str::FromStr, sync::Arc};
+
+	use tokio::sync::Mutex;
+
+	use super::*;
+
+	fn peer_id() -> PeerId {
+		PeerId::from_str("12D3KooW9tHTtS3inCZiYykw4u5G4frbjVFqhkmJX12gSNCVeH3e").unwrap()
+	}
+
+	#[test]
+	fn test_outbox_retransmits_unacknowledged() {
+		let mut outbox = SyncOutbox::default();
+		assert_eq!(outbox.push(Vec::new()), (1, 0));
+		assert_eq!(outbox.push(Vec::new()), (2, 0));
+		assert_eq!(outbox.push(Vec::new()), (3, 0));
+
+		// The receiver only applied the first batch so the rest must be retransmitted
+		outbox.ack(1);
+		assert_eq!(
+			outbox
+				.pending()
+				.into_iter()
+				.map(|(sequence, _)| sequence)
+				.collect::<Vec<_>>(),
+			[2, 3]
+		);
+
+		outbox.ack(3);
+		assert!(outbox.is_empty());
+	}
+
+	#[test]
+	fn test_outbox_overflow_starts_new_epoch() {
+		let mut outbox = SyncOutbox::default();
+		let epoch = outbox.epoch();
+
+		for _ in 0..MAX_PENDING_SYNC_BATCHES {
+			assert_eq!(outbox.push(Vec::new()).1, 0);
+		}
+		assert_eq!(outbox.epoch(), epoch);
+
+		outbox.push(Vec::new());
+		assert_ne!(outbox.epoch(), epoch);
+		assert_eq!(outbox.pending().len(), MAX_PENDING_SYNC_BATCHES);
+		assert_eq!(outbox.pending()[0].0, 2);
+	}
+
+	#[test]
+	fn test_inbox_duplicates_and_gaps() {
+		let (library_id, peer_id, epoch) = (Uuid::new_v4(), peer_id(), Uuid::new_v4());
+		let mut inbox = SyncInbox::default();
+
+		assert_eq!(
+			inbox.receive(library_id, peer_id, epoch, 1),
+			SyncBatchAction::Apply
+		);
+		inbox.applied(library_id, peer_id, epoch, 1);
+
+		// A retransmit of a batch which was already applied
+		assert_eq!(
+			inbox.receive(library_id, peer_id, epoch, 1),
+			SyncBatchAction::Duplicate
+		);
+
+		// Batch 2 was lost so batch 3 can't be applied yet
+		assert_eq!(
+			inbox.receive(library_id, peer_id, epoch, 3),
+			SyncBatchAction::Gap
+		);
+		assert_eq!(inbox.highest_applied(library_id, peer_id), 1);
+
+		assert_eq!(
+			inbox.receive(library_id, peer_id, epoch, 2),
+			SyncBatchAction::Apply
+		);
+		// A batch which failed to apply isn't recorded so it will be applied when retransmitted
+		assert_eq!(
+			inbox.receive(library_id, peer_id, epoch, 2),
+			SyncBatchAction::Apply
+		);
+		inbox.applied(library_id, peer_id, epoch, 2);
+		assert_eq!(inbox.highest_applied(library_id, peer_id), 2);
+
+		// Sequences are scoped per library
+		assert_eq!(inbox.highest_applied(Uuid::new_v4(), peer_id), 0);
+	}
+
+	#[test]
+	fn test_inbox_holds_out_of_order_batches() {
+		let (library_id, peer_id, epoch) = (Uuid::new_v4(), peer_id(), Uuid::new_v4());
+		let mut inbox = SyncInbox::default();
+
+		assert_eq!(
+			inbox.receive(library_id, peer_id, epoch, 1),
+			SyncBatchAction::Apply
+		);
+		inbox.applied(library_id, peer_id, epoch, 1);
+
+		// Batches 3 and 4 arrive before batch 2 so they're held
+		for sequence in [4, 3] {
+			assert_eq!(
+				inbox.receive(library_id, peer_id, epoch, sequence),
+				SyncBatchAction::Gap
+			);
+			assert!(inbox.hold(library_id, peer_id, epoch, sequence, Vec::new()));
+		}
+		assert!(inbox.next_held(library_id, peer_id).is_none());
+
+		assert_eq!(
+			inbox.receive(library_id, peer_id, epoch, 2),
+			SyncBatchAction::Apply
+		);
+		inbox.applied(library_id, peer_id, epoch, 2);
+
+		// The held batches are applied in order once the gap is filled
+		for sequence in [3, 4] {
+			let (held_epoch, held_sequence, _) = inbox.next_held(library_id, peer_id).unwrap();
+			assert_eq!((held_epoch, held_sequence), (epoch, sequence));
+			inbox.applied(library_id, peer_id, epoch, sequence);
+		}
+		assert!(inbox.next_held(library_id, peer_id).is_none());
+		assert_eq!(inbox.highest_applied(library_id, peer_id), 4);
+
+		// A new epoch restarts the sequences so the held batches are dropped
+		assert!(inbox.hold(library_id, peer_id, epoch, 6, Vec::new()));
+		let epoch = Uuid::new_v4();
+		assert_eq!(
+			inbox.receive(library_id, peer_id, epoch, 1),
+			SyncBatchAction::Apply
+		);
+		inbox.applied(library_id, peer_id, epoch, 1);
+		assert!(inbox.next_held(library_id, peer_id).is_none());
+	}
+
+	#[tokio::test]
+	async fn test_inbox_applies_concurrent_batches_in_order() {
+		const BATCHES: u64 = 200;
+
+		let (library_id, peer_id, epoch) = (Uuid::new_v4(), peer_id(), Uuid::new_v4());
+		let inbox = Arc::new(Mutex::new(SyncInbox::default()));
+		let applied = Arc::new(Mutex::new(Vec::new()));
+
+		// The first batch establishes the sequence, the rest are delivered concurrently in any order like the streams from a peer
+		let mut tasks = Vec::new();
+		for sequence in std::iter::once(1).chain((2..=BATCHES).rev()) {
+			let (inbox, applied) = (inbox.clone(), applied.clone());
+			tasks.push(tokio::spawn(async move {
+				tokio::task::yield_now().await;
+
+				let mut inbox = inbox.lock().await;
+				match inbox.receive(library_id, peer_id, epoch, sequence) {
+					SyncBatchAction::Apply => {
+						let mut next = Some((epoch, sequence, Vec::new()));
+						while let Some((epoch, sequence, _)) = next {
+							applied.lock().await.push(sequence);
+							inbox.applied(library_id, peer_id, epoch, sequence);
+							next = inbox.next_held(library_id, peer_id);
+						}
+					}
+					SyncBatchAction::Duplicate => {}
+					SyncBatchAction::Gap => {
+						assert!(inbox.hold(library_id, peer_id, epoch, sequence, Vec::new()));
+					}
+				}
+			}));
+
+			if sequence == 1 {
+				tasks.pop().unwrap().await.unwrap();
+			}
+		}
+
+		// Pings are handled alongside the batches and only see the acknowledged sequence move forward
+		let pings = tokio::spawn({
+			let inbox = inbox.clone();
+			async move {
+				let mut last = 0;
+				for _ in 0..BATCHES {
+					let acked = inbox.lock().await.highest_applied(library_id, peer_id);
+					assert!(acked >= last);
+					last = acked;
+					tokio::task::yield_now().await;
+				}
+			}
+		});
+
+		for task in tasks {
+			task.await.unwrap();
+		}
+		pings.await.unwrap();
+
+		assert_eq!(*applied.lock().await, (1..=BATCHES).collect::<Vec<_>>());
+	}
+
+	#[test]
+	fn test_inbox_new_epoch() {
+		let (library_id, peer_id) = (Uuid::new_v4(), peer_id());
+		let mut inbox = SyncInbox::default();
+
+		// The receiver restarted so it picks up from the first batch it sees
+		let epoch = Uuid::new_v4();
+		assert_eq!(
+			inbox.receive(library_id, peer_id, epoch, 42),
+			SyncBatchAction::Apply
+		);
+		inbox.applied(library_id, peer_id, epoch, 42);
+
+		// The sender dropped batches and started a new epoch
+		let epoch = Uuid::new_v4();
+		assert_eq!(
+			inbox.receive(library_id, peer_id, epoch, 50),
+			SyncBatchAction::Apply
+		);
+		inbox.applied(library_id, peer_id, epoch, 50);
+		assert_eq!(inbox.highest_applied(library_id, peer_id), 50);
+	}
+}
@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+
+use sd_crypto::keys::mac;
+use sd_p2p::Keypair;
+use sd_sync::CRDTOperation;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::library::SyncKey;
+
+use super::MAX_RELAY_HOPS;
+
+/// the BLAKE3 context used to derive the key which sync operations are signed with.
+const SYNC_OPERATION_CONTEXT: &str = "spacedrive 2023-03-20 16:42:11 sync operation signing";
+
+/// A [CRDTOperation] along with a MAC from the sync key of the library it belongs to.
+/// The operation is kept in its encoded form so the exact bytes which were signed can be verified.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedOperation {
+	operation: Vec<u8>,
+	mac: Vec<u8>,
+	/// the signature from the sender's keypair for the library. This is missing from operations sent by older nodes.
+	#[serde(default)]
+	signature: Option<OperationSignature>,
+	/// the number of times the operation has been relayed by another peer, see [MAX_RELAY_HOPS].
+	/// This isn't covered by the MAC or signature so a relay can increment it, which means it must only be used to limit relaying.
+	#[serde(default)]
+	hops: u8,
+}
+
+/// OperationSignature is an Ed25519 signature of an operation from the library keypair of the node which sent it.
+/// Unlike the MAC it can only be created by that node, so the other nodes in the library can't forge its operations.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OperationSignature {
+	public_key: Vec<u8>,
+	signature: Vec<u8>,
+}
+
+#[derive(Debug, Error)]
+pub enum SignedOperationError {
+	#[error("error encoding operation: {0}")]
+	Encode(#[from] rmp_serde::encode::Error),
+	#[error("error decoding operation: {0}")]
+	Decode(#[from] rmp_serde::decode::Error),
+	#[error("operation has an invalid signature")]
+	InvalidSignature,
+	#[error("operation wasn't signed by the keypair its node previously signed with")]
+	UnexpectedSigner,
+}
+
+impl SignedOperation {
+	/// sign authenticates the operation with the library's sync key and signs it with this node's keypair for the library.
+	pub fn sign(
+		key: &SyncKey,
+		keypair: &Keypair,
+		library_id: Uuid,
+		operation: &CRDTOperation,
+	) -> Result<Self, SignedOperationError> {
+		let operation = rmp_serde::to_vec_named(operation)?;
+		let data = signed_data(library_id, &operation);
+
+		Ok(Self {
+			mac: mac::authenticate(key.key(), SYNC_OPERATION_CONTEXT, &data).to_vec(),
+			signature: Some(OperationSignature {
+				public_key: keypair.public_key_bytes().to_vec(),
+				signature: keypair.sign(&data),
+			}),
+			operation,
+			hops: 0,
+		})
+	}
+
+	pub fn hops(&self) -> u8 {
+		self.hops
+	}
+
+	/// relayed returns the operation to forward to other peers with its hop count incremented, or `None` if it has already been relayed [MAX_RELAY_HOPS] times.
+	pub fn relayed(&self) -> Option<Self> {
+		(self.hops < MAX_RELAY_HOPS).then(|| Self {
+			hops: self.hops + 1,
+			..self.clone()
+		})
+	}
+
+	/// signer returns the public key of the library keypair the operation was signed with, if it was signed.
+	/// This is only meaningful once the operation has been verified.
+	pub fn signer(&self) -> Option<&[u8]> {
+		self.signature.as_ref().map(|s| s.public_key.as_slice())
+	}
+
+	/// verify checks the operation was signed with the sync key of the given library, and by its [Self::signer] if it has one, and then decodes it.
+	/// The library id is included in the signature so an operation can't be replayed into another library.
+	pub fn verify(
+		&self,
+		key: &SyncKey,
+		library_id: Uuid,
+	) -> Result<CRDTOperation, SignedOperationError> {
+		let data = signed_data(library_id, &self.operation);
+
+		mac::verify(key.key(), SYNC_OPERATION_CONTEXT, &data, &self.mac)
+			.map_err(|_| SignedOperationError::InvalidSignature)?;
+
+		if let Some(signature) = &self.signature {
+			if !Keypair::verify(&signature.public_key, &data, &signature.signature) {
+				return Err(SignedOperationError::InvalidSignature);
+			}
+		}
+
+		Ok(rmp_serde::from_slice(&self.operation)?)
+	}
+}
+
+/// LibrarySigners is the public key of the library keypair each node has signed its sync operations with, for each library.
+/// The first keypair a node signs with is pinned, so its operations which are signed by a different keypair, or aren't signed after it started signing, are rejected.
+///
+/// Keypairs are pinned to the node which created the operation ([CRDTOperation::node]) rather than the peer it was received from, so operations relayed by another peer are still accepted from their origin.
+#[derive(Debug, Default)]
+pub struct LibrarySigners(HashMap<(Uuid, Uuid), Vec<u8>>);
+
+impl LibrarySigners {
+	/// verify checks the operation with [SignedOperation::verify] and that it was signed by the keypair pinned for the node which created it.
+	/// The operation's keypair is pinned if it's the first signed operation from its node.
+	pub fn verify(
+		&mut self,
+		key: &SyncKey,
+		library_id: Uuid,
+		op: &SignedOperation,
+	) -> Result<CRDTOperation, SignedOperationError> {
+		let verified = op.verify(key, library_id)?;
+		let node = (library_id, verified.node);
+
+		match (self.0.get(&node), op.signer()) {
+			(Some(pinned), Some(signer)) if pinned.as_slice() != signer => {
+				Err(SignedOperationError::UnexpectedSigner)
+			}
+			(Some(_), None) => Err(SignedOperationError::UnexpectedSigner),
+			(None, Some(signer)) => {
+				self.0.insert(node, signer.to_vec());
+				Ok(verified)
+			}
+			_ => Ok(verified),
+		}
+	}
+
+	/// remove_library forgets the keypairs which were pinned for the library.
+	pub fn remove_library(&mut self, library_id: Uuid) {
+		self.0.retain(|(id, _), _| *id != library_id);
+	}
+}
+
+fn signed_data(library_id: Uuid, operation: &[u8]) -> Vec<u8> {
+	let mut buf = Vec::with_capacity(16 + operation.len());
+	buf.extend_from_slice(library_id.as_bytes());
+	buf.extend_from_slice(operation);
+	buf
+}
+
+#[cfg(test)]
+mod tests {
+	use sd_sync::{CRDTOperationType, OwnedOperation};
+	use uhlc::NTP64;
+
+	use super::*;
+
+	fn operation() -> CRDTOperation {
+		CRDTOperation {
+			node: Uuid::new_v4(),
+			timestamp: NTP64(1),
+			id: Uuid::new_v4(),
+			typ: CRDTOperationType::Owned(OwnedOperation {
+				model: "location".to_owned(),
+				items: Vec::new(),
+			}),
+		}
+	}
+
+	#[test]
+	fn test_signed_operation() {
+		let key = SyncKey::default();
+		let keypair = Keypair::generate();
+		let library_id = Uuid::new_v4();
+		let operation = operation();
+
+		let signed = SignedOperation::sign(&key, &keypair, library_id, &operation).unwrap();
+		assert_eq!(signed.verify(&key, library_id).unwrap().id, operation.id);
+		assert_eq!(signed.signer(), Some(keypair.public_key_bytes().as_slice()));
+	}
+
+	#[test]
+	fn test_tampered_operation() {
+		let key = SyncKey::default();
+		let library_id = Uuid::new_v4();
+
+		let mut signed =
+			SignedOperation::sign(&key, &Keypair::generate(), library_id, &operation()).unwrap();
+		let last = signed.operation.len() - 1;
+		signed.operation[last] ^= 1;
+
+		assert!(matches!(
+			signed.verify(&key, library_id),
+			Err(SignedOperationError::InvalidSignature)
+		));
+	}
+
+	#[test]
+	fn test_operation_wrong_key_or_library() {
+		let key = SyncKey::default();
+		let library_id = Uuid::new_v4();
+		let signed =
+			SignedOperation::sign(&key, &Keypair::generate(), library_id, &operation()).unwrap();
+
+		assert!(signed.verify(&SyncKey::default(), library_id).is_err());
+		assert!(signed.verify(&key, Uuid::new_v4()).is_err());
+
+		let unsigned = SignedOperation {
+			mac: Vec::new(),
+			..signed
+		};
+		assert!(unsigned.verify(&key, library_id).is_err());
+	}
+
+	#[test]
+	fn test_operation_signature() {
+		let key = SyncKey::default();
+		let library_id = Uuid::new_v4();
+		let signed =
+			SignedOperation::sign(&key, &Keypair::generate(), library_id, &operation()).unwrap();
+
+		// Operations from older nodes only have a MAC
+		let legacy = SignedOperation {
+			signature: None,
+			..signed.clone()
+		};
+		assert!(legacy.verify(&key, library_id).is_ok());
+		assert_eq!(legacy.signer(), None);
+
+		// Another member of the library can't claim the operation was signed by a different keypair
+		let mut forged = signed;
+		forged.signature.as_mut().unwrap().public_key =
+			Keypair::generate().public_key_bytes().to_vec();
+		assert!(matches!(
+			forged.verify(&key, library_id),
+			Err(SignedOperationError::InvalidSignature)
+		));
+	}
+
+	#[test]
+	fn test_library_signers() {
+		let key = SyncKey::default();
+		let keypair = Keypair::generate();
+		let library_id = Uuid::new_v4();
+		let mut signers = LibrarySigners::default();
+
+		let operation = operation();
+		let signed = SignedOperation::sign(&key, &keypair, library_id, &operation).unwrap();
+		assert!(signers.verify(&key, library_id, &signed).is_ok());
+
+		// Another member of the library can't sign operations as the node once its keypair is pinned
+		let impersonated =
+			SignedOperation::sign(&key, &Keypair::generate(), library_id, &operation).unwrap();
+		assert!(matches!(
+			signers.verify(&key, library_id, &impersonated),
+			Err(SignedOperationError::UnexpectedSigner)
+		));
+		let unsigned = SignedOperation {
+			signature: None,
+			..signed.clone()
+		};
+		assert!(matches!(
+			signers.verify(&key, library_id, &unsigned),
+			Err(SignedOperationError::UnexpectedSigner)
+		));
+
+		// Other nodes pin their own keypair
+		let other = CRDTOperation {
+			node: Uuid::new_v4(),
+			..operation
+		};
+		let other = SignedOperation::sign(&key, &Keypair::generate(), library_id, &other).unwrap();
+		assert!(signers.verify(&key, library_id, &other).is_ok());
+
+		signers.remove_library(library_id);
+		assert!(signers.verify(&key, library_id, &impersonated).is_ok());
+	}
+}
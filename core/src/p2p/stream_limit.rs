@@ -0,0 +1,142 @@
+use std::{
+	collections::HashMap,
+	sync::{Arc, PoisonError},
+	time::Duration,
+};
+
+use sd_p2p::PeerId;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Controls how many inbound streams from a single peer are handled at once.
+/// Every inbound stream is handled in its own task so without a limit a misbehaving peer could exhaust the node's tasks and file descriptors.
+#[derive(Debug, Clone)]
+pub struct StreamLimitConfig {
+	/// the maximum number of streams from a single peer which are handled concurrently.
+	pub max_in_flight: usize,
+	/// how long a stream over the limit waits for another stream from the same peer to finish before it's rejected. `Duration::ZERO` rejects it straight away.
+	pub queue_timeout: Duration,
+}
+
+impl Default for StreamLimitConfig {
+	fn default() -> Self {
+		Self {
+			// Sync batches, requests and transfers to a single peer on a LAN rarely overlap by more than a handful of streams
+			max_in_flight: 32,
+			queue_timeout: Duration::from_secs(5),
+		}
+	}
+}
+
+/// StreamLimits holds a semaphore for each peer which has opened a stream with this node. A permit must be held while a stream is handled.
+#[derive(Debug, Default)]
+pub struct StreamLimits(std::sync::Mutex<HashMap<PeerId, Arc<Semaphore>>>);
+
+impl StreamLimits {
+	fn semaphore(&self, peer_id: PeerId, config: &StreamLimitConfig) -> Arc<Semaphore> {
+		self.0
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.entry(peer_id)
+			.or_insert_with(|| Arc::new(Semaphore::new(config.max_in_flight)))
+			.clone()
+	}
+
+	/// acquire waits for the peer to have less than `max_in_flight` streams in flight. `None` if it's still over the limit after `queue_timeout`.
+	pub async fn acquire(
+		&self,
+		peer_id: PeerId,
+		config: &StreamLimitConfig,
+	) -> Option<OwnedSemaphorePermit> {
+		let semaphore = self.semaphore(peer_id, config);
+		if let Ok(permit) = semaphore.clone().try_acquire_owned() {
+			return Some(permit);
+		}
+
+		tokio::time::timeout(config.queue_timeout, semaphore.acquire_owned())
+			.await
+			.ok()?
+			.ok()
+	}
+
+	/// in_flight returns the number of streams from the peer which are currently being handled.
+	pub fn in_flight(&self, peer_id: &PeerId, config: &StreamLimitConfig) -> usize {
+		self.0
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.get(peer_id)
+			.map(|semaphore| {
+				config
+					.max_in_flight
+					.saturating_sub(semaphore.available_permits())
+			})
+			.unwrap_or_default()
+	}
+
+	/// remove forgets a peer once it has disconnected. Any streams still in flight keep their permits until they finish.
+	pub fn remove(&self, peer_id: &PeerId) {
+		self.0
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.remove(peer_id);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::str::FromStr;
+
+	use super::*;
+
+	fn peer_id(i: usize) -> PeerId {
+		const PEERS: [&str; 2] = [
+			"12D3KooW9tHTtS3inCZiYykw4u5G4frbjVFqhkmJX12gSNCVeH3e",
+			"12D3KooW9xCm2jWjNVrwh51SWCQBMYdMyeU3NpT85QhLVkF6PcNM",
+		];
+		PeerId::from_str(PEERS[i]).unwrap()
+	}
+
+	#[tokio::test]
+	async fn test_stream_limits() {
+		let config = StreamLimitConfig {
+			max_in_flight: 2,
+			queue_timeout: Duration::from_millis(10),
+		};
+		let limits = StreamLimits::default();
+
+		let a = limits.acquire(peer_id(0), &config).await.unwrap();
+		let _b = limits.acquire(peer_id(0), &config).await.unwrap();
+		assert_eq!(limits.in_flight(&peer_id(0), &config), 2);
+
+		// The limit is per peer so other peers aren't affected
+		assert!(limits.acquire(peer_id(0), &config).await.is_none());
+		let _c = limits.acquire(peer_id(1), &config).await.unwrap();
+		assert_eq!(limits.in_flight(&peer_id(1), &config), 1);
+
+		drop(a);
+		assert_eq!(limits.in_flight(&peer_id(0), &config), 1);
+		assert!(limits.acquire(peer_id(0), &config).await.is_some());
+
+		limits.remove(&peer_id(0));
+		assert_eq!(limits.in_flight(&peer_id(0), &config), 0);
+	}
+
+	#[tokio::test]
+	async fn test_stream_limits_queue() {
+		let config = StreamLimitConfig {
+			max_in_flight: 1,
+			queue_timeout: Duration::from_secs(5),
+		};
+		let limits = Arc::new(StreamLimits::default());
+
+		let permit = limits.acquire(peer_id(0), &config).await.unwrap();
+		let queued = tokio::spawn({
+			let limits = limits.clone();
+			let config = config.clone();
+			async move { limits.acquire(peer_id(0), &config).await.is_some() }
+		});
+
+		tokio::time::sleep(Duration::from_millis(10)).await;
+		drop(permit);
+		assert!(queued.await.unwrap());
+	}
+}
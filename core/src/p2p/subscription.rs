@@ -0,0 +1,93 @@
+use std::collections::{HashMap, HashSet};
+
+use sd_p2p::PeerId;
+use uuid::Uuid;
+
+/// Subscriptions tracks which peers have asked to receive the sync events of each library with [super::Request::Subscribe].
+/// Sync events are only sent to the subscribers of the library they were created in.
+#[derive(Debug, Default)]
+pub struct Subscriptions(HashMap<Uuid, HashSet<PeerId>>);
+
+impl Subscriptions {
+	/// subscribe adds the peer to the library's subscribers. Returns `false` if it was already subscribed.
+	pub fn subscribe(&mut self, library_id: Uuid, peer_id: PeerId) -> bool {
+		self.0.entry(library_id).or_default().insert(peer_id)
+	}
+
+	/// unsubscribe removes the peer from the library's subscribers. Returns `false` if it wasn't subscribed.
+	pub fn unsubscribe(&mut self, library_id: Uuid, peer_id: &PeerId) -> bool {
+		let Some(peers) = self.0.get_mut(&library_id) else {
+			return false;
+		};
+
+		let removed = peers.remove(peer_id);
+		if peers.is_empty() {
+			self.0.remove(&library_id);
+		}
+		removed
+	}
+
+	/// remove_peer unsubscribes the peer from every library, Eg. because it disconnected.
+	pub fn remove_peer(&mut self, peer_id: &PeerId) {
+		self.0.retain(|_, peers| {
+			peers.remove(peer_id);
+			!peers.is_empty()
+		});
+	}
+
+	/// remove_library drops every subscriber of the library, Eg. because it was unloaded from this node.
+	pub fn remove_library(&mut self, library_id: &Uuid) {
+		self.0.remove(library_id);
+	}
+
+	/// subscribers returns the peers which are subscribed to the library.
+	pub fn subscribers(&self, library_id: &Uuid) -> HashSet<PeerId> {
+		self.0.get(library_id).cloned().unwrap_or_default()
+	}
+
+	/// is_subscribed returns if the peer is subscribed to the library.
+	pub fn is_subscribed(&self, library_id: &Uuid, peer_id: &PeerId) -> bool {
+		self.0
+			.get(library_id)
+			.map_or(false, |peers| peers.contains(peer_id))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::str::FromStr;
+
+	use super::*;
+
+	fn peer_id() -> PeerId {
+		PeerId::from_str("12D3KooW9xCm2jWjNVrwh51SWCQBMYdMyeU3NpT85QhLVkF6PcNM").unwrap()
+	}
+
+	#[test]
+	fn test_subscriptions() {
+		let (library_a, library_b) = (Uuid::new_v4(), Uuid::new_v4());
+		let peer_id = peer_id();
+		let mut subscriptions = Subscriptions::default();
+
+		assert!(subscriptions.subscribe(library_a, peer_id));
+		assert!(!subscriptions.subscribe(library_a, peer_id));
+		assert!(subscriptions.subscribe(library_b, peer_id));
+		assert_eq!(
+			subscriptions.subscribers(&library_a),
+			HashSet::from([peer_id])
+		);
+
+		// Unsubscribing only affects the given library
+		assert!(subscriptions.unsubscribe(library_a, &peer_id));
+		assert!(!subscriptions.unsubscribe(library_a, &peer_id));
+		assert!(subscriptions.subscribers(&library_a).is_empty());
+		assert!(subscriptions.is_subscribed(&library_b, &peer_id));
+
+		// Disconnecting removes the peer from every library
+		subscriptions.subscribe(library_a, peer_id);
+		subscriptions.remove_peer(&peer_id);
+		assert!(!subscriptions.is_subscribed(&library_a, &peer_id));
+		assert!(!subscriptions.is_subscribed(&library_b, &peer_id));
+		assert!(subscriptions.0.is_empty());
+	}
+}
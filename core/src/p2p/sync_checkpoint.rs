@@ -0,0 +1,196 @@
+//! Sync checkpoints are the high-water marks of the operations applied from each peer in each library.
+//! When a peer reconnects it's asked for the operations after its checkpoint with [super::Request::SyncOperations] so only the delta is transferred.
+//!
+//! The checkpoints are persisted to the node's data directory so they survive restarts. A missing or unreadable file falls back to a full sync.
+
+use std::{
+	collections::{BTreeMap, HashMap},
+	io,
+	path::{Path, PathBuf},
+};
+
+use sd_p2p::PeerId;
+use sd_sync::CRDTOperation;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use uuid::Uuid;
+
+/// the name of the file in the node's data directory which stores the sync checkpoints.
+pub const SYNC_CHECKPOINTS_FILE_NAME: &str = "sync_checkpoints.json";
+
+/// The timestamp of the newest operation applied from each node which created operations in a library.
+/// This is a vector clock as a peer also relays the operations it received from other nodes. An empty checkpoint requests every operation.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncCheckpoint(pub BTreeMap<Uuid, u64>);
+
+impl SyncCheckpoint {
+	/// contains returns if the operation is at or before the checkpoint so it has already been applied.
+	pub fn contains(&self, op: &CRDTOperation) -> bool {
+		self.0
+			.get(&op.node)
+			.map_or(false, |timestamp| op.timestamp.0 <= *timestamp)
+	}
+
+	/// advance moves the checkpoint forward to include the operation. The operations from a node must be applied in timestamp order.
+	pub fn advance(&mut self, op: &CRDTOperation) {
+		let timestamp = self.0.entry(op.node).or_default();
+		*timestamp = (*timestamp).max(op.timestamp.0);
+	}
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredCheckpoint {
+	library_id: Uuid,
+	peer_id: PeerId,
+	checkpoint: SyncCheckpoint,
+}
+
+/// The [SyncCheckpoint] of every peer in every library. This is saved to disk every time a checkpoint is updated.
+#[derive(Debug)]
+pub struct SyncCheckpoints {
+	path: PathBuf,
+	checkpoints: HashMap<(Uuid, PeerId), SyncCheckpoint>,
+}
+
+impl SyncCheckpoints {
+	/// load reads the checkpoints from the data directory. If the file is unreadable every peer is fully synced again instead of failing to start.
+	pub async fn load(data_directory: &Path) -> Self {
+		let path = data_directory.join(SYNC_CHECKPOINTS_FILE_NAME);
+
+		let checkpoints = match tokio::fs::read(&path).await {
+			Ok(bytes) => match serde_json::from_slice::<Vec<StoredCheckpoint>>(&bytes) {
+				Ok(checkpoints) => checkpoints
+					.into_iter()
+					.map(|c| ((c.library_id, c.peer_id), c.checkpoint))
+					.collect(),
+				Err(err) => {
+					warn!("Sync checkpoints at '{}' are corrupted, falling back to a full sync with every peer: {err}", path.display());
+					HashMap::new()
+				}
+			},
+			Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+			Err(err) => {
+				warn!(
+					"Error reading sync checkpoints at '{}', falling back to a full sync with every peer: {err}",
+					path.display()
+				);
+				HashMap::new()
+			}
+		};
+
+		Self { path, checkpoints }
+	}
+
+	/// get returns the checkpoint of the peer in the library. This is empty if the peer has never been synced with.
+	pub fn get(&self, library_id: Uuid, peer_id: PeerId) -> SyncCheckpoint {
+		self.checkpoints
+			.get(&(library_id, peer_id))
+			.cloned()
+			.unwrap_or_default()
+	}
+
+	/// set replaces the checkpoint of the peer in the library and saves the checkpoints to disk.
+	/// The file is written alongside the original and then renamed over it so an interrupted write can't corrupt it.
+	pub async fn set(
+		&mut self,
+		library_id: Uuid,
+		peer_id: PeerId,
+		checkpoint: SyncCheckpoint,
+	) -> io::Result<()> {
+		self.checkpoints.insert((library_id, peer_id), checkpoint);
+
+		let stored = self
+			.checkpoints
+			.iter()
+			.map(|((library_id, peer_id), checkpoint)| StoredCheckpoint {
+				library_id: *library_id,
+				peer_id: *peer_id,
+				checkpoint: checkpoint.clone(),
+			})
+			.collect::<Vec<_>>();
+		let bytes = serde_json::to_vec(&stored)?;
+
+		let mut temp_path = self.path.as_os_str().to_owned();
+		temp_path.push(".tmp");
+		tokio::fs::write(&temp_path, bytes).await?;
+		tokio::fs::rename(&temp_path, &self.path).await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::str::FromStr;
+
+	use sd_sync::{CRDTOperationType, SharedOperation, SharedOperationData};
+	use serde_json::json;
+	use uhlc::NTP64;
+
+	use super::*;
+
+	fn peer_id() -> PeerId {
+		PeerId::from_str("12D3KooW9tHTtS3inCZiYykw4u5G4frbjVFqhkmJX12gSNCVeH3e").unwrap()
+	}
+
+	fn op(node: Uuid, timestamp: u64) -> CRDTOperation {
+		CRDTOperation {
+			node,
+			timestamp: NTP64(timestamp),
+			id: Uuid::new_v4(),
+			typ: CRDTOperationType::Shared(SharedOperation {
+				record_id: json!(1),
+				model: "tag".into(),
+				data: SharedOperationData::Delete,
+			}),
+		}
+	}
+
+	#[test]
+	fn test_checkpoint_vector_clock() {
+		let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+		let mut checkpoint = SyncCheckpoint::default();
+		assert!(!checkpoint.contains(&op(a, 1)));
+
+		checkpoint.advance(&op(a, 5));
+		checkpoint.advance(&op(a, 3));
+		assert!(checkpoint.contains(&op(a, 5)));
+		assert!(!checkpoint.contains(&op(a, 6)));
+
+		// Each node is tracked separately
+		assert!(!checkpoint.contains(&op(b, 1)));
+	}
+
+	#[tokio::test]
+	async fn test_checkpoints_persisted() {
+		let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+		tokio::fs::create_dir_all(&dir).await.unwrap();
+		let (library_id, node) = (Uuid::new_v4(), Uuid::new_v4());
+
+		let mut checkpoints = SyncCheckpoints::load(&dir).await;
+		assert_eq!(
+			checkpoints.get(library_id, peer_id()),
+			SyncCheckpoint::default()
+		);
+
+		let mut checkpoint = SyncCheckpoint::default();
+		checkpoint.advance(&op(node, 42));
+		checkpoints
+			.set(library_id, peer_id(), checkpoint.clone())
+			.await
+			.unwrap();
+
+		let checkpoints = SyncCheckpoints::load(&dir).await;
+		assert_eq!(checkpoints.get(library_id, peer_id()), checkpoint);
+
+		// A corrupted file falls back to a full sync instead of failing
+		tokio::fs::write(dir.join(SYNC_CHECKPOINTS_FILE_NAME), b"{ not json")
+			.await
+			.unwrap();
+		let checkpoints = SyncCheckpoints::load(&dir).await;
+		assert_eq!(
+			checkpoints.get(library_id, peer_id()),
+			SyncCheckpoint::default()
+		);
+
+		tokio::fs::remove_dir_all(&dir).await.unwrap();
+	}
+}
@@ -0,0 +1,204 @@
+use std::sync::{
+	atomic::{AtomicUsize, Ordering},
+	Arc,
+};
+
+use sd_sync::CRDTOperation;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::warn;
+use uuid::Uuid;
+
+/// What happens to a batch of sync operations when the library's sync queue is full because the network can't keep up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BackpressurePolicy {
+	/// wait for the queue to drain. The operations wait in the sync channel in the meantime, which logs how many were skipped if it also fills up.
+	#[default]
+	Block,
+	/// drop the batch and warn. The dropped operations are only sent to peers when they next bootstrap the library.
+	DropWithWarning,
+}
+
+/// Controls the queue between batching the sync operations created in a library and sending them to other peers.
+/// The queue is bounded so a slow network can't cause memory usage to grow without limit.
+#[derive(Debug, Clone)]
+pub struct SyncQueueConfig {
+	/// the maximum number of batches waiting to be sent
+	pub capacity: usize,
+	pub policy: BackpressurePolicy,
+}
+
+impl Default for SyncQueueConfig {
+	fn default() -> Self {
+		Self {
+			capacity: 16,
+			policy: BackpressurePolicy::default(),
+		}
+	}
+}
+
+/// sync_queue creates the queue for a library. `depth` is updated with the number of batches waiting to be sent so it can be exposed in the stats.
+pub fn sync_queue(
+	library_id: Uuid,
+	config: &SyncQueueConfig,
+	depth: Arc<AtomicUsize>,
+) -> (SyncQueueSender, SyncQueueReceiver) {
+	let (tx, rx) = mpsc::channel(config.capacity.max(1));
+	(
+		SyncQueueSender {
+			library_id,
+			policy: config.policy,
+			tx,
+			depth: depth.clone(),
+		},
+		SyncQueueReceiver { rx, depth },
+	)
+}
+
+pub struct SyncQueueSender {
+	library_id: Uuid,
+	policy: BackpressurePolicy,
+	tx: mpsc::Sender<Vec<CRDTOperation>>,
+	depth: Arc<AtomicUsize>,
+}
+
+impl SyncQueueSender {
+	/// push will queue a batch to be sent, applying the `BackpressurePolicy` if the queue is full.
+	/// This returns `false` once the receiver has been dropped.
+	pub async fn push(&self, batch: Vec<CRDTOperation>) -> bool {
+		let permit = match self.tx.try_reserve() {
+			Ok(permit) => permit,
+			Err(TrySendError::Full(())) => match self.policy {
+				BackpressurePolicy::Block => {
+					warn!(
+						"Sync queue for library '{}' is full as the network is falling behind, waiting for it to drain!",
+						self.library_id
+					);
+					match self.tx.reserve().await {
+						Ok(permit) => permit,
+						Err(_) => return false,
+					}
+				}
+				BackpressurePolicy::DropWithWarning => {
+					warn!(
+						"Sync queue for library '{}' is full as the network is falling behind, dropping '{}' operations!",
+						self.library_id,
+						batch.len()
+					);
+					return true;
+				}
+			},
+			Err(TrySendError::Closed(())) => return false,
+		};
+
+		// The depth is incremented before sending so the receiver can never decrement it below zero
+		self.depth.fetch_add(1, Ordering::Relaxed);
+		permit.send(batch);
+		true
+	}
+}
+
+pub struct SyncQueueReceiver {
+	rx: mpsc::Receiver<Vec<CRDTOperation>>,
+	depth: Arc<AtomicUsize>,
+}
+
+impl SyncQueueReceiver {
+	/// recv returns the next batch to send. This returns `None` once the sender has been dropped and the queue is empty.
+	pub async fn recv(&mut self) -> Option<Vec<CRDTOperation>> {
+		let batch = self.rx.recv().await?;
+		self.depth.fetch_sub(1, Ordering::Relaxed);
+		Some(batch)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use sd_sync::{CRDTOperationType, OwnedOperation};
+	use uhlc::NTP64;
+
+	use super::*;
+
+	fn operation() -> CRDTOperation {
+		CRDTOperation {
+			node: Uuid::new_v4(),
+			timestamp: NTP64(1),
+			id: Uuid::new_v4(),
+			typ: CRDTOperationType::Owned(OwnedOperation {
+				model: "location".to_owned(),
+				items: Vec::new(),
+			}),
+		}
+	}
+
+	fn config(policy: BackpressurePolicy) -> SyncQueueConfig {
+		SyncQueueConfig {
+			capacity: 2,
+			policy,
+		}
+	}
+
+	#[tokio::test]
+	async fn test_queue_drop_with_warning() {
+		let depth = Arc::new(AtomicUsize::new(0));
+		let (tx, mut rx) = sync_queue(
+			Uuid::nil(),
+			&config(BackpressurePolicy::DropWithWarning),
+			depth.clone(),
+		);
+
+		for len in 1..=3 {
+			assert!(tx.push((0..len).map(|_| operation()).collect()).await);
+		}
+		assert_eq!(depth.load(Ordering::Relaxed), 2);
+
+		assert_eq!(rx.recv().await.unwrap().len(), 1);
+		assert_eq!(rx.recv().await.unwrap().len(), 2);
+		assert_eq!(depth.load(Ordering::Relaxed), 0);
+
+		drop(tx);
+		assert!(rx.recv().await.is_none());
+	}
+
+	#[tokio::test]
+	async fn test_queue_block() {
+		let depth = Arc::new(AtomicUsize::new(0));
+		let (tx, mut rx) = sync_queue(
+			Uuid::nil(),
+			&config(BackpressurePolicy::Block),
+			depth.clone(),
+		);
+
+		assert!(tx.push(Vec::new()).await);
+		assert!(tx.push(Vec::new()).await);
+
+		// The queue is full so the producer must wait until a batch is received
+		assert!(
+			tokio::time::timeout(Duration::from_millis(50), tx.push(Vec::new()))
+				.await
+				.is_err()
+		);
+		assert_eq!(depth.load(Ordering::Relaxed), 2);
+
+		let push = tokio::spawn(async move { tx.push(Vec::new()).await });
+		rx.recv().await.unwrap();
+		assert!(push.await.unwrap());
+		assert_eq!(depth.load(Ordering::Relaxed), 2);
+
+		rx.recv().await.unwrap();
+		rx.recv().await.unwrap();
+		assert!(rx.recv().await.is_none());
+		assert_eq!(depth.load(Ordering::Relaxed), 0);
+	}
+
+	#[tokio::test]
+	async fn test_queue_closed() {
+		let depth = Arc::new(AtomicUsize::new(0));
+		let (tx, rx) = sync_queue(Uuid::nil(), &SyncQueueConfig::default(), depth.clone());
+		drop(rx);
+
+		assert!(!tx.push(Vec::new()).await);
+		assert_eq!(depth.load(Ordering::Relaxed), 0);
+	}
+}
@@ -0,0 +1,432 @@
+use std::{
+	fmt,
+	io::SeekFrom,
+	path::{Path, PathBuf},
+};
+
+use rspc::Type;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use tokio::{
+	fs::File,
+	io::{self, AsyncRead, AsyncReadExt, AsyncSeekExt},
+};
+use uuid::Uuid;
+
+use crate::{
+	library::LibraryManager, location::file_path_helper::MaterializedPath, prisma::file_path,
+};
+
+use super::Response;
+
+/// the largest chunk which will be sent in a single [Response::FileChunk].
+/// This must be smaller than `DEFAULT_MAX_MESSAGE_SIZE` so the chunk fits into a single message.
+pub const MAX_FILE_CHUNK_SIZE: u32 = 4 * 1024 * 1024; // 4 MiB
+
+/// the chunk size used by [crate::p2p::P2PManager::request_file] when none is specified.
+pub const DEFAULT_FILE_CHUNK_SIZE: u32 = 1024 * 1024; // 1 MiB
+
+/// the size of the buffer used when hashing a file for its [FileChecksum].
+const CHECKSUM_BUF_SIZE: usize = 1024 * 1024; // 1 MiB
+
+/// the hash function a [FileChecksum] was computed with.
+/// This is sent with the checksum so the algorithm can be changed in future. A new algorithm must be added as a new variant alongside a [super::PROTO_VERSION] bump, as older peers can't decode variants they don't know about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChecksumAlgorithm {
+	Blake3,
+}
+
+impl ChecksumAlgorithm {
+	pub(super) fn hasher(&self) -> FileHasher {
+		match self {
+			Self::Blake3 => FileHasher::Blake3(Box::new(blake3::Hasher::new())),
+		}
+	}
+}
+
+/// identifies the file a transfer is reading from the peer. This is the same file as the `library_id`, `location_id` and `file_path_id` of a [super::Request::FileChunkWithChecksum].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Type)]
+pub struct TransferId {
+	pub library_id: Uuid,
+	pub location_id: i32,
+	pub file_path_id: i32,
+}
+
+/// FileRequest is a file to download from a peer with [crate::p2p::P2PManager::request_file].
+#[serde_as]
+#[derive(Debug, Clone, Copy, Deserialize, Type)]
+pub struct FileRequest {
+	pub transfer_id: TransferId,
+	/// the number of bytes which were already written to the destination by an interrupted transfer. The transfer is resumed from here.
+	#[specta(type = String)]
+	#[serde_as(as = "DisplayFromStr")]
+	pub offset: u64,
+	/// the number of bytes requested at a time. This is [DEFAULT_FILE_CHUNK_SIZE] if it's not set.
+	pub chunk_size: Option<u32>,
+}
+
+/// the hash of a file's contents. This is sent with the first [Response::FileChunkWithChecksum] of a transfer so the receiver can verify the file once it's been reassembled.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileChecksum {
+	pub algorithm: ChecksumAlgorithm,
+	pub hash: Vec<u8>,
+}
+
+impl FileChecksum {
+	/// of_reader hashes everything read from `reader` with the given algorithm.
+	pub(super) async fn of_reader(
+		algorithm: ChecksumAlgorithm,
+		reader: &mut (impl AsyncRead + Unpin),
+	) -> Result<Self, io::Error> {
+		let mut hasher = algorithm.hasher();
+		hasher.update_from_reader(reader).await?;
+		Ok(hasher.finalize())
+	}
+}
+
+impl fmt::Debug for FileChecksum {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{:?}(", self.algorithm)?;
+		for byte in &self.hash {
+			write!(f, "{byte:02x}")?;
+		}
+		write!(f, ")")
+	}
+}
+
+impl fmt::Display for FileChecksum {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		fmt::Debug::fmt(self, f)
+	}
+}
+
+/// incrementally computes a [FileChecksum] as a file is received.
+pub(super) enum FileHasher {
+	Blake3(Box<blake3::Hasher>),
+}
+
+impl FileHasher {
+	pub fn update(&mut self, bytes: &[u8]) {
+		match self {
+			Self::Blake3(hasher) => {
+				hasher.update(bytes);
+			}
+		}
+	}
+
+	/// update_from_reader hashes everything read from `reader`. Use [AsyncReadExt::take] to only hash part of a file.
+	pub async fn update_from_reader(
+		&mut self,
+		reader: &mut (impl AsyncRead + Unpin),
+	) -> Result<(), io::Error> {
+		let mut buf = vec![0; CHECKSUM_BUF_SIZE];
+		loop {
+			let len = reader.read(&mut buf).await?;
+			if len == 0 {
+				return Ok(());
+			}
+			self.update(&buf[..len]);
+		}
+	}
+
+	/// finalize returns the checksum of everything hashed so far. More can still be hashed afterwards.
+	pub fn finalize(&self) -> FileChecksum {
+		match self {
+			Self::Blake3(hasher) => FileChecksum {
+				algorithm: ChecksumAlgorithm::Blake3,
+				hash: hasher.finalize().as_bytes().to_vec(),
+			},
+		}
+	}
+}
+
+/// read_file_chunk handles a `Request::FileChunk` by reading the requested range of the file from disk.
+/// If `expected_size` is set and the file no longer has that size it has changed since the transfer started so an error is returned.
+/// With `with_checksum` a [Response::FileChunkWithChecksum] is returned instead which includes the file's checksum if it's the first chunk of the transfer.
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn read_file_chunk(
+	library_manager: &LibraryManager,
+	library_id: Uuid,
+	location_id: i32,
+	file_path_id: i32,
+	offset: u64,
+	len: u32,
+	expected_size: Option<u64>,
+	with_checksum: bool,
+) -> Response {
+	let path = match find_file(library_manager, library_id, location_id, file_path_id).await {
+		Ok(path) => path,
+		Err(response) => return response,
+	};
+
+	match read_chunk(
+		&path,
+		offset,
+		len.min(MAX_FILE_CHUNK_SIZE),
+		expected_size,
+		with_checksum,
+	)
+	.await
+	{
+		Ok(response) => response,
+		Err(err) if err.kind() == io::ErrorKind::NotFound => {
+			Response::Error("file was deleted during the transfer".into())
+		}
+		Err(err) => Response::Error(format!("error reading file: {err}")),
+	}
+}
+
+/// resume_file_transfer handles a `Request::ResumeTransfer` by checking the bytes the peer already has are still the start of the file.
+pub(super) async fn resume_file_transfer(
+	library_manager: &LibraryManager,
+	transfer_id: TransferId,
+	offset: u64,
+	checksum: FileChecksum,
+) -> Response {
+	let path = match find_file(
+		library_manager,
+		transfer_id.library_id,
+		transfer_id.location_id,
+		transfer_id.file_path_id,
+	)
+	.await
+	{
+		Ok(path) => path,
+		Err(response) => return response,
+	};
+
+	match resume_transfer(&path, offset, &checksum).await {
+		Ok(response) => response,
+		Err(err) if err.kind() == io::ErrorKind::NotFound => {
+			Response::Error("file was deleted during the transfer".into())
+		}
+		Err(err) => Response::Error(format!("error reading file: {err}")),
+	}
+}
+
+/// find_file returns the path of a file on disk, or the [Response::Error] to send if it can't be found.
+async fn find_file(
+	library_manager: &LibraryManager,
+	library_id: Uuid,
+	location_id: i32,
+	file_path_id: i32,
+) -> Result<PathBuf, Response> {
+	let Some(library) = library_manager.get_ctx(library_id).await else {
+		return Err(Response::Error("library not found".into()));
+	};
+
+	let file_path = match library
+		.db
+		.file_path()
+		.find_unique(file_path::location_id_id(location_id, file_path_id))
+		.include(file_path::include!({ location }))
+		.exec()
+		.await
+	{
+		Ok(Some(file_path)) => file_path,
+		Ok(None) => return Err(Response::Error("file not found".into())),
+		Err(err) => return Err(Response::Error(format!("error finding file: {err}"))),
+	};
+
+	Ok(
+		Path::new(&file_path.location.path).join(&MaterializedPath::from((
+			location_id,
+			&file_path.materialized_path,
+		))),
+	)
+}
+
+/// resume_transfer hashes the first `offset` bytes of the file and compares it to the `checksum` of the bytes the peer already has.
+/// The same hasher then continues over the rest of the file, so the file is only read once to also get the checksum of the whole file.
+async fn resume_transfer(
+	path: &Path,
+	offset: u64,
+	checksum: &FileChecksum,
+) -> Result<Response, io::Error> {
+	let mut file = File::open(path).await?;
+	let size = file.metadata().await?.len();
+
+	if offset > size {
+		return Ok(Response::Error(format!(
+			"offset '{offset}' is past the end of the file of '{size}' bytes"
+		)));
+	}
+
+	let mut hasher = checksum.algorithm.hasher();
+	hasher
+		.update_from_reader(&mut (&mut file).take(offset))
+		.await?;
+
+	// The file was changed since the peer received the start of it so it has to be transferred again
+	let offset = if hasher.finalize() == *checksum {
+		offset
+	} else {
+		0
+	};
+
+	hasher.update_from_reader(&mut file).await?;
+
+	Ok(Response::TransferResumed {
+		offset,
+		size,
+		checksum: hasher.finalize(),
+	})
+}
+
+async fn read_chunk(
+	path: &Path,
+	offset: u64,
+	len: u32,
+	expected_size: Option<u64>,
+	with_checksum: bool,
+) -> Result<Response, io::Error> {
+	let mut file = File::open(path).await?;
+	let size = file.metadata().await?.len();
+
+	if let Some(expected_size) = expected_size {
+		if expected_size != size {
+			return Ok(Response::Error(format!(
+				"file changed size during the transfer from '{expected_size}' to '{size}' bytes"
+			)));
+		}
+	}
+
+	if offset > size {
+		return Ok(Response::Error(format!(
+			"offset '{offset}' is past the end of the file of '{size}' bytes"
+		)));
+	}
+
+	file.seek(SeekFrom::Start(offset)).await?;
+
+	let mut bytes = Vec::with_capacity((size - offset).min(len as u64) as usize);
+	(&mut file).take(len as u64).read_to_end(&mut bytes).await?;
+
+	let eof = offset + bytes.len() as u64 >= size;
+	if !with_checksum {
+		return Ok(Response::FileChunk { eof, bytes, size });
+	}
+
+	// The first request of a transfer doesn't know the size yet so this only hashes the file once per transfer.
+	// The file is hashed from disk instead of using the indexed `integrity_checksum` so a stale index can't make a valid transfer fail.
+	let checksum = match expected_size {
+		Some(_) => None,
+		None => {
+			file.seek(SeekFrom::Start(0)).await?;
+			Some(FileChecksum::of_reader(ChecksumAlgorithm::Blake3, &mut file).await?)
+		}
+	};
+
+	Ok(Response::FileChunkWithChecksum {
+		bytes,
+		size,
+		eof,
+		checksum,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::io::AsyncWriteExt;
+
+	use super::*;
+
+	#[tokio::test]
+	async fn test_read_chunk_with_checksum() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("file");
+		let contents = (0..=255u8).cycle().take(10_000).collect::<Vec<_>>();
+		File::create(&path)
+			.await
+			.unwrap()
+			.write_all(&contents)
+			.await
+			.unwrap();
+
+		let expected = FileChecksum {
+			algorithm: ChecksumAlgorithm::Blake3,
+			hash: blake3::hash(&contents).as_bytes().to_vec(),
+		};
+
+		// Only the first chunk of the transfer carries the checksum
+		let Response::FileChunkWithChecksum { bytes, checksum, eof, .. } = read_chunk(&path, 0, 4096, None, true).await.unwrap() else {
+			panic!("expected a chunk with a checksum");
+		};
+		assert_eq!(bytes, &contents[..4096]);
+		assert_eq!(checksum, Some(expected.clone()));
+		assert!(!eof);
+
+		let Response::FileChunkWithChecksum { checksum, .. } = read_chunk(&path, 4096, 4096, Some(10_000), true).await.unwrap() else {
+			panic!("expected a chunk with a checksum");
+		};
+		assert_eq!(checksum, None);
+
+		// Hashing the chunks as they're received gives the same checksum
+		let mut hasher = ChecksumAlgorithm::Blake3.hasher();
+		for chunk in contents.chunks(4096) {
+			hasher.update(chunk);
+		}
+		assert_eq!(hasher.finalize(), expected);
+	}
+
+	#[tokio::test]
+	async fn test_resume_transfer() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("file");
+		let contents = (0..=255u8).cycle().take(10_000).collect::<Vec<_>>();
+		File::create(&path)
+			.await
+			.unwrap()
+			.write_all(&contents)
+			.await
+			.unwrap();
+
+		let prefix_checksum = |prefix: &[u8]| {
+			let mut hasher = ChecksumAlgorithm::Blake3.hasher();
+			hasher.update(prefix);
+			hasher.finalize()
+		};
+		let expected = prefix_checksum(&contents);
+
+		// The transfer continues from where it was interrupted
+		assert_eq!(
+			resume_transfer(&path, 4096, &prefix_checksum(&contents[..4096]))
+				.await
+				.unwrap(),
+			Response::TransferResumed {
+				offset: 4096,
+				size: 10_000,
+				checksum: expected.clone(),
+			}
+		);
+
+		// Bytes which aren't the start of the file restart the transfer
+		let mut changed = contents[..4096].to_vec();
+		changed[42] ^= 1;
+		assert_eq!(
+			resume_transfer(&path, 4096, &prefix_checksum(&changed))
+				.await
+				.unwrap(),
+			Response::TransferResumed {
+				offset: 0,
+				size: 10_000,
+				checksum: expected.clone(),
+			}
+		);
+
+		// A completely received file is resumed at its end
+		assert_eq!(
+			resume_transfer(&path, 10_000, &expected).await.unwrap(),
+			Response::TransferResumed {
+				offset: 10_000,
+				size: 10_000,
+				checksum: expected.clone(),
+			}
+		);
+
+		assert!(matches!(
+			resume_transfer(&path, 10_001, &expected).await.unwrap(),
+			Response::Error(err) if err.contains("past the end of the file")
+		));
+	}
+}
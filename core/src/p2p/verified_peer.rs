@@ -0,0 +1,77 @@
+//! Sync operations are only accepted from peers which have been verified as members of the library they're for.
+//!
+//! A peer is a member of a library if it's paired with this node, as pairing is how it received the library's sync key.
+//! Each operation is still checked against the sync key when it's received, see [super::SignedOperation::verify], but operations pushed by a peer which isn't verified are rejected before any of them are decoded.
+
+use std::collections::HashSet;
+
+use sd_p2p::PeerId;
+use uuid::Uuid;
+
+use super::P2PError;
+
+/// VerifiedPeer is the libraries a connected peer has been verified as a member of.
+/// It's established during the handshake and cached until the peer disconnects, or the pairings or libraries on this node change.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifiedPeer {
+	peer_id: PeerId,
+	libraries: HashSet<Uuid>,
+}
+
+impl VerifiedPeer {
+	/// new verifies the peer as a member of each of the `libraries` loaded on this node. Unpaired peers aren't a member of any library.
+	pub fn new(peer_id: PeerId, paired: bool, libraries: impl IntoIterator<Item = Uuid>) -> Self {
+		Self {
+			peer_id,
+			libraries: if paired {
+				libraries.into_iter().collect()
+			} else {
+				HashSet::new()
+			},
+		}
+	}
+
+	pub fn peer_id(&self) -> PeerId {
+		self.peer_id
+	}
+
+	/// authorize returns [P2PError::PeerNotAuthorized] unless the peer has been verified as a member of the library.
+	pub fn authorize(&self, library_id: Uuid) -> Result<(), P2PError> {
+		if self.libraries.contains(&library_id) {
+			return Ok(());
+		}
+
+		Err(P2PError::PeerNotAuthorized {
+			peer_id: self.peer_id,
+			library_id,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::str::FromStr;
+
+	use super::*;
+
+	#[test]
+	fn test_verified_peer() {
+		let peer_id =
+			PeerId::from_str("12D3KooW9tHTtS3inCZiYykw4u5G4frbjVFqhkmJX12gSNCVeH3e").unwrap();
+		let [library_id, other_library_id] = [Uuid::new_v4(), Uuid::new_v4()];
+
+		let peer = VerifiedPeer::new(peer_id, true, [library_id]);
+		assert!(peer.authorize(library_id).is_ok());
+		assert!(matches!(
+			peer.authorize(other_library_id),
+			Err(P2PError::PeerNotAuthorized { library_id, .. }) if library_id == other_library_id
+		));
+
+		// Unpaired peers never received the sync key so they aren't a member of any library
+		let peer = VerifiedPeer::new(peer_id, false, [library_id]);
+		assert!(matches!(
+			peer.authorize(library_id),
+			Err(P2PError::PeerNotAuthorized { peer_id: id, .. }) if id == peer_id
+		));
+	}
+}
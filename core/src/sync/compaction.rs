@@ -0,0 +1,208 @@
+use std::collections::{HashMap, HashSet};
+
+use sd_sync::{CRDTOperation, CRDTOperationType, RelationOperationData, SharedOperationData};
+
+/// What an operation does to the record it belongs to.
+enum Effect {
+	Create,
+	Update(String),
+	Delete,
+}
+
+/// returns the record (or relation) the operation belongs to and what it does to it.
+/// Owned operations return `None` as they are only ever applied by the node which owns them, so they are never compacted.
+fn record_effect(op: &CRDTOperation) -> Option<(String, Effect)> {
+	match &op.typ {
+		CRDTOperationType::Shared(shared_op) => Some((
+			format!("shared:{}:{}", shared_op.model, shared_op.record_id),
+			match &shared_op.data {
+				SharedOperationData::Create(_) => Effect::Create,
+				SharedOperationData::Update { field, .. } => Effect::Update(field.clone()),
+				SharedOperationData::Delete => Effect::Delete,
+			},
+		)),
+		CRDTOperationType::Relation(relation_op) => Some((
+			format!(
+				"relation:{}:{}:{}",
+				relation_op.relation, relation_op.relation_item, relation_op.relation_group
+			),
+			match &relation_op.data {
+				RelationOperationData::Create => Effect::Create,
+				RelationOperationData::Update { field, .. } => Effect::Update(field.clone()),
+				RelationOperationData::Delete => Effect::Delete,
+			},
+		)),
+		CRDTOperationType::Owned(_) => None,
+	}
+}
+
+/// compact_operations removes the operations which have been superseded by a later operation so a new peer doesn't have to replay the entire history of a library.
+/// For each record only the latest delete, the first create after it and the latest update of each field after it are kept.
+/// Applying the compacted operations results in the same state as applying all of them. The operations are returned in timestamp order.
+pub fn compact_operations(mut ops: Vec<CRDTOperation>) -> Vec<CRDTOperation> {
+	ops.sort_by_key(|op| op.timestamp.0);
+
+	let mut keep = vec![true; ops.len()];
+	let mut deleted = HashSet::new();
+	let mut created = HashMap::new();
+	let mut updated = HashSet::new();
+
+	// We walk backwards so the latest operation on each record and field is seen first
+	for (i, op) in ops.iter().enumerate().rev() {
+		let Some((record, effect)) = record_effect(op) else {
+			continue;
+		};
+
+		// Everything before a delete is superseded by it
+		if deleted.contains(&record) {
+			keep[i] = false;
+			continue;
+		}
+
+		match effect {
+			Effect::Delete => {
+				deleted.insert(record);
+			}
+			Effect::Create => {
+				// Only the first create is needed, so any later one we've already kept is redundant
+				if let Some(later) = created.insert(record, i) {
+					keep[later] = false;
+				}
+			}
+			Effect::Update(field) => keep[i] = updated.insert((record, field)),
+		}
+	}
+
+	ops.into_iter()
+		.zip(keep)
+		.filter_map(|(op, keep)| keep.then_some(op))
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeMap;
+
+	use sd_sync::{OwnedOperation, SharedOperation, SharedOperationCreateData};
+	use serde_json::{json, Map, Value};
+	use uhlc::NTP64;
+	use uuid::Uuid;
+
+	use super::*;
+
+	fn shared(timestamp: u64, record: &str, data: SharedOperationData) -> CRDTOperation {
+		CRDTOperation {
+			node: Uuid::nil(),
+			timestamp: NTP64(timestamp),
+			id: Uuid::new_v4(),
+			typ: CRDTOperationType::Shared(SharedOperation {
+				record_id: json!(record),
+				model: "tag".into(),
+				data,
+			}),
+		}
+	}
+
+	fn create(timestamp: u64, record: &str) -> CRDTOperation {
+		let mut data = Map::new();
+		data.insert("name".into(), json!(record));
+		shared(
+			timestamp,
+			record,
+			SharedOperationData::Create(SharedOperationCreateData::Unique(data)),
+		)
+	}
+
+	fn update(timestamp: u64, record: &str, field: &str, value: Value) -> CRDTOperation {
+		shared(
+			timestamp,
+			record,
+			SharedOperationData::Update {
+				field: field.into(),
+				value,
+			},
+		)
+	}
+
+	fn delete(timestamp: u64, record: &str) -> CRDTOperation {
+		shared(timestamp, record, SharedOperationData::Delete)
+	}
+
+	/// applies the shared operations the same way a peer would, returning the resulting records
+	fn apply(ops: &[CRDTOperation]) -> BTreeMap<String, BTreeMap<String, Value>> {
+		let mut ops = ops.to_vec();
+		ops.sort_by_key(|op| op.timestamp.0);
+
+		let mut records = BTreeMap::new();
+		for op in ops {
+			let CRDTOperationType::Shared(op) = op.typ else {
+				continue;
+			};
+			let record = op.record_id.to_string();
+
+			match op.data {
+				SharedOperationData::Create(SharedOperationCreateData::Unique(data)) => {
+					records
+						.entry(record)
+						.or_insert_with(|| data.into_iter().collect());
+				}
+				SharedOperationData::Create(SharedOperationCreateData::Atomic) => {
+					records.entry(record).or_default();
+				}
+				SharedOperationData::Update { field, value } => {
+					if let Some(fields) = records.get_mut(&record) {
+						fields.insert(field, value);
+					}
+				}
+				SharedOperationData::Delete => {
+					records.remove(&record);
+				}
+			}
+		}
+
+		records
+	}
+
+	#[test]
+	fn test_compaction_converges() {
+		let owned = CRDTOperation {
+			node: Uuid::nil(),
+			timestamp: NTP64(5),
+			id: Uuid::new_v4(),
+			typ: CRDTOperationType::Owned(OwnedOperation {
+				model: "location".into(),
+				items: Vec::new(),
+			}),
+		};
+
+		let ops = vec![
+			create(1, "a"),
+			update(2, "a", "color", json!("red")),
+			update(3, "a", "name", json!("b")),
+			update(4, "a", "color", json!("blue")),
+			owned.clone(),
+			create(6, "b"),
+			update(7, "b", "color", json!("red")),
+			delete(8, "b"),
+			create(9, "c"),
+			delete(10, "c"),
+			create(11, "c"),
+			update(12, "c", "color", json!("green")),
+			create(13, "c"),
+			// The operations aren't always in timestamp order
+			update(0, "a", "color", json!("green")),
+		];
+
+		let compacted = compact_operations(ops.clone());
+
+		assert_eq!(apply(&compacted), apply(&ops));
+		assert_eq!(
+			compacted.iter().map(|op| op.id).collect::<Vec<_>>(),
+			[&ops[0], &ops[2], &ops[3], &ops[4], &ops[7], &ops[9], &ops[10], &ops[11]]
+				.iter()
+				.map(|op| op.id)
+				.collect::<Vec<_>>()
+		);
+		assert!(compacted.iter().any(|op| op.id == owned.id));
+	}
+}
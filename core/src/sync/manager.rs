@@ -9,7 +9,7 @@ use tokio::sync::broadcast::{self, Receiver, Sender};
 use uhlc::{HLCBuilder, HLC, NTP64};
 use uuid::Uuid;
 
-use super::ModelSyncData;
+use super::{compact_operations, ModelSyncData};
 
 #[derive(Clone)]
 pub enum SyncMessage {
@@ -184,9 +184,28 @@ impl SyncManager {
 			.collect())
 	}
 
+	/// get_compacted_ops returns the operations needed to bootstrap a new peer with the superseded operations removed.
+	/// The operations stored in the library are left untouched.
+	pub async fn get_compacted_ops(&self) -> prisma_client_rust::Result<Vec<CRDTOperation>> {
+		Ok(compact_operations(self.get_ops().await?))
+	}
+
+	/// ingest_op will apply an operation received from another node.
+	/// Shared operations which have already been applied are skipped so receiving the same operation more than once is harmless.
 	pub async fn ingest_op(&self, op: CRDTOperation) -> prisma_client_rust::Result<()> {
 		let db = &self.db;
 
+		if matches!(op.typ, CRDTOperationType::Shared(_))
+			&& db
+				.shared_operation()
+				.find_unique(shared_operation::id::equals(op.id.as_bytes().to_vec()))
+				.exec()
+				.await?
+				.is_some()
+		{
+			return Ok(());
+		}
+
 		db.node()
 			.upsert(
 				node::pub_id::equals(op.node.as_bytes().to_vec()),
@@ -1,4 +1,6 @@
+mod compaction;
 mod manager;
 
 pub use crate::prisma_sync::*;
+pub use compaction::*;
 pub use manager::*;
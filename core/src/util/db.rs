@@ -63,7 +63,10 @@ pub async fn write_storedkey_to_db(
 				key.key_nonce.to_vec(),
 				key.key.to_vec(),
 				key.salt.to_vec(),
-				vec![],
+				vec![
+					prisma::key::name::set(Some(key.label.clone())),
+					prisma::key::description::set(key.description.clone()),
+				],
 			)
 			.exec()
 			.await?;
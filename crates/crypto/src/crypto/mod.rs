@@ -3,8 +3,11 @@
 use crate::Result;
 use tokio::io::AsyncReadExt;
 
+mod segments;
 mod stream;
 
+pub use self::segments::is_valid_segment_size;
+pub(crate) use self::segments::reencrypt_segments;
 pub use self::stream::{Decryptor, Encryptor};
 
 /// This is used to exhaustively read from an asynchronous reader into a buffer.
@@ -16,7 +19,7 @@ pub use self::stream::{Decryptor, Encryptor};
 /// - when an error has been generated
 ///
 /// It returns the amount of total bytes read, which will be <= the buffer's size.
-async fn exhaustive_read<R>(reader: &mut R, buffer: &mut Box<[u8]>) -> Result<usize>
+pub(crate) async fn exhaustive_read<R>(reader: &mut R, buffer: &mut Box<[u8]>) -> Result<usize>
 where
 	R: AsyncReadExt + Unpin + Send,
 {
@@ -40,8 +43,9 @@ mod tests {
 	use rand_chacha::ChaCha20Rng;
 
 	use crate::{
-		primitives::BLOCK_LEN,
+		primitives::{AEAD_TAG_LEN, BLOCK_LEN},
 		types::{Algorithm, Key, Nonce},
+		Error,
 	};
 
 	use super::*;
@@ -148,7 +152,7 @@ mod tests {
 	}
 
 	#[tokio::test]
-	#[should_panic(expected = "Decrypt")]
+	#[should_panic(expected = "AuthenticationFailed")]
 	async fn aes_decrypt_bytes_missing_aad() {
 		Decryptor::decrypt_bytes(
 			KEY,
@@ -190,6 +194,116 @@ mod tests {
 		assert_eq!(buf, output);
 	}
 
+	#[tokio::test]
+	async fn encrypt_and_decrypt_offloaded() {
+		// an exact multiple of `BLOCK_LEN` so the final block is empty
+		let mut buf = vec![0u8; BLOCK_LEN * 3];
+		ChaCha20Rng::from_entropy().fill_bytes(&mut buf);
+
+		let mut expected = Cursor::new(Vec::new());
+		Encryptor::new(KEY, XCHACHA_NONCE, Algorithm::XChaCha20Poly1305)
+			.unwrap()
+			.encrypt_streams(buf.as_slice(), &mut expected, &AAD)
+			.await
+			.unwrap();
+
+		let mut writer = Cursor::new(Vec::new());
+		Encryptor::new(KEY, XCHACHA_NONCE, Algorithm::XChaCha20Poly1305)
+			.unwrap()
+			.encrypt_streams_offloaded(buf.as_slice(), &mut writer, &AAD)
+			.await
+			.unwrap();
+
+		let encrypted = writer.into_inner();
+		assert_eq!(encrypted, expected.into_inner());
+
+		let mut progress = Vec::new();
+		let mut writer = Cursor::new(Vec::new());
+		Decryptor::new(KEY, XCHACHA_NONCE, Algorithm::XChaCha20Poly1305)
+			.unwrap()
+			.decrypt_stream_offloaded(encrypted.as_slice(), &mut writer, &AAD, |p| {
+				progress.push(p);
+			})
+			.await
+			.unwrap();
+
+		assert_eq!(writer.into_inner(), buf);
+		assert_eq!(progress.len(), 4);
+
+		// the AAD is still authenticated with every block
+		assert!(matches!(
+			Decryptor::new(KEY, XCHACHA_NONCE, Algorithm::XChaCha20Poly1305)
+				.unwrap()
+				.decrypt_streams_offloaded(encrypted.as_slice(), Cursor::new(Vec::new()), &[])
+				.await,
+			Err(Error::AuthenticationFailed { block: None })
+		));
+	}
+
+	#[tokio::test]
+	async fn encrypt_and_decrypt_async() {
+		// a partial final block, and an exact multiple of `BLOCK_LEN` so the final block is empty
+		for len in [BLOCK_LEN + 17, BLOCK_LEN * 2] {
+			let mut buf = vec![0u8; len];
+			ChaCha20Rng::from_entropy().fill_bytes(&mut buf);
+
+			let expected = Encryptor::encrypt_bytes(
+				KEY,
+				XCHACHA_NONCE,
+				Algorithm::XChaCha20Poly1305,
+				&buf,
+				&AAD,
+			)
+			.await
+			.unwrap();
+
+			let encrypted = Encryptor::encrypt_async(
+				KEY,
+				XCHACHA_NONCE,
+				Algorithm::XChaCha20Poly1305,
+				buf.clone(),
+				&AAD,
+			)
+			.await
+			.unwrap();
+			assert_eq!(encrypted, expected);
+			assert_eq!(
+				Encryptor::encrypt(KEY, XCHACHA_NONCE, Algorithm::XChaCha20Poly1305, &buf, &AAD)
+					.unwrap(),
+				expected
+			);
+
+			let decrypted = Decryptor::decrypt_async(
+				KEY,
+				XCHACHA_NONCE,
+				Algorithm::XChaCha20Poly1305,
+				encrypted.clone(),
+				&AAD,
+			)
+			.await
+			.unwrap();
+			assert_eq!(decrypted.expose(), &buf);
+
+			// errors are returned unchanged from the blocking thread pool
+			assert!(matches!(
+				Decryptor::decrypt_async(
+					KEY,
+					XCHACHA_NONCE,
+					Algorithm::XChaCha20Poly1305,
+					encrypted,
+					&[]
+				)
+				.await,
+				Err(Error::AuthenticationFailed { block: None })
+			));
+			assert!(matches!(
+				Encryptor::encrypt_async(KEY, AES_NONCE, Algorithm::XChaCha20Poly1305, buf, &AAD)
+					.await,
+				Err(Error::NonceLengthMismatch)
+			));
+		}
+	}
+
 	#[tokio::test]
 	async fn aes_encrypt_and_decrypt_5_blocks_with_aad() {
 		let mut buf = vec![0u8; BLOCK_LEN * 5];
@@ -280,7 +394,7 @@ mod tests {
 	}
 
 	#[tokio::test]
-	#[should_panic(expected = "Decrypt")]
+	#[should_panic(expected = "AuthenticationFailed")]
 	async fn xchacha_decrypt_bytes_missing_aad() {
 		Decryptor::decrypt_bytes(
 			KEY,
@@ -293,6 +407,79 @@ mod tests {
 		.unwrap();
 	}
 
+	#[tokio::test]
+	async fn decrypt_bytes_with_flipped_byte() {
+		for (nonce, algorithm, expected) in [
+			(AES_NONCE, Algorithm::Aes256Gcm, AES_BYTES_EXPECTED[1]),
+			(
+				XCHACHA_NONCE,
+				Algorithm::XChaCha20Poly1305,
+				XCHACHA_BYTES_EXPECTED[1],
+			),
+		] {
+			// both the ciphertext and the tag are authenticated
+			for i in [0, PLAINTEXT.len(), expected.len() - 1] {
+				let mut ciphertext = expected;
+				ciphertext[i] ^= 0x01;
+
+				assert!(matches!(
+					Decryptor::decrypt_bytes(KEY, nonce, algorithm, &ciphertext, &AAD).await,
+					Err(Error::AuthenticationFailed { block: None })
+				));
+			}
+		}
+	}
+
+	#[tokio::test]
+	async fn verify_streams() {
+		let mut buf = vec![0u8; BLOCK_LEN * 2];
+		ChaCha20Rng::from_entropy().fill_bytes(&mut buf);
+
+		for (nonce, algorithm) in [
+			(AES_NONCE, Algorithm::Aes256Gcm),
+			(XCHACHA_NONCE, Algorithm::XChaCha20Poly1305),
+		] {
+			let mut encrypted = Vec::new();
+			Encryptor::new(KEY, nonce, algorithm)
+				.unwrap()
+				.encrypt_streams(buf.as_slice(), &mut encrypted, &AAD)
+				.await
+				.unwrap();
+
+			Decryptor::new(KEY, nonce, algorithm)
+				.unwrap()
+				.verify_streams(encrypted.as_slice(), &AAD)
+				.await
+				.unwrap();
+
+			// the data is a multiple of the block size, so the final block is empty
+			for (i, block) in [
+				(0, 0),
+				(BLOCK_LEN + AEAD_TAG_LEN, 1),
+				(encrypted.len() - 1, 2),
+			] {
+				let mut altered = encrypted.clone();
+				altered[i] ^= 1;
+
+				assert!(matches!(
+					Decryptor::new(KEY, nonce, algorithm)
+						.unwrap()
+						.verify_streams(altered.as_slice(), &AAD)
+						.await,
+					Err(Error::AuthenticationFailed { block: Some(b) }) if b == block
+				));
+			}
+
+			assert!(matches!(
+				Decryptor::new(KEY, nonce, algorithm)
+					.unwrap()
+					.verify_streams(encrypted.as_slice(), &[])
+					.await,
+				Err(Error::AuthenticationFailed { block: Some(0) })
+			));
+		}
+	}
+
 	#[tokio::test]
 	async fn xchacha_encrypt_and_decrypt_5_blocks() {
 		let mut buf = vec![0u8; BLOCK_LEN * 5];
@@ -351,6 +538,103 @@ mod tests {
 		assert_eq!(buf, output);
 	}
 
+	#[tokio::test]
+	async fn xchacha_encrypt_and_decrypt_with_progress() {
+		let mut buf = vec![0u8; BLOCK_LEN * 2 + 16];
+		ChaCha20Rng::from_entropy().fill_bytes(&mut buf);
+		let mut reader = Cursor::new(buf.clone());
+		let mut writer = Cursor::new(Vec::new());
+		let mut progress = Vec::new();
+
+		let encryptor = Encryptor::new(KEY, XCHACHA_NONCE, Algorithm::XChaCha20Poly1305).unwrap();
+
+		encryptor
+			.encrypt_stream(&mut reader, &mut writer, &[], |p| progress.push(p))
+			.await
+			.unwrap();
+
+		assert_eq!(
+			progress,
+			vec![BLOCK_LEN as u64, BLOCK_LEN as u64 * 2, buf.len() as u64]
+		);
+
+		let ciphertext = writer.into_inner();
+		let mut reader = Cursor::new(ciphertext.clone());
+		let mut writer = Cursor::new(Vec::new());
+		let mut progress = Vec::new();
+
+		let decryptor = Decryptor::new(KEY, XCHACHA_NONCE, Algorithm::XChaCha20Poly1305).unwrap();
+
+		decryptor
+			.decrypt_stream(&mut reader, &mut writer, &[], |p| progress.push(p))
+			.await
+			.unwrap();
+
+		assert_eq!(buf, writer.into_inner());
+		assert_eq!(progress.last().copied(), Some(ciphertext.len() as u64));
+	}
+
+	#[tokio::test]
+	async fn aes_encrypt_and_decrypt_with_total() {
+		let mut buf = vec![0u8; BLOCK_LEN + 1];
+		ChaCha20Rng::from_entropy().fill_bytes(&mut buf);
+		let mut writer = Cursor::new(Vec::new());
+		let mut progress = Vec::new();
+
+		let encryptor = Encryptor::new(KEY, AES_NONCE, Algorithm::Aes256Gcm).unwrap();
+
+		encryptor
+			.encrypt_stream_with_total(
+				Cursor::new(buf.clone()),
+				&mut writer,
+				&AAD,
+				buf.len() as u64,
+				|processed, total| progress.push((processed, total)),
+			)
+			.await
+			.unwrap();
+
+		// the final partial block is flushed and reported as well
+		let total = buf.len() as u64;
+		assert_eq!(progress, vec![(BLOCK_LEN as u64, total), (total, total)]);
+
+		let mut ciphertext = writer.into_inner();
+		let mut writer = Cursor::new(Vec::new());
+		let total = ciphertext.len() as u64;
+
+		let decryptor = Decryptor::new(KEY, AES_NONCE, Algorithm::Aes256Gcm).unwrap();
+
+		decryptor
+			.decrypt_stream_with_total(
+				Cursor::new(ciphertext.clone()),
+				&mut writer,
+				&AAD,
+				total,
+				|processed, t| assert!(processed <= t),
+			)
+			.await
+			.unwrap();
+
+		assert_eq!(buf, writer.into_inner());
+
+		// tampering with the final block is detected
+		*ciphertext.last_mut().unwrap() ^= 1;
+		let decryptor = Decryptor::new(KEY, AES_NONCE, Algorithm::Aes256Gcm).unwrap();
+
+		assert!(matches!(
+			decryptor
+				.decrypt_stream_with_total(
+					Cursor::new(ciphertext),
+					Cursor::new(Vec::new()),
+					&AAD,
+					total,
+					|_, _| (),
+				)
+				.await,
+			Err(Error::AuthenticationFailed { block: None })
+		));
+	}
+
 	#[tokio::test]
 	#[should_panic(expected = "NonceLengthMismatch")]
 	async fn encrypt_with_invalid_nonce() {
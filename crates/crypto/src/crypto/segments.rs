@@ -0,0 +1,545 @@
+//! This module contains segmented encryption, which splits data into independently-keyed segments so that they can be encrypted/decrypted in parallel.
+//!
+//! Each segment is encrypted with STREAM, using a key derived from the master key and the segment's index. This means every segment can use the same nonce.
+//!
+//! The key for the final segment is also derived with a flag, so that entire segments being truncated from the end can be detected.
+//!
+//! Every segment apart from the last one contains `segment_size` bytes of plaintext, so the boundaries of each segment can be calculated from the segment size alone.
+use std::{collections::VecDeque, sync::Arc};
+
+use tokio::{
+	io::{sink, AsyncReadExt, AsyncWriteExt},
+	task::JoinHandle,
+};
+
+use crate::{
+	primitives::{AEAD_TAG_LEN, BLOCK_LEN, MAX_SEGMENT_SIZE, SALT_LEN, SEGMENT_KEY_CONTEXT},
+	types::{Algorithm, Key, Nonce, Salt},
+	Error, Payload, Result,
+};
+use zeroize::Zeroize;
+
+use super::{exhaustive_read, Decryptor, Encryptor};
+
+/// This is used if the available parallelism can't be determined.
+const DEFAULT_PARALLELISM: usize = 4;
+
+/// This checks that a segment size is usable, as segments need to be made up of whole STREAM blocks (and can't exceed `MAX_SEGMENT_SIZE`).
+#[must_use]
+pub const fn is_valid_segment_size(segment_size: usize) -> bool {
+	segment_size != 0 && segment_size % BLOCK_LEN == 0 && segment_size <= MAX_SEGMENT_SIZE
+}
+
+/// This is the size of a full segment once it has been encrypted (each block gains an AEAD tag).
+const fn encrypted_segment_size(segment_size: usize) -> usize {
+	segment_size + (segment_size / BLOCK_LEN) * AEAD_TAG_LEN
+}
+
+/// This derives the key for a single segment.
+fn segment_key(master_key: &Key, index: u64, last: bool) -> Key {
+	let mut salt = [0u8; SALT_LEN];
+	salt[..8].copy_from_slice(&index.to_le_bytes());
+	salt[8] = u8::from(last);
+
+	Key::derive(master_key.clone(), Salt(salt), SEGMENT_KEY_CONTEXT)
+}
+
+/// This reads up to `len` bytes, and returns fewer only once the reader has been exhausted.
+async fn read_segment<R>(reader: &mut R, len: usize) -> Result<Box<[u8]>>
+where
+	R: AsyncReadExt + Unpin + Send,
+{
+	let mut buffer = vec![0u8; len].into_boxed_slice();
+	let count = exhaustive_read(reader, &mut buffer).await?;
+
+	Ok(buffer[..count].into())
+}
+
+/// This gets the number of segments that should be processed at once.
+fn parallelism() -> usize {
+	std::thread::available_parallelism().map_or(DEFAULT_PARALLELISM, std::num::NonZeroUsize::get)
+}
+
+/// This reads each segment from the reader, processes up to `parallelism()` of them at once, and writes them to the writer in order.
+///
+/// `process` is given the segment's index, whether or not it's the last segment, and the segment itself.
+async fn process_segments<R, W, F, P>(
+	mut reader: R,
+	mut writer: W,
+	segment_size: usize,
+	process: P,
+	mut on_progress: F,
+) -> Result<()>
+where
+	R: AsyncReadExt + Unpin + Send,
+	W: AsyncWriteExt + Unpin + Send,
+	F: FnMut(u64) + Send,
+	P: Fn(u64, bool, &[u8]) -> Result<Vec<u8>> + Send + Sync + 'static,
+{
+	let process = Arc::new(process);
+	let mut in_flight: VecDeque<(u64, JoinHandle<Result<Vec<u8>>>)> = VecDeque::new();
+	let mut processed = 0u64;
+	let mut index = 0u64;
+
+	let mut segment = read_segment(&mut reader, segment_size).await?;
+
+	loop {
+		// a full segment is only the last one if nothing follows it
+		let next = if segment.len() == segment_size {
+			read_segment(&mut reader, segment_size).await?
+		} else {
+			Box::default()
+		};
+		let last = segment.len() < segment_size || next.is_empty();
+
+		let len = segment.len() as u64;
+		let process = process.clone();
+		in_flight.push_back((
+			len,
+			tokio::task::spawn_blocking(move || process(index, last, &segment)),
+		));
+
+		if in_flight.len() >= parallelism() || last {
+			while let Some((len, task)) = in_flight.pop_front() {
+				let bytes = task.await.map_err(std::io::Error::from)??;
+				writer.write_all(&bytes).await?;
+
+				processed += len;
+				on_progress(processed);
+
+				if !last {
+					break;
+				}
+			}
+		}
+
+		if last {
+			break;
+		}
+
+		segment = next;
+		index += 1;
+	}
+
+	writer.flush().await?;
+
+	Ok(())
+}
+
+impl Encryptor {
+	/// This encrypts a single segment. It's split into STREAM blocks, and the final block is always encrypted with `encrypt_last()`.
+	fn encrypt_segment(
+		key: Key,
+		nonce: Nonce,
+		algorithm: Algorithm,
+		plaintext: &[u8],
+		aad: &[u8],
+	) -> Result<Vec<u8>> {
+		let mut encryptor = Self::new(key, nonce, algorithm)?;
+		let mut blocks = plaintext.chunks(BLOCK_LEN).peekable();
+		let mut ciphertext = Vec::with_capacity(encrypted_segment_size(plaintext.len()));
+
+		// an empty segment still has a final block, so truncation can be detected
+		if blocks.peek().is_none() {
+			return encryptor.encrypt_last(Payload { aad, msg: &[] });
+		}
+
+		while let Some(block) = blocks.next() {
+			let payload = Payload { aad, msg: block };
+
+			if blocks.peek().is_some() {
+				ciphertext.extend_from_slice(&encryptor.encrypt_next(payload)?);
+			} else {
+				ciphertext.extend_from_slice(&encryptor.encrypt_last(payload)?);
+				break;
+			}
+		}
+
+		Ok(ciphertext)
+	}
+
+	/// This encrypts data in segments of `segment_size` bytes, which are encrypted in parallel.
+	///
+	/// This is only worthwhile for large amounts of data, and the segment size must be a multiple of `BLOCK_LEN`.
+	///
+	/// The data must be decrypted with `Decryptor::decrypt_segments()`, using the same segment size.
+	///
+	/// `on_progress` is called after every segment has been written, with the total amount of bytes read from the reader so far.
+	#[allow(clippy::too_many_arguments)]
+	pub async fn encrypt_segments<R, W, F>(
+		master_key: Key,
+		nonce: Nonce,
+		algorithm: Algorithm,
+		segment_size: usize,
+		reader: R,
+		writer: W,
+		aad: &[u8],
+		on_progress: F,
+	) -> Result<()>
+	where
+		R: AsyncReadExt + Unpin + Send,
+		W: AsyncWriteExt + Unpin + Send,
+		F: FnMut(u64) + Send,
+	{
+		if !is_valid_segment_size(segment_size) {
+			return Err(Error::InvalidSegmentSize);
+		}
+
+		if nonce.len() != algorithm.nonce_len() {
+			return Err(Error::NonceLengthMismatch);
+		}
+
+		let aad = aad.to_vec();
+
+		process_segments(
+			reader,
+			writer,
+			segment_size,
+			move |index, last, segment| {
+				Self::encrypt_segment(
+					segment_key(&master_key, index, last),
+					nonce,
+					algorithm,
+					segment,
+					&aad,
+				)
+			},
+			on_progress,
+		)
+		.await
+	}
+}
+
+impl Decryptor {
+	/// This decrypts a single segment that was encrypted with `Encryptor::encrypt_segment()`.
+	fn decrypt_segment(
+		key: Key,
+		nonce: Nonce,
+		algorithm: Algorithm,
+		ciphertext: &[u8],
+		aad: &[u8],
+	) -> Result<Vec<u8>> {
+		let mut decryptor = Self::new(key, nonce, algorithm)?;
+		let mut blocks = ciphertext.chunks(BLOCK_LEN + AEAD_TAG_LEN).peekable();
+		let mut plaintext = Vec::with_capacity(ciphertext.len());
+
+		if blocks.peek().is_none() {
+			return Err(Error::Decrypt);
+		}
+
+		while let Some(block) = blocks.next() {
+			let payload = Payload { aad, msg: block };
+
+			if blocks.peek().is_some() {
+				plaintext.extend_from_slice(&decryptor.decrypt_next(payload)?);
+			} else {
+				plaintext.extend_from_slice(&decryptor.decrypt_last(payload)?);
+				break;
+			}
+		}
+
+		Ok(plaintext)
+	}
+
+	/// This authenticates a single segment without allocating its plaintext, where `first_block` is the index of its first block within all of the segments.
+	fn verify_segment(
+		key: Key,
+		nonce: Nonce,
+		algorithm: Algorithm,
+		ciphertext: &[u8],
+		aad: &[u8],
+		first_block: u64,
+	) -> Result<()> {
+		let mut decryptor = Self::new(key, nonce, algorithm)?;
+		let mut blocks = ciphertext.chunks(BLOCK_LEN + AEAD_TAG_LEN).peekable();
+		let mut buffer = Vec::with_capacity(BLOCK_LEN + AEAD_TAG_LEN);
+
+		if blocks.peek().is_none() {
+			return Err(Error::Decrypt);
+		}
+
+		let mut index = first_block;
+		while let Some(block) = blocks.next() {
+			buffer.extend_from_slice(block);
+
+			if blocks.peek().is_some() {
+				let result = decryptor.verify_next(aad, &mut buffer);
+				buffer.zeroize();
+				result.map_err(|_| Error::AuthenticationFailed { block: Some(index) })?;
+			} else {
+				let result = decryptor.verify_last(aad, &mut buffer);
+				buffer.zeroize();
+				return result.map_err(|_| Error::AuthenticationFailed { block: Some(index) });
+			}
+
+			index += 1;
+		}
+
+		Ok(())
+	}
+
+	/// This decrypts data that was encrypted with `Encryptor::encrypt_segments()`, and the segments are decrypted in parallel.
+	///
+	/// Each segment is authenticated before it's written, but an error may still be returned for a later segment.
+	/// If that happens, everything that has been written should be discarded.
+	///
+	/// `on_progress` is called after every segment has been written, with the total amount of bytes read from the reader so far.
+	#[allow(clippy::too_many_arguments)]
+	pub async fn decrypt_segments<R, W, F>(
+		master_key: Key,
+		nonce: Nonce,
+		algorithm: Algorithm,
+		segment_size: usize,
+		reader: R,
+		writer: W,
+		aad: &[u8],
+		on_progress: F,
+	) -> Result<()>
+	where
+		R: AsyncReadExt + Unpin + Send,
+		W: AsyncWriteExt + Unpin + Send,
+		F: FnMut(u64) + Send,
+	{
+		if !is_valid_segment_size(segment_size) {
+			return Err(Error::InvalidSegmentSize);
+		}
+
+		if nonce.len() != algorithm.nonce_len() {
+			return Err(Error::NonceLengthMismatch);
+		}
+
+		let aad = aad.to_vec();
+
+		process_segments(
+			reader,
+			writer,
+			encrypted_segment_size(segment_size),
+			move |index, last, segment| {
+				Self::decrypt_segment(
+					segment_key(&master_key, index, last),
+					nonce,
+					algorithm,
+					segment,
+					&aad,
+				)
+			},
+			on_progress,
+		)
+		.await
+	}
+
+	/// This is the same as `Decryptor::verify_streams()`, but for data that was encrypted with `Encryptor::encrypt_segments()`.
+	///
+	/// The segments are still authenticated in parallel, and block indexes count from the start of the first segment.
+	pub async fn verify_segments<R>(
+		master_key: Key,
+		nonce: Nonce,
+		algorithm: Algorithm,
+		segment_size: usize,
+		reader: R,
+		aad: &[u8],
+	) -> Result<()>
+	where
+		R: AsyncReadExt + Unpin + Send,
+	{
+		if !is_valid_segment_size(segment_size) {
+			return Err(Error::InvalidSegmentSize);
+		}
+
+		if nonce.len() != algorithm.nonce_len() {
+			return Err(Error::NonceLengthMismatch);
+		}
+
+		let aad = aad.to_vec();
+		let blocks_per_segment = (segment_size / BLOCK_LEN) as u64;
+
+		// nothing is returned for each segment, so nothing is written to the sink
+		process_segments(
+			reader,
+			sink(),
+			encrypted_segment_size(segment_size),
+			move |index, last, segment| {
+				Self::verify_segment(
+					segment_key(&master_key, index, last),
+					nonce,
+					algorithm,
+					segment,
+					&aad,
+					index * blocks_per_segment,
+				)
+				.map(|()| Vec::new())
+			},
+			|_| (),
+		)
+		.await
+	}
+}
+
+/// This decrypts each segment and immediately encrypts it again with a new master key, so the segments can still be processed in parallel.
+///
+/// The segment boundaries are kept the same, as the segment size is part of the header.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn reencrypt_segments<R, W>(
+	master_key: Key,
+	new_master_key: Key,
+	nonce: Nonce,
+	new_nonce: Nonce,
+	algorithm: Algorithm,
+	segment_size: usize,
+	reader: R,
+	writer: W,
+	aad: &[u8],
+	new_aad: &[u8],
+) -> Result<()>
+where
+	R: AsyncReadExt + Unpin + Send,
+	W: AsyncWriteExt + Unpin + Send,
+{
+	if !is_valid_segment_size(segment_size) {
+		return Err(Error::InvalidSegmentSize);
+	}
+
+	let (aad, new_aad) = (aad.to_vec(), new_aad.to_vec());
+
+	process_segments(
+		reader,
+		writer,
+		encrypted_segment_size(segment_size),
+		move |index, last, segment| {
+			let mut plaintext = Decryptor::decrypt_segment(
+				segment_key(&master_key, index, last),
+				nonce,
+				algorithm,
+				segment,
+				&aad,
+			)?;
+
+			let ciphertext = Encryptor::encrypt_segment(
+				segment_key(&new_master_key, index, last),
+				new_nonce,
+				algorithm,
+				&plaintext,
+				&new_aad,
+			);
+			plaintext.zeroize();
+
+			ciphertext
+		},
+		|_| (),
+	)
+	.await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const ALGORITHM: Algorithm = Algorithm::XChaCha20Poly1305;
+	const SEGMENT_SIZE: usize = BLOCK_LEN * 2;
+
+	async fn encrypt(master_key: &Key, nonce: Nonce, plaintext: &[u8]) -> Vec<u8> {
+		let mut ciphertext = Vec::new();
+		Encryptor::encrypt_segments(
+			master_key.clone(),
+			nonce,
+			ALGORITHM,
+			SEGMENT_SIZE,
+			plaintext,
+			&mut ciphertext,
+			&[],
+			|_| (),
+		)
+		.await
+		.unwrap();
+		ciphertext
+	}
+
+	async fn decrypt(master_key: &Key, nonce: Nonce, ciphertext: &[u8]) -> Result<Vec<u8>> {
+		let mut plaintext = Vec::new();
+		Decryptor::decrypt_segments(
+			master_key.clone(),
+			nonce,
+			ALGORITHM,
+			SEGMENT_SIZE,
+			ciphertext,
+			&mut plaintext,
+			&[],
+			|_| (),
+		)
+		.await
+		.map(|()| plaintext)
+	}
+
+	#[tokio::test]
+	#[allow(clippy::cast_possible_truncation)]
+	async fn encrypt_and_decrypt_segments() {
+		let master_key = Key::generate();
+		let nonce = Nonce::generate(ALGORITHM).unwrap();
+
+		// these cover empty data, partial segments, and data that's an exact multiple of the segment size
+		for len in [
+			0,
+			1,
+			BLOCK_LEN,
+			SEGMENT_SIZE,
+			SEGMENT_SIZE + 1,
+			SEGMENT_SIZE * 3,
+			SEGMENT_SIZE * 3 + BLOCK_LEN + 7,
+		] {
+			let plaintext = (0..len).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+			let ciphertext = encrypt(&master_key, nonce, &plaintext).await;
+
+			assert_eq!(
+				decrypt(&master_key, nonce, &ciphertext).await.unwrap(),
+				plaintext,
+				"{len}"
+			);
+		}
+	}
+
+	#[tokio::test]
+	async fn decrypt_truncated_segments() {
+		let master_key = Key::generate();
+		let nonce = Nonce::generate(ALGORITHM).unwrap();
+
+		let plaintext = vec![0x5A; SEGMENT_SIZE * 3];
+		let ciphertext = encrypt(&master_key, nonce, &plaintext).await;
+
+		// removing an entire segment from the end still leaves valid segments, but the new final segment wasn't encrypted as the last one
+		let truncated = &ciphertext[..encrypted_segment_size(SEGMENT_SIZE) * 2];
+		assert!(matches!(
+			decrypt(&master_key, nonce, truncated).await,
+			Err(Error::AuthenticationFailed { block: None })
+		));
+
+		// segments can't be reordered either, as each one has its own key
+		let size = encrypted_segment_size(SEGMENT_SIZE);
+		let mut reordered = ciphertext[size..size * 2].to_vec();
+		reordered.extend_from_slice(&ciphertext[..size]);
+		reordered.extend_from_slice(&ciphertext[size * 2..]);
+		assert!(matches!(
+			decrypt(&master_key, nonce, &reordered).await,
+			Err(Error::AuthenticationFailed { block: None })
+		));
+	}
+
+	#[tokio::test]
+	async fn invalid_segment_size() {
+		let mut ciphertext = Vec::new();
+
+		assert!(matches!(
+			Encryptor::encrypt_segments(
+				Key::generate(),
+				Nonce::generate(ALGORITHM).unwrap(),
+				ALGORITHM,
+				BLOCK_LEN + 1,
+				[0u8; 16].as_ref(),
+				&mut ciphertext,
+				&[],
+				|_| (),
+			)
+			.await,
+			Err(Error::InvalidSegmentSize)
+		));
+	}
+}
@@ -12,6 +12,7 @@ use aead::{
 use aes_gcm::Aes256Gcm;
 use chacha20poly1305::XChaCha20Poly1305;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use zeroize::Zeroize;
 
 use super::exhaustive_read;
 
@@ -22,8 +23,14 @@ macro_rules! impl_stream {
 	$next_fn:ident, // "encrypt_next"
 	$last_fn:ident, // "encrypt_last"
 	$stream_primitive:ident, // "DecryptorLE31"
+	$stream_fn:ident, // "encrypt_stream"
+	$total_stream_fn:ident, // "encrypt_stream_with_total"
 	$streams_fn:ident, // "encrypt_streams"
+	$offloaded_stream_fn:ident, // "encrypt_stream_offloaded"
+	$offloaded_streams_fn:ident, // "encrypt_streams_offloaded"
 	$bytes_fn:ident, // "encrypt_bytes"
+	$sync_fn:ident, // "encrypt"
+	$async_fn:ident, // "encrypt_async"
 	$bytes_return:ty,
 	$size:expr,
 	$($algorithm:tt),*
@@ -53,7 +60,10 @@ macro_rules! impl_stream {
 				Ok(s)
 			}
 
-			fn $next_fn<'msg, 'aad>(
+			/// This encrypts/decrypts a single block of a stream. The blocks may be any size, but they must be processed in the order they were created in.
+			///
+			/// The final block of the stream must be processed with the associated `last` function instead, so that truncation can be detected.
+			pub fn $next_fn<'msg, 'aad>(
 				&mut self,
 				payload: impl Into<Payload<'msg, 'aad>>,
 			) -> Result<Vec<u8>> {
@@ -65,7 +75,8 @@ macro_rules! impl_stream {
 				.map_err(|_| $error)
 			}
 
-			fn $last_fn<'msg, 'aad>(self, payload: impl Into<Payload<'msg, 'aad>>) -> Result<Vec<u8>> {
+			/// This encrypts/decrypts the final block of a stream, and consumes the stream object.
+			pub fn $last_fn<'msg, 'aad>(self, payload: impl Into<Payload<'msg, 'aad>>) -> Result<Vec<u8>> {
 				match self {
 					$(
 						Self::$algorithm(s) => s.$last_fn(payload),
@@ -82,19 +93,42 @@ macro_rules! impl_stream {
 			///
 			/// The AAD will be authenticated with every block of data.
 			pub async fn $streams_fn<R, W>(
+				self,
+				reader: R,
+				writer: W,
+				aad: &[u8],
+			) -> Result<()>
+			where
+				R: AsyncReadExt + Unpin + Send,
+				W: AsyncWriteExt + Unpin + Send,
+			{
+				self.$stream_fn(reader, writer, aad, |_| ()).await
+			}
+
+			/// This is the same as the associated `encrypt/decrypt_streams` function, but it also reports progress.
+			///
+			/// `on_progress` is called after every block has been written, with the total amount of bytes read from the reader so far.
+			///
+			/// Each block is authenticated before it is written, but an error may still be returned for a later block.
+			/// If that happens, everything that has been written should be discarded.
+			pub async fn $stream_fn<R, W, F>(
 				mut self,
 				mut reader: R,
 				mut writer: W,
 				aad: &[u8],
+				mut on_progress: F,
 			) -> Result<()>
 			where
 				R: AsyncReadExt + Unpin + Send,
 				W: AsyncWriteExt + Unpin + Send,
+				F: FnMut(u64) + Send,
 			{
 				let mut buffer = vec![0u8; $size].into_boxed_slice();
+				let mut processed = 0u64;
 
 				loop {
 					let count = exhaustive_read(&mut reader, &mut buffer).await?;
+					processed += count as u64;
 
 					let payload = Payload {
 						aad,
@@ -104,9 +138,11 @@ macro_rules! impl_stream {
 					if count == $size {
 						let d = self.$next_fn(payload)?;
 						writer.write_all(&d).await?;
+						on_progress(processed);
 					} else {
 						let d = self.$last_fn(payload)?;
 						writer.write_all(&d).await?;
+						on_progress(processed);
 						break;
 					}
 				}
@@ -116,6 +152,101 @@ macro_rules! impl_stream {
 				Ok(())
 			}
 
+			/// This is the same as the associated `encrypt/decrypt_stream` function, but `on_progress` is also called with `total`.
+			///
+			/// `total` should be the length of the reader (e.g. the size of the file), so that a progress bar can be shown. It is only used for reporting progress.
+			pub async fn $total_stream_fn<R, W, F>(
+				self,
+				reader: R,
+				writer: W,
+				aad: &[u8],
+				total: u64,
+				mut on_progress: F,
+			) -> Result<()>
+			where
+				R: AsyncReadExt + Unpin + Send,
+				W: AsyncWriteExt + Unpin + Send,
+				F: FnMut(u64, u64) + Send,
+			{
+				self.$stream_fn(reader, writer, aad, |processed| on_progress(processed, total))
+					.await
+			}
+
+			/// This is the same as the associated `encrypt/decrypt_streams` function, but every block is processed on tokio's blocking thread pool.
+			///
+			/// Encrypting/decrypting a large amount of data is CPU-heavy, so this should be preferred from async tasks to avoid stalling the runtime.
+			///
+			/// The output is identical to the associated `encrypt/decrypt_streams` function.
+			pub async fn $offloaded_streams_fn<R, W>(
+				self,
+				reader: R,
+				writer: W,
+				aad: &[u8],
+			) -> Result<()>
+			where
+				R: AsyncReadExt + Unpin + Send,
+				W: AsyncWriteExt + Unpin + Send,
+			{
+				self.$offloaded_stream_fn(reader, writer, aad, |_| ()).await
+			}
+
+			/// This is the same as the associated `encrypt/decrypt_stream` function, but every block is processed on tokio's blocking thread pool.
+			///
+			/// `on_progress` is called after every block has been written, with the total amount of bytes read from the reader so far.
+			pub async fn $offloaded_stream_fn<R, W, F>(
+				self,
+				mut reader: R,
+				mut writer: W,
+				aad: &[u8],
+				mut on_progress: F,
+			) -> Result<()>
+			where
+				R: AsyncReadExt + Unpin + Send,
+				W: AsyncWriteExt + Unpin + Send,
+				F: FnMut(u64) + Send,
+			{
+				let mut stream = self;
+				let mut buffer = vec![0u8; $size].into_boxed_slice();
+				let mut aad = aad.to_vec();
+				let mut processed = 0u64;
+
+				loop {
+					let count = exhaustive_read(&mut reader, &mut buffer).await?;
+					processed += count as u64;
+
+					// the stream object and buffers are moved to the blocking thread and back, so nothing is reallocated per block
+					let (next, d, b, a) = tokio::task::spawn_blocking(move || {
+						let payload = Payload {
+							aad: &aad,
+							msg: &buffer[..count],
+						};
+
+						if count == $size {
+							let d = stream.$next_fn(payload);
+							(Some(stream), d, buffer, aad)
+						} else {
+							(None, stream.$last_fn(payload), buffer, aad)
+						}
+					})
+					.await
+					.map_err(std::io::Error::from)?;
+
+					writer.write_all(&d?).await?;
+					on_progress(processed);
+
+					(buffer, aad) = (b, a);
+
+					match next {
+						Some(next) => stream = next,
+						None => break,
+					}
+				}
+
+				writer.flush().await?;
+
+				Ok(())
+			}
+
 			/// This should ideally only be used for small amounts of data.
 			///
 			/// It is just a thin wrapper around the associated `encrypt/decrypt_streams` function.
@@ -136,6 +267,52 @@ macro_rules! impl_stream {
 					.map_or_else(Err, |_| Ok(writer.into_inner().into()))
 			}
 
+			/// This encrypts/decrypts bytes synchronously, so it shouldn't be called from async tasks (please use the associated `encrypt/decrypt_async` function instead).
+			///
+			/// The output is identical to the associated `encrypt/decrypt_bytes` function.
+			#[allow(clippy::needless_pass_by_value)]
+			pub fn $sync_fn(
+				key: Key,
+				nonce: Nonce,
+				algorithm: Algorithm,
+				bytes: &[u8],
+				aad: &[u8],
+			) -> Result<$bytes_return> {
+				let mut s = Self::new(key, nonce, algorithm)?;
+				let mut output = Vec::new();
+
+				// every full block is processed with `next`, so the final block is empty if the length is an exact multiple of the block size
+				let mut blocks = bytes.chunks_exact($size);
+				for block in &mut blocks {
+					output.extend(s.$next_fn(Payload { aad, msg: block })?);
+				}
+
+				output.extend(s.$last_fn(Payload {
+					aad,
+					msg: blocks.remainder(),
+				})?);
+
+				Ok(output.into())
+			}
+
+			/// This is the same as the associated `encrypt/decrypt` function, but the bytes are processed on tokio's blocking thread pool.
+			///
+			/// This should be preferred over the associated `encrypt/decrypt_bytes` function from async tasks when there may be a large amount of data, as it won't stall the runtime.
+			///
+			/// Any error is returned unchanged.
+			pub async fn $async_fn(
+				key: Key,
+				nonce: Nonce,
+				algorithm: Algorithm,
+				bytes: Vec<u8>,
+				aad: &[u8],
+			) -> Result<$bytes_return> {
+				let aad = aad.to_vec();
+
+				tokio::task::spawn_blocking(move || Self::$sync_fn(key, nonce, algorithm, &bytes, &aad))
+					.await
+					.map_err(std::io::Error::from)?
+			}
 		}
 	};
 }
@@ -146,8 +323,14 @@ impl_stream!(
 	encrypt_next,
 	encrypt_last,
 	EncryptorLE31,
+	encrypt_stream,
+	encrypt_stream_with_total,
 	encrypt_streams,
+	encrypt_stream_offloaded,
+	encrypt_streams_offloaded,
 	encrypt_bytes,
+	encrypt,
+	encrypt_async,
 	Vec<u8>,
 	BLOCK_LEN,
 	XChaCha20Poly1305,
@@ -156,14 +339,73 @@ impl_stream!(
 
 impl_stream!(
 	Decryptor,
-	Error::Decrypt,
+	Error::AuthenticationFailed { block: None },
 	decrypt_next,
 	decrypt_last,
 	DecryptorLE31,
+	decrypt_stream,
+	decrypt_stream_with_total,
 	decrypt_streams,
+	decrypt_stream_offloaded,
+	decrypt_streams_offloaded,
 	decrypt_bytes,
+	decrypt,
+	decrypt_async,
 	Protected<Vec<u8>>,
 	(BLOCK_LEN + AEAD_TAG_LEN),
 	XChaCha20Poly1305,
 	Aes256Gcm
 );
+
+impl Decryptor {
+	/// This authenticates a single block of a stream by decrypting it in place, so its plaintext is never allocated.
+	///
+	/// The final block of the stream must be authenticated with `verify_last()` instead.
+	pub(super) fn verify_next(&mut self, aad: &[u8], block: &mut Vec<u8>) -> Result<()> {
+		match self {
+			Self::XChaCha20Poly1305(s) => s.decrypt_next_in_place(aad, block),
+			Self::Aes256Gcm(s) => s.decrypt_next_in_place(aad, block),
+		}
+		.map_err(|_| Error::AuthenticationFailed { block: None })
+	}
+
+	/// This authenticates the final block of a stream in place, and consumes the stream object.
+	pub(super) fn verify_last(self, aad: &[u8], block: &mut Vec<u8>) -> Result<()> {
+		match self {
+			Self::XChaCha20Poly1305(s) => s.decrypt_last_in_place(aad, block),
+			Self::Aes256Gcm(s) => s.decrypt_last_in_place(aad, block),
+		}
+		.map_err(|_| Error::AuthenticationFailed { block: None })
+	}
+
+	/// This checks that all of the data that was encrypted with `Encryptor::encrypt_streams()` (or any of the associated functions) authenticates, without writing any of the plaintext.
+	///
+	/// Each block is decrypted in place and then zeroized, so this is suitable for checking that encrypted files haven't been altered (e.g. by a background scrub).
+	///
+	/// `Error::AuthenticationFailed` is returned with the index of the first block that fails authentication.
+	pub async fn verify_streams<R>(mut self, mut reader: R, aad: &[u8]) -> Result<()>
+	where
+		R: AsyncReadExt + Unpin + Send,
+	{
+		let mut buffer = vec![0u8; BLOCK_LEN + AEAD_TAG_LEN].into_boxed_slice();
+		let mut block = Vec::with_capacity(BLOCK_LEN + AEAD_TAG_LEN);
+		let mut index = 0u64;
+
+		loop {
+			let count = exhaustive_read(&mut reader, &mut buffer).await?;
+			block.extend_from_slice(&buffer[..count]);
+
+			if count == buffer.len() {
+				let result = self.verify_next(aad, &mut block);
+				block.zeroize();
+				result.map_err(|_| Error::AuthenticationFailed { block: Some(index) })?;
+			} else {
+				let result = self.verify_last(aad, &mut block);
+				block.zeroize();
+				return result.map_err(|_| Error::AuthenticationFailed { block: Some(index) });
+			}
+
+			index += 1;
+		}
+	}
+}
@@ -23,10 +23,18 @@ pub enum Error {
 	Encrypt,
 	#[error("error while decrypting")]
 	Decrypt,
+	#[error("authentication failed{}, so the data has been altered (or it wasn't encrypted with this key)", .block.map(|block| format!(" at block {block}")).unwrap_or_default())]
+	AuthenticationFailed { block: Option<u64> },
 	#[error("nonce length mismatch")]
 	NonceLengthMismatch,
+	#[error("a nonce was about to be reused with the same key")]
+	NonceReuse,
+	#[error("the encryption algorithm isn't supported by this build")]
+	UnsupportedAlgorithm,
 	#[error("error initialising stream encryption/decryption")]
 	StreamModeInit,
+	#[error("message authentication failed")]
+	InvalidMac,
 
 	// header errors
 	#[error("no keyslots available")]
@@ -35,8 +43,47 @@ pub enum Error {
 	NoPreviewMedia,
 	#[error("no metadata found")]
 	NoMetadata,
-	#[error("tried adding too many keyslots to a header")]
-	TooManyKeyslots,
+	#[error("tried adding too many keyslots to a header ({used} of {max} keyslots are in use)")]
+	TooManyKeyslots { used: usize, max: usize },
+	#[error("the keyslot index is out of range")]
+	KeyslotOutOfRange,
+	#[error("keyslot labels must be between 1 and 31 bytes, and require a V2 (or later) header")]
+	InvalidKeyslotLabel,
+	#[error("segment sizes must be a non-zero multiple of the block size (up to the maximum), and require a V3 (or later) header")]
+	InvalidSegmentSize,
+	#[error("AAD bindings require a V4 (or later) header")]
+	InvalidAadBinding,
+	#[error("the associated data doesn't match what the header was bound to")]
+	AadMismatch,
+	#[error("the header doesn't belong to this ciphertext")]
+	HeaderMismatch,
+	#[error("not a valid header, the file wasn't encrypted by Spacedrive")]
+	InvalidHeaderMagic,
+	#[error(
+		"the header version ({found:#06x}) isn't supported by this build (up to {supported:#06x})"
+	)]
+	UnsupportedHeaderVersion { found: u16, supported: u16 },
+	#[error("the header is damaged or has been tampered with")]
+	HeaderCorrupt,
+	#[error("the header is shorter than expected")]
+	HeaderTruncated,
+	#[error("no chunk manifest found")]
+	NoChunks,
+	#[error("chunk manifests require a V5 header")]
+	InvalidChunkManifest,
+	#[error("no object found with that name")]
+	NoObjects,
+	#[error("an object with that name already exists")]
+	DuplicateObjects,
+	#[error("tried adding too many objects to a header")]
+	TooManyObjects,
+	#[error("object names must be between 1 and 64 bytes, and objects require a V6 header")]
+	InvalidObject,
+	#[error("headers can only be migrated to the same (or a newer) version, and V5 headers can't be migrated")]
+	InvalidMigration,
+	#[cfg(feature = "chunk-dedup")]
+	#[error("a chunk wasn't found in the chunk store")]
+	ChunkNotFound,
 
 	// key manager
 	#[error("requested key wasn't found in the key manager")]
@@ -57,6 +104,14 @@ pub enum Error {
 	NoVerificationKey,
 	#[error("key isn't flagged as memory only")]
 	KeyNotMemoryOnly,
+	#[error("a rotation is already in progress for this key")]
+	KeyRotationInProgress,
+	#[error("key isn't being rotated")]
+	KeyNotRotating,
+	#[error("key labels must be between 1 and 64 characters")]
+	InvalidKeyLabel,
+	#[error("key descriptions can't be longer than 256 characters")]
+	InvalidKeyDescription,
 
 	// general errors
 	#[error("I/O error: {0}")]
@@ -67,6 +122,9 @@ pub enum Error {
 	IncorrectPassword,
 	#[error("error while serializing/deserializing an item")]
 	Serialization,
+	#[cfg(feature = "serde")]
+	#[error("error while serializing/deserializing JSON: {0}")]
+	Json(#[from] serde_json::Error),
 	#[error("string parse error")]
 	StringParse(#[from] FromUtf8Error),
 
@@ -77,10 +135,19 @@ pub enum Error {
 	#[cfg(all(any(target_os = "macos", target_os = "ios"), feature = "os-keyrings"))]
 	#[error("error with the apple keyring: {0}")]
 	AppleKeyringError(#[from] security_framework::base::Error),
+	#[cfg(all(target_os = "windows", feature = "os-keyrings"))]
+	#[error("error with the windows keyring: {0}")]
+	WindowsKeyringError(#[from] keyring::Error),
 	#[cfg(feature = "os-keyrings")]
 	#[error("generic keyring error")]
 	KeyringError,
 	#[cfg(feature = "os-keyrings")]
 	#[error("keyring not available on this platform")]
 	KeyringNotSupported,
+	#[cfg(feature = "hardware-keystore")]
+	#[error("error with the hardware token: {0}")]
+	HardwareTokenError(String),
+	#[cfg(feature = "hardware-keystore")]
+	#[error("error with the PKCS#11 token: {0}")]
+	Pkcs11Error(#[from] cryptoki::error::Error),
 }
@@ -3,6 +3,30 @@ use crate::{primitives::BLOCK_LEN, Result};
 use rand::{RngCore, SeedableRng};
 use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 
+#[cfg(feature = "secure-erase")]
+use std::path::Path;
+
+/// This is the data that a stream will be overwritten with while erasing.
+///
+/// `Random` is recommended, as the other patterns are easier to distinguish from the surrounding free space.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErasePattern {
+	#[default]
+	Random,
+	Zeroes,
+	Byte(u8),
+}
+
+impl ErasePattern {
+	fn fill(self, buf: &mut [u8]) {
+		match self {
+			Self::Random => rand_chacha::ChaCha20Rng::from_entropy().fill_bytes(buf),
+			Self::Zeroes => buf.fill(0),
+			Self::Byte(b) => buf.fill(b),
+		}
+	}
+}
+
 /// This is used for erasing a file.
 ///
 /// It requires the file size, a stream and the amount of passes (to overwrite the entire stream with random data)
@@ -15,6 +39,19 @@ use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 ///
 /// This also does not factor in temporary files, caching, thumbnails, etc.
 pub async fn erase<RW>(stream: &mut RW, size: usize, passes: usize) -> Result<()>
+where
+	RW: AsyncReadExt + AsyncWriteExt + AsyncSeekExt + Unpin + Send,
+{
+	erase_with_pattern(stream, size, passes, ErasePattern::Random).await
+}
+
+/// This is identical to `erase`, but it allows for overwriting the stream with a specific `ErasePattern`.
+pub async fn erase_with_pattern<RW>(
+	stream: &mut RW,
+	size: usize,
+	passes: usize,
+	pattern: ErasePattern,
+) -> Result<()>
 where
 	RW: AsyncReadExt + AsyncWriteExt + AsyncSeekExt + Unpin + Send,
 {
@@ -27,11 +64,11 @@ where
 	for _ in 0..passes {
 		stream.rewind().await?;
 		for _ in 0..block_count {
-			rand_chacha::ChaCha20Rng::from_entropy().fill_bytes(&mut buf);
+			pattern.fill(&mut buf);
 			stream.write_all(&buf).await?;
 		}
 
-		rand_chacha::ChaCha20Rng::from_entropy().fill_bytes(&mut end_buf);
+		pattern.fill(&mut end_buf);
 		stream.write_all(&end_buf).await?;
 		stream.flush().await?;
 	}
@@ -40,3 +77,230 @@ where
 
 	Ok(())
 }
+
+/// This is the result of `shred`, so that the caller knows whether or not the file's contents were actually overwritten.
+#[cfg(feature = "secure-erase")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ShredOutcome {
+	/// The file was overwritten and synced to disk before it was removed.
+	Overwritten,
+	/// Overwriting the file in-place wouldn't have been meaningful, so it was only removed.
+	///
+	/// The reason should be shown to the user as a warning.
+	Unlinked { reason: String },
+}
+
+/// This is used for securely deleting a file, such as the plaintext source after it has been encrypted.
+///
+/// The file is overwritten `passes` times with the provided pattern (syncing to disk after each pass), truncated and then removed.
+///
+/// If the file is on a platform or filesystem where overwriting in-place isn't meaningful (e.g. copy-on-write or network filesystems),
+/// the overwrite is skipped and the file is only removed. This is reported via `ShredOutcome::Unlinked` rather than giving false assurance.
+///
+/// The same caveats as `erase` apply - this is best-effort on flash-based storage devices.
+#[cfg(feature = "secure-erase")]
+pub async fn shred(path: &Path, passes: usize, pattern: ErasePattern) -> Result<ShredOutcome> {
+	if let Some(reason) = overwrite_unsupported_reason(path).await {
+		tokio::fs::remove_file(path).await?;
+		return Ok(ShredOutcome::Unlinked { reason });
+	}
+
+	let mut file = tokio::fs::OpenOptions::new()
+		.read(true)
+		.write(true)
+		.open(path)
+		.await?;
+
+	let size = usize::try_from(file.metadata().await?.len())
+		.map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+
+	// each pass is synced separately, otherwise earlier passes may never leave the page cache
+	for _ in 0..passes {
+		erase_with_pattern(&mut file, size, 1, pattern).await?;
+		file.sync_all().await?;
+	}
+
+	file.set_len(0).await?;
+	file.sync_all().await?;
+	drop(file);
+
+	tokio::fs::remove_file(path).await?;
+
+	Ok(ShredOutcome::Overwritten)
+}
+
+/// These filesystems don't overwrite data in-place (copy-on-write, log-structured, overlays) or the data lives on another machine.
+#[cfg(all(
+	feature = "secure-erase",
+	any(target_os = "linux", target_os = "android")
+))]
+const NON_OVERWRITING_FILESYSTEMS: [&str; 17] = [
+	"btrfs",
+	"zfs",
+	"bcachefs",
+	"f2fs",
+	"nilfs2",
+	"jffs2",
+	"ubifs",
+	"yaffs2",
+	"overlay",
+	"nfs",
+	"nfs4",
+	"cifs",
+	"smb3",
+	"smbfs",
+	"9p",
+	"fuse.sshfs",
+	"fuse.rclone",
+];
+
+/// This returns the reason why overwriting the file at `path` in-place isn't meaningful, if that's the case.
+#[cfg(all(
+	feature = "secure-erase",
+	any(target_os = "linux", target_os = "android")
+))]
+async fn overwrite_unsupported_reason(path: &Path) -> Option<String> {
+	let Ok(path) = tokio::fs::canonicalize(path).await else {
+		return Some("unable to determine the file's filesystem".to_string());
+	};
+
+	let Ok(mounts) = tokio::fs::read_to_string("/proc/mounts").await else {
+		return Some("unable to determine the file's filesystem".to_string());
+	};
+
+	// the mount with the longest matching mount point is the one that the file lives on
+	let fs_type = mounts
+		.lines()
+		.filter_map(|line| {
+			let mut parts = line.split_whitespace();
+			let mount_point = unescape_mount_point(parts.nth(1)?);
+			Some((mount_point, parts.next()?.to_string()))
+		})
+		.filter(|(mount_point, _)| path.starts_with(mount_point))
+		.max_by_key(|(mount_point, _)| mount_point.len())
+		.map(|(_, fs_type)| fs_type)?;
+
+	NON_OVERWRITING_FILESYSTEMS
+		.contains(&fs_type.as_str())
+		.then(|| format!("overwriting files in-place isn't meaningful on {fs_type} filesystems"))
+}
+
+/// `/proc/mounts` escapes whitespace and backslashes within mount points as octal (e.g. `\040`).
+#[cfg(all(
+	feature = "secure-erase",
+	any(target_os = "linux", target_os = "android")
+))]
+fn unescape_mount_point(mount_point: &str) -> String {
+	let mut unescaped = String::with_capacity(mount_point.len());
+	let mut chars = mount_point.chars();
+
+	while let Some(c) = chars.next() {
+		if c == '\\' {
+			let octal: String = chars.by_ref().take(3).collect();
+			match u8::from_str_radix(&octal, 8) {
+				Ok(b) => unescaped.push(char::from(b)),
+				Err(_) => {
+					unescaped.push(c);
+					unescaped.push_str(&octal);
+				}
+			}
+		} else {
+			unescaped.push(c);
+		}
+	}
+
+	unescaped
+}
+
+/// APFS is copy-on-write, so overwriting a file in-place writes the new data to different blocks.
+#[cfg(all(feature = "secure-erase", any(target_os = "macos", target_os = "ios")))]
+#[allow(clippy::unused_async)]
+async fn overwrite_unsupported_reason(_path: &Path) -> Option<String> {
+	Some("overwriting files in-place isn't meaningful on APFS".to_string())
+}
+
+#[cfg(all(feature = "secure-erase", target_os = "windows"))]
+#[allow(clippy::unused_async, clippy::unnecessary_wraps)]
+async fn overwrite_unsupported_reason(_path: &Path) -> Option<String> {
+	None
+}
+
+#[cfg(all(
+	feature = "secure-erase",
+	not(any(
+		target_os = "linux",
+		target_os = "android",
+		target_os = "macos",
+		target_os = "ios",
+		target_os = "windows"
+	))
+))]
+#[allow(clippy::unused_async)]
+async fn overwrite_unsupported_reason(_path: &Path) -> Option<String> {
+	Some("secure erasure isn't supported on this platform".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::Cursor;
+
+	#[tokio::test]
+	async fn erase_with_byte_pattern() {
+		let size = BLOCK_LEN * 2 + 7;
+		let mut stream = Cursor::new(vec![0x55u8; size]);
+
+		erase_with_pattern(&mut stream, size, 2, ErasePattern::Byte(0xAA))
+			.await
+			.unwrap();
+
+		assert_eq!(stream.position(), 0);
+		assert_eq!(stream.into_inner(), vec![0xAAu8; size]);
+	}
+
+	#[tokio::test]
+	async fn erase_random() {
+		let size = BLOCK_LEN + 1;
+		let mut stream = Cursor::new(vec![0u8; size]);
+
+		erase(&mut stream, size, 1).await.unwrap();
+
+		let data = stream.into_inner();
+		assert_eq!(data.len(), size);
+		assert_ne!(data, vec![0u8; size]);
+	}
+
+	#[cfg(feature = "secure-erase")]
+	#[tokio::test]
+	async fn shred_file() {
+		let path = std::env::temp_dir().join(format!("{}.plaintext", uuid::Uuid::new_v4()));
+		tokio::fs::write(&path, vec![1u8; BLOCK_LEN + 3])
+			.await
+			.unwrap();
+
+		shred(&path, 1, ErasePattern::Zeroes).await.unwrap();
+
+		assert!(!path.exists());
+	}
+
+	#[cfg(feature = "secure-erase")]
+	#[tokio::test]
+	async fn shred_missing_file() {
+		let path = std::env::temp_dir().join(format!("{}.plaintext", uuid::Uuid::new_v4()));
+
+		assert!(matches!(
+			shred(&path, 1, ErasePattern::Random).await,
+			Err(crate::Error::Io(_))
+		));
+	}
+
+	#[cfg(all(
+		feature = "secure-erase",
+		any(target_os = "linux", target_os = "android")
+	))]
+	#[test]
+	fn unescape_mount_points() {
+		assert_eq!(unescape_mount_point("/mnt/my\\040drive"), "/mnt/my drive");
+		assert_eq!(unescape_mount_point("/mnt/plain"), "/mnt/plain");
+	}
+}
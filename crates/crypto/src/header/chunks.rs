@@ -0,0 +1,578 @@
+//! This module contains the chunk manifest header item, which is used for deduplicated encryption (behind the `chunk-dedup` feature).
+//!
+//! With deduplicated encryption, the data is split into `CHUNK_LEN` chunks that are each encrypted on their own, and stored within a content-addressed `ChunkStore`.
+//! Each chunk is identified by a keyed hash of its plaintext, so a chunk that's already in the store doesn't need to be stored again.
+//! The header's chunk manifest lists the ID and key of every chunk (in order), and it's encrypted with the header's master key.
+//!
+//! Only V5 headers contain a chunk manifest, and no data follows them as their data lives within the chunk store.
+//!
+//! # Security
+//!
+//! This is convergent encryption, scoped to a `DedupKey`. Each chunk's key is derived from the dedup key and the chunk's plaintext, so anyone with the dedup key can:
+//!
+//! - confirm whether a chunk with known (or guessable) contents is in the store, even without the password for any file that contains it
+//! - see which files share chunks, if they can also decrypt the manifests
+//!
+//! The store itself only learns which chunks are reused (and how often), as chunk IDs can't be computed or reversed without the dedup key.
+//! Identical plaintext under different dedup keys results in unrelated chunk IDs and ciphertexts, so nothing is leaked across dedup keys.
+//!
+//! The dedup key should only be shared between files that belong to the same trust domain (e.g. a single user's backups), and
+//! deduplicated encryption shouldn't be used for low-entropy files where confirming their contents would be harmful.
+//!
+//! Chunks are fixed-size, so inserting data near the start of a file will change every chunk after it.
+use tokio::io::AsyncReadExt;
+
+#[cfg(feature = "chunk-dedup")]
+use std::fmt::Display;
+
+#[cfg(feature = "chunk-dedup")]
+use tokio::io::AsyncWriteExt;
+
+#[cfg(feature = "chunk-dedup")]
+use crate::{
+	crypto::{exhaustive_read, Decryptor, Encryptor},
+	primitives::{to_array, CHUNK_ID_CONTEXT, CHUNK_KEY_CONTEXT, CHUNK_LEN, KEY_LEN},
+	types::Key,
+	Error, Protected,
+};
+
+use crate::{
+	types::{Algorithm, Nonce},
+	Result,
+};
+
+#[cfg(feature = "chunk-dedup")]
+use super::file::{AadBinding, FileHeader, FileHeaderVersion};
+
+/// This is a chunk manifest header item, and it contains the encrypted list of chunk references.
+///
+/// It's created with `FileHeader::encrypt_chunks()`.
+#[derive(Clone)]
+pub struct ChunkManifest {
+	pub version: ChunkManifestVersion,
+	pub algorithm: Algorithm, // encryption algorithm
+	pub manifest_nonce: Nonce,
+	pub manifest: Vec<u8>,
+}
+
+#[derive(Clone, Copy)]
+pub enum ChunkManifestVersion {
+	V1,
+}
+
+/// This identifies an encrypted chunk within a `ChunkStore`.
+///
+/// It's a keyed hash of the chunk's plaintext (and the encryption algorithm), so it reveals nothing about the plaintext without the dedup key.
+#[cfg(feature = "chunk-dedup")]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct ChunkId(pub [u8; 32]);
+
+#[cfg(feature = "chunk-dedup")]
+impl Display for ChunkId {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}", hex::encode(self.0))
+	}
+}
+
+/// This is the key that chunk IDs and chunk keys are derived from. Chunks are only deduplicated between files that use the same dedup key.
+///
+/// Please see the module-level documentation for the security implications of sharing a dedup key.
+#[cfg(feature = "chunk-dedup")]
+#[derive(Clone)]
+pub struct DedupKey(Key);
+
+#[cfg(feature = "chunk-dedup")]
+impl DedupKey {
+	#[must_use]
+	pub const fn new(key: Key) -> Self {
+		Self(key)
+	}
+
+	#[must_use]
+	pub fn generate() -> Self {
+		Self(Key::generate())
+	}
+
+	/// This derives the ID and key of a chunk, from its plaintext.
+	fn derive(&self, algorithm: Algorithm, chunk: &[u8]) -> (ChunkId, Key) {
+		let keyed_hash = |context: &str| {
+			let mut key = blake3::derive_key(context, self.0.expose());
+			let hash = blake3::Hasher::new_keyed(&key)
+				.update(&algorithm.to_bytes())
+				.update(chunk)
+				.finalize();
+
+			zeroize::Zeroize::zeroize(&mut key);
+			*hash.as_bytes()
+		};
+
+		(
+			ChunkId(keyed_hash(CHUNK_ID_CONTEXT)),
+			Key::new(keyed_hash(CHUNK_KEY_CONTEXT)),
+		)
+	}
+}
+
+/// This is a content-addressed store for encrypted chunks.
+///
+/// Chunks are never modified once they've been stored, as an ID always refers to the same ciphertext.
+#[cfg(feature = "chunk-dedup")]
+pub trait ChunkStore: Send + Sync {
+	fn contains(&self, id: &ChunkId) -> Result<bool>;
+	fn put(&self, id: ChunkId, chunk: Vec<u8>) -> Result<()>;
+	fn get(&self, id: &ChunkId) -> Result<Option<Vec<u8>>>;
+}
+
+/// This is a single entry in the chunk manifest.
+#[cfg(feature = "chunk-dedup")]
+struct ChunkRef {
+	id: ChunkId,
+	key: Key,
+	len: u32,
+}
+
+#[cfg(feature = "chunk-dedup")]
+impl ChunkRef {
+	const SIZE: usize = 32 + KEY_LEN + 4;
+
+	fn to_bytes(refs: &[Self]) -> Protected<Vec<u8>> {
+		// this is allocated upfront, so no copies of the chunk keys are left behind by reallocations
+		let mut bytes = Vec::with_capacity(refs.len() * Self::SIZE);
+
+		for r in refs {
+			bytes.extend_from_slice(&r.id.0);
+			bytes.extend_from_slice(r.key.expose());
+			bytes.extend_from_slice(&r.len.to_le_bytes());
+		}
+
+		Protected::new(bytes)
+	}
+
+	fn from_bytes(bytes: &[u8]) -> Result<Vec<Self>> {
+		if bytes.len() % Self::SIZE != 0 {
+			return Err(Error::Serialization);
+		}
+
+		bytes
+			.chunks(Self::SIZE)
+			.map(|r| {
+				Ok(Self {
+					id: ChunkId(to_array(&r[..32])?),
+					key: Key::new(to_array(&r[32..32 + KEY_LEN])?),
+					len: u32::from_le_bytes(to_array(&r[32 + KEY_LEN..])?),
+				})
+			})
+			.collect()
+	}
+}
+
+/// Each chunk key only ever encrypts one plaintext, so a fixed nonce is safe (and it's required for the ciphertext to be deterministic).
+#[cfg(feature = "chunk-dedup")]
+fn chunk_nonce(algorithm: Algorithm) -> Result<Nonce> {
+	Nonce::try_from(vec![0u8; algorithm.nonce_len()])
+}
+
+#[cfg(feature = "chunk-dedup")]
+impl FileHeader {
+	/// This splits the data into chunks, encrypts and stores any that aren't already in the store, and records them in the header's chunk manifest.
+	///
+	/// The master key should be the one that was used for creating this header's keyslots, and it's used for encrypting the manifest.
+	///
+	/// This requires a V5 or V6 header that isn't bound to a context, and it returns the amount of chunks that were stored (the rest were already in the store).
+	pub async fn encrypt_chunks<R, S>(
+		&mut self,
+		master_key: Key,
+		dedup_key: &DedupKey,
+		mut reader: R,
+		store: &S,
+	) -> Result<usize>
+	where
+		R: AsyncReadExt + Unpin + Send,
+		S: ChunkStore + ?Sized,
+	{
+		if !matches!(self.version, FileHeaderVersion::V5 | FileHeaderVersion::V6) {
+			return Err(Error::InvalidChunkManifest);
+		}
+
+		if self.aad_binding != AadBinding::Header {
+			return Err(Error::AadMismatch);
+		}
+
+		let mut buffer = vec![0u8; CHUNK_LEN].into_boxed_slice();
+		let mut refs = Vec::new();
+		let mut stored = 0;
+
+		loop {
+			let count = exhaustive_read(&mut reader, &mut buffer).await?;
+			if count == 0 {
+				break;
+			}
+
+			let chunk = &buffer[..count];
+			let (id, key) = dedup_key.derive(self.algorithm, chunk);
+
+			if !store.contains(&id)? {
+				let encrypted = Encryptor::encrypt_async(
+					key.clone(),
+					chunk_nonce(self.algorithm)?,
+					self.algorithm,
+					chunk.to_vec(),
+					&id.0,
+				)
+				.await?;
+
+				store.put(id, encrypted)?;
+				stored += 1;
+			}
+
+			#[allow(clippy::cast_possible_truncation)]
+			let len = count as u32; // this can't be larger than `CHUNK_LEN`
+			refs.push(ChunkRef { id, key, len });
+
+			if count < CHUNK_LEN {
+				break;
+			}
+		}
+
+		let manifest_nonce = self.nonce_tracker()?.generate(self.algorithm)?;
+
+		let manifest = Encryptor::encrypt_bytes(
+			master_key,
+			manifest_nonce,
+			self.algorithm,
+			ChunkRef::to_bytes(&refs).expose(),
+			&self.generate_aad(),
+		)
+		.await?;
+
+		self.chunks = Some(ChunkManifest {
+			version: ChunkManifestVersion::V1,
+			algorithm: self.algorithm,
+			manifest_nonce,
+			manifest,
+		});
+
+		Ok(stored)
+	}
+
+	/// This reassembles the data from the chunks listed in the header's chunk manifest, and writes it to the writer.
+	///
+	/// `Error::ChunkNotFound` is returned if a chunk is missing from the store, and `Error::AuthenticationFailed` is returned if one has been altered.
+	///
+	/// If an error is returned, everything that has been written should be discarded.
+	pub async fn decrypt_chunks<W, S>(
+		&self,
+		master_key: Key,
+		mut writer: W,
+		store: &S,
+	) -> Result<()>
+	where
+		W: AsyncWriteExt + Unpin + Send,
+		S: ChunkStore + ?Sized,
+	{
+		let manifest = self.chunks.as_ref().ok_or(Error::NoChunks)?;
+
+		let refs = Decryptor::decrypt_bytes(
+			master_key,
+			manifest.manifest_nonce,
+			manifest.algorithm,
+			&manifest.manifest,
+			&self.generate_aad(),
+		)
+		.await?;
+
+		for chunk_ref in ChunkRef::from_bytes(refs.expose())? {
+			let encrypted = store.get(&chunk_ref.id)?.ok_or(Error::ChunkNotFound)?;
+
+			let chunk = Decryptor::decrypt_async(
+				chunk_ref.key,
+				chunk_nonce(manifest.algorithm)?,
+				manifest.algorithm,
+				encrypted,
+				&chunk_ref.id.0,
+			)
+			.await?;
+
+			if chunk.expose().len() != chunk_ref.len as usize {
+				return Err(Error::Decrypt);
+			}
+
+			writer.write_all(chunk.expose()).await?;
+		}
+
+		writer.flush().await?;
+
+		Ok(())
+	}
+}
+
+impl ChunkManifest {
+	#[must_use]
+	pub fn size(&self) -> usize {
+		self.to_bytes().len()
+	}
+
+	/// This function is used to serialize a chunk manifest into bytes
+	#[must_use]
+	pub fn to_bytes(&self) -> Vec<u8> {
+		match self.version {
+			ChunkManifestVersion::V1 => [
+				self.version.to_bytes().as_ref(),
+				self.algorithm.to_bytes().as_ref(),
+				&self.manifest_nonce,
+				&vec![0u8; 24 - self.manifest_nonce.len()],
+				&(self.manifest.len() as u64).to_le_bytes(),
+				&self.manifest,
+			]
+			.into_iter()
+			.flatten()
+			.copied()
+			.collect(),
+		}
+	}
+
+	/// This function reads a chunk manifest from a reader
+	///
+	/// The cursor will be left at the end of the chunk manifest on success
+	///
+	/// The cursor will not be rewound on error.
+	pub async fn from_reader<R>(reader: &mut R) -> Result<Self>
+	where
+		R: AsyncReadExt + Unpin + Send,
+	{
+		let mut version = [0u8; 2];
+		reader.read_exact(&mut version).await?;
+		let version = ChunkManifestVersion::from_bytes(version)?;
+
+		match version {
+			ChunkManifestVersion::V1 => {
+				let mut algorithm = [0u8; 2];
+				reader.read_exact(&mut algorithm).await?;
+				let algorithm = Algorithm::from_bytes(algorithm)?;
+
+				let mut manifest_nonce = vec![0u8; algorithm.nonce_len()];
+				reader.read_exact(&mut manifest_nonce).await?;
+				let manifest_nonce = Nonce::try_from(manifest_nonce)?;
+
+				reader
+					.read_exact(&mut vec![0u8; 24 - manifest_nonce.len()])
+					.await?;
+
+				let mut manifest_length = [0u8; 8];
+				reader.read_exact(&mut manifest_length).await?;
+
+				let manifest_length = u64::from_le_bytes(manifest_length);
+
+				#[allow(clippy::cast_possible_truncation)]
+				let mut manifest = vec![0u8; manifest_length as usize];
+				reader.read_exact(&mut manifest).await?;
+
+				Ok(Self {
+					version,
+					algorithm,
+					manifest_nonce,
+					manifest,
+				})
+			}
+		}
+	}
+}
+
+#[cfg(all(test, feature = "chunk-dedup"))]
+mod tests {
+	use std::{
+		collections::HashMap,
+		io::Cursor,
+		sync::{Mutex, PoisonError},
+	};
+
+	use rand::{RngCore, SeedableRng};
+	use rand_chacha::ChaCha20Rng;
+
+	use crate::{
+		header::keyslot::Keyslot,
+		primitives::LATEST_KEYSLOT,
+		types::{HashingAlgorithm, Params, Salt},
+	};
+
+	use super::*;
+
+	const ALGORITHM: Algorithm = Algorithm::XChaCha20Poly1305;
+	const HASHING_ALGORITHM: HashingAlgorithm = HashingAlgorithm::Argon2id(Params::Standard);
+
+	#[derive(Default)]
+	struct MemoryChunkStore(Mutex<HashMap<ChunkId, Vec<u8>>>);
+
+	impl MemoryChunkStore {
+		fn len(&self) -> usize {
+			self.0.lock().unwrap_or_else(PoisonError::into_inner).len()
+		}
+	}
+
+	impl ChunkStore for MemoryChunkStore {
+		fn contains(&self, id: &ChunkId) -> Result<bool> {
+			Ok(self
+				.0
+				.lock()
+				.unwrap_or_else(PoisonError::into_inner)
+				.contains_key(id))
+		}
+
+		fn put(&self, id: ChunkId, chunk: Vec<u8>) -> Result<()> {
+			self.0
+				.lock()
+				.unwrap_or_else(PoisonError::into_inner)
+				.insert(id, chunk);
+			Ok(())
+		}
+
+		fn get(&self, id: &ChunkId) -> Result<Option<Vec<u8>>> {
+			Ok(self
+				.0
+				.lock()
+				.unwrap_or_else(PoisonError::into_inner)
+				.get(id)
+				.cloned())
+		}
+	}
+
+	async fn chunked_header(mk: Key) -> FileHeader {
+		FileHeader::new(
+			FileHeaderVersion::V5,
+			ALGORITHM,
+			vec![Keyslot::new(
+				LATEST_KEYSLOT,
+				ALGORITHM,
+				HASHING_ALGORITHM,
+				Salt::generate(),
+				Key::generate(),
+				mk,
+			)
+			.await
+			.unwrap()],
+		)
+		.unwrap()
+	}
+
+	fn random_bytes(len: usize) -> Vec<u8> {
+		let mut buf = vec![0u8; len];
+		ChaCha20Rng::from_entropy().fill_bytes(&mut buf);
+		buf
+	}
+
+	#[tokio::test]
+	async fn encrypt_and_decrypt_chunks() {
+		let store = MemoryChunkStore::default();
+		let dedup_key = DedupKey::generate();
+		let mk = Key::generate();
+
+		let plaintext = random_bytes(CHUNK_LEN * 2 + 1024);
+
+		let mut header = chunked_header(mk.clone()).await;
+		let stored = header
+			.encrypt_chunks(mk.clone(), &dedup_key, plaintext.as_slice(), &store)
+			.await
+			.unwrap();
+		assert_eq!(stored, 3);
+
+		// the manifest is stored within the header
+		let bytes = header.to_bytes().unwrap();
+		let (header, _) = FileHeader::from_reader(&mut Cursor::new(bytes))
+			.await
+			.unwrap();
+
+		let mut output = Vec::new();
+		header
+			.decrypt_chunks(mk, &mut output, &store)
+			.await
+			.unwrap();
+
+		assert_eq!(output, plaintext);
+	}
+
+	#[tokio::test]
+	async fn deduplicate_chunks() {
+		let store = MemoryChunkStore::default();
+		let dedup_key = DedupKey::generate();
+
+		let shared = random_bytes(CHUNK_LEN);
+		let first = [shared.as_slice(), &random_bytes(CHUNK_LEN)].concat();
+		let second = [shared.as_slice(), &random_bytes(512)].concat();
+
+		let mk = Key::generate();
+		let mut header = chunked_header(mk.clone()).await;
+		header
+			.encrypt_chunks(mk, &dedup_key, first.as_slice(), &store)
+			.await
+			.unwrap();
+
+		// only the chunk that differs should be stored, even though the file has its own master key
+		let mk = Key::generate();
+		let mut header = chunked_header(mk.clone()).await;
+		let stored = header
+			.encrypt_chunks(mk.clone(), &dedup_key, second.as_slice(), &store)
+			.await
+			.unwrap();
+		assert_eq!(stored, 1);
+		assert_eq!(store.len(), 3);
+
+		let mut output = Vec::new();
+		header
+			.decrypt_chunks(mk, &mut output, &store)
+			.await
+			.unwrap();
+		assert_eq!(output, second);
+
+		// chunks aren't shared between dedup keys
+		let mk = Key::generate();
+		let mut header = chunked_header(mk.clone()).await;
+		let stored = header
+			.encrypt_chunks(mk, &DedupKey::generate(), second.as_slice(), &store)
+			.await
+			.unwrap();
+		assert_eq!(stored, 2);
+	}
+
+	#[tokio::test]
+	async fn decrypt_with_missing_chunk() {
+		let store = MemoryChunkStore::default();
+		let mk = Key::generate();
+
+		let mut header = chunked_header(mk.clone()).await;
+		header
+			.encrypt_chunks(
+				mk.clone(),
+				&DedupKey::generate(),
+				random_bytes(1024).as_slice(),
+				&store,
+			)
+			.await
+			.unwrap();
+
+		assert!(matches!(
+			header
+				.decrypt_chunks(mk, Vec::new(), &MemoryChunkStore::default())
+				.await,
+			Err(Error::ChunkNotFound)
+		));
+	}
+
+	#[tokio::test]
+	async fn encrypt_chunks_requires_v5_header() {
+		let mk = Key::generate();
+		let mut header = chunked_header(mk.clone()).await;
+		header.version = FileHeaderVersion::V4;
+
+		assert!(matches!(
+			header
+				.encrypt_chunks(
+					mk,
+					&DedupKey::generate(),
+					[0u8; 16].as_slice(),
+					&MemoryChunkStore::default()
+				)
+				.await,
+			Err(Error::InvalidChunkManifest)
+		));
+	}
+}
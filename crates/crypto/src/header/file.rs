@@ -29,18 +29,31 @@
 //! // Write the header to the file
 //! header.write(&mut writer).unwrap();
 //! ```
-use std::io::{Cursor, SeekFrom};
+//!
+//! Encrypting and decrypting data with a header is CPU-heavy, but it's always done on tokio's blocking thread pool so it's safe to call from async tasks.
+//! Decrypting a master key with a password requires hashing it, which is also done on the blocking thread pool.
+use std::{
+	io::{self, Cursor, SeekFrom},
+	path::Path,
+};
 
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::{
+	fs::File,
+	io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
 
 use crate::{
-	types::{Algorithm, Key, Nonce},
+	crypto::{is_valid_segment_size, Decryptor, Encryptor},
+	primitives::to_array,
+	types::{Algorithm, Key, Nonce, NonceTracker},
 	Error, Protected, Result,
 };
 
 use super::{
-	keyslot::{Keyslot, KEYSLOT_SIZE},
+	chunks::ChunkManifest,
+	keyslot::{Keyslot, KeyslotInfo, KEYSLOT_LABEL_SIZE, KEYSLOT_SIZE},
 	metadata::Metadata,
+	objects::HeaderObject,
 	preview_media::PreviewMedia,
 };
 
@@ -50,45 +63,93 @@ pub const MAGIC_BYTES: [u8; 7] = [0x62, 0x61, 0x6C, 0x6C, 0x61, 0x70, 0x70];
 
 /// This header is primarily used for encrypting/decrypting single files.
 ///
-/// It has support for 2 keyslots (maximum).
+/// It has support for 2 keyslots (maximum, see `FileHeader::MAX_KEYSLOTS`), and they're tried in order when decrypting the master key.
 ///
 /// You may optionally attach `Metadata` and `PreviewMedia` structs to this header, and they will be accessible on deserialization.
 ///
+/// V3 headers may also set a segment size, and the data will then be encrypted in independently-keyed segments that can be processed in parallel.
+///
+/// V4 headers may also bind the data to caller-provided context (such as the file's logical ID), which is then required for decryption.
+///
+/// V5 headers contain a chunk manifest instead of being followed by the data, as the data is deduplicated into a chunk store (please see the `chunks` module).
+///
+/// V6 headers may also contain objects, which are encrypted items that are addressed by name (please see the `objects` module), and they may contain a chunk manifest just like V5 headers.
+///
 /// This contains everything necessary for decryption, and the entire header can be flaunted with no worries (provided a suitable password was selected by the user).
 #[derive(Clone)]
 pub struct FileHeader {
 	pub version: FileHeaderVersion,
 	pub algorithm: Algorithm,
 	pub nonce: Nonce,
+	pub segment_size: Option<u32>, // `None` for serial encryption, this is part of the AAD
+	pub aad_binding: AadBinding,   // this is part of the AAD
 	pub keyslots: Vec<Keyslot>,
 	pub metadata: Option<Metadata>,
 	pub preview_media: Option<PreviewMedia>,
+	pub chunks: Option<ChunkManifest>, // this is required for V5 headers, optional for V6 headers, and unsupported by older versions
+	pub objects: Vec<HeaderObject>,    // this is only supported by V6 headers
 }
 
 /// This defines the main file header version.
+///
+/// V2 headers can store a label for each keyslot, directly after the keyslots.
+///
+/// V3 headers store the segment size (or zero for serial encryption) directly after the algorithm.
+///
+/// V4 headers store the AAD binding directly after the segment size.
+///
+/// V5 headers are identical to V4 headers, but they store a chunk manifest directly after the preview media (and no data follows them).
+///
+/// V6 headers are identical to V4 headers, but they store the objects and then the chunk manifest directly after the preview media.
+/// Each of these is prefixed with its length (which is zero if it's absent), and no data follows V6 headers that contain a chunk manifest.
 #[derive(Clone, Copy)]
 pub enum FileHeaderVersion {
 	V1,
+	V2,
+	V3,
+	V4,
+	V5,
+	V6,
+}
+
+/// This defines what the encrypted data is authenticated against, and it's recorded in V4 headers.
+///
+/// The data is always bound to the header's AAD. With `Context`, the caller-provided context is appended to the AAD,
+/// so the data can only be decrypted with `decrypt_with_aad()` and the same context that it was encrypted with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AadBinding {
+	Header,
+	Context,
 }
 
 impl FileHeader {
+	/// This is the maximum amount of keyslots that a header can hold, regardless of its version.
+	pub const MAX_KEYSLOTS: usize = 2;
+
 	/// This function is used for creating a file header.
 	pub fn new(
 		version: FileHeaderVersion,
 		algorithm: Algorithm,
 		keyslots: Vec<Keyslot>,
 	) -> Result<Self> {
-		if keyslots.len() > 2 {
-			return Err(Error::TooManyKeyslots);
+		if keyslots.len() > Self::MAX_KEYSLOTS {
+			return Err(Error::TooManyKeyslots {
+				used: keyslots.len(),
+				max: Self::MAX_KEYSLOTS,
+			});
 		}
 
 		let f = Self {
 			version,
 			algorithm,
 			nonce: Nonce::generate(algorithm)?,
+			segment_size: None,
+			aad_binding: AadBinding::Header,
 			keyslots,
 			metadata: None,
 			preview_media: None,
+			chunks: None,
+			objects: Vec::new(),
 		};
 
 		Ok(f)
@@ -100,8 +161,159 @@ impl FileHeader {
 	#[must_use]
 	pub const fn size(version: FileHeaderVersion) -> usize {
 		match version {
-			FileHeaderVersion::V1 => 36,
+			FileHeaderVersion::V1 | FileHeaderVersion::V2 => 36,
+			FileHeaderVersion::V3 => 40,
+			FileHeaderVersion::V4 | FileHeaderVersion::V5 | FileHeaderVersion::V6 => 42,
+		}
+	}
+
+	/// This is the size of the keyslot area that follows the AAD, including any empty keyslots and labels
+	const fn keyslots_size(version: FileHeaderVersion) -> usize {
+		match version {
+			FileHeaderVersion::V1 => KEYSLOT_SIZE * Self::MAX_KEYSLOTS,
+			FileHeaderVersion::V2
+			| FileHeaderVersion::V3
+			| FileHeaderVersion::V4
+			| FileHeaderVersion::V5
+			| FileHeaderVersion::V6 => (KEYSLOT_SIZE + KEYSLOT_LABEL_SIZE) * Self::MAX_KEYSLOTS,
+		}
+	}
+
+	/// This returns how many keyslots are in use, and the maximum amount that the header can hold (`(used, max)`).
+	///
+	/// This can be used to check whether another keyslot can be added before calling `add_keyslot()`.
+	#[must_use]
+	pub fn keyslot_capacity(&self) -> (usize, usize) {
+		(self.keyslots.len(), Self::MAX_KEYSLOTS)
+	}
+
+	/// This describes each keyslot in the order they're tried, without any of their key material.
+	///
+	/// No password is needed, so this can be used on a header that was just read with `from_reader()`.
+	#[must_use]
+	pub fn keyslot_info(&self) -> Vec<KeyslotInfo> {
+		self.keyslots
+			.iter()
+			.enumerate()
+			.map(|(i, keyslot)| keyslot.info(i))
+			.collect()
+	}
+
+	/// This adds a keyslot to the end of the header's keyslots.
+	///
+	/// You receive an error if the header is already at capacity, and it will contain the amount of keyslots in use.
+	pub fn add_keyslot(&mut self, keyslot: Keyslot) -> Result<()> {
+		let (used, max) = self.keyslot_capacity();
+		if used >= max {
+			return Err(Error::TooManyKeyslots { used, max });
+		}
+
+		if keyslot.label.is_some() && matches!(self.version, FileHeaderVersion::V1) {
+			return Err(Error::InvalidKeyslotLabel);
+		}
+
+		self.keyslots.push(keyslot);
+
+		Ok(())
+	}
+
+	/// This moves a keyslot to a new position, shifting the keyslots in between.
+	///
+	/// Keyslots are tried in order, so the most likely keyslot can be moved to the front. The contents of each keyslot are left untouched.
+	///
+	/// You receive an error if either index is out of range.
+	pub fn move_keyslot(&mut self, from: usize, to: usize) -> Result<()> {
+		if from >= self.keyslots.len() || to >= self.keyslots.len() {
+			return Err(Error::KeyslotOutOfRange);
+		}
+
+		let keyslot = self.keyslots.remove(from);
+		self.keyslots.insert(to, keyslot);
+
+		Ok(())
+	}
+
+	/// This sets (or clears) the label of the keyslot at the provided index.
+	///
+	/// Labels aren't encrypted, and they're only supported by V2+ headers.
+	pub fn set_keyslot_label(&mut self, index: usize, label: Option<String>) -> Result<()> {
+		if label.is_some() && matches!(self.version, FileHeaderVersion::V1) {
+			return Err(Error::InvalidKeyslotLabel);
+		}
+
+		let keyslot = self
+			.keyslots
+			.get_mut(index)
+			.ok_or(Error::KeyslotOutOfRange)?;
+
+		let previous = std::mem::replace(&mut keyslot.label, label);
+
+		if let Err(e) = keyslot.label_to_bytes() {
+			keyslot.label = previous;
+			return Err(e);
+		}
+
+		Ok(())
+	}
+
+	/// This enables (or disables) parallel encryption, by setting the size of each segment in bytes.
+	///
+	/// The segment size must be a multiple of `BLOCK_LEN`, and it's only supported by V3+ headers. This should be set before any data is encrypted.
+	pub fn set_segment_size(&mut self, segment_size: Option<u32>) -> Result<()> {
+		if let Some(segment_size) = segment_size {
+			if matches!(self.version, FileHeaderVersion::V1 | FileHeaderVersion::V2)
+				|| !is_valid_segment_size(segment_size as usize)
+			{
+				return Err(Error::InvalidSegmentSize);
+			}
+		}
+
+		self.segment_size = segment_size;
+
+		Ok(())
+	}
+
+	/// This sets what the data will be bound to, and it's only supported by V4+ headers. This should be set before any data is encrypted.
+	///
+	/// Data that's bound to a context must be encrypted with `encrypt_with_aad()`, and decrypted with `decrypt_with_aad()`.
+	pub fn set_aad_binding(&mut self, aad_binding: AadBinding) -> Result<()> {
+		if aad_binding != AadBinding::Header
+			&& matches!(
+				self.version,
+				FileHeaderVersion::V1 | FileHeaderVersion::V2 | FileHeaderVersion::V3
+			) {
+			return Err(Error::InvalidAadBinding);
+		}
+
+		self.aad_binding = aad_binding;
+
+		Ok(())
+	}
+
+	/// This returns a tracker containing every nonce that has been used with this header's master key (for the data, metadata, preview media, chunk manifest and objects).
+	///
+	/// It should be used for generating any new nonces that will be used with the master key.
+	pub fn nonce_tracker(&self) -> Result<NonceTracker> {
+		let mut tracker = NonceTracker::new();
+		tracker.track(self.nonce)?;
+
+		if let Some(metadata) = &self.metadata {
+			tracker.track(metadata.metadata_nonce)?;
+		}
+
+		if let Some(preview_media) = &self.preview_media {
+			tracker.track(preview_media.media_nonce)?;
+		}
+
+		if let Some(chunks) = &self.chunks {
+			tracker.track(chunks.manifest_nonce)?;
+		}
+
+		for object in &self.objects {
+			tracker.track(object.object_nonce)?;
 		}
+
+		Ok(tracker)
 	}
 
 	/// This is a helper function to decrypt a master key from keyslots that are attached to a header, from a user-supplied password.
@@ -156,6 +368,270 @@ impl FileHeader {
 		Ok(())
 	}
 
+	/// This writes the header to its own file, so it can be stored separately from the encrypted data.
+	///
+	/// The detached header is identical to one written with `write()`, so it can be reattached by prepending it to the encrypted data.
+	pub async fn write_detached_header<P>(&self, path: P) -> Result<()>
+	where
+		P: AsRef<Path> + Send,
+	{
+		let mut file = File::create(path).await?;
+		self.write(&mut file).await?;
+		file.flush().await?;
+		Ok(())
+	}
+
+	/// This reads a header that was written with `write_detached_header()`.
+	pub async fn read_detached_header<P>(path: P) -> Result<Self>
+	where
+		P: AsRef<Path> + Send,
+	{
+		let mut file = File::open(path).await?;
+		Self::from_reader(&mut file).await.map(|(header, _)| header)
+	}
+
+	/// This encrypts data without writing the header to the writer, so it can follow `write()` or be used with detached headers.
+	///
+	/// The master key should be the one that was used for creating this header's keyslots.
+	///
+	/// If a segment size has been set, the data is encrypted in parallel segments.
+	///
+	/// If the header is bound to a context, `encrypt_with_aad()` must be used instead and `Error::AadMismatch` is returned.
+	pub async fn encrypt_detached<R, W>(&self, master_key: Key, reader: R, writer: W) -> Result<()>
+	where
+		R: AsyncReadExt + Unpin + Send,
+		W: AsyncWriteExt + Unpin + Send,
+	{
+		if self.aad_binding != AadBinding::Header {
+			return Err(Error::AadMismatch);
+		}
+
+		self.encrypt_data(master_key, reader, writer, &self.generate_aad())
+			.await
+	}
+
+	/// This is identical to `encrypt_detached()`, but the data is also bound to the provided context (such as the file's logical ID).
+	///
+	/// The header must have been bound to a context with `set_aad_binding()`, otherwise `Error::AadMismatch` is returned.
+	pub async fn encrypt_with_aad<R, W>(
+		&self,
+		master_key: Key,
+		reader: R,
+		writer: W,
+		context: &[u8],
+	) -> Result<()>
+	where
+		R: AsyncReadExt + Unpin + Send,
+		W: AsyncWriteExt + Unpin + Send,
+	{
+		if self.aad_binding != AadBinding::Context {
+			return Err(Error::AadMismatch);
+		}
+
+		self.encrypt_data(
+			master_key,
+			reader,
+			writer,
+			&[self.generate_aad().as_slice(), context].concat(),
+		)
+		.await
+	}
+
+	async fn encrypt_data<R, W>(
+		&self,
+		master_key: Key,
+		reader: R,
+		writer: W,
+		aad: &[u8],
+	) -> Result<()>
+	where
+		R: AsyncReadExt + Unpin + Send,
+		W: AsyncWriteExt + Unpin + Send,
+	{
+		match self.segment_size {
+			Some(segment_size) => {
+				Encryptor::encrypt_segments(
+					master_key,
+					self.nonce,
+					self.algorithm,
+					segment_size as usize,
+					reader,
+					writer,
+					aad,
+					|_| (),
+				)
+				.await
+			}
+			None => {
+				Encryptor::new(master_key, self.nonce, self.algorithm)?
+					.encrypt_streams_offloaded(reader, writer, aad)
+					.await
+			}
+		}
+	}
+
+	/// This decrypts data that was encrypted with `encrypt_detached()`, using this header instead of reading one from the reader.
+	///
+	/// The header's nonce identifies the encrypted data, as it's used (along with the rest of the AAD) to authenticate every block.
+	/// If the first block can't be decrypted with a master key from this header, the header belongs to different encrypted data and `Error::HeaderMismatch` is returned.
+	pub async fn decrypt_detached<R, W>(&self, master_key: Key, reader: R, writer: W) -> Result<()>
+	where
+		R: AsyncReadExt + Unpin + Send,
+		W: AsyncWriteExt + Unpin + Send,
+	{
+		if self.aad_binding != AadBinding::Header {
+			return Err(Error::AadMismatch);
+		}
+
+		self.decrypt_data(
+			master_key,
+			reader,
+			writer,
+			&self.generate_aad(),
+			Error::HeaderMismatch,
+		)
+		.await
+	}
+
+	/// This decrypts the data that follows this header, and the reader should be left where `from_reader()` left it.
+	///
+	/// The AAD should be the one returned from `from_reader()`, as it contains the header bytes exactly as they were read.
+	/// If the first block can't be decrypted with a master key from this header, the authenticated header bytes have been altered and `Error::HeaderCorrupt` is returned.
+	pub async fn decrypt<R, W>(
+		&self,
+		master_key: Key,
+		reader: R,
+		writer: W,
+		aad: &[u8],
+	) -> Result<()>
+	where
+		R: AsyncReadExt + Unpin + Send,
+		W: AsyncWriteExt + Unpin + Send,
+	{
+		if self.aad_binding != AadBinding::Header {
+			return Err(Error::AadMismatch);
+		}
+
+		self.decrypt_data(master_key, reader, writer, aad, Error::HeaderCorrupt)
+			.await
+	}
+
+	/// This decrypts data that was encrypted with `encrypt_with_aad()`.
+	///
+	/// The AAD should be the one returned from `from_reader()` (or `generate_aad()` for detached headers), and the context must be the one the data was encrypted with.
+	/// If the first block can't be decrypted, the header, context and data don't belong together and `Error::AadMismatch` is returned.
+	pub async fn decrypt_with_aad<R, W>(
+		&self,
+		master_key: Key,
+		reader: R,
+		writer: W,
+		aad: &[u8],
+		context: &[u8],
+	) -> Result<()>
+	where
+		R: AsyncReadExt + Unpin + Send,
+		W: AsyncWriteExt + Unpin + Send,
+	{
+		if self.aad_binding != AadBinding::Context {
+			return Err(Error::AadMismatch);
+		}
+
+		self.decrypt_data(
+			master_key,
+			reader,
+			writer,
+			&[aad, context].concat(),
+			Error::AadMismatch,
+		)
+		.await
+	}
+
+	/// The master key was decrypted from this header, so a failure on the very first block means the header doesn't authenticate the data.
+	async fn decrypt_data<R, W>(
+		&self,
+		master_key: Key,
+		reader: R,
+		writer: W,
+		aad: &[u8],
+		header_error: Error,
+	) -> Result<()>
+	where
+		R: AsyncReadExt + Unpin + Send,
+		W: AsyncWriteExt + Unpin + Send,
+	{
+		let mut decrypted_any = false;
+		let on_progress = |_| {
+			decrypted_any = true;
+		};
+
+		let result = match self.segment_size {
+			Some(segment_size) => {
+				Decryptor::decrypt_segments(
+					master_key,
+					self.nonce,
+					self.algorithm,
+					segment_size as usize,
+					reader,
+					writer,
+					aad,
+					on_progress,
+				)
+				.await
+			}
+			None => {
+				Decryptor::new(master_key, self.nonce, self.algorithm)?
+					.decrypt_stream_offloaded(reader, writer, aad, on_progress)
+					.await
+			}
+		};
+
+		match result {
+			Err(Error::AuthenticationFailed { .. }) if !decrypted_any => Err(header_error),
+			result => result,
+		}
+	}
+
+	/// This checks that the data that follows this header hasn't been altered, without writing any of the plaintext. The reader should be left where `from_reader()` left it.
+	///
+	/// The AAD should be the one returned from `from_reader()`, and the context must be provided if (and only if) the data is bound to one. Otherwise, `Error::AadMismatch` is returned.
+	///
+	/// `Error::AuthenticationFailed` is returned with the index of the first block that fails authentication. If that's the very first block, the header may have been altered rather than the data.
+	pub async fn verify_integrity<R>(
+		&self,
+		master_key: Key,
+		reader: R,
+		aad: &[u8],
+		context: Option<&[u8]>,
+	) -> Result<()>
+	where
+		R: AsyncReadExt + Unpin + Send,
+	{
+		let aad = match (self.aad_binding, context) {
+			(AadBinding::Header, None) => aad.to_vec(),
+			(AadBinding::Context, Some(context)) => [aad, context].concat(),
+			_ => return Err(Error::AadMismatch),
+		};
+
+		match self.segment_size {
+			Some(segment_size) => {
+				Decryptor::verify_segments(
+					master_key,
+					self.nonce,
+					self.algorithm,
+					segment_size as usize,
+					reader,
+					&aad,
+				)
+				.await
+			}
+			None => {
+				Decryptor::new(master_key, self.nonce, self.algorithm)?
+					.verify_streams(reader, &aad)
+					.await
+			}
+		}
+	}
+
 	/// This is a helper function to find which keyslot a key belongs to.
 	///
 	/// You receive an error if the password doesn't match or if there are no keyslots.
@@ -180,10 +656,35 @@ impl FileHeader {
 	#[must_use]
 	pub fn generate_aad(&self) -> Vec<u8> {
 		match self.version {
-			FileHeaderVersion::V1 => [
+			FileHeaderVersion::V1 | FileHeaderVersion::V2 => [
+				MAGIC_BYTES.as_ref(),
+				&self.version.to_bytes(),
+				&self.algorithm.to_bytes(),
+				&self.nonce,
+				&vec![0u8; 25 - self.nonce.len()],
+			]
+			.into_iter()
+			.flatten()
+			.copied()
+			.collect(),
+			FileHeaderVersion::V3 => [
+				MAGIC_BYTES.as_ref(),
+				&self.version.to_bytes(),
+				&self.algorithm.to_bytes(),
+				&self.segment_size.unwrap_or_default().to_le_bytes(),
+				&self.nonce,
+				&vec![0u8; 25 - self.nonce.len()],
+			]
+			.into_iter()
+			.flatten()
+			.copied()
+			.collect(),
+			FileHeaderVersion::V4 | FileHeaderVersion::V5 | FileHeaderVersion::V6 => [
 				MAGIC_BYTES.as_ref(),
 				&self.version.to_bytes(),
 				&self.algorithm.to_bytes(),
+				&self.segment_size.unwrap_or_default().to_le_bytes(),
+				&self.aad_binding.to_bytes(),
 				&self.nonce,
 				&vec![0u8; 25 - self.nonce.len()],
 			]
@@ -196,14 +697,22 @@ impl FileHeader {
 
 	/// This function serializes a full header.
 	///
-	/// This will include keyslots, metadata and preview media (if provided)
+	/// This will include keyslots, metadata and preview media (if provided), the chunk manifest for V5 and V6 headers, and the objects for V6 headers.
 	///
-	/// An error will be returned if there are no keyslots/more than two keyslots attached.
+	/// An error will be returned if there are no keyslots/more than two keyslots attached, or if a V5 header has no chunk manifest.
 	pub fn to_bytes(&self) -> Result<Vec<u8>> {
 		match self.version {
-			FileHeaderVersion::V1 => {
-				if self.keyslots.len() > 2 {
-					return Err(Error::TooManyKeyslots);
+			FileHeaderVersion::V1
+			| FileHeaderVersion::V2
+			| FileHeaderVersion::V3
+			| FileHeaderVersion::V4
+			| FileHeaderVersion::V5
+			| FileHeaderVersion::V6 => {
+				if self.keyslots.len() > Self::MAX_KEYSLOTS {
+					return Err(Error::TooManyKeyslots {
+						used: self.keyslots.len(),
+						max: Self::MAX_KEYSLOTS,
+					});
 				} else if self.keyslots.is_empty() {
 					return Err(Error::NoKeyslots);
 				}
@@ -215,6 +724,58 @@ impl FileHeader {
 					keyslots.push(vec![0u8; KEYSLOT_SIZE]);
 				}
 
+				let labels = match self.version {
+					FileHeaderVersion::V1 => {
+						if self.keyslots.iter().any(|k| k.label.is_some()) {
+							return Err(Error::InvalidKeyslotLabel);
+						}
+
+						Vec::new()
+					}
+					FileHeaderVersion::V2
+					| FileHeaderVersion::V3
+					| FileHeaderVersion::V4
+					| FileHeaderVersion::V5
+					| FileHeaderVersion::V6 => {
+						let mut labels = self
+							.keyslots
+							.iter()
+							.map(Keyslot::label_to_bytes)
+							.collect::<Result<Vec<_>>>()?
+							.concat();
+
+						labels.resize(KEYSLOT_LABEL_SIZE * Self::MAX_KEYSLOTS, 0);
+						labels
+					}
+				};
+
+				let segment_size = match self.version {
+					FileHeaderVersion::V1 | FileHeaderVersion::V2 => {
+						if self.segment_size.is_some() {
+							return Err(Error::InvalidSegmentSize);
+						}
+
+						Vec::new()
+					}
+					FileHeaderVersion::V3
+					| FileHeaderVersion::V4
+					| FileHeaderVersion::V5
+					| FileHeaderVersion::V6 => self.segment_size.unwrap_or_default().to_le_bytes().to_vec(),
+				};
+
+				let aad_binding = match self.version {
+					FileHeaderVersion::V1 | FileHeaderVersion::V2 | FileHeaderVersion::V3 => {
+						if self.aad_binding != AadBinding::Header {
+							return Err(Error::InvalidAadBinding);
+						}
+
+						Vec::new()
+					}
+					FileHeaderVersion::V4 | FileHeaderVersion::V5 | FileHeaderVersion::V6 => {
+						self.aad_binding.to_bytes().to_vec()
+					}
+				};
+
 				let metadata = self
 					.metadata
 					.as_ref()
@@ -225,16 +786,39 @@ impl FileHeader {
 					.as_ref()
 					.map_or(Vec::new(), PreviewMedia::to_bytes);
 
+				let chunks = match (self.version, &self.chunks) {
+					(FileHeaderVersion::V5, Some(chunks)) => chunks.to_bytes(),
+					(FileHeaderVersion::V5, None) => return Err(Error::NoChunks),
+					(FileHeaderVersion::V6, chunks) => {
+						Self::section_to_bytes(chunks.as_ref().map(ChunkManifest::to_bytes))
+					}
+					(_, Some(_)) => return Err(Error::InvalidChunkManifest),
+					(_, None) => Vec::new(),
+				};
+
+				let objects = match self.version {
+					FileHeaderVersion::V6 => Self::section_to_bytes(
+						(!self.objects.is_empty()).then(|| self.objects_to_bytes()),
+					),
+					_ if self.objects.is_empty() => Vec::new(),
+					_ => return Err(Error::InvalidObject),
+				};
+
 				let header = [
 					MAGIC_BYTES.as_ref(),
 					&self.version.to_bytes(),
 					&self.algorithm.to_bytes(),
+					&segment_size,
+					&aad_binding,
 					&self.nonce,
 					&vec![0u8; 25 - self.nonce.len()],
 					&keyslots[0],
 					&keyslots[1],
+					&labels,
 					&metadata,
 					&preview_media,
+					&objects,
+					&chunks,
 				]
 				.into_iter()
 				.flatten()
@@ -246,22 +830,96 @@ impl FileHeader {
 		}
 	}
 
-	/// This deserializes a header directly from a reader, and leaves the reader at the start of the encrypted data.
+	/// This prefixes a section of a V6 header with its length, so an absent section is written as a zero length.
+	fn section_to_bytes(section: Option<Vec<u8>>) -> Vec<u8> {
+		let section = section.unwrap_or_default();
+
+		(section.len() as u64)
+			.to_le_bytes()
+			.into_iter()
+			.chain(section)
+			.collect()
+	}
+
+	/// This reads a section of a V6 header that was written with `section_to_bytes()`, and it returns `None` if the section is absent.
+	async fn section_from_reader<R>(reader: &mut R) -> Result<Option<Cursor<Vec<u8>>>>
+	where
+		R: AsyncReadExt + Unpin + Send,
+	{
+		let mut len = [0u8; 8];
+		reader.read_exact(&mut len).await?;
+		let len = u64::from_le_bytes(len);
+
+		if len == 0 {
+			return Ok(None);
+		}
+
+		// this is read as it arrives, so a corrupt length can't make us allocate more than the reader contains
+		let mut section = Vec::new();
+		reader.take(len).read_to_end(&mut section).await?;
+
+		if section.len() as u64 != len {
+			return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+		}
+
+		Ok(Some(Cursor::new(section)))
+	}
+
+	/// This checks that a section of a V6 header was read entirely, as anything left over means the length prefix doesn't match its contents.
+	fn ensure_section_read(section: &Cursor<Vec<u8>>) -> Result<()> {
+		if section.position() == section.get_ref().len() as u64 {
+			Ok(())
+		} else {
+			Err(Error::Serialization)
+		}
+	}
+
+	/// This deserializes a header directly from a reader, and leaves the reader at the start of the encrypted data (or the end of headers that contain a chunk manifest).
 	///
 	/// On error, the cursor will not be rewound.
 	///
 	/// It returns both the header, and the AAD that should be used for decryption.
+	///
+	/// If the reader doesn't start with the magic bytes, `Error::InvalidHeaderMagic` is returned as it isn't an encrypted file.
+	/// This is checked before anything else is read, so a file from a newer build returns `Error::UnsupportedHeaderVersion` rather than looking corrupt.
+	/// Otherwise, a header that ends early returns `Error::HeaderTruncated` and one with invalid bytes returns `Error::HeaderCorrupt`.
+	///
+	/// The header is returned as it was written, as the data is bound to its version. Please see `FileHeader::migrate_file()` for upgrading an older file.
 	pub async fn from_reader<R>(reader: &mut R) -> Result<(Self, Vec<u8>)>
 	where
 		R: AsyncReadExt + AsyncSeekExt + Unpin + Send,
 	{
 		let mut magic_bytes = [0u8; MAGIC_BYTES.len()];
-		reader.read_exact(&mut magic_bytes).await?;
+		reader
+			.read_exact(&mut magic_bytes)
+			.await
+			.map_err(|e| Self::classify_error(e.into()))?;
 
 		if magic_bytes != MAGIC_BYTES {
-			return Err(Error::Serialization);
+			return Err(Error::InvalidHeaderMagic);
+		}
+
+		Self::read_header(reader)
+			.await
+			.map_err(Self::classify_error)
+	}
+
+	/// This maps the errors from reading a header onto what they mean for the header, so callers can tell a damaged header apart from an unsupported one.
+	fn classify_error(error: Error) -> Error {
+		match error {
+			Error::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof => Error::HeaderTruncated,
+			Error::Serialization | Error::VecArrSizeMismatch | Error::NonceLengthMismatch => {
+				Error::HeaderCorrupt
+			}
+			e => e,
 		}
+	}
 
+	/// This reads everything after the magic bytes, and it's only called by `from_reader()`.
+	async fn read_header<R>(reader: &mut R) -> Result<(Self, Vec<u8>)>
+	where
+		R: AsyncReadExt + AsyncSeekExt + Unpin + Send,
+	{
 		let mut version = [0u8; 2];
 
 		reader.read_exact(&mut version).await?;
@@ -281,11 +939,47 @@ impl FileHeader {
 
 		// read the header
 		let header = match version {
-			FileHeaderVersion::V1 => {
+			FileHeaderVersion::V1
+			| FileHeaderVersion::V2
+			| FileHeaderVersion::V3
+			| FileHeaderVersion::V4
+			| FileHeaderVersion::V5
+			| FileHeaderVersion::V6 => {
 				let mut algorithm = [0u8; 2];
 				reader.read_exact(&mut algorithm).await?;
 				let algorithm = Algorithm::from_bytes(algorithm)?;
 
+				// zero means the data was encrypted serially
+				let segment_size = if matches!(
+					version,
+					FileHeaderVersion::V3
+						| FileHeaderVersion::V4
+						| FileHeaderVersion::V5
+						| FileHeaderVersion::V6
+				) {
+					let mut segment_size = [0u8; 4];
+					reader.read_exact(&mut segment_size).await?;
+
+					match u32::from_le_bytes(segment_size) {
+						0 => None,
+						s if is_valid_segment_size(s as usize) => Some(s),
+						_ => return Err(Error::HeaderCorrupt),
+					}
+				} else {
+					None
+				};
+
+				let aad_binding = if matches!(
+					version,
+					FileHeaderVersion::V4 | FileHeaderVersion::V5 | FileHeaderVersion::V6
+				) {
+					let mut aad_binding = [0u8; 2];
+					reader.read_exact(&mut aad_binding).await?;
+					AadBinding::from_bytes(aad_binding)?
+				} else {
+					AadBinding::Header
+				};
+
 				let mut nonce = vec![0u8; algorithm.nonce_len()];
 				reader.read_exact(&mut nonce).await?;
 				let nonce = Nonce::try_from(nonce)?;
@@ -293,16 +987,38 @@ impl FileHeader {
 				// read and discard the padding
 				reader.read_exact(&mut vec![0u8; 25 - nonce.len()]).await?;
 
-				let mut keyslot_bytes = vec![0u8; KEYSLOT_SIZE * 2]; // length of 2x keyslots
+				let mut keyslot_bytes = vec![0u8; KEYSLOT_SIZE * Self::MAX_KEYSLOTS];
 				let mut keyslots: Vec<Keyslot> = Vec::new();
 
 				reader.read_exact(&mut keyslot_bytes).await?;
-				let mut keyslot_reader = Cursor::new(keyslot_bytes);
 
-				for _ in 0..2 {
-					Keyslot::from_reader(&mut keyslot_reader)
-						.map(|k| keyslots.push(k))
-						.ok();
+				// empty keyslots are written as zeroes, anything else must be a valid keyslot
+				for keyslot in keyslot_bytes.chunks(KEYSLOT_SIZE) {
+					if keyslot.iter().all(|b| *b == 0) {
+						continue;
+					}
+
+					keyslots.push(Keyslot::from_reader(&mut Cursor::new(keyslot))?);
+				}
+
+				// labels are stored in the same order as the keyslots, which are always written before any empty ones
+				if matches!(
+					version,
+					FileHeaderVersion::V2
+						| FileHeaderVersion::V3
+						| FileHeaderVersion::V4
+						| FileHeaderVersion::V5
+						| FileHeaderVersion::V6
+				) {
+					let mut label_bytes = [0u8; KEYSLOT_LABEL_SIZE * Self::MAX_KEYSLOTS];
+					reader.read_exact(&mut label_bytes).await?;
+
+					for (keyslot, label) in keyslots
+						.iter_mut()
+						.zip(label_bytes.chunks(KEYSLOT_LABEL_SIZE))
+					{
+						keyslot.label = Keyslot::label_from_bytes(to_array(label)?)?;
+					}
 				}
 
 				let metadata = if let Ok(metadata) = Metadata::from_reader(reader).await {
@@ -310,36 +1026,68 @@ impl FileHeader {
 				} else {
 					reader
 						.seek(SeekFrom::Start(
-							Self::size(version) as u64 + (KEYSLOT_SIZE * 2) as u64,
+							(Self::size(version) + Self::keyslots_size(version)) as u64,
 						))
 						.await?;
 					Ok(None)
 				}?;
 
-				let preview_media =
-					if let Ok(preview_media) = PreviewMedia::from_reader(reader).await {
-						Ok::<Option<PreviewMedia>, Error>(Some(preview_media))
-					} else {
-						let seek_len = metadata.as_ref().map_or_else(
-							|| Self::size(version) as u64 + (KEYSLOT_SIZE * 2) as u64,
-							|metadata| {
-								Self::size(version) as u64
-									+ (KEYSLOT_SIZE * 2) as u64 + metadata.size() as u64
-							},
-						);
+				let preview_media = if let Ok(preview_media) =
+					PreviewMedia::from_reader(reader).await
+				{
+					Ok::<Option<PreviewMedia>, Error>(Some(preview_media))
+				} else {
+					let seek_len = metadata.as_ref().map_or_else(
+						|| (Self::size(version) + Self::keyslots_size(version)) as u64,
+						|metadata| {
+							(Self::size(version) + Self::keyslots_size(version) + metadata.size())
+								as u64
+						},
+					);
 
 						reader.seek(SeekFrom::Start(seek_len)).await?;
 
 						Ok(None)
 					}?;
 
+				// both of these are prefixed with their length for V6 headers, as either of them may be absent
+				let objects = match version {
+					FileHeaderVersion::V6 => match Self::section_from_reader(reader).await? {
+						Some(mut section) => {
+							let objects = Self::objects_from_reader(&mut section).await?;
+							Self::ensure_section_read(&section)?;
+							objects
+						}
+						None => Vec::new(),
+					},
+					_ => Vec::new(),
+				};
+
+				// the chunk manifest is mandatory for V5 headers, so it's read directly
+				let chunks = match version {
+					FileHeaderVersion::V5 => Some(ChunkManifest::from_reader(reader).await?),
+					FileHeaderVersion::V6 => match Self::section_from_reader(reader).await? {
+						Some(mut section) => {
+							let chunks = ChunkManifest::from_reader(&mut section).await?;
+							Self::ensure_section_read(&section)?;
+							Some(chunks)
+						}
+						None => None,
+					},
+					_ => None,
+				};
+
 				Self {
 					version,
 					algorithm,
 					nonce,
+					segment_size,
+					aad_binding,
 					keyslots,
 					metadata,
 					preview_media,
+					chunks,
+					objects,
 				}
 			}
 		};
@@ -353,8 +1101,11 @@ mod tests {
 	use std::io::Cursor;
 
 	use crate::{
-		primitives::{LATEST_FILE_HEADER, LATEST_KEYSLOT, LATEST_PREVIEW_MEDIA},
-		types::{HashingAlgorithm, Params, Salt},
+		header::{chunks::ChunkManifestVersion, keyslot::KeyslotVersion},
+		primitives::{
+			BLOCK_LEN, LATEST_FILE_HEADER, LATEST_KEYSLOT, LATEST_PREVIEW_MEDIA, MAX_SEGMENT_SIZE,
+		},
+		types::{HashingAlgorithm, HashingParams, Params, Salt},
 	};
 
 	use super::*;
@@ -393,33 +1144,578 @@ mod tests {
 
 		FileHeader::from_reader(&mut writer).await.unwrap();
 
-		assert!(writer.position() == 260);
+		// this includes the length of the objects and the chunk manifest, which are both empty
+		assert!(writer.position() == 346);
 	}
 
 	#[tokio::test]
-	async fn serialize_and_deserialize_header_with_preview_media() {
-		let mk = Key::generate();
+	async fn serialize_and_deserialize_header_with_custom_params() {
+		let hashing_algorithm =
+			HashingAlgorithm::Argon2id(Params::Custom(HashingParams::new(65_536, 3, 2).unwrap()));
+
 		let mut writer: Cursor<Vec<u8>> = Cursor::new(vec![]);
 
-		let mut header = FileHeader::new(
+		let header = FileHeader::new(
 			LATEST_FILE_HEADER,
 			ALGORITHM,
 			vec![Keyslot::new(
 				LATEST_KEYSLOT,
 				ALGORITHM,
-				HASHING_ALGORITHM,
+				hashing_algorithm,
 				Salt::generate(),
 				Key::generate(),
-				mk.clone(),
+				Key::generate(),
 			)
 			.await
 			.unwrap()],
 		)
 		.unwrap();
 
-		header
-			.add_preview_media(LATEST_PREVIEW_MEDIA, ALGORITHM, mk, &PVM_BYTES)
-			.await
+		header.write(&mut writer).await.unwrap();
+
+		writer.rewind().await.unwrap();
+
+		let (header, _) = FileHeader::from_reader(&mut writer).await.unwrap();
+
+		assert!(header.keyslots[0].hashing_algorithm == hashing_algorithm);
+	}
+
+	#[tokio::test]
+	async fn decrypt_master_key_with_paranoid_params() {
+		let mk = Key::generate();
+		let content_salt = Salt::generate();
+		let password = Protected::new(b"password".to_vec());
+		let hashing_algorithm = HashingAlgorithm::Argon2id(Params::Paranoid);
+
+		let header = FileHeader::new(
+			LATEST_FILE_HEADER,
+			ALGORITHM,
+			vec![Keyslot::new(
+				LATEST_KEYSLOT,
+				ALGORITHM,
+				hashing_algorithm,
+				content_salt,
+				hashing_algorithm
+					.hash_async(password.clone(), content_salt, None)
+					.await
+					.unwrap(),
+				mk.clone(),
+			)
+			.await
+			.unwrap()],
+		)
+		.unwrap();
+
+		let (mut header, _) = FileHeader::from_reader(&mut Cursor::new(header.to_bytes().unwrap()))
+			.await
+			.unwrap();
+		assert!(header.keyslots[0].hashing_algorithm == hashing_algorithm);
+		assert_eq!(
+			header
+				.decrypt_master_key(password.clone())
+				.await
+				.unwrap()
+				.expose(),
+			mk.expose()
+		);
+
+		// the password is hashed with the parameters stored in the keyslot, so different ones derive the wrong key
+		header.keyslots[0].hashing_algorithm = HashingAlgorithm::Argon2id(Params::Standard);
+		assert!(matches!(
+			header.decrypt_master_key(password).await,
+			Err(Error::IncorrectPassword)
+		));
+	}
+
+	async fn header_with_key(mk: Key) -> FileHeader {
+		FileHeader::new(
+			LATEST_FILE_HEADER,
+			ALGORITHM,
+			vec![Keyslot::new(
+				LATEST_KEYSLOT,
+				ALGORITHM,
+				HASHING_ALGORITHM,
+				Salt::generate(),
+				Key::generate(),
+				mk,
+			)
+			.await
+			.unwrap()],
+		)
+		.unwrap()
+	}
+
+	#[tokio::test]
+	async fn encrypt_and_decrypt_with_detached_header() {
+		let mk = Key::generate();
+		let header = header_with_key(mk.clone()).await;
+
+		let path = std::env::temp_dir().join(format!("{}.header", uuid::Uuid::new_v4()));
+		header.write_detached_header(&path).await.unwrap();
+
+		let mut ciphertext = Vec::new();
+		header
+			.encrypt_detached(mk.clone(), PVM_BYTES.as_ref(), &mut ciphertext)
+			.await
+			.unwrap();
+
+		let detached_header = FileHeader::read_detached_header(&path).await.unwrap();
+		let detached_bytes = tokio::fs::read(&path).await.unwrap();
+		tokio::fs::remove_file(&path).await.unwrap();
+
+		let mut plaintext = Vec::new();
+		detached_header
+			.decrypt_detached(mk.clone(), ciphertext.as_slice(), &mut plaintext)
+			.await
+			.unwrap();
+		assert_eq!(plaintext, PVM_BYTES);
+
+		// the detached header is identical to an attached one, so it can be reattached
+		assert_eq!(detached_bytes, header.to_bytes().unwrap());
+
+		let mut reader = Cursor::new([detached_bytes, ciphertext.clone()].concat());
+		let (header, aad) = FileHeader::from_reader(&mut reader).await.unwrap();
+
+		let mut plaintext = Vec::new();
+		Decryptor::new(mk.clone(), header.nonce, header.algorithm)
+			.unwrap()
+			.decrypt_streams(&mut reader, &mut plaintext, &aad)
+			.await
+			.unwrap();
+		assert_eq!(plaintext, PVM_BYTES);
+
+		// a header belonging to different data is rejected, even if the master key is the same
+		let other_header = header_with_key(mk.clone()).await;
+		assert!(matches!(
+			other_header
+				.decrypt_detached(mk, ciphertext.as_slice(), &mut Vec::new())
+				.await,
+			Err(Error::HeaderMismatch)
+		));
+	}
+
+	// a V1 header with a single keyslot, written by a V1 build. new builds must always be able to read this
+	fn v1_header_fixture() -> Vec<u8> {
+		[
+			MAGIC_BYTES.as_ref(),
+			&[0x0A, 0x01],        // header version
+			&[0x0B, 0x01],        // algorithm
+			&[0xE9; 20],          // nonce
+			&[0u8; 5],            // padding
+			&[0x0D, 0x01],        // keyslot version
+			&[0x0B, 0x01],        // keyslot algorithm
+			&[0xA2, 0x01],        // hashing algorithm
+			&[0xFF; 16],          // salt
+			&[0xEE; 16],          // content salt
+			&[0x23; 48],          // encrypted master key
+			&[0xE8; 20],          // keyslot nonce
+			&[0u8; 6],            // keyslot padding
+			&[0u8; KEYSLOT_SIZE], // empty keyslot
+		]
+		.concat()
+	}
+
+	#[tokio::test]
+	async fn deserialize_v1_header_fixture() {
+		let fixture = v1_header_fixture();
+
+		let (header, aad) = FileHeader::from_reader(&mut Cursor::new(fixture.clone()))
+			.await
+			.unwrap();
+
+		assert!(matches!(header.version, FileHeaderVersion::V1));
+		assert!(header.algorithm == Algorithm::XChaCha20Poly1305);
+		assert!(header.nonce == Nonce::XChaCha20Poly1305([0xE9; 20]));
+		assert!(header.keyslots.len() == 1);
+		assert!(header.keyslots[0].hashing_algorithm == HASHING_ALGORITHM);
+		assert!(header.metadata.is_none());
+		assert!(header.preview_media.is_none());
+		assert_eq!(aad, &fixture[..FileHeader::size(FileHeaderVersion::V1)]);
+
+		// the header is read as it was written, so it serializes to the same bytes
+		assert_eq!(header.to_bytes().unwrap(), fixture);
+	}
+
+	#[tokio::test]
+	async fn move_and_label_keyslots() {
+		let mk = Key::generate();
+		let password = Key::generate();
+
+		let mut header = FileHeader::new(
+			LATEST_FILE_HEADER,
+			ALGORITHM,
+			vec![
+				Keyslot::new(
+					LATEST_KEYSLOT,
+					ALGORITHM,
+					HASHING_ALGORITHM,
+					Salt::generate(),
+					Key::generate(),
+					mk.clone(),
+				)
+				.await
+				.unwrap(),
+				Keyslot::new(
+					LATEST_KEYSLOT,
+					ALGORITHM,
+					HASHING_ALGORITHM,
+					Salt::generate(),
+					password.clone(),
+					mk.clone(),
+				)
+				.await
+				.unwrap(),
+			],
+		)
+		.unwrap();
+
+		header
+			.set_keyslot_label(1, Some("recovery".to_string()))
+			.unwrap();
+		let keyslot = header.keyslots[1].to_bytes();
+
+		header.move_keyslot(1, 0).unwrap();
+
+		let (header, _) = FileHeader::from_reader(&mut Cursor::new(header.to_bytes().unwrap()))
+			.await
+			.unwrap();
+
+		// only the position changes, and the label moves with the keyslot
+		assert_eq!(header.keyslots[0].to_bytes(), keyslot);
+		assert_eq!(header.keyslots[0].label.as_deref(), Some("recovery"));
+		assert!(header.keyslots[1].label.is_none());
+
+		let key = header.keyslots[0]
+			.decrypt_master_key_from_prehashed(password)
+			.await
+			.unwrap();
+		assert_eq!(key.expose(), mk.expose());
+	}
+
+	#[tokio::test]
+	async fn inspect_keyslots_without_password() {
+		let fixture = v1_header_fixture();
+		let (header, _) = FileHeader::from_reader(&mut Cursor::new(fixture))
+			.await
+			.unwrap();
+
+		let info = header.keyslot_info();
+		assert!(info.len() == 1);
+		assert!(info[0].index == 0 && info[0].primary);
+		assert!(matches!(info[0].version, KeyslotVersion::V1));
+		assert!(info[0].algorithm == Algorithm::XChaCha20Poly1305);
+		assert!(info[0].hashing_algorithm == HASHING_ALGORITHM);
+		assert!(info[0].content_salt == Salt([0xEE; 16]));
+		assert!(info[0].label.is_none());
+
+		let mut header = header_with_key(Key::generate()).await;
+		header
+			.add_keyslot(
+				Keyslot::new(
+					LATEST_KEYSLOT,
+					Algorithm::Aes256Gcm,
+					HashingAlgorithm::BalloonBlake3(Params::Hardened),
+					Salt::generate(),
+					Key::generate(),
+					Key::generate(),
+				)
+				.await
+				.unwrap(),
+			)
+			.unwrap();
+		header
+			.set_keyslot_label(1, Some("recovery".to_string()))
+			.unwrap();
+
+		// the info follows the keyslots when they're moved
+		header.move_keyslot(1, 0).unwrap();
+		let info = header.keyslot_info();
+		assert!(info.len() == 2);
+		assert!(info[0].primary && info[0].label.as_deref() == Some("recovery"));
+		assert!(info[0].algorithm == Algorithm::Aes256Gcm);
+		assert!(!info[1].primary && info[1].index == 1);
+		assert!(info[1].hashing_algorithm == HASHING_ALGORITHM);
+	}
+
+	#[tokio::test]
+	async fn move_and_label_keyslots_out_of_range() {
+		let mut header = header_with_key(Key::generate()).await;
+
+		assert!(matches!(
+			header.move_keyslot(0, 1),
+			Err(Error::KeyslotOutOfRange)
+		));
+		assert!(matches!(
+			header.move_keyslot(2, 0),
+			Err(Error::KeyslotOutOfRange)
+		));
+		assert!(matches!(
+			header.set_keyslot_label(1, None),
+			Err(Error::KeyslotOutOfRange)
+		));
+
+		// the previous label is kept if the new one is invalid
+		header.set_keyslot_label(0, Some("a".to_string())).unwrap();
+		assert!(matches!(
+			header.set_keyslot_label(0, Some("a".repeat(32))),
+			Err(Error::InvalidKeyslotLabel)
+		));
+		assert_eq!(header.keyslots[0].label.as_deref(), Some("a"));
+
+		// V1 headers have nowhere to store labels
+		header.version = FileHeaderVersion::V1;
+		assert!(matches!(
+			header.set_keyslot_label(0, Some("b".to_string())),
+			Err(Error::InvalidKeyslotLabel)
+		));
+	}
+
+	#[tokio::test]
+	async fn deserialize_header_from_newer_version() {
+		let mut fixture = v1_header_fixture();
+		fixture[MAGIC_BYTES.len() + 1] = 0xFF;
+
+		assert!(matches!(
+			FileHeader::from_reader(&mut Cursor::new(fixture)).await,
+			Err(Error::UnsupportedHeaderVersion {
+				found: 0x0AFF,
+				supported: 0x0A06
+			})
+		));
+	}
+
+	#[tokio::test]
+	async fn deserialize_truncated_header() {
+		let fixture = v1_header_fixture();
+
+		for len in [3, MAGIC_BYTES.len() + 1, 100] {
+			assert!(matches!(
+				FileHeader::from_reader(&mut Cursor::new(&fixture[..len])).await,
+				Err(Error::HeaderTruncated)
+			));
+		}
+	}
+
+	#[tokio::test]
+	async fn deserialize_corrupt_header() {
+		let mut fixture = v1_header_fixture();
+
+		// the keyslot's hashing algorithm
+		let offset = FileHeader::size(FileHeaderVersion::V1) + 4;
+		fixture[offset..offset + 2].copy_from_slice(&[0x77, 0x77]);
+
+		assert!(matches!(
+			FileHeader::from_reader(&mut Cursor::new(fixture)).await,
+			Err(Error::HeaderCorrupt)
+		));
+
+		// the version's first byte doesn't identify a file header
+		let mut fixture = v1_header_fixture();
+		fixture[MAGIC_BYTES.len()] = 0x2A;
+
+		assert!(matches!(
+			FileHeader::from_reader(&mut Cursor::new(fixture)).await,
+			Err(Error::HeaderCorrupt)
+		));
+	}
+
+	#[tokio::test]
+	async fn deserialize_non_spacedrive_file() {
+		// a PNG signature and the start of its IHDR chunk
+		let png = [
+			0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, 0x00, 0x00, 0x00, 0x0D, 0x49, 0x48,
+			0x44, 0x52,
+		];
+
+		for bytes in [png.to_vec(), vec![0u8; 512]] {
+			assert!(matches!(
+				FileHeader::from_reader(&mut Cursor::new(bytes)).await,
+				Err(Error::InvalidHeaderMagic)
+			));
+		}
+	}
+
+	#[test]
+	fn header_version_round_trip() {
+		for version in [
+			FileHeaderVersion::V1,
+			FileHeaderVersion::V2,
+			FileHeaderVersion::V3,
+			FileHeaderVersion::V4,
+			FileHeaderVersion::V5,
+			FileHeaderVersion::V6,
+		] {
+			let bytes = version.to_bytes();
+			assert_eq!(
+				FileHeaderVersion::from_bytes(bytes).unwrap().to_bytes(),
+				bytes
+			);
+		}
+	}
+
+	#[tokio::test]
+	#[allow(clippy::cast_possible_truncation)]
+	async fn encrypt_and_decrypt_segmented() {
+		let mk = Key::generate();
+		let mut header = header_with_key(mk.clone()).await;
+		header.set_segment_size(Some(BLOCK_LEN as u32)).unwrap();
+
+		let data = (0..BLOCK_LEN * 5 / 2)
+			.map(|i| (i % 251) as u8)
+			.collect::<Vec<_>>();
+
+		let mut ciphertext = header.to_bytes().unwrap();
+		header
+			.encrypt_detached(mk.clone(), data.as_slice(), &mut ciphertext)
+			.await
+			.unwrap();
+
+		let mut reader = Cursor::new(ciphertext.clone());
+		let (header, aad) = FileHeader::from_reader(&mut reader).await.unwrap();
+		assert_eq!(header.segment_size, Some(BLOCK_LEN as u32));
+
+		let mut plaintext = Vec::new();
+		header
+			.decrypt(mk.clone(), &mut reader, &mut plaintext, &aad)
+			.await
+			.unwrap();
+		assert_eq!(plaintext, data);
+
+		// the segment size is part of the AAD, so it can't be changed without re-encrypting the data
+		let offset = MAGIC_BYTES.len() + 4;
+		ciphertext[offset..offset + 4].copy_from_slice(&(BLOCK_LEN as u32 * 2).to_le_bytes());
+		let mut reader = Cursor::new(ciphertext);
+		let (header, aad) = FileHeader::from_reader(&mut reader).await.unwrap();
+
+		assert!(matches!(
+			header.decrypt(mk, &mut reader, &mut Vec::new(), &aad).await,
+			Err(Error::HeaderCorrupt)
+		));
+	}
+
+	#[tokio::test]
+	#[allow(clippy::cast_possible_truncation)]
+	async fn set_invalid_segment_size() {
+		let mut header = header_with_key(Key::generate()).await;
+
+		for segment_size in [
+			0,
+			BLOCK_LEN as u32 + 1,
+			(MAX_SEGMENT_SIZE + BLOCK_LEN) as u32,
+		] {
+			assert!(matches!(
+				header.set_segment_size(Some(segment_size)),
+				Err(Error::InvalidSegmentSize)
+			));
+		}
+
+		// only V3 headers have somewhere to store the segment size
+		header.version = FileHeaderVersion::V2;
+		assert!(matches!(
+			header.set_segment_size(Some(BLOCK_LEN as u32)),
+			Err(Error::InvalidSegmentSize)
+		));
+		header.set_segment_size(None).unwrap();
+	}
+
+	#[tokio::test]
+	async fn decrypt_with_tampered_header() {
+		let mk = Key::generate();
+		let header = header_with_key(mk.clone()).await;
+
+		let mut ciphertext = header.to_bytes().unwrap();
+		Encryptor::new(mk.clone(), header.nonce, header.algorithm)
+			.unwrap()
+			.encrypt_streams(PVM_BYTES.as_ref(), &mut ciphertext, &header.generate_aad())
+			.await
+			.unwrap();
+
+		let (header, aad) = FileHeader::from_reader(&mut Cursor::new(ciphertext.clone()))
+			.await
+			.unwrap();
+		let mut plaintext = Vec::new();
+		header
+			.decrypt(
+				mk.clone(),
+				&ciphertext[header.to_bytes().unwrap().len()..],
+				&mut plaintext,
+				&aad,
+			)
+			.await
+			.unwrap();
+		assert_eq!(plaintext, PVM_BYTES);
+
+		// the padding is authenticated but otherwise ignored, so the header still parses
+		ciphertext[aad.len() - 1] = 0xFF;
+		let mut reader = Cursor::new(ciphertext);
+		let (header, aad) = FileHeader::from_reader(&mut reader).await.unwrap();
+
+		assert!(matches!(
+			header.decrypt(mk, &mut reader, &mut Vec::new(), &aad).await,
+			Err(Error::HeaderCorrupt)
+		));
+	}
+
+	#[tokio::test]
+	async fn deserialize_header_with_unsupported_algorithm() {
+		let mut writer: Cursor<Vec<u8>> = Cursor::new(vec![]);
+
+		let header = FileHeader::new(
+			LATEST_FILE_HEADER,
+			Algorithm::Aes256Gcm,
+			vec![Keyslot::new(
+				LATEST_KEYSLOT,
+				Algorithm::Aes256Gcm,
+				HASHING_ALGORITHM,
+				Salt::generate(),
+				Key::generate(),
+				Key::generate(),
+			)
+			.await
+			.unwrap()],
+		)
+		.unwrap();
+
+		header.write(&mut writer).await.unwrap();
+
+		writer.rewind().await.unwrap();
+		let (header, _) = FileHeader::from_reader(&mut writer).await.unwrap();
+		assert!(header.algorithm == Algorithm::Aes256Gcm);
+
+		// the algorithm directly follows the magic bytes and version
+		let mut bytes = writer.into_inner();
+		bytes[MAGIC_BYTES.len() + 3] = 0xFF;
+
+		assert!(matches!(
+			FileHeader::from_reader(&mut Cursor::new(bytes)).await,
+			Err(Error::UnsupportedAlgorithm)
+		));
+	}
+
+	#[tokio::test]
+	async fn serialize_and_deserialize_header_with_preview_media() {
+		let mk = Key::generate();
+		let mut writer: Cursor<Vec<u8>> = Cursor::new(vec![]);
+
+		let mut header = FileHeader::new(
+			LATEST_FILE_HEADER,
+			ALGORITHM,
+			vec![Keyslot::new(
+				LATEST_KEYSLOT,
+				ALGORITHM,
+				HASHING_ALGORITHM,
+				Salt::generate(),
+				Key::generate(),
+				mk.clone(),
+			)
+			.await
+			.unwrap()],
+		)
+		.unwrap();
+
+		header
+			.add_preview_media(LATEST_PREVIEW_MEDIA, ALGORITHM, mk, &PVM_BYTES)
+			.await
 			.unwrap();
 
 		header.write(&mut writer).await.unwrap();
@@ -571,6 +1867,50 @@ mod tests {
 		.unwrap();
 	}
 
+	#[tokio::test]
+	async fn add_keyslots_up_to_capacity() {
+		let mk = Key::generate();
+
+		let mut header = FileHeader::new(LATEST_FILE_HEADER, ALGORITHM, vec![]).unwrap();
+		assert_eq!(header.keyslot_capacity(), (0, FileHeader::MAX_KEYSLOTS));
+
+		for used in 1..=FileHeader::MAX_KEYSLOTS {
+			header
+				.add_keyslot(
+					Keyslot::new(
+						LATEST_KEYSLOT,
+						ALGORITHM,
+						HASHING_ALGORITHM,
+						Salt::generate(),
+						Key::generate(),
+						mk.clone(),
+					)
+					.await
+					.unwrap(),
+				)
+				.unwrap();
+
+			assert_eq!(header.keyslot_capacity(), (used, FileHeader::MAX_KEYSLOTS));
+		}
+
+		let keyslot = Keyslot::new(
+			LATEST_KEYSLOT,
+			ALGORITHM,
+			HASHING_ALGORITHM,
+			Salt::generate(),
+			Key::generate(),
+			mk,
+		)
+		.await
+		.unwrap();
+
+		assert!(matches!(
+			header.add_keyslot(keyslot),
+			Err(Error::TooManyKeyslots { used, max }) if used == max && max == FileHeader::MAX_KEYSLOTS
+		));
+		assert_eq!(header.keyslots.len(), FileHeader::MAX_KEYSLOTS);
+	}
+
 	#[cfg(feature = "serde")]
 	#[tokio::test]
 	async fn serialize_and_deserialize_header_with_all() {
@@ -638,6 +1978,79 @@ mod tests {
 		assert!(header.keyslots.len() == 2);
 	}
 
+	fn chunk_manifest() -> ChunkManifest {
+		ChunkManifest {
+			version: ChunkManifestVersion::V1,
+			algorithm: ALGORITHM,
+			manifest_nonce: Nonce::generate(ALGORITHM).unwrap(),
+			manifest: vec![0x2A; 64],
+		}
+	}
+
+	#[tokio::test]
+	async fn serialize_and_deserialize_v6_header_with_chunks_and_objects() {
+		let mk = Key::generate();
+		let mut header = header_with_key(mk.clone()).await;
+		header.version = FileHeaderVersion::V6;
+
+		// either section may be present without the other
+		for (chunks, objects) in [(false, false), (true, false), (false, true), (true, true)] {
+			header.chunks = chunks.then(chunk_manifest);
+			header.objects.clear();
+			if objects {
+				header
+					.put_object(mk.clone(), "thumbnail", &PVM_BYTES, false)
+					.await
+					.unwrap();
+			}
+
+			let bytes = header.to_bytes().unwrap();
+			let mut reader = Cursor::new([bytes.clone(), PVM_BYTES.to_vec()].concat());
+			let (deserialized, _) = FileHeader::from_reader(&mut reader).await.unwrap();
+
+			assert_eq!(reader.position(), bytes.len() as u64);
+			assert_eq!(deserialized.chunks.is_some(), chunks);
+			assert_eq!(deserialized.objects.len(), usize::from(objects));
+			assert_eq!(deserialized.to_bytes().unwrap(), bytes);
+		}
+
+		// older headers have nowhere to store either of them
+		header.version = FileHeaderVersion::V4;
+		assert!(matches!(
+			header.to_bytes(),
+			Err(Error::InvalidChunkManifest)
+		));
+
+		header.version = FileHeaderVersion::V5;
+		assert!(matches!(header.to_bytes(), Err(Error::InvalidObject)));
+	}
+
+	#[tokio::test]
+	async fn deserialize_v6_header_with_invalid_section_length() {
+		let mut header = header_with_key(Key::generate()).await;
+		header.version = FileHeaderVersion::V6;
+		header.chunks = Some(chunk_manifest());
+
+		let bytes = header.to_bytes().unwrap();
+		let manifest_len = chunk_manifest().size();
+		let offset = bytes.len() - manifest_len - 8;
+
+		// a length that's longer than the section runs past the end of the header
+		let mut longer = bytes.clone();
+		longer[offset..offset + 8].copy_from_slice(&(manifest_len as u64 + 1).to_le_bytes());
+		assert!(matches!(
+			FileHeader::from_reader(&mut Cursor::new(longer)).await,
+			Err(Error::HeaderTruncated)
+		));
+
+		// a length that's shorter than the section can't be a valid chunk manifest
+		let mut shorter = bytes;
+		shorter[offset..offset + 8].copy_from_slice(&(manifest_len as u64 - 1).to_le_bytes());
+		assert!(FileHeader::from_reader(&mut Cursor::new(shorter))
+			.await
+			.is_err());
+	}
+
 	#[tokio::test]
 	async fn aad_validity() {
 		let mut writer: Cursor<Vec<u8>> = Cursor::new(vec![]);
@@ -665,6 +2078,118 @@ mod tests {
 		let (header, aad) = FileHeader::from_reader(&mut writer).await.unwrap();
 
 		assert_eq!(header.generate_aad(), aad);
-		assert_eq!(&header.to_bytes().unwrap()[..36], aad);
+		assert_eq!(
+			&header.to_bytes().unwrap()[..FileHeader::size(LATEST_FILE_HEADER)],
+			aad
+		);
+	}
+
+	async fn bound_file(mk: Key, context: &[u8], plaintext: &[u8]) -> Vec<u8> {
+		let mut header = header_with_key(mk.clone()).await;
+		header.set_aad_binding(AadBinding::Context).unwrap();
+
+		let mut file = header.to_bytes().unwrap();
+		header
+			.encrypt_with_aad(mk, plaintext, &mut file, context)
+			.await
+			.unwrap();
+
+		file
+	}
+
+	async fn decrypt_bound_file(mk: Key, file: Vec<u8>, context: &[u8]) -> Result<Vec<u8>> {
+		let mut reader = Cursor::new(file);
+		let (header, aad) = FileHeader::from_reader(&mut reader).await?;
+		assert_eq!(header.aad_binding, AadBinding::Context);
+
+		let mut plaintext = Vec::new();
+		header
+			.decrypt_with_aad(mk, &mut reader, &mut plaintext, &aad, context)
+			.await?;
+
+		Ok(plaintext)
+	}
+
+	#[tokio::test]
+	async fn encrypt_and_decrypt_with_aad() {
+		let mk = Key::generate();
+		let file = bound_file(mk.clone(), b"object a", &PVM_BYTES).await;
+
+		assert_eq!(
+			decrypt_bound_file(mk.clone(), file.clone(), b"object a")
+				.await
+				.unwrap(),
+			PVM_BYTES
+		);
+
+		assert!(matches!(
+			decrypt_bound_file(mk.clone(), file.clone(), b"object b").await,
+			Err(Error::AadMismatch)
+		));
+
+		// the context is required, so the data can't be decrypted without it
+		let mut reader = Cursor::new(file);
+		let (header, aad) = FileHeader::from_reader(&mut reader).await.unwrap();
+		assert!(matches!(
+			header.decrypt(mk, &mut reader, &mut Vec::new(), &aad).await,
+			Err(Error::AadMismatch)
+		));
+	}
+
+	#[tokio::test]
+	async fn decrypt_with_swapped_headers() {
+		let mk = Key::generate();
+		let file_a = bound_file(mk.clone(), b"object a", b"first file").await;
+		let file_b = bound_file(mk.clone(), b"object b", b"second file").await;
+
+		let header_len =
+			FileHeader::size(LATEST_FILE_HEADER) + FileHeader::keyslots_size(LATEST_FILE_HEADER);
+		let swapped_a = [&file_b[..header_len], &file_a[header_len..]].concat();
+		let swapped_b = [&file_a[..header_len], &file_b[header_len..]].concat();
+
+		for context in [b"object a", b"object b"] {
+			assert!(matches!(
+				decrypt_bound_file(mk.clone(), swapped_a.clone(), context).await,
+				Err(Error::AadMismatch)
+			));
+			assert!(matches!(
+				decrypt_bound_file(mk.clone(), swapped_b.clone(), context).await,
+				Err(Error::AadMismatch)
+			));
+		}
+	}
+
+	#[tokio::test]
+	async fn set_invalid_aad_binding() {
+		let mut header = header_with_key(Key::generate()).await;
+
+		assert!(matches!(
+			header
+				.encrypt_with_aad(
+					Key::generate(),
+					PVM_BYTES.as_ref(),
+					&mut Vec::new(),
+					b"context"
+				)
+				.await,
+			Err(Error::AadMismatch)
+		));
+
+		header.set_aad_binding(AadBinding::Context).unwrap();
+		assert!(matches!(
+			header
+				.encrypt_detached(Key::generate(), PVM_BYTES.as_ref(), &mut Vec::new())
+				.await,
+			Err(Error::AadMismatch)
+		));
+
+		// only V4 headers have somewhere to store the AAD binding
+		header.version = FileHeaderVersion::V3;
+		assert!(matches!(header.to_bytes(), Err(Error::InvalidAadBinding)));
+		assert!(matches!(
+			header.set_aad_binding(AadBinding::Context),
+			Err(Error::InvalidAadBinding)
+		));
+		header.set_aad_binding(AadBinding::Header).unwrap();
 	}
 }
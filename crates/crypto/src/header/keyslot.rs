@@ -25,7 +25,7 @@ use std::io::Read;
 
 use crate::{
 	crypto::{Decryptor, Encryptor},
-	primitives::{ENCRYPTED_KEY_LEN, FILE_KEY_CONTEXT, SALT_LEN},
+	primitives::{ENCRYPTED_KEY_LEN, FILE_KEY_CONTEXT, HASHING_PARAMS_LEN, SALT_LEN},
 	types::{Algorithm, EncryptedKey, HashingAlgorithm, Key, Nonce, Salt},
 	Error, Protected, Result,
 };
@@ -42,10 +42,31 @@ pub struct Keyslot {
 	pub content_salt: Salt,
 	pub master_key: EncryptedKey, // this is encrypted so we can store it
 	pub nonce: Nonce,
+	pub label: Option<String>, // only written by V2+ headers, and it isn't encrypted
+}
+
+/// This describes a keyslot without any of its key material, and it's returned by `FileHeader::keyslot_info()`.
+///
+/// It's available without a password, so it can be used for showing which keys a file has or auditing which algorithms it uses.
+#[derive(Clone)]
+pub struct KeyslotInfo {
+	pub index: usize,
+	pub version: KeyslotVersion,
+	pub algorithm: Algorithm,
+	pub hashing_algorithm: HashingAlgorithm,
+	pub content_salt: Salt, // this matches the content salt of the key (within the key manager) that the keyslot was created with
+	pub label: Option<String>,
+	pub primary: bool, // keyslots are tried in order, so the first keyslot is the one that's expected to unlock the file
 }
 
 pub const KEYSLOT_SIZE: usize = 112;
 
+/// This is how much space each keyslot label takes up within a header (a length byte, followed by the UTF-8 label)
+pub const KEYSLOT_LABEL_SIZE: usize = 32;
+
+/// The maximum length of a keyslot label, in bytes
+pub const MAX_KEYSLOT_LABEL_LEN: usize = KEYSLOT_LABEL_SIZE - 1;
+
 /// This defines the keyslot version
 ///
 /// The goal is to not increment this much, but it's here in case we need to make breaking changes
@@ -92,6 +113,7 @@ impl Keyslot {
 			content_salt,
 			master_key: encrypted_master_key,
 			nonce,
+			label: None,
 		})
 	}
 
@@ -104,7 +126,8 @@ impl Keyslot {
 	pub async fn decrypt_master_key(&self, password: Protected<Vec<u8>>) -> Result<Key> {
 		let key = self
 			.hashing_algorithm
-			.hash(password, self.content_salt, None)
+			.hash_async(password, self.content_salt, None)
+			.await
 			.map_err(|_| Error::PasswordHash)?;
 
 		Key::try_from(
@@ -139,6 +162,20 @@ impl Keyslot {
 		)
 	}
 
+	/// This describes the keyslot without exposing the salt, nonce or encrypted master key.
+	#[must_use]
+	pub fn info(&self, index: usize) -> KeyslotInfo {
+		KeyslotInfo {
+			index,
+			version: self.version,
+			algorithm: self.algorithm,
+			hashing_algorithm: self.hashing_algorithm,
+			content_salt: self.content_salt,
+			label: self.label.clone(),
+			primary: index == 0,
+		}
+	}
+
 	/// This function is used to serialize a keyslot into bytes
 	#[must_use]
 	pub fn to_bytes(&self) -> Vec<u8> {
@@ -151,7 +188,8 @@ impl Keyslot {
 				&self.content_salt,
 				&self.master_key,
 				&self.nonce,
-				&vec![0u8; 26 - self.nonce.len()],
+				self.hashing_algorithm.params_to_bytes().as_ref(),
+				&vec![0u8; 26 - self.nonce.len() - HASHING_PARAMS_LEN],
 			]
 			.into_iter()
 			.flatten()
@@ -160,6 +198,42 @@ impl Keyslot {
 		}
 	}
 
+	/// This function serializes the keyslot's label, and an empty label is written as zeroes
+	///
+	/// An error will be returned if the label is empty or longer than `MAX_KEYSLOT_LABEL_LEN` bytes.
+	pub fn label_to_bytes(&self) -> Result<[u8; KEYSLOT_LABEL_SIZE]> {
+		let mut bytes = [0u8; KEYSLOT_LABEL_SIZE];
+
+		if let Some(label) = &self.label {
+			if label.is_empty() || label.len() > MAX_KEYSLOT_LABEL_LEN {
+				return Err(Error::InvalidKeyslotLabel);
+			}
+
+			#[allow(clippy::cast_possible_truncation)]
+			{
+				bytes[0] = label.len() as u8;
+			}
+			bytes[1..=label.len()].copy_from_slice(label.as_bytes());
+		}
+
+		Ok(bytes)
+	}
+
+	/// This function deserializes a label that was written with `label_to_bytes()`
+	pub fn label_from_bytes(bytes: [u8; KEYSLOT_LABEL_SIZE]) -> Result<Option<String>> {
+		let len = bytes[0] as usize;
+
+		if len == 0 {
+			return Ok(None);
+		} else if len > MAX_KEYSLOT_LABEL_LEN {
+			return Err(Error::Serialization);
+		}
+
+		String::from_utf8(bytes[1..=len].to_vec())
+			.map(Some)
+			.map_err(|_| Error::Serialization)
+	}
+
 	/// This function reads a keyslot from a reader
 	///
 	/// It will leave the cursor at the end of the keyslot on success
@@ -181,7 +255,6 @@ impl Keyslot {
 
 				let mut hashing_algorithm = [0u8; 2];
 				reader.read_exact(&mut hashing_algorithm)?;
-				let hashing_algorithm = HashingAlgorithm::from_bytes(hashing_algorithm)?;
 
 				let mut salt = [0u8; SALT_LEN];
 				reader.read_exact(&mut salt)?;
@@ -196,7 +269,13 @@ impl Keyslot {
 				reader.read_exact(&mut nonce)?;
 				let nonce = Nonce::try_from(nonce)?;
 
-				reader.read_exact(&mut vec![0u8; 26 - nonce.len()])?;
+				// custom hashing parameters are stored at the start of the padding
+				let mut hashing_params = [0u8; HASHING_PARAMS_LEN];
+				reader.read_exact(&mut hashing_params)?;
+				let hashing_algorithm =
+					HashingAlgorithm::from_bytes(hashing_algorithm, hashing_params)?;
+
+				reader.read_exact(&mut vec![0u8; 26 - nonce.len() - HASHING_PARAMS_LEN])?;
 
 				let keyslot = Self {
 					version,
@@ -206,6 +285,7 @@ impl Keyslot {
 					content_salt: Salt(content_salt),
 					master_key: EncryptedKey(master_key),
 					nonce,
+					label: None,
 				};
 
 				Ok(keyslot)
@@ -82,13 +82,13 @@ impl FileHeader {
 	where
 		T: ?Sized + serde::Serialize + Sync + Send,
 	{
-		let metadata_nonce = Nonce::generate(algorithm)?;
+		let metadata_nonce = self.nonce_tracker()?.generate(algorithm)?;
 
 		let encrypted_metadata = Encryptor::encrypt_bytes(
 			master_key,
 			metadata_nonce,
 			algorithm,
-			&serde_json::to_vec(metadata).map_err(|_| Error::Serialization)?,
+			&serde_json::to_vec(metadata)?,
 			&[],
 		)
 		.await?;
@@ -125,7 +125,7 @@ impl FileHeader {
 			)
 			.await?;
 
-			serde_json::from_slice::<T>(metadata.expose()).map_err(|_| Error::Serialization)
+			Ok(serde_json::from_slice::<T>(metadata.expose())?)
 		} else {
 			Err(Error::NoMetadata)
 		}
@@ -153,7 +153,7 @@ impl FileHeader {
 			)
 			.await?;
 
-			serde_json::from_slice::<T>(metadata.expose()).map_err(|_| Error::Serialization)
+			Ok(serde_json::from_slice::<T>(metadata.expose())?)
 		} else {
 			Err(Error::NoMetadata)
 		}
@@ -0,0 +1,377 @@
+//! This module contains header migrations, which upgrade an encrypted file to a newer header version.
+//!
+//! Every supported version can always be read with `FileHeader::from_reader()`, so migrating is only needed for using features of newer headers (such as keyslot labels, segments or objects).
+//!
+//! The keyslots, metadata, preview media and objects are all encrypted with the master key rather than being bound to the header, so they're carried over exactly as they were.
+//! This means a migrated file can be unlocked with the same passwords, and nothing beyond the master key is needed for migrating it.
+//!
+//! The data is bound to the header's AAD (which contains the version and the nonce), so it has to be re-encrypted with the migrated header.
+//!
+//! # Examples
+//!
+//! ```rust,ignore
+//! let (header, aad) = FileHeader::from_reader(&mut reader).await.unwrap();
+//! let master_key = header.decrypt_master_key(password).await.unwrap();
+//!
+//! let migrated = header
+//!     .migrate_file(LATEST_FILE_HEADER, master_key, &mut reader, &mut writer, &aad, None)
+//!     .await
+//!     .unwrap();
+//! ```
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+use crate::{primitives::BLOCK_LEN, types::Key, Error, Result};
+
+use super::file::{FileHeader, FileHeaderVersion};
+
+impl FileHeader {
+	/// This returns where a version sits within the migration path, as each of these versions can store everything that the ones before it can.
+	///
+	/// V5 headers aren't on the path, as their data lives within a chunk store rather than following the header (please see `migrate()` for V6 headers with a chunk manifest).
+	const fn migration_order(version: FileHeaderVersion) -> Option<u8> {
+		match version {
+			FileHeaderVersion::V1 => Some(1),
+			FileHeaderVersion::V2 => Some(2),
+			FileHeaderVersion::V3 => Some(3),
+			FileHeaderVersion::V4 => Some(4),
+			FileHeaderVersion::V5 => None,
+			FileHeaderVersion::V6 => Some(6),
+		}
+	}
+
+	/// This migrates a header to the provided version, keeping everything but the nonce.
+	///
+	/// A new nonce is generated (that isn't in use by anything within the header), as the data must be re-encrypted with the migrated header. Please see `migrate_file()` for that.
+	///
+	/// Headers can only be migrated to the same (or a newer) version, so nothing is ever lost. Otherwise, `Error::InvalidMigration` is returned.
+	///
+	/// Headers with a chunk manifest can't be migrated either, as the manifest is bound to the nonce and their data lives within a chunk store.
+	pub fn migrate(&self, version: FileHeaderVersion) -> Result<Self> {
+		match (
+			Self::migration_order(self.version),
+			Self::migration_order(version),
+		) {
+			(Some(from), Some(to)) if from <= to && self.chunks.is_none() => {}
+			_ => return Err(Error::InvalidMigration),
+		}
+
+		Ok(Self {
+			version,
+			nonce: self.nonce_tracker()?.generate(self.algorithm)?,
+			..self.clone()
+		})
+	}
+
+	/// This migrates an encrypted file to the provided version, by writing the migrated header followed by the re-encrypted data.
+	///
+	/// The reader should be left where `from_reader()` left it, and the AAD should be the one that it returned.
+	///
+	/// The context must be provided if (and only if) the data is bound to one, and it's kept for the migrated data.
+	///
+	/// The data is streamed from the decryptor to the encryptor, so the whole file is never held in memory. On error, the writer may contain a partially-migrated file and it should be discarded.
+	///
+	/// The migrated header is returned.
+	#[allow(clippy::needless_pass_by_value)]
+	pub async fn migrate_file<R, W>(
+		&self,
+		version: FileHeaderVersion,
+		master_key: Key,
+		reader: R,
+		mut writer: W,
+		aad: &[u8],
+		context: Option<&[u8]>,
+	) -> Result<Self>
+	where
+		R: AsyncReadExt + Unpin + Send,
+		W: AsyncWriteExt + Unpin + Send,
+	{
+		let migrated = self.migrate(version)?;
+		migrated.write(&mut writer).await?;
+
+		let (decrypted_writer, decrypted_reader) = duplex(BLOCK_LEN);
+
+		let decrypt = async {
+			match context {
+				Some(context) => {
+					self.decrypt_with_aad(
+						master_key.clone(),
+						reader,
+						decrypted_writer,
+						aad,
+						context,
+					)
+					.await
+				}
+				None => {
+					self.decrypt(master_key.clone(), reader, decrypted_writer, aad)
+						.await
+				}
+			}
+		};
+
+		let encrypt = async {
+			match context {
+				Some(context) => {
+					migrated
+						.encrypt_with_aad(
+							master_key.clone(),
+							decrypted_reader,
+							&mut writer,
+							context,
+						)
+						.await
+				}
+				None => {
+					migrated
+						.encrypt_detached(master_key.clone(), decrypted_reader, &mut writer)
+						.await
+				}
+			}
+		};
+
+		tokio::try_join!(decrypt, encrypt)?;
+		writer.flush().await?;
+
+		Ok(migrated)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Cursor;
+
+	use crate::{
+		header::{
+			file::{AadBinding, MAGIC_BYTES},
+			keyslot::{Keyslot, KEYSLOT_SIZE},
+		},
+		primitives::LATEST_KEYSLOT,
+		types::{Algorithm, HashingAlgorithm, Params, Salt},
+	};
+
+	use super::*;
+
+	const ALGORITHM: Algorithm = Algorithm::XChaCha20Poly1305;
+	const HASHING_ALGORITHM: HashingAlgorithm = HashingAlgorithm::Argon2id(Params::Standard);
+	const DATA: [u8; 4] = [0x01, 0x02, 0x03, 0x04];
+
+	// a V1 header with a single keyslot, this matches the fixture within the `file` module
+	fn v1_header_fixture() -> Vec<u8> {
+		[
+			MAGIC_BYTES.as_ref(),
+			&[0x0A, 0x01],        // header version
+			&[0x0B, 0x01],        // algorithm
+			&[0xE9; 20],          // nonce
+			&[0u8; 5],            // padding
+			&[0x0D, 0x01],        // keyslot version
+			&[0x0B, 0x01],        // keyslot algorithm
+			&[0xA2, 0x01],        // hashing algorithm
+			&[0xFF; 16],          // salt
+			&[0xEE; 16],          // content salt
+			&[0x23; 48],          // encrypted master key
+			&[0xE8; 20],          // keyslot nonce
+			&[0u8; 6],            // keyslot padding
+			&[0u8; KEYSLOT_SIZE], // empty keyslot
+		]
+		.concat()
+	}
+
+	async fn header_with_key(version: FileHeaderVersion, mk: Key, hashed_key: Key) -> FileHeader {
+		FileHeader::new(
+			version,
+			ALGORITHM,
+			vec![Keyslot::new(
+				LATEST_KEYSLOT,
+				ALGORITHM,
+				HASHING_ALGORITHM,
+				Salt::generate(),
+				hashed_key,
+				mk,
+			)
+			.await
+			.unwrap()],
+		)
+		.unwrap()
+	}
+
+	#[tokio::test]
+	async fn migrate_v1_header_fixture_to_v2() {
+		let fixture = v1_header_fixture();
+		let (header, _) = FileHeader::from_reader(&mut Cursor::new(fixture.clone()))
+			.await
+			.unwrap();
+
+		let migrated = header.migrate(FileHeaderVersion::V2).unwrap();
+		let (migrated, _) = FileHeader::from_reader(&mut Cursor::new(migrated.to_bytes().unwrap()))
+			.await
+			.unwrap();
+
+		assert!(matches!(migrated.version, FileHeaderVersion::V2));
+		assert!(migrated.algorithm == header.algorithm);
+		assert!(migrated.nonce != header.nonce);
+
+		// the keyslot is carried over byte-for-byte, so it still decrypts the same master key
+		let keyslot = FileHeader::size(FileHeaderVersion::V1);
+		assert!(migrated.keyslots.len() == 1);
+		assert_eq!(
+			migrated.keyslots[0].to_bytes(),
+			&fixture[keyslot..keyslot + KEYSLOT_SIZE]
+		);
+		assert!(migrated.keyslots[0].label.is_none());
+
+		// and labels can now be set
+		let mut migrated = migrated;
+		migrated
+			.set_keyslot_label(0, Some("migrated".to_string()))
+			.unwrap();
+	}
+
+	#[tokio::test]
+	async fn migrate_v1_file_to_v2() {
+		let mk = Key::generate();
+		let hashed_key = Key::generate(); // not hashed, but that'd be expensive
+		let header = header_with_key(FileHeaderVersion::V1, mk.clone(), hashed_key.clone()).await;
+
+		let mut file = header.to_bytes().unwrap();
+		header
+			.encrypt_detached(mk.clone(), DATA.as_ref(), &mut file)
+			.await
+			.unwrap();
+
+		let mut reader = Cursor::new(file);
+		let (header, aad) = FileHeader::from_reader(&mut reader).await.unwrap();
+
+		// only the master key is needed, and it can come from any keyslot
+		let master_key = header
+			.decrypt_master_key_from_prehashed(vec![hashed_key.clone()])
+			.await
+			.unwrap();
+
+		let mut migrated_file = Vec::new();
+		header
+			.migrate_file(
+				FileHeaderVersion::V2,
+				master_key,
+				&mut reader,
+				&mut migrated_file,
+				&aad,
+				None,
+			)
+			.await
+			.unwrap();
+
+		let mut reader = Cursor::new(migrated_file);
+		let (migrated, aad) = FileHeader::from_reader(&mut reader).await.unwrap();
+		assert!(matches!(migrated.version, FileHeaderVersion::V2));
+
+		let master_key = migrated
+			.decrypt_master_key_from_prehashed(vec![hashed_key])
+			.await
+			.unwrap();
+		assert_eq!(master_key.expose(), mk.expose());
+
+		let mut plaintext = Vec::new();
+		migrated
+			.decrypt(master_key, &mut reader, &mut plaintext, &aad)
+			.await
+			.unwrap();
+		assert_eq!(plaintext, DATA);
+	}
+
+	#[tokio::test]
+	async fn migrate_file_with_objects_and_context() {
+		let mk = Key::generate();
+		let mut header = header_with_key(FileHeaderVersion::V6, mk.clone(), Key::generate()).await;
+		header.set_aad_binding(AadBinding::Context).unwrap();
+		header
+			.put_object(mk.clone(), "thumbnail", &DATA, false)
+			.await
+			.unwrap();
+
+		let mut file = header.to_bytes().unwrap();
+		header
+			.encrypt_with_aad(mk.clone(), DATA.as_ref(), &mut file, b"file-id")
+			.await
+			.unwrap();
+
+		let mut reader = Cursor::new(file.clone());
+		let (header, aad) = FileHeader::from_reader(&mut reader).await.unwrap();
+
+		// the context is required, as the data can't be decrypted without it
+		assert!(matches!(
+			header
+				.migrate_file(
+					FileHeaderVersion::V6,
+					mk.clone(),
+					&mut reader,
+					Vec::new(),
+					&aad,
+					None
+				)
+				.await,
+			Err(Error::AadMismatch)
+		));
+
+		let mut reader = Cursor::new(file);
+		FileHeader::from_reader(&mut reader).await.unwrap();
+
+		let mut migrated_file = Vec::new();
+		header
+			.migrate_file(
+				FileHeaderVersion::V6,
+				mk.clone(),
+				&mut reader,
+				&mut migrated_file,
+				&aad,
+				Some(b"file-id"),
+			)
+			.await
+			.unwrap();
+
+		let mut reader = Cursor::new(migrated_file);
+		let (migrated, aad) = FileHeader::from_reader(&mut reader).await.unwrap();
+		assert!(migrated.aad_binding == AadBinding::Context);
+		assert_eq!(
+			migrated
+				.get_object(mk.clone(), "thumbnail")
+				.await
+				.unwrap()
+				.unwrap()
+				.expose(),
+			&DATA
+		);
+
+		let mut plaintext = Vec::new();
+		migrated
+			.decrypt_with_aad(mk, &mut reader, &mut plaintext, &aad, b"file-id")
+			.await
+			.unwrap();
+		assert_eq!(plaintext, DATA);
+	}
+
+	#[tokio::test]
+	async fn migrate_to_unsupported_version() {
+		let header = header_with_key(FileHeaderVersion::V4, Key::generate(), Key::generate()).await;
+
+		for version in [
+			FileHeaderVersion::V1,
+			FileHeaderVersion::V3,
+			FileHeaderVersion::V5,
+		] {
+			assert!(matches!(
+				header.migrate(version),
+				Err(Error::InvalidMigration)
+			));
+		}
+
+		assert!(header.migrate(FileHeaderVersion::V6).is_ok());
+
+		// V5 headers aren't followed by their data, so they can't be migrated
+		let mut header = header;
+		header.version = FileHeaderVersion::V5;
+		assert!(matches!(
+			header.migrate(FileHeaderVersion::V6),
+			Err(Error::InvalidMigration)
+		));
+	}
+}
@@ -1,8 +1,11 @@
 //! This module will contains all header related functions.
 //!
-//! It handles serialisation, deserialisation, AAD, keyslots and metadata, preview media.
+//! It handles serialisation, deserialisation, AAD, keyslots and metadata, preview media, chunk manifests, objects and migrations.
+pub mod chunks;
 pub mod file;
 pub mod keyslot;
 pub mod metadata;
+pub mod migration;
+pub mod objects;
 pub mod preview_media;
 pub mod serialization;
@@ -0,0 +1,471 @@
+//! This module contains the header objects, which are small items that are stored within a header and addressed by a logical name.
+//!
+//! They're intended for anything that belongs with a file and needs to be fetched on its own, such as thumbnails or previews.
+//!
+//! Each object is encrypted with the header's master key, and its name is used as the AAD so an object can't be moved to a different name.
+//! Object names aren't encrypted (much like keyslot labels), so they shouldn't contain anything sensitive.
+//!
+//! Only V6 headers contain objects.
+//!
+//! # Examples
+//!
+//! ```rust,ignore
+//! let mut header = FileHeader::new(FileHeaderVersion::V6, ALGORITHM, keyslots).unwrap();
+//!
+//! header
+//!     .put_object(master_key.clone(), "thumbnail", &thumbnail, false)
+//!     .await
+//!     .unwrap();
+//!
+//! let thumbnail = header.get_object(master_key, "thumbnail").await.unwrap();
+//! ```
+use tokio::io::AsyncReadExt;
+
+use crate::{
+	crypto::{Decryptor, Encryptor},
+	primitives::LATEST_HEADER_OBJECT,
+	types::{Algorithm, Key, Nonce},
+	Error, Protected, Result,
+};
+
+use super::file::{FileHeader, FileHeaderVersion};
+
+/// The maximum length of an object's name, in bytes
+pub const MAX_OBJECT_NAME_LEN: usize = 64;
+
+/// This is a header object, and it's created with `FileHeader::put_object()`.
+#[derive(Clone)]
+pub struct HeaderObject {
+	pub version: HeaderObjectVersion,
+	pub name: String,         // this isn't encrypted, but it's the AAD for the object
+	pub algorithm: Algorithm, // encryption algorithm
+	pub object_nonce: Nonce,
+	pub object: Vec<u8>,
+}
+
+#[derive(Clone, Copy)]
+pub enum HeaderObjectVersion {
+	V1,
+}
+
+impl FileHeader {
+	/// This is the maximum amount of objects that a header can hold.
+	pub const MAX_OBJECTS: usize = 16;
+
+	/// This encrypts an object with the master key, and stores it within the header under the provided name.
+	///
+	/// If an object with the same name already exists, it's replaced when `overwrite` is set. Otherwise, `Error::DuplicateObjects` is returned.
+	///
+	/// Objects are only supported by V6 headers, and their names must be between 1 and `MAX_OBJECT_NAME_LEN` bytes.
+	#[allow(clippy::needless_pass_by_value)]
+	pub async fn put_object(
+		&mut self,
+		master_key: Key,
+		name: &str,
+		object: &[u8],
+		overwrite: bool,
+	) -> Result<()> {
+		if !matches!(self.version, FileHeaderVersion::V6)
+			|| name.is_empty()
+			|| name.len() > MAX_OBJECT_NAME_LEN
+		{
+			return Err(Error::InvalidObject);
+		}
+
+		let index = self.objects.iter().position(|o| o.name == name);
+
+		match index {
+			Some(_) if !overwrite => return Err(Error::DuplicateObjects),
+			None if self.objects.len() >= Self::MAX_OBJECTS => return Err(Error::TooManyObjects),
+			_ => {}
+		}
+
+		let object_nonce = self.nonce_tracker()?.generate(self.algorithm)?;
+
+		let encrypted_object = Encryptor::encrypt_bytes(
+			master_key,
+			object_nonce,
+			self.algorithm,
+			object,
+			name.as_bytes(),
+		)
+		.await?;
+
+		let object = HeaderObject {
+			version: LATEST_HEADER_OBJECT,
+			name: name.to_string(),
+			algorithm: self.algorithm,
+			object_nonce,
+			object: encrypted_object,
+		};
+
+		match index {
+			Some(index) => self.objects[index] = object,
+			None => self.objects.push(object),
+		}
+
+		Ok(())
+	}
+
+	/// This decrypts the object with the provided name, using the master key.
+	///
+	/// `None` is returned if the header doesn't contain an object with that name.
+	#[allow(clippy::needless_pass_by_value)]
+	pub async fn get_object(
+		&self,
+		master_key: Key,
+		name: &str,
+	) -> Result<Option<Protected<Vec<u8>>>> {
+		match self.objects.iter().find(|o| o.name == name) {
+			Some(object) => Decryptor::decrypt_bytes(
+				master_key,
+				object.object_nonce,
+				object.algorithm,
+				&object.object,
+				object.name.as_bytes(),
+			)
+			.await
+			.map(Some),
+			None => Ok(None),
+		}
+	}
+
+	/// This returns the names of every object within the header, in the order they were added.
+	///
+	/// It doesn't require the master key, as object names aren't encrypted.
+	#[must_use]
+	pub fn list_objects(&self) -> Vec<String> {
+		self.objects.iter().map(|o| o.name.clone()).collect()
+	}
+
+	/// This removes the object with the provided name from the header.
+	///
+	/// You receive `Error::NoObjects` if the header doesn't contain an object with that name.
+	pub fn remove_object(&mut self, name: &str) -> Result<()> {
+		let index = self
+			.objects
+			.iter()
+			.position(|o| o.name == name)
+			.ok_or(Error::NoObjects)?;
+
+		self.objects.remove(index);
+
+		Ok(())
+	}
+
+	/// This serializes the objects (prefixed with the amount of objects), and it's only used by V6 headers.
+	pub(super) fn objects_to_bytes(&self) -> Vec<u8> {
+		#[allow(clippy::cast_possible_truncation)]
+		let len = self.objects.len() as u8;
+
+		[len]
+			.into_iter()
+			.chain(self.objects.iter().flat_map(HeaderObject::to_bytes))
+			.collect()
+	}
+
+	/// This reads the objects that were written with `objects_to_bytes()`.
+	pub(super) async fn objects_from_reader<R>(reader: &mut R) -> Result<Vec<HeaderObject>>
+	where
+		R: AsyncReadExt + Unpin + Send,
+	{
+		let mut len = [0u8; 1];
+		reader.read_exact(&mut len).await?;
+
+		let len = len[0] as usize;
+		if len > Self::MAX_OBJECTS {
+			return Err(Error::Serialization);
+		}
+
+		let mut objects: Vec<HeaderObject> = Vec::with_capacity(len);
+
+		for _ in 0..len {
+			let object = HeaderObject::from_reader(reader).await?;
+
+			if objects.iter().any(|o| o.name == object.name) {
+				return Err(Error::Serialization);
+			}
+
+			objects.push(object);
+		}
+
+		Ok(objects)
+	}
+}
+
+impl HeaderObject {
+	#[must_use]
+	pub fn size(&self) -> usize {
+		self.to_bytes().len()
+	}
+
+	/// This function is used to serialize a header object into bytes
+	///
+	/// This also includes the encrypted object itself, so this may be sizeable
+	#[must_use]
+	pub fn to_bytes(&self) -> Vec<u8> {
+		match self.version {
+			HeaderObjectVersion::V1 => {
+				#[allow(clippy::cast_possible_truncation)]
+				let name_len = self.name.len() as u8;
+
+				[
+					self.version.to_bytes().as_ref(),
+					self.algorithm.to_bytes().as_ref(),
+					&self.object_nonce,
+					&vec![0u8; 24 - self.object_nonce.len()],
+					&[name_len],
+					self.name.as_bytes(),
+					&(self.object.len() as u64).to_le_bytes(),
+					&self.object,
+				]
+				.into_iter()
+				.flatten()
+				.copied()
+				.collect()
+			}
+		}
+	}
+
+	/// This function reads a header object from a reader
+	///
+	/// The cursor will be left at the end of the header object on success
+	///
+	/// The cursor will not be rewound on error.
+	pub async fn from_reader<R>(reader: &mut R) -> Result<Self>
+	where
+		R: AsyncReadExt + Unpin + Send,
+	{
+		let mut version = [0u8; 2];
+		reader.read_exact(&mut version).await?;
+		let version = HeaderObjectVersion::from_bytes(version)?;
+
+		match version {
+			HeaderObjectVersion::V1 => {
+				let mut algorithm = [0u8; 2];
+				reader.read_exact(&mut algorithm).await?;
+				let algorithm = Algorithm::from_bytes(algorithm)?;
+
+				let mut object_nonce = vec![0u8; algorithm.nonce_len()];
+				reader.read_exact(&mut object_nonce).await?;
+				let object_nonce = Nonce::try_from(object_nonce)?;
+
+				reader
+					.read_exact(&mut vec![0u8; 24 - object_nonce.len()])
+					.await?;
+
+				let mut name_len = [0u8; 1];
+				reader.read_exact(&mut name_len).await?;
+
+				let name_len = name_len[0] as usize;
+				if name_len == 0 || name_len > MAX_OBJECT_NAME_LEN {
+					return Err(Error::Serialization);
+				}
+
+				let mut name = vec![0u8; name_len];
+				reader.read_exact(&mut name).await?;
+				let name = String::from_utf8(name).map_err(|_| Error::Serialization)?;
+
+				let mut object_length = [0u8; 8];
+				reader.read_exact(&mut object_length).await?;
+
+				let object_length = u64::from_le_bytes(object_length);
+
+				#[allow(clippy::cast_possible_truncation)]
+				let mut object = vec![0u8; object_length as usize];
+				reader.read_exact(&mut object).await?;
+
+				let header_object = Self {
+					version,
+					name,
+					algorithm,
+					object_nonce,
+					object,
+				};
+
+				Ok(header_object)
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Cursor;
+
+	use crate::{
+		header::keyslot::Keyslot,
+		primitives::LATEST_KEYSLOT,
+		types::{HashingAlgorithm, Params, Salt},
+	};
+
+	use super::*;
+
+	const ALGORITHM: Algorithm = Algorithm::XChaCha20Poly1305;
+	const HASHING_ALGORITHM: HashingAlgorithm = HashingAlgorithm::Argon2id(Params::Standard);
+
+	async fn header_with_key(mk: Key) -> FileHeader {
+		FileHeader::new(
+			FileHeaderVersion::V6,
+			ALGORITHM,
+			vec![Keyslot::new(
+				LATEST_KEYSLOT,
+				ALGORITHM,
+				HASHING_ALGORITHM,
+				Salt::generate(),
+				Key::generate(),
+				mk,
+			)
+			.await
+			.unwrap()],
+		)
+		.unwrap()
+	}
+
+	#[tokio::test]
+	async fn put_and_get_objects() {
+		let mk = Key::generate();
+		let mut header = header_with_key(mk.clone()).await;
+
+		assert!(header
+			.get_object(mk.clone(), "thumbnail")
+			.await
+			.unwrap()
+			.is_none());
+
+		header
+			.put_object(mk.clone(), "thumbnail", b"a nice mountain", false)
+			.await
+			.unwrap();
+		header
+			.put_object(mk.clone(), "preview", b"a nicer mountain", false)
+			.await
+			.unwrap();
+
+		assert!(matches!(
+			header
+				.put_object(mk.clone(), "thumbnail", b"a different mountain", false)
+				.await,
+			Err(Error::DuplicateObjects)
+		));
+
+		header
+			.put_object(mk.clone(), "thumbnail", b"a different mountain", true)
+			.await
+			.unwrap();
+
+		assert_eq!(header.list_objects(), vec!["thumbnail", "preview"]);
+		assert_eq!(
+			header
+				.get_object(mk.clone(), "thumbnail")
+				.await
+				.unwrap()
+				.unwrap()
+				.expose(),
+			b"a different mountain"
+		);
+
+		header.remove_object("preview").unwrap();
+		assert!(matches!(
+			header.remove_object("preview"),
+			Err(Error::NoObjects)
+		));
+		assert_eq!(header.list_objects(), vec!["thumbnail"]);
+	}
+
+	#[tokio::test]
+	async fn serialize_and_deserialize_objects() {
+		let mk = Key::generate();
+		let mut header = header_with_key(mk.clone()).await;
+
+		header
+			.put_object(mk.clone(), "thumbnail", b"a nice mountain", false)
+			.await
+			.unwrap();
+
+		let mut reader = Cursor::new(header.to_bytes().unwrap());
+		let (header, _) = FileHeader::from_reader(&mut reader).await.unwrap();
+
+		assert_eq!(header.list_objects(), vec!["thumbnail"]);
+		assert_eq!(
+			header
+				.get_object(mk, "thumbnail")
+				.await
+				.unwrap()
+				.unwrap()
+				.expose(),
+			b"a nice mountain"
+		);
+	}
+
+	#[tokio::test]
+	async fn objects_are_bound_to_their_name() {
+		let mk = Key::generate();
+		let mut header = header_with_key(mk.clone()).await;
+
+		header
+			.put_object(mk.clone(), "thumbnail", b"a nice mountain", false)
+			.await
+			.unwrap();
+
+		header.objects[0].name = "preview".to_string();
+
+		assert!(header.get_object(mk, "preview").await.is_err());
+	}
+
+	#[tokio::test]
+	async fn put_too_many_objects() {
+		let mk = Key::generate();
+		let mut header = header_with_key(mk.clone()).await;
+
+		for i in 0..FileHeader::MAX_OBJECTS {
+			header
+				.put_object(mk.clone(), &format!("object {i}"), &[], false)
+				.await
+				.unwrap();
+		}
+
+		assert!(matches!(
+			header.put_object(mk.clone(), "one more", &[], false).await,
+			Err(Error::TooManyObjects)
+		));
+
+		// replacing an object doesn't need a free slot
+		header
+			.put_object(mk, "object 0", b"replaced", true)
+			.await
+			.unwrap();
+	}
+
+	#[tokio::test]
+	async fn put_invalid_objects() {
+		let mk = Key::generate();
+		let mut header = header_with_key(mk.clone()).await;
+
+		assert!(matches!(
+			header.put_object(mk.clone(), "", &[], false).await,
+			Err(Error::InvalidObject)
+		));
+		assert!(matches!(
+			header
+				.put_object(mk.clone(), &"a".repeat(MAX_OBJECT_NAME_LEN + 1), &[], false)
+				.await,
+			Err(Error::InvalidObject)
+		));
+
+		// only V6 headers have somewhere to store objects
+		header.version = FileHeaderVersion::V4;
+		assert!(matches!(
+			header.put_object(mk.clone(), "thumbnail", &[], false).await,
+			Err(Error::InvalidObject)
+		));
+
+		header.version = FileHeaderVersion::V6;
+		header
+			.put_object(mk, "thumbnail", &[], false)
+			.await
+			.unwrap();
+		header.version = FileHeaderVersion::V4;
+		assert!(matches!(header.to_bytes(), Err(Error::InvalidObject)));
+	}
+}
@@ -64,10 +64,11 @@ impl FileHeader {
 		master_key: Key,
 		media: &[u8],
 	) -> Result<()> {
-		let media_nonce = Nonce::generate(algorithm)?;
+		let media_nonce = self.nonce_tracker()?.generate(algorithm)?;
 
 		let encrypted_media =
-			Encryptor::encrypt_bytes(master_key, media_nonce, algorithm, media, &[]).await?;
+			Encryptor::encrypt_async(master_key, media_nonce, algorithm, media.to_vec(), &[])
+				.await?;
 
 		self.preview_media = Some(PreviewMedia {
 			version,
@@ -91,11 +92,11 @@ impl FileHeader {
 		let master_key = self.decrypt_master_key(password).await?;
 
 		if let Some(pvm) = self.preview_media.as_ref() {
-			let pvm = Decryptor::decrypt_bytes(
+			let pvm = Decryptor::decrypt_async(
 				master_key,
 				pvm.media_nonce,
 				pvm.algorithm,
-				&pvm.media,
+				pvm.media.clone(),
 				&[],
 			)
 			.await?;
@@ -118,11 +119,11 @@ impl FileHeader {
 		let master_key = self.decrypt_master_key_from_prehashed(hashed_keys).await?;
 
 		if let Some(pvm) = self.preview_media.as_ref() {
-			let pvm = Decryptor::decrypt_bytes(
+			let pvm = Decryptor::decrypt_async(
 				master_key,
 				pvm.media_nonce,
 				pvm.algorithm,
-				&pvm.media,
+				pvm.media.clone(),
 				&[],
 			)
 			.await?;
@@ -4,12 +4,17 @@
 use std::fmt::Display;
 
 use crate::{
-	types::{Algorithm, HashingAlgorithm, Params},
+	primitives::HASHING_PARAMS_LEN,
+	types::{Algorithm, HashingAlgorithm, HashingParams, Params},
 	Error, Result,
 };
 
 use super::{
-	file::FileHeaderVersion, keyslot::KeyslotVersion, metadata::MetadataVersion,
+	chunks::ChunkManifestVersion,
+	file::{AadBinding, FileHeaderVersion},
+	keyslot::KeyslotVersion,
+	metadata::MetadataVersion,
+	objects::HeaderObjectVersion,
 	preview_media::PreviewMediaVersion,
 };
 
@@ -18,12 +23,27 @@ impl FileHeaderVersion {
 	pub const fn to_bytes(&self) -> [u8; 2] {
 		match self {
 			Self::V1 => [0x0A, 0x01],
+			Self::V2 => [0x0A, 0x02],
+			Self::V3 => [0x0A, 0x03],
+			Self::V4 => [0x0A, 0x04],
+			Self::V5 => [0x0A, 0x05],
+			Self::V6 => [0x0A, 0x06],
 		}
 	}
 
+	/// The first byte identifies a file header version, so an unknown second byte means the header was created by a newer build.
 	pub const fn from_bytes(bytes: [u8; 2]) -> Result<Self> {
 		match bytes {
 			[0x0A, 0x01] => Ok(Self::V1),
+			[0x0A, 0x02] => Ok(Self::V2),
+			[0x0A, 0x03] => Ok(Self::V3),
+			[0x0A, 0x04] => Ok(Self::V4),
+			[0x0A, 0x05] => Ok(Self::V5),
+			[0x0A, 0x06] => Ok(Self::V6),
+			[0x0A, _] => Err(Error::UnsupportedHeaderVersion {
+				found: u16::from_be_bytes(bytes),
+				supported: u16::from_be_bytes(Self::V6.to_bytes()),
+			}),
 			_ => Err(Error::Serialization),
 		}
 	}
@@ -33,6 +53,38 @@ impl Display for FileHeaderVersion {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 		match *self {
 			Self::V1 => write!(f, "V1"),
+			Self::V2 => write!(f, "V2"),
+			Self::V3 => write!(f, "V3"),
+			Self::V4 => write!(f, "V4"),
+			Self::V5 => write!(f, "V5"),
+			Self::V6 => write!(f, "V6"),
+		}
+	}
+}
+
+impl AadBinding {
+	#[must_use]
+	pub const fn to_bytes(&self) -> [u8; 2] {
+		match self {
+			Self::Header => [0x0C, 0x00],
+			Self::Context => [0x0C, 0x01],
+		}
+	}
+
+	pub const fn from_bytes(bytes: [u8; 2]) -> Result<Self> {
+		match bytes {
+			[0x0C, 0x00] => Ok(Self::Header),
+			[0x0C, 0x01] => Ok(Self::Context),
+			_ => Err(Error::Serialization),
+		}
+	}
+}
+
+impl Display for AadBinding {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match *self {
+			Self::Header => write!(f, "Header"),
+			Self::Context => write!(f, "Context"),
 		}
 	}
 }
@@ -85,6 +137,54 @@ impl Display for PreviewMediaVersion {
 	}
 }
 
+impl ChunkManifestVersion {
+	#[must_use]
+	pub const fn to_bytes(&self) -> [u8; 2] {
+		match self {
+			Self::V1 => [0x0F, 0x01],
+		}
+	}
+
+	pub const fn from_bytes(bytes: [u8; 2]) -> Result<Self> {
+		match bytes {
+			[0x0F, 0x01] => Ok(Self::V1),
+			_ => Err(Error::Serialization),
+		}
+	}
+}
+
+impl Display for ChunkManifestVersion {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match *self {
+			Self::V1 => write!(f, "V1"),
+		}
+	}
+}
+
+impl HeaderObjectVersion {
+	#[must_use]
+	pub const fn to_bytes(&self) -> [u8; 2] {
+		match self {
+			Self::V1 => [0x10, 0x01],
+		}
+	}
+
+	pub const fn from_bytes(bytes: [u8; 2]) -> Result<Self> {
+		match bytes {
+			[0x10, 0x01] => Ok(Self::V1),
+			_ => Err(Error::Serialization),
+		}
+	}
+}
+
+impl Display for HeaderObjectVersion {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match *self {
+			Self::V1 => write!(f, "V1"),
+		}
+	}
+}
+
 impl MetadataVersion {
 	#[must_use]
 	pub const fn to_bytes(&self) -> [u8; 2] {
@@ -117,28 +217,74 @@ impl HashingAlgorithm {
 				Params::Standard => [0xA2, 0x01],
 				Params::Hardened => [0xA2, 0x02],
 				Params::Paranoid => [0xA2, 0x03],
+				Params::Custom(_) => [0xA2, 0x04],
 			},
 			Self::BalloonBlake3(p) => match p {
 				Params::Standard => [0xB3, 0x01],
 				Params::Hardened => [0xB3, 0x02],
 				Params::Paranoid => [0xB3, 0x03],
+				Params::Custom(_) => [0xB3, 0x04],
 			},
 		}
 	}
 
-	pub const fn from_bytes(bytes: [u8; 2]) -> Result<Self> {
+	/// This returns the serialized custom parameters, or zeroes if a preset is being used.
+	#[must_use]
+	pub fn params_to_bytes(&self) -> [u8; HASHING_PARAMS_LEN] {
+		match self {
+			Self::Argon2id(Params::Custom(p)) | Self::BalloonBlake3(Params::Custom(p)) => {
+				p.to_bytes()
+			}
+			_ => [0u8; HASHING_PARAMS_LEN],
+		}
+	}
+
+	/// The custom parameters are only read if the bytes specify them, so they may be left as zeroes otherwise.
+	pub fn from_bytes(bytes: [u8; 2], params: [u8; HASHING_PARAMS_LEN]) -> Result<Self> {
 		match bytes {
 			[0xA2, 0x01] => Ok(Self::Argon2id(Params::Standard)),
 			[0xA2, 0x02] => Ok(Self::Argon2id(Params::Hardened)),
 			[0xA2, 0x03] => Ok(Self::Argon2id(Params::Paranoid)),
+			[0xA2, 0x04] => Ok(Self::Argon2id(Params::Custom(HashingParams::from_bytes(
+				params,
+			)?))),
 			[0xB3, 0x01] => Ok(Self::BalloonBlake3(Params::Standard)),
 			[0xB3, 0x02] => Ok(Self::BalloonBlake3(Params::Hardened)),
 			[0xB3, 0x03] => Ok(Self::BalloonBlake3(Params::Paranoid)),
+			[0xB3, 0x04] => Ok(Self::BalloonBlake3(Params::Custom(
+				HashingParams::from_bytes(params)?,
+			))),
 			_ => Err(Error::Serialization),
 		}
 	}
 }
 
+impl HashingParams {
+	/// `t_cost` and `p_cost` are stored as single bytes, as their maximums (`MAX_T_COST` and `MAX_P_COST`) are well below `u8::MAX`.
+	#[must_use]
+	#[allow(clippy::cast_possible_truncation)]
+	pub const fn to_bytes(&self) -> [u8; HASHING_PARAMS_LEN] {
+		let m_cost = self.m_cost.to_le_bytes();
+		[
+			m_cost[0],
+			m_cost[1],
+			m_cost[2],
+			m_cost[3],
+			self.t_cost as u8,
+			self.p_cost as u8,
+		]
+	}
+
+	/// The deserialized parameters are validated, so `Error::PasswordHash` will be returned if they're out of range.
+	pub fn from_bytes(bytes: [u8; HASHING_PARAMS_LEN]) -> Result<Self> {
+		Self::new(
+			u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+			bytes[4].into(),
+			bytes[5].into(),
+		)
+	}
+}
+
 impl Display for HashingAlgorithm {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 		match *self {
@@ -154,6 +300,7 @@ impl Display for Params {
 			Self::Standard => write!(f, "Standard"),
 			Self::Hardened => write!(f, "Hardened"),
 			Self::Paranoid => write!(f, "Paranoid"),
+			Self::Custom(_) => write!(f, "Custom"),
 		}
 	}
 }
@@ -167,10 +314,12 @@ impl Algorithm {
 		}
 	}
 
+	/// The first byte identifies an encryption algorithm, so an unknown second byte means the algorithm isn't supported by this build.
 	pub const fn from_bytes(bytes: [u8; 2]) -> Result<Self> {
 		match bytes {
 			[0x0B, 0x01] => Ok(Self::XChaCha20Poly1305),
 			[0x0B, 0x02] => Ok(Self::Aes256Gcm),
+			[0x0B, _] => Err(Error::UnsupportedAlgorithm),
 			_ => Err(Error::Serialization),
 		}
 	}
@@ -0,0 +1,162 @@
+//! This module contains the format used for password-encrypted key backups.
+//!
+//! A backup starts with the magic bytes, the backup version, the encryption algorithm, the hashing algorithm (with any custom parameters), the salt and the nonce.
+//!
+//! This is followed by the encrypted payload, and everything before it is authenticated as AAD.
+//!
+//! The payload contains the root key, followed by the stored keys (which are still encrypted with the root key).
+//! This makes backups self-contained, so only the backup password is needed to import them.
+//!
+//! Backups should be created with `KeyManager::export_keys()`, and imported with `KeyManager::import_keys()`.
+use std::io::{Cursor, Read};
+
+use crate::{
+	crypto::{Decryptor, Encryptor},
+	primitives::{to_array, HASHING_PARAMS_LEN, KEY_BACKUP_CONTEXT, KEY_LEN, SALT_LEN},
+	types::{Algorithm, HashingAlgorithm, Key, Nonce, Salt},
+	Error, Protected, Result,
+};
+
+use super::keymanager::StoredKey;
+
+/// These are the magic bytes that every key backup starts with.
+pub const KEY_BACKUP_MAGIC: [u8; 7] = [0x73, 0x64, 0x6B, 0x65, 0x79, 0x62, 0x6B];
+
+/// This denotes the key backup version.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum KeyBackupVersion {
+	V1,
+}
+
+/// Defines the latest `KeyBackupVersion`
+pub const LATEST_KEY_BACKUP: KeyBackupVersion = KeyBackupVersion::V1;
+
+impl KeyBackupVersion {
+	#[must_use]
+	pub const fn to_bytes(&self) -> [u8; 2] {
+		match self {
+			Self::V1 => [0x2A, 0x01],
+		}
+	}
+
+	/// The first byte identifies a key backup version, so an unknown second byte means the backup was created by a newer build.
+	pub const fn from_bytes(bytes: [u8; 2]) -> Result<Self> {
+		match bytes {
+			[0x2A, 0x01] => Ok(Self::V1),
+			[0x2A, _] => Err(Error::UnsupportedHeaderVersion {
+				found: u16::from_be_bytes(bytes),
+				supported: u16::from_be_bytes(LATEST_KEY_BACKUP.to_bytes()),
+			}),
+			_ => Err(Error::Serialization),
+		}
+	}
+}
+
+/// This function encrypts the root key and stored keys with a key derived from the password, and returns the backup.
+///
+/// The payload is assembled in memory, so no plaintext ever touches the disk.
+#[allow(clippy::needless_pass_by_value)]
+pub async fn encrypt_backup(
+	root_key: &Key,
+	keys: &[StoredKey],
+	password: Protected<Vec<u8>>,
+	algorithm: Algorithm,
+	hashing_algorithm: HashingAlgorithm,
+) -> Result<Vec<u8>> {
+	let salt = Salt::generate();
+	let nonce = Nonce::generate(algorithm)?;
+
+	let aad = [
+		KEY_BACKUP_MAGIC.as_ref(),
+		&LATEST_KEY_BACKUP.to_bytes(),
+		&algorithm.to_bytes(),
+		&hashing_algorithm.to_bytes(),
+		&hashing_algorithm.params_to_bytes(),
+		&salt.0,
+		&nonce,
+	]
+	.concat();
+
+	let key = Key::derive(
+		hashing_algorithm.hash(password, salt, None)?,
+		salt,
+		KEY_BACKUP_CONTEXT,
+	);
+
+	let plaintext = Protected::new(
+		[
+			root_key.expose().as_slice(),
+			serde_json::to_vec(keys)?.as_slice(),
+		]
+		.concat(),
+	);
+	let encrypted_keys =
+		Encryptor::encrypt_bytes(key, nonce, algorithm, plaintext.expose(), &aad).await?;
+
+	Ok([aad, encrypted_keys].concat())
+}
+
+/// This function decrypts a backup created with `encrypt_backup()`, and returns the root key and stored keys within it.
+///
+/// It returns `Error::IncorrectPassword` if the password is wrong, or if the backup has been tampered with.
+#[allow(clippy::needless_pass_by_value)]
+pub async fn decrypt_backup(
+	backup: &[u8],
+	password: Protected<Vec<u8>>,
+) -> Result<(Key, Vec<StoredKey>)> {
+	let mut reader = Cursor::new(backup);
+
+	let mut magic_bytes = [0u8; KEY_BACKUP_MAGIC.len()];
+	reader.read_exact(&mut magic_bytes)?;
+
+	if magic_bytes != KEY_BACKUP_MAGIC {
+		return Err(Error::Serialization);
+	}
+
+	let mut version = [0u8; 2];
+	reader.read_exact(&mut version)?;
+
+	match KeyBackupVersion::from_bytes(version)? {
+		KeyBackupVersion::V1 => {
+			let mut algorithm = [0u8; 2];
+			reader.read_exact(&mut algorithm)?;
+			let algorithm = Algorithm::from_bytes(algorithm)?;
+
+			let mut hashing_algorithm = [0u8; 2];
+			reader.read_exact(&mut hashing_algorithm)?;
+			let mut hashing_params = [0u8; HASHING_PARAMS_LEN];
+			reader.read_exact(&mut hashing_params)?;
+			let hashing_algorithm =
+				HashingAlgorithm::from_bytes(hashing_algorithm, hashing_params)?;
+
+			let mut salt = [0u8; SALT_LEN];
+			reader.read_exact(&mut salt)?;
+			let salt = Salt(salt);
+
+			let mut nonce = vec![0u8; algorithm.nonce_len()];
+			reader.read_exact(&mut nonce)?;
+			let nonce = Nonce::try_from(nonce)?;
+
+			#[allow(clippy::cast_possible_truncation)]
+			let (aad, encrypted_keys) = backup.split_at(reader.position() as usize);
+
+			let key = Key::derive(
+				hashing_algorithm.hash(password, salt, None)?,
+				salt,
+				KEY_BACKUP_CONTEXT,
+			);
+
+			let plaintext = Decryptor::decrypt_bytes(key, nonce, algorithm, encrypted_keys, aad)
+				.await
+				.map_err(|_| Error::IncorrectPassword)?;
+
+			if plaintext.expose().len() < KEY_LEN {
+				return Err(Error::Serialization);
+			}
+
+			let (root_key, keys) = plaintext.expose().split_at(KEY_LEN);
+
+			Ok((Key::new(to_array(root_key)?), serde_json::from_slice(keys)?))
+		}
+	}
+}
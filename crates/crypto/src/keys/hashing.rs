@@ -2,6 +2,9 @@
 //!
 //! Everything contained within is used to hash a user's password into strong key material, suitable for encrypting master keys.
 //!
+//! Hashing is expensive by design (it takes hundreds of milliseconds, and up to 512MiB of memory with `Params::Paranoid`).
+//! `HashingAlgorithm::hash_async()` should be used from async tasks, so that the runtime isn't stalled while hashing.
+//!
 //! # Examples
 //!
 //! ```rust,ignore
@@ -12,12 +15,13 @@
 //! ```
 
 use crate::{
-	primitives::KEY_LEN,
-	types::{HashingAlgorithm, Key, Params, Salt, SecretKey},
+	primitives::{KEY_LEN, MAX_M_COST, MAX_P_COST, MAX_T_COST, MIN_M_COST},
+	types::{HashingAlgorithm, HashingParams, Key, Params, Salt, SecretKey},
 	Error, Protected, Result,
 };
 use argon2::Argon2;
 use balloon_hash::Balloon;
+use zeroize::Zeroize;
 
 impl HashingAlgorithm {
 	/// This function should be used to hash passwords. It handles all appropriate parameters, and uses hashing with a secret key (if provided).
@@ -35,33 +39,103 @@ impl HashingAlgorithm {
 			}
 		}
 	}
+
+	/// This is the same as `hash()`, but the password is hashed on tokio's blocking thread pool.
+	///
+	/// This should be used instead of `hash()` from async tasks.
+	pub async fn hash_async(
+		&self,
+		password: Protected<Vec<u8>>,
+		salt: Salt,
+		secret: Option<SecretKey>,
+	) -> Result<Key> {
+		let algorithm = *self;
+
+		tokio::task::spawn_blocking(move || algorithm.hash(password, salt, secret))
+			.await
+			.map_err(std::io::Error::from)?
+	}
+}
+
+impl HashingParams {
+	/// This function is used to create custom parameters for password hashing.
+	///
+	/// It will return `Error::PasswordHash` if any of the parameters are out of range.
+	pub fn new(m_cost: u32, t_cost: u32, p_cost: u32) -> Result<Self> {
+		let params = Self {
+			m_cost,
+			t_cost,
+			p_cost,
+		};
+
+		params.validate()?;
+		Ok(params)
+	}
+
+	/// This function checks that all of the parameters are within range.
+	pub fn validate(&self) -> Result<()> {
+		if (MIN_M_COST..=MAX_M_COST).contains(&self.m_cost)
+			&& (1..=MAX_T_COST).contains(&self.t_cost)
+			&& (1..=MAX_P_COST).contains(&self.p_cost)
+		{
+			Ok(())
+		} else {
+			Err(Error::PasswordHash)
+		}
+	}
+}
+
+impl Default for HashingAlgorithm {
+	fn default() -> Self {
+		Self::Argon2id(Params::recommended())
+	}
 }
 
 impl Params {
-	/// This function is used to generate parameters for password hashing.
+	/// This returns the preset that should be used if the user hasn't chosen one.
 	///
-	/// This should not be called directly. Call it via the `HashingAlgorithm` struct (e.g. `HashingAlgorithm::Argon2id(Params::Standard).hash()`)
+	/// Mobile devices have far less memory to spare (and will kill an app that uses too much), so they use `Standard`. Everything else uses `Hardened`.
 	#[must_use]
-	pub fn argon2id(&self) -> argon2::Params {
-		match self {
-			// We can use `.unwrap()` here as the values are hardcoded, and this shouldn't error
-			Self::Standard => argon2::Params::new(131_072, 8, 4, None).unwrap(),
-			Self::Hardened => argon2::Params::new(262_144, 8, 4, None).unwrap(),
-			Self::Paranoid => argon2::Params::new(524_288, 8, 4, None).unwrap(),
+	pub const fn recommended() -> Self {
+		if cfg!(any(target_os = "ios", target_os = "android")) {
+			Self::Standard
+		} else {
+			Self::Hardened
 		}
 	}
 
+	/// This function is used to generate parameters for password hashing.
+	///
+	/// This should not be called directly. Call it via the `HashingAlgorithm` struct (e.g. `HashingAlgorithm::Argon2id(Params::Standard).hash()`)
+	pub fn argon2id(&self) -> Result<argon2::Params> {
+		let (m_cost, t_cost, p_cost) = match self {
+			Self::Standard => (131_072, 8, 4),
+			Self::Hardened => (262_144, 8, 4),
+			Self::Paranoid => (524_288, 8, 4),
+			Self::Custom(params) => {
+				params.validate()?;
+				(params.m_cost, params.t_cost, params.p_cost)
+			}
+		};
+
+		argon2::Params::new(m_cost, t_cost, p_cost, None).map_err(|_| Error::PasswordHash)
+	}
+
 	/// This function is used to generate parameters for password hashing.
 	///
 	/// This should not be called directly. Call it via the `HashingAlgorithm` struct (e.g. `HashingAlgorithm::BalloonBlake3(Params::Standard).hash()`)
-	#[must_use]
-	pub fn balloon_blake3(&self) -> balloon_hash::Params {
-		match self {
-			// We can use `.unwrap()` here as the values are hardcoded, and this shouldn't error
-			Self::Standard => balloon_hash::Params::new(131_072, 2, 1).unwrap(),
-			Self::Hardened => balloon_hash::Params::new(262_144, 2, 1).unwrap(),
-			Self::Paranoid => balloon_hash::Params::new(524_288, 2, 1).unwrap(),
-		}
+	pub fn balloon_blake3(&self) -> Result<balloon_hash::Params> {
+		let (s_cost, t_cost, p_cost) = match self {
+			Self::Standard => (131_072, 2, 1),
+			Self::Hardened => (262_144, 2, 1),
+			Self::Paranoid => (524_288, 2, 1),
+			Self::Custom(params) => {
+				params.validate()?;
+				(params.m_cost, params.t_cost, params.p_cost)
+			}
+		};
+
+		balloon_hash::Params::new(s_cost, t_cost, p_cost).map_err(|_| Error::PasswordHash)
 	}
 }
 
@@ -82,13 +156,16 @@ impl PasswordHasher {
 			secret.expose(),
 			argon2::Algorithm::Argon2id,
 			argon2::Version::V0x13,
-			params.argon2id(),
+			params.argon2id()?,
 		)
 		.map_err(|_| Error::PasswordHash)?;
 
-		argon2
+		let hashed_key = argon2
 			.hash_password_into(password.expose(), &salt, &mut key)
-			.map_or(Err(Error::PasswordHash), |_| Ok(Key::new(key)))
+			.map_or(Err(Error::PasswordHash), |_| Ok(Key::new(key)));
+
+		key.zeroize();
+		hashed_key
 	}
 
 	#[allow(clippy::needless_pass_by_value)]
@@ -104,13 +181,16 @@ impl PasswordHasher {
 
 		let balloon = Balloon::<blake3::Hasher>::new(
 			balloon_hash::Algorithm::Balloon,
-			params.balloon_blake3(),
+			params.balloon_blake3()?,
 			Some(secret.expose()),
 		);
 
-		balloon
+		let hashed_key = balloon
 			.hash_into(password.expose(), &salt, &mut key)
-			.map_or(Err(Error::PasswordHash), |_| Ok(Key::new(key)))
+			.map_or(Err(Error::PasswordHash), |_| Ok(Key::new(key)));
+
+		key.zeroize();
+		hashed_key
 	}
 }
 
@@ -230,6 +310,16 @@ mod tests {
 		assert_eq!(&HASH_ARGON2ID_WITH_SECRET_EXPECTED[0], output.expose());
 	}
 
+	#[tokio::test]
+	async fn hash_argon2id_standard_async() {
+		let output = ARGON2ID_STANDARD
+			.hash_async(PASSWORD.to_vec().into(), SALT, Some(SECRET_KEY))
+			.await
+			.unwrap();
+
+		assert_eq!(&HASH_ARGON2ID_WITH_SECRET_EXPECTED[0], output.expose());
+	}
+
 	#[test]
 	fn hash_argon2id_hardened() {
 		let output = ARGON2ID_HARDENED
@@ -320,6 +410,62 @@ mod tests {
 		assert_eq!(&HASH_B3BALLOON_WITH_SECRET_EXPECTED[2], output.expose());
 	}
 
+	#[test]
+	fn hash_argon2id_custom() {
+		// these are the same as the standard params
+		let params = HashingParams::new(131_072, 8, 4).unwrap();
+
+		let output = HashingAlgorithm::Argon2id(Params::Custom(params))
+			.hash(PASSWORD.to_vec().into(), SALT, None)
+			.unwrap();
+
+		assert_eq!(&HASH_ARGON2ID_EXPECTED[0], output.expose());
+	}
+
+	#[test]
+	fn custom_params_out_of_range() {
+		assert!(matches!(
+			HashingParams::new(MIN_M_COST - 1, 8, 4),
+			Err(Error::PasswordHash)
+		));
+		assert!(matches!(
+			HashingParams::new(MIN_M_COST, 0, 4),
+			Err(Error::PasswordHash)
+		));
+		assert!(matches!(
+			HashingParams::new(MIN_M_COST, 8, MAX_P_COST + 1),
+			Err(Error::PasswordHash)
+		));
+
+		// the fields are public, so they must also be validated before hashing
+		let params = HashingParams {
+			m_cost: MAX_M_COST + 1,
+			t_cost: 8,
+			p_cost: 4,
+		};
+
+		assert!(matches!(
+			HashingAlgorithm::Argon2id(Params::Custom(params)).hash(
+				PASSWORD.to_vec().into(),
+				SALT,
+				None
+			),
+			Err(Error::PasswordHash)
+		));
+	}
+
+	#[test]
+	fn recommended_params() {
+		let expected = if cfg!(any(target_os = "ios", target_os = "android")) {
+			Params::Standard
+		} else {
+			Params::Hardened
+		};
+
+		assert!(Params::recommended() == expected);
+		assert!(HashingAlgorithm::default() == HashingAlgorithm::Argon2id(expected));
+	}
+
 	#[test]
 	fn derive_b3() {
 		let output = Key::derive(KEY, SALT, TEST_CONTEXT);
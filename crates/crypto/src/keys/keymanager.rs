@@ -35,27 +35,41 @@
 //! let keys = key_manager.enumerate_hashed_keys();
 //! ```
 
-use std::sync::Arc;
-
-use tokio::sync::Mutex;
+use std::{path::Path, sync::Arc, time::Duration};
+
+use tokio::{
+	fs::{self, File},
+	io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+	sync::{broadcast, watch, Mutex},
+	task::JoinHandle,
+	time::Instant,
+};
 
 use crate::{
-	crypto::{Decryptor, Encryptor},
+	crypto::{exhaustive_read, reencrypt_segments, Decryptor, Encryptor},
+	header::{
+		file::{AadBinding, FileHeader},
+		keyslot::Keyslot,
+	},
 	primitives::{
-		APP_IDENTIFIER, LATEST_STORED_KEY, MASTER_PASSWORD_CONTEXT, ROOT_KEY_CONTEXT,
-		SECRET_KEY_IDENTIFIER,
+		AEAD_TAG_LEN, APP_IDENTIFIER, BLOCK_LEN, LATEST_FILE_HEADER, LATEST_KEYSLOT,
+		LATEST_PREVIEW_MEDIA, LATEST_STORED_KEY, MASTER_PASSWORD_CONTEXT, MAX_KEY_DESCRIPTION_LEN,
+		MAX_KEY_LABEL_LEN, ROOT_KEY_CONTEXT, SECRET_KEY_IDENTIFIER,
 	},
 	types::{
 		Algorithm, EncryptedKey, HashingAlgorithm, Key, Nonce, OnboardingConfig, Salt, SecretKey,
 		SecretKeyString,
 	},
-	Error, Protected, Result,
+	Error, Payload, Protected, Result,
 };
 
-use dashmap::{DashMap, DashSet};
+use dashmap::{mapref::entry::Entry, DashMap, DashSet};
 use uuid::Uuid;
+use zeroize::{Zeroize, ZeroizeOnDrop};
 
-use super::keyring::{Identifier, KeyringInterface};
+#[cfg(feature = "serde")]
+use super::backup;
+use super::keyring::{Identifier, Keyring, KeyringInterface};
 
 /// This is a stored key, and can be freely written to the database.
 ///
@@ -67,6 +81,7 @@ pub struct StoredKey {
 	pub uuid: Uuid, // uuid for identification. shared with mounted keys
 	pub version: StoredKeyVersion,
 	pub key_type: StoredKeyType,
+	#[cfg_attr(feature = "serde", serde(default))]
 	pub algorithm: Algorithm, // encryption algorithm for encrypting the master key. can be changed (requires a re-encryption though)
 	pub hashing_algorithm: HashingAlgorithm, // hashing algorithm used for hashing the key with the content salt
 	pub content_salt: Salt,
@@ -77,6 +92,18 @@ pub struct StoredKey {
 	pub salt: Salt,
 	pub memory_only: bool,
 	pub automount: bool,
+	#[cfg_attr(feature = "serde", serde(default))]
+	pub label: String, // this is only metadata, and is never used for deriving keys
+	#[cfg_attr(feature = "serde", serde(default))]
+	pub description: Option<String>,
+}
+
+impl StoredKey {
+	/// This is the label that a key is given when it's created, until it's renamed.
+	#[must_use]
+	pub fn default_label(uuid: Uuid) -> String {
+		format!("Key {}", uuid.to_string()[..8].to_uppercase())
+	}
 }
 
 /// This denotes the type of key. `Root` keys can be used to unlock the key manager, and `User` keys are ordinary keys.
@@ -105,6 +132,106 @@ pub struct MountedKey {
 	pub hashed_key: Key, // this is hashed with the content salt, for instant access
 }
 
+// the hashed key is zeroized on drop, so unmounting a key scrubs it from memory
+impl ZeroizeOnDrop for MountedKey {}
+
+/// This contains information about a mounted key, and is safe to show to the user as it doesn't contain any secret material.
+///
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "rspc", derive(rspc::Type))]
+pub struct KeyInfo {
+	pub uuid: Uuid,
+	pub label: String,
+	pub description: Option<String>,
+	pub key_type: StoredKeyType,
+	pub algorithm: Algorithm,
+	pub hashing_algorithm: HashingAlgorithm,
+	pub default: bool,
+	pub memory_only: bool,
+	pub automount: bool,
+}
+
+/// This decides what happens to keys from a backup that are already in the keystore.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "rspc", derive(rspc::Type))]
+pub enum ImportPolicy {
+	Skip,
+	Overwrite,
+	Reject, // the import fails if any key is already in the keystore
+}
+
+/// This describes the outcome of `KeyManager::import_keys()`.
+#[derive(Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "rspc", derive(rspc::Type))]
+pub struct ImportReport {
+	pub added: Vec<Uuid>,
+	pub skipped: Vec<Uuid>, // these were already in the keystore, and have been left as they were
+	pub overwritten: Vec<Uuid>,
+}
+
+/// This is the state of a key within the key manager, as returned by `KeyManager::key_state()`.
+///
+/// A key starts out `Unmounted`, and can be queued with `KeyManager::queue_key()` while the user is asked for its password.
+/// `KeyManager::mount_queued()` then moves it through `Mounting` to `Mounted`. Unmounting a key returns it to `Unmounted`.
+///
+/// Keys can also be mounted directly with `KeyManager::mount()`, which skips the `Queued` state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "rspc", derive(rspc::Type))]
+pub enum KeyState {
+	Unmounted,
+	Queued, // waiting for the user to enter the key's password
+	Mounting,
+	Mounted,
+}
+
+/// This describes the outcome of `KeyManager::mount_all()`.
+#[derive(Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "rspc", derive(rspc::Type))]
+pub struct MountReport {
+	pub mounted: Vec<Uuid>,
+	pub skipped: Vec<Uuid>, // these were already mounted, and have been left as they were
+	pub mismatched: Vec<Uuid>, // the password didn't match these keys
+}
+
+/// This describes a key rotation that is in progress.
+///
+/// It should be written to the database alongside the new `StoredKey`, so that the rotation can be resumed with `KeyManager::resume_rotation()` after a crash.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "rspc", derive(rspc::Type))]
+pub struct KeyRotation {
+	pub old: Uuid, // the key that is being replaced
+	pub new: Uuid, // the key that replaces it
+}
+
+/// These are emitted by the key manager, and can be received with `KeyManager::subscribe()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "rspc", derive(rspc::Type))]
+pub enum KeyManagerEvent {
+	KeyExpired(Uuid), // the key was mounted with a TTL, and it was unmounted as it wasn't used in time
+}
+
+/// This keeps track of when a key that was mounted with `KeyManager::mount_with_ttl()` should be unmounted.
+///
+/// The timer is aborted when this is dropped, so unmounting the key manually never leaves it running.
+struct KeyExpiry {
+	ttl: Duration,
+	deadline: watch::Sender<Instant>,
+	timer: JoinHandle<()>,
+}
+
+impl Drop for KeyExpiry {
+	fn drop(&mut self) {
+		self.timer.abort();
+	}
+}
+
 /// This is the key manager itself.
 ///
 /// It contains the keystore, the keymount, the root key and a few other pieces of information.
@@ -114,25 +241,56 @@ pub struct KeyManager {
 	root_key: Mutex<Option<Key>>, // the root key for the vault
 	verification_key: Mutex<Option<StoredKey>>,
 	keystore: DashMap<Uuid, StoredKey>,
-	keymount: DashMap<Uuid, MountedKey>,
+	keymount: Arc<DashMap<Uuid, MountedKey>>,
+	expiries: Arc<DashMap<Uuid, KeyExpiry>>,
+	events: broadcast::Sender<KeyManagerEvent>,
 	default: Mutex<Option<Uuid>>,
+	auto_lock: Mutex<Option<Duration>>, // the TTL that memory-only keys are mounted with, see `KeyManager::set_auto_lock()`
 	mounting_queue: DashSet<Uuid>,
-	keyring: Option<Arc<Mutex<KeyringInterface>>>,
+	password_queue: DashSet<Uuid>, // keys that are waiting for their password, see `KeyState::Queued`
+	rotations: DashMap<Uuid, KeyRotation>, // keyed by the old key's UUID
+	keyring: Option<Arc<Mutex<Box<dyn Keyring + Send>>>>,
 }
 impl KeyManager {
 	/// Initialize the Key Manager with `StoredKeys` retrieved from the database.
+	///
+	/// The secret key is stored within the OS keyring, if one is available.
 	pub async fn new(stored_keys: Vec<StoredKey>) -> Result<Self> {
 		let keyring = KeyringInterface::new()
-			.map(|k| Arc::new(Mutex::new(k)))
+			.map(|k| Box::new(k) as Box<dyn Keyring + Send>)
 			.ok();
 
+		Self::init(stored_keys, keyring).await
+	}
+
+	/// Initialize the Key Manager with `StoredKeys` retrieved from the database, and a custom keyring for the secret key (e.g. a `Pkcs11Token`).
+	///
+	/// Use `KeyManager::onboard()` to store the secret key within it during onboarding.
+	pub async fn with_keyring(
+		stored_keys: Vec<StoredKey>,
+		keyring: Box<dyn Keyring + Send>,
+	) -> Result<Self> {
+		Self::init(stored_keys, Some(keyring)).await
+	}
+
+	async fn init(
+		stored_keys: Vec<StoredKey>,
+		keyring: Option<Box<dyn Keyring + Send>>,
+	) -> Result<Self> {
+		let keyring = keyring.map(|k| Arc::new(Mutex::new(k)));
+
 		let keymanager = Self {
 			root_key: Mutex::new(None),
 			verification_key: Mutex::new(None),
 			keystore: DashMap::new(),
-			keymount: DashMap::new(),
+			keymount: Arc::new(DashMap::new()),
+			expiries: Arc::new(DashMap::new()),
+			events: broadcast::channel(16).0,
 			default: Mutex::new(None),
+			auto_lock: Mutex::new(None),
 			mounting_queue: DashSet::new(),
+			password_queue: DashSet::new(),
+			rotations: DashMap::new(),
 			keyring,
 		};
 
@@ -176,6 +334,13 @@ impl KeyManager {
 			.ok_or(Error::KeyAlreadyMounted)
 	}
 
+	/// This verifies that the target key is not part of a rotation before continuing the operation.
+	pub fn ensure_not_rotating(&self, uuid: Uuid) -> Result<()> {
+		(!self.is_rotating(uuid))
+			.then_some(())
+			.ok_or(Error::KeyRotationInProgress)
+	}
+
 	/// This is used to retrieve an item from OS keyrings
 	pub async fn keyring_retrieve(
 		&self,
@@ -233,7 +398,7 @@ impl KeyManager {
 		Ok(())
 	}
 
-	fn get_keyring(&self) -> Result<Arc<Mutex<KeyringInterface>>> {
+	fn get_keyring(&self) -> Result<Arc<Mutex<Box<dyn Keyring + Send>>>> {
 		self.keyring
 			.as_ref()
 			.map_or(Err(Error::KeyringNotSupported), |k| Ok(k.clone()))
@@ -244,13 +409,47 @@ impl KeyManager {
 	/// This will create a secret key and attempt to store it in OS keyrings.
 	///
 	/// It will also generate a verification key, which should be written to the database.
-	#[allow(clippy::needless_pass_by_value)]
 	pub async fn onboarding(config: OnboardingConfig, library_uuid: Uuid) -> Result<StoredKey> {
+		let (verification_key, secret_key) = Self::generate_verification_key(config).await?;
+
+		// attempt to insert into the OS keyring
+		// can ignore false here as we want to silently error
+		if let Ok(keyring) = KeyringInterface::new() {
+			let identifier = Identifier {
+				application: APP_IDENTIFIER,
+				library_uuid: &library_uuid.to_string(),
+				usage: SECRET_KEY_IDENTIFIER,
+			};
+
+			keyring.insert(identifier, secret_key.into()).ok();
+		}
+
+		Ok(verification_key)
+	}
+
+	/// This is the same as `KeyManager::onboarding()`, but the secret key is stored within this key manager's keyring.
+	///
+	/// Unlike `KeyManager::onboarding()`, an error is returned if the secret key couldn't be stored.
+	/// This ensures that a hardware keyring which isn't present (`Error::KeyringNotSupported`) isn't silently skipped.
+	pub async fn onboard(&self, config: OnboardingConfig, library_uuid: Uuid) -> Result<StoredKey> {
+		let (verification_key, secret_key) = Self::generate_verification_key(config).await?;
+
+		self.keyring_insert(
+			library_uuid,
+			SECRET_KEY_IDENTIFIER.to_string(),
+			secret_key.into(),
+		)
+		.await?;
+
+		Ok(verification_key)
+	}
+
+	/// This generates a secret key, and a verification key (which is unlocked with the master password and the secret key).
+	#[allow(clippy::needless_pass_by_value)]
+	async fn generate_verification_key(config: OnboardingConfig) -> Result<(StoredKey, SecretKey)> {
 		let content_salt = Salt::generate();
 		let secret_key = SecretKey::generate();
 
-		dbg!(SecretKeyString::from(secret_key.clone()).expose());
-
 		let algorithm = config.algorithm;
 		let hashing_algorithm = config.hashing_algorithm;
 
@@ -291,20 +490,10 @@ impl KeyManager {
 		)
 		.await?;
 
-		// attempt to insert into the OS keyring
-		// can ignore false here as we want to silently error
-		if let Ok(keyring) = KeyringInterface::new() {
-			let identifier = Identifier {
-				application: APP_IDENTIFIER,
-				library_uuid: &library_uuid.to_string(),
-				usage: SECRET_KEY_IDENTIFIER,
-			};
-
-			keyring.insert(identifier, secret_key.into()).ok();
-		}
+		let uuid = Uuid::new_v4();
 
 		let verification_key = StoredKey {
-			uuid: Uuid::new_v4(),
+			uuid,
 			version: LATEST_STORED_KEY,
 			key_type: StoredKeyType::Root,
 			algorithm,
@@ -317,9 +506,11 @@ impl KeyManager {
 			salt, // salt used for key derivation
 			memory_only: false,
 			automount: false,
+			label: StoredKey::default_label(uuid),
+			description: None,
 		};
 
-		Ok(verification_key)
+		Ok((verification_key, secret_key))
 	}
 
 	/// This function should be used to populate the keystore with multiple stored keys at a time.
@@ -344,8 +535,11 @@ impl KeyManager {
 	}
 
 	/// This function removes a key from the keystore, keymount and from the default (if set).
+	///
+	/// Keys that are part of a rotation can't be removed until the rotation has finished.
 	pub async fn remove_key(&self, uuid: Uuid) -> Result<()> {
 		self.ensure_unlocked().await?;
+		self.ensure_not_rotating(uuid)?;
 
 		if self.keystore.contains_key(&uuid) {
 			// if key is default, clear it
@@ -360,6 +554,8 @@ impl KeyManager {
 			self.keymount
 				.contains_key(&uuid)
 				.then(|| self.keymount.remove(&uuid));
+			self.expiries.remove(&uuid);
+			self.password_queue.remove(&uuid);
 
 			// remove from keystore
 			self.keystore.remove(&uuid);
@@ -369,32 +565,225 @@ impl KeyManager {
 	}
 
 	/// This is used for changing a master password. It will re-generate a new secret key.
+	///
+	/// The current master password is verified first, and `Error::IncorrectPassword` is returned if it's wrong.
+	/// If `old_secret_key` isn't provided, it's retrieved from the OS keyring.
+	///
+	/// Only the root key is re-encrypted, so no stored keys (or any data that they protect) need to be changed.
+	///
+	/// The current verification key stays in place until the new one has been verified to decrypt the same root key.
+	/// The returned verification key should be written before the old one is removed, as they both remain valid until then.
 	#[allow(clippy::needless_pass_by_value)]
 	pub async fn change_master_password(
 		&self,
+		old_master_password: Protected<String>,
+		old_secret_key: Option<SecretKeyString>,
 		master_password: Protected<String>,
 		algorithm: Algorithm,
 		hashing_algorithm: HashingAlgorithm,
 		library_uuid: Uuid,
-	) -> Result<StoredKey> {
+	) -> Result<(StoredKey, SecretKeyString)> {
 		self.ensure_unlocked().await?;
 
+		let root_key = self.get_root_key().await?;
+		let old_verification_key = self.get_verification_key().await?;
+
+		let old_secret_key = match old_secret_key {
+			Some(secret_key) => secret_key,
+			None => SecretKeyString(
+				self.keyring_retrieve(library_uuid, SECRET_KEY_IDENTIFIER.to_string())
+					.await?,
+			),
+		};
+
+		let old_root_key = Self::decrypt_root_key(
+			&old_verification_key,
+			old_master_password,
+			old_secret_key.into(),
+		)
+		.await?;
+
+		if !Self::keys_match(&old_root_key, &root_key) {
+			return Err(Error::IncorrectPassword);
+		}
+
 		let secret_key = SecretKey::generate();
-		let content_salt = Salt::generate();
 
-		dbg!(SecretKeyString::from(secret_key.clone()).expose());
+		let verification_key = Self::wrap_root_key(
+			&root_key,
+			master_password.clone(),
+			secret_key.clone(),
+			algorithm,
+			hashing_algorithm,
+		)
+		.await?;
 
-		let hashed_password = hashing_algorithm.hash(
-			master_password.into(),
-			content_salt,
-			Some(secret_key.clone()),
-		)?;
+		// the new verification key must decrypt the same root key before it replaces the old one, otherwise every stored key would be lost
+		let new_root_key =
+			Self::decrypt_root_key(&verification_key, master_password, secret_key.clone()).await?;
+
+		if !Self::keys_match(&new_root_key, &root_key) {
+			return Err(Error::Decrypt);
+		}
+
+		// will update if it's already present
+		self.keyring_insert(
+			library_uuid,
+			SECRET_KEY_IDENTIFIER.to_string(),
+			secret_key.clone().into(),
+		)
+		.await
+		.ok();
+
+		*self.verification_key.lock().await = Some(verification_key.clone());
+
+		Ok((verification_key, secret_key.into()))
+	}
+
+	/// This is used for rotating the root key, along with the verification key that unlocks it.
+	///
+	/// A new root key is generated, and the master key of every stored key is re-encrypted with it.
+	/// The new verification key uses the same master password, secret key, algorithm and hashing algorithm as the current one.
+	///
+	/// The master password is verified first, and `Error::IncorrectPassword` is returned if it's wrong.
+	/// If `secret_key` isn't provided, it's retrieved from the OS keyring.
+	///
+	/// Every stored key is re-encrypted before anything is replaced, so if any of them fail the key manager is left exactly as it was.
+	/// The returned verification key and stored keys must be written to the database together, as stored keys can only be mounted with the root key that they were encrypted with.
+	///
+	/// The keys themselves aren't changed, so mounted keys stay mounted and any data that they protect doesn't need to be re-encrypted.
+	#[allow(clippy::needless_pass_by_value)]
+	pub async fn rotate_root_key(
+		&self,
+		master_password: Protected<String>,
+		secret_key: Option<SecretKeyString>,
+		library_uuid: Uuid,
+	) -> Result<(StoredKey, Vec<StoredKey>)> {
+		self.ensure_unlocked().await?;
+
+		let old_verification_key = self.get_verification_key().await?;
+
+		let secret_key: SecretKey = match secret_key {
+			Some(secret_key) => secret_key,
+			None => SecretKeyString(
+				self.keyring_retrieve(library_uuid, SECRET_KEY_IDENTIFIER.to_string())
+					.await?,
+			),
+		}
+		.into();
+
+		// this is held until the rotation has finished, so no keys can be added or mounted with the old root key in the meantime
+		let mut root_key = self.root_key.lock().await;
+		let old_root_key = root_key.clone().ok_or(Error::NotUnlocked)?;
+
+		let decrypted_root_key = Self::decrypt_root_key(
+			&old_verification_key,
+			master_password.clone(),
+			secret_key.clone(),
+		)
+		.await?;
+
+		if !Self::keys_match(&decrypted_root_key, &old_root_key) {
+			return Err(Error::IncorrectPassword);
+		}
+
+		let new_root_key = Key::generate();
+
+		let mut stored_keys = Vec::with_capacity(self.keystore.len());
+		for stored_key in self.dump_keystore() {
+			stored_keys
+				.push(Self::rewrap_stored_key(stored_key, &old_root_key, &new_root_key).await?);
+		}
+
+		let verification_key = Self::wrap_root_key(
+			&new_root_key,
+			master_password.clone(),
+			secret_key.clone(),
+			old_verification_key.algorithm,
+			old_verification_key.hashing_algorithm,
+		)
+		.await?;
+
+		// the new verification key must decrypt the new root key before anything is replaced, otherwise every stored key would be lost
+		let decrypted_root_key =
+			Self::decrypt_root_key(&verification_key, master_password, secret_key).await?;
+
+		if !Self::keys_match(&decrypted_root_key, &new_root_key) {
+			return Err(Error::Decrypt);
+		}
+
+		// keys that were removed during the rotation aren't re-added
+		for stored_key in &stored_keys {
+			if let Some(mut key) = self.keystore.get_mut(&stored_key.uuid) {
+				*key = stored_key.clone();
+			}
+		}
+
+		*self.verification_key.lock().await = Some(verification_key.clone());
+		*root_key = Some(new_root_key);
+
+		Ok((verification_key, stored_keys))
+	}
+
+	/// This re-encrypts a stored key's master key from one root key to another, with a newly generated salt and nonce.
+	///
+	/// The key itself (and the master key that it's encrypted with) stay the same.
+	async fn rewrap_stored_key(
+		mut stored_key: StoredKey,
+		old_root_key: &Key,
+		new_root_key: &Key,
+	) -> Result<StoredKey> {
+		match stored_key.version {
+			StoredKeyVersion::V1 => {
+				let master_key = Decryptor::decrypt_bytes(
+					Key::derive(old_root_key.clone(), stored_key.salt, ROOT_KEY_CONTEXT),
+					stored_key.master_key_nonce,
+					stored_key.algorithm,
+					&stored_key.master_key,
+					&[],
+				)
+				.await?;
+
+				let salt = Salt::generate();
+				let master_key_nonce = Nonce::generate(stored_key.algorithm)?;
+
+				stored_key.master_key = EncryptedKey::try_from(
+					Encryptor::encrypt_bytes(
+						Key::derive(new_root_key.clone(), salt, ROOT_KEY_CONTEXT),
+						master_key_nonce,
+						stored_key.algorithm,
+						master_key.expose(),
+						&[],
+					)
+					.await?,
+				)?;
+				stored_key.master_key_nonce = master_key_nonce;
+				stored_key.salt = salt;
+
+				Ok(stored_key)
+			}
+		}
+	}
+
+	/// This encrypts the root key with a newly generated master key, which is then encrypted with the hashed master password.
+	///
+	/// It returns the verification key which is used to unlock the key manager.
+	async fn wrap_root_key(
+		root_key: &Key,
+		master_password: Protected<String>,
+		secret_key: SecretKey,
+		algorithm: Algorithm,
+		hashing_algorithm: HashingAlgorithm,
+	) -> Result<StoredKey> {
+		let content_salt = Salt::generate();
+
+		let hashed_password =
+			hashing_algorithm.hash(master_password.into(), content_salt, Some(secret_key))?;
 
 		// Generate items we'll need for encryption
 		let master_key = Key::generate();
 		let master_key_nonce = Nonce::generate(algorithm)?;
 
-		let root_key = self.get_root_key().await?;
 		let root_key_nonce = Nonce::generate(algorithm)?;
 
 		let salt = Salt::generate();
@@ -420,17 +809,10 @@ impl KeyManager {
 		)
 		.await?;
 
-		// will update if it's already present
-		self.keyring_insert(
-			library_uuid,
-			SECRET_KEY_IDENTIFIER.to_string(),
-			secret_key.into(),
-		)
-		.await
-		.ok();
+		let uuid = Uuid::new_v4();
 
-		let verification_key = StoredKey {
-			uuid: Uuid::new_v4(),
+		Ok(StoredKey {
+			uuid,
 			version: LATEST_STORED_KEY,
 			key_type: StoredKeyType::Root,
 			algorithm,
@@ -443,11 +825,80 @@ impl KeyManager {
 			salt,
 			memory_only: false,
 			automount: false,
-		};
+			label: StoredKey::default_label(uuid),
+			description: None,
+		})
+	}
 
-		*self.verification_key.lock().await = Some(verification_key.clone());
+	/// This decrypts the root key from a verification key, and returns `Error::IncorrectPassword` if the master password or secret key are wrong.
+	async fn decrypt_root_key(
+		verification_key: &StoredKey,
+		master_password: Protected<String>,
+		secret_key: SecretKey,
+	) -> Result<Key> {
+		match verification_key.version {
+			StoredKeyVersion::V1 => {
+				let hashed_password = verification_key.hashing_algorithm.hash(
+					master_password.into(),
+					verification_key.content_salt,
+					Some(secret_key),
+				)?;
 
-		Ok(verification_key)
+				let master_key = Decryptor::decrypt_bytes(
+					Key::derive(
+						hashed_password,
+						verification_key.salt,
+						MASTER_PASSWORD_CONTEXT,
+					),
+					verification_key.master_key_nonce,
+					verification_key.algorithm,
+					&verification_key.master_key,
+					&[],
+				)
+				.await
+				.map_err(|_| Error::IncorrectPassword)?;
+
+				Key::try_from(
+					Decryptor::decrypt_bytes(
+						Key::try_from(master_key)?,
+						verification_key.key_nonce,
+						verification_key.algorithm,
+						&verification_key.key,
+						&[],
+					)
+					.await?,
+				)
+			}
+		}
+	}
+
+	/// This compares two keys in constant-time, as `blake3::Hash` implements `PartialEq` in constant-time.
+	fn keys_match(a: &Key, b: &Key) -> bool {
+		blake3::Hash::from(*a.expose()) == blake3::Hash::from(*b.expose())
+	}
+
+	/// This exports every user key as a backup, which is encrypted with a key derived from the provided password.
+	///
+	/// Memory-only keys are excluded. The root key is included within the encrypted backup, so it can be imported with `KeyManager::import_keys()` using only the provided password.
+	///
+	/// The backup uses the same algorithm and hashing algorithm as the verification key.
+	#[cfg(feature = "serde")]
+	pub async fn export_keys(&self, password: Protected<String>) -> Result<Vec<u8>> {
+		self.ensure_unlocked().await?;
+
+		let verification_key = self.get_verification_key().await?;
+
+		let mut stored_keys = self.dump_keystore();
+		stored_keys.retain(|k| !k.memory_only && k.key_type == StoredKeyType::User);
+
+		backup::encrypt_backup(
+			&self.get_root_key().await?,
+			&stored_keys,
+			password.into(),
+			verification_key.algorithm,
+			verification_key.hashing_algorithm,
+		)
+		.await
 	}
 
 	/// This re-encrypts master keys so they can be imported from a key backup into the current key manager.
@@ -524,48 +975,122 @@ impl KeyManager {
 				continue;
 			}
 
-			match key.version {
-				StoredKeyVersion::V1 => {
-					// decrypt the key's master key
-					let master_key = Decryptor::decrypt_bytes(
-						Key::derive(old_root_key.clone(), key.salt, ROOT_KEY_CONTEXT),
-						key.master_key_nonce,
-						key.algorithm,
-						&key.master_key,
-						&[],
-					)
-					.await
-					.map_or(Err(Error::IncorrectPassword), Key::try_from)?;
+			let updated_key = self.rewrap_master_key(&key, &old_root_key).await?;
 
-					// generate a new nonce
-					let master_key_nonce = Nonce::generate(key.algorithm)?;
+			reencrypted_keys.push(updated_key.clone());
+			self.keystore.insert(updated_key.uuid, updated_key);
+		}
 
-					let salt = Salt::generate();
+		Ok(reencrypted_keys)
+	}
 
-					// encrypt the master key with the current root key
-					let encrypted_master_key = EncryptedKey::try_from(
-						Encryptor::encrypt_bytes(
-							Key::derive(self.get_root_key().await?, salt, ROOT_KEY_CONTEXT),
-							master_key_nonce,
-							key.algorithm,
-							master_key.expose(),
-							&[],
-						)
-						.await?,
-					)?;
+	/// This imports the keys from a backup created with `KeyManager::export_keys()`, and only requires the password that it was exported with.
+	///
+	/// Keys that are already in the keystore are either skipped or overwritten, depending on the `ImportPolicy`. With `ImportPolicy::Reject`, `Error::DuplicateObjects` is returned and nothing is imported.
+	/// Mounted keys and keys that are part of a rotation are never overwritten. If any would be, `Error::KeyAlreadyMounted` or `Error::KeyRotationInProgress` is returned and nothing is imported.
+	///
+	/// The added and overwritten keys should be written to the database, by retrieving them with `KeyManager::access_keystore()`.
+	#[cfg(feature = "serde")]
+	pub async fn import_keys(
+		&self,
+		backup: &[u8],
+		password: Protected<String>,
+		policy: ImportPolicy,
+	) -> Result<ImportReport> {
+		self.ensure_unlocked().await?;
 
-					let mut updated_key = key.clone();
-					updated_key.master_key_nonce = master_key_nonce;
-					updated_key.master_key = encrypted_master_key;
-					updated_key.salt = salt;
+		let (old_root_key, stored_keys) = backup::decrypt_backup(backup, password.into()).await?;
 
-					reencrypted_keys.push(updated_key.clone());
-					self.keystore.insert(updated_key.uuid, updated_key);
+		let keys: Vec<StoredKey> = stored_keys
+			.into_iter()
+			.filter(|k| k.key_type == StoredKeyType::User)
+			.collect();
+
+		for key in keys.iter().filter(|k| self.keystore.contains_key(&k.uuid)) {
+			match policy {
+				ImportPolicy::Skip => {}
+				ImportPolicy::Overwrite => {
+					self.ensure_not_mounted(key.uuid)?;
+					self.ensure_not_rotating(key.uuid)?;
 				}
+				ImportPolicy::Reject => return Err(Error::DuplicateObjects),
 			}
 		}
 
-		Ok(reencrypted_keys)
+		let mut report = ImportReport::default();
+
+		// re-wrap everything before touching the keystore, so a failure can't leave a partial import behind
+		let mut rewrapped_keys = Vec::new();
+
+		for key in keys {
+			let exists = self.keystore.contains_key(&key.uuid);
+
+			if exists && policy == ImportPolicy::Skip {
+				report.skipped.push(key.uuid);
+				continue;
+			}
+
+			rewrapped_keys.push((exists, self.rewrap_master_key(&key, &old_root_key).await?));
+		}
+
+		for (exists, key) in rewrapped_keys {
+			if exists {
+				report.overwritten.push(key.uuid);
+			} else {
+				report.added.push(key.uuid);
+			}
+
+			self.keystore.insert(key.uuid, key);
+		}
+
+		Ok(report)
+	}
+
+	/// This re-encrypts a stored key's master key, so that it's encrypted with the current root key instead of `old_root_key`.
+	async fn rewrap_master_key(&self, key: &StoredKey, old_root_key: &Key) -> Result<StoredKey> {
+		match key.version {
+			StoredKeyVersion::V1 => {
+				// decrypt the key's master key
+				let master_key = Decryptor::decrypt_bytes(
+					Key::derive(old_root_key.clone(), key.salt, ROOT_KEY_CONTEXT),
+					key.master_key_nonce,
+					key.algorithm,
+					&key.master_key,
+					&[],
+				)
+				.await
+				.map_or(Err(Error::IncorrectPassword), Key::try_from)?;
+
+				// generate a new nonce
+				let master_key_nonce = Nonce::generate(key.algorithm)?;
+
+				let salt = Salt::generate();
+
+				// encrypt the master key with the current root key
+				let encrypted_master_key = EncryptedKey::try_from(
+					Encryptor::encrypt_bytes(
+						Key::derive(self.get_root_key().await?, salt, ROOT_KEY_CONTEXT),
+						master_key_nonce,
+						key.algorithm,
+						master_key.expose(),
+						&[],
+					)
+					.await?,
+				)?;
+
+				let mut updated_key = key.clone();
+				updated_key.master_key_nonce = master_key_nonce;
+				updated_key.master_key = encrypted_master_key;
+				updated_key.salt = salt;
+
+				// backups from before keys had labels won't contain one
+				if updated_key.label.is_empty() {
+					updated_key.label = StoredKey::default_label(updated_key.uuid);
+				}
+
+				Ok(updated_key)
+			}
+		}
 	}
 
 	/// This is used for unlocking the key manager, and requires both the master password and the secret key.
@@ -579,6 +1104,8 @@ impl KeyManager {
 	/// The invalidate function is to handle query invalidation, so that the UI updates correctly. Leave it blank if this isn't required.
 	///
 	/// Note: The invalidation function is ran after updating the queue both times, so it isn't required externally.
+	///
+	/// Unlocking requires hashing the master password, which is expensive, so this is done on tokio's blocking thread pool.
 	#[allow(clippy::needless_pass_by_value)]
 	pub async fn unlock<F>(
 		&self,
@@ -618,11 +1145,12 @@ impl KeyManager {
 			StoredKeyVersion::V1 => {
 				let hashed_password = verification_key
 					.hashing_algorithm
-					.hash(
+					.hash_async(
 						master_password.into(),
 						verification_key.content_salt,
 						Some(secret_key),
 					)
+					.await
 					.map_err(|e| {
 						self.remove_from_queue(verification_key.uuid).ok();
 						e
@@ -685,7 +1213,19 @@ impl KeyManager {
 	/// This function does not return a value by design.
 	///
 	/// This is to ensure that only functions which require access to the mounted key receive it.
+	///
+	/// Mounting a key requires hashing it, which is expensive, so this is done on tokio's blocking thread pool.
+	///
+	/// If auto-lock is enabled, memory-only keys are mounted with its TTL (see `KeyManager::set_auto_lock()`).
 	pub async fn mount(&self, uuid: Uuid) -> Result<()> {
+		match *self.auto_lock.lock().await {
+			Some(ttl) if self.is_memory_only(uuid).await? => self.mount_with_ttl(uuid, ttl).await,
+			_ => self.mount_key(uuid).await,
+		}
+	}
+
+	/// This mounts a key without a TTL, and it's only called by `mount()` and `mount_with_ttl()`.
+	async fn mount_key(&self, uuid: Uuid) -> Result<()> {
 		self.ensure_unlocked().await?;
 		self.ensure_not_mounted(uuid)?;
 		self.ensure_not_queued(uuid)?;
@@ -731,7 +1271,8 @@ impl KeyManager {
 					// Hash the key once with the parameters/algorithm the user selected during first mount
 					let hashed_key = stored_key
 						.hashing_algorithm
-						.hash(key, stored_key.content_salt, None)
+						.hash_async(key, stored_key.content_salt, None)
+						.await
 						.map_err(|e| {
 							self.remove_from_queue(uuid).ok();
 							e
@@ -744,6 +1285,7 @@ impl KeyManager {
 							hashed_key,
 						},
 					);
+					self.password_queue.remove(&uuid);
 
 					self.remove_from_queue(uuid)?;
 				}
@@ -755,6 +1297,192 @@ impl KeyManager {
 		}
 	}
 
+	/// This function queues a key to be mounted once the user has entered its password, with `KeyManager::mount_queued()`.
+	///
+	/// The key must not be mounted or queued already.
+	pub fn queue_key(&self, uuid: Uuid) -> Result<()> {
+		if !self.keystore.contains_key(&uuid) {
+			return Err(Error::KeyNotFound);
+		}
+
+		self.ensure_not_mounted(uuid)?;
+
+		if !self.password_queue.insert(uuid) {
+			return Err(Error::KeyAlreadyQueued);
+		}
+
+		Ok(())
+	}
+
+	/// This function removes a key from the queue, without mounting it.
+	pub fn unqueue_key(&self, uuid: Uuid) -> Result<()> {
+		self.password_queue
+			.remove(&uuid)
+			.ok_or(Error::KeyNotQueued)?;
+
+		Ok(())
+	}
+
+	/// This function mounts a key that was queued with `KeyManager::queue_key()`, once the user has entered its password.
+	///
+	/// `Error::KeyNotQueued` is returned if the key isn't queued, and `Error::IncorrectPassword` is returned if the password is wrong.
+	///
+	/// The key stays queued if it failed to mount, so the user can try again.
+	pub async fn mount_queued(&self, uuid: Uuid, password: Protected<String>) -> Result<()> {
+		if !self.password_queue.contains(&uuid) {
+			return Err(Error::KeyNotQueued);
+		}
+
+		if !self.verify_password(uuid, password).await? {
+			return Err(Error::IncorrectPassword);
+		}
+
+		self.mount(uuid).await
+	}
+
+	/// This function returns the current state of a key. See `KeyState` for how a key moves between each state.
+	pub fn key_state(&self, uuid: Uuid) -> Result<KeyState> {
+		if !self.keystore.contains_key(&uuid) {
+			return Err(Error::KeyNotFound);
+		}
+
+		// keys are still queued while they're mounting, so this is checked first
+		let state = if self.is_queued(uuid) {
+			KeyState::Mounting
+		} else if self.keymount.contains_key(&uuid) {
+			KeyState::Mounted
+		} else if self.password_queue.contains(&uuid) {
+			KeyState::Queued
+		} else {
+			KeyState::Unmounted
+		};
+
+		Ok(state)
+	}
+
+	/// This function mounts every user key in the keystore that matches the provided password.
+	///
+	/// Hashing a key with its content salt is the expensive part of mounting it, so keys that share a content salt and hashing algorithm are only hashed once.
+	///
+	/// Keys that are already mounted are skipped, rather than returning `Error::KeyAlreadyMounted`.
+	#[allow(clippy::needless_pass_by_value)]
+	pub async fn mount_all(&self, password: Protected<String>) -> Result<MountReport> {
+		self.ensure_unlocked().await?;
+
+		// these are collected first, so the keystore isn't locked while the keys are hashed
+		let user_keys = self
+			.keystore
+			.iter()
+			.filter(|k| k.key_type == StoredKeyType::User)
+			.map(|k| (k.uuid, k.hashing_algorithm, k.content_salt))
+			.collect::<Vec<_>>();
+
+		let password_hash = blake3::hash(password.expose().as_bytes());
+		let mut hashed_keys: Vec<(HashingAlgorithm, Salt, Key)> = Vec::new();
+		let mut report = MountReport::default();
+
+		for (uuid, hashing_algorithm, content_salt) in user_keys {
+			if self.keymount.contains_key(&uuid) || self.is_queued(uuid) {
+				report.skipped.push(uuid);
+				continue;
+			}
+
+			// `blake3::Hash` implements `PartialEq` in constant-time
+			let key = self.get_key(uuid).await?;
+			if blake3::hash(key.expose().as_bytes()) != password_hash {
+				report.mismatched.push(uuid);
+				continue;
+			}
+
+			self.mounting_queue.insert(uuid);
+
+			let hashed_key = if let Some((_, _, hashed_key)) = hashed_keys
+				.iter()
+				.find(|(a, s, _)| *a == hashing_algorithm && *s == content_salt)
+			{
+				hashed_key.clone()
+			} else {
+				let hashed_key = hashing_algorithm
+					.hash(key.into(), content_salt, None)
+					.map_err(|e| {
+						self.remove_from_queue(uuid).ok();
+						e
+					})?;
+				hashed_keys.push((hashing_algorithm, content_salt, hashed_key.clone()));
+				hashed_key
+			};
+
+			self.keymount.insert(uuid, MountedKey { uuid, hashed_key });
+			self.password_queue.remove(&uuid);
+			self.remove_from_queue(uuid)?;
+			report.mounted.push(uuid);
+		}
+
+		Ok(report)
+	}
+
+	/// This function mounts a key in the same way as `KeyManager::mount()`, but the key is unmounted again once `ttl` has passed without it being used.
+	///
+	/// This is intended for memory-only keys on shared machines. Each access to the mounted key restarts the TTL.
+	///
+	/// A `KeyManagerEvent::KeyExpired` event is emitted when the key expires, so the user can be asked to enter it again.
+	pub async fn mount_with_ttl(&self, uuid: Uuid, ttl: Duration) -> Result<()> {
+		self.mount_key(uuid).await?;
+
+		let (deadline, mut deadline_rx) = watch::channel(Instant::now() + ttl);
+		let keymount = Arc::downgrade(&self.keymount);
+		let expiries = Arc::downgrade(&self.expiries);
+		let events = self.events.clone();
+
+		// the entry is held while spawning, so the timer can't remove the expiry before it's been inserted
+		let entry = self.expiries.entry(uuid);
+		let timer = tokio::spawn(async move {
+			loop {
+				let deadline = *deadline_rx.borrow_and_update();
+				match tokio::time::timeout_at(deadline, deadline_rx.changed()).await {
+					Ok(Ok(())) => {}      // the key was used, so wait for the new deadline
+					Ok(Err(_)) => return, // the expiry was dropped
+					Err(_) => break,
+				}
+			}
+
+			// the key manager may have been dropped in the meantime
+			if let (Some(keymount), Some(expiries)) = (keymount.upgrade(), expiries.upgrade()) {
+				keymount.remove(&uuid);
+				// this aborts the timer, but that only takes effect once it yields so the event is still sent
+				expiries.remove(&uuid);
+				events.send(KeyManagerEvent::KeyExpired(uuid)).ok();
+			}
+		});
+		entry.insert(KeyExpiry {
+			ttl,
+			deadline,
+			timer,
+		});
+
+		Ok(())
+	}
+
+	/// This enables auto-lock for memory-only keys, so they're unmounted (and wiped) once `ttl` has passed without them being used.
+	///
+	/// It only applies to keys that are mounted afterwards, and `None` disables it. A `KeyManagerEvent::KeyExpired` event is emitted for each key that's locked.
+	pub async fn set_auto_lock(&self, ttl: Option<Duration>) {
+		*self.auto_lock.lock().await = ttl;
+	}
+
+	/// This restarts the TTL of a key that was mounted with `KeyManager::mount_with_ttl()`, as it has just been used.
+	fn refresh_expiry(&self, uuid: Uuid) {
+		if let Some(expiry) = self.expiries.get(&uuid) {
+			expiry.deadline.send(Instant::now() + expiry.ttl).ok();
+		}
+	}
+
+	/// This function returns a receiver for all events emitted by the key manager.
+	#[must_use]
+	pub fn subscribe(&self) -> broadcast::Receiver<KeyManagerEvent> {
+		self.events.subscribe()
+	}
+
 	/// This function is used for getting the key value itself, from a given UUID.
 	pub async fn get_key(&self, uuid: Uuid) -> Result<Protected<String>> {
 		self.ensure_unlocked().await?;
@@ -790,6 +1518,17 @@ impl KeyManager {
 		}
 	}
 
+	/// This function is used for checking a password against a stored key, without mounting it.
+	///
+	/// It returns `false` if the password is incorrect, and the mount state of the key is never changed.
+	#[allow(clippy::needless_pass_by_value)]
+	pub async fn verify_password(&self, uuid: Uuid, password: Protected<String>) -> Result<bool> {
+		let key = self.get_key(uuid).await?;
+
+		// `blake3::Hash` implements `PartialEq` in constant-time
+		Ok(blake3::hash(key.expose().as_bytes()) == blake3::hash(password.expose().as_bytes()))
+	}
+
 	/// This function is used to add a new key/password to the keystore.
 	///
 	/// You should use this when a new key is added, as it will generate salts/nonces/etc.
@@ -864,6 +1603,8 @@ impl KeyManager {
 				salt,
 				memory_only,
 				automount,
+				label: StoredKey::default_label(uuid),
+				description: None,
 			},
 		);
 
@@ -871,104 +1612,562 @@ impl KeyManager {
 		Ok(uuid)
 	}
 
-	/// This function is for accessing the internal keymount.
+	/// This is used for rotating a key, while keeping all data that it protects accessible.
 	///
-	/// We could add a log to this, so that the user can view accesses
-	pub async fn access_keymount(&self, uuid: Uuid) -> Result<MountedKey> {
+	/// A new key is generated and added to the keystore with the same settings as the old key. If the old key is mounted, the new key will be mounted too.
+	///
+	/// The old key remains valid until `KeyManager::finish_rotation()` is called, so there is never a point where data can't be decrypted.
+	///
+	/// The new `StoredKey` (from `KeyManager::access_keystore()`) and the returned `KeyRotation` must be written to the database before any keyslots are re-wrapped.
+	/// That way, a crash at any point can be recovered from with `KeyManager::resume_rotation()`.
+	pub async fn rotate_key(&self, uuid: Uuid) -> Result<KeyRotation> {
 		self.ensure_unlocked().await?;
+		self.ensure_not_rotating(uuid)?;
 
-		self.keymount
-			.get(&uuid)
-			.map_or(Err(Error::KeyNotFound), |v| Ok(v.clone()))
-	}
+		let stored_key = self.access_keystore(uuid).await?;
 
-	/// This function is for accessing a `StoredKey`.
-	pub async fn access_keystore(&self, uuid: Uuid) -> Result<StoredKey> {
-		self.ensure_unlocked().await?;
+		let new = self
+			.add_to_keystore(
+				Protected::new(hex::encode(Key::generate().expose())),
+				stored_key.algorithm,
+				stored_key.hashing_algorithm,
+				stored_key.memory_only,
+				stored_key.automount,
+				None,
+			)
+			.await?;
 
-		self.keystore
-			.get(&uuid)
-			.map_or(Err(Error::KeyNotFound), |v| Ok(v.clone()))
-	}
+		// the new key replaces the old one, so it should look the same to the user
+		if let Some(mut new_key) = self.keystore.get_mut(&new) {
+			new_key.label = stored_key.label;
+			new_key.description = stored_key.description;
+		}
 
-	/// This allows you to set the default key
-	pub async fn set_default(&self, uuid: Uuid) -> Result<()> {
-		self.ensure_unlocked().await?;
+		let rotation = KeyRotation { old: uuid, new };
 
-		if self.keystore.contains_key(&uuid) {
-			*self.default.lock().await = Some(uuid);
-			Ok(())
-		} else {
-			Err(Error::KeyNotFound)
+		// another rotation could have started while we were generating the new key
+		if let Err(e) = self.start_rotation(rotation) {
+			self.keystore.remove(&new);
+			return Err(e);
+		}
+
+		if self.keymount.contains_key(&uuid) {
+			self.mount(new).await?;
 		}
+
+		Ok(rotation)
 	}
 
-	/// This allows you to get the default key's UUID
-	pub async fn get_default(&self) -> Result<Uuid> {
+	/// This is used for resuming a rotation after the application has restarted (e.g. due to a crash).
+	///
+	/// Both keys need to be in the keystore. If the old key is mounted, the new key will be mounted too.
+	///
+	/// If the new key is missing, it was never written to the database and so nothing can have been re-wrapped with it. The rotation should be discarded.
+	///
+	/// If the old key is missing, the rotation had already finished. The rotation should be discarded.
+	pub async fn resume_rotation(&self, rotation: KeyRotation) -> Result<()> {
 		self.ensure_unlocked().await?;
 
-		self.default.lock().await.ok_or(Error::NoDefaultKeySet)
-	}
+		if !self.keystore.contains_key(&rotation.old) || !self.keystore.contains_key(&rotation.new)
+		{
+			return Err(Error::KeyNotFound);
+		}
 
-	/// This should ONLY be used internally, for accessing the root key.
-	async fn get_root_key(&self) -> Result<Key> {
-		self.root_key.lock().await.clone().ok_or(Error::NotUnlocked)
+		self.start_rotation(rotation)?;
+
+		if self.keymount.contains_key(&rotation.old) && !self.keymount.contains_key(&rotation.new) {
+			self.mount(rotation.new).await?;
+		}
+
+		Ok(())
 	}
 
-	pub async fn get_verification_key(&self) -> Result<StoredKey> {
-		self.verification_key
-			.lock()
-			.await
-			.clone()
-			.ok_or(Error::NoVerificationKey)
+	fn start_rotation(&self, rotation: KeyRotation) -> Result<()> {
+		self.ensure_not_rotating(rotation.old)?;
+		self.ensure_not_rotating(rotation.new)?;
+
+		match self.rotations.entry(rotation.old) {
+			Entry::Occupied(_) => Err(Error::KeyRotationInProgress),
+			Entry::Vacant(entry) => {
+				entry.insert(rotation);
+				Ok(())
+			}
+		}
 	}
 
-	/// This is used for checking if a key is memory only.
-	pub async fn is_memory_only(&self, uuid: Uuid) -> Result<bool> {
+	/// This re-wraps the header's keyslot that belongs to the old key of a rotation, so that it belongs to the new key instead.
+	///
+	/// The master key of the header doesn't change, so the data it protects doesn't need to be re-encrypted.
+	///
+	/// It returns `true` if the header was changed and needs to be written back. It returns `false` if no keyslots belong to the old key, or if the header has already been re-wrapped.
+	///
+	/// This is safe to call again on the same header after resuming a rotation.
+	///
+	/// Both keys should be mounted beforehand, otherwise they will need to be hashed on every call.
+	pub async fn rewrap_keyslots(
+		&self,
+		rotation: &KeyRotation,
+		header: &mut FileHeader,
+	) -> Result<bool> {
 		self.ensure_unlocked().await?;
+		self.ensure_rotation(rotation)?;
 
-		self.keystore
-			.get(&uuid)
-			.map_or(Err(Error::KeyNotFound), |v| Ok(v.memory_only))
+		let old_key = self.hash_key(rotation.old).await?;
+		let new_key = self.hash_key(rotation.new).await?;
+		let new_stored_key = self.access_keystore(rotation.new).await?;
+
+		for keyslot in &header.keyslots {
+			if keyslot
+				.decrypt_master_key_from_prehashed(new_key.clone())
+				.await
+				.is_ok()
+			{
+				return Ok(false);
+			}
+		}
+
+		for keyslot in &mut header.keyslots {
+			if let Ok(master_key) = keyslot
+				.decrypt_master_key_from_prehashed(old_key.clone())
+				.await
+			{
+				*keyslot = Keyslot::new(
+					keyslot.version,
+					keyslot.algorithm,
+					new_stored_key.hashing_algorithm,
+					new_stored_key.content_salt,
+					new_key,
+					master_key,
+				)
+				.await?;
+
+				return Ok(true);
+			}
+		}
+
+		Ok(false)
 	}
 
-	/// This is for changing the automount status of a key in the keystore.
+	/// This finishes a rotation, and should only be called once all affected headers have been re-wrapped and written.
 	///
-	/// The database needs to be updated externally
-	pub async fn change_automount_status(&self, uuid: Uuid, status: bool) -> Result<()> {
+	/// The old key is unmounted and removed from the keystore, and the new key replaces it as the default (if the old key was the default).
+	///
+	/// The old key should then be removed from the database, and the `KeyRotation` discarded.
+	pub async fn finish_rotation(&self, rotation: &KeyRotation) -> Result<()> {
 		self.ensure_unlocked().await?;
+		self.ensure_rotation(rotation)?;
 
-		let updated_key = self
-			.keystore
-			.get(&uuid)
-			.map_or(Err(Error::KeyNotFound), |v| {
-				let mut updated_key = v.clone();
-				updated_key.automount = status;
-				Ok(updated_key)
-			})?;
+		self.rotations.remove(&rotation.old);
 
-		self.keystore.remove(&uuid);
-		self.keystore.insert(uuid, updated_key);
-		Ok(())
+		// do this manually to prevent deadlocks
+		let mut default = self.default.lock().await;
+		if *default == Some(rotation.old) {
+			*default = Some(rotation.new);
+		}
+		drop(default);
+
+		self.remove_key(rotation.old).await
 	}
 
-	/// This function is for getting an entire collection of hashed keys.
-	///
-	/// These are ideal for passing over to decryption functions, as each decryption attempt is negligible, performance wise.
-	#[must_use]
-	pub fn enumerate_hashed_keys(&self) -> Vec<Key> {
-		self.keymount
-			.iter()
-			.map(|mounted_key| mounted_key.hashed_key.clone())
-			.collect::<Vec<Key>>()
+	/// This verifies that the rotation is in progress before continuing the operation.
+	fn ensure_rotation(&self, rotation: &KeyRotation) -> Result<()> {
+		self.rotations
+			.get(&rotation.old)
+			.filter(|r| **r == *rotation)
+			.map_or(Err(Error::KeyNotRotating), |_| Ok(()))
 	}
 
-	/// This function is for converting a memory-only key to a saved key which syncs to the library.
+	/// This re-encrypts a file from one key to another, without the plaintext ever being written to disk.
 	///
-	/// The returned value needs to be written to the database.
-	pub async fn sync_to_database(&self, uuid: Uuid) -> Result<StoredKey> {
-		if !self.is_memory_only(uuid).await? {
-			return Err(Error::KeyNotMemoryOnly);
+	/// A new master key is generated and the contents are re-encrypted with it, so the old master key can't be used to decrypt the new file.
+	///
+	/// Both keys must be mounted. Preview media and the segment size are carried over, as is metadata if the `serde` feature is enabled.
+	///
+	/// The new file is written alongside the original and then renamed over it, so the original is left untouched if this is interrupted.
+	///
+	/// Files that are bound to a context can't be re-encrypted here, as the context isn't known, and `Error::AadMismatch` is returned.
+	pub async fn reencrypt_file<P>(&self, path: P, from: Uuid, to: Uuid) -> Result<()>
+	where
+		P: AsRef<Path> + Send,
+	{
+		let from_key = self.access_mounted_key(from).await?.hashed_key;
+		let to_key = self.access_mounted_key(to).await?.hashed_key;
+		let to_stored_key = self.access_keystore(to).await?;
+
+		let path = path.as_ref();
+		let mut reader = File::open(path).await?;
+		let (header, aad) = FileHeader::from_reader(&mut reader).await?;
+
+		if header.aad_binding != AadBinding::Header {
+			return Err(Error::AadMismatch);
+		}
+
+		let master_key = header
+			.decrypt_master_key_from_prehashed(vec![from_key.clone()])
+			.await?;
+
+		let new_master_key = Key::generate();
+		let mut new_header = FileHeader::new(
+			LATEST_FILE_HEADER,
+			header.algorithm,
+			vec![
+				Keyslot::new(
+					LATEST_KEYSLOT,
+					header.algorithm,
+					to_stored_key.hashing_algorithm,
+					to_stored_key.content_salt,
+					to_key,
+					new_master_key.clone(),
+				)
+				.await?,
+			],
+		)?;
+		new_header.set_segment_size(header.segment_size)?;
+
+		if header.preview_media.is_some() {
+			let media = header
+				.decrypt_preview_media_from_prehashed(vec![from_key.clone()])
+				.await?;
+
+			new_header
+				.add_preview_media(
+					LATEST_PREVIEW_MEDIA,
+					header.algorithm,
+					new_master_key.clone(),
+					media.expose(),
+				)
+				.await?;
+		}
+
+		#[cfg(feature = "serde")]
+		if header.metadata.is_some() {
+			let metadata: serde_json::Value = header
+				.decrypt_metadata_from_prehashed(vec![from_key])
+				.await?;
+
+			new_header
+				.add_metadata(
+					crate::primitives::LATEST_METADATA,
+					header.algorithm,
+					new_master_key.clone(),
+					&metadata,
+				)
+				.await?;
+		}
+
+		let mut temp_path = path.as_os_str().to_owned();
+		temp_path.push(format!(".{}.tmp", Uuid::new_v4()));
+
+		let result = async {
+			let mut writer = File::create(&temp_path).await?;
+			new_header.write(&mut writer).await?;
+
+			if let Some(segment_size) = header.segment_size {
+				reencrypt_segments(
+					master_key,
+					new_master_key,
+					header.nonce,
+					new_header.nonce,
+					header.algorithm,
+					segment_size as usize,
+					&mut reader,
+					&mut writer,
+					&aad,
+					&new_header.generate_aad(),
+				)
+				.await?;
+			} else {
+				Self::reencrypt_streams(
+					Decryptor::new(master_key, header.nonce, header.algorithm)?,
+					Encryptor::new(new_master_key, new_header.nonce, new_header.algorithm)?,
+					&mut reader,
+					&mut writer,
+					&aad,
+					&new_header.generate_aad(),
+				)
+				.await?;
+			}
+
+			writer.sync_all().await?;
+			Ok::<(), Error>(())
+		}
+		.await;
+
+		// the original must be closed before it can be replaced on some platforms
+		drop(reader);
+
+		match result {
+			Ok(()) => Ok(fs::rename(&temp_path, path).await?),
+			Err(e) => {
+				fs::remove_file(&temp_path).await.ok();
+				Err(e)
+			}
+		}
+	}
+
+	/// This decrypts each block and immediately encrypts it again, so only a single block of plaintext is held in memory at a time.
+	///
+	/// The blocks line up, as every block apart from the last is `BLOCK_LEN` plaintext bytes (plus the AEAD tag).
+	async fn reencrypt_streams<R, W>(
+		mut decryptor: Decryptor,
+		mut encryptor: Encryptor,
+		reader: &mut R,
+		writer: &mut W,
+		aad: &[u8],
+		new_aad: &[u8],
+	) -> Result<()>
+	where
+		R: AsyncReadExt + Unpin + Send,
+		W: AsyncWriteExt + Unpin + Send,
+	{
+		let mut buffer = vec![0u8; BLOCK_LEN + AEAD_TAG_LEN].into_boxed_slice();
+
+		loop {
+			let count = exhaustive_read(reader, &mut buffer).await?;
+
+			let payload = Payload {
+				aad,
+				msg: &buffer[..count],
+			};
+
+			if count == buffer.len() {
+				let mut plaintext = decryptor.decrypt_next(payload)?;
+				let ciphertext = encryptor.encrypt_next(Payload {
+					aad: new_aad,
+					msg: &plaintext,
+				});
+				plaintext.zeroize();
+
+				writer.write_all(&ciphertext?).await?;
+			} else {
+				let mut plaintext = decryptor.decrypt_last(payload)?;
+				let ciphertext = encryptor.encrypt_last(Payload {
+					aad: new_aad,
+					msg: &plaintext,
+				});
+				plaintext.zeroize();
+
+				writer.write_all(&ciphertext?).await?;
+				break;
+			}
+		}
+
+		writer.flush().await?;
+
+		Ok(())
+	}
+
+	/// This returns `Error::KeyNotMounted` if the key exists but isn't mounted.
+	async fn access_mounted_key(&self, uuid: Uuid) -> Result<MountedKey> {
+		self.ensure_unlocked().await?;
+
+		if !self.keystore.contains_key(&uuid) {
+			return Err(Error::KeyNotFound);
+		}
+
+		self.refresh_expiry(uuid);
+
+		self.keymount
+			.get(&uuid)
+			.map_or(Err(Error::KeyNotMounted), |v| Ok(v.clone()))
+	}
+
+	/// This hashes a stored key with its content salt, without mounting it.
+	async fn hash_key(&self, uuid: Uuid) -> Result<Key> {
+		if let Some(mounted_key) = self.keymount.get(&uuid) {
+			return Ok(mounted_key.hashed_key.clone());
+		}
+
+		let stored_key = self.access_keystore(uuid).await?;
+		let key = self.get_key(uuid).await?;
+
+		stored_key.hashing_algorithm.hash(
+			Protected::new(key.expose().as_bytes().to_vec()),
+			stored_key.content_salt,
+			None,
+		)
+	}
+
+	/// This function is for accessing the internal keymount.
+	///
+	/// We could add a log to this, so that the user can view accesses
+	///
+	/// `Error::KeyNotMounted` is returned for keys that are in the keystore, but aren't mounted (e.g. they've expired).
+	pub async fn access_keymount(&self, uuid: Uuid) -> Result<MountedKey> {
+		self.ensure_unlocked().await?;
+		self.refresh_expiry(uuid);
+
+		self.keymount.get(&uuid).map_or_else(
+			|| {
+				if self.keystore.contains_key(&uuid) {
+					Err(Error::KeyNotMounted)
+				} else {
+					Err(Error::KeyNotFound)
+				}
+			},
+			|v| Ok(v.clone()),
+		)
+	}
+
+	/// This function is for accessing a `StoredKey`.
+	pub async fn access_keystore(&self, uuid: Uuid) -> Result<StoredKey> {
+		self.ensure_unlocked().await?;
+
+		self.keystore
+			.get(&uuid)
+			.map_or(Err(Error::KeyNotFound), |v| Ok(v.clone()))
+	}
+
+	/// This function changes a key's label, which must be between 1 and `MAX_KEY_LABEL_LEN` characters.
+	///
+	/// The label is only metadata, so the updated `StoredKey` (from `KeyManager::access_keystore()`) should be written to the database.
+	pub async fn rename_key(&self, uuid: Uuid, label: String) -> Result<()> {
+		self.ensure_unlocked().await?;
+
+		if label.trim().is_empty() || label.chars().count() > MAX_KEY_LABEL_LEN {
+			return Err(Error::InvalidKeyLabel);
+		}
+
+		self.keystore
+			.get_mut(&uuid)
+			.map_or(Err(Error::KeyNotFound), |mut key| {
+				key.label = label;
+				Ok(())
+			})
+	}
+
+	/// This function changes (or clears) a key's description, which can't be longer than `MAX_KEY_DESCRIPTION_LEN` characters.
+	pub async fn set_key_description(&self, uuid: Uuid, description: Option<String>) -> Result<()> {
+		self.ensure_unlocked().await?;
+
+		if description
+			.as_ref()
+			.map_or(false, |d| d.chars().count() > MAX_KEY_DESCRIPTION_LEN)
+		{
+			return Err(Error::InvalidKeyDescription);
+		}
+
+		self.keystore
+			.get_mut(&uuid)
+			.map_or(Err(Error::KeyNotFound), |mut key| {
+				key.description = description;
+				Ok(())
+			})
+	}
+
+	/// This allows you to set the default key, or clear it by providing `None`
+	///
+	/// The key manager doesn't persist this, so it should also be written to the database.
+	pub async fn set_default(&self, uuid: Option<Uuid>) -> Result<()> {
+		self.ensure_unlocked().await?;
+
+		if uuid.map_or(true, |uuid| self.keystore.contains_key(&uuid)) {
+			*self.default.lock().await = uuid;
+			Ok(())
+		} else {
+			Err(Error::KeyNotFound)
+		}
+	}
+
+	/// This allows you to get the default key's UUID
+	///
+	/// If the default key is no longer in the keystore, it's cleared and `Error::KeyNotFound` is returned.
+	pub async fn get_default(&self) -> Result<Uuid> {
+		self.ensure_unlocked().await?;
+
+		let mut default = self.default.lock().await;
+		let uuid = default.ok_or(Error::NoDefaultKeySet)?;
+
+		if self.keystore.contains_key(&uuid) {
+			Ok(uuid)
+		} else {
+			*default = None;
+			Err(Error::KeyNotFound)
+		}
+	}
+
+	/// This should ONLY be used internally, for accessing the root key.
+	async fn get_root_key(&self) -> Result<Key> {
+		self.root_key.lock().await.clone().ok_or(Error::NotUnlocked)
+	}
+
+	pub async fn get_verification_key(&self) -> Result<StoredKey> {
+		self.verification_key
+			.lock()
+			.await
+			.clone()
+			.ok_or(Error::NoVerificationKey)
+	}
+
+	/// This is used for checking if a key is memory only.
+	pub async fn is_memory_only(&self, uuid: Uuid) -> Result<bool> {
+		self.ensure_unlocked().await?;
+
+		self.keystore
+			.get(&uuid)
+			.map_or(Err(Error::KeyNotFound), |v| Ok(v.memory_only))
+	}
+
+	/// This is for changing the automount status of a key in the keystore.
+	///
+	/// The database needs to be updated externally
+	pub async fn change_automount_status(&self, uuid: Uuid, status: bool) -> Result<()> {
+		self.ensure_unlocked().await?;
+
+		let updated_key = self
+			.keystore
+			.get(&uuid)
+			.map_or(Err(Error::KeyNotFound), |v| {
+				let mut updated_key = v.clone();
+				updated_key.automount = status;
+				Ok(updated_key)
+			})?;
+
+		self.keystore.remove(&uuid);
+		self.keystore.insert(uuid, updated_key);
+		Ok(())
+	}
+
+	/// This function is for getting an entire collection of hashed keys.
+	///
+	/// These are ideal for passing over to decryption functions, as each decryption attempt is negligible, performance wise.
+	#[must_use]
+	pub fn enumerate_hashed_keys(&self) -> Vec<Key> {
+		self.keymount
+			.iter()
+			.map(|mounted_key| {
+				self.refresh_expiry(mounted_key.uuid);
+				mounted_key.hashed_key.clone()
+			})
+			.collect::<Vec<Key>>()
+	}
+
+	/// This checks that an encrypted file hasn't been altered, using whichever mounted key can decrypt its master key (just like a normal decryption).
+	///
+	/// The reader should be at the start of the file. None of the plaintext is written, so this is suitable for scrubbing encrypted files in the background.
+	///
+	/// Files that are bound to a context can't be verified here, as the context isn't known, and `Error::AadMismatch` is returned.
+	/// `Error::AuthenticationFailed` is returned with the index of the first block that fails authentication.
+	pub async fn verify_integrity<R>(&self, mut reader: R) -> Result<()>
+	where
+		R: AsyncReadExt + AsyncSeekExt + Unpin + Send,
+	{
+		let (header, aad) = FileHeader::from_reader(&mut reader).await?;
+		let master_key = header
+			.decrypt_master_key_from_prehashed(self.enumerate_hashed_keys())
+			.await?;
+
+		header
+			.verify_integrity(master_key, reader, &aad, None)
+			.await
+	}
+
+	/// This function is for converting a memory-only key to a saved key which syncs to the library.
+	///
+	/// The returned value needs to be written to the database.
+	pub async fn sync_to_database(&self, uuid: Uuid) -> Result<StoredKey> {
+		if !self.is_memory_only(uuid).await? {
+			return Err(Error::KeyNotMemoryOnly);
 		}
 
 		let updated_key = self
@@ -1004,13 +2203,20 @@ impl KeyManager {
 		// if it doesn't, we're going to need to find another way to call drop on these values
 		// that way they will be zeroized and removed from memory fully
 		self.keymount.clear();
+		self.expiries.clear();
 	}
 
 	/// This function is for unmounting a key from the key manager
 	///
 	/// This does not remove the key from the key store
+	///
+	/// If the key was mounted with a TTL, it's cancelled.
+	///
+	/// The hashed key is wiped as soon as it's removed, rather than whenever the removed value is dropped.
 	pub fn unmount(&self, uuid: Uuid) -> Result<()> {
-		self.keymount.remove(&uuid).ok_or(Error::KeyNotMounted)?;
+		self.expiries.remove(&uuid);
+		let (_, mut mounted_key) = self.keymount.remove(&uuid).ok_or(Error::KeyNotMounted)?;
+		mounted_key.hashed_key.wipe();
 
 		Ok(())
 	}
@@ -1027,6 +2233,28 @@ impl KeyManager {
 		self.keymount.iter().map(|key| key.uuid).collect()
 	}
 
+	/// This function returns information about all mounted keys, without exposing any of their secret material
+	pub async fn mounted_keys(&self) -> Vec<KeyInfo> {
+		let default = *self.default.lock().await;
+
+		self.keymount
+			.iter()
+			.filter_map(|mounted| {
+				self.keystore.get(&mounted.uuid).map(|stored| KeyInfo {
+					uuid: stored.uuid,
+					label: stored.label.clone(),
+					description: stored.description.clone(),
+					key_type: stored.key_type.clone(),
+					algorithm: stored.algorithm,
+					hashing_algorithm: stored.hashing_algorithm,
+					default: default == Some(stored.uuid),
+					memory_only: stored.memory_only,
+					automount: stored.automount,
+				})
+			})
+			.collect()
+	}
+
 	/// This function gets the entire internal key manager queue
 	pub fn get_queue(&self) -> Vec<Uuid> {
 		self.mounting_queue.iter().map(|u| *u).collect()
@@ -1044,6 +2272,20 @@ impl KeyManager {
 			.contains(&self.get_verification_key().await?.uuid))
 	}
 
+	/// This function checks to see if a key is part of a rotation (either as the old or the new key)
+	#[must_use]
+	pub fn is_rotating(&self, uuid: Uuid) -> bool {
+		self.rotations
+			.iter()
+			.any(|r| r.old == uuid || r.new == uuid)
+	}
+
+	/// This function returns all rotations that are in progress
+	#[must_use]
+	pub fn get_rotations(&self) -> Vec<KeyRotation> {
+		self.rotations.iter().map(|r| *r).collect()
+	}
+
 	/// This function removes a key from the mounting queue (if present)
 	pub fn remove_from_queue(&self, uuid: Uuid) -> Result<()> {
 		self.mounting_queue
@@ -1053,3 +2295,1326 @@ impl KeyManager {
 		Ok(())
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use crate::{primitives::KEY_LEN, types::Params};
+
+	use rand::{RngCore, SeedableRng};
+
+	use super::*;
+
+	const ALGORITHM: Algorithm = Algorithm::XChaCha20Poly1305;
+	const HASHING_ALGORITHM: HashingAlgorithm = HashingAlgorithm::Argon2id(Params::Standard);
+
+	// unlocking requires the secret key from the OS keyring, so the root key is set directly instead
+	async fn unlocked_key_manager(stored_keys: Vec<StoredKey>, root_key: Key) -> KeyManager {
+		let key_manager = KeyManager::new(stored_keys).await.unwrap();
+		*key_manager.root_key.lock().await = Some(root_key);
+		key_manager
+	}
+
+	async fn add_key(key_manager: &KeyManager, password: &str, content_salt: Option<Salt>) -> Uuid {
+		key_manager
+			.add_to_keystore(
+				Protected::new(password.to_string()),
+				ALGORITHM,
+				HASHING_ALGORITHM,
+				false,
+				false,
+				content_salt,
+			)
+			.await
+			.unwrap()
+	}
+
+	async fn add_mounted_key(key_manager: &KeyManager) -> Uuid {
+		let uuid = key_manager
+			.add_to_keystore(
+				Protected::new("password".to_string()),
+				ALGORITHM,
+				HASHING_ALGORITHM,
+				false,
+				false,
+				None,
+			)
+			.await
+			.unwrap();
+		key_manager.mount(uuid).await.unwrap();
+		uuid
+	}
+
+	async fn header_for_key(key_manager: &KeyManager, uuid: Uuid, master_key: Key) -> FileHeader {
+		let stored_key = key_manager.access_keystore(uuid).await.unwrap();
+		let hashed_key = key_manager.access_keymount(uuid).await.unwrap().hashed_key;
+
+		FileHeader::new(
+			LATEST_FILE_HEADER,
+			ALGORITHM,
+			vec![Keyslot::new(
+				LATEST_KEYSLOT,
+				ALGORITHM,
+				stored_key.hashing_algorithm,
+				stored_key.content_salt,
+				hashed_key,
+				master_key,
+			)
+			.await
+			.unwrap()],
+		)
+		.unwrap()
+	}
+
+	async fn assert_accessible(key_manager: &KeyManager, header: &FileHeader, master_key: &Key) {
+		let decrypted_key = header
+			.decrypt_master_key_from_prehashed(key_manager.enumerate_hashed_keys())
+			.await
+			.unwrap();
+		assert_eq!(decrypted_key.expose(), master_key.expose());
+	}
+
+	#[cfg(feature = "serde")]
+	#[tokio::test]
+	async fn stored_key_without_algorithm() {
+		let key_manager = unlocked_key_manager(vec![], Key::generate()).await;
+		let uuid = key_manager
+			.add_to_keystore(
+				Protected::new("password".to_string()),
+				Algorithm::Aes256Gcm,
+				HASHING_ALGORITHM,
+				false,
+				false,
+				None,
+			)
+			.await
+			.unwrap();
+		let stored_key = key_manager.access_keystore(uuid).await.unwrap();
+
+		let mut value = serde_json::to_value(&stored_key).unwrap();
+		assert!(serde_json::from_value::<StoredKey>(value.clone()).unwrap() == stored_key);
+
+		// keys which were stored before the algorithm was recorded use the original algorithm
+		value.as_object_mut().unwrap().remove("algorithm");
+		let stored_key = serde_json::from_value::<StoredKey>(value).unwrap();
+		assert!(stored_key.algorithm == Algorithm::XChaCha20Poly1305);
+	}
+
+	#[tokio::test]
+	async fn rotate_key() {
+		let key_manager = unlocked_key_manager(vec![], Key::generate()).await;
+		let old = add_mounted_key(&key_manager).await;
+		key_manager.set_default(Some(old)).await.unwrap();
+
+		let master_key = Key::generate();
+		let mut header = header_for_key(&key_manager, old, master_key.clone()).await;
+
+		let rotation = key_manager.rotate_key(old).await.unwrap();
+		assert!(matches!(
+			key_manager.rotate_key(old).await,
+			Err(Error::KeyRotationInProgress)
+		));
+		assert!(matches!(
+			key_manager.remove_key(old).await,
+			Err(Error::KeyRotationInProgress)
+		));
+		assert!(key_manager.get_mounted_uuids().contains(&rotation.new));
+
+		assert!(key_manager
+			.rewrap_keyslots(&rotation, &mut header)
+			.await
+			.unwrap());
+		assert!(!key_manager
+			.rewrap_keyslots(&rotation, &mut header)
+			.await
+			.unwrap());
+
+		key_manager.finish_rotation(&rotation).await.unwrap();
+		assert!(key_manager.access_keystore(old).await.is_err());
+		assert!(!key_manager.get_mounted_uuids().contains(&old));
+		assert_eq!(key_manager.get_default().await.unwrap(), rotation.new);
+		assert!(!key_manager.is_rotating(rotation.new));
+
+		assert_accessible(&key_manager, &header, &master_key).await;
+	}
+
+	#[tokio::test]
+	async fn resume_rotation_after_crash() {
+		let root_key = Key::generate();
+		let key_manager = unlocked_key_manager(vec![], root_key.clone()).await;
+		let old = add_mounted_key(&key_manager).await;
+
+		let master_key = Key::generate();
+		let mut header = header_for_key(&key_manager, old, master_key.clone()).await;
+
+		let rotation = key_manager.rotate_key(old).await.unwrap();
+		assert!(key_manager
+			.rewrap_keyslots(&rotation, &mut header)
+			.await
+			.unwrap());
+
+		// simulate a crash before the rotation finished, only keeping what was written to the database
+		let stored_keys = key_manager.dump_keystore();
+		drop(key_manager);
+
+		let key_manager = unlocked_key_manager(stored_keys, root_key).await;
+		key_manager.mount(old).await.unwrap();
+
+		assert!(matches!(
+			key_manager.rewrap_keyslots(&rotation, &mut header).await,
+			Err(Error::KeyNotRotating)
+		));
+
+		key_manager.resume_rotation(rotation).await.unwrap();
+		assert!(key_manager.get_mounted_uuids().contains(&rotation.new));
+		assert_accessible(&key_manager, &header, &master_key).await;
+
+		// the header was re-wrapped before the crash
+		assert!(!key_manager
+			.rewrap_keyslots(&rotation, &mut header)
+			.await
+			.unwrap());
+
+		key_manager.finish_rotation(&rotation).await.unwrap();
+		assert_accessible(&key_manager, &header, &master_key).await;
+
+		// the old key is gone, so the rotation can't be resumed again
+		assert!(matches!(
+			key_manager.resume_rotation(rotation).await,
+			Err(Error::KeyNotFound)
+		));
+	}
+
+	#[tokio::test]
+	async fn change_master_password() {
+		let root_key = Key::generate();
+		let secret_key = SecretKey::generate();
+		let verification_key = KeyManager::wrap_root_key(
+			&root_key,
+			Protected::new("old password".to_string()),
+			secret_key.clone(),
+			ALGORITHM,
+			HASHING_ALGORITHM,
+		)
+		.await
+		.unwrap();
+
+		let key_manager = KeyManager::new(vec![verification_key.clone()])
+			.await
+			.unwrap();
+		key_manager
+			.unlock(
+				Protected::new("old password".to_string()),
+				Some(secret_key.clone().into()),
+				Uuid::nil(),
+				|| (),
+			)
+			.await
+			.unwrap();
+
+		let uuid = add_mounted_key(&key_manager).await;
+		let key = key_manager.get_key(uuid).await.unwrap();
+
+		assert!(matches!(
+			key_manager
+				.change_master_password(
+					Protected::new("wrong password".to_string()),
+					Some(secret_key.clone().into()),
+					Protected::new("new password".to_string()),
+					ALGORITHM,
+					HASHING_ALGORITHM,
+					Uuid::nil(),
+				)
+				.await,
+			Err(Error::IncorrectPassword)
+		));
+		assert_eq!(
+			key_manager.get_verification_key().await.unwrap().uuid,
+			verification_key.uuid
+		);
+
+		let (new_verification_key, new_secret_key) = key_manager
+			.change_master_password(
+				Protected::new("old password".to_string()),
+				Some(secret_key.into()),
+				Protected::new("new password".to_string()),
+				ALGORITHM,
+				HASHING_ALGORITHM,
+				Uuid::nil(),
+			)
+			.await
+			.unwrap();
+
+		// only the verification key is replaced, the stored keys are untouched
+		let mut stored_keys = key_manager.dump_keystore();
+		stored_keys.push(new_verification_key);
+		drop(key_manager);
+
+		let key_manager = KeyManager::new(stored_keys).await.unwrap();
+		assert!(matches!(
+			key_manager
+				.unlock(
+					Protected::new("old password".to_string()),
+					Some(new_secret_key.clone()),
+					Uuid::nil(),
+					|| (),
+				)
+				.await,
+			Err(Error::IncorrectPassword)
+		));
+
+		key_manager
+			.unlock(
+				Protected::new("new password".to_string()),
+				Some(new_secret_key),
+				Uuid::nil(),
+				|| (),
+			)
+			.await
+			.unwrap();
+		key_manager.mount(uuid).await.unwrap();
+		assert_eq!(
+			key_manager.get_key(uuid).await.unwrap().expose(),
+			key.expose()
+		);
+	}
+
+	#[tokio::test]
+	async fn change_master_password_rolls_back_on_failure() {
+		let root_key = Key::generate();
+		let secret_key = SecretKey::generate();
+		let (key_manager, verification_key) =
+			key_manager_with_verification_key(&root_key, &secret_key).await;
+
+		let uuid = add_mounted_key(&key_manager).await;
+		let key = key_manager.get_key(uuid).await.unwrap();
+		let stored_key = key_manager.access_keystore(uuid).await.unwrap();
+
+		// the master password is right but the secret key isn't, so the root key can't be decrypted
+		assert!(matches!(
+			key_manager
+				.change_master_password(
+					Protected::new("password".to_string()),
+					Some(SecretKey::generate().into()),
+					Protected::new("new password".to_string()),
+					ALGORITHM,
+					HASHING_ALGORITHM,
+					Uuid::nil(),
+				)
+				.await,
+			Err(Error::IncorrectPassword)
+		));
+
+		assert_eq!(
+			key_manager.get_verification_key().await.unwrap().uuid,
+			verification_key.uuid
+		);
+		assert!(
+			key_manager.access_keystore(uuid).await.unwrap().master_key == stored_key.master_key
+		);
+		assert_eq!(
+			key_manager.get_key(uuid).await.unwrap().expose(),
+			key.expose()
+		);
+
+		// the old master password and secret key still unlock the same keys
+		let mut stored_keys = key_manager.dump_keystore();
+		stored_keys.push(key_manager.get_verification_key().await.unwrap());
+		drop(key_manager);
+
+		let key_manager = KeyManager::new(stored_keys).await.unwrap();
+		key_manager
+			.unlock(
+				Protected::new("password".to_string()),
+				Some(secret_key.into()),
+				Uuid::nil(),
+				|| (),
+			)
+			.await
+			.unwrap();
+		key_manager.mount(uuid).await.unwrap();
+		assert_eq!(
+			key_manager.get_key(uuid).await.unwrap().expose(),
+			key.expose()
+		);
+	}
+
+	async fn key_manager_with_verification_key(
+		root_key: &Key,
+		secret_key: &SecretKey,
+	) -> (KeyManager, StoredKey) {
+		let verification_key = KeyManager::wrap_root_key(
+			root_key,
+			Protected::new("password".to_string()),
+			secret_key.clone(),
+			ALGORITHM,
+			HASHING_ALGORITHM,
+		)
+		.await
+		.unwrap();
+
+		let key_manager = KeyManager::new(vec![verification_key.clone()])
+			.await
+			.unwrap();
+		key_manager
+			.unlock(
+				Protected::new("password".to_string()),
+				Some(secret_key.clone().into()),
+				Uuid::nil(),
+				|| (),
+			)
+			.await
+			.unwrap();
+
+		(key_manager, verification_key)
+	}
+
+	#[tokio::test]
+	async fn rotate_root_key() {
+		let root_key = Key::generate();
+		let secret_key = SecretKey::generate();
+		let (key_manager, old_verification_key) =
+			key_manager_with_verification_key(&root_key, &secret_key).await;
+
+		let mounted = add_mounted_key(&key_manager).await;
+		let unmounted = [
+			add_key(&key_manager, "first password", None).await,
+			add_key(&key_manager, "second password", None).await,
+		];
+
+		assert!(matches!(
+			key_manager
+				.rotate_root_key(
+					Protected::new("wrong password".to_string()),
+					Some(secret_key.clone().into()),
+					Uuid::nil(),
+				)
+				.await,
+			Err(Error::IncorrectPassword)
+		));
+
+		let (verification_key, stored_keys) = key_manager
+			.rotate_root_key(
+				Protected::new("password".to_string()),
+				Some(secret_key.clone().into()),
+				Uuid::nil(),
+			)
+			.await
+			.unwrap();
+
+		assert_eq!(stored_keys.len(), 3);
+		assert_ne!(verification_key.uuid, old_verification_key.uuid);
+		assert!(key_manager.get_mounted_uuids().contains(&mounted));
+
+		// keys can still be mounted (and added) before the key manager is reloaded
+		key_manager.mount(unmounted[0]).await.unwrap();
+		let added = add_key(&key_manager, "third password", None).await;
+
+		let mut stored_keys = key_manager.dump_keystore();
+
+		// the old verification key unlocks the old root key, which can't mount any of the stored keys
+		let old_key_manager =
+			KeyManager::new([stored_keys.clone(), vec![old_verification_key]].concat())
+				.await
+				.unwrap();
+		old_key_manager
+			.unlock(
+				Protected::new("password".to_string()),
+				Some(secret_key.clone().into()),
+				Uuid::nil(),
+				|| (),
+			)
+			.await
+			.unwrap();
+		assert!(matches!(
+			old_key_manager.mount(mounted).await,
+			Err(Error::IncorrectPassword)
+		));
+
+		stored_keys.push(verification_key);
+		drop(key_manager);
+
+		let key_manager = KeyManager::new(stored_keys).await.unwrap();
+		key_manager
+			.unlock(
+				Protected::new("password".to_string()),
+				Some(secret_key.into()),
+				Uuid::nil(),
+				|| (),
+			)
+			.await
+			.unwrap();
+
+		for (uuid, password) in [
+			(mounted, "password"),
+			(unmounted[0], "first password"),
+			(unmounted[1], "second password"),
+			(added, "third password"),
+		] {
+			key_manager.mount(uuid).await.unwrap();
+			assert_eq!(key_manager.get_key(uuid).await.unwrap().expose(), password);
+		}
+	}
+
+	#[tokio::test]
+	async fn rotate_root_key_rolls_back_on_failure() {
+		let root_key = Key::generate();
+		let secret_key = SecretKey::generate();
+		let (key_manager, verification_key) =
+			key_manager_with_verification_key(&root_key, &secret_key).await;
+
+		let valid = add_key(&key_manager, "password", None).await;
+		let corrupt = add_key(&key_manager, "password", None).await;
+		key_manager
+			.keystore
+			.get_mut(&corrupt)
+			.unwrap()
+			.master_key_nonce = Nonce::generate(ALGORITHM).unwrap();
+
+		let stored_keys = key_manager.access_keystore(valid).await.unwrap();
+
+		assert!(key_manager
+			.rotate_root_key(
+				Protected::new("password".to_string()),
+				Some(secret_key.into()),
+				Uuid::nil(),
+			)
+			.await
+			.is_err());
+
+		// nothing was replaced, so the key that could be re-encrypted is still encrypted with the old root key
+		assert_eq!(
+			key_manager.get_verification_key().await.unwrap().uuid,
+			verification_key.uuid
+		);
+		assert!(
+			key_manager.access_keystore(valid).await.unwrap().master_key == stored_keys.master_key
+		);
+		key_manager.mount(valid).await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn rotate_root_key_without_verification_key() {
+		let key_manager = unlocked_key_manager(vec![], Key::generate()).await;
+
+		assert!(matches!(
+			key_manager
+				.rotate_root_key(
+					Protected::new("password".to_string()),
+					Some(SecretKey::generate().into()),
+					Uuid::nil(),
+				)
+				.await,
+			Err(Error::NoVerificationKey)
+		));
+	}
+
+	#[derive(Default, Clone)]
+	struct MemoryKeyring(Arc<std::sync::Mutex<Option<Vec<u8>>>>);
+
+	impl Keyring for MemoryKeyring {
+		fn insert(&self, _: Identifier, value: SecretKeyString) -> Result<()> {
+			*self.0.lock().unwrap() = Some(value.expose().as_bytes().to_vec());
+			Ok(())
+		}
+
+		fn retrieve(&self, _: Identifier) -> Result<Protected<Vec<u8>>> {
+			self.0
+				.lock()
+				.unwrap()
+				.clone()
+				.map(Protected::new)
+				.ok_or(Error::KeyringError)
+		}
+
+		fn delete(&self, _: Identifier) -> Result<()> {
+			*self.0.lock().unwrap() = None;
+			Ok(())
+		}
+	}
+
+	struct MissingKeyring;
+
+	impl Keyring for MissingKeyring {
+		fn insert(&self, _: Identifier, _: SecretKeyString) -> Result<()> {
+			Err(Error::KeyringNotSupported)
+		}
+
+		fn retrieve(&self, _: Identifier) -> Result<Protected<Vec<u8>>> {
+			Err(Error::KeyringNotSupported)
+		}
+
+		fn delete(&self, _: Identifier) -> Result<()> {
+			Err(Error::KeyringNotSupported)
+		}
+	}
+
+	fn onboarding_config() -> OnboardingConfig {
+		OnboardingConfig {
+			password: Protected::new("password".to_string()),
+			algorithm: ALGORITHM,
+			hashing_algorithm: HASHING_ALGORITHM,
+		}
+	}
+
+	#[tokio::test]
+	async fn onboard_with_keyring() {
+		let library_uuid = Uuid::new_v4();
+		let keyring = MemoryKeyring::default();
+		let key_manager = KeyManager::with_keyring(vec![], Box::new(keyring.clone()))
+			.await
+			.unwrap();
+
+		let verification_key = key_manager
+			.onboard(onboarding_config(), library_uuid)
+			.await
+			.unwrap();
+		assert!(keyring.0.lock().unwrap().is_some());
+
+		// the secret key is sourced from the keyring
+		key_manager
+			.populate_keystore(vec![verification_key])
+			.await
+			.unwrap();
+		key_manager
+			.unlock(
+				Protected::new("password".to_string()),
+				None,
+				library_uuid,
+				|| (),
+			)
+			.await
+			.unwrap();
+		assert!(key_manager.is_unlocked().await);
+	}
+
+	#[tokio::test]
+	async fn onboard_without_keyring() {
+		let key_manager = KeyManager::with_keyring(vec![], Box::new(MissingKeyring))
+			.await
+			.unwrap();
+
+		assert!(matches!(
+			key_manager
+				.onboard(onboarding_config(), Uuid::new_v4())
+				.await,
+			Err(Error::KeyringNotSupported)
+		));
+	}
+
+	#[tokio::test]
+	async fn verify_password() {
+		let key_manager = unlocked_key_manager(vec![], Key::generate()).await;
+		let mounted = add_mounted_key(&key_manager).await;
+		let unmounted = key_manager
+			.add_to_keystore(
+				Protected::new("password".to_string()),
+				ALGORITHM,
+				HASHING_ALGORITHM,
+				false,
+				false,
+				None,
+			)
+			.await
+			.unwrap();
+
+		for uuid in [mounted, unmounted] {
+			assert!(key_manager
+				.verify_password(uuid, Protected::new("password".to_string()))
+				.await
+				.unwrap());
+			assert!(!key_manager
+				.verify_password(uuid, Protected::new("wrong password".to_string()))
+				.await
+				.unwrap());
+		}
+
+		assert_eq!(key_manager.get_mounted_uuids(), vec![mounted]);
+
+		assert!(matches!(
+			key_manager
+				.verify_password(Uuid::new_v4(), Protected::new("password".to_string()))
+				.await,
+			Err(Error::KeyNotFound)
+		));
+	}
+
+	#[tokio::test]
+	async fn mounted_keys() {
+		let key_manager = unlocked_key_manager(vec![], Key::generate()).await;
+		let default = add_mounted_key(&key_manager).await;
+		let other = add_mounted_key(&key_manager).await;
+		key_manager.set_default(Some(default)).await.unwrap();
+
+		let mut keys = key_manager.mounted_keys().await;
+		keys.sort_by_key(|k| k.uuid != default);
+
+		assert_eq!(keys.len(), 2);
+		assert!(keys[0].uuid == default && keys[0].default);
+		assert!(keys[1].uuid == other && !keys[1].default);
+		assert!(keys.iter().all(|k| k.algorithm == ALGORITHM
+			&& k.hashing_algorithm == HASHING_ALGORITHM
+			&& k.key_type == StoredKeyType::User
+			&& k.label == StoredKey::default_label(k.uuid)));
+
+		key_manager.unmount(other).unwrap();
+
+		let keys = key_manager.mounted_keys().await;
+		assert_eq!(keys.len(), 1);
+		assert!(keys[0].uuid == default);
+	}
+
+	#[tokio::test]
+	async fn queue_and_mount_key() {
+		let key_manager = unlocked_key_manager(vec![], Key::generate()).await;
+		let uuid = add_key(&key_manager, "password", None).await;
+		assert_eq!(key_manager.key_state(uuid).unwrap(), KeyState::Unmounted);
+
+		// keys must be queued before they can be mounted with a password
+		assert!(matches!(
+			key_manager
+				.mount_queued(uuid, Protected::new("password".to_string()))
+				.await,
+			Err(Error::KeyNotQueued)
+		));
+
+		key_manager.queue_key(uuid).unwrap();
+		assert_eq!(key_manager.key_state(uuid).unwrap(), KeyState::Queued);
+		assert!(matches!(
+			key_manager.queue_key(uuid),
+			Err(Error::KeyAlreadyQueued)
+		));
+
+		// the key stays queued if the password is wrong
+		assert!(matches!(
+			key_manager
+				.mount_queued(uuid, Protected::new("wrong".to_string()))
+				.await,
+			Err(Error::IncorrectPassword)
+		));
+		assert_eq!(key_manager.key_state(uuid).unwrap(), KeyState::Queued);
+
+		key_manager
+			.mount_queued(uuid, Protected::new("password".to_string()))
+			.await
+			.unwrap();
+		assert_eq!(key_manager.key_state(uuid).unwrap(), KeyState::Mounted);
+		assert!(matches!(
+			key_manager.queue_key(uuid),
+			Err(Error::KeyAlreadyMounted)
+		));
+
+		key_manager.unmount(uuid).unwrap();
+		assert_eq!(key_manager.key_state(uuid).unwrap(), KeyState::Unmounted);
+
+		key_manager.queue_key(uuid).unwrap();
+		key_manager.unqueue_key(uuid).unwrap();
+		assert_eq!(key_manager.key_state(uuid).unwrap(), KeyState::Unmounted);
+		assert!(matches!(
+			key_manager.unqueue_key(uuid),
+			Err(Error::KeyNotQueued)
+		));
+
+		assert!(matches!(
+			key_manager.key_state(Uuid::new_v4()),
+			Err(Error::KeyNotFound)
+		));
+	}
+
+	#[tokio::test]
+	async fn mount_all() {
+		let key_manager = unlocked_key_manager(vec![], Key::generate()).await;
+		let content_salt = Salt::generate();
+
+		let a = add_key(&key_manager, "password", Some(content_salt)).await;
+		let b = add_key(&key_manager, "password", Some(content_salt)).await;
+		let c = add_key(&key_manager, "password", None).await;
+		let other = add_key(&key_manager, "other", None).await;
+		let mounted = add_mounted_key(&key_manager).await;
+
+		let mut report = key_manager
+			.mount_all(Protected::new("password".to_string()))
+			.await
+			.unwrap();
+		report.mounted.sort();
+
+		let mut expected = vec![a, b, c];
+		expected.sort();
+		assert_eq!(report.mounted, expected);
+		assert_eq!(report.skipped, vec![mounted]);
+		assert_eq!(report.mismatched, vec![other]);
+
+		// keys with the same content salt have the same hashed key, so it's reused
+		let hashed_a = key_manager.access_keymount(a).await.unwrap().hashed_key;
+		let hashed_b = key_manager.access_keymount(b).await.unwrap().hashed_key;
+		let hashed_c = key_manager.access_keymount(c).await.unwrap().hashed_key;
+		assert_eq!(hashed_a.expose(), hashed_b.expose());
+		assert_ne!(hashed_a.expose(), hashed_c.expose());
+		assert!(!key_manager.get_mounted_uuids().contains(&other));
+		assert!(key_manager.get_queue().is_empty());
+	}
+
+	#[tokio::test]
+	async fn mount_with_ttl() {
+		let key_manager = unlocked_key_manager(vec![], Key::generate()).await;
+		let mut events = key_manager.subscribe();
+
+		let uuid = add_mounted_key(&key_manager).await;
+		key_manager.unmount(uuid).unwrap();
+		key_manager
+			.mount_with_ttl(uuid, Duration::from_millis(200))
+			.await
+			.unwrap();
+
+		// using the key restarts the TTL
+		for _ in 0..3 {
+			tokio::time::sleep(Duration::from_millis(100)).await;
+			key_manager.access_keymount(uuid).await.unwrap();
+		}
+		assert_eq!(key_manager.get_mounted_uuids(), vec![uuid]);
+
+		assert_eq!(
+			events.recv().await.unwrap(),
+			KeyManagerEvent::KeyExpired(uuid)
+		);
+		assert!(key_manager.get_mounted_uuids().is_empty());
+		assert!(key_manager.expiries.is_empty());
+	}
+
+	#[tokio::test]
+	async fn auto_lock_memory_only_keys() {
+		let key_manager = unlocked_key_manager(vec![], Key::generate()).await;
+		let mut events = key_manager.subscribe();
+		key_manager
+			.set_auto_lock(Some(Duration::from_millis(200)))
+			.await;
+
+		let memory_only = key_manager
+			.add_to_keystore(
+				Protected::new("password".to_string()),
+				ALGORITHM,
+				HASHING_ALGORITHM,
+				true,
+				false,
+				None,
+			)
+			.await
+			.unwrap();
+		// the stored key is added first, as hashing its password could take longer than the TTL
+		let other = add_mounted_key(&key_manager).await;
+		key_manager.mount(memory_only).await.unwrap();
+
+		// using the key restarts the TTL
+		for _ in 0..3 {
+			tokio::time::sleep(Duration::from_millis(100)).await;
+			key_manager.access_keymount(memory_only).await.unwrap();
+		}
+
+		assert_eq!(
+			events.recv().await.unwrap(),
+			KeyManagerEvent::KeyExpired(memory_only)
+		);
+		assert!(matches!(
+			key_manager.access_keymount(memory_only).await,
+			Err(Error::KeyNotMounted)
+		));
+
+		// keys that are stored aren't affected
+		assert_eq!(key_manager.get_mounted_uuids(), vec![other]);
+
+		key_manager.set_auto_lock(None).await;
+		key_manager.mount(memory_only).await.unwrap();
+		assert!(key_manager.expiries.is_empty());
+	}
+
+	#[tokio::test]
+	async fn unmount_removes_key() {
+		let key_manager = unlocked_key_manager(vec![], Key::generate()).await;
+		let uuid = add_mounted_key(&key_manager).await;
+		assert_eq!(key_manager.get_mounted_uuids(), vec![uuid]);
+
+		key_manager.unmount(uuid).unwrap();
+		assert!(key_manager.get_mounted_uuids().is_empty());
+		assert!(matches!(
+			key_manager.access_keymount(uuid).await,
+			Err(Error::KeyNotMounted)
+		));
+		assert!(matches!(
+			key_manager.unmount(uuid),
+			Err(Error::KeyNotMounted)
+		));
+	}
+
+	#[tokio::test]
+	async fn unmount_cancels_ttl() {
+		let key_manager = unlocked_key_manager(vec![], Key::generate()).await;
+		let mut events = key_manager.subscribe();
+
+		let uuid = add_mounted_key(&key_manager).await;
+		key_manager.unmount(uuid).unwrap();
+		key_manager
+			.mount_with_ttl(uuid, Duration::from_millis(50))
+			.await
+			.unwrap();
+		key_manager.unmount(uuid).unwrap();
+		assert!(key_manager.expiries.is_empty());
+
+		// mounting it again without a TTL keeps it mounted
+		key_manager.mount(uuid).await.unwrap();
+		tokio::time::sleep(Duration::from_millis(150)).await;
+
+		assert_eq!(key_manager.get_mounted_uuids(), vec![uuid]);
+		assert!(events.try_recv().is_err());
+	}
+
+	#[tokio::test]
+	async fn set_and_clear_default() {
+		let key_manager = unlocked_key_manager(vec![], Key::generate()).await;
+		let uuid = add_mounted_key(&key_manager).await;
+
+		assert!(matches!(
+			key_manager.get_default().await,
+			Err(Error::NoDefaultKeySet)
+		));
+
+		key_manager.set_default(Some(uuid)).await.unwrap();
+		assert_eq!(key_manager.get_default().await.unwrap(), uuid);
+
+		key_manager.set_default(None).await.unwrap();
+		assert!(matches!(
+			key_manager.get_default().await,
+			Err(Error::NoDefaultKeySet)
+		));
+
+		assert!(matches!(
+			key_manager.set_default(Some(Uuid::new_v4())).await,
+			Err(Error::KeyNotFound)
+		));
+
+		// removing the default key clears it
+		key_manager.set_default(Some(uuid)).await.unwrap();
+		key_manager.remove_key(uuid).await.unwrap();
+		assert!(matches!(
+			key_manager.get_default().await,
+			Err(Error::NoDefaultKeySet)
+		));
+	}
+
+	#[tokio::test]
+	async fn rename_key() {
+		let key_manager = unlocked_key_manager(vec![], Key::generate()).await;
+		let uuid = add_mounted_key(&key_manager).await;
+		let hashed_key = key_manager.access_keymount(uuid).await.unwrap().hashed_key;
+
+		key_manager
+			.rename_key(uuid, "Photos backup key".to_string())
+			.await
+			.unwrap();
+		key_manager
+			.set_key_description(uuid, Some("for the NAS".to_string()))
+			.await
+			.unwrap();
+
+		let stored_key = key_manager.access_keystore(uuid).await.unwrap();
+		assert_eq!(stored_key.label, "Photos backup key");
+		assert_eq!(stored_key.description.as_deref(), Some("for the NAS"));
+
+		for label in [
+			String::new(),
+			" ".to_string(),
+			"a".repeat(MAX_KEY_LABEL_LEN + 1),
+		] {
+			assert!(matches!(
+				key_manager.rename_key(uuid, label).await,
+				Err(Error::InvalidKeyLabel)
+			));
+		}
+
+		assert!(matches!(
+			key_manager
+				.set_key_description(uuid, Some("a".repeat(MAX_KEY_DESCRIPTION_LEN + 1)))
+				.await,
+			Err(Error::InvalidKeyDescription)
+		));
+
+		assert!(matches!(
+			key_manager
+				.rename_key(Uuid::new_v4(), "label".to_string())
+				.await,
+			Err(Error::KeyNotFound)
+		));
+
+		// the label is only metadata, so the key itself is unaffected
+		key_manager.unmount(uuid).unwrap();
+		key_manager.mount(uuid).await.unwrap();
+		assert!(KeyManager::keys_match(
+			&key_manager.access_keymount(uuid).await.unwrap().hashed_key,
+			&hashed_key
+		));
+	}
+
+	// `keys_match()` compares the whole digest with `blake3::Hash`'s constant-time `PartialEq`, so a difference at any position must be caught
+	#[test]
+	fn keys_match_compares_every_byte() {
+		let key = Key::generate();
+		assert!(KeyManager::keys_match(&key, &Key::new(*key.expose())));
+
+		for i in 0..KEY_LEN {
+			let mut other = *key.expose();
+			other[i] ^= 0x01;
+			assert!(!KeyManager::keys_match(&key, &Key::new(other)), "{i}");
+		}
+	}
+
+	#[cfg(feature = "serde")]
+	async fn decrypt_with_key(key_manager: &KeyManager, uuid: Uuid, encrypted: &[u8]) -> Vec<u8> {
+		let mut reader = std::io::Cursor::new(encrypted);
+		let (header, aad) = FileHeader::from_reader(&mut reader).await.unwrap();
+
+		let hashed_key = key_manager.access_keymount(uuid).await.unwrap().hashed_key;
+		let master_key = header
+			.decrypt_master_key_from_prehashed(vec![hashed_key])
+			.await
+			.unwrap();
+
+		let mut decrypted = Vec::new();
+		header
+			.decrypt(master_key, &mut reader, &mut decrypted, &aad)
+			.await
+			.unwrap();
+		decrypted
+	}
+
+	#[cfg(feature = "serde")]
+	#[tokio::test]
+	async fn export_and_import_keys() {
+		let root_key = Key::generate();
+		let verification_key = KeyManager::wrap_root_key(
+			&root_key,
+			Protected::new("master password".to_string()),
+			SecretKey::generate(),
+			ALGORITHM,
+			HASHING_ALGORITHM,
+		)
+		.await
+		.unwrap();
+
+		let key_manager = KeyManager::new(vec![verification_key]).await.unwrap();
+
+		assert!(matches!(
+			key_manager
+				.export_keys(Protected::new("backup password".to_string()))
+				.await,
+			Err(Error::NotUnlocked)
+		));
+
+		*key_manager.root_key.lock().await = Some(root_key);
+		let uuid = add_mounted_key(&key_manager).await;
+
+		// encrypt some data before the keys are exported
+		let master_key = Key::generate();
+		let header = header_for_key(&key_manager, uuid, master_key.clone()).await;
+		let mut encrypted = header.to_bytes().unwrap();
+		Encryptor::new(master_key, header.nonce, header.algorithm)
+			.unwrap()
+			.encrypt_streams(
+				b"plaintext".as_ref(),
+				&mut encrypted,
+				&header.generate_aad(),
+			)
+			.await
+			.unwrap();
+
+		let exported = key_manager
+			.export_keys(Protected::new("backup password".to_string()))
+			.await
+			.unwrap();
+
+		// a fresh key manager, with a different root key
+		let other_key_manager = unlocked_key_manager(vec![], Key::generate()).await;
+
+		assert!(matches!(
+			other_key_manager
+				.import_keys(
+					&exported,
+					Protected::new("wrong password".to_string()),
+					ImportPolicy::Skip
+				)
+				.await,
+			Err(Error::IncorrectPassword)
+		));
+
+		// the salt
+		let mut tampered = exported.clone();
+		tampered[backup::KEY_BACKUP_MAGIC.len() + 12] ^= 0x01;
+		assert!(matches!(
+			other_key_manager
+				.import_keys(
+					&tampered,
+					Protected::new("backup password".to_string()),
+					ImportPolicy::Skip
+				)
+				.await,
+			Err(Error::IncorrectPassword)
+		));
+
+		let report = other_key_manager
+			.import_keys(
+				&exported,
+				Protected::new("backup password".to_string()),
+				ImportPolicy::Skip,
+			)
+			.await
+			.unwrap();
+		assert_eq!(report.added, vec![uuid]);
+		assert!(report.skipped.is_empty() && report.overwritten.is_empty());
+
+		other_key_manager.mount(uuid).await.unwrap();
+		assert_eq!(
+			decrypt_with_key(&other_key_manager, uuid, &encrypted).await,
+			b"plaintext"
+		);
+
+		// a second import finds the key already exists
+		let report = other_key_manager
+			.import_keys(
+				&exported,
+				Protected::new("backup password".to_string()),
+				ImportPolicy::Skip,
+			)
+			.await
+			.unwrap();
+		assert!(report.added.is_empty() && report.overwritten.is_empty());
+		assert_eq!(report.skipped, vec![uuid]);
+
+		assert!(matches!(
+			other_key_manager
+				.import_keys(
+					&exported,
+					Protected::new("backup password".to_string()),
+					ImportPolicy::Reject
+				)
+				.await,
+			Err(Error::DuplicateObjects)
+		));
+
+		// mounted keys can't be overwritten
+		assert!(matches!(
+			other_key_manager
+				.import_keys(
+					&exported,
+					Protected::new("backup password".to_string()),
+					ImportPolicy::Overwrite
+				)
+				.await,
+			Err(Error::KeyAlreadyMounted)
+		));
+
+		other_key_manager.unmount(uuid).unwrap();
+
+		let report = other_key_manager
+			.import_keys(
+				&exported,
+				Protected::new("backup password".to_string()),
+				ImportPolicy::Overwrite,
+			)
+			.await
+			.unwrap();
+		assert_eq!(report.overwritten, vec![uuid]);
+		assert_eq!(other_key_manager.dump_keystore().len(), 1);
+
+		other_key_manager.mount(uuid).await.unwrap();
+		assert_eq!(
+			decrypt_with_key(&other_key_manager, uuid, &encrypted).await,
+			b"plaintext"
+		);
+	}
+
+	async fn write_encrypted_file(
+		path: &Path,
+		header: &FileHeader,
+		master_key: Key,
+		plaintext: &[u8],
+	) {
+		let mut writer = File::create(path).await.unwrap();
+		header.write(&mut writer).await.unwrap();
+
+		header
+			.encrypt_detached(master_key, plaintext, &mut writer)
+			.await
+			.unwrap();
+	}
+
+	#[tokio::test]
+	async fn reencrypt_file() {
+		let key_manager = unlocked_key_manager(vec![], Key::generate()).await;
+		let key_a = add_mounted_key(&key_manager).await;
+		let key_b = add_mounted_key(&key_manager).await;
+
+		let mut plaintext = vec![0u8; BLOCK_LEN + 64];
+		rand_chacha::ChaCha20Rng::from_entropy().fill_bytes(&mut plaintext);
+
+		let master_key = Key::generate();
+		let header = header_for_key(&key_manager, key_a, master_key.clone()).await;
+
+		let path = std::env::temp_dir().join(format!("{}.bytes", Uuid::new_v4()));
+		write_encrypted_file(&path, &header, master_key, &plaintext).await;
+
+		key_manager
+			.reencrypt_file(&path, key_a, key_b)
+			.await
+			.unwrap();
+
+		let mut reader = File::open(&path).await.unwrap();
+		let (header, aad) = FileHeader::from_reader(&mut reader).await.unwrap();
+
+		let hashed_key_a = key_manager.access_keymount(key_a).await.unwrap().hashed_key;
+		let hashed_key_b = key_manager.access_keymount(key_b).await.unwrap().hashed_key;
+
+		assert!(matches!(
+			header
+				.decrypt_master_key_from_prehashed(vec![hashed_key_a])
+				.await,
+			Err(Error::IncorrectPassword)
+		));
+
+		let master_key = header
+			.decrypt_master_key_from_prehashed(vec![hashed_key_b])
+			.await
+			.unwrap();
+
+		let mut decrypted = Vec::new();
+		Decryptor::new(master_key, header.nonce, header.algorithm)
+			.unwrap()
+			.decrypt_streams(&mut reader, &mut decrypted, &aad)
+			.await
+			.unwrap();
+
+		drop(reader);
+		fs::remove_file(&path).await.unwrap();
+
+		assert_eq!(decrypted, plaintext);
+	}
+
+	#[tokio::test]
+	#[allow(clippy::cast_possible_truncation)]
+	async fn reencrypt_segmented_file() {
+		let key_manager = unlocked_key_manager(vec![], Key::generate()).await;
+		let key_a = add_mounted_key(&key_manager).await;
+		let key_b = add_mounted_key(&key_manager).await;
+
+		let mut plaintext = vec![0u8; BLOCK_LEN * 3 + 64];
+		rand_chacha::ChaCha20Rng::from_entropy().fill_bytes(&mut plaintext);
+
+		let master_key = Key::generate();
+		let mut header = header_for_key(&key_manager, key_a, master_key.clone()).await;
+		header.set_segment_size(Some(BLOCK_LEN as u32)).unwrap();
+
+		let path = std::env::temp_dir().join(format!("{}.bytes", Uuid::new_v4()));
+		write_encrypted_file(&path, &header, master_key, &plaintext).await;
+
+		key_manager
+			.reencrypt_file(&path, key_a, key_b)
+			.await
+			.unwrap();
+
+		let mut reader = File::open(&path).await.unwrap();
+		let (header, aad) = FileHeader::from_reader(&mut reader).await.unwrap();
+		assert_eq!(header.segment_size, Some(BLOCK_LEN as u32));
+
+		let hashed_key_b = key_manager.access_keymount(key_b).await.unwrap().hashed_key;
+		let master_key = header
+			.decrypt_master_key_from_prehashed(vec![hashed_key_b])
+			.await
+			.unwrap();
+
+		let mut decrypted = Vec::new();
+		header
+			.decrypt(master_key, &mut reader, &mut decrypted, &aad)
+			.await
+			.unwrap();
+
+		drop(reader);
+		fs::remove_file(&path).await.unwrap();
+
+		assert_eq!(decrypted, plaintext);
+	}
+
+	#[tokio::test]
+	#[allow(clippy::cast_possible_truncation)]
+	async fn verify_file_integrity() {
+		let key_manager = unlocked_key_manager(vec![], Key::generate()).await;
+		let key = add_mounted_key(&key_manager).await;
+
+		let mut plaintext = vec![0u8; BLOCK_LEN * 3 + 64];
+		rand_chacha::ChaCha20Rng::from_entropy().fill_bytes(&mut plaintext);
+
+		// this covers both streamed and segmented data, and each segment is a single block
+		for segment_size in [None, Some(BLOCK_LEN as u32)] {
+			let master_key = Key::generate();
+			let mut header = header_for_key(&key_manager, key, master_key.clone()).await;
+			header.set_segment_size(segment_size).unwrap();
+
+			let mut file = Vec::new();
+			header.write(&mut file).await.unwrap();
+			let header_len = file.len();
+			header
+				.encrypt_detached(master_key, plaintext.as_slice(), &mut file)
+				.await
+				.unwrap();
+
+			key_manager
+				.verify_integrity(std::io::Cursor::new(file.clone()))
+				.await
+				.unwrap();
+
+			// the failing block is reported
+			let mut altered = file.clone();
+			altered[header_len + (BLOCK_LEN + AEAD_TAG_LEN) * 2 + 1] ^= 1;
+			assert!(matches!(
+				key_manager
+					.verify_integrity(std::io::Cursor::new(altered))
+					.await,
+				Err(Error::AuthenticationFailed { block: Some(2) })
+			));
+
+			// truncation is detected too, as the final block that's left wasn't encrypted as the last one
+			let truncated = file[..header_len + (BLOCK_LEN + AEAD_TAG_LEN) * 2].to_vec();
+			assert!(matches!(
+				key_manager
+					.verify_integrity(std::io::Cursor::new(truncated))
+					.await,
+				Err(Error::AuthenticationFailed { block: Some(_) })
+			));
+		}
+	}
+
+	#[tokio::test]
+	async fn reencrypt_file_requires_mounted_keys() {
+		let key_manager = unlocked_key_manager(vec![], Key::generate()).await;
+		let mounted = add_mounted_key(&key_manager).await;
+		let unmounted = key_manager
+			.add_to_keystore(
+				Protected::new("password".to_string()),
+				ALGORITHM,
+				HASHING_ALGORITHM,
+				false,
+				false,
+				None,
+			)
+			.await
+			.unwrap();
+
+		let path = std::env::temp_dir().join(format!("{}.bytes", Uuid::new_v4()));
+
+		assert!(matches!(
+			key_manager.reencrypt_file(&path, mounted, unmounted).await,
+			Err(Error::KeyNotMounted)
+		));
+		assert!(matches!(
+			key_manager
+				.reencrypt_file(&path, Uuid::new_v4(), mounted)
+				.await,
+			Err(Error::KeyNotFound)
+		));
+	}
+}
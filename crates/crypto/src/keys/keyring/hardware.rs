@@ -0,0 +1,180 @@
+//! This is Spacedrive's hardware-backed key store. It seals the secret key with a hardware token, so the key that protects it never leaves the hardware.
+//!
+//! The sealed secret key is stored within another keyring (usually the OS keyring), and it can only be unsealed by the same token.
+//!
+//! [`super::pkcs11::Pkcs11Token`] supports any token with a PKCS#11 module (and it can be used as a keyring by itself), and other hardware (e.g. a TPM) can be supported by implementing [`HardwareToken`].
+
+use super::{Identifier, Keyring};
+use crate::{types::SecretKeyString, Error, Protected, Result};
+
+/// This is implemented for each type of hardware that can seal the secret key.
+pub trait HardwareToken: Send {
+	/// This should return `false` if the device is missing, or it isn't usable (e.g. the token was removed).
+	fn is_present(&self) -> bool;
+
+	/// This seals the data with a key that never leaves the token, and returns the sealed blob.
+	fn seal(&self, data: &[u8]) -> Result<Vec<u8>>;
+
+	/// This unseals a blob that was returned by `seal()`.
+	fn unseal(&self, sealed: &[u8]) -> Result<Protected<Vec<u8>>>;
+}
+
+/// This is a keyring that seals the secret key with a [`HardwareToken`] before storing it in the `backing` keyring.
+pub struct HardwareKeyStore {
+	token: Box<dyn HardwareToken>,
+	backing: Box<dyn Keyring + Send>,
+}
+
+impl HardwareKeyStore {
+	/// This returns `Error::KeyringNotSupported` if the token isn't present, so a software key store is never used in its place.
+	pub fn new(token: Box<dyn HardwareToken>, backing: Box<dyn Keyring + Send>) -> Result<Self> {
+		if !token.is_present() {
+			return Err(Error::KeyringNotSupported);
+		}
+
+		Ok(Self { token, backing })
+	}
+
+	fn ensure_present(&self) -> Result<()> {
+		self.token
+			.is_present()
+			.then_some(())
+			.ok_or(Error::KeyringNotSupported)
+	}
+}
+
+impl Keyring for HardwareKeyStore {
+	fn insert(&self, identifier: Identifier, value: SecretKeyString) -> Result<()> {
+		self.ensure_present()?;
+
+		let sealed = self.token.seal(value.expose().as_bytes())?;
+
+		// the sealed blob is hex encoded, as the backing keyring only accepts strings
+		self.backing
+			.insert(identifier, SecretKeyString::new(hex::encode(sealed)))
+	}
+
+	fn retrieve(&self, identifier: Identifier) -> Result<Protected<Vec<u8>>> {
+		self.ensure_present()?;
+
+		let sealed = self.backing.retrieve(identifier)?;
+		let sealed = hex::decode(sealed.expose()).map_err(|_| Error::KeyringError)?;
+
+		self.token.unseal(&sealed)
+	}
+
+	fn delete(&self, identifier: Identifier) -> Result<()> {
+		self.backing.delete(identifier)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		collections::HashMap,
+		sync::{
+			atomic::{AtomicBool, Ordering},
+			Arc, Mutex,
+		},
+	};
+
+	use super::*;
+
+	const IDENTIFIER: Identifier = Identifier {
+		application: "Spacedrive",
+		library_uuid: "00000000-0000-0000-0000-000000000000",
+		usage: "Secret key",
+	};
+
+	#[derive(Default, Clone)]
+	struct MemoryKeyring(Arc<Mutex<HashMap<String, Vec<u8>>>>);
+
+	impl Keyring for MemoryKeyring {
+		fn insert(&self, identifier: Identifier, value: SecretKeyString) -> Result<()> {
+			self.0.lock().unwrap().insert(
+				identifier.usage.to_string(),
+				value.expose().as_bytes().to_vec(),
+			);
+			Ok(())
+		}
+
+		fn retrieve(&self, identifier: Identifier) -> Result<Protected<Vec<u8>>> {
+			self.0
+				.lock()
+				.unwrap()
+				.get(identifier.usage)
+				.cloned()
+				.map(Protected::new)
+				.ok_or(Error::KeyringError)
+		}
+
+		fn delete(&self, identifier: Identifier) -> Result<()> {
+			self.0.lock().unwrap().remove(identifier.usage);
+			Ok(())
+		}
+	}
+
+	// this isn't secure, it's only used to check that the secret key is sealed before it's stored
+	struct XorToken(Arc<AtomicBool>);
+
+	impl HardwareToken for XorToken {
+		fn is_present(&self) -> bool {
+			self.0.load(Ordering::Relaxed)
+		}
+
+		fn seal(&self, data: &[u8]) -> Result<Vec<u8>> {
+			Ok(data.iter().map(|b| b ^ 0xAA).collect())
+		}
+
+		fn unseal(&self, sealed: &[u8]) -> Result<Protected<Vec<u8>>> {
+			Ok(Protected::new(sealed.iter().map(|b| b ^ 0xAA).collect()))
+		}
+	}
+
+	#[test]
+	fn hardware_key_store() {
+		let present = Arc::new(AtomicBool::new(true));
+		let backing = MemoryKeyring::default();
+		let key_store = HardwareKeyStore::new(
+			Box::new(XorToken(present.clone())),
+			Box::new(backing.clone()),
+		)
+		.unwrap();
+
+		let secret_key = "AAAAAA-BBBBBB-CCCCCC-DDDDDD-EEEEEE-FFFFFF";
+		key_store
+			.insert(IDENTIFIER, SecretKeyString::new(secret_key.to_string()))
+			.unwrap();
+
+		// only the sealed secret key should be stored
+		assert_ne!(
+			backing.retrieve(IDENTIFIER).unwrap().expose(),
+			secret_key.as_bytes()
+		);
+		assert_eq!(
+			key_store.retrieve(IDENTIFIER).unwrap().expose(),
+			secret_key.as_bytes()
+		);
+
+		// the sealed secret key can't be used without the token
+		present.store(false, Ordering::Relaxed);
+		assert!(matches!(
+			key_store.retrieve(IDENTIFIER),
+			Err(Error::KeyringNotSupported)
+		));
+
+		key_store.delete(IDENTIFIER).unwrap();
+		assert!(backing.retrieve(IDENTIFIER).is_err());
+	}
+
+	#[test]
+	fn hardware_key_store_without_token() {
+		assert!(matches!(
+			HardwareKeyStore::new(
+				Box::new(XorToken(Arc::new(AtomicBool::new(false)))),
+				Box::new(MemoryKeyring::default()),
+			),
+			Err(Error::KeyringNotSupported)
+		));
+	}
+}
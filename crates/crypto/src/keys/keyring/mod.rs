@@ -6,6 +6,15 @@ pub mod linux;
 #[cfg(any(target_os = "macos", target_os = "ios"))]
 pub mod apple;
 
+#[cfg(target_os = "windows")]
+pub mod windows;
+
+#[cfg(feature = "hardware-keystore")]
+pub mod hardware;
+
+#[cfg(feature = "hardware-keystore")]
+pub mod pkcs11;
+
 /// This identifier is platform-agnostic and is used for identifying keys within OS keyrings
 #[derive(Clone, Copy)]
 pub struct Identifier<'a> {
@@ -38,8 +47,25 @@ impl<'a> Identifier<'a> {
 	pub fn to_apple_account(self) -> String {
 		format!("{} - {}", self.library_uuid, self.usage)
 	}
+
+	#[cfg(target_os = "windows")]
+	#[must_use]
+	pub fn to_windows_user(self) -> String {
+		format!("{} - {}", self.library_uuid, self.usage)
+	}
+
+	#[cfg(feature = "hardware-keystore")]
+	#[must_use]
+	pub fn to_pkcs11_label(self) -> String {
+		format!("{} - {}", self.library_uuid, self.usage)
+	}
 }
 
+/// This is where the key manager stores the secret key, which is required (alongside the master password) to unlock it.
+///
+/// The [`KeyringInterface`] is the default implementation. [`pkcs11::Pkcs11Token`] and [`hardware::HardwareKeyStore`] seal the secret key with a hardware token instead (behind the `hardware-keystore` feature).
+///
+/// A keyring only holds the secret key, so the key manager's cryptographic operations are identical regardless of the backend.
 pub trait Keyring {
 	fn insert(&self, identifier: Identifier, value: SecretKeyString) -> Result<()>;
 	fn retrieve(&self, identifier: Identifier) -> Result<Protected<Vec<u8>>>;
@@ -53,7 +79,12 @@ pub struct KeyringInterface {
 
 impl KeyringInterface {
 	pub fn new() -> Result<Self> {
-		#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "ios")))]
+		#[cfg(not(any(
+			target_os = "linux",
+			target_os = "macos",
+			target_os = "ios",
+			target_os = "windows"
+		)))]
 		return Err(crate::Error::KeyringNotSupported);
 
 		#[cfg(target_os = "linux")]
@@ -62,7 +93,15 @@ impl KeyringInterface {
 		#[cfg(any(target_os = "macos", target_os = "ios"))]
 		let keyring = Box::new(self::apple::AppleKeyring {});
 
-		#[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios"))]
+		#[cfg(target_os = "windows")]
+		let keyring = Box::new(self::windows::WindowsKeyring {});
+
+		#[cfg(any(
+			target_os = "linux",
+			target_os = "macos",
+			target_os = "ios",
+			target_os = "windows"
+		))]
 		Ok(Self { keyring })
 	}
 
@@ -78,3 +117,17 @@ impl KeyringInterface {
 		self.keyring.delete(identifier)
 	}
 }
+
+impl Keyring for KeyringInterface {
+	fn insert(&self, identifier: Identifier, value: SecretKeyString) -> Result<()> {
+		Self::insert(self, identifier, value)
+	}
+
+	fn retrieve(&self, identifier: Identifier) -> Result<Protected<Vec<u8>>> {
+		Self::retrieve(self, identifier)
+	}
+
+	fn delete(&self, identifier: Identifier) -> Result<()> {
+		Self::delete(self, identifier)
+	}
+}
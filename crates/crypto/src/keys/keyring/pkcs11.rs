@@ -0,0 +1,270 @@
+//! This is Spacedrive's PKCS#11 [`HardwareToken`]. It depends on the `cryptoki` crate, and works with any token that has a PKCS#11 module (e.g. smart cards, security keys, or `SoftHSM`).
+//!
+//! The secret key is sealed with AES-256-GCM, by a key that's generated on the token and can never be extracted from it.
+//!
+//! It's also a [`Keyring`], which stores the sealed secret key as a private data object on the token itself.
+
+use std::path::Path;
+
+use cryptoki::{
+	context::{CInitializeArgs, Pkcs11},
+	error::{Error as CryptokiError, RvError},
+	mechanism::{aead::GcmParams, Mechanism},
+	object::{Attribute, AttributeType, KeyType, ObjectClass, ObjectHandle},
+	session::{Session, UserType},
+	slot::Slot,
+	types::AuthPin,
+};
+
+use super::{hardware::HardwareToken, Identifier, Keyring};
+use crate::{types::SecretKeyString, Error, Protected, Result};
+
+/// The label of the key that seals the secret key, so it can be found on the token again.
+const KEY_LABEL: &[u8] = b"Spacedrive secret key";
+
+const NONCE_LEN: usize = 12;
+const TAG_BITS: u64 = 128;
+
+pub struct Pkcs11Token {
+	pkcs11: Pkcs11,
+	slot: Slot,
+	session: Session,
+	key: ObjectHandle,
+}
+
+impl Pkcs11Token {
+	/// This loads the PKCS#11 `module`, and logs in to the token with the given `label`.
+	///
+	/// The sealing key is generated on the token if it doesn't have one yet.
+	///
+	/// This returns `Error::KeyringNotSupported` if there's no token with that label.
+	pub fn new(module: impl AsRef<Path>, label: &str, pin: &Protected<String>) -> Result<Self> {
+		let pkcs11 = Pkcs11::new(module)?;
+		pkcs11.initialize(CInitializeArgs::OsThreads)?;
+
+		let slot = pkcs11
+			.get_slots_with_token()?
+			.into_iter()
+			.find(|slot| {
+				pkcs11
+					.get_token_info(*slot)
+					.map_or(false, |info| info.label() == label)
+			})
+			.ok_or(Error::KeyringNotSupported)?;
+
+		let session = pkcs11.open_rw_session(slot)?;
+		session.login(UserType::User, Some(&AuthPin::new(pin.expose().clone())))?;
+
+		let key = Self::find_or_generate_key(&session)?;
+
+		Ok(Self {
+			pkcs11,
+			slot,
+			session,
+			key,
+		})
+	}
+
+	fn find_or_generate_key(session: &Session) -> Result<ObjectHandle> {
+		let template = [
+			Attribute::Class(ObjectClass::SECRET_KEY),
+			Attribute::KeyType(KeyType::AES),
+			Attribute::Label(KEY_LABEL.to_vec()),
+		];
+
+		if let Some(key) = session.find_objects(&template)?.first() {
+			return Ok(*key);
+		}
+
+		let key = session.generate_key(
+			&Mechanism::AesKeyGen,
+			&[
+				Attribute::Token(true),
+				Attribute::Private(true),
+				Attribute::Sensitive(true),
+				Attribute::Extractable(false),
+				Attribute::Encrypt(true),
+				Attribute::Decrypt(true),
+				Attribute::ValueLen(32.into()),
+				Attribute::Label(KEY_LABEL.to_vec()),
+			],
+		)?;
+
+		Ok(key)
+	}
+
+	/// This matches the data object that a sealed secret key is stored in.
+	fn data_template(identifier: Identifier) -> Vec<Attribute> {
+		vec![
+			Attribute::Class(ObjectClass::DATA),
+			Attribute::Application(identifier.application.as_bytes().to_vec()),
+			Attribute::Label(identifier.to_pkcs11_label().into_bytes()),
+		]
+	}
+}
+
+impl HardwareToken for Pkcs11Token {
+	fn is_present(&self) -> bool {
+		self.pkcs11
+			.get_slot_info(self.slot)
+			.map_or(false, |info| info.token_present())
+			&& self.session.get_session_info().is_ok()
+	}
+
+	fn seal(&self, data: &[u8]) -> Result<Vec<u8>> {
+		// the nonce is generated by the token, and it's stored alongside the ciphertext
+		let mut nonce = [0u8; NONCE_LEN];
+		self.session.generate_random_slice(&mut nonce)?;
+
+		let params = GcmParams::new(&nonce, &[], TAG_BITS.into());
+		let ciphertext = self
+			.session
+			.encrypt(&Mechanism::AesGcm(params), self.key, data)?;
+
+		Ok([nonce.as_slice(), &ciphertext].concat())
+	}
+
+	fn unseal(&self, sealed: &[u8]) -> Result<Protected<Vec<u8>>> {
+		if sealed.len() < NONCE_LEN {
+			return Err(Error::Decrypt);
+		}
+
+		let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+		let params = GcmParams::new(nonce, &[], TAG_BITS.into());
+
+		// only an invalid tag means the blob was tampered with (or sealed by another token), anything else is an error with the token itself
+		self.session
+			.decrypt(&Mechanism::AesGcm(params), self.key, ciphertext)
+			.map(Protected::new)
+			.map_err(|err| match err {
+				CryptokiError::Pkcs11(RvError::EncryptedDataInvalid) => {
+					Error::AuthenticationFailed { block: None }
+				}
+				err => err.into(),
+			})
+	}
+}
+
+impl Keyring for Pkcs11Token {
+	/// This replaces any secret key that's already stored for the identifier.
+	fn insert(&self, identifier: Identifier, value: SecretKeyString) -> Result<()> {
+		let sealed = self.seal(value.expose().as_bytes())?;
+		self.delete(identifier)?;
+
+		let mut template = Self::data_template(identifier);
+		template.extend([
+			Attribute::Token(true),
+			Attribute::Private(true),
+			Attribute::Value(sealed),
+		]);
+		self.session.create_object(&template)?;
+
+		Ok(())
+	}
+
+	fn retrieve(&self, identifier: Identifier) -> Result<Protected<Vec<u8>>> {
+		let object = *self
+			.session
+			.find_objects(&Self::data_template(identifier))?
+			.first()
+			.ok_or(Error::KeyringError)?;
+
+		let sealed = self
+			.session
+			.get_attributes(object, &[AttributeType::Value])?
+			.into_iter()
+			.find_map(|attribute| match attribute {
+				Attribute::Value(value) => Some(value),
+				_ => None,
+			})
+			.ok_or(Error::KeyringError)?;
+
+		self.unseal(&sealed)
+	}
+
+	fn delete(&self, identifier: Identifier) -> Result<()> {
+		for object in self
+			.session
+			.find_objects(&Self::data_template(identifier))?
+		{
+			self.session.destroy_object(object)?;
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const IDENTIFIER: Identifier = Identifier {
+		application: "Spacedrive",
+		library_uuid: "00000000-0000-0000-0000-000000000000",
+		usage: "Secret key",
+	};
+
+	// e.g. `PKCS11_MODULE=/usr/lib/softhsm/libsofthsm2.so PKCS11_TOKEN=test PKCS11_PIN=1234`
+	fn login() -> Pkcs11Token {
+		let var = |name| std::env::var(name).unwrap_or_else(|_| panic!("{name} isn't set"));
+
+		Pkcs11Token::new(
+			var("PKCS11_MODULE"),
+			&var("PKCS11_TOKEN"),
+			&Protected::new(var("PKCS11_PIN")),
+		)
+		.unwrap()
+	}
+
+	#[test]
+	#[ignore = "requires a PKCS#11 token"]
+	fn pkcs11_token() {
+		let token = login();
+		assert!(token.is_present());
+
+		let secret_key = b"AAAAAA-BBBBBB-CCCCCC-DDDDDD-EEEEEE-FFFFFF";
+		let sealed = token.seal(secret_key).unwrap();
+		assert!(!sealed.windows(secret_key.len()).any(|w| w == secret_key));
+		assert_eq!(token.unseal(&sealed).unwrap().expose(), secret_key);
+
+		let mut tampered = sealed.clone();
+		*tampered.last_mut().unwrap() ^= 1;
+		assert!(matches!(
+			token.unseal(&tampered),
+			Err(Error::AuthenticationFailed { block: None })
+		));
+
+		// the sealing key is persisted on the token, so it can be unsealed after logging in again
+		drop(token);
+		assert_eq!(login().unseal(&sealed).unwrap().expose(), secret_key);
+	}
+
+	#[test]
+	#[ignore = "requires a PKCS#11 token"]
+	fn pkcs11_keyring() {
+		let token = login();
+
+		let secret_key = "AAAAAA-BBBBBB-CCCCCC-DDDDDD-EEEEEE-FFFFFF";
+		token
+			.insert(IDENTIFIER, SecretKeyString::new(secret_key.to_string()))
+			.unwrap();
+		assert_eq!(
+			token.retrieve(IDENTIFIER).unwrap().expose(),
+			secret_key.as_bytes()
+		);
+
+		// the sealed secret key is stored on the token, so it can be retrieved after logging in again
+		drop(token);
+		let token = login();
+		assert_eq!(
+			token.retrieve(IDENTIFIER).unwrap().expose(),
+			secret_key.as_bytes()
+		);
+
+		token.delete(IDENTIFIER).unwrap();
+		assert!(matches!(
+			token.retrieve(IDENTIFIER),
+			Err(Error::KeyringError)
+		));
+	}
+}
@@ -0,0 +1,65 @@
+//! This is Spacedrive's Windows OS keyring integration. It depends on the `keyring` crate.
+//!
+//! Secrets are stored as generic credentials within the Windows Credential Manager.
+
+use super::{Identifier, Keyring};
+use crate::{types::SecretKeyString, Error, Protected, Result};
+use keyring::Entry;
+
+pub struct WindowsKeyring;
+
+impl WindowsKeyring {
+	fn entry(identifier: Identifier) -> Result<Entry> {
+		Entry::new(identifier.application, &identifier.to_windows_user())
+			.map_err(Error::WindowsKeyringError)
+	}
+}
+
+impl Keyring for WindowsKeyring {
+	fn insert(&self, identifier: Identifier, value: SecretKeyString) -> Result<()> {
+		Self::entry(identifier)?
+			.set_password(value.expose())
+			.map_err(Error::WindowsKeyringError)
+	}
+	fn retrieve(&self, identifier: Identifier) -> Result<Protected<Vec<u8>>> {
+		Self::entry(identifier)?
+			.get_password()
+			.map(|password| Protected::new(password.into_bytes()))
+			.map_err(Error::WindowsKeyringError)
+	}
+	fn delete(&self, identifier: Identifier) -> Result<()> {
+		Self::entry(identifier)?
+			.delete_password()
+			.map_err(Error::WindowsKeyringError)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn windows_keyring() {
+		// a random library id so the test can't clash with a real credential or another run
+		let library_uuid = uuid::Uuid::new_v4().to_string();
+		let identifier = Identifier {
+			application: "Spacedrive",
+			library_uuid: &library_uuid,
+			usage: "Secret key test",
+		};
+		let secret_key = SecretKeyString::new("0a1b2c-3d4e5f".to_string());
+
+		let keyring = WindowsKeyring;
+		keyring.insert(identifier, secret_key.clone()).unwrap();
+		assert_eq!(
+			keyring.retrieve(identifier).unwrap().expose(),
+			secret_key.expose().as_bytes()
+		);
+
+		keyring.delete(identifier).unwrap();
+		assert!(matches!(
+			keyring.retrieve(identifier),
+			Err(Error::WindowsKeyringError(_))
+		));
+	}
+}
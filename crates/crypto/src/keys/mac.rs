@@ -0,0 +1,92 @@
+//! This module contains functions for authenticating data with a shared key.
+//!
+//! This uses BLAKE3 in keyed mode, with a key derived from the provided key and context. Anyone who holds the key can create and verify a MAC.
+//!
+//! # Examples
+//!
+//! ```rust,ignore
+//! let key = Key::generate();
+//! let mac = authenticate(&key, CONTEXT, b"data");
+//! verify(&key, CONTEXT, b"data", &mac).unwrap();
+//! ```
+
+use zeroize::Zeroize;
+
+use crate::{primitives::to_array, types::Key, Error, Result};
+
+/// The length of a MAC, in bytes.
+pub const MAC_LEN: usize = 32;
+
+/// This creates a MAC for `data`, which can later be checked with `verify()`.
+#[must_use]
+pub fn authenticate(key: &Key, context: &str, data: &[u8]) -> [u8; MAC_LEN] {
+	let mut key = blake3::derive_key(context, key.expose());
+	let mac = blake3::keyed_hash(&key, data);
+
+	key.zeroize();
+
+	mac.into()
+}
+
+/// This verifies that `mac` was created for `data` with the same key and context.
+///
+/// The comparison is constant-time.
+pub fn verify(key: &Key, context: &str, data: &[u8], mac: &[u8]) -> Result<()> {
+	let mac = blake3::Hash::from(to_array::<MAC_LEN>(mac).map_err(|_| Error::InvalidMac)?);
+
+	// `blake3::Hash` implements `PartialEq` in constant-time
+	if mac == blake3::Hash::from(authenticate(key, context, data)) {
+		Ok(())
+	} else {
+		Err(Error::InvalidMac)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const TEST_CONTEXT: &str = "spacedrive 2023-03-20 16:21:04 test mac";
+
+	const KEY: Key = Key::new([
+		0x23, 0x23, 0x23, 0x23, 0x23, 0x23, 0x23, 0x23, 0x23, 0x23, 0x23, 0x23, 0x23, 0x23, 0x23,
+		0x23, 0x23, 0x23, 0x23, 0x23, 0x23, 0x23, 0x23, 0x23, 0x23, 0x23, 0x23, 0x23, 0x23, 0x23,
+		0x23, 0x23,
+	]);
+
+	const DATA: [u8; 4] = [0x64, 0x61, 0x74, 0x61];
+
+	#[test]
+	fn mac_verify() {
+		let mac = authenticate(&KEY, TEST_CONTEXT, &DATA);
+		verify(&KEY, TEST_CONTEXT, &DATA, &mac).unwrap();
+	}
+
+	#[test]
+	fn mac_verify_tampered_data() {
+		let mac = authenticate(&KEY, TEST_CONTEXT, &DATA);
+
+		let mut data = DATA;
+		data[0] ^= 1;
+
+		assert!(verify(&KEY, TEST_CONTEXT, &data, &mac).is_err());
+	}
+
+	#[test]
+	fn mac_verify_wrong_key() {
+		let mac = authenticate(&KEY, TEST_CONTEXT, &DATA);
+		assert!(verify(&Key::generate(), TEST_CONTEXT, &DATA, &mac).is_err());
+	}
+
+	#[test]
+	fn mac_verify_wrong_context() {
+		let mac = authenticate(&KEY, TEST_CONTEXT, &DATA);
+		assert!(verify(&KEY, "spacedrive 2023-03-20 16:21:29 other", &DATA, &mac).is_err());
+	}
+
+	#[test]
+	fn mac_verify_truncated() {
+		let mac = authenticate(&KEY, TEST_CONTEXT, &DATA);
+		assert!(verify(&KEY, TEST_CONTEXT, &DATA, &mac[..MAC_LEN - 1]).is_err());
+	}
+}
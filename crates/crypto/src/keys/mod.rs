@@ -1,9 +1,13 @@
 //! This module contains all key and hashing related functions.
 
 pub mod hashing;
+pub mod mac;
 
 #[cfg(all(feature = "keymanager", feature = "os-keyrings"))]
 pub mod keymanager;
 
+#[cfg(all(feature = "keymanager", feature = "os-keyrings", feature = "serde"))]
+pub mod backup;
+
 #[cfg(feature = "os-keyrings")]
 pub mod keyring;
@@ -7,7 +7,7 @@ use zeroize::Zeroize;
 use crate::{
 	header::{
 		file::FileHeaderVersion, keyslot::KeyslotVersion, metadata::MetadataVersion,
-		preview_media::PreviewMediaVersion,
+		objects::HeaderObjectVersion, preview_media::PreviewMediaVersion,
 	},
 	Error, Result,
 };
@@ -26,15 +26,46 @@ pub const SECRET_KEY_LEN: usize = 18;
 /// The file size gain is 16 bytes per 1048576 bytes (due to the AEAD tag), plus the size of the header.
 pub const BLOCK_LEN: usize = 1_048_576;
 
+/// The size of each chunk for deduplicated encryption. Every chunk except the last is exactly this size.
+#[cfg(feature = "chunk-dedup")]
+pub const CHUNK_LEN: usize = BLOCK_LEN;
+
 /// This is the default AEAD tag size for all encryption algorithms used within the crate.
 pub const AEAD_TAG_LEN: usize = 16;
 
+/// The default segment size for parallel encryption (16 MiB). This must be a multiple of `BLOCK_LEN`.
+pub const DEFAULT_SEGMENT_SIZE: usize = BLOCK_LEN * 16;
+
+/// The maximum segment size for parallel encryption (256 MiB), as a whole segment is held in memory for each thread.
+pub const MAX_SEGMENT_SIZE: usize = BLOCK_LEN * 256;
+
 /// The length of encrypted master keys (`KEY_LEN` + `AEAD_TAG_LEN`)
 pub const ENCRYPTED_KEY_LEN: usize = 48;
 
 /// The length of plain master/hashed keys
 pub const KEY_LEN: usize = 32;
 
+/// The length of serialized custom password-hashing parameters. These are stored within the keyslot's padding.
+pub const HASHING_PARAMS_LEN: usize = 6;
+
+/// The minimum memory cost for custom password-hashing parameters (16MiB for Argon2id).
+pub const MIN_M_COST: u32 = 16_384;
+
+/// The maximum memory cost for custom password-hashing parameters (4GiB for Argon2id).
+pub const MAX_M_COST: u32 = 4_194_304;
+
+/// The maximum amount of iterations for custom password-hashing parameters.
+pub const MAX_T_COST: u32 = 64;
+
+/// The maximum degree of parallelism for custom password-hashing parameters.
+pub const MAX_P_COST: u32 = 16;
+
+/// The maximum length (in characters) of a key's label.
+pub const MAX_KEY_LABEL_LEN: usize = 64;
+
+/// The maximum length (in characters) of a key's description.
+pub const MAX_KEY_DESCRIPTION_LEN: usize = 256;
+
 /// Used for OS keyrings to identify our items.
 pub const APP_IDENTIFIER: &str = "Spacedrive";
 
@@ -42,7 +73,9 @@ pub const APP_IDENTIFIER: &str = "Spacedrive";
 pub const SECRET_KEY_IDENTIFIER: &str = "Secret key";
 
 /// Defines the latest `FileHeaderVersion`
-pub const LATEST_FILE_HEADER: FileHeaderVersion = FileHeaderVersion::V1;
+///
+/// V6 headers can store everything that older headers can (including a chunk manifest), so they're used for every new file.
+pub const LATEST_FILE_HEADER: FileHeaderVersion = FileHeaderVersion::V6;
 
 /// Defines the latest `KeyslotVersion`
 pub const LATEST_KEYSLOT: KeyslotVersion = KeyslotVersion::V1;
@@ -53,6 +86,9 @@ pub const LATEST_METADATA: MetadataVersion = MetadataVersion::V1;
 /// Defines the latest `PreviewMediaVersion`
 pub const LATEST_PREVIEW_MEDIA: PreviewMediaVersion = PreviewMediaVersion::V1;
 
+/// Defines the latest `HeaderObjectVersion`
+pub const LATEST_HEADER_OBJECT: HeaderObjectVersion = HeaderObjectVersion::V1;
+
 /// Defines the latest `StoredKeyVersion`
 #[cfg(feature = "keymanager")]
 pub const LATEST_STORED_KEY: StoredKeyVersion = StoredKeyVersion::V1;
@@ -67,6 +103,20 @@ pub const MASTER_PASSWORD_CONTEXT: &str =
 /// Defines the context string for BLAKE3-KDF in regards to file key derivation (for file encryption)
 pub const FILE_KEY_CONTEXT: &str = "spacedrive 2022-12-14 12:54:12 file key derivation";
 
+/// Defines the context string for BLAKE3-KDF in regards to key backup derivation
+pub const KEY_BACKUP_CONTEXT: &str = "spacedrive 2023-04-12 10:02:47 key backup derivation";
+
+/// Defines the context string for BLAKE3-KDF in regards to segment key derivation (for parallel file encryption)
+pub const SEGMENT_KEY_CONTEXT: &str = "spacedrive 2023-04-20 09:41:26 segment key derivation";
+
+/// Defines the context string for BLAKE3-KDF in regards to chunk ID derivation (for deduplicated file encryption)
+#[cfg(feature = "chunk-dedup")]
+pub const CHUNK_ID_CONTEXT: &str = "spacedrive 2026-10-14 11:02:39 chunk id derivation";
+
+/// Defines the context string for BLAKE3-KDF in regards to chunk key derivation (for deduplicated file encryption)
+#[cfg(feature = "chunk-dedup")]
+pub const CHUNK_KEY_CONTEXT: &str = "spacedrive 2026-10-14 11:03:05 chunk key derivation";
+
 /// This is used for converting a `&[u8]` to an array of bytes.
 ///
 /// It calls `Clone`, via `to_vec()`.
@@ -29,7 +29,7 @@
 //! ```
 //!
 use std::{fmt::Debug, mem::swap};
-use zeroize::Zeroize;
+use zeroize::{Zeroize, ZeroizeOnDrop};
 #[derive(Clone)]
 pub struct Protected<T>
 where
@@ -51,6 +51,11 @@ where
 	}
 
 	pub fn zeroize(mut self) {
+		self.wipe();
+	}
+
+	/// This zeroes the data in place, without waiting for it to be dropped.
+	pub fn wipe(&mut self) {
 		self.data.zeroize();
 	}
 }
@@ -89,6 +94,9 @@ where
 	}
 }
 
+// this is guaranteed by the `Drop` implementation above
+impl<T> ZeroizeOnDrop for Protected<T> where T: Zeroize {}
+
 impl<T> Debug for Protected<T>
 where
 	T: Zeroize,
@@ -1,9 +1,9 @@
 //! This module defines all of the possible types used throughout this crate,
 //! in an effort to add additional type safety.
 use aead::generic_array::{ArrayLength, GenericArray};
-use rand::{RngCore, SeedableRng};
-use std::ops::Deref;
-use zeroize::Zeroize;
+use rand::{CryptoRng, RngCore, SeedableRng};
+use std::{collections::HashSet, ops::Deref};
+use zeroize::{Zeroize, ZeroizeOnDrop};
 
 use crate::{Error, Protected};
 
@@ -15,7 +15,7 @@ use serde_big_array::BigArray;
 /// This should be used for providing a nonce to encrypt/decrypt functions.
 ///
 /// You may also generate a nonce for a given algorithm with `Nonce::generate()`
-#[derive(Clone, Copy, Eq, PartialEq)]
+#[derive(Clone, Copy, Eq, PartialEq, Hash)]
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[cfg_attr(feature = "rspc", derive(rspc::Type))]
 pub enum Nonce {
@@ -37,6 +37,25 @@ pub enum Params {
 	Standard,
 	Hardened,
 	Paranoid,
+	Custom(HashingParams),
+}
+
+/// These are custom password-hashing parameters, for when none of the presets are suitable.
+///
+/// `m_cost` is the memory cost (in KiB for Argon2id), `t_cost` is the amount of iterations and `p_cost` is the degree of parallelism.
+///
+/// They're validated before hashing, and `Error::PasswordHash` will be returned if any are out of range.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+	feature = "serde",
+	derive(serde::Serialize),
+	derive(serde::Deserialize)
+)]
+#[cfg_attr(feature = "rspc", derive(rspc::Type))]
+pub struct HashingParams {
+	pub m_cost: u32,
+	pub t_cost: u32,
+	pub p_cost: u32,
 }
 
 /// This defines all available password hashing algorithms.
@@ -54,9 +73,18 @@ pub enum HashingAlgorithm {
 }
 
 impl Nonce {
+	/// This generates a random nonce with a CSPRNG (`ChaCha20`, seeded by the operating system).
 	pub fn generate(algorithm: Algorithm) -> crate::Result<Self> {
+		Self::generate_with_rng(algorithm, &mut rand_chacha::ChaCha20Rng::from_entropy())
+	}
+
+	/// This generates a random nonce with the provided RNG, which must be cryptographically secure.
+	pub fn generate_with_rng<R>(algorithm: Algorithm, rng: &mut R) -> crate::Result<Self>
+	where
+		R: RngCore + CryptoRng,
+	{
 		let mut nonce = vec![0u8; algorithm.nonce_len()];
-		rand_chacha::ChaCha20Rng::from_entropy().fill_bytes(&mut nonce);
+		rng.fill_bytes(&mut nonce);
 		Self::try_from(nonce)
 	}
 
@@ -77,6 +105,49 @@ impl Nonce {
 	}
 }
 
+/// This keeps track of every nonce that has been used with a single key, so that a nonce is never used twice.
+///
+/// Reusing a nonce with the same key is catastrophic for AEADs, so `Error::NonceReuse` is returned instead of allowing it.
+#[derive(Clone, Default)]
+pub struct NonceTracker(HashSet<Nonce>);
+
+impl NonceTracker {
+	#[must_use]
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// This records a nonce as used, and returns an error if it has already been used.
+	pub fn track(&mut self, nonce: Nonce) -> crate::Result<()> {
+		if self.0.insert(nonce) {
+			Ok(())
+		} else {
+			Err(Error::NonceReuse)
+		}
+	}
+
+	/// This generates a random nonce with `Nonce::generate()`, and records it as used.
+	pub fn generate(&mut self, algorithm: Algorithm) -> crate::Result<Nonce> {
+		self.generate_with_rng(algorithm, &mut rand_chacha::ChaCha20Rng::from_entropy())
+	}
+
+	/// This generates a random nonce with the provided RNG, and records it as used.
+	///
+	/// A collision should never happen with a secure RNG, but an error is returned if it does.
+	pub fn generate_with_rng<R>(
+		&mut self,
+		algorithm: Algorithm,
+		rng: &mut R,
+	) -> crate::Result<Nonce>
+	where
+		R: RngCore + CryptoRng,
+	{
+		let nonce = Nonce::generate_with_rng(algorithm, rng)?;
+		self.track(nonce)?;
+		Ok(nonce)
+	}
+}
+
 impl<I> From<Nonce> for GenericArray<u8, I>
 where
 	I: ArrayLength<u8>,
@@ -121,7 +192,9 @@ impl Deref for Nonce {
 }
 
 /// These are all possible algorithms that can be used for encryption and decryption
-#[derive(Clone, Copy, Eq, PartialEq, Hash)]
+///
+/// `XChaCha20Poly1305` is the default, as it was the only algorithm before the algorithm was stored alongside keys.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Default)]
 #[cfg_attr(
 	feature = "serde",
 	derive(serde::Serialize),
@@ -129,6 +202,7 @@ impl Deref for Nonce {
 )]
 #[cfg_attr(feature = "rspc", derive(rspc::Type))]
 pub enum Algorithm {
+	#[default]
 	XChaCha20Poly1305,
 	Aes256Gcm,
 }
@@ -163,11 +237,14 @@ impl Key {
 	pub fn derive(key: Self, salt: Salt, context: &str) -> Self {
 		let mut input = key.expose().to_vec();
 		input.extend_from_slice(&salt);
-		let key = blake3::derive_key(context, &input);
+		let mut key = blake3::derive_key(context, &input);
 
 		input.zeroize();
 
-		Self::new(key)
+		let derived_key = Self::new(key);
+		key.zeroize();
+
+		derived_key
 	}
 
 	#[must_use]
@@ -175,14 +252,25 @@ impl Key {
 		self.0.expose()
 	}
 
+	pub fn wipe(&mut self) {
+		self.0.wipe();
+	}
+
 	#[must_use]
 	pub fn generate() -> Self {
 		let mut key = [0u8; KEY_LEN];
 		rand_chacha::ChaCha20Rng::from_entropy().fill_bytes(&mut key);
-		Self::new(key)
+
+		let generated_key = Self::new(key);
+		key.zeroize();
+
+		generated_key
 	}
 }
 
+// the inner `Protected` value is zeroized on drop
+impl ZeroizeOnDrop for Key {}
+
 impl<I> From<Key> for GenericArray<u8, I>
 where
 	I: ArrayLength<u8>,
@@ -229,10 +317,17 @@ impl SecretKey {
 	pub fn generate() -> Self {
 		let mut secret_key = [0u8; SECRET_KEY_LEN];
 		rand_chacha::ChaCha20Rng::from_entropy().fill_bytes(&mut secret_key);
-		Self::new(secret_key)
+
+		let generated_key = Self::new(secret_key);
+		secret_key.zeroize();
+
+		generated_key
 	}
 }
 
+// the inner `Protected` value is zeroized on drop
+impl ZeroizeOnDrop for SecretKey {}
+
 impl Deref for SecretKey {
 	type Target = Protected<[u8; SECRET_KEY_LEN]>;
 
@@ -259,6 +354,9 @@ impl SecretKeyString {
 	}
 }
 
+// the inner `Protected` value is zeroized on drop
+impl ZeroizeOnDrop for SecretKeyString {}
+
 impl From<SecretKey> for SecretKeyString {
 	fn from(v: SecretKey) -> Self {
 		let hex_string: String = hex::encode_upper(v.0.expose())
@@ -363,3 +461,76 @@ pub struct OnboardingConfig {
 	pub algorithm: Algorithm,
 	pub hashing_algorithm: HashingAlgorithm,
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// this fails to compile if any of the key types don't zeroize on drop
+	const fn assert_zeroize_on_drop<T: ZeroizeOnDrop>() {}
+
+	#[test]
+	fn key_types_zeroize_on_drop() {
+		assert_zeroize_on_drop::<Protected<Vec<u8>>>();
+		assert_zeroize_on_drop::<Protected<String>>();
+		assert_zeroize_on_drop::<Key>();
+		assert_zeroize_on_drop::<SecretKey>();
+		assert_zeroize_on_drop::<SecretKeyString>();
+	}
+
+	#[test]
+	fn key_wipe() {
+		let mut key = Key::generate();
+		assert_ne!(key.expose(), &[0u8; KEY_LEN]);
+
+		key.wipe();
+		assert_eq!(key.expose(), &[0u8; KEY_LEN]);
+	}
+
+	// this always produces the same bytes, and it's only marked as secure so it can be used for forcing a nonce collision
+	struct StubRng(u8);
+
+	impl RngCore for StubRng {
+		fn next_u32(&mut self) -> u32 {
+			u32::from_le_bytes([self.0; 4])
+		}
+
+		fn next_u64(&mut self) -> u64 {
+			u64::from_le_bytes([self.0; 8])
+		}
+
+		fn fill_bytes(&mut self, dest: &mut [u8]) {
+			dest.fill(self.0);
+		}
+
+		fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+			self.fill_bytes(dest);
+			Ok(())
+		}
+	}
+
+	impl CryptoRng for StubRng {}
+
+	#[test]
+	fn nonce_reuse_is_rejected() {
+		let mut tracker = NonceTracker::new();
+
+		let nonce = tracker
+			.generate_with_rng(Algorithm::XChaCha20Poly1305, &mut StubRng(0x23))
+			.unwrap();
+
+		assert!(matches!(
+			tracker.generate_with_rng(Algorithm::XChaCha20Poly1305, &mut StubRng(0x23)),
+			Err(Error::NonceReuse)
+		));
+		assert!(matches!(tracker.track(nonce), Err(Error::NonceReuse)));
+
+		// different bytes (or a different nonce length) aren't a collision
+		tracker
+			.generate_with_rng(Algorithm::XChaCha20Poly1305, &mut StubRng(0x24))
+			.unwrap();
+		tracker
+			.generate_with_rng(Algorithm::Aes256Gcm, &mut StubRng(0x23))
+			.unwrap();
+	}
+}
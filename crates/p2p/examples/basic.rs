@@ -1,8 +1,18 @@
 use std::{collections::HashMap, env, time::Duration};
 
-use sd_p2p::{spacetime::SpaceTimeStream, Event, Keypair, Manager, Metadata};
-use tokio::{io::AsyncReadExt, time::sleep};
-use tracing::{debug, error, info};
+use sd_p2p::{
+	spacetime::SpaceTimeStream, Event, Keypair, Manager, ManagerConfig, Metadata,
+	DEFAULT_EVENT_STREAM_CAPACITY,
+};
+use thiserror::Error;
+use tokio::{
+	io::{AsyncRead, AsyncReadExt},
+	time::sleep,
+};
+use tracing::{debug, info, warn};
+
+/// the largest message this example will read from a peer.
+const MAX_MESSAGE_SIZE: u64 = 64 * 1024;
 
 #[derive(Debug, Clone)]
 pub struct PeerMetadata {
@@ -10,6 +20,8 @@ pub struct PeerMetadata {
 }
 
 impl Metadata for PeerMetadata {
+	type Typed = ();
+
 	fn to_hashmap(self) -> HashMap<String, String> {
 		HashMap::from([("name".to_owned(), self.name)])
 	}
@@ -30,6 +42,30 @@ impl Metadata for PeerMetadata {
 	}
 }
 
+#[derive(Debug, Error)]
+enum MessageError {
+	#[error("io error reading message: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("message is larger than {MAX_MESSAGE_SIZE} bytes")]
+	TooLarge,
+	#[error("message is not valid UTF-8: {0}")]
+	InvalidUtf8(#[from] std::string::FromUtf8Error),
+}
+
+/// read_message reads a message sent with `broadcast` or `send_to` until the peer closes the stream.
+async fn read_message(stream: impl AsyncRead + Unpin) -> Result<String, MessageError> {
+	let mut buf = Vec::new();
+	stream
+		.take(MAX_MESSAGE_SIZE + 1)
+		.read_to_end(&mut buf)
+		.await?;
+	if buf.len() as u64 > MAX_MESSAGE_SIZE {
+		return Err(MessageError::TooLarge);
+	}
+
+	Ok(String::from_utf8(buf)?)
+}
+
 #[tokio::main]
 async fn main() {
 	tracing_subscriber::fmt()
@@ -44,11 +80,16 @@ async fn main() {
 
 	let keypair = Keypair::generate();
 
-	let (manager, mut stream) = Manager::new("p2p-demo", &keypair, || async move {
-		PeerMetadata {
-			name: "TODO".to_string(),
-		}
-	})
+	let (manager, stream) = Manager::new(
+		"p2p-demo",
+		&keypair,
+		ManagerConfig::default(),
+		|| async move {
+			PeerMetadata {
+				name: "TODO".to_string(),
+			}
+		},
+	)
 	.await
 	.unwrap();
 
@@ -58,8 +99,9 @@ async fn main() {
 		manager.listen_addrs().await
 	);
 
-	tokio::spawn(async move {
-		// Your application must keeping poll this stream to keep the P2P system running
+	let event_loop = tokio::spawn(async move {
+		// The manager stream is polled on its own task so a slow event loop can't stall the P2P system. If this loop falls behind the oldest events are dropped and it receives `Event::Lagged`.
+		let mut stream = stream.into_event_stream(DEFAULT_EVENT_STREAM_CAPACITY);
 		while let Some(event) = stream.next().await {
 			match event {
 				Event::PeerDiscovered(event) => {
@@ -67,28 +109,43 @@ async fn main() {
 						"Discovered peer by id '{}' with address '{:?}' and metadata: {:?}",
 						event.peer_id, event.addresses, event.metadata
 					);
-					event.dial().await; // We connect to everyone we find on the network. Your app will probs wanna restrict this!
+
+					// We connect to everyone we find on the network. Your app will probs wanna restrict this!
+					// The dial is spawned as it can't complete while this loop is waiting on it instead of polling the stream.
+					tokio::spawn(async move {
+						let peer_id = event.peer_id;
+						if let Err(err) = event.dial().await {
+							println!("Failed to dial peer '{}': {}", peer_id, err);
+						}
+					});
+				}
+				// The peer stopped advertising itself. It may still be connected or dialable by address.
+				Event::PeerExpired { id, .. } => {
+					println!("Peer '{}' expired", id);
+				}
+				// An active connection with the peer was lost. It may still be advertising itself.
+				Event::PeerDisconnected(peer_id) => {
+					println!("Peer '{}' disconnected", peer_id);
 				}
 				Event::PeerMessage(event) => {
 					debug!("Peer '{}' established stream", event.peer_id);
 
+					// A message which can't be read or decoded is skipped. Your app shouldn't let one bad message from a peer take down the event loop!
 					tokio::spawn(async move {
-						match event.stream {
+						let peer_id = event.peer_id;
+						let (kind, result) = match event.stream {
 							SpaceTimeStream::Broadcast(mut stream) => {
-								let mut buf = [0; 100];
-								let n = stream.read(&mut buf).await.unwrap();
-								println!(
-									"GOT BROADCAST: {:?}",
-									std::str::from_utf8(&buf[..n]).unwrap()
-								);
+								("BROADCAST", read_message(&mut stream).await)
 							}
 							SpaceTimeStream::Unicast(mut stream) => {
-								let mut buf = [0; 100];
-								let n = stream.read(&mut buf).await.unwrap();
-								println!(
-									"GOT UNICAST: {:?}",
-									std::str::from_utf8(&buf[..n]).unwrap()
-								);
+								("UNICAST", read_message(&mut stream).await)
+							}
+						};
+
+						match result {
+							Ok(msg) => println!("GOT {kind}: {msg:?}"),
+							Err(err) => {
+								warn!("Skipping malformed {kind} from peer '{peer_id}': {err}")
 							}
 						}
 					});
@@ -97,16 +154,16 @@ async fn main() {
 			}
 		}
 
-		error!("Manager event stream closed! The core is unstable from this point forward!");
-		// process.exit(1); // TODO: Should I?
+		debug!("Manager event stream closed!");
 	});
 
 	if env::var("PING").as_deref() != Ok("skip") {
+		let manager = manager.clone();
 		tokio::spawn(async move {
 			sleep(Duration::from_millis(500)).await;
 
 			// Send pings to every client every 3 second after startup
-			loop {
+			while !manager.is_shutdown() {
 				sleep(Duration::from_secs(3)).await;
 				manager
 					.broadcast(
@@ -120,9 +177,10 @@ async fn main() {
 		});
 	}
 
-	// TODO: proper shutdown
-	// https://docs.rs/ctrlc/latest/ctrlc/
-	// https://docs.rs/system_shutdown/latest/system_shutdown/
-
+	// Your application should call `shutdown` when it's closed (Eg. on Ctrl+C) so peers see the node go offline straight away.
+	// The event stream will return `None` once it's shut down.
 	tokio::time::sleep(Duration::from_secs(100)).await;
+
+	manager.shutdown().await;
+	event_loop.await.unwrap();
 }
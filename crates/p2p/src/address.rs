@@ -0,0 +1,140 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// the kind of network an address is reachable on. When dialing a peer its addresses are tried in this order so the fastest network which is likely to work is used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "specta", derive(specta::Type))]
+pub enum AddressKind {
+	/// a private address on the local network. Eg. `192.168.1.2` or an IPv6 unique local address.
+	Lan,
+	/// a link-local address which is only reachable on the same network segment.
+	LinkLocal,
+	/// an address handed out by a mesh VPN. Eg. Tailscale's `100.64.0.0/10` and `fd7a:115c:a1e0::/48` ranges.
+	Vpn,
+	/// a globally routable address.
+	Public,
+	/// a loopback or unspecified address which can only reach a peer running on the same device.
+	Loopback,
+}
+
+impl AddressKind {
+	pub fn of(addr: &SocketAddr) -> Self {
+		match addr.ip() {
+			IpAddr::V4(ip) => Self::of_ipv4(ip),
+			IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+				Some(ip) => Self::of_ipv4(ip),
+				None => Self::of_ipv6(ip),
+			},
+		}
+	}
+
+	fn of_ipv4(ip: Ipv4Addr) -> Self {
+		let octets = ip.octets();
+		if ip.is_loopback() || ip.is_unspecified() {
+			Self::Loopback
+		} else if ip.is_link_local() {
+			Self::LinkLocal
+		} else if ip.is_private() {
+			Self::Lan
+		} else if octets[0] == 100 && (octets[1] & 0xc0) == 64 {
+			Self::Vpn
+		} else {
+			Self::Public
+		}
+	}
+
+	fn of_ipv6(ip: Ipv6Addr) -> Self {
+		let segments = ip.segments();
+		if ip.is_loopback() || ip.is_unspecified() {
+			Self::Loopback
+		} else if (segments[0] & 0xffc0) == 0xfe80 {
+			Self::LinkLocal
+		} else if segments[..3] == [0xfd7a, 0x115c, 0xa1e0] {
+			Self::Vpn
+		} else if (segments[0] & 0xfe00) == 0xfc00 {
+			Self::Lan
+		} else {
+			Self::Public
+		}
+	}
+}
+
+/// order_addresses returns the addresses in the order they should be dialed, with any duplicates removed.
+/// The `preferred` addresses (Eg. one pinned by the user or the one the peer was last connected through) are tried first in the order given.
+/// The remaining addresses follow ordered by their [AddressKind], keeping the order they were given in within each kind.
+pub fn order_addresses(
+	preferred: &[SocketAddr],
+	addresses: impl IntoIterator<Item = SocketAddr>,
+) -> Vec<SocketAddr> {
+	let mut rest = addresses
+		.into_iter()
+		.filter(|addr| !preferred.contains(addr))
+		.collect::<Vec<_>>();
+	rest.sort_by_key(AddressKind::of);
+
+	let mut ordered: Vec<SocketAddr> = Vec::with_capacity(preferred.len() + rest.len());
+	for addr in preferred.iter().copied().chain(rest) {
+		if !ordered.contains(&addr) {
+			ordered.push(addr);
+		}
+	}
+	ordered
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn addr(s: &str) -> SocketAddr {
+		s.parse().unwrap()
+	}
+
+	#[test]
+	fn test_address_kind() {
+		assert_eq!(AddressKind::of(&addr("192.168.1.2:1")), AddressKind::Lan);
+		assert_eq!(AddressKind::of(&addr("[fd00::1]:1")), AddressKind::Lan);
+		assert_eq!(
+			AddressKind::of(&addr("169.254.0.1:1")),
+			AddressKind::LinkLocal
+		);
+		assert_eq!(
+			AddressKind::of(&addr("[fe80::1]:1")),
+			AddressKind::LinkLocal
+		);
+		assert_eq!(
+			AddressKind::of(&addr("100.101.102.103:1")),
+			AddressKind::Vpn
+		);
+		assert_eq!(
+			AddressKind::of(&addr("[fd7a:115c:a1e0::1]:1")),
+			AddressKind::Vpn
+		);
+		assert_eq!(AddressKind::of(&addr("1.1.1.1:1")), AddressKind::Public);
+		assert_eq!(
+			AddressKind::of(&addr("[::ffff:192.168.1.2]:1")),
+			AddressKind::Lan
+		);
+		assert_eq!(AddressKind::of(&addr("127.0.0.1:1")), AddressKind::Loopback);
+	}
+
+	#[test]
+	fn test_order_addresses() {
+		let (lan, vpn, public, pinned) = (
+			addr("192.168.1.2:1"),
+			addr("100.64.0.1:1"),
+			addr("1.1.1.1:1"),
+			addr("1.0.0.1:1"),
+		);
+
+		assert_eq!(
+			order_addresses(&[], [public, vpn, lan, vpn]),
+			vec![lan, vpn, public]
+		);
+
+		// Preferred addresses are tried first even if the peer isn't advertising them
+		assert_eq!(
+			order_addresses(&[pinned, public], [public, vpn, lan]),
+			vec![pinned, public, lan, vpn]
+		);
+	}
+}
@@ -0,0 +1,85 @@
+use std::sync::Arc;
+
+use libp2p::{
+	dcutr, identify, relay,
+	swarm::{behaviour::toggle::Toggle, NetworkBehaviour},
+};
+
+use crate::{spacetime::SpaceTime, Keypair, Manager, ManagerStreamAction, Metadata};
+
+/// Behaviour is the [`NetworkBehaviour`](libp2p::swarm::NetworkBehaviour) of the [`libp2p::Swarm`](libp2p::Swarm).
+/// It's [SpaceTime] along with the behaviours for connecting with peers on other networks, which are only enabled when [crate::ManagerConfig::relays] is set.
+#[derive(NetworkBehaviour)]
+#[behaviour(
+	out_event = "BehaviourEvent<TMetadata>",
+	prelude = "libp2p::swarm::derive_prelude"
+)]
+pub(crate) struct Behaviour<TMetadata: Metadata> {
+	pub(crate) spacetime: SpaceTime<TMetadata>,
+	/// reserves a slot on each relay so peers can dial us through it.
+	relay: Toggle<relay::client::Behaviour>,
+	/// upgrades relayed connections to direct connections by hole punching.
+	dcutr: Toggle<dcutr::Behaviour>,
+	/// learns the addresses peers observe us at, which are the addresses DCUtR hole punches with.
+	identify: Toggle<identify::Behaviour>,
+}
+
+impl<TMetadata: Metadata> Behaviour<TMetadata> {
+	/// `relay` is the relay client created alongside its transport, or `None` if relaying is disabled.
+	pub(crate) fn new(
+		manager: Arc<Manager<TMetadata>>,
+		keypair: &Keypair,
+		application_name: &str,
+		relay: Option<relay::client::Behaviour>,
+		hole_punching: bool,
+	) -> Self {
+		let peer_id = manager.peer_id.0;
+		let hole_punching = hole_punching && relay.is_some();
+
+		Self {
+			spacetime: SpaceTime::new(manager),
+			relay: relay.into(),
+			dcutr: hole_punching.then(|| dcutr::Behaviour::new(peer_id)).into(),
+			identify: hole_punching
+				.then(|| {
+					identify::Behaviour::new(identify::Config::new(
+						format!("/{application_name}/1.0.0"),
+						keypair.public(),
+					))
+				})
+				.into(),
+		}
+	}
+}
+
+#[derive(Debug)]
+pub(crate) enum BehaviourEvent<TMetadata: Metadata> {
+	SpaceTime(ManagerStreamAction<TMetadata>),
+	Relay(relay::client::Event),
+	Dcutr(dcutr::Event),
+	Identify(Box<identify::Event>),
+}
+
+impl<TMetadata: Metadata> From<ManagerStreamAction<TMetadata>> for BehaviourEvent<TMetadata> {
+	fn from(event: ManagerStreamAction<TMetadata>) -> Self {
+		Self::SpaceTime(event)
+	}
+}
+
+impl<TMetadata: Metadata> From<relay::client::Event> for BehaviourEvent<TMetadata> {
+	fn from(event: relay::client::Event) -> Self {
+		Self::Relay(event)
+	}
+}
+
+impl<TMetadata: Metadata> From<dcutr::Event> for BehaviourEvent<TMetadata> {
+	fn from(event: dcutr::Event) -> Self {
+		Self::Dcutr(event)
+	}
+}
+
+impl<TMetadata: Metadata> From<identify::Event> for BehaviourEvent<TMetadata> {
+	fn from(event: identify::Event) -> Self {
+		Self::Identify(Box::new(event))
+	}
+}
@@ -0,0 +1,76 @@
+use std::{
+	collections::{HashMap, HashSet},
+	net::SocketAddr,
+	sync::{PoisonError, RwLock},
+	time::{Duration, Instant},
+};
+
+use crate::PeerId;
+
+/// how long new connections from the address of a blocked peer are refused without waiting for the handshake to identify them.
+const BLOCKED_ADDR_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// Blocklist holds the peers which connections are refused with.
+/// This uses synchronous locks as it's checked from the synchronous libp2p callbacks.
+#[derive(Debug, Default)]
+pub(crate) struct Blocklist {
+	peers: RwLock<HashSet<PeerId>>,
+	/// the addresses blocked peers have recently connected from.
+	/// Connections from these are refused before the handshake so a blocked peer reconnecting in a loop can't make us do any expensive work.
+	addrs: RwLock<HashMap<SocketAddr, (PeerId, Instant)>>,
+}
+
+impl Blocklist {
+	pub(crate) fn new(peers: HashSet<PeerId>) -> Self {
+		Self {
+			peers: RwLock::new(peers),
+			addrs: Default::default(),
+		}
+	}
+
+	pub(crate) fn is_blocked(&self, peer_id: &PeerId) -> bool {
+		self.peers
+			.read()
+			.unwrap_or_else(PoisonError::into_inner)
+			.contains(peer_id)
+	}
+
+	pub(crate) fn peers(&self) -> HashSet<PeerId> {
+		self.peers
+			.read()
+			.unwrap_or_else(PoisonError::into_inner)
+			.clone()
+	}
+
+	pub(crate) fn block(&self, peer_id: PeerId) {
+		self.peers
+			.write()
+			.unwrap_or_else(PoisonError::into_inner)
+			.insert(peer_id);
+	}
+
+	pub(crate) fn unblock(&self, peer_id: &PeerId) {
+		self.peers
+			.write()
+			.unwrap_or_else(PoisonError::into_inner)
+			.remove(peer_id);
+		self.addrs
+			.write()
+			.unwrap_or_else(PoisonError::into_inner)
+			.retain(|_, (id, _)| id != peer_id);
+	}
+
+	/// refuse connections from the address for `BLOCKED_ADDR_COOLDOWN`. This is called when a blocked peer connects from it.
+	pub(crate) fn refuse_addr(&self, addr: SocketAddr, peer_id: PeerId) {
+		self.addrs
+			.write()
+			.unwrap_or_else(PoisonError::into_inner)
+			.insert(addr, (peer_id, Instant::now()));
+	}
+
+	pub(crate) fn is_addr_refused(&self, addr: &SocketAddr) -> bool {
+		let mut addrs = self.addrs.write().unwrap_or_else(PoisonError::into_inner);
+		addrs.retain(|_, (_, refused_at)| refused_at.elapsed() < BLOCKED_ADDR_COOLDOWN);
+		addrs.contains_key(addr)
+	}
+}
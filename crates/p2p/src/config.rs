@@ -0,0 +1,97 @@
+use std::{
+	collections::HashSet,
+	net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+	time::Duration,
+};
+
+use libp2p::Multiaddr;
+
+use crate::{transport_order, PeerId, Transport};
+
+/// the number of keepalives which can be missed before the connection is considered dead.
+const MAX_MISSED_KEEPALIVES: u32 = 3;
+
+/// configuration for the [crate::Manager].
+#[derive(Debug, Clone)]
+pub struct ManagerConfig {
+	/// how often a keepalive is sent on an idle connection to stop it from timing out.
+	/// If `MAX_MISSED_KEEPALIVES` are missed in a row the connection will be closed and a `PeerDisconnected` event emitted.
+	pub keepalive_interval: Duration,
+	/// is mDNS discovery enabled when the manager starts. This can be changed at runtime with [crate::Manager::set_discovery_enabled].
+	pub discovery_enabled: bool,
+	/// the peers which connections are refused with. This can be changed at runtime with [crate::Manager::block_peer].
+	pub blocked_peers: HashSet<PeerId>,
+	/// how long a discovered peer is remembered without readvertising itself before a `PeerExpired` event is emitted.
+	/// Peers readvertise every minute so this should be longer than that or peers will repeatedly expire and be rediscovered.
+	pub mdns_ttl: Duration,
+	/// the port to listen on. `0` will pick a random free port each time the manager is created.
+	/// Every transport listens on the same port so a peer can be dialed with any of them using the addresses it advertises.
+	pub listen_port: u16,
+	/// the addresses of the network interfaces to listen on. If empty the manager listens on every IPv4 and IPv6 interface.
+	/// The listen addresses can't be changed once the manager has been created.
+	pub listen_addrs: Vec<IpAddr>,
+	/// how long each of a peer's addresses is tried before moving on to the next one when dialing it.
+	/// This should be short enough that an unreachable address (Eg. a VPN which is down) doesn't hold up the ones after it.
+	pub dial_timeout: Duration,
+	/// the transports to use in order of preference. A peer is dialed with each transport in turn until a connection is established.
+	/// TCP is always used as the last fallback even if it's not included, see [Self::transport_order].
+	pub transports: Vec<Transport>,
+	/// the circuit relays used to connect with peers on other networks. Relaying is disabled if this is empty so peers can only connect directly.
+	/// Each address must end with the relay's peer id, Eg. `/ip4/203.0.113.1/udp/7373/quic-v1/p2p/<peer id>`.
+	/// A slot is reserved on every relay so peers can dial us through it.
+	pub relays: Vec<Multiaddr>,
+	/// are relayed connections upgraded to direct connections with DCUtR hole punching when possible. This has no effect if there are no `relays`.
+	/// Hole punching is done with the addresses peers observe us at so it needs a transport which can be reached from other networks.
+	pub hole_punching: bool,
+}
+
+impl ManagerConfig {
+	/// how long a connection can be idle before it's considered dead.
+	pub(crate) fn idle_timeout(&self) -> Duration {
+		self.keepalive_interval * MAX_MISSED_KEEPALIVES
+	}
+
+	/// the transports the manager will listen and dial with in the order they're tried.
+	pub fn transport_order(&self) -> Vec<Transport> {
+		transport_order(&self.transports)
+	}
+
+	/// the socket addresses the manager will listen on.
+	pub(crate) fn listen_socket_addrs(&self) -> Vec<SocketAddr> {
+		#[cfg(any(test, feature = "test-utils"))]
+		if self.transport_order() == [Transport::Memory] {
+			return vec![crate::memory_listen_addr(self.listen_port)];
+		}
+
+		let addrs = if self.listen_addrs.is_empty() {
+			vec![
+				IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+				IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+			]
+		} else {
+			self.listen_addrs.clone()
+		};
+
+		addrs
+			.into_iter()
+			.map(|addr| SocketAddr::new(addr, self.listen_port))
+			.collect()
+	}
+}
+
+impl Default for ManagerConfig {
+	fn default() -> Self {
+		Self {
+			keepalive_interval: Duration::from_secs(15),
+			discovery_enabled: true,
+			blocked_peers: HashSet::new(),
+			mdns_ttl: Duration::from_secs(3 * 60),
+			listen_port: 0,
+			listen_addrs: Vec::new(),
+			dial_timeout: Duration::from_secs(3),
+			transports: vec![Transport::Quic, Transport::Tcp],
+			relays: Vec::new(),
+			hole_punching: true,
+		}
+	}
+}
@@ -0,0 +1,161 @@
+use std::io;
+
+use libp2p::{swarm, TransportError};
+use thiserror::Error;
+
+/// the reason a peer couldn't be dialed. This is returned by [crate::Manager::dial] so the application can show why a peer won't connect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "specta", derive(specta::Type))]
+pub enum DialError {
+	/// none of the peer's addresses responded within [crate::ManagerConfig::dial_timeout].
+	#[error("timed out connecting to the peer")]
+	Timeout,
+	/// the peer (or something between us and it) actively refused the connection.
+	#[error("the peer refused the connection")]
+	Refused,
+	/// the peer doesn't speak a protocol version this node understands.
+	#[error("the peer is running an incompatible version")]
+	Incompatible,
+	/// the peer's identity couldn't be verified. Eg. a different peer is now listening on the address.
+	#[error("the peer's identity couldn't be verified")]
+	AuthFailed,
+	/// the peer is on this node's blocklist so it was never dialed.
+	#[error("the peer is blocked")]
+	Blocked,
+	/// the peer has no usable addresses or the connection failed for a reason which couldn't be classified.
+	#[error("the peer couldn't be reached")]
+	Unreachable,
+	/// the dial was abandoned before it finished. Eg. the P2P manager was shut down.
+	#[error("the dial was aborted")]
+	Aborted,
+	/// the peer id is this node's own so it was never dialed. Eg. the node discovered its own advertisement.
+	#[error("can't dial this node's own peer id")]
+	LocalPeer,
+}
+
+impl DialError {
+	/// classify converts an error from the [libp2p::Swarm] into a [DialError].
+	/// When several addresses were tried the first one which failed for a specific reason is used, so the reason for the most preferred address wins.
+	pub(crate) fn classify(err: &swarm::DialError) -> Self {
+		match err {
+			swarm::DialError::Banned => Self::Blocked,
+			swarm::DialError::WrongPeerId { .. } => Self::AuthFailed,
+			swarm::DialError::Aborted => Self::Aborted,
+			swarm::DialError::LocalPeerId { .. } => Self::LocalPeer,
+			swarm::DialError::Transport(errors) => errors
+				.iter()
+				.map(|(_, err)| match err {
+					TransportError::MultiaddrNotSupported(_) => Self::Unreachable,
+					TransportError::Other(err) => Self::classify_io(err),
+				})
+				.find(|reason| *reason != Self::Unreachable)
+				.unwrap_or(Self::Unreachable),
+			_ => Self::Unreachable,
+		}
+	}
+
+	// The QUIC transport is boxed so its errors are erased into `io::Error`. Where the kind doesn't tell us enough we fall back to the message of the underlying error.
+	fn classify_io(err: &io::Error) -> Self {
+		match err.kind() {
+			io::ErrorKind::TimedOut => return Self::Timeout,
+			io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset => {
+				return Self::Refused
+			}
+			_ => {}
+		}
+
+		let message = err.to_string().to_lowercase();
+		if message.contains("timed out") || message.contains("timeout") {
+			Self::Timeout
+		} else if message.contains("version")
+			|| message.contains("application protocol")
+			|| message.contains("negotiat")
+		{
+			Self::Incompatible
+		} else if message.contains("certificate")
+			|| message.contains("crypto")
+			|| message.contains("peer id")
+			|| message.contains("noise")
+		{
+			Self::AuthFailed
+		} else if message.contains("refused") || message.contains("reset") {
+			Self::Refused
+		} else {
+			Self::Unreachable
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::socketaddr_to_quic_multiaddr;
+
+	use super::*;
+
+	fn io_err(kind: io::ErrorKind, message: &str) -> io::Error {
+		io::Error::new(kind, message.to_string())
+	}
+
+	#[test]
+	fn test_classify_io() {
+		assert_eq!(
+			DialError::classify_io(&io_err(io::ErrorKind::TimedOut, "")),
+			DialError::Timeout
+		);
+		assert_eq!(
+			DialError::classify_io(&io_err(
+				io::ErrorKind::Other,
+				"Handshake with the remote timed out."
+			)),
+			DialError::Timeout
+		);
+		assert_eq!(
+			DialError::classify_io(&io_err(io::ErrorKind::ConnectionRefused, "")),
+			DialError::Refused
+		);
+		assert_eq!(
+			DialError::classify_io(&io_err(
+				io::ErrorKind::Other,
+				"peer doesn't implement any supported version"
+			)),
+			DialError::Incompatible
+		);
+		assert_eq!(
+			DialError::classify_io(&io_err(
+				io::ErrorKind::Other,
+				"the cryptographic handshake failed: invalid peer certificate"
+			)),
+			DialError::AuthFailed
+		);
+		assert_eq!(
+			DialError::classify_io(&io_err(io::ErrorKind::Other, "network unreachable")),
+			DialError::Unreachable
+		);
+	}
+
+	#[test]
+	fn test_classify_prefers_specific_reason() {
+		let addr = socketaddr_to_quic_multiaddr(&"127.0.0.1:1".parse().unwrap());
+		let err = swarm::DialError::Transport(vec![
+			(
+				addr.clone(),
+				TransportError::MultiaddrNotSupported(addr.clone()),
+			),
+			(
+				addr.clone(),
+				TransportError::Other(io_err(io::ErrorKind::ConnectionRefused, "")),
+			),
+			(
+				addr,
+				TransportError::Other(io_err(io::ErrorKind::TimedOut, "")),
+			),
+		]);
+
+		assert_eq!(DialError::classify(&err), DialError::Refused);
+		assert_eq!(
+			DialError::classify(&swarm::DialError::Banned),
+			DialError::Blocked
+		);
+	}
+}
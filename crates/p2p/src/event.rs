@@ -18,7 +18,10 @@ pub enum Event<TMetadata: Metadata> {
 	RemoveListenAddr(SocketAddr),
 	/// discovered peer on your local network
 	PeerDiscovered(DiscoveredPeer<TMetadata>),
-	/// a discovered peer has disappeared from the network
+	/// a discovered peer has readvertised itself with different metadata
+	PeerMetadataChanged(DiscoveredPeer<TMetadata>),
+	/// a discovered peer has disappeared from the network. This is emitted when the peer de-advertises itself or doesn't readvertise within [crate::ManagerConfig::mdns_ttl].
+	/// The peer may still be connected or dialable by address, use `PeerDisconnected` to know when a connection is lost.
 	PeerExpired {
 		id: PeerId,
 		// Will be none if we receive the expire event without having ever seen a discover event.
@@ -27,11 +30,15 @@ pub enum Event<TMetadata: Metadata> {
 	/// communication was established with a peer.
 	/// Theere could actually be multiple connections under the hood but we smooth it over in this API.
 	PeerConnected(ConnectedPeer),
+	/// a relayed connection with a peer has been upgraded to a direct connection by hole punching. The relayed connection is closed once this is emitted.
+	PeerConnectionUpgraded(ConnectedPeer),
 	/// communication was lost with a peer.
 	PeerDisconnected(PeerId),
 	/// the peer has opened a new substream
 	#[cfg_attr(any(feature = "serde", feature = "specta"), serde(skip))]
 	PeerMessage(PeerMessageEvent<TMetadata>),
+	/// the consumer of an [crate::EventStream] fell behind and this many of the oldest events were dropped.
+	Lagged { skipped: u64 },
 }
 
 #[derive(Debug)]
@@ -0,0 +1,229 @@
+use std::{
+	collections::VecDeque,
+	mem,
+	sync::{Arc, Mutex},
+};
+
+use tokio::sync::Notify;
+
+use crate::{AsyncFn, Event, ManagerStream, Metadata};
+
+/// the number of events an [EventStream] buffers by default.
+pub const DEFAULT_EVENT_STREAM_CAPACITY: usize = 1024;
+
+impl<TMetadata, TMetadataFn> ManagerStream<TMetadata, TMetadataFn>
+where
+	TMetadata: Metadata,
+	TMetadataFn: AsyncFn<Output = TMetadata>,
+{
+	/// into_event_stream polls the [ManagerStream] on its own task so the P2P system keeps running no matter how slowly the returned [EventStream] is consumed.
+	/// At most `capacity` events are buffered. Once it's full the oldest event is dropped to make room, so a stalled consumer can't exhaust memory or block networking.
+	/// Dropping an [Event::PeerMessage] closes its stream so the peer sees the request fail.
+	pub fn into_event_stream(mut self, capacity: usize) -> EventStream<TMetadata>
+	where
+		TMetadataFn::Future: 'static,
+	{
+		let (tx, rx) = channel(capacity);
+		tokio::spawn(async move {
+			while let Some(event) = self.next().await {
+				if tx.send(event).is_err() {
+					break;
+				}
+			}
+		});
+
+		EventStream { rx }
+	}
+}
+
+/// EventStream is a bounded buffer of the events from a [ManagerStream]. It's created with [ManagerStream::into_event_stream].
+pub struct EventStream<TMetadata: Metadata> {
+	rx: Receiver<Event<TMetadata>>,
+}
+
+impl<TMetadata: Metadata> EventStream<TMetadata> {
+	/// next returns the next event, or `None` once the [ManagerStream] has shut down.
+	/// If events were dropped because the buffer was full an [Event::Lagged] is returned first, followed by the events which were kept.
+	pub async fn next(&mut self) -> Option<Event<TMetadata>> {
+		match self.rx.recv().await? {
+			Ok(event) => Some(event),
+			Err(skipped) => Some(Event::Lagged { skipped }),
+		}
+	}
+}
+
+struct Buffer<T> {
+	events: VecDeque<T>,
+	capacity: usize,
+	/// the number of events dropped since the receiver was last told.
+	lagged: u64,
+	closed: bool,
+}
+
+struct Shared<T> {
+	buffer: Mutex<Buffer<T>>,
+	notify: Notify,
+}
+
+fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+	let shared = Arc::new(Shared {
+		buffer: Mutex::new(Buffer {
+			events: VecDeque::new(),
+			capacity: capacity.max(1),
+			lagged: 0,
+			closed: false,
+		}),
+		notify: Notify::new(),
+	});
+
+	(
+		Sender {
+			shared: shared.clone(),
+		},
+		Receiver { shared },
+	)
+}
+
+struct Sender<T> {
+	shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+	/// send never waits for the receiver. This returns the event if the receiver has been dropped.
+	fn send(&self, event: T) -> Result<(), T> {
+		if Arc::strong_count(&self.shared) == 1 {
+			return Err(event);
+		}
+
+		{
+			let mut buffer = self
+				.shared
+				.buffer
+				.lock()
+				.unwrap_or_else(|err| err.into_inner());
+			if buffer.events.len() >= buffer.capacity {
+				buffer.events.pop_front();
+				buffer.lagged += 1;
+			}
+			buffer.events.push_back(event);
+		}
+
+		self.shared.notify.notify_one();
+		Ok(())
+	}
+}
+
+impl<T> Drop for Sender<T> {
+	fn drop(&mut self) {
+		self.shared
+			.buffer
+			.lock()
+			.unwrap_or_else(|err| err.into_inner())
+			.closed = true;
+		self.shared.notify.notify_one();
+	}
+}
+
+struct Receiver<T> {
+	shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+	/// recv returns `Err` with the number of dropped events before any event which was sent after them.
+	async fn recv(&mut self) -> Option<Result<T, u64>> {
+		loop {
+			{
+				let mut buffer = self
+					.shared
+					.buffer
+					.lock()
+					.unwrap_or_else(|err| err.into_inner());
+				if buffer.lagged > 0 {
+					return Some(Err(mem::take(&mut buffer.lagged)));
+				}
+				if let Some(event) = buffer.events.pop_front() {
+					return Some(Ok(event));
+				}
+				if buffer.closed {
+					return None;
+				}
+			}
+
+			// `notify_one` stores a permit if we aren't waiting yet so a send between the check above and here isn't missed
+			self.shared.notify.notified().await;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use tokio::time::timeout;
+
+	use super::*;
+
+	#[tokio::test]
+	async fn test_flood_drops_oldest() {
+		let (tx, mut rx) = channel(16);
+
+		// The sender never waits for the receiver so this finishes even though nothing is being received
+		let sender = tokio::spawn(async move {
+			for i in 0..10_000u32 {
+				tx.send(i).unwrap();
+				if i % 1000 == 0 {
+					tokio::task::yield_now().await;
+				}
+			}
+		});
+		timeout(Duration::from_secs(5), sender)
+			.await
+			.unwrap()
+			.unwrap();
+
+		assert_eq!(rx.recv().await, Some(Err(10_000 - 16)));
+		for i in 10_000 - 16..10_000 {
+			assert_eq!(rx.recv().await, Some(Ok(i)));
+		}
+
+		// The sender was dropped once it had finished
+		assert_eq!(rx.recv().await, None);
+	}
+
+	#[tokio::test]
+	async fn test_slow_receiver() {
+		let (tx, mut rx) = channel(4);
+
+		let sender = tokio::spawn(async move {
+			for i in 0..100u32 {
+				tx.send(i).unwrap();
+				tokio::task::yield_now().await;
+			}
+		});
+
+		let mut received = Vec::new();
+		let mut skipped = 0;
+		while let Some(event) = rx.recv().await {
+			match event {
+				Ok(i) => received.push(i),
+				Err(n) => skipped += n,
+			}
+			tokio::time::sleep(Duration::from_millis(1)).await;
+		}
+		sender.await.unwrap();
+
+		// Every event is either received in order or counted as skipped
+		assert_eq!(received.len() as u64 + skipped, 100);
+		assert!(received.windows(2).all(|w| w[0] < w[1]));
+		assert_eq!(received.last(), Some(&99));
+	}
+
+	#[tokio::test]
+	async fn test_receiver_dropped() {
+		let (tx, rx) = channel(4);
+		tx.send(1).unwrap();
+
+		drop(rx);
+		assert_eq!(tx.send(2), Err(2));
+	}
+}
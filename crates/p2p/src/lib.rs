@@ -1,17 +1,37 @@
 //! Rust Peer to Peer Networking Library
 
+mod address;
+mod behaviour;
+mod blocklist;
+mod config;
+mod dial;
 mod event;
+mod event_stream;
 mod manager;
 mod manager_stream;
 mod mdns;
+mod metrics;
 mod peer;
 pub mod spaceblock;
 pub mod spacetime;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+mod transport;
 mod utils;
 
+pub use address::*;
+pub(crate) use behaviour::*;
+pub(crate) use blocklist::*;
+pub use config::*;
+pub use dial::*;
 pub use event::*;
+pub use event_stream::*;
 pub use manager::*;
 pub use manager_stream::*;
 pub use mdns::*;
+pub use metrics::*;
 pub use peer::*;
+pub use transport::*;
 pub use utils::*;
+
+pub use libp2p::Multiaddr;
@@ -1,14 +1,26 @@
-use std::{collections::HashSet, net::SocketAddr, sync::Arc};
+use std::{
+	collections::{HashMap, HashSet},
+	net::SocketAddr,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+};
 
-use libp2p::{core::muxing::StreamMuxerBox, quic, Swarm, Transport};
+use libp2p::{
+	core::{muxing::StreamMuxerBox, transport::Boxed, upgrade},
+	futures::future::Either,
+	multiaddr::Protocol,
+	noise, quic, relay, tcp, yamux, Multiaddr, Swarm, Transport as _,
+};
 use thiserror::Error;
 use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, error, warn};
 
 use crate::{
-	spacetime::{SpaceTime, UnicastStream},
-	AsyncFn, DiscoveredPeer, Keypair, ManagerStream, ManagerStreamAction, Mdns, MdnsState,
-	Metadata, PeerId,
+	is_valid_relay_addr, spacetime::UnicastStream, AsyncFn, Behaviour, Blocklist, DialError,
+	DiscoveredPeer, Keypair, ManagerConfig, ManagerStats, ManagerStream, ManagerStreamAction, Mdns,
+	MdnsState, Metadata, Metrics, PeerId, PeerStats, Transport,
 };
 
 /// Is the core component of the P2P system that holds the state and delegates actions to the other components
@@ -17,14 +29,23 @@ pub struct Manager<TMetadata: Metadata> {
 	pub(crate) mdns_state: Arc<MdnsState<TMetadata>>,
 	pub(crate) peer_id: PeerId,
 	pub(crate) application_name: &'static [u8],
+	pub(crate) metrics: Metrics,
+	pub(crate) blocklist: Blocklist,
+	/// the transports from [ManagerConfig::transport_order]. The first is the primary transport whose listen addresses are advertised.
+	pub(crate) transports: Vec<Transport>,
+	/// the relays from [ManagerConfig::relays]. Peers are dialed through them once their direct addresses have been tried.
+	pub(crate) relays: Vec<Multiaddr>,
 	event_stream_tx: mpsc::Sender<ManagerStreamAction<TMetadata>>,
+	is_shutdown: AtomicBool,
 }
 
 impl<TMetadata: Metadata> Manager<TMetadata> {
 	/// create a new P2P manager. Please do your best to make the callback closures as fast as possible because they will slow the P2P event loop!
+	/// The `application_name` is used for both the mDNS service and the libp2p protocol name so managers with different names won't discover or be able to talk to each other.
 	pub async fn new<TMetadataFn>(
-		application_name: &'static str,
+		application_name: &str,
 		keypair: &Keypair,
+		config: ManagerConfig,
 		fn_get_metadata: TMetadataFn,
 	) -> Result<(Arc<Self>, ManagerStream<TMetadata, TMetadataFn>), ManagerError>
 	where
@@ -36,10 +57,20 @@ impl<TMetadata: Metadata> Manager<TMetadata> {
 			.then_some(())
 			.ok_or(ManagerError::InvalidAppName)?;
 
+		if let Some(addr) = config.relays.iter().find(|addr| !is_valid_relay_addr(addr)) {
+			return Err(ManagerError::InvalidRelayAddr(addr.clone()));
+		}
+
 		let peer_id = PeerId(keypair.public().to_peer_id());
 		let (event_stream_tx, event_stream_rx) = mpsc::channel(1024);
 
-		let (mdns, mdns_state) = Mdns::new(application_name, peer_id, fn_get_metadata).unwrap();
+		let (mdns, mdns_state) = Mdns::new(
+			application_name,
+			peer_id,
+			fn_get_metadata,
+			config.discovery_enabled,
+			config.mdns_ttl,
+		)?;
 		let this = Arc::new(Self {
 			mdns_state,
 			// Look this is bad but it's hard to avoid. Technically a memory leak but it's a small amount of memory and is should done on startup on the P2P system.
@@ -49,27 +80,61 @@ impl<TMetadata: Metadata> Manager<TMetadata> {
 					.to_vec(),
 			)),
 			peer_id,
+			metrics: Default::default(),
+			blocklist: Blocklist::new(config.blocked_peers.clone()),
+			transports: config.transport_order(),
+			relays: config.relays.clone(),
 			event_stream_tx,
+			is_shutdown: AtomicBool::new(false),
 		});
 
+		// The relay client is made up of a transport for the relayed connections and a behaviour which manages the reservations they're made through
+		let (relay_transport, relay_behaviour) = if config.relays.is_empty() {
+			(None, None)
+		} else {
+			let (transport, behaviour) = relay::client::new(peer_id.0);
+			(Some(transport), Some(behaviour))
+		};
+
 		let mut swarm = Swarm::with_tokio_executor(
-			quic::GenTransport::<quic::tokio::Provider>::new(quic::Config::new(keypair.inner()))
-				.map(|(p, c), _| (p, StreamMuxerBox::new(c)))
-				.boxed(),
-			SpaceTime::new(this.clone()),
+			build_transport(keypair, &config, relay_transport)?,
+			Behaviour::new(
+				this.clone(),
+				keypair,
+				application_name,
+				relay_behaviour,
+				config.hole_punching,
+			),
 			keypair.public().to_peer_id(),
 		);
-		{
+
+		// The other transports are listened on once the primary transport has been assigned a port so every transport shares it.
+		// `transport_order` always includes TCP so there is at least one transport.
+		let primary = this.transports[0];
+		let mut pending_listeners = HashMap::new();
+		for addr in config.listen_socket_addrs() {
 			let listener_id = swarm
-            .listen_on("/ip4/0.0.0.0/udp/0/quic-v1".parse().expect("Error passing libp2p multiaddr. This value is hardcoded so this should be impossible."))
-            .unwrap();
-			debug!("created ipv4 listener with id '{:?}'", listener_id);
+				.listen_on(primary.multiaddr(&addr))
+				.map_err(|err| ManagerError::Listen {
+					addr,
+					error: err.to_string(),
+				})?;
+			debug!(
+				"created {primary:?} listener on '{addr}' with id '{:?}'",
+				listener_id
+			);
+			pending_listeners.insert(listener_id, addr.ip());
 		}
-		{
-			let listener_id = swarm
-        .listen_on("/ip6/::/udp/0/quic-v1".parse().expect("Error passing libp2p multiaddr. This value is hardcoded so this should be impossible."))
-        .unwrap();
-			debug!("created ipv4 listener with id '{:?}'", listener_id);
+
+		// Listening through a relay reserves a slot on it. A relay being unreachable isn't fatal as peers on the same network can still connect directly.
+		for relay in &config.relays {
+			match swarm.listen_on(relay.clone().with(Protocol::P2pCircuit)) {
+				Ok(listener_id) => debug!(
+					"created listener through relay '{relay}' with id '{:?}'",
+					listener_id
+				),
+				Err(err) => warn!("error listening through relay '{relay}': {err}"),
+			}
 		}
 
 		Ok((
@@ -80,10 +145,26 @@ impl<TMetadata: Metadata> Manager<TMetadata> {
 				swarm,
 				mdns,
 				queued_events: Default::default(),
+				pending_dials: Default::default(),
+				pending_listeners,
 			},
 		))
 	}
 
+	/// the multiaddrs to dial a peer at. Every address is tried with the preferred transport before falling back to the next one.
+	/// The relays are tried last so a relayed connection is only used when the peer can't be reached directly. The swarm appends the peer id to them when dialing.
+	pub(crate) fn dial_multiaddrs(&self, addresses: &[SocketAddr]) -> Vec<Multiaddr> {
+		self.transports
+			.iter()
+			.flat_map(|transport| addresses.iter().map(|addr| transport.multiaddr(addr)))
+			.chain(
+				self.relays
+					.iter()
+					.map(|relay| relay.clone().with(Protocol::P2pCircuit)),
+			)
+			.collect()
+	}
+
 	pub(crate) async fn emit(&self, event: ManagerStreamAction<TMetadata>) {
 		match self.event_stream_tx.send(event).await {
 			Ok(_) => {}
@@ -125,13 +206,118 @@ impl<TMetadata: Metadata> Manager<TMetadata> {
 		let mut stream = rx.await.map_err(|_| {
 			warn!("failed to queue establishing stream to peer '{peer_id}'!");
 		})?;
-		stream.write_discriminator().await.unwrap(); // TODO: Error handling
+		stream.write_discriminator().await.map_err(|err| {
+			warn!("failed to write discriminator to stream with peer '{peer_id}': {err}");
+		})?;
 		Ok(stream)
 	}
 
 	pub async fn broadcast(&self, data: Vec<u8>) {
 		self.emit(ManagerStreamAction::BroadcastData(data)).await;
 	}
+
+	/// send_to will send the data to a single connected peer. This uses the same one-way stream as `broadcast`.
+	pub async fn send_to(&self, peer_id: PeerId, data: Vec<u8>) {
+		self.emit(ManagerStreamAction::SendTo(peer_id, data)).await;
+	}
+
+	/// dial will attempt to connect to a peer at the given addresses. This does nothing if the peer is already connected.
+	/// The addresses are tried one at a time in the order given, each for up to [ManagerConfig::dial_timeout], so the most preferred address should be first (see [crate::order_addresses]).
+	/// The address which was connected through is the `address` of the `PeerConnected` event.
+	/// This waits for the dial to finish and returns why it failed if none of the addresses could be connected to.
+	pub async fn dial(&self, peer_id: PeerId, addresses: Vec<SocketAddr>) -> Result<(), DialError> {
+		if peer_id == self.peer_id {
+			return Err(DialError::LocalPeer);
+		}
+
+		let (tx, rx) = oneshot::channel();
+		self.emit(ManagerStreamAction::Dial {
+			peer_id,
+			addresses,
+			tx,
+		})
+		.await;
+		rx.await.unwrap_or(Err(DialError::Aborted))
+	}
+
+	/// dial_address will attempt to connect to a peer at the given address without it being discovered.
+	/// As the peer is unknown only the most preferred transport is used.
+	/// A `PeerConnected` event will be emitted once the connection has been established.
+	pub async fn dial_address(&self, addr: SocketAddr) {
+		self.emit(ManagerStreamAction::DialAddress(addr)).await;
+	}
+
+	/// set_discovery_enabled will start or stop advertising and browsing for peers on mDNS.
+	/// Disabling discovery will expire all discovered peers but established connections are kept open.
+	pub async fn set_discovery_enabled(&self, enabled: bool) {
+		self.emit(ManagerStreamAction::SetDiscoveryEnabled(enabled))
+			.await;
+	}
+
+	/// peer_stats returns the traffic with a single peer since the manager was started.
+	/// This will be all zeros if the peer has never been connected to.
+	pub fn peer_stats(&self, peer_id: PeerId) -> PeerStats {
+		self.metrics.stats(&peer_id)
+	}
+
+	/// totals returns the traffic with all peers since the manager was started.
+	pub fn totals(&self) -> PeerStats {
+		self.metrics.totals()
+	}
+
+	/// stats returns the traffic with every peer which has been connected to since the manager was started, along with the totals.
+	pub fn stats(&self) -> ManagerStats {
+		self.metrics.snapshot()
+	}
+
+	/// block_peer will close all connections with the peer and refuse any new connections with it.
+	pub async fn block_peer(&self, peer_id: PeerId) {
+		self.blocklist.block(peer_id);
+		self.disconnect(peer_id).await;
+	}
+
+	/// unblock_peer will allow connections with a peer which was blocked with `block_peer`.
+	pub fn unblock_peer(&self, peer_id: PeerId) {
+		self.blocklist.unblock(&peer_id);
+	}
+
+	pub fn blocked_peers(&self) -> HashSet<PeerId> {
+		self.blocklist.peers()
+	}
+
+	pub fn is_blocked(&self, peer_id: &PeerId) -> bool {
+		self.blocklist.is_blocked(peer_id)
+	}
+
+	/// update_metadata will change the metadata advertised to other peers. This is used instead of the metadata function from now on.
+	/// Updates are debounced so calling this many times in quick succession will only cause a single mDNS advertisement.
+	pub async fn update_metadata(&self, metadata: TMetadata) {
+		self.emit(ManagerStreamAction::UpdateMetadata(metadata))
+			.await;
+	}
+
+	/// disconnect will close all connections with the peer. A `PeerDisconnected` event will be emitted once they're closed.
+	pub async fn disconnect(&self, peer_id: PeerId) {
+		self.emit(ManagerStreamAction::Disconnect(peer_id)).await;
+	}
+
+	/// shutdown will close all connections, stop advertising on mDNS and cause `ManagerStream::next` to return `None`.
+	/// Calling this more than once is a no-op.
+	pub async fn shutdown(&self) {
+		if self.is_shutdown.swap(true, Ordering::SeqCst) {
+			return;
+		}
+
+		let (tx, rx) = oneshot::channel();
+		self.emit(ManagerStreamAction::Shutdown(tx)).await;
+		rx.await.unwrap_or_else(|_| {
+			warn!("Error receiving shutdown response from the 'ManagerStream'. It's likely already closed!");
+		});
+	}
+
+	pub fn is_shutdown(&self) -> bool {
+		self.is_shutdown.load(Ordering::Relaxed)
+	}
 }
 
 #[derive(Error, Debug)]
@@ -142,4 +328,88 @@ pub enum ManagerError {
 	InvalidAppName,
 	#[error("error with mdns discovery: {0}")]
 	Mdns(#[from] mdns_sd::Error),
+	#[error("error listening on '{addr}'. Is the port already in use? {error}")]
+	Listen { addr: SocketAddr, error: String },
+	#[error("error creating transport: {0}")]
+	Transport(String),
+	#[error("the relay address '{0}' is invalid. Ensure it ends with the relay's peer id!")]
+	InvalidRelayAddr(Multiaddr),
+}
+
+/// build_transport creates the libp2p transport supporting every [Transport].
+/// Dials use whichever transport supports the multiaddr so the preference is applied by the order of the multiaddrs (see [Manager::dial_multiaddrs]).
+/// Relayed connections are only supported when the `relay` transport is given, see [ManagerConfig::relays].
+fn build_transport(
+	keypair: &Keypair,
+	config: &ManagerConfig,
+	relay: Option<relay::client::Transport>,
+) -> Result<Boxed<(libp2p::PeerId, StreamMuxerBox)>, ManagerError> {
+	let transport = build_direct_transport(keypair, config)?;
+	let Some(relay) = relay else {
+		return Ok(transport);
+	};
+
+	// A relayed connection is a stream through the relay so it's secured and multiplexed end to end like TCP
+	let relay = relay
+		.upgrade(upgrade::Version::V1)
+		.authenticate(
+			noise::NoiseAuthenticated::xx(keypair.inner())
+				.map_err(|err| ManagerError::Transport(err.to_string()))?,
+		)
+		.multiplex(yamux::YamuxConfig::default())
+		.timeout(config.dial_timeout)
+		.map(|(p, c), _| (p, StreamMuxerBox::new(c)));
+
+	Ok(relay
+		.or_transport(transport)
+		.map(|output, _| match output {
+			Either::Left(output) | Either::Right(output) => output,
+		})
+		.boxed())
+}
+
+/// build_direct_transport creates the transport for connecting directly with peers.
+fn build_direct_transport(
+	keypair: &Keypair,
+	config: &ManagerConfig,
+) -> Result<Boxed<(libp2p::PeerId, StreamMuxerBox)>, ManagerError> {
+	#[cfg(any(test, feature = "test-utils"))]
+	if config.transport_order() == [Transport::Memory] {
+		return Ok(libp2p::core::transport::MemoryTransport::default()
+			.upgrade(upgrade::Version::V1)
+			.authenticate(
+				noise::NoiseAuthenticated::xx(keypair.inner())
+					.map_err(|err| ManagerError::Transport(err.to_string()))?,
+			)
+			.multiplex(yamux::YamuxConfig::default())
+			.timeout(config.dial_timeout)
+			.map(|(p, c), _| (p, StreamMuxerBox::new(c)))
+			.boxed());
+	}
+
+	// Keepalives are handled by QUIC so idle connections don't need any application level pings.
+	let mut quic_config = quic::Config::new(keypair.inner());
+	quic_config.keep_alive_interval = config.keepalive_interval;
+	quic_config.max_idle_timeout = config.idle_timeout().as_millis() as u32;
+	quic_config.handshake_timeout = config.dial_timeout;
+	let quic = quic::GenTransport::<quic::tokio::Provider>::new(quic_config)
+		.map(|(p, c), _| (p, StreamMuxerBox::new(c)));
+
+	// TCP has no built in encryption or multiplexing so they're negotiated on top of it
+	let tcp = tcp::tokio::Transport::new(tcp::Config::default().nodelay(true))
+		.upgrade(upgrade::Version::V1)
+		.authenticate(
+			noise::NoiseAuthenticated::xx(keypair.inner())
+				.map_err(|err| ManagerError::Transport(err.to_string()))?,
+		)
+		.multiplex(yamux::YamuxConfig::default())
+		.timeout(config.dial_timeout)
+		.map(|(p, c), _| (p, StreamMuxerBox::new(c)));
+
+	Ok(quic
+		.or_transport(tcp)
+		.map(|output, _| match output {
+			Either::Left(output) | Either::Right(output) => output,
+		})
+		.boxed())
 }
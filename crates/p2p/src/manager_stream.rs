@@ -1,20 +1,29 @@
-use std::{collections::VecDeque, fmt, net::SocketAddr, sync::Arc};
+use std::{
+	collections::{HashMap, VecDeque},
+	fmt,
+	net::{IpAddr, SocketAddr},
+	sync::Arc,
+};
 
 use libp2p::{
+	core::transport::ListenerId,
+	dcutr,
 	futures::StreamExt,
+	relay,
 	swarm::{
 		dial_opts::{DialOpts, PeerCondition},
 		NetworkBehaviourAction, NotifyHandler, SwarmEvent,
 	},
-	Swarm,
+	Multiaddr, Swarm,
 };
 use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, error, warn};
 
 use crate::{
-	quic_multiaddr_to_socketaddr, socketaddr_to_quic_multiaddr,
-	spacetime::{OutboundRequest, SpaceTime, UnicastStream},
-	AsyncFn, Event, Manager, Mdns, Metadata, PeerId,
+	is_relayed, multiaddr_to_socketaddr,
+	spacetime::{OutboundRequest, UnicastStream},
+	AsyncFn, Behaviour, BehaviourEvent, DialError, Event, Manager, Mdns, Metadata, PeerId,
+	Transport,
 };
 
 /// TODO
@@ -23,15 +32,29 @@ pub enum ManagerStreamAction<TMetadata: Metadata> {
 	Event(Event<TMetadata>),
 	/// TODO
 	GetConnectedPeers(oneshot::Sender<Vec<PeerId>>),
-	/// Tell the [`libp2p::Swarm`](libp2p::Swarm) to establish a new connection to a peer.
+	/// Tell the [`libp2p::Swarm`](libp2p::Swarm) to establish a new connection to a peer. The addresses are tried one at a time in order.
+	/// `tx` is sent the result once the connection is established or every address has failed.
 	Dial {
 		peer_id: PeerId,
 		addresses: Vec<SocketAddr>,
+		tx: oneshot::Sender<Result<(), DialError>>,
 	},
+	/// Tell the [`libp2p::Swarm`](libp2p::Swarm) to establish a new connection to an address where the peer id is not known.
+	DialAddress(SocketAddr),
 	/// TODO
 	StartStream(PeerId, oneshot::Sender<UnicastStream>),
 	/// TODO
 	BroadcastData(Vec<u8>),
+	/// send data to a single peer using a broadcast stream.
+	SendTo(PeerId, Vec<u8>),
+	/// start or stop mDNS discovery.
+	SetDiscoveryEnabled(bool),
+	/// close all connections with a peer.
+	Disconnect(PeerId),
+	/// change the metadata advertised over mDNS.
+	UpdateMetadata(TMetadata),
+	/// the node is shutting down. The `ManagerStream` should convert this into `None` and then drop itself.
+	Shutdown(oneshot::Sender<()>),
 }
 
 impl<TMetadata: Metadata> fmt::Debug for ManagerStreamAction<TMetadata> {
@@ -46,6 +69,25 @@ impl<TMetadata: Metadata> From<Event<TMetadata>> for ManagerStreamAction<TMetada
 	}
 }
 
+/// PendingDial is a dial of a peer which is trying its addresses one at a time.
+pub(crate) struct PendingDial {
+	/// every caller waiting for the dial. A dial started while another is in progress waits for the same result.
+	txs: Vec<oneshot::Sender<Result<(), DialError>>>,
+	/// the addresses which haven't been tried yet, in the order they'll be tried.
+	remaining: VecDeque<Multiaddr>,
+	/// why the addresses which have been tried failed.
+	reason: Option<DialError>,
+}
+
+impl PendingDial {
+	// The reason for the most preferred address wins unless it couldn't be classified
+	fn record(&mut self, reason: DialError) {
+		if self.reason.map_or(true, |r| r == DialError::Unreachable) {
+			self.reason = Some(reason);
+		}
+	}
+}
+
 /// TODO
 pub struct ManagerStream<TMetadata, TMetadataFn>
 where
@@ -54,9 +96,13 @@ where
 {
 	pub(crate) manager: Arc<Manager<TMetadata>>,
 	pub(crate) event_stream_rx: mpsc::Receiver<ManagerStreamAction<TMetadata>>,
-	pub(crate) swarm: Swarm<SpaceTime<TMetadata>>,
+	pub(crate) swarm: Swarm<Behaviour<TMetadata>>,
 	pub(crate) mdns: Mdns<TMetadata, TMetadataFn>,
 	pub(crate) queued_events: VecDeque<Event<TMetadata>>,
+	/// the dial which is still in progress for each peer.
+	pub(crate) pending_dials: HashMap<libp2p::PeerId, PendingDial>,
+	/// the listeners of the primary transport which haven't been assigned an address yet. Once they are the other transports are listened on with the same port.
+	pub(crate) pending_listeners: HashMap<ListenerId, IpAddr>,
 }
 
 impl<TMetadata, TMetadataFn> ManagerStream<TMetadata, TMetadataFn>
@@ -65,6 +111,7 @@ where
 	TMetadataFn: AsyncFn<Output = TMetadata>,
 {
 	// Your application should keep polling this until `None` is received or the P2P system will be halted.
+	// Use `ManagerStream::into_event_stream` if the events can't always be handled straight away.
 	pub async fn next(&mut self) -> Option<Event<TMetadata>> {
 		// We loop polling internal services until an event comes in that needs to be sent to the parent application.
 		loop {
@@ -81,26 +128,62 @@ where
 				},
 				event = self.event_stream_rx.recv() => {
 					// If the sender has shut down we return `None` to also shut down too.
-					if let Some(event) = self.handle_manager_stream_action(event?) {
-						return Some(event);
+					match event? {
+						ManagerStreamAction::Shutdown(tx) => {
+							self.shutdown(tx);
+							return None;
+						}
+						event => {
+							if let Some(event) = self.handle_manager_stream_action(event).await {
+								return Some(event);
+							}
+						}
 					}
 				}
 				event = self.swarm.select_next_some() => {
 					match event {
-						SwarmEvent::Behaviour(event) => {
-							if let Some(event) = self.handle_manager_stream_action(event) {
+						SwarmEvent::Behaviour(BehaviourEvent::SpaceTime(event)) => {
+							if let Some(event) = self.handle_manager_stream_action(event).await {
 								return Some(event);
 							}
 						},
-						SwarmEvent::ConnectionEstablished { .. } => {},
+						SwarmEvent::Behaviour(BehaviourEvent::Relay(event)) => match event {
+							relay::client::Event::ReservationReqAccepted { relay_peer_id, renewal, .. } => debug!("reservation with relay '{}' accepted (renewal: {})", relay_peer_id, renewal),
+							relay::client::Event::ReservationReqFailed { relay_peer_id, error, .. } => warn!("error reserving a slot on relay '{}', peers won't be able to dial us through it: {}", relay_peer_id, error),
+							event => debug!("relay event: {:?}", event),
+						},
+						SwarmEvent::Behaviour(BehaviourEvent::Dcutr(event)) => match event {
+							dcutr::Event::DirectConnectionUpgradeFailed { remote_peer_id, error } => debug!("error upgrading relayed connection with '{}' to a direct connection: {}", remote_peer_id, error),
+							event => debug!("dcutr event: {:?}", event),
+						},
+						SwarmEvent::Behaviour(BehaviourEvent::Identify(event)) => debug!("identify event: {:?}", event),
+						SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+							// Any connection with the peer satisfies the dials, even if it was the peer who dialed us
+							self.resolve_dial(peer_id, Ok(()));
+						},
 						SwarmEvent::ConnectionClosed { .. } => {},
 						SwarmEvent::IncomingConnection { local_addr, .. } => debug!("incoming connection from '{}'", local_addr),
 						SwarmEvent::IncomingConnectionError { local_addr, error, .. } => warn!("handshake error with incoming connection from '{}': {}", local_addr, error),
-						SwarmEvent::OutgoingConnectionError { peer_id, error } => warn!("error establishing connection with '{:?}': {}", peer_id, error),
+						SwarmEvent::OutgoingConnectionError { peer_id, error } => {
+							warn!("error establishing connection with '{:?}': {}", peer_id, error);
+							if let Some(peer_id) = peer_id {
+								self.dial_failed(peer_id, DialError::classify(&error));
+							}
+						},
 						SwarmEvent::BannedPeer { peer_id, .. } => warn!("banned peer '{}' attempted to connection and was rejected", peer_id),
-						SwarmEvent::NewListenAddr { address, .. } => {
-							match quic_multiaddr_to_socketaddr(address) {
+						SwarmEvent::NewListenAddr { listener_id, address } => {
+							let is_primary = self.is_primary_transport(&address);
+							match multiaddr_to_socketaddr(address) {
 								Ok(addr) => {
+									if let Some(ip) = self.pending_listeners.remove(&listener_id) {
+										self.listen_fallback_transports(SocketAddr::new(ip, addr.port()));
+									}
+
+									// Every transport listens on the same addresses so they're only advertised once
+									if !is_primary {
+										continue;
+									}
+
 									debug!("listen address added: {}", addr);
 									self.mdns.register_addr(addr).await;
 									return Some(Event::AddListenAddr(addr));
@@ -112,7 +195,11 @@ where
 							}
 						},
 						SwarmEvent::ExpiredListenAddr { address, .. } => {
-							match quic_multiaddr_to_socketaddr(address) {
+							if !self.is_primary_transport(&address) {
+								continue;
+							}
+
+							match multiaddr_to_socketaddr(address) {
 								Ok(addr) => {
 									debug!("listen address added: {}", addr);
 									self.mdns.unregister_addr(&addr).await;
@@ -127,7 +214,11 @@ where
 						SwarmEvent::ListenerClosed { listener_id, addresses, reason } => {
 							debug!("listener '{:?}' was closed due to: {:?}", listener_id, reason);
 							for address in addresses {
-								match quic_multiaddr_to_socketaddr(address) {
+								if !self.is_primary_transport(&address) {
+									continue;
+								}
+
+								match multiaddr_to_socketaddr(address) {
 									Ok(addr) => {
 										debug!("listen address added: {}", addr);
 										self.mdns.unregister_addr(&addr).await;
@@ -151,7 +242,107 @@ where
 		}
 	}
 
-	fn handle_manager_stream_action(
+	fn shutdown(&mut self, tx: oneshot::Sender<()>) {
+		debug!("shutting down P2P manager");
+
+		match self.mdns.unregister_mdns() {
+			Ok(_) => {}
+			Err(err) => warn!("error unregistering mdns service: {}", err),
+		}
+
+		let connected_peers = self.swarm.connected_peers().copied().collect::<Vec<_>>();
+		for peer_id in connected_peers {
+			self.swarm.disconnect_peer_id(peer_id).ok();
+		}
+
+		tx.send(())
+			.map_err(|_| {
+				error!("Error sending response to `Shutdown` request! Sending was dropped!")
+			})
+			.ok();
+	}
+
+	// Relayed listen addresses are the relay's address so they're never advertised
+	fn is_primary_transport(&self, address: &libp2p::Multiaddr) -> bool {
+		!is_relayed(address) && Transport::of(address) == self.manager.transports.first().copied()
+	}
+
+	// A fallback transport failing to listen isn't fatal as the primary transport is still listening
+	fn listen_fallback_transports(&mut self, addr: SocketAddr) {
+		for transport in self.manager.transports.iter().skip(1) {
+			match self.swarm.listen_on(transport.multiaddr(&addr)) {
+				Ok(listener_id) => debug!(
+					"created {:?} listener on '{}' with id '{:?}'",
+					transport, addr, listener_id
+				),
+				Err(err) => warn!(
+					"error listening with {:?} on '{}', peers won't be able to fall back to it: {}",
+					transport, addr, err
+				),
+			}
+		}
+	}
+
+	fn resolve_dial(&mut self, peer_id: libp2p::PeerId, result: Result<(), DialError>) {
+		for tx in self
+			.pending_dials
+			.remove(&peer_id)
+			.into_iter()
+			.flat_map(|pending| pending.txs)
+		{
+			tx.send(result).ok();
+		}
+	}
+
+	// The address which failed is recorded and the next address is dialed
+	fn dial_failed(&mut self, peer_id: libp2p::PeerId, reason: DialError) {
+		let Some(pending) = self.pending_dials.get_mut(&peer_id) else {
+			return;
+		};
+
+		pending.record(reason);
+		self.dial_next(peer_id);
+	}
+
+	// Each address is dialed on its own once the previous one has failed so its timeout only starts once it is tried.
+	// The swarm creates the dial of every address it's given up front, so timeouts would otherwise run for addresses which haven't been tried yet.
+	fn dial_next(&mut self, peer_id: libp2p::PeerId) {
+		loop {
+			let Some(pending) = self.pending_dials.get_mut(&peer_id) else {
+				return;
+			};
+			let Some(address) = pending.remaining.pop_front() else {
+				let reason = pending.reason.unwrap_or(DialError::Unreachable);
+				self.resolve_dial(peer_id, Err(reason));
+				return;
+			};
+
+			match self.swarm.dial(
+				DialOpts::peer_id(peer_id)
+					.condition(PeerCondition::Disconnected)
+					.addresses(vec![address.clone()])
+					.build(),
+			) {
+				Ok(_) => return,
+				// The peer connected to us in the meantime so there is nothing to do
+				Err(libp2p::swarm::DialError::DialPeerConditionFalse(_)) => {
+					self.resolve_dial(peer_id, Ok(()));
+					return;
+				}
+				Err(err) => {
+					warn!(
+						"error dialing peer '{}' with address '{}': {}",
+						peer_id, address, err
+					);
+					if let Some(pending) = self.pending_dials.get_mut(&peer_id) {
+						pending.record(DialError::classify(&err));
+					}
+				}
+			}
+		}
+	}
+
+	async fn handle_manager_stream_action(
 		&mut self,
 		event: ManagerStreamAction<TMetadata>,
 	) -> Option<Event<TMetadata>> {
@@ -170,32 +361,71 @@ where
 					})
 					.ok();
 			}
-			ManagerStreamAction::Dial { peer_id, addresses } => {
+			ManagerStreamAction::Dial {
+				peer_id,
+				addresses,
+				tx,
+			} => {
+				if self.manager.is_blocked(&peer_id) {
+					debug!("not dialing blocked peer '{peer_id}'");
+					tx.send(Err(DialError::Blocked)).ok();
+					return None;
+				}
+
+				if self.swarm.is_connected(&peer_id.0) {
+					tx.send(Ok(())).ok();
+					return None;
+				}
+
+				// A dial which is already in progress is joined rather than racing it with the new addresses
+				if let Some(pending) = self.pending_dials.get_mut(&peer_id.0) {
+					pending.txs.push(tx);
+					return None;
+				}
+
+				// Only dialing one address at a time means a slow address is never raced against a preferred one
+				self.pending_dials.insert(
+					peer_id.0,
+					PendingDial {
+						txs: vec![tx],
+						remaining: self.manager.dial_multiaddrs(&addresses).into(),
+						reason: None,
+					},
+				);
+				self.dial_next(peer_id.0);
+			}
+			ManagerStreamAction::DialAddress(addr) => {
 				match self.swarm.dial(
-					DialOpts::peer_id(peer_id.0)
-						.condition(PeerCondition::Disconnected)
-						.addresses(addresses.iter().map(socketaddr_to_quic_multiaddr).collect())
+					DialOpts::unknown_peer_id()
+						.address(self.manager.transports[0].multiaddr(&addr))
 						.build(),
 				) {
 					Ok(_) => {}
-					Err(err) => warn!(
-						"error dialing peer '{}' with addresses '{:?}': {}",
-						peer_id, addresses, err
-					),
+					Err(err) => warn!("error dialing address '{}': {}", addr, err),
 				}
 			}
 			ManagerStreamAction::StartStream(peer_id, rx) => {
-				self.swarm.behaviour_mut().pending_events.push_back(
-					NetworkBehaviourAction::NotifyHandler {
+				if !self.swarm.is_connected(&peer_id.0) {
+					// Dropping `rx` will cause `Manager::stream` to return an error instead of waiting forever.
+					warn!(
+						"Attempted to start a stream with peer '{peer_id}' which is not connected!"
+					);
+					return None;
+				}
+
+				self.swarm
+					.behaviour_mut()
+					.spacetime
+					.pending_events
+					.push_back(NetworkBehaviourAction::NotifyHandler {
 						peer_id: peer_id.0,
 						handler: NotifyHandler::Any,
 						event: OutboundRequest::Unicast(rx),
-					},
-				);
+					});
 			}
 			ManagerStreamAction::BroadcastData(data) => {
 				let connected_peers = self.swarm.connected_peers().copied().collect::<Vec<_>>();
-				let behaviour = self.swarm.behaviour_mut();
+				let behaviour = &mut self.swarm.behaviour_mut().spacetime;
 				debug!("Broadcasting message to '{:?}'", connected_peers);
 				for peer_id in connected_peers {
 					behaviour
@@ -207,6 +437,38 @@ where
 						});
 				}
 			}
+			ManagerStreamAction::SendTo(peer_id, data) => {
+				if !self.swarm.is_connected(&peer_id.0) {
+					warn!("Attempted to send data to peer '{peer_id}' which is not connected!");
+					return None;
+				}
+
+				self.swarm
+					.behaviour_mut()
+					.spacetime
+					.pending_events
+					.push_back(NetworkBehaviourAction::NotifyHandler {
+						peer_id: peer_id.0,
+						handler: NotifyHandler::Any,
+						event: OutboundRequest::Broadcast(data),
+					});
+			}
+			ManagerStreamAction::SetDiscoveryEnabled(enabled) => {
+				debug!("setting mdns discovery enabled to '{}'", enabled);
+				let expired = self.mdns.set_enabled(enabled).await;
+				self.queued_events.extend(expired);
+			}
+			ManagerStreamAction::UpdateMetadata(metadata) => {
+				self.mdns.update_metadata(metadata);
+			}
+			ManagerStreamAction::Disconnect(peer_id) => {
+				if self.swarm.disconnect_peer_id(peer_id.0).is_err() {
+					debug!("Attempted to disconnect from peer '{peer_id}' which is not connected");
+				}
+			}
+			ManagerStreamAction::Shutdown(_) => {
+				unreachable!("'ManagerStreamAction::Shutdown' is handled by 'ManagerStream::next'!")
+			}
 		}
 
 		None
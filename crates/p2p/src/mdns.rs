@@ -14,11 +14,19 @@ use tokio::{
 };
 use tracing::{debug, error, warn};
 
-use crate::{AsyncFn, DiscoveredPeer, Event, Manager, Metadata, PeerId};
+use crate::{
+	decode_metadata, encode_metadata, AsyncFn, DiscoveredPeer, Event, Manager, Metadata, PeerId,
+};
 
 /// TODO
 const MDNS_READVERTISEMENT_INTERVAL: Duration = Duration::from_secs(60); // Every minute re-advertise
 
+/// how long to wait after the metadata is updated before readvertising so many updates in quick succession only cause a single advertisement.
+const METADATA_UPDATE_DEBOUNCE: Duration = Duration::from_secs(1);
+
+/// how often the discovered peers are checked for any which haven't readvertised themselves within the TTL.
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
 /// TODO
 #[derive(Debug)]
 pub struct MdnsState<TMetadata: Metadata> {
@@ -35,10 +43,18 @@ where
 	// used to ignore events from our own mdns advertisement
 	peer_id: PeerId,
 	fn_get_metadata: TMetadataFn,
+	/// set by `update_metadata`. This replaces the result of `fn_get_metadata` for all future advertisements.
+	metadata: Option<TMetadata>,
 	mdns_daemon: ServiceDaemon,
-	mdns_service_receiver: flume::Receiver<ServiceEvent>,
+	/// this is `None` while discovery is disabled
+	mdns_service_receiver: Option<flume::Receiver<ServiceEvent>>,
 	service_name: String,
 	next_mdns_advertisement: Pin<Box<Sleep>>,
+	/// how long a peer can go without readvertising itself before it's expired.
+	ttl: Duration,
+	/// when each discovered peer last advertised itself.
+	last_seen: HashMap<PeerId, Instant>,
+	next_expiry_check: Pin<Box<Sleep>>,
 	state: Arc<MdnsState<TMetadata>>,
 }
 
@@ -48,16 +64,21 @@ where
 	TMetadataFn: AsyncFn<Output = TMetadata>,
 {
 	pub fn new(
-		application_name: &'static str,
+		application_name: &str,
 		peer_id: PeerId,
 		fn_get_metadata: TMetadataFn,
+		enabled: bool,
+		ttl: Duration,
 	) -> Result<(Self, Arc<MdnsState<TMetadata>>), mdns_sd::Error>
 	where
 		TMetadataFn: AsyncFn<Output = TMetadata>,
 	{
 		let mdns_daemon = ServiceDaemon::new()?;
 		let service_name = format!("_{}._udp.local.", application_name);
-		let mdns_service_receiver = mdns_daemon.browse(&service_name)?;
+		let mdns_service_receiver = match enabled {
+			true => Some(mdns_daemon.browse(&service_name)?),
+			false => None,
+		};
 
 		let state = Arc::new(MdnsState {
 			discovered: RwLock::new(Default::default()),
@@ -67,10 +88,14 @@ where
 			Self {
 				peer_id,
 				fn_get_metadata,
+				metadata: None,
 				mdns_daemon,
 				mdns_service_receiver,
 				service_name,
 				next_mdns_advertisement: Box::pin(sleep_until(Instant::now())), // Trigger an advertisement immediately
+				ttl,
+				last_seen: HashMap::new(),
+				next_expiry_check: Box::pin(sleep_until(Instant::now() + EXPIRY_CHECK_INTERVAL)),
 				state: state.clone(),
 			},
 			state,
@@ -82,9 +107,66 @@ where
 			.unregister(&format!("{}.{}", self.peer_id, self.service_name))
 	}
 
+	pub fn is_enabled(&self) -> bool {
+		self.mdns_service_receiver.is_some()
+	}
+
+	/// start or stop advertising and browsing on mDNS. When discovery is disabled all the discovered peers are removed and their `PeerExpired` events returned.
+	pub async fn set_enabled(&mut self, enabled: bool) -> Vec<Event<TMetadata>> {
+		if enabled == self.is_enabled() {
+			return Vec::new();
+		}
+
+		if enabled {
+			match self.mdns_daemon.browse(&self.service_name) {
+				Ok(receiver) => self.mdns_service_receiver = Some(receiver),
+				Err(err) => warn!("error starting mdns browse: {}", err),
+			}
+
+			// Trigger an advertisement immediately
+			self.next_mdns_advertisement = Box::pin(sleep_until(Instant::now()));
+			return Vec::new();
+		}
+
+		self.mdns_service_receiver = None;
+		match self.mdns_daemon.stop_browse(&self.service_name) {
+			Ok(_) => {}
+			Err(err) => warn!("error stopping mdns browse: {}", err),
+		}
+		match self.unregister_mdns() {
+			Ok(_) => {}
+			Err(err) => warn!("error unregistering mdns service: {}", err),
+		}
+
+		self.last_seen.clear();
+		self.state
+			.discovered
+			.write()
+			.await
+			.drain()
+			.map(|(id, peer)| Event::PeerExpired {
+				id,
+				metadata: Some(peer.metadata),
+			})
+			.collect()
+	}
+
+	/// update_metadata will change the metadata which is advertised and queue a readvertisement.
+	pub fn update_metadata(&mut self, metadata: TMetadata) {
+		self.metadata = Some(metadata);
+
+		if self.next_mdns_advertisement.deadline() > (Instant::now() + METADATA_UPDATE_DEBOUNCE) {
+			self.next_mdns_advertisement =
+				Box::pin(sleep_until(Instant::now() + METADATA_UPDATE_DEBOUNCE));
+		}
+	}
+
 	/// Do an mdns advertisement to the network.
 	async fn advertise(&mut self) {
-		let metadata = (self.fn_get_metadata)().await.to_hashmap();
+		let metadata = encode_metadata(match &self.metadata {
+			Some(metadata) => metadata.clone(),
+			None => (self.fn_get_metadata)().await,
+		});
 
 		// This is in simple terms converts from `Vec<(ip, port)>` to `Vec<(Vec<Ip>, port)>`
 		let mut services = HashMap::<u16, ServiceInfo>::new();
@@ -106,7 +188,7 @@ where
 					&format!("{}.", self.peer_id),
 					*addr.ip(),
 					addr.port(),
-					Some(metadata.clone()), // TODO: Prevent the user defining a value that overflows a DNS record
+					Some(metadata.clone()),
 				) {
 					Ok(service) => service,
 					Err(err) => {
@@ -130,11 +212,36 @@ where
 			Box::pin(sleep_until(Instant::now() + MDNS_READVERTISEMENT_INTERVAL));
 	}
 
+	/// expire_peer removes the first discovered peer which hasn't advertised itself within the TTL and returns its `PeerExpired` event.
+	/// The peer may still be connected or dialable by address, it just isn't advertising on the local network anymore.
+	async fn expire_peer(&mut self) -> Option<Event<TMetadata>> {
+		let now = Instant::now();
+		let Some(peer_id) = find_expired(&self.last_seen, now, self.ttl) else {
+			self.next_expiry_check = Box::pin(sleep_until(now + EXPIRY_CHECK_INTERVAL));
+			return None;
+		};
+
+		// Check again straight away in case other peers have also expired
+		self.next_expiry_check = Box::pin(sleep_until(now));
+		self.last_seen.remove(&peer_id);
+		debug!(
+			"peer '{}' expired after not advertising for {:?}",
+			peer_id, self.ttl
+		);
+
+		let peer = self.state.discovered.write().await.remove(&peer_id);
+		Some(Event::PeerExpired {
+			id: peer_id,
+			metadata: peer.map(|p| p.metadata),
+		})
+	}
+
 	// TODO: if the channel's sender is dropped will this cause the `tokio::select` in the `manager.rs` to infinitely loop?
 	pub async fn poll(&mut self, manager: &Arc<Manager<TMetadata>>) -> Option<Event<TMetadata>> {
 		tokio::select! {
-			_ = &mut self.next_mdns_advertisement => self.advertise().await,
-			event = self.mdns_service_receiver.recv_async() => {
+			_ = &mut self.next_mdns_advertisement, if self.mdns_service_receiver.is_some() => self.advertise().await,
+			_ = &mut self.next_expiry_check, if self.mdns_service_receiver.is_some() => return self.expire_peer().await,
+			event = next_service_event(&self.mdns_service_receiver) => {
 				let event = event.unwrap(); // TODO: Error handling
 				match event {
 					ServiceEvent::SearchStarted(_) => {}
@@ -151,43 +258,48 @@ where
 									return None;
 								}
 
-								match TMetadata::from_hashmap(
-									&info
-										.get_properties()
-										.iter()
-										.map(|v| (v.key().to_owned(), v.val().to_owned()))
-										.collect(),
-								) {
+								let properties = info
+									.get_properties()
+									.iter()
+									.map(|v| (v.key().to_owned(), v.val().to_owned()))
+									.collect::<HashMap<_, _>>();
+
+								match decode_metadata::<TMetadata>(&properties) {
 									Ok(metadata) => {
-										let peer = {
-											let mut discovered_peers =
-												self.state.discovered.write().await;
+										let mut discovered_peers =
+											self.state.discovered.write().await;
+										self.last_seen.insert(peer_id, Instant::now());
 
-											let peer = if let Some(peer) = discovered_peers.remove(&peer_id) {
+										let addresses = info
+											.get_addresses()
+											.iter()
+											.map(|addr| {
+												SocketAddr::new(IpAddr::V4(*addr), info.get_port())
+											})
+											.collect::<Vec<_>>();
 
-												peer
-											} else {
-												DiscoveredPeer {
+										match discovered_peers.get_mut(&peer_id) {
+											// The metadata is compared in its advertised form as `TMetadata` isn't required to implement `PartialEq`
+											Some(peer)
+												if peer.metadata.clone().to_hashmap()
+													!= metadata.clone().to_hashmap() =>
+											{
+												peer.metadata = metadata;
+												peer.addresses = addresses;
+												return Some(Event::PeerMetadataChanged(peer.clone()));
+											}
+											Some(peer) => return Some(Event::PeerDiscovered(peer.clone())),
+											None => {
+												let peer = DiscoveredPeer {
 													manager: manager.clone(),
 													peer_id,
 													metadata,
-													addresses: info
-														.get_addresses()
-														.iter()
-														.map(|addr| {
-															SocketAddr::new(
-																IpAddr::V4(*addr),
-																info.get_port(),
-															)
-														})
-														.collect(),
-												}
-											};
-
-											discovered_peers.insert(peer_id, peer.clone());
-											peer
-										};
-										return Some(Event::PeerDiscovered(peer));
+													addresses,
+												};
+												discovered_peers.insert(peer_id, peer.clone());
+												return Some(Event::PeerDiscovered(peer));
+											}
+										}
 									}
 									Err(err) => {
 										error!("error parsing metadata for peer '{}': {}", raw_peer_id, err)
@@ -214,6 +326,7 @@ where
 									let mut discovered_peers =
 										self.state.discovered.write().await;
 									let peer = discovered_peers.remove(&peer_id);
+									self.last_seen.remove(&peer_id);
 
 									return Some(Event::PeerExpired {
 										id: peer_id,
@@ -257,3 +370,49 @@ where
 		}
 	}
 }
+
+/// find_expired returns a peer which hasn't advertised itself within `ttl` of `now`. A peer which readvertises is reinserted into `last_seen` so it's never expired while it's still advertising.
+fn find_expired(
+	last_seen: &HashMap<PeerId, Instant>,
+	now: Instant,
+	ttl: Duration,
+) -> Option<PeerId> {
+	last_seen
+		.iter()
+		.find(|(_, last_seen)| now.duration_since(**last_seen) >= ttl)
+		.map(|(peer_id, _)| *peer_id)
+}
+
+/// waits for the next mDNS event. This will never resolve while discovery is disabled.
+async fn next_service_event(
+	receiver: &Option<flume::Receiver<ServiceEvent>>,
+) -> Result<ServiceEvent, flume::RecvError> {
+	match receiver {
+		Some(receiver) => receiver.recv_async().await,
+		None => std::future::pending().await,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_find_expired() {
+		let ttl = Duration::from_secs(60);
+		let a = PeerId::from_str("12D3KooW9tHTtS3inCZiYykw4u5G4frbjVFqhkmJX12gSNCVeH3e").unwrap();
+		let b = PeerId::from_str("12D3KooW9xCm2jWjNVrwh51SWCQBMYdMyeU3NpT85QhLVkF6PcNM").unwrap();
+
+		let start = Instant::now();
+		let mut last_seen = HashMap::from([(a, start), (b, start)]);
+		assert_eq!(find_expired(&last_seen, start + ttl / 2, ttl), None);
+
+		// `a` reappears before it expires so only `b` is reported as gone
+		last_seen.insert(a, start + ttl / 2);
+		assert_eq!(find_expired(&last_seen, start + ttl, ttl), Some(b));
+
+		last_seen.remove(&b);
+		assert_eq!(find_expired(&last_seen, start + ttl, ttl), None);
+		assert_eq!(find_expired(&last_seen, start + ttl * 3 / 2, ttl), Some(a));
+	}
+}
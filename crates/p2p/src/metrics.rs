@@ -0,0 +1,221 @@
+use std::{
+	collections::HashMap,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc, PoisonError, RwLock,
+	},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::PeerId;
+
+/// PeerStats is a snapshot of the traffic with a peer.
+/// A message is a single broadcast or unicast stream so the data sent over a long lived unicast stream is only counted as one message.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerStats {
+	pub bytes_sent: u64,
+	pub bytes_received: u64,
+	pub messages_sent: u64,
+	pub messages_received: u64,
+	/// the number of the messages which were broadcasts. The rest were unicast streams.
+	pub broadcasts_sent: u64,
+	pub broadcasts_received: u64,
+	/// the last time data was sent to or received from the peer. `None` if nothing has ever been exchanged.
+	pub last_activity: Option<SystemTime>,
+}
+
+impl PeerStats {
+	fn merge(mut self, other: Self) -> Self {
+		self.bytes_sent += other.bytes_sent;
+		self.bytes_received += other.bytes_received;
+		self.messages_sent += other.messages_sent;
+		self.messages_received += other.messages_received;
+		self.broadcasts_sent += other.broadcasts_sent;
+		self.broadcasts_received += other.broadcasts_received;
+		self.last_activity = self.last_activity.max(other.last_activity);
+		self
+	}
+}
+
+/// ManagerStats is a snapshot of the traffic with every peer which has been connected to since the manager was started, see [crate::Manager::stats].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ManagerStats {
+	/// the sum of the traffic with every peer.
+	pub totals: PeerStats,
+	pub peers: HashMap<PeerId, PeerStats>,
+}
+
+/// the kinds of message which are counted separately in [PeerStats].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MessageKind {
+	Broadcast,
+	Unicast,
+}
+
+/// PeerMetrics holds the counters for a single peer. These are updated by the streams as data is read and written so they use atomics to avoid locking on the hot path.
+#[derive(Debug, Default)]
+pub(crate) struct PeerMetrics {
+	bytes_sent: AtomicU64,
+	bytes_received: AtomicU64,
+	messages_sent: AtomicU64,
+	messages_received: AtomicU64,
+	broadcasts_sent: AtomicU64,
+	broadcasts_received: AtomicU64,
+	/// milliseconds since the unix epoch. `0` if there hasn't been any activity.
+	last_activity: AtomicU64,
+}
+
+impl PeerMetrics {
+	pub(crate) fn record_sent(&self, bytes: usize) {
+		self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+		self.touch();
+	}
+
+	pub(crate) fn record_received(&self, bytes: usize) {
+		self.bytes_received
+			.fetch_add(bytes as u64, Ordering::Relaxed);
+		self.touch();
+	}
+
+	pub(crate) fn record_message_sent(&self, kind: MessageKind) {
+		self.messages_sent.fetch_add(1, Ordering::Relaxed);
+		if kind == MessageKind::Broadcast {
+			self.broadcasts_sent.fetch_add(1, Ordering::Relaxed);
+		}
+		self.touch();
+	}
+
+	pub(crate) fn record_message_received(&self, kind: MessageKind) {
+		self.messages_received.fetch_add(1, Ordering::Relaxed);
+		if kind == MessageKind::Broadcast {
+			self.broadcasts_received.fetch_add(1, Ordering::Relaxed);
+		}
+		self.touch();
+	}
+
+	fn touch(&self) {
+		let now = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default()
+			.as_millis() as u64;
+		self.last_activity.fetch_max(now, Ordering::Relaxed);
+	}
+
+	pub(crate) fn stats(&self) -> PeerStats {
+		let last_activity = self.last_activity.load(Ordering::Relaxed);
+
+		PeerStats {
+			bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+			bytes_received: self.bytes_received.load(Ordering::Relaxed),
+			messages_sent: self.messages_sent.load(Ordering::Relaxed),
+			messages_received: self.messages_received.load(Ordering::Relaxed),
+			broadcasts_sent: self.broadcasts_sent.load(Ordering::Relaxed),
+			broadcasts_received: self.broadcasts_received.load(Ordering::Relaxed),
+			last_activity: (last_activity != 0)
+				.then(|| UNIX_EPOCH + Duration::from_millis(last_activity)),
+		}
+	}
+}
+
+/// Metrics holds the [PeerMetrics] of every peer which has been connected to.
+/// Peers are never removed so their counters survive reconnects. The lock is only taken when a connection is established or the stats are read.
+#[derive(Debug, Default)]
+pub(crate) struct Metrics(RwLock<HashMap<PeerId, Arc<PeerMetrics>>>);
+
+impl Metrics {
+	/// get the counters for a peer, creating them if it's the first time the peer has been seen.
+	pub(crate) fn peer(&self, peer_id: PeerId) -> Arc<PeerMetrics> {
+		if let Some(metrics) = self
+			.0
+			.read()
+			.unwrap_or_else(PoisonError::into_inner)
+			.get(&peer_id)
+		{
+			return metrics.clone();
+		}
+
+		self.0
+			.write()
+			.unwrap_or_else(PoisonError::into_inner)
+			.entry(peer_id)
+			.or_default()
+			.clone()
+	}
+
+	pub(crate) fn stats(&self, peer_id: &PeerId) -> PeerStats {
+		self.0
+			.read()
+			.unwrap_or_else(PoisonError::into_inner)
+			.get(peer_id)
+			.map(|metrics| metrics.stats())
+			.unwrap_or_default()
+	}
+
+	pub(crate) fn totals(&self) -> PeerStats {
+		self.0
+			.read()
+			.unwrap_or_else(PoisonError::into_inner)
+			.values()
+			.map(|metrics| metrics.stats())
+			.fold(PeerStats::default(), PeerStats::merge)
+	}
+
+	/// snapshot reads the counters of every peer at once so the totals match the peers.
+	pub(crate) fn snapshot(&self) -> ManagerStats {
+		let peers = self
+			.0
+			.read()
+			.unwrap_or_else(PoisonError::into_inner)
+			.iter()
+			.map(|(peer_id, metrics)| (*peer_id, metrics.stats()))
+			.collect::<HashMap<_, _>>();
+
+		ManagerStats {
+			totals: peers
+				.values()
+				.copied()
+				.fold(PeerStats::default(), PeerStats::merge),
+			peers,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::str::FromStr;
+
+	use super::*;
+
+	#[test]
+	fn test_snapshot() {
+		let metrics = Metrics::default();
+		let [a, b] = [
+			"12D3KooW9tHTtS3inCZiYykw4u5G4frbjVFqhkmJX12gSNCVeH3e",
+			"12D3KooW9xCm2jWjNVrwh51SWCQBMYdMyeU3NpT85QhLVkF6PcNM",
+		]
+		.map(|peer_id| PeerId::from_str(peer_id).unwrap());
+
+		let peer = metrics.peer(a);
+		peer.record_message_sent(MessageKind::Broadcast);
+		peer.record_sent(100);
+		peer.record_message_received(MessageKind::Unicast);
+		peer.record_received(10);
+		let peer = metrics.peer(b);
+		peer.record_message_sent(MessageKind::Unicast);
+		peer.record_sent(50);
+
+		let stats = metrics.snapshot();
+		assert_eq!(stats.peers.len(), 2);
+		assert_eq!(stats.peers[&a].broadcasts_sent, 1);
+		assert_eq!(stats.peers[&a].messages_received, 1);
+		assert_eq!(stats.peers[&a].broadcasts_received, 0);
+		assert_eq!(stats.peers[&b], metrics.stats(&b));
+
+		assert_eq!(stats.totals, metrics.totals());
+		assert_eq!(stats.totals.bytes_sent, 150);
+		assert_eq!(stats.totals.bytes_received, 10);
+		assert_eq!(stats.totals.messages_sent, 2);
+		assert_eq!(stats.totals.broadcasts_sent, 1);
+		assert!(stats.totals.last_activity.is_some());
+	}
+}
@@ -1,6 +1,6 @@
 use std::{net::SocketAddr, sync::Arc};
 
-use crate::{Manager, ManagerStreamAction, Metadata, PeerId};
+use crate::{order_addresses, DialError, Manager, Metadata, PeerId, Transport};
 
 /// Represents a discovered peer.
 /// This is held by [Manager] to keep track of discovered peers
@@ -19,14 +19,11 @@ pub struct DiscoveredPeer<TMetadata: Metadata> {
 }
 
 impl<TMetadata: Metadata> DiscoveredPeer<TMetadata> {
-	/// dial will queue an event to start a connection with the peer
-	pub async fn dial(self) {
+	/// dial will start a connection with the peer and wait for it to be established. Its addresses are tried in the order returned by [order_addresses].
+	pub async fn dial(self) -> Result<(), DialError> {
 		self.manager
-			.emit(ManagerStreamAction::Dial {
-				peer_id: self.peer_id,
-				addresses: self.addresses,
-			})
-			.await;
+			.dial(self.peer_id, order_addresses(&[], self.addresses))
+			.await
 	}
 }
 
@@ -38,4 +35,10 @@ impl<TMetadata: Metadata> DiscoveredPeer<TMetadata> {
 pub struct ConnectedPeer {
 	/// get the peer id of the discovered peer
 	pub peer_id: PeerId,
+	/// get the address of the remote peer for the connection. This will be `None` if it's not a valid address for any [Transport].
+	pub address: Option<SocketAddr>,
+	/// get the transport the connection was established with. For a relayed connection this is the transport of the connection with the relay.
+	pub transport: Option<Transport>,
+	/// is the connection through a relay, see [crate::ManagerConfig::relays]. The `address` of a relayed connection is always `None` as only the relay's address is known.
+	pub relayed: bool,
 }
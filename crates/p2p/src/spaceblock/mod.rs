@@ -8,7 +8,7 @@ use std::{
 	path::{Path, PathBuf},
 };
 
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 use crate::spacetime::{SpaceTimeStream, UnicastStream};
 
@@ -35,7 +35,7 @@ pub struct TransferRequest {
 }
 
 impl TransferRequest {
-	pub async fn from_stream(stream: &mut UnicastStream) -> Result<Self, ()> {
+	pub async fn from_stream(stream: &mut (impl AsyncRead + Unpin)) -> Result<Self, ()> {
 		let name_len = stream.read_u8().await.map_err(|_| ())?; // TODO: Error handling
 		let mut name = vec![0u8; name_len as usize];
 		stream.read_exact(&mut name).await.map_err(|_| ())?; // TODO: Error handling
@@ -1,5 +1,6 @@
 use std::{
-	collections::VecDeque,
+	collections::{HashMap, HashSet, VecDeque},
+	net::SocketAddr,
 	sync::Arc,
 	task::{Context, Poll},
 };
@@ -8,7 +9,7 @@ use libp2p::{
 	core::{ConnectedPoint, Endpoint},
 	swarm::{
 		derive_prelude::{ConnectionEstablished, ConnectionId, FromSwarm},
-		ConnectionClosed, ConnectionDenied, ConnectionHandler, NetworkBehaviour,
+		CloseConnection, ConnectionClosed, ConnectionDenied, ConnectionHandler, NetworkBehaviour,
 		NetworkBehaviourAction, PollParameters, THandler, THandlerInEvent,
 	},
 	Multiaddr,
@@ -16,7 +17,10 @@ use libp2p::{
 use thiserror::Error;
 use tracing::debug;
 
-use crate::{ConnectedPeer, Event, Manager, ManagerStreamAction, Metadata, PeerId};
+use crate::{
+	multiaddr_to_socketaddr, ConnectedPeer, Event, Manager, ManagerStreamAction, Metadata, PeerId,
+	Transport,
+};
 
 use super::SpaceTimeConnection;
 
@@ -30,6 +34,16 @@ pub const EMPTY_QUEUE_SHRINK_THRESHOLD: usize = 100;
 #[derive(Debug, Error)]
 pub enum OutboundFailure {}
 
+/// the reason a connection with a peer on the blocklist is refused.
+#[derive(Debug, Error)]
+#[error("peer '{0}' is blocked")]
+pub struct PeerBlocked(PeerId);
+
+/// the reason a connection from an address a blocked peer recently connected from is refused.
+#[derive(Debug, Error)]
+#[error("address '{0}' was recently used by a blocked peer")]
+pub struct RefusedAddr(SocketAddr);
+
 /// SpaceTime is a [`NetworkBehaviour`](libp2p::NetworkBehaviour) that implements the SpaceTime protocol.
 /// This protocol sits under the application to abstract many complexities of 2 way connections and deals with authentication, chucking, etc.
 pub struct SpaceTime<TMetadata: Metadata> {
@@ -39,6 +53,8 @@ pub struct SpaceTime<TMetadata: Metadata> {
 	>,
 	// For future me's sake, DON't try and refactor this to use shared state (for the nth time), it doesn't fit into libp2p's synchronous trait and polling model!!!
 	// pub(crate) connected_peers: HashMap<PeerId, ConnectedPeer>,
+	/// the open connections with each peer which are through a relay. These are closed once a direct connection with the peer is established.
+	relayed_connections: HashMap<libp2p::PeerId, HashSet<ConnectionId>>,
 }
 
 impl<TMetadata: Metadata> SpaceTime<TMetadata> {
@@ -48,6 +64,7 @@ impl<TMetadata: Metadata> SpaceTime<TMetadata> {
 			manager,
 			pending_events: VecDeque::new(),
 			// connected_peers: HashMap::new(),
+			relayed_connections: HashMap::new(),
 		}
 	}
 }
@@ -56,17 +73,39 @@ impl<TMetadata: Metadata> NetworkBehaviour for SpaceTime<TMetadata> {
 	type ConnectionHandler = SpaceTimeConnection<TMetadata>;
 	type OutEvent = ManagerStreamAction<TMetadata>;
 
+	fn handle_pending_inbound_connection(
+		&mut self,
+		_connection_id: ConnectionId,
+		_local_addr: &Multiaddr,
+		remote_addr: &Multiaddr,
+	) -> Result<(), ConnectionDenied> {
+		if let Ok(addr) = multiaddr_to_socketaddr(remote_addr.clone()) {
+			if self.manager.blocklist.is_addr_refused(&addr) {
+				debug!("refusing inbound connection from '{addr}' which a blocked peer recently connected from");
+				return Err(ConnectionDenied::new(RefusedAddr(addr)));
+			}
+		}
+
+		Ok(())
+	}
+
 	fn handle_established_inbound_connection(
 		&mut self,
 		_connection_id: ConnectionId,
 		peer_id: libp2p::PeerId,
 		_local_addr: &Multiaddr,
-		_remote_addr: &Multiaddr,
+		remote_addr: &Multiaddr,
 	) -> Result<THandler<Self>, ConnectionDenied> {
-		Ok(SpaceTimeConnection::new(
-			PeerId(peer_id),
-			self.manager.clone(),
-		))
+		let peer_id = PeerId(peer_id);
+		if self.manager.blocklist.is_blocked(&peer_id) {
+			debug!("refusing inbound connection from blocked peer '{peer_id}'");
+			if let Ok(addr) = multiaddr_to_socketaddr(remote_addr.clone()) {
+				self.manager.blocklist.refuse_addr(addr, peer_id);
+			}
+			return Err(ConnectionDenied::new(PeerBlocked(peer_id)));
+		}
+
+		Ok(SpaceTimeConnection::new(peer_id, self.manager.clone()))
 	}
 
 	fn handle_pending_outbound_connection(
@@ -87,16 +126,20 @@ impl<TMetadata: Metadata> NetworkBehaviour for SpaceTime<TMetadata> {
 		_addr: &Multiaddr,
 		_role_override: Endpoint,
 	) -> Result<THandler<Self>, ConnectionDenied> {
-		Ok(SpaceTimeConnection::new(
-			PeerId(peer_id),
-			self.manager.clone(),
-		))
+		let peer_id = PeerId(peer_id);
+		if self.manager.blocklist.is_blocked(&peer_id) {
+			debug!("refusing outbound connection to blocked peer '{peer_id}'");
+			return Err(ConnectionDenied::new(PeerBlocked(peer_id)));
+		}
+
+		Ok(SpaceTimeConnection::new(peer_id, self.manager.clone()))
 	}
 
 	fn on_swarm_event(&mut self, event: FromSwarm<Self::ConnectionHandler>) {
 		match event {
 			FromSwarm::ConnectionEstablished(ConnectionEstablished {
 				peer_id,
+				connection_id,
 				endpoint,
 				other_established,
 				..
@@ -105,12 +148,52 @@ impl<TMetadata: Metadata> NetworkBehaviour for SpaceTime<TMetadata> {
 					ConnectedPoint::Dialer { address, .. } => Some(address.clone()),
 					ConnectedPoint::Listener { .. } => None,
 				};
+				let remote_address = match endpoint {
+					ConnectedPoint::Dialer { address, .. } => address,
+					ConnectedPoint::Listener { send_back_addr, .. } => send_back_addr,
+				};
+				let relayed = endpoint.is_relayed();
 				debug!(
-					"connection established with peer '{}' found at '{:?}'; peer has {} active connections",
-					peer_id, address, other_established
+					"connection established with peer '{}' found at '{:?}' (relayed: {}); peer has {} active connections",
+					peer_id, address, relayed, other_established
 				);
 
+				let libp2p_peer_id = peer_id;
 				let peer_id = PeerId(peer_id);
+				let connected_peer = ConnectedPeer {
+					peer_id,
+					address: (!relayed)
+						.then(|| multiaddr_to_socketaddr(remote_address.clone()).ok())
+						.flatten(),
+					transport: Transport::of(remote_address),
+					relayed,
+				};
+
+				if relayed {
+					self.relayed_connections
+						.entry(libp2p_peer_id)
+						.or_default()
+						.insert(connection_id);
+				} else if let Some(connections) = self.relayed_connections.remove(&libp2p_peer_id) {
+					// The direct connection is used from now on so the relayed connections are closed before they hit the relay's limits
+					debug!(
+						"relayed connection with peer '{}' upgraded to a direct connection",
+						peer_id
+					);
+					for connection in connections {
+						self.pending_events
+							.push_back(NetworkBehaviourAction::CloseConnection {
+								peer_id: libp2p_peer_id,
+								connection: CloseConnection::One(connection),
+							});
+					}
+					self.pending_events
+						.push_back(NetworkBehaviourAction::GenerateEvent(
+							ManagerStreamAction::Event(Event::PeerConnectionUpgraded(
+								connected_peer.clone(),
+							)),
+						));
+				}
 
 				// TODO: Move this block onto into `connection.rs` -> will probs be required for the ConnectionEstablishmentPayload stuff
 				{
@@ -118,18 +201,24 @@ impl<TMetadata: Metadata> NetworkBehaviour for SpaceTime<TMetadata> {
 					if other_established == 0 {
 						self.pending_events
 							.push_back(NetworkBehaviourAction::GenerateEvent(
-								ManagerStreamAction::Event(Event::PeerConnected(ConnectedPeer {
-									peer_id,
-								})),
+								ManagerStreamAction::Event(Event::PeerConnected(connected_peer)),
 							));
 					}
 				}
 			}
 			FromSwarm::ConnectionClosed(ConnectionClosed {
 				peer_id,
+				connection_id,
 				remaining_established,
 				..
 			}) => {
+				if let Some(connections) = self.relayed_connections.get_mut(&peer_id) {
+					connections.remove(&connection_id);
+					if connections.is_empty() {
+						self.relayed_connections.remove(&peer_id);
+					}
+				}
+
 				let peer_id = PeerId(peer_id);
 				if remaining_established == 0 {
 					debug!("Disconnected from peer '{}'", peer_id);
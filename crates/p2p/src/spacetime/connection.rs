@@ -1,9 +1,12 @@
-use libp2p::swarm::{
-	handler::{
-		ConnectionEvent, ConnectionHandler, ConnectionHandlerEvent, ConnectionHandlerUpgrErr,
-		FullyNegotiatedInbound, KeepAlive,
+use libp2p::{
+	core::upgrade::{NegotiationError, UpgradeError},
+	swarm::{
+		handler::{
+			ConnectionEvent, ConnectionHandler, ConnectionHandlerEvent, ConnectionHandlerUpgrErr,
+			FullyNegotiatedInbound, KeepAlive,
+		},
+		SubstreamProtocol,
 	},
-	SubstreamProtocol,
 };
 use std::{
 	collections::VecDeque,
@@ -12,9 +15,9 @@ use std::{
 	task::{Context, Poll},
 	time::Duration,
 };
-use tracing::error;
+use tracing::{error, warn};
 
-use crate::{Manager, ManagerStreamAction, Metadata, PeerId};
+use crate::{Manager, ManagerStreamAction, Metadata, PeerId, PeerMetrics};
 
 use super::{InboundProtocol, OutboundProtocol, OutboundRequest, EMPTY_QUEUE_SHRINK_THRESHOLD};
 
@@ -25,6 +28,8 @@ const SUBSTREAM_TIMEOUT: Duration = Duration::from_secs(10); // TODO: Tune value
 pub struct SpaceTimeConnection<TMetadata: Metadata> {
 	peer_id: PeerId,
 	manager: Arc<Manager<TMetadata>>,
+	metrics: Arc<PeerMetrics>,
+	keep_alive: KeepAlive,
 	pending_events: VecDeque<
 		ConnectionHandlerEvent<
 			OutboundProtocol,
@@ -39,10 +44,29 @@ impl<TMetadata: Metadata> SpaceTimeConnection<TMetadata> {
 	pub(super) fn new(peer_id: PeerId, manager: Arc<Manager<TMetadata>>) -> Self {
 		Self {
 			peer_id,
+			metrics: manager.metrics.peer(peer_id),
 			manager,
+			keep_alive: KeepAlive::Yes,
 			pending_events: VecDeque::new(),
 		}
 	}
+
+	/// the peer doesn't "speak the same language" (eg. it's using a different application name) so the connection is closed.
+	fn reject_peer(&mut self) {
+		warn!(
+			"closing connection with peer '{}' as it doesn't support the protocol '{}'",
+			self.peer_id,
+			String::from_utf8_lossy(self.manager.application_name)
+		);
+		self.keep_alive = KeepAlive::No;
+	}
+}
+
+fn is_protocol_mismatch<E>(err: &ConnectionHandlerUpgrErr<E>) -> bool {
+	matches!(
+		err,
+		ConnectionHandlerUpgrErr::Upgrade(UpgradeError::Select(NegotiationError::Failed))
+	)
 }
 
 // pub enum Connection
@@ -61,6 +85,7 @@ impl<TMetadata: Metadata> ConnectionHandler for SpaceTimeConnection<TMetadata> {
 			InboundProtocol {
 				peer_id: self.peer_id,
 				manager: self.manager.clone(),
+				metrics: self.metrics.clone(),
 			},
 			(),
 		)
@@ -75,7 +100,7 @@ impl<TMetadata: Metadata> ConnectionHandler for SpaceTimeConnection<TMetadata> {
 		self.pending_events
 			.push_back(ConnectionHandlerEvent::OutboundSubstreamRequest {
 				protocol: SubstreamProtocol::new(
-					OutboundProtocol(self.manager.application_name, req),
+					OutboundProtocol(self.manager.application_name, req, self.metrics.clone()),
 					(),
 				) // TODO: Use `info` here maybe to pass into about the client. Idk?
 				.with_timeout(SUBSTREAM_TIMEOUT),
@@ -83,7 +108,7 @@ impl<TMetadata: Metadata> ConnectionHandler for SpaceTimeConnection<TMetadata> {
 	}
 
 	fn connection_keep_alive(&self) -> KeepAlive {
-		KeepAlive::Yes // TODO: Make this work how the old one did with storing it on `self` and updating on events
+		self.keep_alive // TODO: Make this work how the old one did by updating it based on the open streams
 	}
 
 	fn poll(
@@ -125,12 +150,18 @@ impl<TMetadata: Metadata> ConnectionHandler for SpaceTimeConnection<TMetadata> {
 			}
 			ConnectionEvent::FullyNegotiatedOutbound(_) => {}
 			ConnectionEvent::DialUpgradeError(event) => {
-				error!("DialUpgradeError: {:#?}", event.error);
+				if is_protocol_mismatch(&event.error) {
+					self.reject_peer();
+				} else {
+					error!("DialUpgradeError: {:#?}", event.error);
+				}
 			}
 			ConnectionEvent::ListenUpgradeError(event) => {
-				error!("DialUpgradeError: {:#?}", event.error);
-
-				// TODO: If `event.error` close connection cause we don't "speak the same language"!
+				if is_protocol_mismatch(&event.error) {
+					self.reject_peer();
+				} else {
+					error!("ListenUpgradeError: {:#?}", event.error);
+				}
 			}
 			ConnectionEvent::AddressChange(_) => {
 				// TODO: Should we be telling `SpaceTime` to update it's info here or is it also getting this event?
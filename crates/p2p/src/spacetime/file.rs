@@ -0,0 +1,177 @@
+use std::io;
+
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::UnicastStream;
+
+/// the size of the buffer a file is read into and written from. This bounds the memory used by a transfer regardless of the size of the file.
+pub const FILE_CHUNK_SIZE: usize = 64 * 1024; // 64 KiB
+
+#[derive(Debug, Error)]
+pub enum FileTransferError {
+	#[error("io error transferring file: {0}")]
+	Io(#[from] io::Error),
+	#[error("the file is {len} bytes which is larger than the maximum of {max} bytes")]
+	TooLarge { len: u64, max: u64 },
+	#[error("the file ended after {transferred} of {len} bytes")]
+	UnexpectedEof { transferred: u64, len: u64 },
+}
+
+impl UnicastStream {
+	/// send_file writes `len` bytes from `reader` to the peer, which should receive it with [UnicastStream::recv_file].
+	/// `on_progress` is called with the total number of bytes sent after each chunk. Each chunk is only read once the previous one has been written so a slow peer slows down reading.
+	pub async fn send_file(
+		&mut self,
+		reader: impl AsyncRead + Unpin,
+		len: u64,
+		on_progress: impl FnMut(u64),
+	) -> Result<(), FileTransferError> {
+		send_file(self, reader, len, on_progress).await
+	}
+
+	/// recv_file reads a file sent with [UnicastStream::send_file] into `writer` and returns its length.
+	/// Files larger than `max_size` are rejected before any of it is read. `on_progress` is called with the total number of bytes received after each chunk.
+	pub async fn recv_file(
+		&mut self,
+		writer: impl AsyncWrite + Unpin,
+		max_size: u64,
+		on_progress: impl FnMut(u64),
+	) -> Result<u64, FileTransferError> {
+		recv_file(self, writer, max_size, on_progress).await
+	}
+}
+
+/// A file is framed as its length as a u64 little endian followed by exactly that many bytes.
+async fn send_file(
+	stream: &mut (impl AsyncWrite + Unpin),
+	mut reader: impl AsyncRead + Unpin,
+	len: u64,
+	mut on_progress: impl FnMut(u64),
+) -> Result<(), FileTransferError> {
+	stream.write_u64_le(len).await?;
+
+	let mut buf = vec![0u8; FILE_CHUNK_SIZE];
+	let mut sent = 0;
+	while sent < len {
+		let max =
+			usize::try_from(len - sent).map_or(buf.len(), |remaining| remaining.min(buf.len()));
+		let n = reader.read(&mut buf[..max]).await?;
+		if n == 0 {
+			// The peer is expecting `len` bytes so the transfer can't be finished
+			return Err(FileTransferError::UnexpectedEof {
+				transferred: sent,
+				len,
+			});
+		}
+
+		stream.write_all(&buf[..n]).await?;
+		sent += n as u64;
+		on_progress(sent);
+	}
+
+	stream.flush().await?;
+	Ok(())
+}
+
+async fn recv_file(
+	stream: &mut (impl AsyncRead + Unpin),
+	mut writer: impl AsyncWrite + Unpin,
+	max_size: u64,
+	mut on_progress: impl FnMut(u64),
+) -> Result<u64, FileTransferError> {
+	let len = stream.read_u64_le().await?;
+	if len > max_size {
+		return Err(FileTransferError::TooLarge { len, max: max_size });
+	}
+
+	let mut buf = vec![0u8; FILE_CHUNK_SIZE];
+	let mut received = 0;
+	while received < len {
+		let max =
+			usize::try_from(len - received).map_or(buf.len(), |remaining| remaining.min(buf.len()));
+		let n = stream.read(&mut buf[..max]).await?;
+		if n == 0 {
+			return Err(FileTransferError::UnexpectedEof {
+				transferred: received,
+				len,
+			});
+		}
+
+		writer.write_all(&buf[..n]).await?;
+		received += n as u64;
+		on_progress(received);
+	}
+
+	writer.flush().await?;
+	Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::io::duplex;
+
+	use super::*;
+
+	#[tokio::test]
+	async fn test_file_larger_than_buffer() {
+		let data = (0..3 * FILE_CHUNK_SIZE + 42)
+			.map(|i| i as u8)
+			.collect::<Vec<_>>();
+		// A small pipe means the sender has to wait for the receiver to catch up
+		let (mut tx, mut rx) = duplex(1024);
+
+		let mut sent_progress = Vec::new();
+		let mut received_progress = Vec::new();
+		let mut received = Vec::new();
+		let (sent, len) = tokio::join!(
+			send_file(&mut tx, &data[..], data.len() as u64, |n| sent_progress
+				.push(n)),
+			recv_file(&mut rx, &mut received, u64::MAX, |n| received_progress
+				.push(n)),
+		);
+
+		sent.unwrap();
+		assert_eq!(len.unwrap(), data.len() as u64);
+		assert_eq!(received, data);
+		assert_eq!(sent_progress.last(), Some(&(data.len() as u64)));
+		assert_eq!(received_progress.last(), Some(&(data.len() as u64)));
+		assert!(received_progress.windows(2).all(|w| w[0] < w[1]));
+	}
+
+	#[tokio::test]
+	async fn test_file_too_large() {
+		let mut buf = Vec::new();
+		send_file(&mut buf, &[0u8; 100][..], 100, |_| {})
+			.await
+			.unwrap();
+
+		let mut received = Vec::new();
+		assert!(matches!(
+			recv_file(&mut &buf[..], &mut received, 99, |_| {}).await,
+			Err(FileTransferError::TooLarge { len: 100, max: 99 })
+		));
+		assert!(received.is_empty());
+	}
+
+	#[tokio::test]
+	async fn test_file_unexpected_eof() {
+		let mut buf = Vec::new();
+		assert!(matches!(
+			send_file(&mut buf, &[0u8; 10][..], 20, |_| {}).await,
+			Err(FileTransferError::UnexpectedEof {
+				transferred: 10,
+				len: 20
+			})
+		));
+
+		// The receiver sees the stream end before the whole file was sent
+		assert!(matches!(
+			recv_file(&mut &buf[..], Vec::new(), u64::MAX, |_| {}).await,
+			Err(FileTransferError::UnexpectedEof {
+				transferred: 10,
+				len: 20
+			})
+		));
+	}
+}
@@ -3,6 +3,7 @@
 
 mod behaviour;
 mod connection;
+mod file;
 mod libp2p;
 mod proto_inbound;
 mod proto_outbound;
@@ -11,6 +12,7 @@ mod stream;
 pub use self::libp2p::*;
 pub use behaviour::*;
 pub use connection::*;
+pub use file::*;
 pub use proto_inbound::*;
 pub use proto_outbound::*;
 pub use stream::*;
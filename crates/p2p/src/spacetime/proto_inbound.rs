@@ -3,13 +3,14 @@ use std::{future::Future, pin::Pin, sync::Arc};
 use libp2p::{core::UpgradeInfo, swarm::NegotiatedSubstream, InboundUpgrade};
 use tracing::debug;
 
-use crate::{Manager, ManagerStreamAction, Metadata, PeerId, PeerMessageEvent};
+use crate::{Manager, ManagerStreamAction, Metadata, PeerId, PeerMessageEvent, PeerMetrics};
 
 use super::{SpaceTimeProtocolName, SpaceTimeStream};
 
 pub struct InboundProtocol<TMetadata: Metadata> {
 	pub(crate) peer_id: PeerId,
 	pub(crate) manager: Arc<Manager<TMetadata>>,
+	pub(crate) metrics: Arc<PeerMetrics>,
 }
 
 impl<TMetadata: Metadata> UpgradeInfo for InboundProtocol<TMetadata> {
@@ -29,11 +30,20 @@ impl<TMetadata: Metadata> InboundUpgrade<NegotiatedSubstream> for InboundProtoco
 	fn upgrade_inbound(self, io: NegotiatedSubstream, _: Self::Info) -> Self::Future {
 		Box::pin(async move {
 			let id = 1; // TODO
+			   // The connection should have already been refused but the peer may have been blocked since it was established.
+			if self.manager.is_blocked(&self.peer_id) {
+				debug!(
+					"stream({}, {id}): refusing inbound stream from blocked peer",
+					self.peer_id
+				);
+				return Err(());
+			}
+
 			debug!(
 				"stream({}, {id}): accepting inbound connection",
 				self.peer_id
 			);
-			let stream = SpaceTimeStream::from_stream(io).await;
+			let stream = SpaceTimeStream::from_stream(io, self.metrics).await;
 			debug!(
 				"stream({}, {id}): stream of type {} accepted",
 				self.peer_id,
@@ -1,6 +1,7 @@
 use std::{
 	future::{ready, Ready},
 	io::ErrorKind,
+	sync::Arc,
 };
 
 use libp2p::{
@@ -12,7 +13,9 @@ use libp2p::{
 use tokio::sync::oneshot;
 use tracing::error;
 
-use super::{SpaceTimeProtocolName, UnicastStream, BROADCAST_DISCRIMINATOR};
+use crate::{MessageKind, PeerMetrics};
+
+use super::{broadcast_header, SpaceTimeProtocolName, UnicastStream};
 
 #[derive(Debug)]
 pub enum OutboundRequest {
@@ -20,7 +23,11 @@ pub enum OutboundRequest {
 	Unicast(oneshot::Sender<UnicastStream>),
 }
 
-pub struct OutboundProtocol(pub(crate) &'static [u8], pub(crate) OutboundRequest);
+pub struct OutboundProtocol(
+	pub(crate) &'static [u8],
+	pub(crate) OutboundRequest,
+	pub(crate) Arc<PeerMetrics>,
+);
 
 impl UpgradeInfo for OutboundProtocol {
 	type Info = SpaceTimeProtocolName;
@@ -37,13 +44,22 @@ impl OutboundUpgrade<NegotiatedSubstream> for OutboundProtocol {
 	type Future = Ready<Result<(), ()>>;
 
 	fn upgrade_outbound(self, mut io: NegotiatedSubstream, _protocol: Self::Info) -> Self::Future {
+		let metrics = self.2;
 		match self.1 {
 			OutboundRequest::Broadcast(data) => {
 				tokio::spawn(async move {
-					io.write_all(&[BROADCAST_DISCRIMINATOR]).await.unwrap();
-					if let Err(err) = io.write_all(&data).await {
+					let Ok(len) = u32::try_from(data.len()) else {
+						error!("Broadcast of {} bytes is too large to send!", data.len());
+						return;
+					};
+
+					let header = broadcast_header(len);
+					io.write_all(&header).await.unwrap();
+					metrics.record_message_sent(MessageKind::Broadcast);
+					match io.write_all(&data).await {
+						Ok(_) => metrics.record_sent(header.len() + data.len()),
 						// TODO: Print the peer which we failed to send to here
-						error!("Error sending broadcast: {:?}", err);
+						Err(err) => error!("Error sending broadcast: {:?}", err),
 					}
 					io.flush().await.unwrap();
 
@@ -62,7 +78,8 @@ impl OutboundUpgrade<NegotiatedSubstream> for OutboundProtocol {
 			}
 			OutboundRequest::Unicast(sender) => {
 				// We write the discriminator to the stream in the `Manager::stream` method before returning the stream to the user to make async a tad nicer.
-				sender.send(UnicastStream::new(io)).unwrap();
+				metrics.record_message_sent(MessageKind::Unicast);
+				sender.send(UnicastStream::new(io, metrics)).unwrap();
 			}
 		}
 
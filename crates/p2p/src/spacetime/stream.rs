@@ -1,19 +1,36 @@
 use std::{
 	io::{self, ErrorKind},
 	pin::Pin,
+	sync::Arc,
 	task::{Context, Poll},
 };
 
 use libp2p::{futures::AsyncWriteExt, swarm::NegotiatedSubstream};
 use tokio::io::{
-	AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt as TokioAsyncWriteExt, ReadBuf,
+	AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt as TokioAsyncWriteExt, ReadBuf, Take,
 };
 use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt};
 use tracing::error;
 
+use crate::{MessageKind, PeerMetrics};
+
 pub const BROADCAST_DISCRIMINATOR: u8 = 0;
 pub const UNICAST_DISCRIMINATOR: u8 = 1;
 
+/// broadcast_header is written before the payload of a broadcast.
+/// The length is required because the sender keeps the stream open until the receiver has finished with it so the receiver can't wait for EOF.
+pub(crate) fn broadcast_header(len: u32) -> [u8; 5] {
+	let mut header = [BROADCAST_DISCRIMINATOR, 0, 0, 0, 0];
+	header[1..].copy_from_slice(&len.to_le_bytes());
+	header
+}
+
+/// read_broadcast will read the length of the broadcast (after the discriminator) and limit the reader to the payload.
+async fn read_broadcast<T: AsyncRead + Unpin>(mut io: T) -> io::Result<Take<T>> {
+	let len = io.read_u32_le().await?;
+	Ok(io.take(len as u64))
+}
+
 #[derive(Debug)]
 pub enum SpaceTimeStream {
 	Broadcast(BroadcastStream),
@@ -21,12 +38,21 @@ pub enum SpaceTimeStream {
 }
 
 impl SpaceTimeStream {
-	pub(crate) async fn from_stream(io: NegotiatedSubstream) -> Self {
+	pub(crate) async fn from_stream(io: NegotiatedSubstream, metrics: Arc<PeerMetrics>) -> Self {
 		let mut io = io.compat();
 		let discriminator = io.read_u8().await.unwrap(); // TODO: Timeout on this
+		metrics.record_received(1);
 		match discriminator {
-			BROADCAST_DISCRIMINATOR => Self::Broadcast(BroadcastStream(Some(io))),
-			UNICAST_DISCRIMINATOR => Self::Unicast(UnicastStream(io)),
+			BROADCAST_DISCRIMINATOR => {
+				metrics.record_message_received(MessageKind::Broadcast);
+				let io = read_broadcast(io).await.unwrap(); // TODO: Error handling
+				metrics.record_received(4);
+				Self::Broadcast(BroadcastStream(Some(io), metrics))
+			}
+			UNICAST_DISCRIMINATOR => {
+				metrics.record_message_received(MessageKind::Unicast);
+				Self::Unicast(UnicastStream(io, metrics))
+			}
 			_ => todo!(), // TODO: Error handling
 		}
 	}
@@ -42,7 +68,7 @@ impl SpaceTimeStream {
 		match self {
 			Self::Broadcast(mut stream) => {
 				if let Some(stream) = stream.0.take() {
-					BroadcastStream::close_inner(stream).await
+					BroadcastStream::close_inner(stream.into_inner()).await
 				} else if cfg!(debug_assertions) {
 					panic!("'BroadcastStream' should never be 'None' here!");
 				} else {
@@ -70,8 +96,9 @@ impl AsyncRead for SpaceTimeStream {
 
 /// A broadcast is a message sent to many peers in the network.
 /// Due to this it is not possible to respond to a broadcast.
+/// Reading will return EOF once the whole payload has been read so `read_to_end` can be used to receive it.
 #[derive(Debug)]
-pub struct BroadcastStream(Option<Compat<NegotiatedSubstream>>);
+pub struct BroadcastStream(Option<Take<Compat<NegotiatedSubstream>>>, Arc<PeerMetrics>);
 
 impl BroadcastStream {
 	async fn close_inner(mut io: Compat<NegotiatedSubstream>) -> Result<(), io::Error> {
@@ -92,7 +119,13 @@ impl AsyncRead for BroadcastStream {
 		cx: &mut Context<'_>,
 		buf: &mut ReadBuf<'_>,
 	) -> Poll<io::Result<()>> {
-		Pin::new(&mut self.get_mut().0.as_mut().expect("'BroadcastStream' can only be 'None' if this method is called after 'Drop' which ain't happening!")).poll_read(cx, buf)
+		let this = self.get_mut();
+		let filled = buf.filled().len();
+		let result = Pin::new(&mut this.0.as_mut().expect("'BroadcastStream' can only be 'None' if this method is called after 'Drop' which ain't happening!")).poll_read(cx, buf);
+		if let Poll::Ready(Ok(())) = result {
+			this.1.record_received(buf.filled().len() - filled);
+		}
+		result
 	}
 }
 
@@ -101,7 +134,7 @@ impl Drop for BroadcastStream {
 		// This may be `None` if the user manually called `Self::close`
 		if let Some(stream) = self.0.take() {
 			tokio::spawn(async move {
-				Self::close_inner(stream).await.unwrap();
+				Self::close_inner(stream.into_inner()).await.unwrap();
 			});
 		}
 	}
@@ -109,18 +142,20 @@ impl Drop for BroadcastStream {
 
 /// A unicast stream is a direct stream to a specific peer.
 #[derive(Debug)]
-pub struct UnicastStream(Compat<NegotiatedSubstream>);
+pub struct UnicastStream(Compat<NegotiatedSubstream>, Arc<PeerMetrics>);
 
 // TODO: Utils for sending msgpack and stuff over the stream. -> Have a max size of reading buffers so we are less susceptible to DoS attacks.
 
 impl UnicastStream {
-	pub(crate) fn new(io: NegotiatedSubstream) -> Self {
-		Self(io.compat())
+	pub(crate) fn new(io: NegotiatedSubstream, metrics: Arc<PeerMetrics>) -> Self {
+		Self(io.compat(), metrics)
 	}
 
 	pub(crate) async fn write_discriminator(&mut self) -> io::Result<()> {
 		// TODO: Timeout if the peer doesn't accept the byte quick enough
-		self.0.write_all(&[UNICAST_DISCRIMINATOR]).await
+		self.0.write_all(&[UNICAST_DISCRIMINATOR]).await?;
+		self.1.record_sent(1);
+		Ok(())
 	}
 
 	pub async fn close(self) -> Result<(), io::Error> {
@@ -134,7 +169,13 @@ impl AsyncRead for UnicastStream {
 		cx: &mut Context<'_>,
 		buf: &mut ReadBuf<'_>,
 	) -> Poll<io::Result<()>> {
-		Pin::new(&mut self.get_mut().0).poll_read(cx, buf)
+		let this = self.get_mut();
+		let filled = buf.filled().len();
+		let result = Pin::new(&mut this.0).poll_read(cx, buf);
+		if let Poll::Ready(Ok(())) = result {
+			this.1.record_received(buf.filled().len() - filled);
+		}
+		result
 	}
 }
 
@@ -144,7 +185,12 @@ impl AsyncWrite for UnicastStream {
 		cx: &mut Context<'_>,
 		buf: &[u8],
 	) -> Poll<io::Result<usize>> {
-		Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+		let this = self.get_mut();
+		let result = Pin::new(&mut this.0).poll_write(cx, buf);
+		if let Poll::Ready(Ok(len)) = result {
+			this.1.record_sent(len);
+		}
+		result
 	}
 
 	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
@@ -155,3 +201,34 @@ impl AsyncWrite for UnicastStream {
 		Pin::new(&mut self.get_mut().0).poll_shutdown(cx)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use tokio::io::duplex;
+
+	use super::*;
+
+	#[tokio::test]
+	async fn test_broadcast_larger_than_buffer() {
+		let data = (0..10 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+		// The sender is never dropped so this is reading to the end of the payload not the stream
+		let (mut tx, mut rx) = duplex(64);
+
+		let (sent, received) = tokio::join!(
+			async {
+				tx.write_all(&broadcast_header(data.len() as u32)).await?;
+				tx.write_all(&data).await
+			},
+			async {
+				assert_eq!(rx.read_u8().await?, BROADCAST_DISCRIMINATOR);
+
+				let mut buf = Vec::new();
+				read_broadcast(rx).await?.read_to_end(&mut buf).await?;
+				Ok::<_, io::Error>(buf)
+			}
+		);
+
+		sent.unwrap();
+		assert_eq!(received.unwrap(), data);
+	}
+}
@@ -0,0 +1,557 @@
+//! Utilities for testing the P2P system with two in-process [Manager]s which are connected over loopback.
+//! [TestHarness::in_memory] connects them with [Transport::Memory] instead so tests don't touch the network at all.
+//! Discovery is disabled unless [TestHarness::wait_for_discovery] is called so tests don't depend on mDNS working on the machine running them.
+//! This is only available with the `test-utils` feature.
+
+use std::{
+	collections::HashMap,
+	net::{IpAddr, Ipv4Addr, SocketAddr},
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc,
+	},
+	time::Duration,
+};
+
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	sync::mpsc,
+	task::JoinHandle,
+	time::timeout,
+};
+
+use crate::{
+	spacetime::SpaceTimeStream, Event, Keypair, Manager, ManagerConfig, Metadata, Transport,
+};
+
+/// how long the helpers wait for an event before panicking so a broken test fails instead of hanging forever.
+pub const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// used to give each harness a unique application name so managers from concurrently running tests can't talk to each other.
+static HARNESS_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// application_name returns a unique application name for a harness. It's kept short as mDNS service names can't be longer than 15 bytes.
+fn application_name() -> String {
+	format!(
+		"sd{:x}-{}",
+		std::process::id(),
+		HARNESS_ID.fetch_add(1, Ordering::Relaxed)
+	)
+}
+
+/// A minimal metadata type for tests which don't care about the metadata.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestMetadata {
+	pub name: String,
+}
+
+impl Metadata for TestMetadata {
+	type Typed = ();
+
+	fn to_hashmap(self) -> HashMap<String, String> {
+		HashMap::from([("name".to_owned(), self.name)])
+	}
+
+	fn from_hashmap(data: &HashMap<String, String>) -> Result<Self, String> {
+		Ok(Self {
+			name: data
+				.get("name")
+				.ok_or_else(|| "DNS record for field 'name' missing!".to_owned())?
+				.to_owned(),
+		})
+	}
+}
+
+/// A single manager which has its event stream polled in the background. The events are queued so they can be asserted on in order.
+pub struct TestPeer<TMetadata: Metadata> {
+	pub manager: Arc<Manager<TMetadata>>,
+	/// the loopback address the manager is listening on.
+	pub address: SocketAddr,
+	events: mpsc::UnboundedReceiver<Event<TMetadata>>,
+	task: Option<JoinHandle<()>>,
+}
+
+impl<TMetadata: Metadata> TestPeer<TMetadata> {
+	/// new creates a manager with the given config and waits for it to start listening.
+	pub async fn new(application_name: &str, config: ManagerConfig, metadata: TMetadata) -> Self {
+		let (manager, mut stream) =
+			Manager::new(application_name, &Keypair::generate(), config, move || {
+				let metadata = metadata.clone();
+				async move { metadata }
+			})
+			.await
+			.expect("failed to create manager");
+
+		let (tx, events) = mpsc::unbounded_channel();
+		let task = tokio::spawn(async move {
+			while let Some(event) = stream.next().await {
+				if tx.send(event).is_err() {
+					break;
+				}
+			}
+		});
+
+		let mut peer = Self {
+			manager,
+			address: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+			events,
+			task: Some(task),
+		};
+
+		peer.address = peer
+			.wait_for(|event| match event {
+				Event::AddListenAddr(addr) if addr.ip().is_loopback() => Some(addr),
+				_ => None,
+			})
+			.await;
+
+		peer
+	}
+
+	/// next_event returns the next event emitted by the manager. This panics if no event is emitted within [TEST_TIMEOUT].
+	pub async fn next_event(&mut self) -> Event<TMetadata> {
+		timeout(TEST_TIMEOUT, self.events.recv())
+			.await
+			.expect("timed out waiting for an event")
+			.expect("the manager's event stream was closed")
+	}
+
+	/// wait_for skips events until `f` returns `Some`. This panics if no matching event is emitted within [TEST_TIMEOUT].
+	pub async fn wait_for<T>(&mut self, mut f: impl FnMut(Event<TMetadata>) -> Option<T>) -> T {
+		timeout(TEST_TIMEOUT, async {
+			loop {
+				let event = self
+					.events
+					.recv()
+					.await
+					.expect("the manager's event stream was closed");
+				if let Some(value) = f(event) {
+					return value;
+				}
+			}
+		})
+		.await
+		.expect("timed out waiting for a matching event")
+	}
+
+	/// next_message waits for the next stream opened by a peer.
+	pub async fn next_message(&mut self) -> SpaceTimeStream {
+		self.wait_for(|event| match event {
+			Event::PeerMessage(event) => Some(event.stream),
+			_ => None,
+		})
+		.await
+	}
+
+	/// shutdown shuts down the manager and waits for its event stream to finish.
+	pub async fn shutdown(mut self) {
+		self.manager.shutdown().await;
+		if let Some(task) = self.task.take() {
+			timeout(TEST_TIMEOUT, task)
+				.await
+				.expect("timed out waiting for the manager to shutdown")
+				.expect("the manager's event stream panicked");
+		}
+	}
+}
+
+impl<TMetadata: Metadata> Drop for TestPeer<TMetadata> {
+	fn drop(&mut self) {
+		// The test panicked or didn't call `shutdown` so the event stream is stopped to release the sockets
+		if let Some(task) = self.task.take() {
+			task.abort();
+		}
+	}
+}
+
+/// Two managers `a` and `b` which only listen on loopback.
+pub struct TestHarness<TMetadata: Metadata> {
+	pub a: TestPeer<TMetadata>,
+	pub b: TestPeer<TMetadata>,
+}
+
+impl TestHarness<TestMetadata> {
+	/// new creates two managers with the default test config and [TestMetadata].
+	pub async fn new() -> Self {
+		Self::with_metadata(
+			TestMetadata { name: "a".into() },
+			TestMetadata { name: "b".into() },
+		)
+		.await
+	}
+
+	/// in_memory creates two managers with [TestMetadata] which are connected with [Transport::Memory], see [Self::memory_config].
+	pub async fn in_memory() -> Self {
+		Self::with_config(
+			Self::memory_config(),
+			TestMetadata { name: "a".into() },
+			TestMetadata { name: "b".into() },
+		)
+		.await
+	}
+}
+
+impl<TMetadata: Metadata> TestHarness<TMetadata> {
+	/// config returns the config used by the harness. Managers listen on a random loopback port with discovery disabled.
+	pub fn config() -> ManagerConfig {
+		ManagerConfig {
+			discovery_enabled: false,
+			listen_port: 0,
+			listen_addrs: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+			..Default::default()
+		}
+	}
+
+	/// memory_config is the same as [Self::config] but the managers are connected with [Transport::Memory].
+	pub fn memory_config() -> ManagerConfig {
+		ManagerConfig {
+			transports: vec![Transport::Memory],
+			..Self::config()
+		}
+	}
+
+	pub async fn with_metadata(a: TMetadata, b: TMetadata) -> Self {
+		Self::with_config(Self::config(), a, b).await
+	}
+
+	pub async fn with_config(config: ManagerConfig, a: TMetadata, b: TMetadata) -> Self {
+		let application_name = application_name();
+
+		Self {
+			a: TestPeer::new(&application_name, config.clone(), a).await,
+			b: TestPeer::new(&application_name, config, b).await,
+		}
+	}
+
+	/// connect dials `b` from `a` and waits for both of them to see the connection.
+	pub async fn connect(&mut self) {
+		let (a_id, b_id) = (self.a.manager.peer_id(), self.b.manager.peer_id());
+		self.a.manager.dial_address(self.b.address).await;
+
+		self.a
+			.wait_for(|event| match event {
+				Event::PeerConnected(peer) if peer.peer_id == b_id => Some(()),
+				_ => None,
+			})
+			.await;
+		self.b
+			.wait_for(|event| match event {
+				Event::PeerConnected(peer) if peer.peer_id == a_id => Some(()),
+				_ => None,
+			})
+			.await;
+	}
+
+	/// wait_for_discovery enables mDNS on both managers and waits for them to discover each other.
+	/// This relies on multicast working on loopback so prefer [TestHarness::connect] unless discovery is being tested.
+	pub async fn wait_for_discovery(&mut self) {
+		let (a_id, b_id) = (self.a.manager.peer_id(), self.b.manager.peer_id());
+		self.a.manager.set_discovery_enabled(true).await;
+		self.b.manager.set_discovery_enabled(true).await;
+
+		self.a
+			.wait_for(|event| match event {
+				Event::PeerDiscovered(peer) if peer.peer_id == b_id => Some(()),
+				_ => None,
+			})
+			.await;
+		self.b
+			.wait_for(|event| match event {
+				Event::PeerDiscovered(peer) if peer.peer_id == a_id => Some(()),
+				_ => None,
+			})
+			.await;
+	}
+
+	/// send_and_await opens a unicast stream from `a` to `b`, writes `data` and returns everything `b` reads from the stream.
+	pub async fn send_and_await(&mut self, data: Vec<u8>) -> Vec<u8> {
+		let b_id = self.b.manager.peer_id();
+		let (manager, b) = (&self.a.manager, &mut self.b);
+
+		let (_, received) = tokio::join!(
+			async {
+				let mut stream = manager.stream(b_id).await.expect("failed to open stream");
+				stream.write_all(&data).await.expect("failed to write");
+				stream.close().await.expect("failed to close stream");
+			},
+			async {
+				let mut buf = Vec::new();
+				match b.next_message().await {
+					SpaceTimeStream::Unicast(mut stream) => {
+						timeout(TEST_TIMEOUT, stream.read_to_end(&mut buf))
+							.await
+							.expect("timed out reading stream")
+							.expect("failed to read stream");
+					}
+					SpaceTimeStream::Broadcast(_) => panic!("expected a unicast stream"),
+				}
+				buf
+			}
+		);
+
+		received
+	}
+
+	/// broadcast_and_await broadcasts `data` from `a` and returns the payload `b` receives.
+	pub async fn broadcast_and_await(&mut self, data: Vec<u8>) -> Vec<u8> {
+		self.a.manager.broadcast(data).await;
+
+		let mut buf = Vec::new();
+		match self.b.next_message().await {
+			SpaceTimeStream::Broadcast(mut stream) => {
+				timeout(TEST_TIMEOUT, stream.read_to_end(&mut buf))
+					.await
+					.expect("timed out reading broadcast")
+					.expect("failed to read broadcast");
+			}
+			SpaceTimeStream::Unicast(_) => panic!("expected a broadcast stream"),
+		}
+		buf
+	}
+
+	/// shutdown shuts down both managers. Every test should call this so the sockets are closed before the next test.
+	pub async fn shutdown(self) {
+		tokio::join!(self.a.shutdown(), self.b.shutdown());
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use libp2p::{
+		core::{muxing::StreamMuxerBox, upgrade},
+		futures::StreamExt,
+		multiaddr::Protocol,
+		noise, relay,
+		swarm::{AddressScore, SwarmEvent},
+		tcp, yamux, Multiaddr, Swarm, Transport as _,
+	};
+
+	use crate::{DialError, ManagerError};
+
+	use super::*;
+
+	/// start_relay runs a circuit relay on loopback in the background and returns its address.
+	async fn start_relay() -> (Multiaddr, JoinHandle<()>) {
+		let keypair = Keypair::generate();
+		let peer_id = keypair.public().to_peer_id();
+		let transport = tcp::tokio::Transport::new(tcp::Config::default())
+			.upgrade(upgrade::Version::V1)
+			.authenticate(noise::NoiseAuthenticated::xx(keypair.inner()).unwrap())
+			.multiplex(yamux::YamuxConfig::default())
+			.map(|(p, c), _| (p, StreamMuxerBox::new(c)))
+			.boxed();
+		let mut swarm = Swarm::with_tokio_executor(
+			transport,
+			relay::Behaviour::new(peer_id, Default::default()),
+			peer_id,
+		);
+
+		swarm
+			.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+			.unwrap();
+		let addr = loop {
+			if let SwarmEvent::NewListenAddr { address, .. } = swarm.select_next_some().await {
+				break address;
+			}
+		};
+		// The relay gives its addresses to the peers which reserve a slot on it so it must know them
+		swarm.add_external_address(addr.clone(), AddressScore::Infinite);
+
+		let task = tokio::spawn(async move {
+			loop {
+				swarm.select_next_some().await;
+			}
+		});
+		(addr.with(Protocol::P2p(peer_id.into())), task)
+	}
+
+	#[tokio::test]
+	async fn test_harness_unicast_and_broadcast() {
+		let mut harness = TestHarness::new().await;
+		harness.connect().await;
+
+		assert_eq!(
+			harness.send_and_await(b"unicast".to_vec()).await,
+			b"unicast"
+		);
+		assert_eq!(
+			harness.broadcast_and_await(b"broadcast".to_vec()).await,
+			b"broadcast"
+		);
+
+		harness.shutdown().await;
+	}
+
+	#[tokio::test]
+	async fn test_memory_transport() {
+		let mut harness = TestHarness::in_memory().await;
+		let b_id = harness.b.manager.peer_id();
+		harness.a.manager.dial_address(harness.b.address).await;
+		let peer = harness
+			.a
+			.wait_for(|event| match event {
+				Event::PeerConnected(peer) if peer.peer_id == b_id => Some(peer),
+				_ => None,
+			})
+			.await;
+		assert_eq!(peer.transport, Some(Transport::Memory));
+		harness
+			.b
+			.wait_for(|event| match event {
+				Event::PeerConnected(peer) if peer.peer_id != b_id => Some(()),
+				_ => None,
+			})
+			.await;
+
+		assert_eq!(
+			harness.send_and_await(b"unicast".to_vec()).await,
+			b"unicast"
+		);
+		assert_eq!(
+			harness.broadcast_and_await(b"broadcast".to_vec()).await,
+			b"broadcast"
+		);
+
+		harness.shutdown().await;
+	}
+
+	#[tokio::test]
+	async fn test_transport_fallback() {
+		let application_name = application_name();
+
+		// `b` only listens with TCP so `a` has to fall back to it once QUIC fails
+		let mut a = TestPeer::new(
+			&application_name,
+			TestHarness::<TestMetadata>::config(),
+			TestMetadata { name: "a".into() },
+		)
+		.await;
+		let b = TestPeer::new(
+			&application_name,
+			ManagerConfig {
+				transports: vec![Transport::Tcp],
+				..TestHarness::<TestMetadata>::config()
+			},
+			TestMetadata { name: "b".into() },
+		)
+		.await;
+
+		a.manager
+			.dial(b.manager.peer_id(), vec![b.address])
+			.await
+			.unwrap();
+		let peer = a
+			.wait_for(|event| match event {
+				Event::PeerConnected(peer) => Some(peer),
+				_ => None,
+			})
+			.await;
+		assert_eq!(peer.transport, Some(Transport::Tcp));
+
+		tokio::join!(a.shutdown(), b.shutdown());
+	}
+
+	#[tokio::test]
+	async fn test_relayed_connection() {
+		let (relay, relay_task) = start_relay().await;
+
+		// A relay can only be reserved on by its peer id
+		let mut without_peer_id = relay.clone();
+		without_peer_id.pop();
+		let result = Manager::new(
+			&format!("sd-p2p-test-{}-invalid-relay", std::process::id()),
+			&Keypair::generate(),
+			ManagerConfig {
+				relays: vec![without_peer_id],
+				..TestHarness::<TestMetadata>::config()
+			},
+			|| async {
+				TestMetadata {
+					name: "invalid".into(),
+				}
+			},
+		)
+		.await;
+		assert!(matches!(result, Err(ManagerError::InvalidRelayAddr(_))));
+
+		// Hole punching is disabled so the connection stays relayed even though the peers could reach each other directly on loopback
+		let mut harness = TestHarness::with_config(
+			ManagerConfig {
+				relays: vec![relay],
+				hole_punching: false,
+				..TestHarness::<TestMetadata>::config()
+			},
+			TestMetadata { name: "a".into() },
+			TestMetadata { name: "b".into() },
+		)
+		.await;
+		let (a_id, b_id) = (harness.a.manager.peer_id(), harness.b.manager.peer_id());
+
+		// `b` is dialed without any direct addresses so the only way to reach it is through the relay, which it has to have reserved a slot on first
+		timeout(TEST_TIMEOUT, async {
+			while harness.a.manager.dial(b_id, vec![]).await.is_err() {
+				tokio::time::sleep(Duration::from_millis(50)).await;
+			}
+		})
+		.await
+		.expect("timed out dialing through the relay");
+
+		let peer = harness
+			.a
+			.wait_for(|event| match event {
+				Event::PeerConnected(peer) if peer.peer_id == b_id => Some(peer),
+				_ => None,
+			})
+			.await;
+		assert!(peer.relayed);
+		assert_eq!(peer.address, None);
+		harness
+			.b
+			.wait_for(|event| match event {
+				Event::PeerConnected(peer) if peer.peer_id == a_id => Some(peer.relayed),
+				_ => None,
+			})
+			.await
+			.then_some(())
+			.expect("expected a relayed connection");
+
+		assert_eq!(
+			harness.send_and_await(b"unicast".to_vec()).await,
+			b"unicast"
+		);
+
+		harness.shutdown().await;
+		relay_task.abort();
+	}
+
+	#[tokio::test]
+	async fn test_self_discovery_ignored() {
+		let mut harness = TestHarness::new().await;
+		let (a_id, b_id) = (harness.a.manager.peer_id(), harness.b.manager.peer_id());
+		harness.a.manager.set_discovery_enabled(true).await;
+		harness.b.manager.set_discovery_enabled(true).await;
+
+		// `a` receives its own advertisement over multicast loopback as well as `b`'s
+		harness
+			.a
+			.wait_for(|event| match event {
+				Event::PeerDiscovered(peer) => {
+					assert_ne!(peer.peer_id, a_id, "discovered own advertisement");
+					(peer.peer_id == b_id).then_some(())
+				}
+				_ => None,
+			})
+			.await;
+
+		let discovered = harness.a.manager.get_discovered_peers().await;
+		assert!(discovered.iter().any(|peer| peer.peer_id == b_id));
+		assert!(discovered.iter().all(|peer| peer.peer_id != a_id));
+
+		assert_eq!(
+			harness.a.manager.dial(a_id, vec![harness.a.address]).await,
+			Err(DialError::LocalPeer)
+		);
+
+		harness.shutdown().await;
+	}
+}
@@ -0,0 +1,126 @@
+use std::net::SocketAddr;
+#[cfg(any(test, feature = "test-utils"))]
+use std::{
+	net::{IpAddr, Ipv4Addr},
+	sync::atomic::{AtomicU16, Ordering},
+};
+
+use libp2p::{multiaddr::Protocol, Multiaddr};
+
+use crate::{socketaddr_to_quic_multiaddr, socketaddr_to_tcp_multiaddr};
+
+/// the transports a [crate::Manager] can connect to peers with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "specta", derive(specta::Type))]
+pub enum Transport {
+	/// QUIC over UDP. This has the fastest connection setup but UDP is blocked or throttled on some networks.
+	Quic,
+	/// TCP secured with Noise and multiplexed with Yamux. This is used as the fallback as it's allowed on almost every network.
+	Tcp,
+	/// an in-process transport secured and multiplexed the same way as TCP so managers can be connected in tests without touching the network.
+	/// It's never combined with the other transports. A socket address is mapped to the memory port with the same number, see [crate::test_utils].
+	#[cfg(any(test, feature = "test-utils"))]
+	Memory,
+}
+
+impl Transport {
+	/// the multiaddr to dial or listen on `addr` with this transport.
+	pub(crate) fn multiaddr(&self, addr: &SocketAddr) -> Multiaddr {
+		match self {
+			Self::Quic => socketaddr_to_quic_multiaddr(addr),
+			Self::Tcp => socketaddr_to_tcp_multiaddr(addr),
+			#[cfg(any(test, feature = "test-utils"))]
+			Self::Memory => Multiaddr::empty().with(Protocol::Memory(addr.port().into())),
+		}
+	}
+
+	/// of returns the transport a multiaddr is for or `None` if it's not for a supported transport.
+	pub fn of(addr: &Multiaddr) -> Option<Self> {
+		#[cfg(any(test, feature = "test-utils"))]
+		if let Some(Protocol::Memory(_)) = addr.iter().next() {
+			return Some(Self::Memory);
+		}
+
+		let mut protocols = addr.iter().skip(1);
+		match (protocols.next(), protocols.next()) {
+			(Some(Protocol::Udp(_)), Some(Protocol::QuicV1)) => Some(Self::Quic),
+			(Some(Protocol::Tcp(_)), _) => Some(Self::Tcp),
+			_ => None,
+		}
+	}
+}
+
+/// transport_order returns the transports in the order they should be tried, with any duplicates removed.
+/// TCP is always included (last if it's not in `preference`) so a node can never become unreachable because of its transport config.
+pub(crate) fn transport_order(preference: &[Transport]) -> Vec<Transport> {
+	#[cfg(any(test, feature = "test-utils"))]
+	if preference.contains(&Transport::Memory) {
+		return vec![Transport::Memory];
+	}
+
+	let mut order = Vec::with_capacity(preference.len() + 1);
+	for transport in preference.iter().copied().chain([Transport::Tcp]) {
+		if !order.contains(&transport) {
+			order.push(transport);
+		}
+	}
+	order
+}
+
+/// the next port a manager using [Transport::Memory] listens on when the config doesn't set one. Ports are shared by the whole process so each manager is given its own.
+#[cfg(any(test, feature = "test-utils"))]
+static NEXT_MEMORY_PORT: AtomicU16 = AtomicU16::new(1);
+
+/// memory_listen_addr returns the address a manager using [Transport::Memory] listens on. Unlike the other transports a port of `0` can't be assigned by the OS.
+#[cfg(any(test, feature = "test-utils"))]
+pub(crate) fn memory_listen_addr(port: u16) -> SocketAddr {
+	let port = match port {
+		0 => NEXT_MEMORY_PORT.fetch_add(1, Ordering::Relaxed),
+		port => port,
+	};
+	SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_transport_order() {
+		assert_eq!(
+			transport_order(&[Transport::Quic, Transport::Tcp]),
+			vec![Transport::Quic, Transport::Tcp]
+		);
+		assert_eq!(
+			transport_order(&[Transport::Tcp, Transport::Quic, Transport::Tcp]),
+			vec![Transport::Tcp, Transport::Quic]
+		);
+
+		// TCP is the fallback even if it was left out
+		assert_eq!(
+			transport_order(&[Transport::Quic]),
+			vec![Transport::Quic, Transport::Tcp]
+		);
+		assert_eq!(transport_order(&[]), vec![Transport::Tcp]);
+
+		// The in-memory transport never falls back to the network
+		assert_eq!(
+			transport_order(&[Transport::Quic, Transport::Memory]),
+			vec![Transport::Memory]
+		);
+	}
+
+	#[test]
+	fn test_transport_of() {
+		let addr = "192.168.1.2:1234".parse().unwrap();
+		for transport in [Transport::Quic, Transport::Tcp, Transport::Memory] {
+			assert_eq!(Transport::of(&transport.multiaddr(&addr)), Some(transport));
+		}
+
+		assert_eq!(
+			Transport::of(&"/ip4/192.168.1.2/udp/1234".parse().unwrap()),
+			None
+		);
+	}
+}
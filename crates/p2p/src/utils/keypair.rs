@@ -16,6 +16,31 @@ impl Keypair {
 	pub fn inner(&self) -> &libp2p::identity::Keypair {
 		&self.0
 	}
+
+	/// the encoded Ed25519 public key. This is what [Keypair::verify] expects.
+	pub fn public_key_bytes(&self) -> [u8; 32] {
+		match &self.0 {
+			libp2p::identity::Keypair::Ed25519(keypair) => keypair.public().encode(),
+			#[allow(unreachable_patterns)]
+			_ => unreachable!(),
+		}
+	}
+
+	/// sign the message with the Ed25519 secret key.
+	pub fn sign(&self, msg: &[u8]) -> Vec<u8> {
+		match &self.0 {
+			libp2p::identity::Keypair::Ed25519(keypair) => keypair.sign(msg),
+			#[allow(unreachable_patterns)]
+			_ => unreachable!(),
+		}
+	}
+
+	/// verify the signature of a message against an encoded Ed25519 public key returned by [Keypair::public_key_bytes].
+	pub fn verify(public_key: &[u8], msg: &[u8], signature: &[u8]) -> bool {
+		ed25519::PublicKey::decode(public_key)
+			.map(|public_key| public_key.verify(msg, signature))
+			.unwrap_or(false)
+	}
 }
 
 impl Serialize for Keypair {
@@ -1,10 +1,204 @@
 use std::collections::HashMap;
 
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::{debug, warn};
+
+/// the TXT record key which the typed metadata is advertised under.
+const TYPED_METADATA_KEY: &str = "typed";
+
+/// A single DNS TXT record entry (`key=value`) is prefixed by its length in a single byte so it can be at most 255 bytes.
+pub const MAX_TXT_ENTRY_LEN: usize = 255;
+
+/// The whole TXT record should fit within a single mDNS packet. Refer to RFC 6763 section 6.2.
+pub const MAX_TXT_RECORD_LEN: usize = 1300;
+
 /// this trait must be implemented for the metadata type to allow it to be converted to MDNS DNS records.
 pub trait Metadata: Clone + Send + Sync + 'static {
+	/// Typed is an optional serde representation of the metadata which is advertised alongside the string map so fields don't have to be stringified and reparsed.
+	/// Use `()` if the metadata should only be advertised as a string map.
+	type Typed: Serialize + DeserializeOwned;
+
 	fn to_hashmap(self) -> HashMap<String, String>;
 
 	fn from_hashmap(data: &HashMap<String, String>) -> Result<Self, String>
 	where
 		Self: Sized;
+
+	/// to_typed returns the typed representation to advertise. It's dropped if it doesn't fit in the TXT record so the string map must always be complete.
+	fn to_typed(&self) -> Option<Self::Typed> {
+		None
+	}
+
+	/// from_typed is used instead of `from_hashmap` when a peer advertised typed metadata which could be decoded.
+	/// The string map is also provided for any fields which aren't part of the typed representation.
+	fn from_typed(_typed: Self::Typed, data: &HashMap<String, String>) -> Result<Self, String>
+	where
+		Self: Sized,
+	{
+		Self::from_hashmap(data)
+	}
+}
+
+/// encode_metadata converts the metadata into the entries of a DNS TXT record.
+/// Entries which are too large for a TXT record are dropped and if the whole record is too large the typed metadata and then the largest entries are dropped until it fits.
+pub fn encode_metadata<TMetadata: Metadata>(metadata: TMetadata) -> HashMap<String, String> {
+	let typed = metadata
+		.to_typed()
+		.and_then(|typed| match rmp_serde::to_vec(&typed) {
+			Ok(bytes) => Some(encode_hex(&bytes)),
+			Err(err) => {
+				warn!("error encoding typed metadata: {}", err);
+				None
+			}
+		});
+
+	let mut data = metadata.to_hashmap();
+	if let Some(typed) = typed {
+		data.insert(TYPED_METADATA_KEY.to_owned(), typed);
+	}
+
+	data.retain(|key, value| {
+		let fits = entry_len(key, value) <= MAX_TXT_ENTRY_LEN;
+		if !fits {
+			warn!(
+				"dropping metadata field '{}' as it's too large for a DNS TXT record",
+				key
+			);
+		}
+		fits
+	});
+
+	while data.iter().map(|(k, v)| entry_len(k, v) + 1).sum::<usize>() > MAX_TXT_RECORD_LEN {
+		let key = if data.contains_key(TYPED_METADATA_KEY) {
+			TYPED_METADATA_KEY.to_owned()
+		} else {
+			match data.iter().max_by_key(|(k, v)| entry_len(k, v)) {
+				Some((key, _)) => key.clone(),
+				None => break,
+			}
+		};
+
+		warn!(
+			"dropping metadata field '{}' as the DNS TXT record is too large",
+			key
+		);
+		data.remove(&key);
+	}
+
+	data
+}
+
+/// decode_metadata converts the entries of a DNS TXT record back into the metadata.
+/// The typed metadata is preferred but the string map is used if it is missing or can't be decoded (Eg. it was advertised by a different version).
+pub fn decode_metadata<TMetadata: Metadata>(
+	data: &HashMap<String, String>,
+) -> Result<TMetadata, String> {
+	if let Some(typed) = data.get(TYPED_METADATA_KEY) {
+		match decode_hex(typed).map(|bytes| rmp_serde::from_slice::<TMetadata::Typed>(&bytes)) {
+			Some(Ok(typed)) => return TMetadata::from_typed(typed, data),
+			Some(Err(err)) => debug!("falling back to untyped metadata: {}", err),
+			None => debug!("falling back to untyped metadata: invalid hex"),
+		}
+	}
+
+	TMetadata::from_hashmap(data)
+}
+
+fn entry_len(key: &str, value: &str) -> usize {
+	key.len() + 1 + value.len()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+	if s.len() % 2 != 0 {
+		return None;
+	}
+
+	(0..s.len())
+		.step_by(2)
+		.map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[derive(Debug, Clone, PartialEq)]
+	struct TestMetadata {
+		name: String,
+		version: (u32, u32, u32),
+	}
+
+	impl Metadata for TestMetadata {
+		type Typed = (u32, u32, u32);
+
+		fn to_hashmap(self) -> HashMap<String, String> {
+			let (major, minor, patch) = self.version;
+			HashMap::from([
+				("name".to_owned(), self.name),
+				("version".to_owned(), format!("{major}.{minor}.{patch}")),
+			])
+		}
+
+		fn from_hashmap(data: &HashMap<String, String>) -> Result<Self, String> {
+			let version = data
+				.get("version")
+				.and_then(|v| {
+					let mut parts = v.split('.').map(|p| p.parse().ok());
+					Some((parts.next()??, parts.next()??, parts.next()??))
+				})
+				.ok_or("invalid version")?;
+
+			Ok(Self {
+				name: data.get("name").cloned().unwrap_or_default(),
+				version,
+			})
+		}
+
+		fn to_typed(&self) -> Option<Self::Typed> {
+			Some(self.version)
+		}
+
+		fn from_typed(typed: Self::Typed, data: &HashMap<String, String>) -> Result<Self, String> {
+			Ok(Self {
+				name: data.get("name").cloned().unwrap_or_default(),
+				version: typed,
+			})
+		}
+	}
+
+	#[test]
+	fn test_typed_metadata() {
+		let metadata = TestMetadata {
+			name: "Spacedrive".into(),
+			version: (0, 1, 2),
+		};
+
+		let data = encode_metadata(metadata.clone());
+		assert!(data.contains_key(TYPED_METADATA_KEY));
+		assert_eq!(decode_metadata::<TestMetadata>(&data), Ok(metadata.clone()));
+
+		// The string map is used if the typed metadata can't be decoded
+		let mut data = data;
+		data.insert(TYPED_METADATA_KEY.to_owned(), "zz".into());
+		assert_eq!(decode_metadata::<TestMetadata>(&data), Ok(metadata));
+	}
+
+	#[test]
+	fn test_oversized_metadata() {
+		let data = encode_metadata(TestMetadata {
+			name: "a".repeat(MAX_TXT_ENTRY_LEN),
+			version: (0, 1, 2),
+		});
+
+		assert!(!data.contains_key("name"));
+		assert_eq!(
+			decode_metadata::<TestMetadata>(&data).map(|m| m.version),
+			Ok((0, 1, 2))
+		);
+	}
 }
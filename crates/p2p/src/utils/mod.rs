@@ -7,5 +7,9 @@ mod peer_id;
 pub(crate) use async_fn::*;
 pub use keypair::*;
 pub use metadata::*;
-pub(crate) use multiaddr::*;
+pub(crate) use multiaddr::{
+	is_relayed, is_valid_relay_addr, multiaddr_to_socketaddr, socketaddr_to_quic_multiaddr,
+	socketaddr_to_tcp_multiaddr,
+};
+pub use multiaddr::{parse_peer_address, InvalidPeerAddress};
 pub use peer_id::*;
@@ -1,13 +1,23 @@
-use std::net::{IpAddr, SocketAddr};
+use std::{
+	net::{IpAddr, Ipv4Addr, SocketAddr},
+	str::FromStr,
+};
 
 use libp2p::{multiaddr::Protocol, Multiaddr};
+use thiserror::Error;
 
 // TODO: Turn these into From/Into impls on a wrapper type
 
-pub(crate) fn quic_multiaddr_to_socketaddr(m: Multiaddr) -> Result<SocketAddr, String> {
+pub(crate) fn multiaddr_to_socketaddr(m: Multiaddr) -> Result<SocketAddr, String> {
 	let mut addr_parts = m.iter();
 
 	let addr = match addr_parts.next() {
+		// The in-memory transport used in tests has no address so its port is shown as a loopback address
+		Some(Protocol::Memory(port)) => {
+			return u16::try_from(port)
+				.map(|port| SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port))
+				.map_err(|_| format!("Invalid multiaddr. Memory port '{port}' is too large"))
+		}
 		Some(Protocol::Ip4(addr)) => IpAddr::V4(addr),
 		Some(Protocol::Ip6(addr)) => IpAddr::V6(addr),
 		Some(proto) => {
@@ -20,10 +30,10 @@ pub(crate) fn quic_multiaddr_to_socketaddr(m: Multiaddr) -> Result<SocketAddr, S
 	};
 
 	let port = match addr_parts.next() {
-		Some(Protocol::Udp(port)) => port,
+		Some(Protocol::Udp(port)) | Some(Protocol::Tcp(port)) => port,
 		Some(proto) => {
 			return Err(format!(
-				"Invalid multiaddr. Segment 2 expected protocol 'Udp' but found  '{}'",
+				"Invalid multiaddr. Segment 2 expected protocol 'Udp' or 'Tcp' but found  '{}'",
 				proto
 			))
 		}
@@ -33,6 +43,17 @@ pub(crate) fn quic_multiaddr_to_socketaddr(m: Multiaddr) -> Result<SocketAddr, S
 	Ok(SocketAddr::new(addr, port))
 }
 
+/// is_relayed returns whether the address is for a connection through a relay. The IP and port of a relayed address are the relay's, not the peer's.
+pub(crate) fn is_relayed(addr: &Multiaddr) -> bool {
+	addr.iter().any(|protocol| protocol == Protocol::P2pCircuit)
+}
+
+/// is_valid_relay_addr returns whether the address can be used as a relay, see [crate::ManagerConfig::relays].
+/// Circuits are made to a relay by its peer id so it must be at the end of the address.
+pub(crate) fn is_valid_relay_addr(addr: &Multiaddr) -> bool {
+	matches!(addr.iter().last(), Some(Protocol::P2p(_))) && !is_relayed(addr)
+}
+
 pub(crate) fn socketaddr_to_quic_multiaddr(m: &SocketAddr) -> Multiaddr {
 	let mut addr = Multiaddr::empty();
 	match m {
@@ -43,3 +64,47 @@ pub(crate) fn socketaddr_to_quic_multiaddr(m: &SocketAddr) -> Multiaddr {
 	addr.push(Protocol::QuicV1);
 	addr
 }
+
+pub(crate) fn socketaddr_to_tcp_multiaddr(m: &SocketAddr) -> Multiaddr {
+	let mut addr = Multiaddr::empty();
+	match m {
+		SocketAddr::V4(ip) => addr.push(Protocol::Ip4(*ip.ip())),
+		SocketAddr::V6(ip) => addr.push(Protocol::Ip6(*ip.ip())),
+	}
+	addr.push(Protocol::Tcp(m.port()));
+	addr
+}
+
+#[derive(Debug, Error)]
+#[error("invalid peer address '{0}'. Expected an address like '192.168.1.2:7373' or '/ip4/192.168.1.2/udp/7373/quic-v1'")]
+pub struct InvalidPeerAddress(String);
+
+/// parse_peer_address will parse an address entered by the user into the socket address of a peer.
+/// Both `ip:port` socket addresses and QUIC multiaddrs are accepted.
+pub fn parse_peer_address(input: &str) -> Result<SocketAddr, InvalidPeerAddress> {
+	let input = input.trim();
+	let err = || InvalidPeerAddress(input.to_string());
+
+	let addr = if input.starts_with('/') {
+		let multiaddr = Multiaddr::from_str(input).map_err(|_| err())?;
+
+		// We only support QUIC so the multiaddr must be `/ip*/.../udp/.../quic-v1` optionally followed by the peer id
+		let mut protocols = multiaddr.iter().skip(2);
+		if !matches!(protocols.next(), Some(Protocol::QuicV1))
+			|| !matches!(protocols.next(), None | Some(Protocol::P2p(_)))
+			|| protocols.next().is_some()
+		{
+			return Err(err());
+		}
+
+		multiaddr_to_socketaddr(multiaddr).map_err(|_| err())?
+	} else {
+		SocketAddr::from_str(input).map_err(|_| err())?
+	};
+
+	if addr.port() == 0 || addr.ip().is_unspecified() || addr.ip().is_multicast() {
+		return Err(err());
+	}
+
+	Ok(addr)
+}
@@ -5,6 +5,64 @@ use std::{fmt::Display, str::FromStr};
 #[cfg_attr(feature = "serde", serde(transparent))]
 pub struct PeerId(pub(crate) libp2p::PeerId);
 
+/// every peer id derived from an Ed25519 public key starts with this so it's skipped when creating a fingerprint.
+const ED25519_PREFIX: &str = "12D3KooW";
+
+/// the number of characters from each end of the peer id which are included in a fingerprint.
+pub const FINGERPRINT_LEN: usize = 6;
+
+const FINGERPRINT_SEPARATOR: &str = "...";
+
+impl PeerId {
+	/// to_base58 returns the canonical string form of the peer id. This is the same as the `Display` implementation and can be parsed back with `from_base58`.
+	pub fn to_base58(&self) -> String {
+		self.0.to_base58()
+	}
+
+	/// from_base58 parses a peer id from the string returned by `to_base58`.
+	pub fn from_base58(s: &str) -> Result<Self, libp2p::core::ParseError> {
+		Self::from_str(s.trim())
+	}
+
+	/// fingerprint returns a short form of the peer id for compact display (Eg. `9tHTtS...CVeH3e`) which users can compare across two screens.
+	/// It's not unique so it must only be used for display and compared to a full peer id with `matches_fingerprint`.
+	pub fn fingerprint(&self) -> String {
+		let s = self.to_base58();
+		let body = s.strip_prefix(ED25519_PREFIX).unwrap_or(&s);
+
+		if body.len() <= FINGERPRINT_LEN * 2 {
+			return body.to_owned();
+		}
+
+		format!(
+			"{}{FINGERPRINT_SEPARATOR}{}",
+			&body[..FINGERPRINT_LEN],
+			&body[body.len() - FINGERPRINT_LEN..]
+		)
+	}
+
+	/// matches_fingerprint checks if a fingerprint (Eg. one copied by the user) was created from this peer id. A full peer id is also accepted.
+	pub fn matches_fingerprint(&self, fingerprint: &str) -> bool {
+		let fingerprint = fingerprint.trim();
+		let s = self.to_base58();
+		let body = s.strip_prefix(ED25519_PREFIX).unwrap_or(&s);
+
+		match fingerprint
+			.split_once(FINGERPRINT_SEPARATOR)
+			.or_else(|| fingerprint.split_once('\u{2026}'))
+		{
+			Some((start, end)) => {
+				start.len() == FINGERPRINT_LEN
+					&& end.len() == FINGERPRINT_LEN
+					&& body.len() > FINGERPRINT_LEN * 2
+					&& body.starts_with(start)
+					&& body.ends_with(end)
+			}
+			None => fingerprint == s || fingerprint == body,
+		}
+	}
+}
+
 impl FromStr for PeerId {
 	type Err = libp2p::core::ParseError;
 
@@ -40,3 +98,38 @@ impl specta::Type for PeerId {
 		<String as specta::Type>::definition(opts)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const PEER_ID: &str = "12D3KooW9tHTtS3inCZiYykw4u5G4frbjVFqhkmJX12gSNCVeH3e";
+
+	#[test]
+	fn test_base58_roundtrip() {
+		let peer_id = PeerId::from_base58(PEER_ID).unwrap();
+		assert_eq!(peer_id.to_base58(), PEER_ID);
+		assert_eq!(peer_id.to_string(), PEER_ID);
+		assert_eq!(PeerId::from_base58(&peer_id.to_base58()).unwrap(), peer_id);
+
+		let peer_id = PeerId(libp2p::PeerId::random());
+		assert_eq!(PeerId::from_base58(&peer_id.to_base58()).unwrap(), peer_id);
+
+		assert!(PeerId::from_base58("not a peer id").is_err());
+	}
+
+	#[test]
+	fn test_fingerprint() {
+		let peer_id = PeerId::from_base58(PEER_ID).unwrap();
+		let fingerprint = peer_id.fingerprint();
+		assert_eq!(fingerprint, "9tHTtS...CVeH3e");
+		assert!(peer_id.matches_fingerprint(&fingerprint));
+		assert!(peer_id.matches_fingerprint(" 9tHTtS\u{2026}CVeH3e "));
+		assert!(peer_id.matches_fingerprint(PEER_ID));
+
+		let other = PeerId(libp2p::PeerId::random());
+		assert!(!other.matches_fingerprint(&fingerprint));
+		assert!(!peer_id.matches_fingerprint("9tHT...eH3e"));
+		assert!(!peer_id.matches_fingerprint(""));
+	}
+}
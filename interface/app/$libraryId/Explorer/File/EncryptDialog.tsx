@@ -73,7 +73,8 @@ export default (props: Props) => {
 			path_id: props.path_id,
 			metadata: data.metadata,
 			preview_media: data.previewMedia,
-			output_path: data.outputPath || null
+			output_path: data.outputPath || null,
+			segment_size: null
 		})
 	);
 
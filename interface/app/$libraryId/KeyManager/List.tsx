@@ -41,7 +41,7 @@ export default () => {
 					key={key.uuid}
 					data={{
 						id: key.uuid,
-						name: `Key ${key.uuid.substring(0, 8).toUpperCase()}`,
+						name: key.label,
 						queue: mountingQueue.current,
 						mounted: mountedKeys.includes(key),
 						default: defaultKey.data === key.uuid,
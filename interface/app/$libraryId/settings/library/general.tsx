@@ -18,7 +18,9 @@ export const Component = () => {
 		editLibrary.mutate({
 			id: library.uuid,
 			name: value.name ?? null,
-			description: value.description ?? null
+			description: value.description ?? null,
+			shareable: value.shareable ?? null,
+			sync_enabled: null
 		})
 	);
 
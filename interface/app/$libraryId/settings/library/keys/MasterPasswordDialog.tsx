@@ -14,6 +14,7 @@ import { PasswordMeter } from '~/components/PasswordMeter';
 import { generatePassword } from '~/util';
 
 const schema = z.object({
+	oldMasterPassword: z.string(),
 	masterPassword: z.string(),
 	masterPassword2: z.string(),
 	encryptionAlgo: z.string(),
@@ -29,10 +30,9 @@ export default (props: UseDialogProps) => {
 			});
 		},
 		onError: () => {
-			// this should never really happen
 			showAlertDialog({
 				title: 'Master Password Change Error',
-				value: 'There was an error while changing your master password.'
+				value: 'There was an error while changing your master password. Please check your current password is correct.'
 			});
 		}
 	});
@@ -52,6 +52,7 @@ export default (props: UseDialogProps) => {
 		defaultValues: {
 			encryptionAlgo: 'XChaCha20Poly1305',
 			hashingAlgo: 'Argon2id-s',
+			oldMasterPassword: '',
 			masterPassword: '',
 			masterPassword2: ''
 		}
@@ -69,6 +70,7 @@ export default (props: UseDialogProps) => {
 			return changeMasterPassword.mutateAsync({
 				algorithm: data.encryptionAlgo as Algorithm,
 				hashing_algorithm,
+				old_password: data.oldMasterPassword,
 				password: data.masterPassword
 			});
 		}
@@ -84,10 +86,17 @@ export default (props: UseDialogProps) => {
 			ctaDanger={true}
 			ctaLabel="Change"
 		>
+			<Input
+				placeholder="Current password"
+				type="password"
+				className="mt-3"
+				{...form.register('oldMasterPassword', { required: true })}
+			/>
+
 			<Input
 				placeholder="New password"
 				type={show.masterPassword ? 'text' : 'password'}
-				className="mt-3 mb-2"
+				className="mt-2 mb-2"
 				{...form.register('masterPassword', { required: true })}
 				right={
 					<div className="flex">
@@ -10,6 +10,13 @@ import { SearchBar } from './Explorer/TopBar';
 import * as PageLayout from './PageLayout';
 import classes from './spacedrop.module.scss';
 
+// Eg. "Linux (aarch64)"
+function peerPlatform({ operating_system: os, architecture }: PeerMetadata) {
+	if (!os) return null;
+	const name = typeof os === 'string' ? os : os.Other;
+	return architecture ? `${name} (${architecture})` : name;
+}
+
 // TODO: move this to UI, copied from Inspector
 const Pill = tw.span`mt-1 inline border border-transparent px-0.5 text-[9px] font-medium shadow shadow-app-shade/5 bg-app-selected rounded text-ink-dull`;
 
@@ -91,6 +98,9 @@ function TemporarySpacedropDemo() {
 		onData(data) {
 			if (data.type === 'DiscoveredPeer') {
 				setDiscoveredPeer([discoveredPeers.set(data.peer_id, data.metadata)]);
+			} else if (data.type === 'ExpiredPeer') {
+				discoveredPeers.delete(data.peer_id);
+				setDiscoveredPeer([discoveredPeers]);
 			}
 		}
 	});
@@ -117,6 +127,7 @@ function TemporarySpacedropDemo() {
 				{[...discoveredPeers.entries()].map(([peerId, metdata]) => (
 					<option key={peerId} value={peerId}>
 						{metdata.name}
+						{peerPlatform(metdata) && ` - ${peerPlatform(metdata)}`}
 					</option>
 				))}
 			</select>
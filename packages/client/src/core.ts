@@ -13,8 +13,10 @@ export type Procedures = {
         { key: "keys.getSecretKey", input: LibraryArgs<null>, result: string | null } | 
         { key: "keys.isKeyManagerUnlocking", input: LibraryArgs<null>, result: boolean | null } | 
         { key: "keys.isUnlocked", input: LibraryArgs<null>, result: boolean } | 
+        { key: "keys.keyState", input: LibraryArgs<string>, result: KeyState } | 
         { key: "keys.list", input: LibraryArgs<null>, result: StoredKey[] } | 
         { key: "keys.listMounted", input: LibraryArgs<null>, result: string[] } | 
+        { key: "keys.mountedKeys", input: LibraryArgs<null>, result: KeyInfo[] } | 
         { key: "library.getStatistics", input: LibraryArgs<null>, result: Statistics } | 
         { key: "library.list", input: never, result: LibraryConfigWrapped[] } | 
         { key: "locations.getById", input: LibraryArgs<number>, result: location_with_indexer_rules | null } | 
@@ -24,6 +26,15 @@ export type Procedures = {
         { key: "locations.indexer_rules.listForLocation", input: LibraryArgs<number>, result: IndexerRule[] } | 
         { key: "locations.list", input: LibraryArgs<null>, result: { id: number, pub_id: number[], node_id: number, name: string, path: string, total_capacity: number | null, available_capacity: number | null, is_archived: boolean, generate_preview_media: boolean, sync_preview_media: boolean, hidden: boolean, date_created: string, node: Node }[] } | 
         { key: "nodeState", input: never, result: NodeState } | 
+        { key: "p2p.blockedPeers", input: never, result: string[] } | 
+        { key: "p2p.connectedPeers", input: never, result: ConnectedPeer[] } | 
+        { key: "p2p.lanes", input: never, result: LaneStats } | 
+        { key: "p2p.listenAddrs", input: never, result: string[] } | 
+        { key: "p2p.manualPeers", input: never, result: string[] } | 
+        { key: "p2p.peerAddresses", input: string, result: PeerAddresses } | 
+        { key: "p2p.status", input: never, result: P2PStatus } | 
+        { key: "p2p.syncQueues", input: never, result: SyncQueueStats[] } | 
+        { key: "p2p.traffic", input: never, result: P2PTraffic } | 
         { key: "sync.messages", input: LibraryArgs<null>, result: CRDTOperation[] } | 
         { key: "tags.get", input: LibraryArgs<number>, result: Tag | null } | 
         { key: "tags.getExplorerData", input: LibraryArgs<number>, result: ExplorerData } | 
@@ -50,14 +61,24 @@ export type Procedures = {
         { key: "keys.changeMasterPassword", input: LibraryArgs<MasterPasswordChangeArgs>, result: null } | 
         { key: "keys.clearMasterPassword", input: LibraryArgs<null>, result: null } | 
         { key: "keys.deleteFromLibrary", input: LibraryArgs<string>, result: null } | 
+        { key: "keys.exportKeys", input: LibraryArgs<ExportKeysArgs>, result: null } | 
+        { key: "keys.importKeys", input: LibraryArgs<ImportKeysArgs>, result: ImportReport } | 
         { key: "keys.mount", input: LibraryArgs<string>, result: null } | 
+        { key: "keys.mountAll", input: LibraryArgs<string>, result: MountReport } | 
+        { key: "keys.mountQueued", input: LibraryArgs<MountQueuedArgs>, result: null } | 
+        { key: "keys.mountWithTtl", input: LibraryArgs<MountWithTtlArgs>, result: null } | 
+        { key: "keys.queue", input: LibraryArgs<string>, result: null } | 
+        { key: "keys.rename", input: LibraryArgs<KeyRenameArgs>, result: null } | 
         { key: "keys.restoreKeystore", input: LibraryArgs<RestoreBackupArgs>, result: number } | 
-        { key: "keys.setDefault", input: LibraryArgs<string>, result: null } | 
+        { key: "keys.rotateRootKey", input: LibraryArgs<string>, result: null } | 
+        { key: "keys.setDefault", input: LibraryArgs<string | null>, result: null } | 
         { key: "keys.syncKeyToLibrary", input: LibraryArgs<string>, result: null } | 
         { key: "keys.unlockKeyManager", input: LibraryArgs<UnlockKeyManagerArgs>, result: null } | 
         { key: "keys.unmount", input: LibraryArgs<string>, result: null } | 
         { key: "keys.unmountAll", input: LibraryArgs<null>, result: null } | 
+        { key: "keys.unqueue", input: LibraryArgs<string>, result: null } | 
         { key: "keys.updateAutomountStatus", input: LibraryArgs<AutomountUpdateArgs>, result: null } | 
+        { key: "keys.verifyPassword", input: LibraryArgs<VerifyPasswordArgs>, result: boolean } | 
         { key: "library.create", input: CreateLibraryArgs, result: LibraryConfigWrapped } | 
         { key: "library.delete", input: string, result: null } | 
         { key: "library.edit", input: EditLibraryArgs, result: null } | 
@@ -70,8 +91,25 @@ export type Procedures = {
         { key: "locations.quickRescan", input: LibraryArgs<LightScanArgs>, result: null } | 
         { key: "locations.relink", input: LibraryArgs<string>, result: null } | 
         { key: "locations.update", input: LibraryArgs<LocationUpdateArgs>, result: null } | 
+        { key: "nodes.edit", input: EditNodeArgs, result: null } | 
         { key: "nodes.tokenizeSensitiveKey", input: TokenizeKeyArgs, result: TokenizeResponse } | 
+        { key: "p2p.acceptPairing", input: string, result: null } | 
+        { key: "p2p.addManualPeer", input: string, result: string } | 
+        { key: "p2p.blockPeer", input: string, result: null } | 
+        { key: "p2p.confirmPairing", input: ConfirmPairingArgs, result: null } | 
+        { key: "p2p.connect", input: string, result: null } | 
+        { key: "p2p.disconnect", input: string, result: null } | 
+        { key: "p2p.initiatePairing", input: string, result: string } | 
+        { key: "p2p.pinAddress", input: PinAddressArgs, result: string | null } | 
+        { key: "p2p.rejectPairing", input: string, result: null } | 
+        { key: "p2p.removeManualPeer", input: string, result: null } | 
+        { key: "p2p.requestFile", input: RequestFileArgs, result: string } | 
+        { key: "p2p.setDialPolicy", input: DialPolicy, result: null } | 
+        { key: "p2p.setDiscoveryEnabled", input: boolean, result: null } | 
+        { key: "p2p.setPeerNickname", input: SetPeerNicknameArgs, result: string | null } | 
         { key: "p2p.spacedrop", input: SpacedropArgs, result: null } | 
+        { key: "p2p.unblockPeer", input: string, result: null } | 
+        { key: "p2p.unpair", input: string, result: null } | 
         { key: "tags.assign", input: LibraryArgs<TagAssignArgs>, result: null } | 
         { key: "tags.create", input: LibraryArgs<TagCreateArgs>, result: Tag } | 
         { key: "tags.delete", input: LibraryArgs<number>, result: null } | 
@@ -79,11 +117,17 @@ export type Procedures = {
     subscriptions: 
         { key: "invalidateQuery", input: never, result: InvalidateOperationEvent } | 
         { key: "jobs.newThumbnail", input: LibraryArgs<null>, result: string } | 
+        { key: "keys.keyExpired", input: LibraryArgs<null>, result: string } | 
         { key: "locations.online", input: never, result: number[][] } | 
         { key: "p2p.events", input: never, result: P2PEvent } | 
         { key: "sync.newMessage", input: LibraryArgs<null>, result: CRDTOperation }
 };
 
+/**
+ *  the kind of network an address is reachable on. When dialing a peer its addresses are tried in this order so the fastest network which is likely to work is used.
+ */
+export type AddressKind = "Lan" | "LinkLocal" | "Vpn" | "Public" | "Loopback"
+
 /**
  *  These are all possible algorithms that can be used for encryption and decryption
  */
@@ -105,9 +149,29 @@ export type CRDTOperationType = SharedOperation | RelationOperation | OwnedOpera
  */
 export type ConfigMetadata = { version: string | null }
 
+export type ConfirmPairingArgs = { peer_id: string, code: string }
+
+/**
+ *  A peer which currently has an active connection with this node.
+ */
+export type ConnectedPeer = { peer_id: string, metadata: PeerMetadata | null, nickname: string | null, name: string | null, addresses: string[], transport: Transport | null, relayed: boolean, connected_at: string, connected_for: number, latency: number | null, in_flight_streams: number }
+
 export type CreateLibraryArgs = { name: string, auth: AuthOption, algorithm: Algorithm, hashing_algorithm: HashingAlgorithm }
 
-export type EditLibraryArgs = { id: string, name: string | null, description: string | null }
+/**
+ *  the reason a peer couldn't be dialed. This is returned by [crate::Manager::dial] so the application can show why a peer won't connect.
+ */
+export type DialError = "Timeout" | "Refused" | "Incompatible" | "AuthFailed" | "Blocked" | "Unreachable" | "Aborted" | "LocalPeer"
+
+/**
+ *  Controls which discovered peers are automatically dialed.
+ *  Peers which don't match the policy are still emitted to the frontend as discovered but are never connected to.
+ */
+export type DialPolicy = "All" | "Paired" | { Allowlist: string[] } | "Manual"
+
+export type EditLibraryArgs = { id: string, name: string | null, description: string | null, shareable: boolean | null, sync_enabled: boolean | null }
+
+export type EditNodeArgs = { name: string }
 
 /**
  *  This should be used for passing an encrypted key around.
@@ -122,6 +186,8 @@ export type ExplorerData = { context: ExplorerContext, items: ExplorerItem[] }
 
 export type ExplorerItem = { type: "Path", has_thumbnail: boolean, item: file_path_with_object } | { type: "Object", has_thumbnail: boolean, item: object_with_file_paths }
 
+export type ExportKeysArgs = { password: string, path: string }
+
 export type FileCopierJobInit = { source_location_id: number, source_path_id: number, target_location_id: number, target_path: string, target_file_name_suffix: string | null }
 
 export type FileCutterJobInit = { source_location_id: number, source_path_id: number, target_location_id: number, target_path: string }
@@ -130,12 +196,17 @@ export type FileDecryptorJobInit = { location_id: number, path_id: number, mount
 
 export type FileDeleterJobInit = { location_id: number, path_id: number }
 
-export type FileEncryptorJobInit = { location_id: number, path_id: number, key_uuid: string, algorithm: Algorithm, metadata: boolean, preview_media: boolean, output_path: string | null }
+export type FileEncryptorJobInit = { location_id: number, path_id: number, key_uuid: string | null, algorithm: Algorithm, metadata: boolean, preview_media: boolean, output_path: string | null, segment_size: number | null }
 
 export type FileEraserJobInit = { location_id: number, path_id: number, passes: string }
 
 export type FilePath = { id: number, is_dir: boolean, cas_id: string | null, integrity_checksum: string | null, location_id: number, materialized_path: string, name: string, extension: string, size_in_bytes: string, inode: number[], device: number[], object_id: number | null, parent_id: number | null, key_id: number | null, date_created: string, date_modified: string, date_indexed: string }
 
+/**
+ *  FileRequest is a file to download from a peer with [crate::p2p::P2PManager::request_file].
+ */
+export type FileRequest = { transfer_id: TransferId, offset: string, chunk_size: number | null }
+
 export type GenerateThumbsForLocationArgs = { id: number, path: string }
 
 export type GetArgs = { id: number }
@@ -145,8 +216,16 @@ export type GetArgs = { id: number }
  */
 export type HashingAlgorithm = { name: "Argon2id", params: Params } | { name: "BalloonBlake3", params: Params }
 
+export type HashingParams = { m_cost: number, t_cost: number, p_cost: number }
+
 export type IdentifyUniqueFilesArgs = { id: number, path: string }
 
+export type ImportKeysArgs = { password: string, path: string, policy: ImportPolicy }
+
+export type ImportPolicy = "Skip" | "Overwrite" | "Reject"
+
+export type ImportReport = { added: string[], skipped: string[], overwritten: string[] }
+
 export type IndexerRule = { id: number, kind: number, name: string, parameters: number[], date_created: string, date_modified: string }
 
 /**
@@ -169,6 +248,24 @@ export type JobStatus = "Queued" | "Running" | "Completed" | "Canceled" | "Faile
 
 export type KeyAddArgs = { algorithm: Algorithm, hashing_algorithm: HashingAlgorithm, key: string, library_sync: boolean, automount: boolean }
 
+export type KeyInfo = { uuid: string, label: string, description: string | null, key_type: StoredKeyType, algorithm: Algorithm, hashing_algorithm: HashingAlgorithm, default: boolean, memory_only: boolean, automount: boolean }
+
+export type KeyRenameArgs = { uuid: string, label: string, description: string | null }
+
+export type KeyState = "Unmounted" | "Queued" | "Mounting" | "Mounted"
+
+/**
+ *  A peer which this node reconnects to on startup. These are persisted to the node config so they are dialed straight away instead of waiting for them to be discovered.
+ *  A peer is only forgotten once the user unpairs it (and it has no nickname), never because it couldn't be reached.
+ */
+export type KnownPeer = { addresses: string[], paired: boolean, nickname: string | null, device_id: string | null }
+
+/**
+ *  A snapshot of the lanes so the effect of bulk transfers on the control lane can be checked.
+ *  The latencies are averaged over the control requests sent since the node started.
+ */
+export type LaneStats = { control_in_flight: number, bulk_in_flight: number, control_latency: number | null, control_latency_during_bulk: number | null, bulk_delays: number, bulk_delay: number }
+
 /**
  *  Can wrap a query argument to require it to contain a `library_id` and provide helpers for working with libraries.
  */
@@ -177,7 +274,7 @@ export type LibraryArgs<T> = { library_id: string, arg: T }
 /**
  *  LibraryConfig holds the configuration for a specific library. This is stored as a '{uuid}.sdlibrary' file.
  */
-export type LibraryConfig = ({ version: string | null }) & { name: string, description: string }
+export type LibraryConfig = ({ version: string | null }) & { name: string, description: string, shareable: boolean, sync_disabled: boolean }
 
 export type LibraryConfigWrapped = { uuid: string, config: LibraryConfig }
 
@@ -204,18 +301,24 @@ export type LocationExplorerArgs = { location_id: number, path: string, limit: n
  */
 export type LocationUpdateArgs = { id: number, name: string | null, generate_preview_media: boolean | null, sync_preview_media: boolean | null, hidden: boolean | null, indexer_rules_ids: number[] }
 
-export type MasterPasswordChangeArgs = { password: string, algorithm: Algorithm, hashing_algorithm: HashingAlgorithm }
+export type MasterPasswordChangeArgs = { old_password: string, password: string, algorithm: Algorithm, hashing_algorithm: HashingAlgorithm }
 
 export type MediaData = { id: number, pixel_width: number | null, pixel_height: number | null, longitude: number | null, latitude: number | null, fps: number | null, capture_device_make: string | null, capture_device_model: string | null, capture_device_software: string | null, duration_seconds: number | null, codecs: string | null, streams: number | null }
 
+export type MountQueuedArgs = { uuid: string, password: string }
+
+export type MountReport = { mounted: string[], skipped: string[], mismatched: string[] }
+
+export type MountWithTtlArgs = { uuid: string, ttl_secs: number }
+
 export type Node = { id: number, pub_id: number[], name: string, platform: number, version: string | null, last_seen: string, timezone: string | null, date_created: string }
 
 /**
  *  NodeConfig is the configuration for a node. This is shared between all libraries and is stored in a JSON file on disk.
  */
-export type NodeConfig = ({ version: string | null }) & { id: string, name: string, p2p_port: number | null, p2p_email: string | null, p2p_img_url: string | null }
+export type NodeConfig = ({ version: string | null }) & { id: string, name: string, p2p_port: number | null, p2p_email: string | null, p2p_img_url: string | null, p2p_dial_policy: DialPolicy, p2p_paired_peers: string[], p2p_discovery_enabled: boolean, p2p_manual_peers: string[], p2p_network_name: string | null, p2p_blocked_peers: string[], p2p_listen_addrs: string[], p2p_pinned_addresses: { [key: string]: string }, p2p_transports: Transport[], p2p_relays: string[], p2p_known_peers: { [key: string]: KnownPeer } }
 
-export type NodeState = (({ version: string | null }) & { id: string, name: string, p2p_port: number | null, p2p_email: string | null, p2p_img_url: string | null }) & { data_path: string }
+export type NodeState = (({ version: string | null }) & { id: string, name: string, p2p_port: number | null, p2p_email: string | null, p2p_img_url: string | null, p2p_dial_policy: DialPolicy, p2p_paired_peers: string[], p2p_discovery_enabled: boolean, p2p_manual_peers: string[], p2p_network_name: string | null, p2p_blocked_peers: string[], p2p_listen_addrs: string[], p2p_pinned_addresses: { [key: string]: string }, p2p_transports: Transport[], p2p_relays: string[], p2p_known_peers: { [key: string]: KnownPeer } }) & { data_path: string }
 
 /**
  *  This should be used for providing a nonce to encrypt/decrypt functions.
@@ -232,7 +335,7 @@ export type ObjectValidatorArgs = { id: number, path: string }
  *  Represents the operating system which the remote peer is running.
  *  This is not used internally and predominantly is designed to be used for display purposes by the embedding application.
  */
-export type OperatingSystem = "Windows" | "Linux" | "MacOS" | "Ios" | "Android" | { Other: string }
+export type OperatingSystem = "Windows" | "Linux" | "MacOS" | "Ios" | "Android" | "FreeBSD" | "OpenBSD" | "NetBSD" | { Other: string }
 
 export type OwnedOperation = { model: string, items: OwnedOperationItem[] }
 
@@ -243,21 +346,49 @@ export type OwnedOperationItem = { id: any, data: OwnedOperationData }
 /**
  *  TODO: P2P event for the frontend
  */
-export type P2PEvent = { type: "DiscoveredPeer", peer_id: string, metadata: PeerMetadata } | { type: "SyncOperation", library_id: string, operations: CRDTOperation[] }
+export type P2PEvent = { type: "DiscoveredPeer", peer_id: string, metadata: PeerMetadata, addresses: string[] } | { type: "ExpiredPeer", peer_id: string } | { type: "PeerMetadataChanged", peer_id: string, metadata: PeerMetadata } | { type: "PeerIncompatible", peer_id: string, version: string | null, protocol_version: number | null } | { type: "ConnectedPeer", peer_id: string, address: string | null, transport: Transport | null, relayed: boolean } | { type: "ConnectionUpgraded", peer_id: string, address: string | null, transport: Transport | null } | { type: "DisconnectedPeer", peer_id: string } | { type: "DialFailed", peer_id: string, reason: DialError } | { type: "BootstrapProgress", peer_id: string, progress: PeerBootstrapProgress } | { type: "FileTransferProgress", peer_id: string, library_id: string, file_path_id: number, transferred: string, total: string } | { type: "SyncOperation", library_id: string, operations: CRDTOperation[] } | { type: "ConnectingPeer", peer_id: string, attempt: number } | { type: "ReconnectedPeer", peer_id: string } | { type: "PairingRequest", peer_id: string } | { type: "PairingConfirmed", peer_id: string } | { type: "PairingExpired", peer_id: string } | { type: "Paired", peer_id: string, replaced: string[] } | { type: "ListenAddrsChanged", addresses: string[] } | { type: "SubsystemDown" } | { type: "SubsystemRestarted" } | { type: "SubsystemFailed", error: string }
+
+/**
+ *  A snapshot of the state of the P2P subsystem. This is returned by `p2p.status` so the frontend can show whether P2P is working without subscribing to events.
+ */
+export type P2PStatus = { listening: boolean, listen_addrs: string[], discovery_enabled: boolean, connected_peer_count: number, known_peer_count: number, last_error: string | null, malformed_sync_messages: number }
+
+/**
+ *  The data exchanged with peers since the node was started. This is returned by `p2p.traffic` to help diagnose slow syncs.
+ */
+export type P2PTraffic = { totals: Traffic, peers: PeerTraffic[] }
 
 /**
  *  These parameters define the password-hashing level.
  * 
  *  The greater the parameter, the longer the password will take to hash.
  */
-export type Params = "Standard" | "Hardened" | "Paranoid"
+export type Params = "Standard" | "Hardened" | "Paranoid" | { Custom: HashingParams }
+
+export type PeerAddress = { address: string, kind: AddressKind }
+
+/**
+ *  The addresses of a peer in the order they will be dialed. This is returned by `peerAddresses` to help troubleshoot connections.
+ */
+export type PeerAddresses = { addresses: PeerAddress[], pinned: string | null, last_connected: string | null }
+
+/**
+ *  The stages of bootstrapping a connection with a peer which shares a library with this node.
+ */
+export type PeerBootstrapProgress = "Connecting" | "ExchangingMetadata" | "TransferringKeys" | { InitialSync: { synced: number, total: number } } | "Done" | { Error: string }
 
-export type PeerMetadata = { name: string, operating_system: OperatingSystem | null, version: string | null, email: string | null, img_url: string | null }
+export type PeerMetadata = { name: string, device_id: string | null, operating_system: OperatingSystem | null, architecture: string | null, version: string | null, protocol_version: number | null, email: string | null, img_url: string | null, compression: boolean, libraries: string[] }
+
+export type PeerTraffic = { peer_id: string, name: string | null, traffic: Traffic }
+
+export type PinAddressArgs = { peer_id: string, address: string | null }
 
 export type RelationOperation = { relation_item: string, relation_group: string, relation: string, data: RelationOperationData }
 
 export type RelationOperationData = "Create" | { Update: { field: string, value: any } } | "Delete"
 
+export type RequestFileArgs = { peer_id: string, file: FileRequest, path: string }
+
 export type RestoreBackupArgs = { password: string, secret_key: string, path: string }
 
 export type RuleKind = "AcceptFilesByGlob" | "RejectFilesByGlob" | "AcceptIfChildrenDirectoriesArePresent" | "RejectIfChildrenDirectoriesArePresent"
@@ -273,6 +404,8 @@ export type SetFavoriteArgs = { id: number, favorite: boolean }
 
 export type SetNoteArgs = { id: number, note: string | null }
 
+export type SetPeerNicknameArgs = { peer_id: string, nickname: string | null }
+
 export type SharedOperation = { record_id: any, model: string, data: SharedOperationData }
 
 export type SharedOperationCreateData = { u: { [key: string]: any } } | "a"
@@ -288,7 +421,7 @@ export type Statistics = { id: number, date_captured: string, total_object_count
  * 
  *  It contains no sensitive information that is not encrypted.
  */
-export type StoredKey = { uuid: string, version: StoredKeyVersion, key_type: StoredKeyType, algorithm: Algorithm, hashing_algorithm: HashingAlgorithm, content_salt: Salt, master_key: EncryptedKey, master_key_nonce: Nonce, key_nonce: Nonce, key: number[], salt: Salt, memory_only: boolean, automount: boolean }
+export type StoredKey = { uuid: string, version: StoredKeyVersion, key_type: StoredKeyType, algorithm: Algorithm, hashing_algorithm: HashingAlgorithm, content_salt: Salt, master_key: EncryptedKey, master_key_nonce: Nonce, key_nonce: Nonce, key: number[], salt: Salt, memory_only: boolean, automount: boolean, label: string, description: string | null }
 
 /**
  *  This denotes the type of key. `Root` keys can be used to unlock the key manager, and `User` keys are ordinary keys.
@@ -300,6 +433,8 @@ export type StoredKeyType = "User" | "Root"
  */
 export type StoredKeyVersion = "V1"
 
+export type SyncQueueStats = { library_id: string, depth: number, capacity: number }
+
 export type Tag = { id: number, pub_id: number[], name: string | null, color: string | null, total_objects: number | null, redundancy_goal: number | null, date_created: string, date_modified: string }
 
 export type TagAssignArgs = { object_id: number, tag_id: number, unassign: boolean }
@@ -312,8 +447,25 @@ export type TokenizeKeyArgs = { secret_key: string }
 
 export type TokenizeResponse = { token: string }
 
+/**
+ *  The data exchanged with a peer, or with every peer for [P2PTraffic::totals]. A message is a single broadcast or stream.
+ */
+export type Traffic = { bytes_sent: string, bytes_received: string, messages_sent: number, messages_received: number, broadcasts_sent: number, broadcasts_received: number, last_activity: string | null }
+
+/**
+ *  identifies the file a transfer is reading from the peer. This is the same file as the `library_id`, `location_id` and `file_path_id` of a [super::Request::FileChunkWithChecksum].
+ */
+export type TransferId = { library_id: string, location_id: number, file_path_id: number }
+
+/**
+ *  the transports a [crate::Manager] can connect to peers with.
+ */
+export type Transport = "Quic" | "Tcp"
+
 export type UnlockKeyManagerArgs = { password: string, secret_key: string }
 
+export type VerifyPasswordArgs = { uuid: string, password: string }
+
 export type Volume = { name: string, mount_point: string, total_capacity: string, available_capacity: string, is_removable: boolean, disk_type: string | null, file_system: string | null, is_root_filesystem: boolean }
 
 export type file_path_with_object = { id: number, is_dir: boolean, cas_id: string | null, integrity_checksum: string | null, location_id: number, materialized_path: string, name: string, extension: string, size_in_bytes: string, inode: number[], device: number[], object_id: number | null, parent_id: number | null, key_id: number | null, date_created: string, date_modified: string, date_indexed: string, object: Object | null }